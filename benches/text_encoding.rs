@@ -0,0 +1,92 @@
+//! Encoding of text-heavy result sets with `encode_field` and the fast paths
+//! `encode_str_field` and `encode_bytes_field`.
+//!
+//! Run with `cargo bench --bench text_encoding`. Both paths produce the same
+//! bytes, so the difference is only the cost of `ToSql` dispatch and
+//! intermediate copies.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use pgwire::api::results::{DataRowEncoder, FieldFormat, FieldInfo};
+use pgwire::api::Type;
+use pgwire::messages::data::DataRow;
+
+const ROWS: usize = 20_000;
+const COLUMNS: usize = 10;
+const ROUNDS: usize = 10;
+
+fn schema(ty: Type) -> Arc<Vec<FieldInfo>> {
+    Arc::new(
+        (0..COLUMNS)
+            .map(|idx| FieldInfo::new(format!("c{idx}"), None, None, ty.clone(), FieldFormat::Text))
+            .collect(),
+    )
+}
+
+/// Best time of `ROUNDS` rounds encoding `ROWS` rows with `encode_row`
+fn measure<F>(name: &str, expected: &DataRow, mut encode_row: F)
+where
+    F: FnMut() -> DataRow,
+{
+    // same bytes as the generic path
+    assert_eq!(expected, &encode_row(), "{name}");
+
+    let mut best = Duration::MAX;
+    // the encoded bytes are used, so that rows are not optimized away
+    let mut encoded_bytes = 0;
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        for _ in 0..ROWS {
+            encoded_bytes += encode_row()
+                .fields()
+                .iter()
+                .map(|field| field.as_ref().map_or(0, |field| field.len()))
+                .sum::<usize>();
+        }
+        best = best.min(start.elapsed());
+    }
+    assert!(encoded_bytes > 0);
+    println!(
+        "{name:<28} {:>8.1} ns/row",
+        best.as_nanos() as f64 / ROWS as f64
+    );
+}
+
+fn main() {
+    let values: Vec<String> = (0..COLUMNS)
+        .map(|idx| format!("value of column {idx} in a text heavy row"))
+        .collect();
+
+    let text_schema = schema(Type::VARCHAR);
+    let encode_text = |fast: bool| {
+        let mut encoder = DataRowEncoder::new(text_schema.clone());
+        for value in &values {
+            if fast {
+                encoder.encode_str_field(value).unwrap();
+            } else {
+                encoder.encode_field(&value.as_str()).unwrap();
+            }
+        }
+        encoder.finish().unwrap()
+    };
+    let expected = encode_text(false);
+    measure("text encode_field", &expected, || encode_text(false));
+    measure("text encode_str_field", &expected, || encode_text(true));
+
+    let bytea_schema = schema(Type::BYTEA);
+    let encode_bytea = |fast: bool| {
+        let mut encoder = DataRowEncoder::new(bytea_schema.clone());
+        for value in &values {
+            if fast {
+                encoder.encode_bytes_field(value.as_bytes()).unwrap();
+            } else {
+                encoder.encode_field(&value.as_bytes()).unwrap();
+            }
+        }
+        encoder.finish().unwrap()
+    };
+    let expected = encode_bytea(false);
+    measure("bytea encode_field", &expected, || encode_bytea(false));
+    measure("bytea encode_bytes_field", &expected, || encode_bytea(true));
+}
@@ -0,0 +1,99 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures::{stream, StreamExt};
+use tokio::net::TcpListener;
+
+use pgwire::api::auth::noop::NoopStartupHandler;
+use pgwire::api::copy::NoopCopyHandler;
+use pgwire::api::query::{PlaceholderExtendedQueryHandler, SimpleQueryHandler};
+use pgwire::api::results::{DataRowEncoder, FieldFormat, FieldInfo, QueryResponse, Response, Tag};
+use pgwire::api::{ClientInfo, MakeHandler, StatelessMakeHandler, Type};
+use pgwire::error::PgWireResult;
+use pgwire::tokio::process_socket;
+
+/// Serves rows decoded from a buffer it owns, without copying them into an
+/// owned collection first.
+///
+/// The row stream of a `QueryResponse<'a>` may borrow from the handler and
+/// from the query string, as `do_query` ties both to `'a`. It's sent to
+/// client before the call on the handler returns, so the borrow is held for
+/// the whole stream.
+pub struct TableProcessor {
+    /// rows as `id,name` lines
+    table: String,
+}
+
+#[async_trait]
+impl SimpleQueryHandler for TableProcessor {
+    async fn do_query<'a, 'b: 'a, C>(
+        &'b self,
+        _client: &C,
+        query: &'a str,
+    ) -> PgWireResult<Vec<Response<'a>>>
+    where
+        C: ClientInfo + Unpin + Send + Sync,
+    {
+        let query = query.trim().trim_end_matches(';');
+        if !query.starts_with("SELECT") {
+            return Ok(vec![Response::Execution(Tag::new_for_execution(
+                "OK", None,
+            ))]);
+        }
+        // `SELECT ... WHERE name LIKE 'prefix%'`, borrowed from the query
+        let prefix = query
+            .split_once("LIKE '")
+            .map_or("", |(_, pattern)| pattern.trim_end_matches("%'"));
+
+        let f1 = FieldInfo::new("id".into(), None, None, Type::INT4, FieldFormat::Text);
+        let f2 = FieldInfo::new("name".into(), None, None, Type::VARCHAR, FieldFormat::Text);
+        let schema = Arc::new(vec![f1, f2]);
+
+        let schema_ref = schema.clone();
+        // each `&str` points into `self.table`
+        let rows = stream::iter(self.table.lines())
+            .filter_map(|line| futures::future::ready(line.split_once(',')))
+            .filter(move |(_, name)| futures::future::ready(name.starts_with(prefix)))
+            .map(move |(id, name)| {
+                let mut encoder = DataRowEncoder::new(schema_ref.clone());
+                encoder.encode_str_field(id)?;
+                encoder.encode_str_field(name)?;
+                encoder.finish()
+            });
+
+        Ok(vec![Response::Query(QueryResponse::new(schema, rows))])
+    }
+}
+
+#[tokio::main]
+pub async fn main() {
+    let processor = Arc::new(StatelessMakeHandler::new(Arc::new(TableProcessor {
+        table: "0,Tom\n1,Jerry\n2,Tuffy".to_owned(),
+    })));
+    // We have not implemented extended query in this server, use placeholder instead
+    let placeholder = Arc::new(StatelessMakeHandler::new(Arc::new(
+        PlaceholderExtendedQueryHandler,
+    )));
+    let authenticator = Arc::new(StatelessMakeHandler::new(Arc::new(NoopStartupHandler)));
+
+    let server_addr = "127.0.0.1:5432";
+    let listener = TcpListener::bind(server_addr).await.unwrap();
+    println!("Listening to {}", server_addr);
+    loop {
+        let incoming_socket = listener.accept().await.unwrap();
+        let authenticator_ref = authenticator.make();
+        let processor_ref = processor.make();
+        let placeholder_ref = placeholder.make();
+        tokio::spawn(async move {
+            process_socket(
+                incoming_socket.0,
+                None,
+                authenticator_ref,
+                processor_ref,
+                placeholder_ref,
+                Arc::new(NoopCopyHandler),
+            )
+            .await
+        });
+    }
+}
@@ -1,20 +1,20 @@
 use std::sync::Arc;
 
 use async_trait::async_trait;
-use datafusion::arrow::array::{Array, BooleanArray, PrimitiveArray, StringArray};
-use datafusion::arrow::datatypes::{
-    DataType, Float32Type, Float64Type, Int16Type, Int32Type, Int64Type, Int8Type, UInt32Type,
-};
+use datafusion::arrow::datatypes::Schema;
 use datafusion::arrow::record_batch::RecordBatch;
 use datafusion::prelude::*;
 use futures::{stream, StreamExt};
 use tokio::net::TcpListener;
 use tokio::sync::Mutex;
 
+use pgwire::api::arrow::{encode_record_batch, schema_to_fields};
 use pgwire::api::auth::noop::NoopStartupHandler;
+use pgwire::api::copy::NoopCopyHandler;
+use pgwire::api::portal::Format;
 use pgwire::api::query::{PlaceholderExtendedQueryHandler, SimpleQueryHandler};
-use pgwire::api::results::{DataRowEncoder, FieldFormat, FieldInfo, QueryResponse, Response, Tag};
-use pgwire::api::{ClientInfo, MakeHandler, StatelessMakeHandler, Type};
+use pgwire::api::results::{QueryResponse, Response, Tag};
+use pgwire::api::{ClientInfo, MakeHandler, StatelessMakeHandler};
 use pgwire::error::{ErrorInfo, PgWireError, PgWireResult};
 use pgwire::tokio::process_socket;
 
@@ -22,6 +22,12 @@ pub struct DfSessionService {
     session_context: Arc<Mutex<SessionContext>>,
 }
 
+impl Default for DfSessionService {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DfSessionService {
     pub fn new() -> DfSessionService {
         DfSessionService {
@@ -70,53 +76,9 @@ impl SimpleQueryHandler for DfSessionService {
     }
 }
 
-fn into_pg_type(df_type: &DataType) -> PgWireResult<Type> {
-    Ok(match df_type {
-        DataType::Null => Type::UNKNOWN,
-        DataType::Boolean => Type::BOOL,
-        DataType::Int8 => Type::CHAR,
-        DataType::Int16 => Type::INT2,
-        DataType::Int32 => Type::INT4,
-        DataType::Int64 => Type::INT8,
-        DataType::UInt8 => Type::CHAR,
-        DataType::UInt16 => Type::INT2,
-        DataType::UInt32 => Type::INT4,
-        DataType::UInt64 => Type::INT8,
-        DataType::Timestamp(_, _) => Type::TIMESTAMP,
-        DataType::Time32(_) | DataType::Time64(_) => Type::TIME,
-        DataType::Date32 | DataType::Date64 => Type::DATE,
-        DataType::Binary => Type::BYTEA,
-        DataType::Float32 => Type::FLOAT4,
-        DataType::Float64 => Type::FLOAT8,
-        DataType::Utf8 => Type::VARCHAR,
-        _ => {
-            return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
-                "ERROR".to_owned(),
-                "XX000".to_owned(),
-                format!("Unsupported Datatype {df_type}"),
-            ))));
-        }
-    })
-}
-
 async fn encode_dataframe<'a>(df: DataFrame) -> PgWireResult<QueryResponse<'a>> {
-    let schema = df.schema();
-    let fields = Arc::new(
-        schema
-            .fields()
-            .iter()
-            .map(|f| {
-                let pg_type = into_pg_type(f.data_type())?;
-                Ok(FieldInfo::new(
-                    f.name().into(),
-                    None,
-                    None,
-                    pg_type,
-                    FieldFormat::Text,
-                ))
-            })
-            .collect::<PgWireResult<Vec<FieldInfo>>>()?,
-    );
+    let schema: Schema = df.schema().into();
+    let fields = Arc::new(schema_to_fields(&schema, &Format::UnifiedText)?);
 
     let recordbatch_stream = df
         .execute_stream()
@@ -126,97 +88,19 @@ async fn encode_dataframe<'a>(df: DataFrame) -> PgWireResult<QueryResponse<'a>>
     let fields_ref = fields.clone();
     let pg_row_stream = recordbatch_stream
         .map(move |rb: datafusion::error::Result<RecordBatch>| {
-            let rb = rb.unwrap();
-            let rows = rb.num_rows();
-            let cols = rb.num_columns();
-            let mut results = Vec::with_capacity(rows);
-
-            for row in 0..rows {
-                let mut encoder = DataRowEncoder::new(fields_ref.clone());
-                for col in 0..cols {
-                    let array = rb.column(col);
-                    if array.is_null(row) {
-                        encoder.encode_field(&None::<i8>).unwrap();
-                    } else {
-                        encode_value(&mut encoder, array, row).unwrap();
-                    }
-                }
-                results.push(encoder.finish());
+            let rows = rb
+                .map_err(|e| PgWireError::ApiError(Box::new(e)))
+                .and_then(|rb| encode_record_batch(&fields_ref, &rb));
+            match rows {
+                Ok(rows) => stream::iter(rows.into_iter().map(Ok).collect::<Vec<_>>()),
+                Err(e) => stream::iter(vec![Err(e)]),
             }
-
-            stream::iter(results)
         })
         .flatten();
 
     Ok(QueryResponse::new(fields, pg_row_stream))
 }
 
-fn get_bool_value(arr: &Arc<dyn Array>, idx: usize) -> bool {
-    arr.as_any()
-        .downcast_ref::<BooleanArray>()
-        .unwrap()
-        .value(idx)
-}
-
-macro_rules! get_primitive_value {
-    ($name:ident, $t:ty, $pt:ty) => {
-        fn $name(arr: &Arc<dyn Array>, idx: usize) -> $pt {
-            arr.as_any()
-                .downcast_ref::<PrimitiveArray<$t>>()
-                .unwrap()
-                .value(idx)
-        }
-    };
-}
-
-get_primitive_value!(get_i8_value, Int8Type, i8);
-get_primitive_value!(get_i16_value, Int16Type, i16);
-get_primitive_value!(get_i32_value, Int32Type, i32);
-get_primitive_value!(get_i64_value, Int64Type, i64);
-// get_primitive_value!(get_u8_value, UInt8Type, u8);
-// get_primitive_value!(get_u16_value, UInt16Type, u16);
-get_primitive_value!(get_u32_value, UInt32Type, u32);
-// get_primitive_value!(get_u64_value, UInt64Type, u64);
-get_primitive_value!(get_f32_value, Float32Type, f32);
-get_primitive_value!(get_f64_value, Float64Type, f64);
-
-fn get_utf8_value(arr: &Arc<dyn Array>, idx: usize) -> &str {
-    arr.as_any()
-        .downcast_ref::<StringArray>()
-        .unwrap()
-        .value(idx)
-}
-
-fn encode_value(
-    encoder: &mut DataRowEncoder,
-    arr: &Arc<dyn Array>,
-    idx: usize,
-) -> PgWireResult<()> {
-    match arr.data_type() {
-        DataType::Boolean => encoder.encode_field(&get_bool_value(arr, idx))?,
-        DataType::Int8 => encoder.encode_field(&get_i8_value(arr, idx))?,
-        DataType::Int16 => encoder.encode_field(&get_i16_value(arr, idx))?,
-        DataType::Int32 => encoder.encode_field(&get_i32_value(arr, idx))?,
-        DataType::Int64 => encoder.encode_field(&get_i64_value(arr, idx))?,
-        DataType::UInt32 => encoder.encode_field(&get_u32_value(arr, idx))?,
-        DataType::Float32 => encoder.encode_field(&get_f32_value(arr, idx))?,
-        DataType::Float64 => encoder.encode_field(&get_f64_value(arr, idx))?,
-        DataType::Utf8 => encoder.encode_field(&get_utf8_value(arr, idx))?,
-        _ => {
-            return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
-                "ERROR".to_owned(),
-                "XX000".to_owned(),
-                format!(
-                    "Unsupported Datatype {} and array {:?}",
-                    arr.data_type(),
-                    &arr
-                ),
-            ))))
-        }
-    }
-    Ok(())
-}
-
 #[tokio::main]
 pub async fn main() {
     let processor = Arc::new(StatelessMakeHandler::new(Arc::new(DfSessionService::new())));
@@ -241,6 +125,7 @@ pub async fn main() {
                 authenticator_ref,
                 processor_ref,
                 placeholder_ref,
+                Arc::new(NoopCopyHandler),
             )
             .await
         });
@@ -6,6 +6,7 @@ use tokio::net::TcpListener;
 
 use gluesql::prelude::*;
 use pgwire::api::auth::noop::NoopStartupHandler;
+use pgwire::api::copy::NoopCopyHandler;
 use pgwire::api::query::{PlaceholderExtendedQueryHandler, SimpleQueryHandler};
 use pgwire::api::results::{DataRowEncoder, FieldFormat, FieldInfo, QueryResponse, Response, Tag};
 use pgwire::api::{ClientInfo, MakeHandler, StatelessMakeHandler, Type};
@@ -128,20 +129,20 @@ impl SimpleQueryHandler for GluesqlProcessor {
 
                             Ok(Response::Query(QueryResponse::new(
                                 fields,
-                                stream::iter(results.into_iter()),
+                                stream::iter(results),
                             )))
                         }
                         Payload::Insert(rows) => Ok(Response::Execution(Tag::new_for_execution(
                             "Insert",
-                            Some(*rows),
+                            Some(*rows as u64),
                         ))),
                         Payload::Delete(rows) => Ok(Response::Execution(Tag::new_for_execution(
                             "Delete",
-                            Some(*rows),
+                            Some(*rows as u64),
                         ))),
                         Payload::Update(rows) => Ok(Response::Execution(Tag::new_for_execution(
                             "Update",
-                            Some(*rows),
+                            Some(*rows as u64),
                         ))),
                         Payload::Create => {
                             Ok(Response::Execution(Tag::new_for_execution("Create", None)))
@@ -199,6 +200,7 @@ pub async fn main() {
                 authenticator_ref,
                 processor_ref,
                 placeholder_ref,
+                Arc::new(NoopCopyHandler),
             )
             .await
         });
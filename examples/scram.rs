@@ -11,6 +11,7 @@ use tokio_rustls::TlsAcceptor;
 
 use pgwire::api::auth::scram::{gen_salted_password, MakeSASLScramAuthStartupHandler};
 use pgwire::api::auth::{AuthSource, DefaultServerParameterProvider, LoginInfo, Password};
+use pgwire::api::copy::NoopCopyHandler;
 use pgwire::api::query::{PlaceholderExtendedQueryHandler, SimpleQueryHandler};
 use pgwire::api::results::{Response, Tag};
 
@@ -108,6 +109,7 @@ pub async fn main() {
                 authenticator_ref,
                 processor_ref,
                 placeholder_ref,
+                Arc::new(NoopCopyHandler),
             )
             .await
         });
@@ -6,15 +6,16 @@ use async_trait::async_trait;
 use futures::{stream, StreamExt};
 use rustls_pemfile::{certs, pkcs8_private_keys};
 use tokio::net::TcpListener;
-use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::rustls::{Certificate, PrivateKey};
 use tokio_rustls::TlsAcceptor;
 
 use pgwire::api::auth::noop::NoopStartupHandler;
+use pgwire::api::copy::NoopCopyHandler;
 use pgwire::api::query::{PlaceholderExtendedQueryHandler, SimpleQueryHandler};
 use pgwire::api::results::{DataRowEncoder, FieldFormat, FieldInfo, QueryResponse, Response, Tag};
 use pgwire::api::{ClientInfo, MakeHandler, StatelessMakeHandler, Type};
 use pgwire::error::PgWireResult;
-use pgwire::tokio::process_socket;
+use pgwire::tokio::{build_tls_acceptor, process_socket, TlsOptions};
 
 pub struct DummyProcessor;
 
@@ -36,7 +37,7 @@ impl SimpleQueryHandler for DummyProcessor {
                 (Some(2), None),
             ];
             let schema_ref = schema.clone();
-            let data_row_stream = stream::iter(data.into_iter()).map(move |r| {
+            let data_row_stream = stream::iter(data).map(move |r| {
                 let mut encoder = DataRowEncoder::new(schema_ref.clone());
                 encoder.encode_field(&r.0)?;
                 encoder.encode_field(&r.1)?;
@@ -65,13 +66,7 @@ fn setup_tls() -> Result<TlsAcceptor, IOError> {
         .map_err(|_| IOError::new(ErrorKind::InvalidInput, "invalid key"))
         .map(|mut keys| keys.drain(..).map(PrivateKey).next().unwrap())?;
 
-    let config = ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
-        .with_single_cert(cert, key)
-        .map_err(|err| IOError::new(ErrorKind::InvalidInput, err))?;
-
-    Ok(TlsAcceptor::from(Arc::new(config)))
+    build_tls_acceptor(cert, key, &TlsOptions::new())
 }
 
 #[tokio::main]
@@ -101,6 +96,7 @@ pub async fn main() {
                 authenticator_ref,
                 processor_ref,
                 placeholder_ref,
+                Arc::new(NoopCopyHandler),
             )
             .await
         });
@@ -8,6 +8,7 @@ use pgwire::api::auth::md5pass::{hash_md5_password, MakeMd5PasswordAuthStartupHa
 use pgwire::api::auth::{
     AuthSource, DefaultServerParameterProvider, LoginInfo, Password, ServerParameterProvider,
 };
+use pgwire::api::copy::NoopCopyHandler;
 use pgwire::api::portal::{Format, Portal};
 use pgwire::api::query::{ExtendedQueryHandler, SimpleQueryHandler, StatementOrPortal};
 use pgwire::api::results::{
@@ -16,7 +17,7 @@ use pgwire::api::results::{
 use pgwire::api::stmt::NoopQueryParser;
 use pgwire::api::store::MemPortalStore;
 use pgwire::api::{ClientInfo, MakeHandler, Type};
-use pgwire::error::{ErrorInfo, PgWireError, PgWireResult};
+use pgwire::error::{ErrorInfo, PgWireError, PgWireResult, Severity, SqlState};
 use pgwire::messages::data::DataRow;
 use pgwire::tokio::process_socket;
 use rusqlite::Rows;
@@ -93,9 +94,10 @@ impl SimpleQueryHandler for SqliteBackend {
         } else {
             conn.execute(query, ())
                 .map(|affected_rows| {
-                    vec![Response::Execution(
-                        Tag::new_for_execution("OK", Some(affected_rows)).into(),
-                    )]
+                    vec![Response::Execution(Tag::new_for_execution(
+                        "OK",
+                        Some(affected_rows as u64),
+                    ))]
                 })
                 .map_err(|e| PgWireError::ApiError(Box::new(e)))
         }
@@ -111,8 +113,8 @@ fn name_to_type(name: &str) -> PgWireResult<Type> {
         "BINARY" => Ok(Type::BYTEA),
         "FLOAT" => Ok(Type::FLOAT8),
         _ => Err(PgWireError::UserError(Box::new(ErrorInfo::new(
-            "ERROR".to_owned(),
-            "42846".to_owned(),
+            Severity::Error,
+            SqlState::CannotCoerce,
             format!("Unsupported data type: {name}"),
         )))),
     }
@@ -167,7 +169,7 @@ fn encode_row_data(
         results.push(encoder.finish());
     }
 
-    stream::iter(results.into_iter())
+    stream::iter(results)
 }
 
 fn get_params(portal: &Portal<String>) -> Vec<Box<dyn ToSql>> {
@@ -258,7 +260,7 @@ impl ExtendedQueryHandler for SqliteBackend {
         } else {
             stmt.execute::<&[&dyn rusqlite::ToSql]>(params_ref.as_ref())
                 .map(|affected_rows| {
-                    Response::Execution(Tag::new_for_execution("OK", Some(affected_rows)).into())
+                    Response::Execution(Tag::new_for_execution("OK", Some(affected_rows as u64)))
                 })
                 .map_err(|e| PgWireError::ApiError(Box::new(e)))
         }
@@ -275,19 +277,19 @@ impl ExtendedQueryHandler for SqliteBackend {
         let conn = self.conn.lock().unwrap();
         match target {
             StatementOrPortal::Statement(stmt) => {
-                let param_types = Some(stmt.parameter_types().clone());
+                let param_types = stmt.parameter_types().clone();
                 let stmt = conn
                     .prepare_cached(stmt.statement())
                     .map_err(|e| PgWireError::ApiError(Box::new(e)))?;
                 row_desc_from_stmt(&stmt, &Format::UnifiedBinary)
-                    .map(|fields| DescribeResponse::new(param_types, fields))
+                    .map(|fields| DescribeResponse::statement(param_types, fields))
             }
             StatementOrPortal::Portal(portal) => {
                 let stmt = conn
                     .prepare_cached(portal.statement().statement())
                     .map_err(|e| PgWireError::ApiError(Box::new(e)))?;
                 row_desc_from_stmt(&stmt, portal.result_column_format())
-                    .map(|fields| DescribeResponse::new(None, fields))
+                    .map(DescribeResponse::portal)
             }
         }
     }
@@ -315,6 +317,7 @@ impl MakeHandler for MakeSqliteBackend {
     fn make(&self) -> Self::Handler {
         Arc::new(SqliteBackend {
             conn: self.conn.clone(),
+            // statement names are per connection, so is the store
             portal_store: Arc::new(MemPortalStore::new()),
             query_parser: self.query_parser.clone(),
         })
@@ -343,6 +346,7 @@ pub async fn main() {
                 authenticator_ref,
                 processor_ref.clone(),
                 processor_ref,
+                Arc::new(NoopCopyHandler),
             )
             .await
         });
@@ -0,0 +1,34 @@
+#![no_main]
+
+use bytes::{Buf, BytesMut};
+use libfuzzer_sys::fuzz_target;
+use pgwire::messages::startup::PasswordMessageFamily;
+use pgwire::messages::PgWireFrontendMessage;
+
+fuzz_target!(|data: &[u8]| {
+    let mut buf = BytesMut::from(data);
+    loop {
+        let remaining = buf.remaining();
+        match PgWireFrontendMessage::decode(&mut buf) {
+            Ok(Some(message)) => {
+                assert!(buf.remaining() < remaining, "message decoded without progress");
+                // password messages are decoded by authentication handlers
+                if let PgWireFrontendMessage::PasswordMessageFamily(PasswordMessageFamily::Raw(
+                    body,
+                )) = message
+                {
+                    let raw = || PasswordMessageFamily::Raw(body.clone());
+                    let _ = raw().into_password();
+                    let _ = raw().into_sasl_initial_response();
+                    let _ = raw().into_sasl_response();
+                    let _ = raw().into_gss_response();
+                }
+            }
+            Ok(None) => {
+                assert_eq!(remaining, buf.remaining(), "incomplete message consumed");
+                break;
+            }
+            Err(_) => break,
+        }
+    }
+});
@@ -0,0 +1,12 @@
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use pgwire::messages::startup::{SslRequest, Startup};
+use pgwire::messages::Message;
+
+fuzz_target!(|data: &[u8]| {
+    let mut buf = BytesMut::from(data);
+    let _ = SslRequest::decode(&mut buf);
+    let _ = Startup::decode(&mut buf);
+});
@@ -0,0 +1,389 @@
+//! Enumeration of live connections and their activity, like
+//! `pg_stat_activity` of postgres.
+//!
+//! Set a `ConnectionRegistry` as `connection_registry` of `ServerOptions`.
+//! Each connection is then registered when accepted, and removed when it's
+//! closed. The user, database and `application_name` of a connection are
+//! updated from its startup parameters, and its state and current query as it
+//! processes messages. Keep a clone of the registry to list connections, for
+//! example to answer a `SHOW CONNECTIONS` command or to export metrics.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use super::{
+    ClientInfo, ConnectionNegotiation, PgWireConnectionState, TlsSession, TransactionStatus,
+    METADATA_DATABASE, METADATA_USER,
+};
+use crate::messages::extendedquery::TARGET_TYPE_BYTE_STATEMENT;
+use crate::messages::PgWireFrontendMessage;
+
+pub const METADATA_APPLICATION_NAME: &str = "application_name";
+
+/// State of a connection, as column `state` of `pg_stat_activity`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActivityState {
+    /// startup and authentication in progress
+    Starting,
+    /// waiting for a new command
+    Idle,
+    /// executing a query, or in an extended query pipeline not yet ended by
+    /// `Sync`
+    Active,
+    /// waiting for a new command in a transaction block
+    IdleInTransaction,
+    /// waiting for a new command in a failed transaction block
+    IdleInTransactionAborted,
+}
+
+impl ActivityState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ActivityState::Starting => "starting",
+            ActivityState::Idle => "idle",
+            ActivityState::Active => "active",
+            ActivityState::IdleInTransaction => "idle in transaction",
+            ActivityState::IdleInTransactionAborted => "idle in transaction (aborted)",
+        }
+    }
+
+    fn idle(status: TransactionStatus) -> ActivityState {
+        match status {
+            TransactionStatus::Idle => ActivityState::Idle,
+            TransactionStatus::Transaction => ActivityState::IdleInTransaction,
+            TransactionStatus::Error => ActivityState::IdleInTransactionAborted,
+        }
+    }
+}
+
+impl fmt::Display for ActivityState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Snapshot of a connection in `ConnectionRegistry`
+#[derive(Debug, Clone, Getters)]
+#[getset(get = "pub")]
+pub struct ConnectionActivity {
+    /// pid of the connection, unique in the registry, as sent to client in
+    /// `BackendKeyData`
+    pid: i32,
+    client_addr: SocketAddr,
+    user: Option<String>,
+    database: Option<String>,
+    application_name: Option<String>,
+    /// negotiated TLS parameters, `None` for plaintext connections, like
+    /// `pg_stat_ssl`
+    tls: Option<TlsSession>,
+    /// encryption requested by client before startup, and protocol version
+    /// of its startup packet
+    negotiation: ConnectionNegotiation,
+    state: ActivityState,
+    /// current query when active, otherwise the last query
+    query: Option<String>,
+    backend_start: SystemTime,
+    /// time the current or the last query started
+    query_start: Option<SystemTime>,
+}
+
+/// Registry of live connections of a server
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionRegistry {
+    inner: Arc<ConnectionRegistryInner>,
+}
+
+#[derive(Debug, Default)]
+struct ConnectionRegistryInner {
+    last_pid: AtomicI32,
+    connections: Mutex<HashMap<i32, ConnectionActivity>>,
+}
+
+impl ConnectionRegistry {
+    pub fn new() -> ConnectionRegistry {
+        ConnectionRegistry::default()
+    }
+
+    /// Snapshot of all live connections, ordered by pid
+    pub fn connections(&self) -> Vec<ConnectionActivity> {
+        let mut connections: Vec<ConnectionActivity> = self
+            .inner
+            .connections
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect();
+        connections.sort_by_key(|c| c.pid);
+        connections
+    }
+
+    /// Snapshot of connection `pid`
+    pub fn get(&self, pid: i32) -> Option<ConnectionActivity> {
+        self.inner.connections.lock().unwrap().get(&pid).cloned()
+    }
+
+    /// Number of live connections
+    pub fn len(&self) -> usize {
+        self.inner.connections.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Register a new connection from `client_addr`, it's removed when the
+    /// returned registration is dropped. The connection gets `pid` when it's
+    /// already given by a `CancellationRegistry`, otherwise a new one.
+    pub(crate) fn register(&self, client_addr: SocketAddr, pid: Option<i32>) -> RegisteredActivity {
+        let mut connections = self.inner.connections.lock().unwrap();
+        let pid = pid.unwrap_or_else(|| loop {
+            let pid = self
+                .inner
+                .last_pid
+                .fetch_add(1, Ordering::Relaxed)
+                .wrapping_add(1);
+            if pid > 0 && !connections.contains_key(&pid) {
+                break pid;
+            }
+        });
+        let activity = ConnectionActivity {
+            pid,
+            client_addr,
+            user: None,
+            database: None,
+            application_name: None,
+            tls: None,
+            negotiation: ConnectionNegotiation::default(),
+            state: ActivityState::Starting,
+            query: None,
+            backend_start: SystemTime::now(),
+            query_start: None,
+        };
+        connections.insert(pid, activity);
+        RegisteredActivity {
+            registry: self.clone(),
+            pid,
+            statements: HashMap::new(),
+            portals: HashMap::new(),
+            pending: None,
+        }
+    }
+
+    fn update<F>(&self, pid: i32, f: F)
+    where
+        F: FnOnce(&mut ConnectionActivity),
+    {
+        if let Some(activity) = self.inner.connections.lock().unwrap().get_mut(&pid) {
+            f(activity);
+        }
+    }
+}
+
+/// Registration of a connection in `ConnectionRegistry`, removed on drop
+#[derive(Debug)]
+pub(crate) struct RegisteredActivity {
+    registry: ConnectionRegistry,
+    pid: i32,
+    /// query strings of prepared statements and portals, to report the query
+    /// of `Execute`
+    statements: HashMap<String, Arc<str>>,
+    portals: HashMap<String, Arc<str>>,
+    /// change of statements or portals by the message being processed,
+    /// applied when it succeeded
+    pending: Option<PendingChange>,
+}
+
+#[derive(Debug)]
+enum PendingChange {
+    Statement(String, Arc<str>),
+    Portal(String, Arc<str>),
+    Close(u8, String),
+}
+
+impl RegisteredActivity {
+    pub(crate) fn pid(&self) -> i32 {
+        self.pid
+    }
+
+    /// Update activity before a message from client is processed
+    pub(crate) fn start(&mut self, message: &PgWireFrontendMessage) {
+        self.pending = match message {
+            PgWireFrontendMessage::Parse(parse) => Some(PendingChange::Statement(
+                parse.name().clone().unwrap_or_default(),
+                Arc::from(parse.query().as_str()),
+            )),
+            PgWireFrontendMessage::Bind(bind) => {
+                let statement = bind.statement_name().as_deref().unwrap_or_default();
+                self.statements.get(statement).map(|query| {
+                    PendingChange::Portal(
+                        bind.portal_name().clone().unwrap_or_default(),
+                        query.clone(),
+                    )
+                })
+            }
+            PgWireFrontendMessage::Close(close) => Some(PendingChange::Close(
+                close.target_type(),
+                close.name().clone().unwrap_or_default(),
+            )),
+            _ => None,
+        };
+        let query = match message {
+            PgWireFrontendMessage::Query(query) => Some(Arc::from(query.query().as_str())),
+            PgWireFrontendMessage::Execute(execute) => self
+                .portals
+                .get(execute.name().as_deref().unwrap_or_default())
+                .cloned(),
+            _ => None,
+        };
+
+        if let Some(query) = query {
+            self.registry.update(self.pid, |activity| {
+                activity.state = ActivityState::Active;
+                activity.query = Some(query.to_string());
+                activity.query_start = Some(SystemTime::now());
+            });
+        }
+    }
+
+    /// Update activity after a message from client is processed, the
+    /// connection is idle when `ReadyForQuery` has been sent for it.
+    /// Statements and portals of the message are only recorded when it
+    /// `succeeded`.
+    pub(crate) fn finish<C: ClientInfo>(
+        &mut self,
+        client: &C,
+        ready_for_query_sent: bool,
+        succeeded: bool,
+    ) {
+        match self.pending.take() {
+            Some(PendingChange::Statement(name, query)) if succeeded => {
+                self.statements.insert(name, query);
+            }
+            Some(PendingChange::Portal(name, query)) if succeeded => {
+                self.portals.insert(name, query);
+            }
+            Some(PendingChange::Close(target_type, name)) if succeeded => {
+                if target_type == TARGET_TYPE_BYTE_STATEMENT {
+                    self.statements.remove(&name);
+                } else {
+                    self.portals.remove(&name);
+                }
+            }
+            _ => {}
+        }
+
+        let metadata = client.metadata();
+        let tls = client.extensions().get::<TlsSession>();
+        let negotiation = client.extensions().get::<ConnectionNegotiation>();
+        self.registry.update(self.pid, |activity| {
+            activity.user = metadata.get(METADATA_USER).cloned();
+            activity.database = metadata.get(METADATA_DATABASE).cloned();
+            activity.application_name = metadata.get(METADATA_APPLICATION_NAME).cloned();
+            activity.tls = tls.cloned();
+            activity.negotiation = negotiation.copied().unwrap_or_default();
+            activity.state = match client.state() {
+                PgWireConnectionState::AwaitingStartup
+                | PgWireConnectionState::AuthenticationInProgress => ActivityState::Starting,
+                PgWireConnectionState::CopyInProgress(_) => ActivityState::Active,
+                _ if ready_for_query_sent => ActivityState::idle(client.transaction_status()),
+                _ => activity.state,
+            };
+        });
+    }
+}
+
+impl Drop for RegisteredActivity {
+    fn drop(&mut self) {
+        if let Ok(mut connections) = self.registry.inner.connections.lock() {
+            connections.remove(&self.pid);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::mock::MockClient;
+    use crate::messages::extendedquery::{
+        Bind, Close, Execute, Parse, Sync as PgSync, TARGET_TYPE_BYTE_PORTAL,
+    };
+    use crate::messages::simplequery::Query;
+
+    #[test]
+    fn test_activity_of_extended_query() {
+        let registry = ConnectionRegistry::new();
+        let mut client = MockClient::new();
+        client.set_state(PgWireConnectionState::ReadyForQuery);
+        let mut activity = registry.register(*client.socket_addr(), None);
+        let pid = activity.pid();
+        assert_eq!(pid, registry.connections()[0].pid);
+
+        // a failed `Parse` does not replace the statement
+        let parse = |query: &str| {
+            PgWireFrontendMessage::Parse(Parse::new(
+                Some("s1".to_owned()),
+                query.to_owned(),
+                vec![],
+            ))
+        };
+        activity.start(&parse("SELECT 1"));
+        activity.finish(&client, false, true);
+        activity.start(&parse("SELECT 2"));
+        activity.finish(&client, false, false);
+
+        let messages = [
+            PgWireFrontendMessage::Bind(Bind::new(
+                None,
+                Some("s1".to_owned()),
+                vec![],
+                vec![],
+                vec![],
+            )),
+            PgWireFrontendMessage::Execute(Execute::new(None, 0)),
+        ];
+        for message in &messages {
+            activity.start(message);
+            activity.finish(&client, false, true);
+        }
+        let snapshot = registry.get(pid).unwrap();
+        assert_eq!(ActivityState::Active, *snapshot.state());
+        assert_eq!(Some("SELECT 1"), snapshot.query().as_deref());
+
+        let sync = PgWireFrontendMessage::Sync(PgSync::new());
+        activity.start(&sync);
+        client.set_transaction_status(TransactionStatus::Transaction);
+        activity.finish(&client, true, true);
+        let snapshot = registry.get(pid).unwrap();
+        assert_eq!(ActivityState::IdleInTransaction, *snapshot.state());
+        // the last query is kept when idle
+        assert_eq!(Some("SELECT 1"), snapshot.query().as_deref());
+
+        let query = PgWireFrontendMessage::Query(Query::new("COMMIT".to_owned()));
+        activity.start(&query);
+        assert_eq!(ActivityState::Active, *registry.get(pid).unwrap().state());
+        client.set_transaction_status(TransactionStatus::Idle);
+        activity.finish(&client, true, true);
+        assert_eq!("idle", registry.get(pid).unwrap().state().to_string());
+
+        // closed portals are forgotten
+        activity.start(&PgWireFrontendMessage::Close(Close::new(
+            TARGET_TYPE_BYTE_PORTAL,
+            None,
+        )));
+        activity.finish(&client, false, true);
+        activity.start(&messages[1]);
+        activity.finish(&client, false, false);
+        assert_eq!(
+            Some("COMMIT"),
+            registry.get(pid).unwrap().query().as_deref()
+        );
+
+        drop(activity);
+        assert!(registry.is_empty());
+    }
+}
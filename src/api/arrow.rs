@@ -0,0 +1,321 @@
+//! Conversion of Arrow record batches to query results, enabled by feature
+//! `with-arrow`.
+//!
+//! `schema_to_fields` maps an Arrow schema to `FieldInfo`s, and
+//! `encode_record_batch` encodes rows of a `RecordBatch` into `DataRow`s,
+//! with nulls checked per value of each column. This is what an Arrow based
+//! engine needs to answer queries, without depending on a full query engine
+//! like DataFusion.
+//!
+//! Supported types are nulls, booleans, integers, floats, strings, binaries,
+//! dates and timestamps. Unsigned integers are mapped to the next wider signed
+//! type, as Postgres has no unsigned integers, and `UInt64` values above
+//! `i64::MAX` are rejected with `22003`. Other types are rejected with
+//! `0A000`.
+
+use std::sync::Arc;
+
+use arrow::array::{
+    as_boolean_array, as_generic_binary_array, as_largestring_array, as_primitive_array,
+    as_string_array, Array,
+};
+use arrow::datatypes::{
+    DataType, Date32Type, Date64Type, Float32Type, Float64Type, Int16Type, Int32Type, Int64Type,
+    Int8Type, Schema, TimeUnit, TimestampMicrosecondType, TimestampMillisecondType,
+    TimestampNanosecondType, TimestampSecondType, UInt16Type, UInt32Type, UInt64Type, UInt8Type,
+};
+use arrow::record_batch::RecordBatch;
+use chrono::{TimeZone, Utc};
+
+use super::portal::Format;
+use super::results::{DataRowEncoder, FieldInfo};
+use super::Type;
+use crate::error::{ErrorInfo, PgWireError, PgWireResult, Severity, SqlState};
+use crate::messages::data::DataRow;
+
+/// Map Arrow type to Postgres type
+pub fn into_pg_type(data_type: &DataType) -> PgWireResult<Type> {
+    Ok(match data_type {
+        DataType::Null => Type::UNKNOWN,
+        DataType::Boolean => Type::BOOL,
+        DataType::Int8 | DataType::Int16 | DataType::UInt8 => Type::INT2,
+        DataType::Int32 | DataType::UInt16 => Type::INT4,
+        DataType::Int64 | DataType::UInt32 | DataType::UInt64 => Type::INT8,
+        DataType::Timestamp(_, None) => Type::TIMESTAMP,
+        DataType::Timestamp(_, Some(_)) => Type::TIMESTAMPTZ,
+        DataType::Date32 | DataType::Date64 => Type::DATE,
+        DataType::Binary | DataType::LargeBinary => Type::BYTEA,
+        DataType::Float32 => Type::FLOAT4,
+        DataType::Float64 => Type::FLOAT8,
+        DataType::Utf8 | DataType::LargeUtf8 => Type::VARCHAR,
+        _ => return Err(unsupported_type(data_type)),
+    })
+}
+
+/// Map Arrow schema to fields, in result formats `format`
+pub fn schema_to_fields(schema: &Schema, format: &Format) -> PgWireResult<Vec<FieldInfo>> {
+    schema
+        .fields()
+        .iter()
+        .enumerate()
+        .map(|(idx, f)| {
+            Ok(FieldInfo::new(
+                f.name().into(),
+                None,
+                None,
+                into_pg_type(f.data_type())?,
+                format.format_for(idx),
+            ))
+        })
+        .collect()
+}
+
+/// Encode all rows of a record batch, with `fields` from `schema_to_fields`
+pub fn encode_record_batch(
+    fields: &Arc<Vec<FieldInfo>>,
+    batch: &RecordBatch,
+) -> PgWireResult<Vec<DataRow>> {
+    let mut rows = Vec::with_capacity(batch.num_rows());
+    for row in 0..batch.num_rows() {
+        let mut encoder = DataRowEncoder::new(fields.clone());
+        for column in batch.columns() {
+            encode_value(&mut encoder, column.as_ref(), row)?;
+        }
+        rows.push(encoder.finish()?);
+    }
+    Ok(rows)
+}
+
+/// Fields and rows of a record batch, in result formats `format`
+pub fn record_batch_to_rows(
+    batch: &RecordBatch,
+    format: &Format,
+) -> PgWireResult<(Vec<FieldInfo>, Vec<DataRow>)> {
+    let fields = Arc::new(schema_to_fields(&batch.schema(), format)?);
+    let rows = encode_record_batch(&fields, batch)?;
+    let fields = Arc::try_unwrap(fields).unwrap_or_else(|fields| fields.as_ref().clone());
+    Ok((fields, rows))
+}
+
+fn unsupported_type(data_type: &DataType) -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        Severity::Error,
+        SqlState::FeatureNotSupported,
+        format!("unsupported arrow type {data_type}"),
+    )))
+}
+
+fn encode_value(encoder: &mut DataRowEncoder, arr: &dyn Array, idx: usize) -> PgWireResult<()> {
+    if arr.is_null(idx) {
+        return encoder.encode_field(&None::<i8>);
+    }
+
+    match arr.data_type() {
+        DataType::Null => encoder.encode_field(&None::<i8>),
+        DataType::Boolean => encoder.encode_field(&as_boolean_array(arr).value(idx)),
+        DataType::Int8 => {
+            encoder.encode_field(&(as_primitive_array::<Int8Type>(arr).value(idx) as i16))
+        }
+        DataType::Int16 => encoder.encode_field(&as_primitive_array::<Int16Type>(arr).value(idx)),
+        DataType::Int32 => encoder.encode_field(&as_primitive_array::<Int32Type>(arr).value(idx)),
+        DataType::Int64 => encoder.encode_field(&as_primitive_array::<Int64Type>(arr).value(idx)),
+        DataType::UInt8 => {
+            encoder.encode_field(&(as_primitive_array::<UInt8Type>(arr).value(idx) as i16))
+        }
+        DataType::UInt16 => {
+            encoder.encode_field(&(as_primitive_array::<UInt16Type>(arr).value(idx) as i32))
+        }
+        DataType::UInt32 => {
+            encoder.encode_field(&(as_primitive_array::<UInt32Type>(arr).value(idx) as i64))
+        }
+        DataType::UInt64 => {
+            let value = as_primitive_array::<UInt64Type>(arr).value(idx);
+            let value = i64::try_from(value).map_err(|_| {
+                PgWireError::UserError(Box::new(ErrorInfo::new(
+                    Severity::Error,
+                    SqlState::NumericValueOutOfRange,
+                    "bigint out of range",
+                )))
+            })?;
+            encoder.encode_field(&value)
+        }
+        DataType::Float32 => {
+            encoder.encode_field(&as_primitive_array::<Float32Type>(arr).value(idx))
+        }
+        DataType::Float64 => {
+            encoder.encode_field(&as_primitive_array::<Float64Type>(arr).value(idx))
+        }
+        DataType::Utf8 => encoder.encode_field(&as_string_array(arr).value(idx)),
+        DataType::LargeUtf8 => encoder.encode_field(&as_largestring_array(arr).value(idx)),
+        DataType::Binary => encoder.encode_field(&as_generic_binary_array::<i32>(arr).value(idx)),
+        DataType::LargeBinary => {
+            encoder.encode_field(&as_generic_binary_array::<i64>(arr).value(idx))
+        }
+        DataType::Date32 => {
+            encoder.encode_field(&as_primitive_array::<Date32Type>(arr).value_as_date(idx))
+        }
+        DataType::Date64 => {
+            encoder.encode_field(&as_primitive_array::<Date64Type>(arr).value_as_date(idx))
+        }
+        DataType::Timestamp(unit, tz) => {
+            let value = match unit {
+                TimeUnit::Second => {
+                    as_primitive_array::<TimestampSecondType>(arr).value_as_datetime(idx)
+                }
+                TimeUnit::Millisecond => {
+                    as_primitive_array::<TimestampMillisecondType>(arr).value_as_datetime(idx)
+                }
+                TimeUnit::Microsecond => {
+                    as_primitive_array::<TimestampMicrosecondType>(arr).value_as_datetime(idx)
+                }
+                TimeUnit::Nanosecond => {
+                    as_primitive_array::<TimestampNanosecondType>(arr).value_as_datetime(idx)
+                }
+            };
+            // values of timestamps with time zone are stored in UTC
+            if tz.is_some() {
+                encoder.encode_field(&value.map(|v| Utc.from_utc_datetime(&v)))
+            } else {
+                encoder.encode_field(&value)
+            }
+        }
+        data_type => Err(unsupported_type(data_type)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use arrow::array::{
+        BooleanArray, Date32Array, Float64Array, Int32Array, ListArray, StringArray,
+        TimestampMicrosecondArray, UInt64Array,
+    };
+    use arrow::datatypes::{Field, Int32Type};
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::api::results::FieldFormat;
+
+    fn text(row: &DataRow) -> Vec<Option<String>> {
+        row.fields()
+            .iter()
+            .map(|f| f.as_ref().map(|v| String::from_utf8_lossy(v).into_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn test_encode_record_batch() {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int32, false),
+            Field::new("score", DataType::Float64, true),
+            Field::new("name", DataType::Utf8, true),
+            Field::new("active", DataType::Boolean, true),
+            Field::new("day", DataType::Date32, true),
+            Field::new("ts", DataType::Timestamp(TimeUnit::Microsecond, None), true),
+            Field::new(
+                "ts_tz",
+                DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".to_owned())),
+                true,
+            ),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(Int32Array::from(vec![1, 2])),
+                Arc::new(Float64Array::from(vec![Some(0.5), None])),
+                Arc::new(StringArray::from(vec![None, Some("tom")])),
+                Arc::new(BooleanArray::from(vec![Some(true), None])),
+                Arc::new(Date32Array::from(vec![Some(1), None])),
+                Arc::new(TimestampMicrosecondArray::from(vec![Some(1_000_001), None])),
+                Arc::new(
+                    TimestampMicrosecondArray::from(vec![None, Some(0)])
+                        .with_timezone("UTC".to_owned()),
+                ),
+            ],
+        )
+        .unwrap();
+
+        let (fields, rows) = record_batch_to_rows(&batch, &Format::UnifiedText).unwrap();
+        assert_eq!(
+            vec![
+                &Type::INT4,
+                &Type::FLOAT8,
+                &Type::VARCHAR,
+                &Type::BOOL,
+                &Type::DATE,
+                &Type::TIMESTAMP,
+                &Type::TIMESTAMPTZ
+            ],
+            fields.iter().map(|f| f.datatype()).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![
+                Some("1".to_owned()),
+                Some("0.5".to_owned()),
+                None,
+                Some("t".to_owned()),
+                Some("1970-01-02".to_owned()),
+                Some("1970-01-01 00:00:01.000001".to_owned()),
+                None
+            ],
+            text(&rows[0])
+        );
+        assert_eq!(
+            vec![
+                Some("2".to_owned()),
+                None,
+                Some("tom".to_owned()),
+                None,
+                None,
+                None,
+                Some("1970-01-01 00:00:00.000000+00".to_owned())
+            ],
+            text(&rows[1])
+        );
+    }
+
+    #[test]
+    fn test_encode_record_batch_binary() {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int32, false),
+            Field::new("name", DataType::Utf8, false),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(Int32Array::from(vec![7])),
+                Arc::new(StringArray::from(vec!["tom"])),
+            ],
+        )
+        .unwrap();
+
+        let (fields, rows) = record_batch_to_rows(&batch, &Format::Individual(vec![1, 0])).unwrap();
+        assert_eq!(&FieldFormat::Binary, fields[0].format());
+        assert_eq!(&FieldFormat::Text, fields[1].format());
+        assert_eq!(
+            &vec![
+                Some(Bytes::from_static(&[0, 0, 0, 7])),
+                Some(Bytes::from_static(b"tom"))
+            ],
+            rows[0].fields()
+        );
+    }
+
+    #[test]
+    fn test_unsupported_values() {
+        let schema = Arc::new(Schema::new(vec![Field::new("n", DataType::UInt64, false)]));
+        let batch =
+            RecordBatch::try_new(schema, vec![Arc::new(UInt64Array::from(vec![1, u64::MAX]))])
+                .unwrap();
+        match record_batch_to_rows(&batch, &Format::UnifiedText) {
+            Err(PgWireError::UserError(error)) => assert_eq!("22003", error.code()),
+            result => panic!("unexpected result {result:?}"),
+        }
+
+        let list = ListArray::from_iter_primitive::<Int32Type, _, _>(vec![Some(vec![Some(1)])]);
+        let batch = RecordBatch::try_from_iter(vec![("l", Arc::new(list) as _)]).unwrap();
+        match record_batch_to_rows(&batch, &Format::UnifiedText) {
+            Err(PgWireError::UserError(error)) => assert_eq!("0A000", error.code()),
+            result => panic!("unexpected result {result:?}"),
+        }
+    }
+}
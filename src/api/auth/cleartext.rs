@@ -7,8 +7,7 @@ use super::{
     AuthSource, ClientInfo, LoginInfo, PgWireConnectionState, ServerParameterProvider,
     StartupHandler,
 };
-use crate::error::{ErrorInfo, PgWireError, PgWireResult};
-use crate::messages::response::ErrorResponse;
+use crate::error::{PgWireError, PgWireResult};
 use crate::messages::startup::Authentication;
 use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
 
@@ -16,6 +15,21 @@ use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
 pub struct CleartextPasswordAuthStartupHandler<A, P> {
     auth_source: A,
     parameter_provider: P,
+    #[new(value = "1")]
+    max_attempts: usize,
+}
+
+impl<A, P> CleartextPasswordAuthStartupHandler<A, P> {
+    /// Allow `max_attempts` passwords on a connection, 1 by default like
+    /// postgres.
+    ///
+    /// After each wrong password but the last one, a notice is sent and the
+    /// password is asked again. This is not standard, libpq doesn't send the
+    /// password again on the same connection, and is only useful to custom
+    /// clients.
+    pub fn set_max_attempts(&mut self, max_attempts: usize) {
+        self.max_attempts = max_attempts;
+    }
 }
 
 #[async_trait]
@@ -47,19 +61,13 @@ impl<V: AuthSource, P: ServerParameterProvider> StartupHandler
                 let login_info = LoginInfo::from_client_info(client);
                 let pass = self.auth_source.get_password(&login_info).await?;
                 if pass.password() == pwd.password().as_bytes() {
-                    super::finish_authentication(client, &self.parameter_provider).await
-                } else {
-                    let error_info = ErrorInfo::new(
-                        "FATAL".to_owned(),
-                        "28P01".to_owned(),
-                        "Password authentication failed".to_owned(),
-                    );
-                    let error = ErrorResponse::from(error_info);
-
+                    super::finish_authentication(client, &self.parameter_provider).await?
+                } else if super::reject_password(client, self.max_attempts).await? {
                     client
-                        .feed(PgWireBackendMessage::ErrorResponse(error))
+                        .send(PgWireBackendMessage::Authentication(
+                            Authentication::CleartextPassword,
+                        ))
                         .await?;
-                    client.close().await?;
                 }
             }
             _ => {}
@@ -67,3 +75,87 @@ impl<V: AuthSource, P: ServerParameterProvider> StartupHandler
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use bytes::BytesMut;
+
+    use super::*;
+    use crate::api::auth::{DefaultServerParameterProvider, Password};
+    use crate::api::mock::MockClient;
+    use crate::messages::startup::{PasswordMessageFamily, Startup};
+
+    struct StaticAuthSource;
+
+    #[async_trait]
+    impl AuthSource for StaticAuthSource {
+        async fn get_password(&self, _login: &LoginInfo) -> PgWireResult<Password> {
+            Ok(Password::new(None, b"secret".to_vec()))
+        }
+    }
+
+    async fn send_password(
+        handler: &CleartextPasswordAuthStartupHandler<
+            StaticAuthSource,
+            DefaultServerParameterProvider,
+        >,
+        client: &mut MockClient,
+        password: &[u8],
+    ) -> Vec<PgWireBackendMessage> {
+        let mut message = BytesMut::from(password);
+        message.extend_from_slice(b"\0");
+        handler
+            .on_startup(
+                client,
+                PgWireFrontendMessage::PasswordMessageFamily(PasswordMessageFamily::Raw(message)),
+            )
+            .await
+            .unwrap();
+        client.take_messages()
+    }
+
+    #[tokio::test]
+    async fn test_password_attempts() {
+        let mut handler = CleartextPasswordAuthStartupHandler::new(
+            StaticAuthSource,
+            DefaultServerParameterProvider,
+        );
+        handler.set_max_attempts(2);
+        let mut client = MockClient::new();
+        handler
+            .on_startup(&mut client, PgWireFrontendMessage::Startup(Startup::new()))
+            .await
+            .unwrap();
+        client.take_messages();
+
+        // asked again after the first wrong password
+        assert!(matches!(
+            send_password(&handler, &mut client, b"wrong")
+                .await
+                .as_slice(),
+            [
+                PgWireBackendMessage::NoticeResponse(_),
+                PgWireBackendMessage::Authentication(Authentication::CleartextPassword)
+            ]
+        ));
+        match send_password(&handler, &mut client, b"wrong")
+            .await
+            .as_slice()
+        {
+            [PgWireBackendMessage::ErrorResponse(error)] => {
+                assert!(error.fields().iter().any(|f| f.1 == "28P01"))
+            }
+            messages => panic!("unexpected messages {messages:?}"),
+        }
+
+        // a new connection starts over, and succeeds with the right password
+        let mut client = MockClient::new();
+        send_password(&handler, &mut client, b"wrong").await;
+        assert!(matches!(
+            send_password(&handler, &mut client, b"secret")
+                .await
+                .first(),
+            Some(PgWireBackendMessage::Authentication(Authentication::Ok))
+        ));
+    }
+}
@@ -6,19 +6,21 @@ use futures::sink::{Sink, SinkExt};
 use tokio::sync::Mutex;
 
 use super::{
-    AuthSource, ClientInfo, LoginInfo, PgWireConnectionState, ServerParameterProvider,
-    StartupHandler,
+    AuthSource, ClientInfo, LoginInfo, MockAuthentication, PgWireConnectionState,
+    ServerParameterProvider, StartupHandler,
 };
 use crate::api::MakeHandler;
-use crate::error::{ErrorInfo, PgWireError, PgWireResult};
-use crate::messages::response::ErrorResponse;
+use crate::error::{PgWireError, PgWireResult};
 use crate::messages::startup::Authentication;
 use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
 
 pub struct Md5PasswordAuthStartupHandler<A, P> {
     auth_source: Arc<A>,
     parameter_provider: Arc<P>,
-    cached_password: Mutex<Vec<u8>>,
+    /// `None` for unknown users under mock authentication, never matched
+    cached_password: Mutex<Option<Vec<u8>>>,
+    max_attempts: usize,
+    mock_auth: Option<MockAuthentication>,
 }
 
 #[async_trait]
@@ -40,40 +42,34 @@ impl<A: AuthSource, P: ServerParameterProvider> StartupHandler
                 super::save_startup_parameters_to_metadata(client, startup);
                 client.set_state(PgWireConnectionState::AuthenticationInProgress);
 
-                let login_info = LoginInfo::from_client_info(client);
-                let salt_and_pass = self.auth_source.get_password(&login_info).await?;
-
-                let salt = salt_and_pass
-                    .salt()
-                    .as_ref()
-                    .expect("Salt is required for Md5Password authentication");
-
-                *self.cached_password.lock().await = salt_and_pass.password().clone();
-
+                let salt = self
+                    .cache_password(&LoginInfo::from_client_info(client), 0)
+                    .await?;
                 client
                     .send(PgWireBackendMessage::Authentication(
-                        Authentication::MD5Password(salt.clone()),
+                        Authentication::MD5Password(salt),
                     ))
                     .await?;
             }
             PgWireFrontendMessage::PasswordMessageFamily(pwd) => {
                 let pwd = pwd.into_password()?;
-                let cached_pass = self.cached_password.lock().await;
-
-                if pwd.password().as_bytes() == *cached_pass {
-                    super::finish_authentication(client, self.parameter_provider.as_ref()).await
-                } else {
-                    let error_info = ErrorInfo::new(
-                        "FATAL".to_owned(),
-                        "28P01".to_owned(),
-                        "Password authentication failed".to_owned(),
-                    );
-                    let error = ErrorResponse::from(error_info);
+                let matched =
+                    self.cached_password.lock().await.as_deref() == Some(pwd.password().as_bytes());
 
+                if matched {
+                    super::finish_authentication(client, self.parameter_provider.as_ref()).await?
+                } else if super::reject_password(client, self.max_attempts).await? {
+                    // a salt is used once, like postgres does for each
+                    // connection, so that a hash can't be replayed
+                    let attempt = super::failed_password_attempts(client);
+                    let salt = self
+                        .cache_password(&LoginInfo::from_client_info(client), attempt)
+                        .await?;
                     client
-                        .feed(PgWireBackendMessage::ErrorResponse(error))
+                        .send(PgWireBackendMessage::Authentication(
+                            Authentication::MD5Password(salt),
+                        ))
                         .await?;
-                    client.close().await?;
                 }
             }
             _ => {}
@@ -82,6 +78,36 @@ impl<A: AuthSource, P: ServerParameterProvider> StartupHandler
     }
 }
 
+impl<A: AuthSource, P> Md5PasswordAuthStartupHandler<A, P> {
+    /// Get the hashed password for password `attempt` from the `AuthSource`
+    /// and cache it, returning its salt. The `AuthSource` is asked on each
+    /// attempt, for a new salt.
+    async fn cache_password(
+        &self,
+        login_info: &LoginInfo<'_>,
+        attempt: usize,
+    ) -> PgWireResult<Vec<u8>> {
+        let (salt, password) = match self.auth_source.get_password(login_info).await {
+            Err(PgWireError::UnknownUser(_)) if self.mock_auth.is_some() => {
+                let user = login_info.user().map_or("", |user| user.as_str());
+                let mock_auth = self.mock_auth.as_ref().unwrap();
+                (mock_auth.salt_of_attempt(user, attempt, 4), None)
+            }
+            salt_and_pass => {
+                let salt_and_pass = salt_and_pass?;
+                let salt = salt_and_pass
+                    .salt()
+                    .clone()
+                    .expect("Salt is required for Md5Password authentication");
+                (salt, Some(salt_and_pass.password().clone()))
+            }
+        };
+
+        *self.cached_password.lock().await = password;
+        Ok(salt)
+    }
+}
+
 /// This function is to compute postgres standard md5 hashed password
 ///
 /// concat('md5', md5(concat(md5(concat(password, username)), random-salt)))
@@ -102,6 +128,32 @@ pub fn hash_md5_password(username: &str, password: &str, salt: &[u8]) -> String
 pub struct MakeMd5PasswordAuthStartupHandler<A, P> {
     auth_source: Arc<A>,
     parameter_provider: Arc<P>,
+    #[new(value = "1")]
+    max_attempts: usize,
+    #[new(default)]
+    mock_auth: Option<MockAuthentication>,
+}
+
+impl<A, P> MakeMd5PasswordAuthStartupHandler<A, P> {
+    /// Allow `max_attempts` passwords on a connection, 1 by default like
+    /// postgres. See `CleartextPasswordAuthStartupHandler::set_max_attempts`.
+    ///
+    /// Each attempt gets the password and a new salt from the `AuthSource`,
+    /// so it should generate a random salt on each call.
+    pub fn set_max_attempts(&mut self, max_attempts: usize) {
+        self.max_attempts = max_attempts;
+    }
+
+    /// Enable or disable mock authentication of unknown users, disabled by
+    /// default.
+    ///
+    /// When enabled, users for which the `AuthSource` returns
+    /// `PgWireError::UnknownUser` are asked for a password with a fake salt,
+    /// and rejected with `28P01` like for a wrong password. Otherwise the
+    /// error is returned right away, which reveals they don't exist.
+    pub fn set_mock_authentication(&mut self, enabled: bool) {
+        MockAuthentication::toggle(&mut self.mock_auth, enabled);
+    }
 }
 
 impl<V, P> MakeHandler for MakeMd5PasswordAuthStartupHandler<V, P> {
@@ -111,13 +163,40 @@ impl<V, P> MakeHandler for MakeMd5PasswordAuthStartupHandler<V, P> {
         Arc::new(Md5PasswordAuthStartupHandler {
             auth_source: self.auth_source.clone(),
             parameter_provider: self.parameter_provider.clone(),
-            cached_password: Mutex::new(vec![]),
+            cached_password: Mutex::new(None),
+            max_attempts: self.max_attempts,
+            mock_auth: self.mock_auth.clone(),
         })
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use bytes::BytesMut;
+
+    use super::*;
+    use crate::api::auth::{DefaultServerParameterProvider, Password};
+    use crate::api::mock::MockClient;
+    use crate::messages::startup::{PasswordMessageFamily, Startup};
+
+    /// Knows only user `alice`, with a new salt on each call
+    struct AliceAuthSource;
+
+    #[async_trait]
+    impl AuthSource for AliceAuthSource {
+        async fn get_password(&self, login: &LoginInfo) -> PgWireResult<Password> {
+            match login.user().map(String::as_str) {
+                Some("alice") => {
+                    let salt = rand::random::<[u8; 4]>().to_vec();
+                    let password = hash_md5_password("alice", "secret", &salt);
+                    Ok(Password::new(Some(salt), password.into_bytes()))
+                }
+                user => Err(PgWireError::UnknownUser(
+                    user.unwrap_or_default().to_owned(),
+                )),
+            }
+        }
+    }
 
     #[test]
     fn test_hash_md5_passwd() {
@@ -127,6 +206,162 @@ mod tests {
 
         let result = "md521fe459d77d3e3ea9c9fcd5c11030d30";
 
-        assert_eq!(result, super::hash_md5_password(username, password, &salt));
+        assert_eq!(result, hash_md5_password(username, password, &salt));
+    }
+
+    #[tokio::test]
+    async fn test_md5_mock_authentication() {
+        let mut make_handler = MakeMd5PasswordAuthStartupHandler::new(
+            Arc::new(AliceAuthSource),
+            Arc::new(DefaultServerParameterProvider),
+        );
+        let startup = |user: &str| {
+            let mut startup = Startup::new();
+            startup
+                .parameters_mut()
+                .insert("user".to_owned(), user.to_owned());
+            PgWireFrontendMessage::Startup(startup)
+        };
+        let salt = |messages: Vec<PgWireBackendMessage>| match messages.as_slice() {
+            [PgWireBackendMessage::Authentication(Authentication::MD5Password(salt))] => {
+                salt.clone()
+            }
+            messages => panic!("unexpected messages {messages:?}"),
+        };
+
+        // disabled by default
+        let handler = make_handler.make();
+        let mut client = MockClient::new();
+        assert!(matches!(
+            handler.on_startup(&mut client, startup("nobody")).await,
+            Err(PgWireError::UnknownUser(_))
+        ));
+
+        // unknown users get the same salt on each connection, and are
+        // rejected like a wrong password
+        make_handler.set_mock_authentication(true);
+        let mut salts = vec![];
+        for user in ["nobody", "nobody", "alice"] {
+            let handler = make_handler.make();
+            let mut client = MockClient::new();
+            handler
+                .on_startup(&mut client, startup(user))
+                .await
+                .unwrap();
+            let salt = salt(client.take_messages());
+            let password = hash_md5_password(user, "secret", &salt);
+            let mut message = BytesMut::from(password.as_bytes());
+            message.extend_from_slice(b"\0");
+            handler
+                .on_startup(
+                    &mut client,
+                    PgWireFrontendMessage::PasswordMessageFamily(PasswordMessageFamily::Raw(
+                        message,
+                    )),
+                )
+                .await
+                .unwrap();
+            match (user, client.take_messages().as_slice()) {
+                ("nobody", [PgWireBackendMessage::ErrorResponse(error)]) => {
+                    assert!(error.fields().iter().any(|f| f.1 == "28P01"))
+                }
+                ("alice", [PgWireBackendMessage::Authentication(Authentication::Ok), ..]) => {}
+                (_, messages) => panic!("unexpected messages {messages:?}"),
+            }
+            salts.push(salt);
+        }
+        assert_eq!(salts[0], salts[1]);
+        assert_eq!(4, salts[0].len());
+    }
+
+    fn password_message(user: &str, password: &str, salt: &[u8]) -> PgWireFrontendMessage {
+        let mut message = BytesMut::from(hash_md5_password(user, password, salt).as_bytes());
+        message.extend_from_slice(b"\0");
+        PgWireFrontendMessage::PasswordMessageFamily(PasswordMessageFamily::Raw(message))
+    }
+
+    #[tokio::test]
+    async fn test_md5_password_retry() {
+        let mut make_handler = MakeMd5PasswordAuthStartupHandler::new(
+            Arc::new(AliceAuthSource),
+            Arc::new(DefaultServerParameterProvider),
+        );
+        make_handler.set_max_attempts(2);
+        make_handler.set_mock_authentication(true);
+
+        for user in ["alice", "nobody"] {
+            let handler = make_handler.make();
+            let mut client = MockClient::new();
+            let mut startup = Startup::new();
+            startup
+                .parameters_mut()
+                .insert("user".to_owned(), user.to_owned());
+            handler
+                .on_startup(&mut client, PgWireFrontendMessage::Startup(startup))
+                .await
+                .unwrap();
+            let first_salt = match client.take_messages().as_slice() {
+                [PgWireBackendMessage::Authentication(Authentication::MD5Password(salt))] => {
+                    salt.clone()
+                }
+                messages => panic!("unexpected messages {messages:?}"),
+            };
+
+            // the retry asks with a new salt
+            handler
+                .on_startup(&mut client, password_message(user, "wrong", &first_salt))
+                .await
+                .unwrap();
+            let salt = match client.take_messages().as_slice() {
+                [PgWireBackendMessage::NoticeResponse(_), PgWireBackendMessage::Authentication(Authentication::MD5Password(salt))] => {
+                    salt.clone()
+                }
+                messages => panic!("unexpected messages {messages:?}"),
+            };
+            assert_ne!(first_salt, salt, "{user}");
+
+            handler
+                .on_startup(&mut client, password_message(user, "secret", &salt))
+                .await
+                .unwrap();
+            match (user, client.take_messages().as_slice()) {
+                ("alice", [PgWireBackendMessage::Authentication(Authentication::Ok), ..]) => {}
+                ("nobody", [PgWireBackendMessage::ErrorResponse(error)]) => {
+                    assert!(error.fields().iter().any(|f| f.1 == "28P01"))
+                }
+                (_, messages) => panic!("unexpected messages {messages:?}"),
+            }
+        }
+
+        // a hash of the previous salt is rejected
+        let handler = make_handler.make();
+        let mut client = MockClient::new();
+        let mut startup = Startup::new();
+        startup
+            .parameters_mut()
+            .insert("user".to_owned(), "alice".to_owned());
+        handler
+            .on_startup(&mut client, PgWireFrontendMessage::Startup(startup))
+            .await
+            .unwrap();
+        let first_salt = match client.take_messages().as_slice() {
+            [PgWireBackendMessage::Authentication(Authentication::MD5Password(salt))] => {
+                salt.clone()
+            }
+            messages => panic!("unexpected messages {messages:?}"),
+        };
+        for password in ["wrong", "secret"] {
+            handler
+                .on_startup(
+                    &mut client,
+                    password_message("alice", password, &first_salt),
+                )
+                .await
+                .unwrap();
+        }
+        assert!(matches!(
+            client.take_messages().last(),
+            Some(PgWireBackendMessage::ErrorResponse(_))
+        ));
     }
 }
@@ -1,16 +1,19 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
 
 use async_trait::async_trait;
 use futures::sink::{Sink, SinkExt};
 use futures::stream;
 use rand;
+use ring::digest;
 
-use super::{ClientInfo, PgWireConnectionState, METADATA_DATABASE, METADATA_USER};
-use crate::error::{PgWireError, PgWireResult};
-use crate::messages::response::{ReadyForQuery, READY_STATUS_IDLE};
+use super::params::ReportedParameters;
+use super::{BackendKey, ClientInfo, PgWireConnectionState, METADATA_DATABASE, METADATA_USER};
+use crate::error::{ErrorInfo, PgWireError, PgWireResult, Severity, SqlState};
+use crate::messages::response::{ErrorResponse, ReadyForQuery, READY_STATUS_IDLE};
 use crate::messages::startup::{Authentication, BackendKeyData, ParameterStatus, Startup};
 use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
+use crate::types::IntervalStyle;
 
 /// Handles startup process and frontend messages
 #[async_trait]
@@ -27,12 +30,63 @@ pub trait StartupHandler: Send + Sync {
         PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>;
 }
 
+/// Provide parameters reported to client by `ParameterStatus` after
+/// authentication.
+///
+/// `integer_datetimes` is always reported as `on`, because binary date and
+/// time values are always encoded as int64.
+#[async_trait]
 pub trait ServerParameterProvider: Send + Sync {
     fn server_parameters<C>(&self, _client: &C) -> Option<HashMap<String, String>>
     where
         C: ClientInfo;
+
+    /// Notices sent to client after authentication, before `ReadyForQuery`,
+    /// like a login banner or a password expiry warning. Clients show them
+    /// at connect. None by default.
+    ///
+    /// Startup handlers can also send `NoticeResponse` themselves before
+    /// `AuthenticationOk`, with `send_notice`.
+    fn login_notices<C>(&self, _client: &C) -> Vec<ErrorInfo>
+    where
+        C: ClientInfo,
+    {
+        Vec::new()
+    }
+
+    /// Add to or change messages sent at the end of startup, after they are
+    /// filled with the parameters, notices and backend key of the connection.
+    /// Nothing by default.
+    fn startup_messages<C>(&self, _client: &C, _messages: &mut StartupMessages)
+    where
+        C: ClientInfo,
+    {
+    }
+
+    /// Called once when the session starts, after `AuthenticationOk`, the
+    /// parameters and notices, before `BackendKeyData` and the first
+    /// `ReadyForQuery`. Apply session defaults computed at login here, like
+    /// `search_path` of the user with `set_parameter`, or send more notices.
+    /// An error is returned by the startup handler, then sent to client and
+    /// the connection is closed. Nothing by default.
+    async fn on_session_start<C>(&self, _client: &mut C) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        Ok(())
+    }
 }
 
+const INTEGER_DATETIMES: &str = "integer_datetimes";
+pub const SERVER_VERSION: &str = "server_version";
+/// `server_version` reported by `DefaultServerParameterProvider`, a real
+/// postgres version so that clients parsing it get a supported version
+pub const DEFAULT_SERVER_VERSION: &str = "16.0";
+pub const INTERVAL_STYLE: &str = "IntervalStyle";
+pub const SERVER_VERSION_NUM: &str = "server_version_num";
+
 /// Default noop parameter provider
 pub struct DefaultServerParameterProvider;
 
@@ -41,20 +95,104 @@ impl ServerParameterProvider for DefaultServerParameterProvider {
     where
         C: ClientInfo,
     {
-        let mut params = HashMap::with_capacity(4);
-        params.insert(
-            "server_version".to_owned(),
-            env!("CARGO_PKG_VERSION").to_owned(),
-        );
+        let mut params = HashMap::with_capacity(6);
+        params.insert(SERVER_VERSION.to_owned(), DEFAULT_SERVER_VERSION.to_owned());
         params.insert("server_encoding".to_owned(), "UTF8".to_owned());
         params.insert("client_encoding".to_owned(), "UTF8".to_owned());
         params.insert("DateStyle".to_owned(), "ISO YMD".to_owned());
-        params.insert("integer_datetimes".to_owned(), "on".to_owned());
+        params.insert(INTEGER_DATETIMES.to_owned(), "on".to_owned());
+        params.insert(
+            INTERVAL_STYLE.to_owned(),
+            IntervalStyle::default().as_str().to_owned(),
+        );
 
         Some(params)
     }
 }
 
+/// Parameter provider reporting another postgres version, for clients that
+/// check it before enabling features or connecting at all.
+///
+/// `server_version` is replaced by the given version, and
+/// `server_version_num` is derived from it. Other parameters are provided by
+/// the wrapped provider.
+///
+/// Some client behaviors keyed off the reported version:
+///
+/// * libpq reports `server_version` as `PQserverVersion`, which psql uses to
+///   choose catalog queries of `\d` commands, and pg_dump to refuse servers
+///   older than its minimum supported version (9.2 for pg_dump 15).
+/// * JDBC and Npgsql parse `server_version`, and adapt type loading and some
+///   features to it, like multirange types of Npgsql on 14 and later.
+/// * Tools reading `SHOW server_version_num` or `current_setting` compare it
+///   numerically, like `version >= 140000` for pipeline mode.
+///
+/// Reporting a recent major version, like `16.0`, is usually the most
+/// compatible choice, but also makes clients send the catalog queries of that
+/// version.
+#[derive(Debug, new)]
+pub struct ServerVersionParameterProvider<P> {
+    inner: P,
+    version: String,
+}
+
+#[async_trait]
+impl<P: ServerParameterProvider> ServerParameterProvider for ServerVersionParameterProvider<P> {
+    fn server_parameters<C>(&self, client: &C) -> Option<HashMap<String, String>>
+    where
+        C: ClientInfo,
+    {
+        let mut params = self.inner.server_parameters(client).unwrap_or_default();
+        params.insert(SERVER_VERSION.to_owned(), self.version.clone());
+        params.remove(SERVER_VERSION_NUM);
+        Some(params)
+    }
+
+    fn login_notices<C>(&self, client: &C) -> Vec<ErrorInfo>
+    where
+        C: ClientInfo,
+    {
+        self.inner.login_notices(client)
+    }
+
+    fn startup_messages<C>(&self, client: &C, messages: &mut StartupMessages)
+    where
+        C: ClientInfo,
+    {
+        self.inner.startup_messages(client, messages)
+    }
+
+    async fn on_session_start<C>(&self, client: &mut C) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.inner.on_session_start(client).await
+    }
+}
+
+/// Get `server_version_num` of a version string, like `140005` for `14.5`
+/// and `90603` for `9.6.3`.
+///
+/// Version suffixes like `16beta1` or `15.2 (Debian 15.2-1)` are ignored, as
+/// libpq does. Returns `None` when the version doesn't start with a number.
+pub fn server_version_num(version: &str) -> Option<u32> {
+    let numbers = version
+        .split(|c: char| !c.is_ascii_digit() && c != '.')
+        .next()
+        .unwrap_or_default();
+    let mut parts = numbers.split('.').map(|part| part.parse::<u32>().ok());
+    let major = parts.next().flatten()?;
+    let minor = parts.next().flatten().unwrap_or(0);
+    if major >= 10 {
+        Some(major * 10000 + minor)
+    } else {
+        let patch = parts.next().flatten().unwrap_or(0);
+        Some(major * 10000 + minor * 100 + patch)
+    }
+}
+
 #[derive(Debug, new, Getters, Clone)]
 #[getset(get = "pub")]
 pub struct Password {
@@ -71,7 +209,7 @@ pub struct LoginInfo<'a> {
 }
 
 impl<'a> LoginInfo<'a> {
-    pub fn from_client_info<C>(client: &'a C) -> LoginInfo
+    pub fn from_client_info<C>(client: &'a C) -> LoginInfo<'a>
     where
         C: ClientInfo,
     {
@@ -94,10 +232,55 @@ impl<'a> LoginInfo<'a> {
 pub trait AuthSource: Send + Sync {
     /// Get password from the `AuthSource`.
     ///
-    /// `Password` has a an optional salt field when it's hashed.
+    /// `Password` has a an optional salt field when it's hashed. Return
+    /// `PgWireError::UnknownUser` for users that don't exist, so that
+    /// handlers with mock authentication can hide it from clients.
     async fn get_password(&self, login: &LoginInfo) -> PgWireResult<Password>;
 }
 
+/// Mock authentication of users unknown to the `AuthSource`. The password
+/// exchange goes on with a fake salt, and fails like for a wrong password,
+/// so that clients can't tell which users exist.
+///
+/// Salts are derived from the user name and a random secret, so that
+/// repeated attempts of a user get the same salt, like a real one.
+#[derive(Debug, Clone)]
+pub(crate) struct MockAuthentication {
+    secret: [u8; 32],
+}
+
+impl MockAuthentication {
+    pub(crate) fn new() -> MockAuthentication {
+        MockAuthentication {
+            secret: rand::random(),
+        }
+    }
+
+    /// Enable `mock_auth` with a new secret, or disable it
+    pub(crate) fn toggle(mock_auth: &mut Option<MockAuthentication>, enabled: bool) {
+        if !enabled {
+            *mock_auth = None;
+        } else if mock_auth.is_none() {
+            *mock_auth = Some(MockAuthentication::new());
+        }
+    }
+
+    /// Fake salt of `len` bytes for `user`, at most 32
+    pub(crate) fn salt(&self, user: &str, len: usize) -> Vec<u8> {
+        self.salt_of_attempt(user, 0, len)
+    }
+
+    /// Fake salt of `len` bytes for password `attempt` of `user`, for
+    /// handlers asking for a new salt on each attempt
+    pub(crate) fn salt_of_attempt(&self, user: &str, attempt: usize, len: usize) -> Vec<u8> {
+        let mut context = digest::Context::new(&digest::SHA256);
+        context.update(&self.secret);
+        context.update(user.as_bytes());
+        context.update(&(attempt as u64).to_be_bytes());
+        context.finish().as_ref()[..len].to_vec()
+    }
+}
+
 pub fn save_startup_parameters_to_metadata<C>(client: &mut C, startup_message: &Startup)
 where
     C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
@@ -111,36 +294,528 @@ where
     );
 }
 
-pub async fn finish_authentication<C, P>(client: &mut C, server_parameter_provider: &P)
+/// Send a `NoticeResponse` to client during startup, like a warning before
+/// asking for password.
+///
+/// The message is buffered, and sent with the next response of the handler.
+pub async fn send_notice<C>(client: &mut C, notice: ErrorInfo) -> PgWireResult<()>
 where
     C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
     C::Error: Debug,
-    P: ServerParameterProvider,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
 {
-    let mut messages = vec![PgWireBackendMessage::Authentication(Authentication::Ok)];
+    client
+        .feed(PgWireBackendMessage::NoticeResponse(notice.into()))
+        .await?;
+    Ok(())
+}
+
+/// Number of wrong passwords sent on a connection, kept in its extensions
+struct FailedPasswordAttempts(usize);
+
+/// Number of wrong passwords sent on the connection of `client`
+pub(crate) fn failed_password_attempts<C>(client: &C) -> usize
+where
+    C: ClientInfo,
+{
+    client
+        .extensions()
+        .get::<FailedPasswordAttempts>()
+        .map_or(0, |attempts| attempts.0)
+}
+
+/// Handle a wrong password of a password handler allowing `max_attempts`.
+///
+/// While attempts are left, a notice is sent and `true` is returned, for the
+/// handler to ask for the password again. Then the connection is closed with
+/// error `28P01`.
+pub(crate) async fn reject_password<C>(client: &mut C, max_attempts: usize) -> PgWireResult<bool>
+where
+    C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+    C::Error: Debug,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+{
+    let failed = failed_password_attempts(client) + 1;
+    client
+        .extensions_mut()
+        .insert(FailedPasswordAttempts(failed));
+
+    if failed < max_attempts {
+        let notice = ErrorInfo::new(
+            Severity::Notice,
+            SqlState::InvalidPassword,
+            format!(
+                "Password authentication failed, {} attempts left",
+                max_attempts - failed
+            ),
+        );
+        send_notice(client, notice).await?;
+        Ok(true)
+    } else {
+        let error_info = ErrorInfo::new(
+            Severity::Fatal,
+            SqlState::InvalidPassword,
+            "Password authentication failed",
+        );
+        client
+            .feed(PgWireBackendMessage::ErrorResponse(ErrorResponse::from(
+                error_info,
+            )))
+            .await?;
+        client.close().await?;
+        Ok(false)
+    }
+}
+
+/// Messages sent to client at the end of startup, after `AuthenticationOk`.
+///
+/// They are sent in the order of postgres, `ParameterStatus` of all
+/// parameters first, ordered by name ignoring case, then the notices. `BackendKeyData` and
+/// `ReadyForQuery` follow after `on_session_start`, so that parameters it sets
+/// are also reported before them. Change them with
+/// `ServerParameterProvider::startup_messages`.
+#[derive(Debug, Getters)]
+#[getset(get = "pub")]
+pub struct StartupMessages {
+    parameters: BTreeMap<String, String>,
+    notices: Vec<ErrorInfo>,
+    /// the key of `BackendKeyData`, also used for cancellation of queries
+    backend_key: BackendKey,
+}
+
+impl StartupMessages {
+    /// Set parameter `name` reported by `ParameterStatus`
+    pub fn set_parameter<N, V>(&mut self, name: N, value: V)
+    where
+        N: Into<String>,
+        V: Into<String>,
+    {
+        self.parameters.insert(name.into(), value.into());
+    }
+
+    /// Remove parameter `name`, so that it's not reported
+    pub fn remove_parameter(&mut self, name: &str) -> Option<String> {
+        self.parameters.remove(name)
+    }
+
+    /// Add a notice sent after the parameters
+    pub fn add_notice(&mut self, notice: ErrorInfo) {
+        self.notices.push(notice);
+    }
+}
 
-    if let Some(parameters) = server_parameter_provider.server_parameters(client) {
-        for (k, v) in parameters {
-            messages.push(PgWireBackendMessage::ParameterStatus(ParameterStatus::new(
-                k, v,
-            )));
+pub async fn finish_authentication<C, P>(
+    client: &mut C,
+    server_parameter_provider: &P,
+) -> PgWireResult<()>
+where
+    C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+    C::Error: Debug,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    P: ServerParameterProvider,
+{
+    let mut parameters = server_parameter_provider
+        .server_parameters(client)
+        .unwrap_or_default();
+    // binary encoding of date and time types is always int64 based, old
+    // clients check this parameter before decoding them
+    parameters.insert(INTEGER_DATETIMES.to_owned(), "on".to_owned());
+    // report both or none of version parameters, so that clients reading
+    // either see the same version
+    if !parameters.contains_key(SERVER_VERSION_NUM) {
+        if let Some(num) = parameters
+            .get(SERVER_VERSION)
+            .and_then(|version| server_version_num(version))
+        {
+            parameters.insert(SERVER_VERSION_NUM.to_owned(), num.to_string());
         }
     }
+    let backend_key = client
+        .extensions()
+        .get::<BackendKey>()
+        .copied()
+        .unwrap_or_else(|| BackendKey::new(std::process::id() as i32, rand::random::<i32>()));
+    let mut startup = StartupMessages {
+        parameters: parameters.into_iter().collect(),
+        notices: server_parameter_provider.login_notices(client),
+        backend_key,
+    };
+    server_parameter_provider.startup_messages(client, &mut startup);
 
-    // TODO: store this backend key
-    messages.push(PgWireBackendMessage::BackendKeyData(BackendKeyData::new(
-        std::process::id() as i32,
-        rand::random::<i32>(),
-    )));
-    messages.push(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(
-        READY_STATUS_IDLE,
-    )));
+    client.extensions_mut().insert(ReportedParameters::new(
+        startup.parameters.clone().into_iter().collect(),
+    ));
+    let mut messages = vec![PgWireBackendMessage::Authentication(Authentication::Ok)];
+    // postgres orders its parameters by name ignoring case
+    let mut parameters = startup.parameters.into_iter().collect::<Vec<_>>();
+    parameters.sort_by_cached_key(|(k, _)| k.to_ascii_lowercase());
+    for (k, v) in parameters {
+        messages.push(PgWireBackendMessage::ParameterStatus(ParameterStatus::new(
+            k, v,
+        )));
+    }
+    for notice in startup.notices {
+        messages.push(PgWireBackendMessage::NoticeResponse(notice.into()));
+    }
     let mut message_stream = stream::iter(messages.into_iter().map(Ok));
-    client.send_all(&mut message_stream).await.unwrap();
+    client.send_all(&mut message_stream).await?;
+
+    // an error of the hook is sent as the startup error, closing the
+    // connection
+    server_parameter_provider.on_session_start(client).await?;
+
+    client
+        .feed(PgWireBackendMessage::BackendKeyData(BackendKeyData::new(
+            *startup.backend_key.pid(),
+            *startup.backend_key.secret_key(),
+        )))
+        .await?;
+    client
+        .send(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(
+            READY_STATUS_IDLE,
+        )))
+        .await?;
     client.set_state(PgWireConnectionState::ReadyForQuery);
+    Ok(())
 }
 
 pub mod cleartext;
 pub mod md5pass;
 pub mod noop;
+pub mod oauth;
+pub mod sasl;
 pub mod scram;
+pub mod selector;
+pub mod sspi;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::mock::MockClient;
+    use crate::api::params::set_parameter;
+    use crate::error::{Severity, SqlState};
+
+    struct LegacyParameterProvider;
+
+    impl ServerParameterProvider for LegacyParameterProvider {
+        fn server_parameters<C>(&self, _client: &C) -> Option<HashMap<String, String>>
+        where
+            C: ClientInfo,
+        {
+            let mut params = HashMap::new();
+            params.insert(INTEGER_DATETIMES.to_owned(), "off".to_owned());
+            Some(params)
+        }
+    }
+
+    fn reported_parameters(client: &mut MockClient) -> HashMap<String, String> {
+        client
+            .take_messages()
+            .into_iter()
+            .filter_map(|msg| match msg {
+                PgWireBackendMessage::ParameterStatus(status) => {
+                    Some((status.name().clone(), status.value().clone()))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_integer_datetimes_reported() {
+        let mut client = MockClient::new();
+        finish_authentication(&mut client, &DefaultServerParameterProvider)
+            .await
+            .unwrap();
+        let params = reported_parameters(&mut client);
+        assert_eq!(
+            Some("on"),
+            params.get(INTEGER_DATETIMES).map(String::as_str)
+        );
+
+        let mut client = MockClient::new();
+        finish_authentication(&mut client, &LegacyParameterProvider)
+            .await
+            .unwrap();
+        let params = reported_parameters(&mut client);
+        assert_eq!(
+            Some("on"),
+            params.get(INTEGER_DATETIMES).map(String::as_str)
+        );
+    }
+
+    struct BannerParameterProvider;
+
+    impl ServerParameterProvider for BannerParameterProvider {
+        fn server_parameters<C>(&self, _client: &C) -> Option<HashMap<String, String>>
+        where
+            C: ClientInfo,
+        {
+            None
+        }
+
+        fn login_notices<C>(&self, client: &C) -> Vec<ErrorInfo>
+        where
+            C: ClientInfo,
+        {
+            vec![ErrorInfo::new(
+                Severity::Warning,
+                SqlState::Warning,
+                format!(
+                    "password of user {:?} expires in 3 days",
+                    client
+                        .metadata()
+                        .get(METADATA_USER)
+                        .cloned()
+                        .unwrap_or_default()
+                ),
+            )]
+        }
+    }
+
+    #[tokio::test]
+    async fn test_login_notices() {
+        let mut client = MockClient::new();
+        client
+            .metadata_mut()
+            .insert(METADATA_USER.to_owned(), "alice".to_owned());
+        send_notice(
+            &mut client,
+            ErrorInfo::new(
+                Severity::Notice,
+                SqlState::SuccessfulCompletion,
+                "authorized use only",
+            ),
+        )
+        .await
+        .unwrap();
+        finish_authentication(&mut client, &BannerParameterProvider)
+            .await
+            .unwrap();
+
+        let messages = client.take_messages();
+        let notices: Vec<String> = messages
+            .iter()
+            .filter_map(|msg| match msg {
+                PgWireBackendMessage::NoticeResponse(notice) => notice
+                    .fields()
+                    .iter()
+                    .find(|f| f.0 == b'M')
+                    .map(|f| f.1.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                "authorized use only",
+                "password of user \"alice\" expires in 3 days"
+            ],
+            notices
+        );
+        assert!(matches!(
+            messages.first(),
+            Some(PgWireBackendMessage::NoticeResponse(_))
+        ));
+        assert!(matches!(
+            &messages[1..3],
+            [
+                PgWireBackendMessage::Authentication(Authentication::Ok),
+                PgWireBackendMessage::ParameterStatus(_)
+            ]
+        ));
+        assert!(matches!(
+            messages.last(),
+            Some(PgWireBackendMessage::ReadyForQuery(_))
+        ));
+    }
+
+    struct CustomStartupProvider;
+
+    impl ServerParameterProvider for CustomStartupProvider {
+        fn server_parameters<C>(&self, client: &C) -> Option<HashMap<String, String>>
+        where
+            C: ClientInfo,
+        {
+            DefaultServerParameterProvider.server_parameters(client)
+        }
+
+        fn startup_messages<C>(&self, _client: &C, messages: &mut StartupMessages)
+        where
+            C: ClientInfo,
+        {
+            messages.set_parameter("application_name", "psql");
+            messages.remove_parameter("DateStyle");
+            messages.add_notice(ErrorInfo::new(
+                Severity::Notice,
+                SqlState::SuccessfulCompletion,
+                "welcome",
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_startup_messages() {
+        let mut client = MockClient::new();
+        client.extensions_mut().insert(BackendKey::new(7, 42));
+        finish_authentication(&mut client, &CustomStartupProvider)
+            .await
+            .unwrap();
+
+        let messages = client
+            .take_messages()
+            .into_iter()
+            .map(|msg| match msg {
+                PgWireBackendMessage::Authentication(Authentication::Ok) => "auth".to_owned(),
+                PgWireBackendMessage::ParameterStatus(status) => status.name().clone(),
+                PgWireBackendMessage::NoticeResponse(_) => "notice".to_owned(),
+                PgWireBackendMessage::BackendKeyData(key) => {
+                    format!("key {} {}", key.pid(), key.secret_key())
+                }
+                PgWireBackendMessage::ReadyForQuery(_) => "ready".to_owned(),
+                msg => panic!("unexpected message {msg:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                "auth",
+                "application_name",
+                "client_encoding",
+                "integer_datetimes",
+                "IntervalStyle",
+                "server_encoding",
+                "server_version",
+                "server_version_num",
+                "notice",
+                "key 7 42",
+                "ready"
+            ],
+            messages
+        );
+        let reported = client.extensions().get::<ReportedParameters>().unwrap();
+        assert_eq!(Some("psql"), reported.get("application_name"));
+        assert_eq!(None, reported.get("DateStyle"));
+    }
+
+    struct SessionDefaultsProvider;
+
+    #[async_trait]
+    impl ServerParameterProvider for SessionDefaultsProvider {
+        fn server_parameters<C>(&self, _client: &C) -> Option<HashMap<String, String>>
+        where
+            C: ClientInfo,
+        {
+            let mut params = HashMap::new();
+            params.insert("search_path".to_owned(), "public".to_owned());
+            Some(params)
+        }
+
+        async fn on_session_start<C>(&self, client: &mut C) -> PgWireResult<()>
+        where
+            C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            match client.metadata().get(METADATA_USER).map(String::as_str) {
+                Some("alice") => set_parameter(client, "search_path", "alice, public").await,
+                _ => Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                    Severity::Fatal,
+                    SqlState::InvalidAuthorizationSpecification,
+                    "no session defaults",
+                )))),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_on_session_start() {
+        let mut client = MockClient::new();
+        client
+            .metadata_mut()
+            .insert(METADATA_USER.to_owned(), "alice".to_owned());
+        finish_authentication(&mut client, &SessionDefaultsProvider)
+            .await
+            .unwrap();
+        let messages = client.take_messages();
+        assert!(matches!(
+            &messages[messages.len() - 3..],
+            [
+                PgWireBackendMessage::ParameterStatus(status),
+                PgWireBackendMessage::BackendKeyData(_),
+                PgWireBackendMessage::ReadyForQuery(_)
+            ] if status.value() == "alice, public"
+        ));
+        assert_eq!(
+            Some("alice, public"),
+            client
+                .extensions()
+                .get::<ReportedParameters>()
+                .and_then(|params| params.get("search_path"))
+        );
+        assert!(matches!(
+            client.state(),
+            PgWireConnectionState::ReadyForQuery
+        ));
+
+        // the error is returned to end the connection, before BackendKeyData
+        let mut client = MockClient::new();
+        let result = finish_authentication(&mut client, &SessionDefaultsProvider).await;
+        assert!(matches!(result, Err(PgWireError::UserError(_))));
+        assert!(!client.take_messages().iter().any(|message| matches!(
+            message,
+            PgWireBackendMessage::BackendKeyData(_) | PgWireBackendMessage::ErrorResponse(_)
+        )));
+        assert!(!matches!(
+            client.state(),
+            PgWireConnectionState::ReadyForQuery
+        ));
+    }
+
+    #[test]
+    fn test_server_version_num() {
+        for (version, num) in [
+            ("16.1", Some(160001)),
+            ("14", Some(140000)),
+            ("9.6.3", Some(90603)),
+            ("9.2", Some(90200)),
+            ("16beta1", Some(160000)),
+            ("15.2 (Debian 15.2-1.pgdg110+1)", Some(150002)),
+            ("16.0", Some(160000)),
+            ("devel", None),
+            ("", None),
+        ] {
+            assert_eq!(num, server_version_num(version), "{version}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_version_reported() {
+        let mut client = MockClient::new();
+        finish_authentication(&mut client, &DefaultServerParameterProvider)
+            .await
+            .unwrap();
+        let params = reported_parameters(&mut client);
+        assert_eq!(Some("16.0"), params.get(SERVER_VERSION).map(String::as_str));
+        assert_eq!(
+            Some("160000"),
+            params.get(SERVER_VERSION_NUM).map(String::as_str)
+        );
+
+        let mut client = MockClient::new();
+        finish_authentication(
+            &mut client,
+            &ServerVersionParameterProvider::new(DefaultServerParameterProvider, "14.5".to_owned()),
+        )
+        .await
+        .unwrap();
+        let params = reported_parameters(&mut client);
+        assert_eq!(Some("14.5"), params.get(SERVER_VERSION).map(String::as_str));
+        assert_eq!(
+            Some("140005"),
+            params.get(SERVER_VERSION_NUM).map(String::as_str)
+        );
+        assert_eq!(
+            Some("UTF8"),
+            params.get("server_encoding").map(String::as_str)
+        );
+    }
+}
@@ -3,10 +3,18 @@ use std::fmt::Debug;
 use async_trait::async_trait;
 use futures::sink::Sink;
 
-use super::{ClientInfo, DefaultServerParameterProvider, StartupHandler};
+use super::{ClientInfo, DefaultServerParameterProvider, StartupHandler, METADATA_USER};
 use crate::error::{PgWireError, PgWireResult};
 use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
 
+/// A startup handler that performs no authentication, the `trust` method in
+/// postgres.
+///
+/// The handler accepts every connection that provides a `user` in its startup
+/// packet, and responds with `AuthenticationOk` immediately, followed by the
+/// parameter status, backend key data and `ReadyForQuery` sequence. Startup
+/// parameters are saved into client metadata as is, so the role name can be
+/// read from `METADATA_USER` unchanged.
 pub struct NoopStartupHandler;
 
 #[async_trait]
@@ -23,7 +31,10 @@ impl StartupHandler for NoopStartupHandler {
     {
         if let PgWireFrontendMessage::Startup(ref startup) = message {
             super::save_startup_parameters_to_metadata(client, startup);
-            super::finish_authentication(client, &DefaultServerParameterProvider).await;
+            if !client.metadata().contains_key(METADATA_USER) {
+                return Err(PgWireError::UserNameRequired);
+            }
+            super::finish_authentication(client, &DefaultServerParameterProvider).await?;
         }
         Ok(())
     }
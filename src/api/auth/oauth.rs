@@ -0,0 +1,278 @@
+//! OAUTHBEARER authentication, defined in
+//! [RFC7628](https://www.rfc-editor.org/rfc/rfc7628), for clients presenting
+//! tokens of an identity provider, like cloud IAM.
+//!
+//! This mechanism only extracts the bearer token from the client message, the
+//! token is validated by an `OAuthTokenValidator` supplied by the application.
+//! When a token is missing or rejected, the server replies with an error
+//! status pointing to the configured issuer, so that clients can fetch a new
+//! token and reconnect.
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+
+use super::sasl::{SaslExchange, SaslMechanism, SaslStep};
+use super::LoginInfo;
+use crate::error::{ErrorInfo, PgWireError, PgWireResult, Severity, SqlState};
+
+pub const OAUTHBEARER: &str = "OAUTHBEARER";
+
+/// Validate bearer tokens presented by clients
+#[async_trait]
+pub trait OAuthTokenValidator: Send + Sync {
+    /// Validate `token` of client `login`, typically by verifying its
+    /// signature and checking that it is issued for the user.
+    ///
+    /// Return `false` to reject the token.
+    async fn validate(&self, login: &LoginInfo, token: &str) -> PgWireResult<bool>;
+}
+
+/// The `OAUTHBEARER` mechanism
+#[derive(Debug, new)]
+pub struct OAuthBearerMechanism<V> {
+    validator: Arc<V>,
+    /// url of openid discovery document, reported to clients without a valid
+    /// token
+    #[new(default)]
+    openid_configuration: Option<String>,
+    /// scope required for tokens, reported to clients without a valid token
+    #[new(default)]
+    scope: Option<String>,
+}
+
+impl<V> OAuthBearerMechanism<V> {
+    /// Set url of the openid discovery document of the issuer, like
+    /// `https://example.com/.well-known/openid-configuration`
+    pub fn set_openid_configuration(&mut self, url: String) {
+        self.openid_configuration = Some(url);
+    }
+
+    /// Set scope that client should request for tokens
+    pub fn set_scope(&mut self, scope: String) {
+        self.scope = Some(scope);
+    }
+
+    /// Error status sent to client, as defined in
+    /// [RFC7628](https://www.rfc-editor.org/rfc/rfc7628#section-3.2.2)
+    fn error_status(&self) -> String {
+        let mut status = String::from("{\"status\":\"invalid_token\"");
+        if let Some(ref scope) = self.scope {
+            status.push_str(&format!(",\"scope\":{}", json_string(scope)));
+        }
+        if let Some(ref url) = self.openid_configuration {
+            status.push_str(&format!(",\"openid-configuration\":{}", json_string(url)));
+        }
+        status.push('}');
+        status
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[async_trait]
+impl<V: OAuthTokenValidator + 'static> SaslMechanism for OAuthBearerMechanism<V> {
+    fn name(&self) -> &str {
+        OAUTHBEARER
+    }
+
+    async fn init(&self, login: &LoginInfo) -> PgWireResult<Box<dyn SaslExchange>> {
+        Ok(Box::new(OAuthBearerExchange {
+            validator: self.validator.clone(),
+            user: login.user().cloned(),
+            database: login.database().cloned(),
+            host: login.host().clone(),
+            error_status: self.error_status(),
+            error_sent: false,
+        }))
+    }
+}
+
+struct OAuthBearerExchange<V> {
+    validator: Arc<V>,
+    user: Option<String>,
+    database: Option<String>,
+    host: String,
+    error_status: String,
+    /// the token is rejected and error status is sent to client
+    error_sent: bool,
+}
+
+impl<V> OAuthBearerExchange<V> {
+    fn rejected(&self) -> PgWireError {
+        PgWireError::UserError(Box::new(ErrorInfo::new(
+            Severity::Fatal,
+            SqlState::InvalidAuthorizationSpecification,
+            format!(
+                "OAuth bearer authentication failed for user {:?}",
+                self.user.as_deref().unwrap_or_default()
+            ),
+        )))
+    }
+}
+
+#[async_trait]
+impl<V: OAuthTokenValidator> SaslExchange for OAuthBearerExchange<V> {
+    async fn step(&mut self, input: &[u8]) -> PgWireResult<SaslStep> {
+        if self.error_sent {
+            // client acknowledges the error status with a single kvsep
+            return Err(self.rejected());
+        }
+
+        let token = parse_client_response(input)?;
+        let valid = if token.is_empty() {
+            // discovery request of clients without a token
+            false
+        } else {
+            let login = LoginInfo::new(
+                self.user.as_ref(),
+                self.database.as_ref(),
+                self.host.clone(),
+            );
+            self.validator.validate(&login, token).await?
+        };
+        if valid {
+            Ok(SaslStep::Success(None))
+        } else {
+            self.error_sent = true;
+            Ok(SaslStep::Continue(Bytes::from(self.error_status.clone())))
+        }
+    }
+}
+
+/// Get bearer token from client initial response
+///
+/// ```text
+/// gs2-header kvsep *kvpair kvsep
+/// ```
+///
+/// where `kvsep` is `\x01`, and the token is in kvpair `auth=Bearer <token>`.
+/// An empty token is returned for `auth=`.
+fn parse_client_response(input: &[u8]) -> PgWireResult<&str> {
+    let invalid = || PgWireError::InvalidSASLMessage("malformed OAUTHBEARER message".to_owned());
+    let input = std::str::from_utf8(input).map_err(|_| invalid())?;
+
+    // channel binding is not supported by OAUTHBEARER
+    let (cbind_flag, rest) = input.split_once(',').ok_or_else(invalid)?;
+    if cbind_flag != "n" && cbind_flag != "y" {
+        return Err(invalid());
+    }
+    let (_authzid, rest) = rest.split_once(',').ok_or_else(invalid)?;
+    let pairs = rest
+        .strip_prefix('\x01')
+        .and_then(|r| r.strip_suffix("\x01\x01"))
+        .ok_or_else(invalid)?;
+
+    let auth = pairs
+        .split('\x01')
+        .find_map(|kv| kv.strip_prefix("auth="))
+        .ok_or_else(invalid)?;
+    if auth.is_empty() {
+        return Ok("");
+    }
+    let (scheme, token) = auth.split_once(' ').ok_or_else(invalid)?;
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return Err(invalid());
+    }
+    Ok(token.trim())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct StaticTokenValidator;
+
+    #[async_trait]
+    impl OAuthTokenValidator for StaticTokenValidator {
+        async fn validate(&self, login: &LoginInfo, token: &str) -> PgWireResult<bool> {
+            Ok(login.user().map(String::as_str) == Some("alice") && token == "t0ken")
+        }
+    }
+
+    fn mechanism() -> OAuthBearerMechanism<StaticTokenValidator> {
+        let mut mechanism = OAuthBearerMechanism::new(Arc::new(StaticTokenValidator));
+        mechanism.set_openid_configuration(
+            "https://example.com/.well-known/openid-configuration".to_owned(),
+        );
+        mechanism.set_scope("openid \"db\"".to_owned());
+        mechanism
+    }
+
+    async fn exchange(user: &str) -> Box<dyn SaslExchange> {
+        let user = user.to_owned();
+        let login = LoginInfo::new(Some(&user), None, "127.0.0.1".to_owned());
+        mechanism().init(&login).await.unwrap()
+    }
+
+    #[test]
+    fn test_parse_client_response() {
+        assert_eq!(
+            "t0ken",
+            parse_client_response(b"n,,\x01auth=Bearer t0ken\x01\x01").unwrap()
+        );
+        assert_eq!(
+            "t0ken",
+            parse_client_response(b"y,a=alice,\x01host=db\x01auth=bearer t0ken\x01\x01").unwrap()
+        );
+        assert_eq!("", parse_client_response(b"n,,\x01auth=\x01\x01").unwrap());
+
+        for input in [
+            &b""[..],
+            b"p=tls-server-end-point,,\x01auth=Bearer t0ken\x01\x01",
+            b"n,,\x01auth=Basic t0ken\x01\x01",
+            b"n,,\x01host=db\x01\x01",
+            b"n,,auth=Bearer t0ken",
+        ] {
+            assert!(parse_client_response(input).is_err(), "{input:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_oauth_bearer() {
+        let mut exchange = exchange("alice").await;
+        assert!(matches!(
+            exchange
+                .step(b"n,,\x01auth=Bearer t0ken\x01\x01")
+                .await
+                .unwrap(),
+            SaslStep::Success(None)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_oauth_bearer_rejected() {
+        for (user, input) in [
+            ("alice", &b"n,,\x01auth=\x01\x01"[..]),
+            ("alice", b"n,,\x01auth=Bearer expired\x01\x01"),
+            ("bob", b"n,,\x01auth=Bearer t0ken\x01\x01"),
+        ] {
+            let mut exchange = exchange(user).await;
+            match exchange.step(input).await.unwrap() {
+                SaslStep::Continue(status) => assert_eq!(
+                    &br#"{"status":"invalid_token","scope":"openid \"db\"","openid-configuration":"https://example.com/.well-known/openid-configuration"}"#[..],
+                    status.as_ref()
+                ),
+                step => panic!("unexpected step {step:?}"),
+            }
+            assert!(matches!(
+                exchange.step(b"\x01").await,
+                Err(PgWireError::UserError(info)) if info.code() == "28000"
+            ));
+        }
+    }
+}
@@ -0,0 +1,420 @@
+//! SASL authentication with pluggable mechanisms.
+//!
+//! The server advertises names of all registered `SaslMechanism`s in
+//! `AuthenticationSASL`. The client picks one of them in its
+//! `SASLInitialResponse`, and the exchange created by that mechanism processes
+//! client messages until it succeeds or fails. `SCRAM-SHA-256` is provided in
+//! the `scram` module and `OAUTHBEARER` in the `oauth` module.
+
+use std::fmt::{self, Debug};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{Sink, SinkExt};
+use tokio::sync::Mutex;
+
+use super::{LoginInfo, ServerParameterProvider, StartupHandler, METADATA_USER};
+use crate::api::{ClientInfo, MakeHandler, PgWireConnectionState};
+use crate::error::{ErrorInfo, PgWireError, PgWireResult, Severity, SqlState};
+use crate::messages::startup::Authentication;
+use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
+
+/// Result of processing a message from client
+#[derive(Debug)]
+pub enum SaslStep {
+    /// Send this challenge to client with `AuthenticationSASLContinue`, and
+    /// wait for the next response
+    Continue(Bytes),
+    /// The client is authenticated. Optional additional data is sent to client
+    /// with `AuthenticationSASLFinal`.
+    Success(Option<Bytes>),
+}
+
+/// A SASL mechanism, like `SCRAM-SHA-256`
+#[async_trait]
+pub trait SaslMechanism: Send + Sync {
+    /// Name of the mechanism advertised to client
+    fn name(&self) -> &str;
+
+    /// Whether this mechanism is only advertised on TLS connections, like
+    /// `SCRAM-SHA-256-PLUS` which binds to the server certificate.
+    fn requires_secure_connection(&self) -> bool {
+        false
+    }
+
+    /// Start an exchange for the client selecting this mechanism
+    async fn init(&self, login: &LoginInfo) -> PgWireResult<Box<dyn SaslExchange>>;
+}
+
+/// State of an exchange between server and a client
+#[async_trait]
+pub trait SaslExchange: Send {
+    /// Process a message from client, starting from the data of its
+    /// `SASLInitialResponse`, which is empty when the client sends no initial
+    /// response.
+    ///
+    /// Return an error to reject the client. `UserError` is sent to client as
+    /// is, other errors are reported as `28000`.
+    async fn step(&mut self, input: &[u8]) -> PgWireResult<SaslStep>;
+}
+
+/// Registered mechanisms, in the order they are advertised
+#[derive(Default, Clone)]
+pub struct SaslMechanismRegistry {
+    mechanisms: Vec<Arc<dyn SaslMechanism>>,
+}
+
+impl Debug for SaslMechanismRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SaslMechanismRegistry")
+            .field(
+                "mechanisms",
+                &self.mechanisms.iter().map(|m| m.name()).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl SaslMechanismRegistry {
+    pub fn new() -> SaslMechanismRegistry {
+        SaslMechanismRegistry::default()
+    }
+
+    /// Register `mechanism`, replacing the registered one of the same name
+    pub fn register<M: SaslMechanism + 'static>(&mut self, mechanism: M) {
+        let mechanism = Arc::new(mechanism);
+        if let Some(m) = self
+            .mechanisms
+            .iter_mut()
+            .find(|m| m.name() == mechanism.name())
+        {
+            *m = mechanism;
+        } else {
+            self.mechanisms.push(mechanism);
+        }
+    }
+
+    /// Get the mechanism of `name`
+    pub fn get(&self, name: &str) -> Option<&Arc<dyn SaslMechanism>> {
+        self.mechanisms.iter().find(|m| m.name() == name)
+    }
+
+    /// Names of mechanisms available on a connection
+    pub fn names(&self, secure: bool) -> Vec<String> {
+        self.mechanisms
+            .iter()
+            .filter(|m| secure || !m.requires_secure_connection())
+            .map(|m| m.name().to_owned())
+            .collect()
+    }
+}
+
+/// Startup handler for SASL authentication, create one for each connection
+/// with `MakeSASLAuthStartupHandler`
+pub struct SASLAuthStartupHandler<P> {
+    mechanisms: Arc<SaslMechanismRegistry>,
+    parameter_provider: Arc<P>,
+    exchange: Mutex<Option<Box<dyn SaslExchange>>>,
+}
+
+impl<P> SASLAuthStartupHandler<P> {
+    async fn start_exchange(
+        &self,
+        login: &LoginInfo<'_>,
+        secure: bool,
+        mechanism: &str,
+    ) -> PgWireResult<Box<dyn SaslExchange>> {
+        let mechanism = self
+            .mechanisms
+            .get(mechanism)
+            .filter(|m| secure || !m.requires_secure_connection())
+            .ok_or_else(|| {
+                PgWireError::UserError(Box::new(ErrorInfo::new(
+                    Severity::Fatal,
+                    SqlState::ProtocolViolation,
+                    format!(
+                        "client selected an invalid SASL authentication mechanism {mechanism:?}"
+                    ),
+                )))
+            })?;
+        mechanism.init(login).await
+    }
+}
+
+#[async_trait]
+impl<P: ServerParameterProvider> StartupHandler for SASLAuthStartupHandler<P> {
+    async fn on_startup<C>(
+        &self,
+        client: &mut C,
+        message: PgWireFrontendMessage,
+    ) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        match message {
+            PgWireFrontendMessage::Startup(ref startup) => {
+                super::save_startup_parameters_to_metadata(client, startup);
+                client.set_state(PgWireConnectionState::AuthenticationInProgress);
+                client
+                    .send(PgWireBackendMessage::Authentication(Authentication::SASL(
+                        self.mechanisms.names(client.is_secure()),
+                    )))
+                    .await?;
+            }
+            PgWireFrontendMessage::PasswordMessageFamily(msg) => {
+                let step = {
+                    let mut exchange = self.exchange.lock().await;
+                    if let Some(exchange) = exchange.as_mut() {
+                        let resp = msg.into_sasl_response()?;
+                        exchange.step(resp.data().as_ref()).await
+                    } else {
+                        let resp = msg.into_sasl_initial_response()?;
+                        let login_info = LoginInfo::from_client_info(client);
+                        match self
+                            .start_exchange(&login_info, client.is_secure(), resp.auth_method())
+                            .await
+                        {
+                            Ok(new_exchange) => {
+                                let new_exchange = exchange.insert(new_exchange);
+                                let data = resp.data().as_deref().unwrap_or_default();
+                                new_exchange.step(data).await
+                            }
+                            Err(e) => Err(e),
+                        }
+                    }
+                };
+
+                match step {
+                    Ok(SaslStep::Continue(data)) => {
+                        client
+                            .send(PgWireBackendMessage::Authentication(
+                                Authentication::SASLContinue(data),
+                            ))
+                            .await?;
+                    }
+                    Ok(SaslStep::Success(data)) => {
+                        if let Some(data) = data {
+                            client
+                                .feed(PgWireBackendMessage::Authentication(
+                                    Authentication::SASLFinal(data),
+                                ))
+                                .await?;
+                        }
+                        super::finish_authentication(client, self.parameter_provider.as_ref())
+                            .await?;
+                    }
+                    Err(e) => {
+                        let error_info = if let PgWireError::UserError(info) = e {
+                            *info
+                        } else {
+                            let user = client.metadata().get(METADATA_USER).cloned();
+                            ErrorInfo::new(
+                                Severity::Fatal,
+                                SqlState::InvalidAuthorizationSpecification,
+                                format!(
+                                    "SASL authentication failed for user {:?}: {e}",
+                                    user.unwrap_or_default()
+                                ),
+                            )
+                        };
+                        client
+                            .feed(PgWireBackendMessage::ErrorResponse(error_info.into()))
+                            .await?;
+                        client.close().await?;
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, new)]
+pub struct MakeSASLAuthStartupHandler<P> {
+    mechanisms: Arc<SaslMechanismRegistry>,
+    parameter_provider: Arc<P>,
+}
+
+impl<P: ServerParameterProvider> MakeHandler for MakeSASLAuthStartupHandler<P> {
+    type Handler = Arc<SASLAuthStartupHandler<P>>;
+
+    fn make(&self) -> Self::Handler {
+        Arc::new(SASLAuthStartupHandler {
+            mechanisms: self.mechanisms.clone(),
+            parameter_provider: self.parameter_provider.clone(),
+            exchange: Mutex::new(None),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::auth::DefaultServerParameterProvider;
+    use crate::api::mock::MockClient;
+    use crate::messages::startup::{PasswordMessageFamily, SASLInitialResponse, Startup};
+    use crate::messages::Message;
+
+    /// Accepts `hello` then `world`, optionally only on TLS connections
+    struct EchoMechanism(&'static str, bool);
+
+    struct EchoExchange(usize);
+
+    #[async_trait]
+    impl SaslMechanism for EchoMechanism {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn requires_secure_connection(&self) -> bool {
+            self.1
+        }
+
+        async fn init(&self, _login: &LoginInfo) -> PgWireResult<Box<dyn SaslExchange>> {
+            Ok(Box::new(EchoExchange(0)))
+        }
+    }
+
+    #[async_trait]
+    impl SaslExchange for EchoExchange {
+        async fn step(&mut self, input: &[u8]) -> PgWireResult<SaslStep> {
+            self.0 += 1;
+            match (self.0, input) {
+                (1, b"hello") => Ok(SaslStep::Continue(Bytes::from_static(b"challenge"))),
+                (2, b"world") => Ok(SaslStep::Success(Some(Bytes::from_static(b"done")))),
+                _ => Err(PgWireError::ApiError("invalid input".into())),
+            }
+        }
+    }
+
+    fn initial_response(mechanism: &str, data: &'static [u8]) -> PgWireFrontendMessage {
+        let mut buf = bytes::BytesMut::new();
+        SASLInitialResponse::new(mechanism.to_owned(), Some(Bytes::from_static(data)))
+            .encode(&mut buf)
+            .unwrap();
+        // strip message type and length
+        let body = buf.split_off(5);
+        PgWireFrontendMessage::PasswordMessageFamily(PasswordMessageFamily::Raw(body))
+    }
+
+    fn response(data: &'static [u8]) -> PgWireFrontendMessage {
+        PgWireFrontendMessage::PasswordMessageFamily(PasswordMessageFamily::Raw(data.into()))
+    }
+
+    fn handler() -> Arc<SASLAuthStartupHandler<DefaultServerParameterProvider>> {
+        let mut mechanisms = SaslMechanismRegistry::new();
+        mechanisms.register(EchoMechanism("ECHO", false));
+        mechanisms.register(EchoMechanism("ECHO-PLUS", true));
+        MakeSASLAuthStartupHandler::new(
+            Arc::new(mechanisms),
+            Arc::new(DefaultServerParameterProvider),
+        )
+        .make()
+    }
+
+    async fn startup(
+        handler: &SASLAuthStartupHandler<DefaultServerParameterProvider>,
+    ) -> MockClient {
+        let mut client = MockClient::new();
+        let mut startup = Startup::new();
+        startup
+            .parameters_mut()
+            .insert("user".to_owned(), "alice".to_owned());
+        handler
+            .on_startup(&mut client, PgWireFrontendMessage::Startup(startup))
+            .await
+            .unwrap();
+        client
+    }
+
+    #[test]
+    fn test_registry() {
+        let mut mechanisms = SaslMechanismRegistry::new();
+        mechanisms.register(EchoMechanism("ECHO", false));
+        mechanisms.register(EchoMechanism("ECHO-PLUS", true));
+        mechanisms.register(EchoMechanism("ECHO", true));
+
+        assert_eq!(vec!["ECHO", "ECHO-PLUS"], mechanisms.names(true));
+        assert!(mechanisms.names(false).is_empty());
+        assert!(mechanisms.get("ECHO").unwrap().requires_secure_connection());
+        assert!(mechanisms.get("SCRAM-SHA-256").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_sasl_auth() {
+        let handler = handler();
+        let mut client = startup(&handler).await;
+        match client.take_messages().as_slice() {
+            [PgWireBackendMessage::Authentication(Authentication::SASL(mechanisms))] => {
+                assert_eq!(&vec!["ECHO".to_owned()], mechanisms)
+            }
+            msgs => panic!("unexpected messages {msgs:?}"),
+        }
+
+        handler
+            .on_startup(&mut client, initial_response("ECHO", b"hello"))
+            .await
+            .unwrap();
+        match client.take_messages().as_slice() {
+            [PgWireBackendMessage::Authentication(Authentication::SASLContinue(data))] => {
+                assert_eq!(&b"challenge"[..], data.as_ref())
+            }
+            msgs => panic!("unexpected messages {msgs:?}"),
+        }
+
+        handler
+            .on_startup(&mut client, response(b"world"))
+            .await
+            .unwrap();
+        let messages = client.take_messages();
+        assert!(matches!(
+            &messages[0],
+            PgWireBackendMessage::Authentication(Authentication::SASLFinal(data)) if data.as_ref() == b"done"
+        ));
+        assert!(matches!(
+            messages[1],
+            PgWireBackendMessage::Authentication(Authentication::Ok)
+        ));
+        assert!(matches!(
+            client.state(),
+            PgWireConnectionState::ReadyForQuery
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_sasl_auth_rejected() {
+        for (mechanism, data, code) in [
+            ("ECHO", &b"world"[..], "28000"),
+            // not advertised on plaintext connections
+            ("ECHO-PLUS", &b"hello"[..], "08P01"),
+            ("SCRAM-SHA-256", &b"hello"[..], "08P01"),
+        ] {
+            let handler = handler();
+            let mut client = startup(&handler).await;
+            client.take_messages();
+
+            handler
+                .on_startup(&mut client, initial_response(mechanism, data))
+                .await
+                .unwrap();
+            match client.take_messages().as_slice() {
+                [PgWireBackendMessage::ErrorResponse(error)] => {
+                    assert!(
+                        error.fields().contains(&(b'C', code.to_owned())),
+                        "{mechanism}: {error:?}"
+                    );
+                }
+                msgs => panic!("unexpected messages {msgs:?}"),
+            }
+            assert!(matches!(
+                client.state(),
+                PgWireConnectionState::AuthenticationInProgress
+            ));
+        }
+    }
+}
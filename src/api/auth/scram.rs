@@ -1,5 +1,11 @@
+//! SCRAM-SHA-256 authentication, as a `SaslMechanism`.
+//!
+//! `MakeSASLScramAuthStartupHandler` creates a SASL startup handler with
+//! `SCRAM-SHA-256`, and `SCRAM-SHA-256-PLUS` when a server certificate is
+//! configured. Register `ScramSha256Mechanism` in a `SaslMechanismRegistry`
+//! to offer it along with other mechanisms.
+
 use std::borrow::Cow;
-use std::fmt::Debug;
 use std::num::NonZeroU32;
 use std::ops::BitXor;
 use std::sync::Arc;
@@ -8,39 +14,127 @@ use async_trait::async_trait;
 use base64::engine::general_purpose::STANDARD;
 use base64::Engine;
 use bytes::Bytes;
-use futures::{Sink, SinkExt};
 use ring::digest;
 use ring::hmac;
 use ring::pbkdf2;
-use tokio::sync::Mutex;
 use x509_certificate::certificate::CapturedX509Certificate;
 use x509_certificate::SignatureAlgorithm;
 
-use crate::api::auth::{AuthSource, LoginInfo, Password};
-use crate::api::{ClientInfo, MakeHandler, PgWireConnectionState};
-use crate::error::{PgWireError, PgWireResult};
-use crate::messages::startup::Authentication;
-use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
+use super::sasl::{
+    MakeSASLAuthStartupHandler, SASLAuthStartupHandler, SaslExchange, SaslMechanism,
+    SaslMechanismRegistry, SaslStep,
+};
+use crate::api::auth::{AuthSource, LoginInfo, MockAuthentication, Password};
+use crate::api::MakeHandler;
+use crate::error::{ErrorInfo, PgWireError, PgWireResult, Severity, SqlState};
+
+use super::ServerParameterProvider;
+
+/// The `SCRAM-SHA-256` mechanism, or `SCRAM-SHA-256-PLUS` with
+/// `tls-server-end-point` channel binding when created with a server
+/// certificate.
+#[derive(Debug)]
+pub struct ScramSha256Mechanism<A> {
+    auth_db: Arc<A>,
+    /// base64 encoded certificate signature for tls-server-end-point channel binding
+    server_cert_sig: Option<Arc<String>>,
+    /// iterations
+    iterations: usize,
+    mock_auth: Option<MockAuthentication>,
+}
+
+/// Length of salts of mock authentication, the default of postgres
+const MOCK_SALT_LEN: usize = 16;
+
+impl<A> ScramSha256Mechanism<A> {
+    /// Create `SCRAM-SHA-256` mechanism, see
+    /// `MakeSASLScramAuthStartupHandler::set_iterations` for `iterations`.
+    pub fn new(auth_db: Arc<A>, iterations: usize) -> ScramSha256Mechanism<A> {
+        ScramSha256Mechanism {
+            auth_db,
+            server_cert_sig: None,
+            iterations,
+            mock_auth: Some(MockAuthentication::new()),
+        }
+    }
+
+    /// Create `SCRAM-SHA-256-PLUS` mechanism, bound to the first certificate
+    /// of pem data `certs_pem`.
+    pub fn with_channel_binding(
+        auth_db: Arc<A>,
+        certs_pem: &[u8],
+        iterations: usize,
+    ) -> PgWireResult<ScramSha256Mechanism<A>> {
+        let sig = compute_cert_signature(certs_pem)?;
+        Ok(ScramSha256Mechanism {
+            auth_db,
+            server_cert_sig: Some(Arc::new(STANDARD.encode(sig))),
+            iterations,
+            mock_auth: Some(MockAuthentication::new()),
+        })
+    }
+
+    /// Enable or disable mock authentication, enabled by default like
+    /// postgres.
+    ///
+    /// When enabled, users for which the `AuthSource` returns
+    /// `PgWireError::UnknownUser` go through the exchange with a fake salt,
+    /// and are rejected with `28P01` like for a wrong password. Otherwise
+    /// they are rejected right away, which reveals they don't exist.
+    pub fn set_mock_authentication(&mut self, enabled: bool) {
+        MockAuthentication::toggle(&mut self.mock_auth, enabled);
+    }
+}
+
+#[async_trait]
+impl<A: AuthSource> SaslMechanism for ScramSha256Mechanism<A> {
+    fn name(&self) -> &str {
+        if self.server_cert_sig.is_some() {
+            "SCRAM-SHA-256-PLUS"
+        } else {
+            "SCRAM-SHA-256"
+        }
+    }
+
+    fn requires_secure_connection(&self) -> bool {
+        self.server_cert_sig.is_some()
+    }
 
-use super::{ServerParameterProvider, StartupHandler};
+    async fn init(&self, login: &LoginInfo) -> PgWireResult<Box<dyn SaslExchange>> {
+        let user = login.user().cloned().unwrap_or_default();
+        let password = match self.auth_db.get_password(login).await {
+            Err(PgWireError::UnknownUser(_)) if self.mock_auth.is_some() => {
+                let salt = self.mock_auth.as_ref().unwrap().salt(&user, MOCK_SALT_LEN);
+                // no proof matches a random salted password
+                Password::new(Some(salt), rand::random::<[u8; 32]>().to_vec())
+            }
+            password => password?,
+        };
+        Ok(Box::new(ScramExchange {
+            user,
+            password,
+            server_cert_sig: self.server_cert_sig.clone(),
+            iterations: self.iterations,
+            state: ScramState::Initial,
+        }))
+    }
+}
 
 #[derive(Debug)]
-pub enum ScramState {
+enum ScramState {
     Initial,
-    // cached password, channel_binding and partial auth-message
-    ServerFirstSent(Password, String, String),
+    // channel_binding and partial auth-message
+    ServerFirstSent(String, String),
 }
 
 #[derive(Debug)]
-pub struct SASLScramAuthStartupHandler<A, P> {
-    auth_db: Arc<A>,
-    parameter_provider: Arc<P>,
-    /// state of the client-server communication
-    state: Mutex<ScramState>,
-    /// base64 encoded certificate signature for tls-server-end-point channel binding
+struct ScramExchange {
+    user: String,
+    password: Password,
     server_cert_sig: Option<Arc<String>>,
-    /// iterations
     iterations: usize,
+    /// state of the client-server communication
+    state: ScramState,
 }
 
 /// Compute salted password from raw password as defined in
@@ -69,7 +163,7 @@ pub fn random_nonce() -> String {
     STANDARD.encode(buf)
 }
 
-impl<A, P> SASLScramAuthStartupHandler<A, P> {
+impl ScramExchange {
     fn compute_channel_binding(&self, client_channel_binding: &str) -> String {
         if client_channel_binding.starts_with("p=tls-server-end-point") {
             format!(
@@ -87,147 +181,78 @@ impl<A, P> SASLScramAuthStartupHandler<A, P> {
 }
 
 #[async_trait]
-impl<A: AuthSource, P: ServerParameterProvider> StartupHandler
-    for SASLScramAuthStartupHandler<A, P>
-{
-    async fn on_startup<C>(
-        &self,
-        client: &mut C,
-        message: PgWireFrontendMessage,
-    ) -> PgWireResult<()>
-    where
-        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
-        C::Error: Debug,
-        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
-    {
-        match message {
-            PgWireFrontendMessage::Startup(ref startup) => {
-                super::save_startup_parameters_to_metadata(client, startup);
-                client.set_state(PgWireConnectionState::AuthenticationInProgress);
-                let supported_mechanisms = if self.server_cert_sig.is_some() {
-                    vec!["SCRAM-SHA-256".to_owned(), "SCRAM-SHA-256-PLUS".to_owned()]
-                } else {
-                    vec!["SCRAM-SHA-256".to_owned()]
-                };
-                client
-                    .send(PgWireBackendMessage::Authentication(Authentication::SASL(
-                        supported_mechanisms,
-                    )))
-                    .await?;
+impl SaslExchange for ScramExchange {
+    async fn step(&mut self, input: &[u8]) -> PgWireResult<SaslStep> {
+        match self.state {
+            ScramState::Initial => {
+                // initial response, client_first
+                if input.is_empty() {
+                    return Err(PgWireError::InvalidScramMessage(
+                        "Empty client-first".to_owned(),
+                    ));
+                }
+                let client_first = ClientFirst::try_new(String::from_utf8_lossy(input).as_ref())?;
+                // channel binding is required by, and only allowed with, the
+                // -PLUS mechanism
+                if client_first.cbind_flag.starts_with("p=") != self.server_cert_sig.is_some() {
+                    return Err(PgWireError::InvalidScramMessage(format!(
+                        "Unexpected channel binding flag {}",
+                        client_first.cbind_flag
+                    )));
+                }
+
+                // create server_first and send
+                let mut new_nonce = client_first.nonce.clone();
+                new_nonce.push_str(random_nonce().as_str());
+
+                let server_first = ServerFirst::new(
+                    new_nonce,
+                    STANDARD.encode(
+                        self.password
+                            .salt()
+                            .as_ref()
+                            .expect("Salt required for SCRAM auth source"),
+                    ),
+                    self.iterations,
+                );
+                let server_first_message = server_first.message();
+
+                self.state = ScramState::ServerFirstSent(
+                    client_first.channel_binding(),
+                    format!("{},{}", client_first.bare(), &server_first_message),
+                );
+                Ok(SaslStep::Continue(Bytes::from(server_first_message)))
             }
-            PgWireFrontendMessage::PasswordMessageFamily(msg) => {
-                let salt_and_salted_pass = {
-                    let state = self.state.lock().await;
-                    match *state {
-                        ScramState::Initial => {
-                            let login_info = LoginInfo::from_client_info(client);
-                            self.auth_db.get_password(&login_info).await?
-                        }
-                        ScramState::ServerFirstSent(ref pass, _, _) => pass.clone(),
-                    }
-                };
-
-                let mut success = false;
-                let resp = {
-                    // this should never block
-                    let mut state = self.state.lock().await;
-                    match *state {
-                        ScramState::Initial => {
-                            // initial response, client_first
-                            let resp = msg.into_sasl_initial_response()?;
-                            // parse into client_first
-                            let client_first = resp
-                                .data()
-                                .as_ref()
-                                .ok_or_else(|| {
-                                    PgWireError::InvalidScramMessage(
-                                        "Empty client-first".to_owned(),
-                                    )
-                                })
-                                .and_then(|data| {
-                                    ClientFirst::try_new(String::from_utf8_lossy(data).as_ref())
-                                })?;
-                            // dbg!(&client_first);
-
-                            // create server_first and send
-                            let mut new_nonce = client_first.nonce.clone();
-                            new_nonce.push_str(random_nonce().as_str());
-
-                            let server_first = ServerFirst::new(
-                                new_nonce,
-                                STANDARD.encode(
-                                    salt_and_salted_pass
-                                        .salt()
-                                        .as_ref()
-                                        .expect("Salt required for SCRAM auth source"),
-                                ),
-                                self.iterations,
-                            );
-                            let server_first_message = server_first.message();
-
-                            *state = ScramState::ServerFirstSent(
-                                salt_and_salted_pass,
-                                client_first.channel_binding(),
-                                format!("{},{}", client_first.bare(), &server_first_message),
-                            );
-                            Authentication::SASLContinue(Bytes::from(server_first_message))
-                        }
-                        ScramState::ServerFirstSent(
-                            _,
-                            ref channel_binding_prefix,
-                            ref partial_auth_msg,
-                        ) => {
-                            // second response, client_final
-                            let resp = msg.into_sasl_response()?;
-                            let client_final = ClientFinal::try_new(
-                                String::from_utf8_lossy(resp.data().as_ref()).as_ref(),
-                            )?;
-                            // dbg!(&client_final);
-
-                            let channel_binding =
-                                self.compute_channel_binding(channel_binding_prefix);
-                            client_final.validate_channel_binding(&channel_binding)?;
-
-                            let salted_password = salt_and_salted_pass.password();
-                            let client_key = hmac(salted_password.as_ref(), b"Client Key");
-                            let stored_key = h(client_key.as_ref());
-                            let auth_msg =
-                                format!("{},{}", partial_auth_msg, client_final.without_proof());
-                            let client_signature = hmac(stored_key.as_ref(), auth_msg.as_bytes());
-
-                            let computed_client_proof = STANDARD.encode(
-                                xor(client_key.as_ref(), client_signature.as_ref()).as_slice(),
-                            );
-
-                            if computed_client_proof == client_final.proof {
-                                let server_key = hmac(salted_password.as_ref(), b"Server Key");
-                                let server_signature =
-                                    hmac(server_key.as_ref(), auth_msg.as_bytes());
-                                let server_final =
-                                    ServerFinalSuccess::new(STANDARD.encode(server_signature));
-                                success = true;
-                                Authentication::SASLFinal(Bytes::from(server_final.message()))
-                            } else {
-                                let server_final =
-                                    ServerFinalError::new("invalid-proof".to_owned());
-                                Authentication::SASLFinal(Bytes::from(server_final.message()))
-                            }
-                        }
-                    }
-                };
-
-                client
-                    .send(PgWireBackendMessage::Authentication(resp))
-                    .await?;
-
-                if success {
-                    super::finish_authentication(client, self.parameter_provider.as_ref()).await
+            ScramState::ServerFirstSent(ref channel_binding_prefix, ref partial_auth_msg) => {
+                // second response, client_final
+                let client_final = ClientFinal::try_new(String::from_utf8_lossy(input).as_ref())?;
+
+                let channel_binding = self.compute_channel_binding(channel_binding_prefix);
+                client_final.validate_channel_binding(&channel_binding)?;
+
+                let salted_password = self.password.password();
+                let client_key = hmac(salted_password.as_ref(), b"Client Key");
+                let stored_key = h(client_key.as_ref());
+                let auth_msg = format!("{},{}", partial_auth_msg, client_final.without_proof());
+                let client_signature = hmac(stored_key.as_ref(), auth_msg.as_bytes());
+
+                let computed_client_proof =
+                    STANDARD.encode(xor(client_key.as_ref(), client_signature.as_ref()).as_slice());
+
+                if computed_client_proof == client_final.proof {
+                    let server_key = hmac(salted_password.as_ref(), b"Server Key");
+                    let server_signature = hmac(server_key.as_ref(), auth_msg.as_bytes());
+                    let server_final = ServerFinalSuccess::new(STANDARD.encode(server_signature));
+                    Ok(SaslStep::Success(Some(Bytes::from(server_final.message()))))
+                } else {
+                    Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                        Severity::Fatal,
+                        SqlState::InvalidPassword,
+                        format!("password authentication failed for user {:?}", self.user),
+                    ))))
                 }
             }
-            _ => {}
         }
-
-        Ok(())
     }
 }
 
@@ -239,6 +264,8 @@ pub struct MakeSASLScramAuthStartupHandler<A, P> {
     server_cert_sig: Option<Arc<String>>,
     #[new(value = "4096")]
     iterations: usize,
+    #[new(value = "Some(MockAuthentication::new())")]
+    mock_auth: Option<MockAuthentication>,
 }
 
 impl<A, P> MakeSASLScramAuthStartupHandler<A, P> {
@@ -246,7 +273,8 @@ impl<A, P> MakeSASLScramAuthStartupHandler<A, P> {
     /// certificate.
     ///
     /// Original pem data is required here. We will decode pem and use the first
-    /// certificate as server certificate.
+    /// certificate as server certificate. `SCRAM-SHA-256-PLUS` is only
+    /// advertised on TLS connections.
     pub fn configure_certificate(&mut self, certs_pem: &[u8]) -> PgWireResult<()> {
         let sig = compute_cert_signature(certs_pem)?;
         self.server_cert_sig = Some(Arc::new(STANDARD.encode(sig)));
@@ -264,19 +292,50 @@ impl<A, P> MakeSASLScramAuthStartupHandler<A, P> {
     pub fn set_iterations(&mut self, iterations: usize) {
         self.iterations = iterations;
     }
-}
 
-impl<A, P> MakeHandler for MakeSASLScramAuthStartupHandler<A, P> {
-    type Handler = Arc<SASLScramAuthStartupHandler<A, P>>;
+    /// Enable or disable mock authentication of unknown users, enabled by
+    /// default. See `ScramSha256Mechanism::set_mock_authentication`.
+    pub fn set_mock_authentication(&mut self, enabled: bool) {
+        MockAuthentication::toggle(&mut self.mock_auth, enabled);
+    }
+}
 
-    fn make(&self) -> Self::Handler {
-        Arc::new(SASLScramAuthStartupHandler {
+impl<A: AuthSource + 'static, P> MakeSASLScramAuthStartupHandler<A, P> {
+    /// Mechanisms offered to clients, register them with other mechanisms to
+    /// create a `MakeSASLAuthStartupHandler`.
+    pub fn mechanisms(&self) -> SaslMechanismRegistry {
+        // mechanisms share the secret of mock authentication, so that salts
+        // of a user are the same on all connections
+        let mut mechanisms = SaslMechanismRegistry::new();
+        mechanisms.register(ScramSha256Mechanism {
             auth_db: self.auth_db.clone(),
-            parameter_provider: self.parameter_provider.clone(),
-            state: Mutex::new(ScramState::Initial),
-            server_cert_sig: self.server_cert_sig.clone(),
+            server_cert_sig: None,
             iterations: self.iterations,
-        })
+            mock_auth: self.mock_auth.clone(),
+        });
+        if let Some(ref server_cert_sig) = self.server_cert_sig {
+            mechanisms.register(ScramSha256Mechanism {
+                auth_db: self.auth_db.clone(),
+                server_cert_sig: Some(server_cert_sig.clone()),
+                iterations: self.iterations,
+                mock_auth: self.mock_auth.clone(),
+            });
+        }
+        mechanisms
+    }
+}
+
+impl<A: AuthSource + 'static, P: ServerParameterProvider> MakeHandler
+    for MakeSASLScramAuthStartupHandler<A, P>
+{
+    type Handler = Arc<SASLAuthStartupHandler<P>>;
+
+    fn make(&self) -> Self::Handler {
+        MakeSASLAuthStartupHandler::new(
+            Arc::new(self.mechanisms()),
+            self.parameter_provider.clone(),
+        )
+        .make()
     }
 }
 
@@ -405,17 +464,6 @@ impl ServerFinalSuccess {
     }
 }
 
-#[derive(Debug, new)]
-struct ServerFinalError {
-    error: String,
-}
-
-impl ServerFinalError {
-    fn message(&self) -> String {
-        format!("e={}", self.error)
-    }
-}
-
 fn hi(normalized_password: &[u8], salt: &[u8], iterations: usize) -> Vec<u8> {
     let mut buf = [0u8; 32];
 
@@ -454,7 +502,7 @@ fn xor(lhs: &[u8], rhs: &[u8]) -> Vec<u8> {
 /// 1. use sha-256 if the certificate's algorithm is md5 or sha-1
 /// 2. use the certificate's algorithm if it's neither md5 or sha-1
 /// 3. if the certificate has 0 or more than 1 signature algorithm, the
-/// behaviour is undefined at the time.
+///    behaviour is undefined at the time.
 fn compute_cert_signature(cert: &[u8]) -> PgWireResult<Vec<u8>> {
     let certs = CapturedX509Certificate::from_pem_multiple(cert)
         .map_err(|e| PgWireError::ApiError(Box::new(e)))?;
@@ -475,3 +523,139 @@ fn compute_cert_signature(cert: &[u8]) -> PgWireResult<Vec<u8>> {
         _ => Err(PgWireError::UnsupportedCertificateSignatureAlgorithm),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::auth::DefaultServerParameterProvider;
+
+    struct PencilAuthSource;
+
+    /// Knows no user
+    struct EmptyAuthSource;
+
+    #[async_trait]
+    impl AuthSource for EmptyAuthSource {
+        async fn get_password(&self, login: &LoginInfo) -> PgWireResult<Password> {
+            Err(PgWireError::UnknownUser(
+                login.user().cloned().unwrap_or_default(),
+            ))
+        }
+    }
+
+    #[async_trait]
+    impl AuthSource for PencilAuthSource {
+        async fn get_password(&self, _login: &LoginInfo) -> PgWireResult<Password> {
+            let salt = b"salt".to_vec();
+            let password = gen_salted_password("pencil", &salt, 4096);
+            Ok(Password::new(Some(salt), password))
+        }
+    }
+
+    async fn client_final(exchange: &mut Box<dyn SaslExchange>, password: &str) -> String {
+        let server_first = match exchange.step(b"n,,n=,r=rOprNGfwEbeRWgbNEkqO").await {
+            Ok(SaslStep::Continue(data)) => String::from_utf8(data.to_vec()).unwrap(),
+            step => panic!("unexpected step {step:?}"),
+        };
+        let nonce = server_first
+            .split(',')
+            .next()
+            .and_then(|r| r.strip_prefix("r=rOprNGfwEbeRWgbNEkqO"))
+            .unwrap();
+        let without_proof = format!("c=biws,r=rOprNGfwEbeRWgbNEkqO{nonce}");
+        let auth_msg = format!("n=,r=rOprNGfwEbeRWgbNEkqO,{server_first},{without_proof}");
+
+        let salted_password = gen_salted_password(password, b"salt", 4096);
+        let client_key = hmac(&salted_password, b"Client Key");
+        let client_signature = hmac(&h(&client_key), auth_msg.as_bytes());
+        let proof = STANDARD.encode(xor(&client_key, &client_signature));
+        format!("{without_proof},p={proof}")
+    }
+
+    #[tokio::test]
+    async fn test_scram_exchange() {
+        let mechanism = ScramSha256Mechanism::new(Arc::new(PencilAuthSource), 4096);
+        assert_eq!("SCRAM-SHA-256", mechanism.name());
+        let user = "alice".to_owned();
+        let login = LoginInfo::new(Some(&user), None, "127.0.0.1".to_owned());
+
+        let mut exchange = mechanism.init(&login).await.unwrap();
+        let final_message = client_final(&mut exchange, "pencil").await;
+        match exchange.step(final_message.as_bytes()).await {
+            Ok(SaslStep::Success(Some(data))) => assert!(data.starts_with(b"v=")),
+            step => panic!("unexpected step {step:?}"),
+        }
+
+        let mut exchange = mechanism.init(&login).await.unwrap();
+        let final_message = client_final(&mut exchange, "eraser").await;
+        assert!(matches!(
+            exchange.step(final_message.as_bytes()).await,
+            Err(PgWireError::UserError(info)) if info.code() == "28P01"
+        ));
+
+        // channel binding requires SCRAM-SHA-256-PLUS
+        let mut exchange = mechanism.init(&login).await.unwrap();
+        assert!(matches!(
+            exchange
+                .step(b"p=tls-server-end-point,,n=,r=rOprNGfwEbeRWgbNEkqO")
+                .await,
+            Err(PgWireError::InvalidScramMessage(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_scram_mock_authentication() {
+        let make_handler = MakeSASLScramAuthStartupHandler::new(
+            Arc::new(EmptyAuthSource),
+            Arc::new(DefaultServerParameterProvider),
+        );
+        let user = "nobody".to_owned();
+        let login = LoginInfo::new(Some(&user), None, "127.0.0.1".to_owned());
+        let server_first = |data: Bytes| {
+            let message = String::from_utf8(data.to_vec()).unwrap();
+            message.split(',').nth(1).unwrap().to_owned()
+        };
+
+        // the exchange of unknown users goes on, with the same salt on each
+        // connection, and fails like a wrong password
+        let mut salts = vec![];
+        for _ in 0..2 {
+            let mechanisms = make_handler.mechanisms();
+            let mechanism = mechanisms.get("SCRAM-SHA-256").unwrap();
+            let mut exchange = mechanism.init(&login).await.unwrap();
+            match exchange.step(b"n,,n=,r=rOprNGfwEbeRWgbNEkqO").await {
+                Ok(SaslStep::Continue(data)) => salts.push(server_first(data)),
+                step => panic!("unexpected step {step:?}"),
+            }
+        }
+        assert_eq!(salts[0], salts[1]);
+        assert_eq!(16, STANDARD.decode(&salts[0][2..]).unwrap().len());
+
+        let mut exchange = make_handler
+            .mechanisms()
+            .get("SCRAM-SHA-256")
+            .unwrap()
+            .init(&login)
+            .await
+            .unwrap();
+        let final_message = client_final(&mut exchange, "pencil").await;
+        match exchange.step(final_message.as_bytes()).await {
+            Err(PgWireError::UserError(info)) => {
+                assert_eq!("28P01", info.code());
+                assert_eq!(
+                    "password authentication failed for user \"nobody\"",
+                    info.message()
+                );
+            }
+            step => panic!("unexpected step {step:?}"),
+        }
+
+        // without mock authentication, unknown users are rejected right away
+        let mut mechanism = ScramSha256Mechanism::new(Arc::new(EmptyAuthSource), 4096);
+        mechanism.set_mock_authentication(false);
+        assert!(matches!(
+            mechanism.init(&login).await,
+            Err(PgWireError::UnknownUser(_))
+        ));
+    }
+}
@@ -0,0 +1,252 @@
+//! Authentication method selected for each connection, like rules of
+//! `pg_hba.conf`.
+//!
+//! An `AuthSelector` is called with the user, database and peer address from
+//! the startup message, and whether the connection is over TLS. It returns the
+//! startup handler of the method to run, which then processes all startup
+//! messages of this connection. As `StartupHandler` is generic over clients,
+//! methods are usually composed as an enum of handlers, delegating
+//! `on_startup` to the variant.
+
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures::{Sink, SinkExt};
+use tokio::sync::Mutex;
+
+use super::{LoginInfo, StartupHandler, METADATA_USER};
+use crate::api::{ClientInfo, MakeHandler};
+use crate::error::{ErrorInfo, PgWireError, PgWireResult, Severity, SqlState};
+use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
+
+/// Select authentication method of a connection
+#[async_trait]
+pub trait AuthSelector: Send + Sync {
+    /// Startup handler running the selected method
+    type Handler: StartupHandler;
+
+    /// Select method for client `login`, `secure` is true for TLS
+    /// connections.
+    ///
+    /// Return an error to reject the client, like postgres does when no
+    /// `pg_hba.conf` entry matches. `UserError` is sent to client as is, other
+    /// errors are reported as `28000`.
+    async fn select(&self, login: &LoginInfo, secure: bool) -> PgWireResult<Self::Handler>;
+}
+
+/// Startup handler running the method selected by `AuthSelector`, create one
+/// for each connection with `MakeSelectAuthStartupHandler`
+pub struct SelectAuthStartupHandler<S: AuthSelector> {
+    selector: Arc<S>,
+    selected: Mutex<Option<S::Handler>>,
+}
+
+#[async_trait]
+impl<S: AuthSelector> StartupHandler for SelectAuthStartupHandler<S> {
+    async fn on_startup<C>(
+        &self,
+        client: &mut C,
+        message: PgWireFrontendMessage,
+    ) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let mut selected = self.selected.lock().await;
+        if let PgWireFrontendMessage::Startup(ref startup) = message {
+            super::save_startup_parameters_to_metadata(client, startup);
+            let secure = client.is_secure();
+            let login_info = LoginInfo::from_client_info(client);
+            match self.selector.select(&login_info, secure).await {
+                Ok(handler) => *selected = Some(handler),
+                Err(e) => {
+                    let error_info = if let PgWireError::UserError(error_info) = e {
+                        *error_info
+                    } else {
+                        let user = client.metadata().get(METADATA_USER).cloned();
+                        ErrorInfo::new(
+                            Severity::Fatal,
+                            SqlState::InvalidAuthorizationSpecification,
+                            format!(
+                                "no authentication method for user {:?}: {e}",
+                                user.unwrap_or_default()
+                            ),
+                        )
+                    };
+                    client
+                        .feed(PgWireBackendMessage::ErrorResponse(error_info.into()))
+                        .await?;
+                    client.close().await?;
+                    return Ok(());
+                }
+            }
+        }
+
+        match selected.as_ref() {
+            Some(handler) => handler.on_startup(client, message).await,
+            // messages before the startup message
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, new)]
+pub struct MakeSelectAuthStartupHandler<S> {
+    selector: Arc<S>,
+}
+
+impl<S: AuthSelector> MakeHandler for MakeSelectAuthStartupHandler<S> {
+    type Handler = Arc<SelectAuthStartupHandler<S>>;
+
+    fn make(&self) -> Self::Handler {
+        Arc::new(SelectAuthStartupHandler {
+            selector: self.selector.clone(),
+            selected: Mutex::new(None),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::BytesMut;
+
+    use super::*;
+    use crate::api::auth::cleartext::CleartextPasswordAuthStartupHandler;
+    use crate::api::auth::noop::NoopStartupHandler;
+    use crate::api::auth::{AuthSource, DefaultServerParameterProvider, Password};
+    use crate::api::mock::MockClient;
+    use crate::api::PgWireConnectionState;
+    use crate::messages::startup::{Authentication, PasswordMessageFamily, Startup};
+
+    struct StaticAuthSource;
+
+    #[async_trait]
+    impl AuthSource for StaticAuthSource {
+        async fn get_password(&self, _login: &LoginInfo) -> PgWireResult<Password> {
+            Ok(Password::new(None, b"secret".to_vec()))
+        }
+    }
+
+    enum Method {
+        Trust(NoopStartupHandler),
+        Password(
+            CleartextPasswordAuthStartupHandler<StaticAuthSource, DefaultServerParameterProvider>,
+        ),
+    }
+
+    #[async_trait]
+    impl StartupHandler for Method {
+        async fn on_startup<C>(
+            &self,
+            client: &mut C,
+            message: PgWireFrontendMessage,
+        ) -> PgWireResult<()>
+        where
+            C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            match self {
+                Method::Trust(handler) => handler.on_startup(client, message).await,
+                Method::Password(handler) => handler.on_startup(client, message).await,
+            }
+        }
+    }
+
+    /// trust for local connections to database `internal`, password for
+    /// others, except user `nobody`
+    struct HbaSelector;
+
+    #[async_trait]
+    impl AuthSelector for HbaSelector {
+        type Handler = Method;
+
+        async fn select(&self, login: &LoginInfo, _secure: bool) -> PgWireResult<Method> {
+            if login.user().map(String::as_str) == Some("nobody") {
+                return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                    Severity::Fatal,
+                    SqlState::InvalidAuthorizationSpecification,
+                    format!("no pg_hba.conf entry for host {:?}", login.host()),
+                ))));
+            }
+            if login.host() == "127.0.0.1"
+                && login.database().map(String::as_str) == Some("internal")
+            {
+                Ok(Method::Trust(NoopStartupHandler))
+            } else {
+                Ok(Method::Password(CleartextPasswordAuthStartupHandler::new(
+                    StaticAuthSource,
+                    DefaultServerParameterProvider,
+                )))
+            }
+        }
+    }
+
+    async fn startup(
+        user: &str,
+        database: &str,
+    ) -> (Arc<SelectAuthStartupHandler<HbaSelector>>, MockClient) {
+        let handler = MakeSelectAuthStartupHandler::new(Arc::new(HbaSelector)).make();
+        let mut client = MockClient::new();
+        let mut startup = Startup::new();
+        startup
+            .parameters_mut()
+            .insert("user".to_owned(), user.to_owned());
+        startup
+            .parameters_mut()
+            .insert("database".to_owned(), database.to_owned());
+        handler
+            .on_startup(&mut client, PgWireFrontendMessage::Startup(startup))
+            .await
+            .unwrap();
+        (handler, client)
+    }
+
+    #[tokio::test]
+    async fn test_select_auth_method() {
+        let (_, mut client) = startup("alice", "internal").await;
+        assert!(matches!(
+            client.take_messages().first(),
+            Some(PgWireBackendMessage::Authentication(Authentication::Ok))
+        ));
+        assert!(matches!(
+            client.state(),
+            PgWireConnectionState::ReadyForQuery
+        ));
+
+        let (handler, mut client) = startup("alice", "postgres").await;
+        assert!(matches!(
+            client.take_messages().as_slice(),
+            [PgWireBackendMessage::Authentication(
+                Authentication::CleartextPassword
+            )]
+        ));
+        handler
+            .on_startup(
+                &mut client,
+                PgWireFrontendMessage::PasswordMessageFamily(PasswordMessageFamily::Raw(
+                    BytesMut::from(&b"secret\0"[..]),
+                )),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.take_messages().first(),
+            Some(PgWireBackendMessage::Authentication(Authentication::Ok))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_select_auth_rejected() {
+        let (_, mut client) = startup("nobody", "internal").await;
+        match client.take_messages().as_slice() {
+            [PgWireBackendMessage::ErrorResponse(error)] => assert!(error
+                .fields()
+                .iter()
+                .any(|f| f.1 == "no pg_hba.conf entry for host \"127.0.0.1\"")),
+            messages => panic!("unexpected messages {messages:?}"),
+        }
+    }
+}
@@ -0,0 +1,285 @@
+//! SSPI authentication, the Windows negotiate path for Active Directory
+//! integrated clients.
+//!
+//! The server sends `AuthenticationSSPI`, and then exchanges tokens with the
+//! client until the security context is established. Tokens are processed by
+//! an `SspiAuthenticator` supplied by the application, typically backed by the
+//! Windows `sspi` crate.
+
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{Sink, SinkExt};
+use tokio::sync::Mutex;
+
+use super::{LoginInfo, ServerParameterProvider, StartupHandler, METADATA_USER};
+use crate::api::{ClientInfo, MakeHandler, PgWireConnectionState};
+use crate::error::{ErrorInfo, PgWireError, PgWireResult, Severity, SqlState};
+use crate::messages::startup::Authentication;
+use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
+
+/// Result of processing a token from client
+#[derive(Debug)]
+pub enum SspiStep {
+    /// The context is not established yet, send this token to client and wait
+    /// for the next one
+    Continue(Bytes),
+    /// The context is established, and the client principal is mapped to
+    /// postgres `role`. An optional final token is sent to client.
+    Complete { role: String, token: Option<Bytes> },
+}
+
+/// Process SSPI tokens for a connection
+#[async_trait]
+pub trait SspiAuthenticator: Send + Sync {
+    /// Per-connection security context
+    type Context: Send;
+
+    /// Create security context for a new connection
+    async fn new_context(&self, login: &LoginInfo) -> PgWireResult<Self::Context>;
+
+    /// Process a token received from client.
+    ///
+    /// Return an error to reject the client, for example when the principal
+    /// cannot be mapped to a role.
+    async fn accept_token(
+        &self,
+        context: &mut Self::Context,
+        token: &[u8],
+    ) -> PgWireResult<SspiStep>;
+}
+
+/// Startup handler for SSPI authentication, create one for each connection
+/// with `MakeSspiAuthStartupHandler`
+pub struct SspiAuthStartupHandler<A: SspiAuthenticator, P> {
+    authenticator: Arc<A>,
+    parameter_provider: Arc<P>,
+    context: Mutex<Option<A::Context>>,
+}
+
+#[async_trait]
+impl<A: SspiAuthenticator, P: ServerParameterProvider> StartupHandler
+    for SspiAuthStartupHandler<A, P>
+{
+    async fn on_startup<C>(
+        &self,
+        client: &mut C,
+        message: PgWireFrontendMessage,
+    ) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        match message {
+            PgWireFrontendMessage::Startup(ref startup) => {
+                super::save_startup_parameters_to_metadata(client, startup);
+                client.set_state(PgWireConnectionState::AuthenticationInProgress);
+                client
+                    .send(PgWireBackendMessage::Authentication(Authentication::SSPI))
+                    .await?;
+            }
+            PgWireFrontendMessage::PasswordMessageFamily(msg) => {
+                let token = msg.into_gss_response()?;
+
+                let step = {
+                    let mut context = self.context.lock().await;
+                    if context.is_none() {
+                        let login_info = LoginInfo::from_client_info(client);
+                        *context = Some(self.authenticator.new_context(&login_info).await?);
+                    }
+                    // safe to unwrap, context initialized above
+                    let context = context.as_mut().unwrap();
+                    self.authenticator
+                        .accept_token(context, token.data().as_ref())
+                        .await
+                };
+
+                match step {
+                    Ok(SspiStep::Continue(token)) => {
+                        client
+                            .send(PgWireBackendMessage::Authentication(
+                                Authentication::GSSContinue(token),
+                            ))
+                            .await?;
+                    }
+                    Ok(SspiStep::Complete { role, token }) => {
+                        if let Some(token) = token {
+                            client
+                                .feed(PgWireBackendMessage::Authentication(
+                                    Authentication::GSSContinue(token),
+                                ))
+                                .await?;
+                        }
+                        client.metadata_mut().insert(METADATA_USER.to_owned(), role);
+                        super::finish_authentication(client, self.parameter_provider.as_ref())
+                            .await?;
+                    }
+                    Err(e) => {
+                        let user = client.metadata().get(METADATA_USER).cloned();
+                        let error_info = ErrorInfo::new(
+                            Severity::Fatal,
+                            SqlState::InvalidAuthorizationSpecification,
+                            format!(
+                                "SSPI authentication failed for user {:?}: {e}",
+                                user.unwrap_or_default()
+                            ),
+                        );
+                        client
+                            .feed(PgWireBackendMessage::ErrorResponse(error_info.into()))
+                            .await?;
+                        client.close().await?;
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, new)]
+pub struct MakeSspiAuthStartupHandler<A, P> {
+    authenticator: Arc<A>,
+    parameter_provider: Arc<P>,
+}
+
+impl<A: SspiAuthenticator, P: ServerParameterProvider> MakeHandler
+    for MakeSspiAuthStartupHandler<A, P>
+{
+    type Handler = Arc<SspiAuthStartupHandler<A, P>>;
+
+    fn make(&self) -> Self::Handler {
+        Arc::new(SspiAuthStartupHandler {
+            authenticator: self.authenticator.clone(),
+            parameter_provider: self.parameter_provider.clone(),
+            context: Mutex::new(None),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::auth::DefaultServerParameterProvider;
+    use crate::api::mock::MockClient;
+    use crate::messages::startup::{PasswordMessageFamily, Startup};
+
+    /// Accepts `hello` then `world`, and maps the client to role `alice`
+    struct TwoStepAuthenticator;
+
+    #[async_trait]
+    impl SspiAuthenticator for TwoStepAuthenticator {
+        type Context = usize;
+
+        async fn new_context(&self, _login: &LoginInfo) -> PgWireResult<Self::Context> {
+            Ok(0)
+        }
+
+        async fn accept_token(
+            &self,
+            context: &mut Self::Context,
+            token: &[u8],
+        ) -> PgWireResult<SspiStep> {
+            *context += 1;
+            match (*context, token) {
+                (1, b"hello") => Ok(SspiStep::Continue(Bytes::from_static(b"challenge"))),
+                (2, b"world") => Ok(SspiStep::Complete {
+                    role: "alice".to_owned(),
+                    token: None,
+                }),
+                _ => Err(PgWireError::ApiError("invalid token".into())),
+            }
+        }
+    }
+
+    fn token(data: &'static [u8]) -> PgWireFrontendMessage {
+        PgWireFrontendMessage::PasswordMessageFamily(PasswordMessageFamily::Raw(data.into()))
+    }
+
+    async fn startup(
+        handler: &SspiAuthStartupHandler<TwoStepAuthenticator, DefaultServerParameterProvider>,
+        client: &mut MockClient,
+    ) {
+        let mut startup = Startup::new();
+        startup
+            .parameters_mut()
+            .insert("user".to_owned(), "DOMAIN\\alice".to_owned());
+        handler
+            .on_startup(client, PgWireFrontendMessage::Startup(startup))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sspi_auth() {
+        let make_handler = MakeSspiAuthStartupHandler::new(
+            Arc::new(TwoStepAuthenticator),
+            Arc::new(DefaultServerParameterProvider),
+        );
+        let handler = make_handler.make();
+        let mut client = MockClient::new();
+        startup(&handler, &mut client).await;
+        assert!(matches!(
+            client.take_messages().as_slice(),
+            [PgWireBackendMessage::Authentication(Authentication::SSPI)]
+        ));
+
+        handler
+            .on_startup(&mut client, token(b"hello"))
+            .await
+            .unwrap();
+        match client.take_messages().as_slice() {
+            [PgWireBackendMessage::Authentication(Authentication::GSSContinue(data))] => {
+                assert_eq!(&b"challenge"[..], data.as_ref())
+            }
+            msgs => panic!("unexpected messages {msgs:?}"),
+        }
+
+        handler
+            .on_startup(&mut client, token(b"world"))
+            .await
+            .unwrap();
+        let messages = client.take_messages();
+        assert!(matches!(
+            messages[0],
+            PgWireBackendMessage::Authentication(Authentication::Ok)
+        ));
+        assert_eq!(
+            Some("alice"),
+            client.metadata().get(METADATA_USER).map(String::as_str)
+        );
+        assert!(matches!(
+            client.state(),
+            PgWireConnectionState::ReadyForQuery
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_sspi_auth_rejected() {
+        let make_handler = MakeSspiAuthStartupHandler::new(
+            Arc::new(TwoStepAuthenticator),
+            Arc::new(DefaultServerParameterProvider),
+        );
+        let handler = make_handler.make();
+        let mut client = MockClient::new();
+        startup(&handler, &mut client).await;
+        client.take_messages();
+
+        handler
+            .on_startup(&mut client, token(b"world"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.take_messages().as_slice(),
+            [PgWireBackendMessage::ErrorResponse(_)]
+        ));
+        assert!(matches!(
+            client.state(),
+            PgWireConnectionState::AuthenticationInProgress
+        ));
+    }
+}
@@ -0,0 +1,50 @@
+//! Cache of query results for repeated executions of prepared statements.
+//!
+//! Return a `ResultCache` from `ExtendedQueryHandler::result_cache` to enable
+//! it. On `Execute` of a portal, the cache is consulted before `do_query`. On
+//! a hit, the cached rows and command tag are sent to client without calling
+//! the handler. On a miss, rows of the response are collected while they are
+//! sent, and the complete result is put into the cache.
+//!
+//! The cache is only used outside transaction blocks, so a transaction always
+//! sees its own writes, for executions of the whole portal, and for query
+//! responses completed with `SELECT`. Responses with a tag of
+//! `QueryResponse::with_command_tag`, like `UPDATE ... RETURNING`, have side
+//! effects and are always executed. The key, like statement and parameters, and invalidation are up
+//! to the implementation. Rows are encoded in the result formats of the
+//! portal, so the key should include `result_column_format` unless all
+//! clients use the same formats.
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use super::portal::Portal;
+use super::results::{FieldInfo, Tag};
+use crate::messages::data::DataRow;
+
+/// A complete result of a query, as sent to client
+#[derive(Debug, Clone, new, Getters)]
+#[getset(get = "pub")]
+pub struct CachedResult {
+    row_schema: Arc<Vec<FieldInfo>>,
+    rows: Vec<DataRow>,
+    tag: Tag,
+}
+
+/// Cache of query results, keyed by the executed portal
+#[async_trait]
+pub trait ResultCache<S: Send + Sync>: Send + Sync {
+    /// Get the cached result of executing `portal`
+    async fn get(&self, portal: &Portal<S>) -> Option<Arc<CachedResult>>;
+
+    /// Put the result of executing `portal`. Return without storing it to
+    /// skip caching, for example for statements with side effects.
+    async fn put(&self, portal: &Portal<S>, result: Arc<CachedResult>);
+
+    /// Max number of rows of a cached result, 1000 by default. Rows of larger
+    /// results are not kept in memory for caching.
+    fn max_rows(&self) -> usize {
+        1000
+    }
+}
@@ -0,0 +1,597 @@
+//! Emulation of catalog queries issued by drivers at connect.
+//!
+//! Drivers and tools like psql, JDBC and tokio-postgres send a few catalog
+//! queries right after startup, like `SELECT current_schema()` or `SHOW
+//! transaction_isolation`. `CatalogEmulationHandler` wraps the query handler
+//! of an application, answers these probes with canned responses, and
+//! delegates other queries to the wrapped handler.
+//!
+//! Probes are matched by the whole query text, ignoring case, repeated
+//! whitespaces and the trailing semicolon. Use
+//! `CatalogEmulationHandler::with_probe` to add or override a probe, and
+//! `without_probe` to pass a query to the wrapped handler.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::sink::Sink;
+use futures::stream;
+use postgres_types::Type;
+
+use super::auth::{DEFAULT_SERVER_VERSION, SERVER_VERSION};
+use super::params::ReportedParameters;
+use super::portal::{Format, Portal};
+use super::query::{ExtendedQueryHandler, QueryRewriter, SimpleQueryHandler, StatementOrPortal};
+use super::results::{DataRowEncoder, DescribeResponse, FieldInfo, QueryResponse, Response};
+use super::transaction::TransactionIsolation;
+use super::{ClientInfo, METADATA_DATABASE, METADATA_USER};
+use crate::error::{PgWireError, PgWireResult};
+use crate::messages::extendedquery::{Bind, Close, Parse, Sync as PgSync};
+use crate::messages::PgWireBackendMessage;
+
+/// Rows of a probe response, as text values of each column
+pub type ProbeRows = Vec<Vec<Option<String>>>;
+
+type ProbeFn = dyn Fn(&ProbeContext<'_>) -> PgWireResult<ProbeRows> + Send + Sync;
+
+/// Connection and query information for answering a probe
+pub struct ProbeContext<'a> {
+    metadata: &'a HashMap<String, String>,
+    reported_parameters: Option<&'a ReportedParameters>,
+    isolation: TransactionIsolation,
+    parameters: Vec<(i16, Option<Bytes>)>,
+}
+
+impl<'a> ProbeContext<'a> {
+    fn new<C: ClientInfo>(client: &'a C, parameters: Vec<(i16, Option<Bytes>)>) -> Self {
+        ProbeContext {
+            metadata: client.metadata(),
+            reported_parameters: client.extensions().get::<ReportedParameters>(),
+            isolation: client
+                .extensions()
+                .get::<TransactionIsolation>()
+                .copied()
+                .unwrap_or_default(),
+            parameters,
+        }
+    }
+
+    /// Startup metadata of the connection
+    pub fn metadata(&self) -> &HashMap<String, String> {
+        self.metadata
+    }
+
+    /// Current value of a parameter reported to client
+    pub fn parameter_status(&self, name: &str) -> Option<&str> {
+        self.reported_parameters.and_then(|params| params.get(name))
+    }
+
+    /// Isolation levels of the connection, see `ClientInfo::isolation_level`
+    pub fn isolation(&self) -> &TransactionIsolation {
+        &self.isolation
+    }
+
+    /// Bound parameters of extended query with their format codes, empty for
+    /// simple query
+    pub fn parameters(&self) -> &[(i16, Option<Bytes>)] {
+        &self.parameters
+    }
+
+    /// Get an integer parameter, like an oid, in text or binary format
+    pub fn parameter_as_i64(&self, idx: usize) -> Option<i64> {
+        let (format, value) = self.parameters.get(idx)?;
+        let value = value.as_ref()?;
+        if *format == 0 {
+            std::str::from_utf8(value).ok()?.trim().parse().ok()
+        } else {
+            match value.len() {
+                2 => Some(i16::from_be_bytes([value[0], value[1]]) as i64),
+                4 => Some(i32::from_be_bytes([value[0], value[1], value[2], value[3]]) as i64),
+                8 => Some(i64::from_be_bytes(value[..].try_into().ok()?)),
+                _ => None,
+            }
+        }
+    }
+}
+
+/// A canned response to a catalog query
+#[derive(Clone)]
+pub struct Probe {
+    columns: Vec<(String, Type)>,
+    parameter_types: Vec<Type>,
+    answer: Arc<ProbeFn>,
+}
+
+impl Debug for Probe {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Probe")
+            .field("columns", &self.columns)
+            .field("parameter_types", &self.parameter_types)
+            .finish()
+    }
+}
+
+impl Probe {
+    /// Create probe with result `columns`, answered by `answer`
+    pub fn new<F>(columns: Vec<(&str, Type)>, answer: F) -> Probe
+    where
+        F: Fn(&ProbeContext<'_>) -> PgWireResult<ProbeRows> + Send + Sync + 'static,
+    {
+        Probe {
+            columns: columns
+                .into_iter()
+                .map(|(name, datatype)| (name.to_owned(), datatype))
+                .collect(),
+            parameter_types: Vec::new(),
+            answer: Arc::new(answer),
+        }
+    }
+
+    /// Create probe of one row and one text column, answered by `answer`
+    pub fn single_value<F>(column: &str, answer: F) -> Probe
+    where
+        F: Fn(&ProbeContext<'_>) -> Option<String> + Send + Sync + 'static,
+    {
+        Probe::new(vec![(column, Type::TEXT)], move |ctx| {
+            Ok(vec![vec![answer(ctx)]])
+        })
+    }
+
+    /// Create probe of one row and one text column with a constant value
+    pub fn constant(column: &str, value: &str) -> Probe {
+        let value = value.to_owned();
+        Probe::single_value(column, move |_| Some(value.clone()))
+    }
+
+    /// Set types of parameters, reported in `ParameterDescription`
+    pub fn with_parameter_types(mut self, types: Vec<Type>) -> Probe {
+        self.parameter_types = types;
+        self
+    }
+
+    fn fields(&self, format: &Format) -> Vec<FieldInfo> {
+        self.columns
+            .iter()
+            .enumerate()
+            .map(|(idx, (name, datatype))| {
+                FieldInfo::new(
+                    name.clone(),
+                    None,
+                    None,
+                    datatype.clone(),
+                    format.format_for(idx),
+                )
+            })
+            .collect()
+    }
+
+    fn respond<'a>(&self, ctx: &ProbeContext<'_>, format: &Format) -> PgWireResult<Response<'a>> {
+        let schema = Arc::new(self.fields(format));
+        let rows = (self.answer)(ctx)?
+            .into_iter()
+            .map(|row| {
+                let mut encoder = DataRowEncoder::new(schema.clone());
+                for value in row {
+                    encoder.encode_field(&value)?;
+                }
+                encoder.finish()
+            })
+            .collect::<Vec<_>>();
+        Ok(Response::Query(QueryResponse::new(
+            schema,
+            stream::iter(rows),
+        )))
+    }
+}
+
+/// Normalize query text for matching probes
+fn normalize(query: &str) -> String {
+    query
+        .trim()
+        .trim_end_matches(';')
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+fn default_probes() -> HashMap<String, Probe> {
+    let mut probes = HashMap::new();
+    let mut add = |query: &str, probe: Probe| {
+        probes.insert(normalize(query), probe);
+    };
+
+    add(
+        "SELECT current_schema()",
+        Probe::new(vec![("current_schema", Type::NAME)], |_| {
+            Ok(vec![vec![Some("public".to_owned())]])
+        }),
+    );
+    add(
+        "SELECT current_database()",
+        Probe::new(vec![("current_database", Type::NAME)], |ctx| {
+            let database = ctx
+                .metadata()
+                .get(METADATA_DATABASE)
+                .or_else(|| ctx.metadata().get(METADATA_USER))
+                .cloned();
+            Ok(vec![vec![database]])
+        }),
+    );
+    for user in ["current_user", "session_user"] {
+        add(
+            &format!("SELECT {user}"),
+            Probe::new(vec![(user, Type::NAME)], |ctx| {
+                Ok(vec![vec![ctx.metadata().get(METADATA_USER).cloned()]])
+            }),
+        );
+    }
+    add(
+        "SELECT version()",
+        Probe::single_value("version", |ctx| {
+            let version = ctx
+                .parameter_status(SERVER_VERSION)
+                .unwrap_or(DEFAULT_SERVER_VERSION);
+            Some(format!("PostgreSQL {version}"))
+        }),
+    );
+    for query in [
+        "SHOW transaction_isolation",
+        "SHOW TRANSACTION ISOLATION LEVEL",
+    ] {
+        add(
+            query,
+            Probe::single_value("transaction_isolation", |ctx| {
+                Some(ctx.isolation().current().to_string())
+            }),
+        );
+    }
+    add(
+        "SHOW default_transaction_isolation",
+        Probe::single_value("default_transaction_isolation", |ctx| {
+            Some(ctx.isolation().session().to_string())
+        }),
+    );
+    add(
+        "SHOW search_path",
+        Probe::constant("search_path", "\"$user\", public"),
+    );
+    add(
+        "SELECT typname FROM pg_type WHERE oid = $1",
+        Probe::new(vec![("typname", Type::NAME)], |ctx| {
+            let typname = ctx
+                .parameter_as_i64(0)
+                .and_then(|oid| Type::from_oid(oid as u32))
+                .map(|ty| ty.name().to_owned());
+            Ok(typname.into_iter().map(|name| vec![Some(name)]).collect())
+        })
+        .with_parameter_types(vec![Type::OID]),
+    );
+
+    probes
+}
+
+/// Query handler answering catalog probes of drivers, and delegating other
+/// queries to `inner`.
+///
+/// Besides the probes, `SHOW <parameter>` is answered for parameters reported
+/// to client at startup.
+///
+/// `do_query` and `do_describe` of the wrapped handler are called for
+/// queries other than probes. Other methods of `ExtendedQueryHandler` are
+/// delegated except `on_execute` and `on_describe`, and `on_query` of
+/// `SimpleQueryHandler` is not delegated.
+#[derive(Debug)]
+pub struct CatalogEmulationHandler<H> {
+    inner: Arc<H>,
+    probes: HashMap<String, Probe>,
+}
+
+impl<H> CatalogEmulationHandler<H> {
+    /// Create handler with default probes
+    pub fn new(inner: Arc<H>) -> CatalogEmulationHandler<H> {
+        CatalogEmulationHandler {
+            inner,
+            probes: default_probes(),
+        }
+    }
+
+    /// Answer `query` with `probe`, replacing the default probe of the query
+    pub fn with_probe(mut self, query: &str, probe: Probe) -> CatalogEmulationHandler<H> {
+        self.probes.insert(normalize(query), probe);
+        self
+    }
+
+    /// Pass `query` to the wrapped handler
+    pub fn without_probe(mut self, query: &str) -> CatalogEmulationHandler<H> {
+        self.probes.remove(&normalize(query));
+        self
+    }
+
+    /// The wrapped handler
+    pub fn inner(&self) -> &Arc<H> {
+        &self.inner
+    }
+
+    fn find_probe<C: ClientInfo>(&self, client: &C, query: &str) -> Option<Probe> {
+        let query = normalize(query);
+        if let Some(probe) = self.probes.get(&query) {
+            return Some(probe.clone());
+        }
+
+        // SHOW of a reported parameter
+        let name = query.strip_prefix("show ")?;
+        let params = client.extensions().get::<ReportedParameters>()?;
+        params.get(name)?;
+        let name = name.to_owned();
+        Some(Probe::single_value(&name.clone(), move |ctx| {
+            ctx.parameter_status(&name).map(ToOwned::to_owned)
+        }))
+    }
+}
+
+#[async_trait]
+impl<H: SimpleQueryHandler> SimpleQueryHandler for CatalogEmulationHandler<H> {
+    fn query_rewriter(&self) -> Option<&dyn QueryRewriter> {
+        self.inner.query_rewriter()
+    }
+
+    fn detect_transaction_control(&self) -> bool {
+        self.inner.detect_transaction_control()
+    }
+
+    async fn do_query<'a, 'b: 'a, C>(
+        &'b self,
+        client: &C,
+        query: &'a str,
+    ) -> PgWireResult<Vec<Response<'a>>>
+    where
+        C: ClientInfo + Unpin + Send + Sync,
+    {
+        if let Some(probe) = self.find_probe(client, query) {
+            let ctx = ProbeContext::new(client, Vec::new());
+            Ok(vec![probe.respond(&ctx, &Format::UnifiedText)?])
+        } else {
+            self.inner.do_query(client, query).await
+        }
+    }
+}
+
+#[async_trait]
+impl<H> ExtendedQueryHandler for CatalogEmulationHandler<H>
+where
+    H: ExtendedQueryHandler,
+    H::Statement: AsRef<str>,
+{
+    type Statement = H::Statement;
+    type QueryParser = H::QueryParser;
+    type PortalStore = H::PortalStore;
+
+    fn portal_store(&self) -> Arc<Self::PortalStore> {
+        self.inner.portal_store()
+    }
+
+    fn query_parser(&self) -> Arc<Self::QueryParser> {
+        self.inner.query_parser()
+    }
+
+    fn detect_transaction_control(&self) -> bool {
+        self.inner.detect_transaction_control()
+    }
+
+    async fn on_parse<C>(&self, client: &mut C, message: Parse) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.inner.on_parse(client, message).await
+    }
+
+    async fn on_bind<C>(&self, client: &mut C, message: Bind) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.inner.on_bind(client, message).await
+    }
+
+    async fn on_sync<C>(&self, client: &mut C, message: PgSync) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.inner.on_sync(client, message).await
+    }
+
+    async fn on_close<C>(&self, client: &mut C, message: Close) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.inner.on_close(client, message).await
+    }
+
+    async fn do_describe<C>(
+        &self,
+        client: &mut C,
+        target: StatementOrPortal<'_, Self::Statement>,
+    ) -> PgWireResult<DescribeResponse>
+    where
+        C: ClientInfo + Unpin + Send + Sync,
+    {
+        let query = match &target {
+            StatementOrPortal::Statement(stmt) => stmt.statement().as_ref(),
+            StatementOrPortal::Portal(portal) => portal.statement().statement().as_ref(),
+        };
+        if let Some(probe) = self.find_probe(client, query) {
+            let fields = probe.fields(&Format::UnifiedText);
+            Ok(match target {
+                StatementOrPortal::Statement(_) => {
+                    DescribeResponse::statement(probe.parameter_types.clone(), fields)
+                }
+                StatementOrPortal::Portal(_) => DescribeResponse::portal(fields),
+            })
+        } else {
+            self.inner.do_describe(client, target).await
+        }
+    }
+
+    async fn do_query<'a, 'b: 'a, C>(
+        &'b self,
+        client: &mut C,
+        portal: &'a Portal<Self::Statement>,
+        max_rows: usize,
+    ) -> PgWireResult<Response<'a>>
+    where
+        C: ClientInfo + Unpin + Send + Sync,
+    {
+        if let Some(probe) = self.find_probe(client, portal.statement().statement().as_ref()) {
+            let ctx = ProbeContext::new(client, portal.raw_parameters());
+            probe.respond(&ctx, portal.result_column_format())
+        } else {
+            self.inner.do_query(client, portal, max_rows).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::auth::{finish_authentication, DefaultServerParameterProvider};
+    use crate::api::mock::MockClient;
+    use crate::api::query::PlaceholderExtendedQueryHandler;
+    use futures::StreamExt;
+
+    use crate::api::results::{QueryResponseItem, Tag};
+    use crate::api::stmt::StoredStatement;
+    use crate::messages::simplequery::Query;
+
+    struct AppQueryHandler;
+
+    #[async_trait]
+    impl SimpleQueryHandler for AppQueryHandler {
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &C,
+            _query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            Ok(vec![Response::Execution(Tag::new_for_execution(
+                "APP", None,
+            ))])
+        }
+    }
+
+    async fn query(handler: &CatalogEmulationHandler<AppQueryHandler>, sql: &str) -> Vec<String> {
+        let mut client = MockClient::new();
+        client
+            .metadata_mut()
+            .insert(METADATA_USER.to_owned(), "alice".to_owned());
+        finish_authentication(&mut client, &DefaultServerParameterProvider)
+            .await
+            .unwrap();
+        client.take_messages();
+
+        handler
+            .on_query(&mut client, Query::new(sql.to_owned()))
+            .await
+            .unwrap();
+        client
+            .take_messages()
+            .into_iter()
+            .filter_map(|msg| match msg {
+                PgWireBackendMessage::DataRow(row) => {
+                    Some(String::from_utf8(row.fields()[0].clone().unwrap().to_vec()).unwrap())
+                }
+                PgWireBackendMessage::CommandComplete(cc) => Some(cc.tag().clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_catalog_probes() {
+        let handler = CatalogEmulationHandler::new(Arc::new(AppQueryHandler));
+        assert_eq!(
+            vec!["public", "SELECT 1"],
+            query(&handler, "select  CURRENT_SCHEMA();").await
+        );
+        assert_eq!(
+            vec!["alice", "SELECT 1"],
+            query(&handler, "SELECT current_user").await
+        );
+        // reported parameter
+        assert_eq!(
+            vec!["ISO YMD", "SELECT 1"],
+            query(&handler, "SHOW DateStyle").await
+        );
+        assert_eq!(
+            vec!["160000", "SELECT 1"],
+            query(&handler, "SHOW server_version_num").await
+        );
+        assert_eq!(
+            vec!["read committed", "SELECT 1"],
+            query(&handler, "show transaction isolation level").await
+        );
+        // delegated
+        assert_eq!(vec!["APP"], query(&handler, "SHOW work_mem").await);
+        assert_eq!(vec!["APP"], query(&handler, "SELECT 1").await);
+
+        let handler = CatalogEmulationHandler::new(Arc::new(AppQueryHandler))
+            .with_probe(
+                "SELECT current_schema()",
+                Probe::new(vec![("current_schema", Type::NAME)], |_| {
+                    Ok(vec![vec![Some("app".to_owned())]])
+                }),
+            )
+            .without_probe("SELECT current_user");
+        assert_eq!(
+            vec!["app", "SELECT 1"],
+            query(&handler, "SELECT current_schema()").await
+        );
+        assert_eq!(vec!["APP"], query(&handler, "SELECT current_user").await);
+    }
+
+    #[tokio::test]
+    async fn test_extended_catalog_probe() {
+        let handler = CatalogEmulationHandler::new(Arc::new(PlaceholderExtendedQueryHandler));
+        let mut client = MockClient::new();
+        let statement = Arc::new(StoredStatement::new(
+            "probe".to_owned(),
+            "SELECT typname FROM pg_type WHERE oid = $1".to_owned(),
+            vec![],
+        ));
+
+        let describe = handler
+            .do_describe(&mut client, StatementOrPortal::Statement(&statement))
+            .await
+            .unwrap();
+        assert_eq!(Some(&vec![Type::OID]), describe.parameters().as_ref());
+        assert_eq!(&Type::NAME, describe.fields()[0].datatype());
+
+        for (format, value) in [(0, Bytes::from("23")), (1, Bytes::from(vec![0, 0, 0, 23]))] {
+            let bind = Bind::new(None, None, vec![format], vec![Some(value)], vec![]);
+            let portal = Portal::try_new(&bind, statement.clone()).unwrap();
+            let response = handler.do_query(&mut client, &portal, 0).await.unwrap();
+            let items = match response {
+                Response::Query(response) => response.data_rows.collect::<Vec<_>>().await,
+                _ => panic!("unexpected response"),
+            };
+            match items.as_slice() {
+                [Ok(QueryResponseItem::Row(row))] => {
+                    assert_eq!(Some(Bytes::from("int4")), row.fields()[0])
+                }
+                _ => panic!("unexpected rows {items:?}"),
+            };
+        }
+    }
+}
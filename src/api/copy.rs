@@ -0,0 +1,594 @@
+//! Handling and parsing data of `COPY ... FROM STDIN`.
+//!
+//! Clients send `COPY` data as a sequence of `CopyData` chunks, and a record
+//! can be split into any number of chunks. `CopyHandler` receives the chunks
+//! as they arrive. `CopyRowParser` accepts chunks and yields complete rows.
+//! `copy_rows` wraps it as a `Stream`.
+
+use std::collections::VecDeque;
+use std::fmt::Debug;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::sink::Sink;
+use futures::stream::{self, Stream, StreamExt};
+
+use super::results::Tag;
+use super::ClientInfo;
+use crate::error::{ErrorInfo, PgWireError, PgWireResult, Severity, SqlState};
+use crate::messages::copy::{CopyData, CopyDone, CopyFail};
+use crate::messages::PgWireBackendMessage;
+
+/// Handler for data of `COPY FROM STDIN`, which is started by a
+/// `Response::CopyIn` from query handlers.
+///
+/// Each `CopyData` is passed to `on_copy_data` as is. Clients split data
+/// at arbitrary positions, so a row, or even a field, can span many frames,
+/// and a frame can contain many rows. Feed the data to a `CopyRowParser` to
+/// reassemble rows.
+///
+/// An error from the handler aborts the copy, and the connection stays
+/// usable. The error is sent to client right away, so it stops sending, and
+/// is followed by `ReadyForQuery`, or by `ReadyForQuery` on the next `Sync`
+/// for a copy started from extended query. Copy messages the client sent
+/// before it saw the error are discarded. Parse errors of `CopyRowParser`
+/// are reported as `22P04` and `22021`.
+#[async_trait]
+pub trait CopyHandler: Send + Sync {
+    /// Called for each `CopyData` frame received
+    async fn on_copy_data<C>(&self, client: &mut C, copy_data: CopyData) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>;
+
+    /// Called when all data received. Returns tag of the command, usually
+    /// `COPY` with number of rows copied.
+    async fn on_copy_done<C>(&self, client: &mut C, copy_done: CopyDone) -> PgWireResult<Tag>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>;
+
+    /// Called when client aborted the copy, for example to clean up data
+    /// received. Error `57014` is sent to client afterwards.
+    async fn on_copy_fail<C>(&self, _client: &mut C, _copy_fail: CopyFail) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        Ok(())
+    }
+}
+
+/// A copy handler for servers which never start `COPY FROM STDIN`. It rejects
+/// any copy data.
+#[derive(Debug, Clone)]
+pub struct NoopCopyHandler;
+
+impl NoopCopyHandler {
+    fn not_supported() -> PgWireError {
+        PgWireError::UserError(Box::new(ErrorInfo::new(
+            Severity::Error,
+            SqlState::FeatureNotSupported,
+            "COPY FROM STDIN is not supported",
+        )))
+    }
+}
+
+#[async_trait]
+impl CopyHandler for NoopCopyHandler {
+    async fn on_copy_data<C>(&self, _client: &mut C, _copy_data: CopyData) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        Err(Self::not_supported())
+    }
+
+    async fn on_copy_done<C>(&self, _client: &mut C, _copy_done: CopyDone) -> PgWireResult<Tag>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        Err(Self::not_supported())
+    }
+}
+
+/// Options of `FORMAT text`, the default format of `COPY`.
+#[derive(Debug, Clone, new, Getters, Setters)]
+#[getset(get = "pub", set = "pub")]
+pub struct CopyTextOptions {
+    /// field delimiter, tab by default
+    #[new(value = "b'\\t'")]
+    delimiter: u8,
+    /// string representing null value, `\N` by default
+    #[new(value = "\"\\\\N\".to_owned()")]
+    null: String,
+}
+
+impl Default for CopyTextOptions {
+    fn default() -> Self {
+        CopyTextOptions::new()
+    }
+}
+
+/// Options of `FORMAT csv`.
+#[derive(Debug, Clone, new, Getters, Setters)]
+#[getset(get = "pub", set = "pub")]
+pub struct CopyCsvOptions {
+    /// field delimiter, `,` by default
+    #[new(value = "b','")]
+    delimiter: u8,
+    /// quote character, `"` by default
+    #[new(value = "b'\"'")]
+    quote: u8,
+    /// escape character inside quoted value, same as `quote` by default
+    #[new(value = "b'\"'")]
+    escape: u8,
+    /// string representing null value, an unquoted empty string by default
+    #[new(default)]
+    null: String,
+    /// skip the first line as header
+    #[new(default)]
+    header: bool,
+}
+
+impl Default for CopyCsvOptions {
+    fn default() -> Self {
+        CopyCsvOptions::new()
+    }
+}
+
+/// Format of `COPY` data
+#[derive(Debug, Clone)]
+pub enum CopyFormat {
+    Text(CopyTextOptions),
+    Csv(CopyCsvOptions),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ParserState {
+    Normal,
+    // text: after a backslash
+    Backslash,
+    // csv: inside quoted value
+    Quoted,
+    // csv: quote found inside quoted value, when quote and escape are the same
+    // we need next byte to tell if it's an escaped quote
+    QuoteInQuoted,
+    // csv: escape found inside quoted value
+    EscapeInQuoted,
+    // after `\r`, skip the `\n` that follows
+    CarriageReturn,
+}
+
+/// Incremental parser of `COPY` data in text or csv format.
+///
+/// Feed the parser with chunks of data via `feed`, take parsed rows with
+/// `next_row`, and call `finish` when all data received. Each row is a list of
+/// fields, `None` for null.
+#[derive(Debug)]
+pub struct CopyRowParser {
+    format: CopyFormat,
+    state: ParserState,
+    field: Vec<u8>,
+    field_quoted: bool,
+    line_started: bool,
+    row: Vec<Option<String>>,
+    rows: VecDeque<Vec<Option<String>>>,
+    skip_header: bool,
+    // end-of-data marker `\.` received
+    ended: bool,
+}
+
+impl CopyRowParser {
+    pub fn new(format: CopyFormat) -> CopyRowParser {
+        let skip_header = matches!(format, CopyFormat::Csv(ref options) if options.header);
+        CopyRowParser {
+            format,
+            state: ParserState::Normal,
+            field: Vec::new(),
+            field_quoted: false,
+            line_started: false,
+            row: Vec::new(),
+            rows: VecDeque::new(),
+            skip_header,
+            ended: false,
+        }
+    }
+
+    /// Parse a chunk of data. Rows completed by this chunk are available from
+    /// `next_row`.
+    pub fn feed(&mut self, data: &[u8]) -> PgWireResult<()> {
+        for b in data {
+            if self.ended {
+                break;
+            }
+            self.line_started = true;
+            match self.format {
+                CopyFormat::Text(_) => self.feed_text(*b)?,
+                CopyFormat::Csv(_) => self.feed_csv(*b)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Finish parsing, the last line may come without line break.
+    pub fn finish(&mut self) -> PgWireResult<()> {
+        match self.state {
+            ParserState::Quoted | ParserState::EscapeInQuoted => {
+                return Err(PgWireError::InvalidCopyData(
+                    "unterminated CSV quoted field".to_owned(),
+                ));
+            }
+            ParserState::Backslash => {
+                return Err(PgWireError::InvalidCopyData(
+                    "end of data after backslash".to_owned(),
+                ));
+            }
+            _ => {}
+        }
+        self.state = ParserState::Normal;
+
+        if self.line_started && !self.ended {
+            self.end_line()?;
+        }
+        self.ended = true;
+        Ok(())
+    }
+
+    /// Take the next parsed row
+    pub fn next_row(&mut self) -> Option<Vec<Option<String>>> {
+        self.rows.pop_front()
+    }
+
+    fn feed_text(&mut self, b: u8) -> PgWireResult<()> {
+        let delimiter = match self.format {
+            CopyFormat::Text(ref options) => options.delimiter,
+            _ => unreachable!(),
+        };
+        match self.state {
+            ParserState::Backslash => {
+                // raw bytes are kept until the end of the field, so null
+                // string can be compared before unescaping
+                self.field.push(b);
+                self.state = ParserState::Normal;
+            }
+            ParserState::CarriageReturn if b == b'\n' => {
+                self.state = ParserState::Normal;
+                self.line_started = false;
+            }
+            _ => {
+                self.state = ParserState::Normal;
+                if b == b'\\' {
+                    self.field.push(b);
+                    self.state = ParserState::Backslash;
+                } else if b == delimiter {
+                    self.end_field()?;
+                } else if b == b'\n' {
+                    self.end_line()?;
+                } else if b == b'\r' {
+                    self.end_line()?;
+                    self.state = ParserState::CarriageReturn;
+                } else {
+                    self.field.push(b);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn feed_csv(&mut self, b: u8) -> PgWireResult<()> {
+        let (delimiter, quote, escape) = match self.format {
+            CopyFormat::Csv(ref options) => (options.delimiter, options.quote, options.escape),
+            _ => unreachable!(),
+        };
+        match self.state {
+            ParserState::Quoted => {
+                if b == escape && escape != quote {
+                    self.state = ParserState::EscapeInQuoted;
+                } else if b == quote {
+                    if escape == quote {
+                        self.state = ParserState::QuoteInQuoted;
+                    } else {
+                        self.state = ParserState::Normal;
+                    }
+                } else {
+                    self.field.push(b);
+                }
+            }
+            ParserState::EscapeInQuoted => {
+                if b != quote && b != escape {
+                    self.field.push(escape);
+                }
+                self.field.push(b);
+                self.state = ParserState::Quoted;
+            }
+            ParserState::QuoteInQuoted if b == quote => {
+                self.field.push(b);
+                self.state = ParserState::Quoted;
+            }
+            ParserState::CarriageReturn if b == b'\n' => {
+                self.state = ParserState::Normal;
+                self.line_started = false;
+            }
+            _ => {
+                self.state = ParserState::Normal;
+                if b == quote {
+                    self.field_quoted = true;
+                    self.state = ParserState::Quoted;
+                } else if b == delimiter {
+                    self.end_field()?;
+                } else if b == b'\n' {
+                    self.end_line()?;
+                } else if b == b'\r' {
+                    self.end_line()?;
+                    self.state = ParserState::CarriageReturn;
+                } else {
+                    self.field.push(b);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn end_field(&mut self) -> PgWireResult<()> {
+        let raw = std::mem::take(&mut self.field);
+        let quoted = std::mem::replace(&mut self.field_quoted, false);
+
+        let value = match self.format {
+            CopyFormat::Text(ref options) => {
+                if raw == options.null.as_bytes() {
+                    None
+                } else {
+                    Some(unescape_text(&raw))
+                }
+            }
+            CopyFormat::Csv(ref options) => {
+                if !quoted && raw == options.null.as_bytes() {
+                    None
+                } else {
+                    Some(raw)
+                }
+            }
+        };
+
+        let value = value
+            .map(String::from_utf8)
+            .transpose()
+            .map_err(PgWireError::InvalidUtf8String)?;
+        self.row.push(value);
+        Ok(())
+    }
+
+    fn end_line(&mut self) -> PgWireResult<()> {
+        self.line_started = false;
+        if self.row.is_empty() && !self.field_quoted && self.field == b"\\." {
+            self.field.clear();
+            self.ended = true;
+            return Ok(());
+        }
+
+        self.end_field()?;
+        let row = std::mem::take(&mut self.row);
+        if self.skip_header {
+            self.skip_header = false;
+        } else {
+            self.rows.push_back(row);
+        }
+        Ok(())
+    }
+}
+
+/// Decode backslash escapes of text format
+fn unescape_text(raw: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(raw.len());
+    let mut iter = raw.iter().copied().peekable();
+    while let Some(b) = iter.next() {
+        if b != b'\\' {
+            result.push(b);
+            continue;
+        }
+        let c = match iter.next() {
+            Some(c) => c,
+            None => {
+                result.push(b);
+                break;
+            }
+        };
+        match c {
+            b'b' => result.push(0x08),
+            b'f' => result.push(0x0c),
+            b'n' => result.push(b'\n'),
+            b'r' => result.push(b'\r'),
+            b't' => result.push(b'\t'),
+            b'v' => result.push(0x0b),
+            b'0'..=b'7' => {
+                let mut value = c - b'0';
+                for _ in 0..2 {
+                    match iter.peek() {
+                        Some(d @ b'0'..=b'7') => {
+                            value = value.wrapping_mul(8).wrapping_add(d - b'0');
+                            iter.next();
+                        }
+                        _ => break,
+                    }
+                }
+                result.push(value);
+            }
+            b'x' if iter.peek().map_or(false, u8::is_ascii_hexdigit) => {
+                let mut value = 0u8;
+                for _ in 0..2 {
+                    match iter.peek().and_then(|d| (*d as char).to_digit(16)) {
+                        Some(d) => {
+                            value = value * 16 + d as u8;
+                            iter.next();
+                        }
+                        None => break,
+                    }
+                }
+                result.push(value);
+            }
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/// Parse stream of `COPY` data chunks into stream of rows.
+///
+/// The stream ends after an error, either from the input or from parsing.
+pub fn copy_rows<S>(
+    data: S,
+    format: CopyFormat,
+) -> impl Stream<Item = PgWireResult<Vec<Option<String>>>>
+where
+    S: Stream<Item = PgWireResult<Bytes>> + Unpin,
+{
+    let parser = CopyRowParser::new(format);
+    stream::unfold(
+        (data, parser, None, false),
+        |(mut data, mut parser, mut error, mut done)| async move {
+            loop {
+                if let Some(row) = parser.next_row() {
+                    return Some((Ok(row), (data, parser, error, done)));
+                }
+                if let Some(e) = error.take() {
+                    return Some((Err(e), (data, parser, None, true)));
+                }
+                if done {
+                    return None;
+                }
+
+                let result = match data.next().await {
+                    Some(Ok(chunk)) => parser.feed(&chunk),
+                    Some(Err(e)) => Err(e),
+                    None => {
+                        done = true;
+                        parser.finish()
+                    }
+                };
+                if let Err(e) = result {
+                    error = Some(e);
+                }
+            }
+        },
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use futures::TryStreamExt;
+
+    use super::*;
+
+    fn parse_in_chunks(
+        format: CopyFormat,
+        data: &[u8],
+        chunk_size: usize,
+    ) -> Vec<Vec<Option<String>>> {
+        let mut parser = CopyRowParser::new(format);
+        for chunk in data.chunks(chunk_size) {
+            parser.feed(chunk).unwrap();
+        }
+        parser.finish().unwrap();
+
+        let mut rows = Vec::new();
+        while let Some(row) = parser.next_row() {
+            rows.push(row);
+        }
+        rows
+    }
+
+    fn row(fields: &[Option<&str>]) -> Vec<Option<String>> {
+        fields.iter().map(|f| f.map(ToOwned::to_owned)).collect()
+    }
+
+    #[test]
+    fn test_csv() {
+        let data = b"id,name,note\r\n1,\"Tom, Jr.\",\"say \"\"hi\"\"\nbye\"\n2,,\"\"\n";
+        let mut options = CopyCsvOptions::new();
+        options.set_header(true);
+
+        let expected = vec![
+            row(&[Some("1"), Some("Tom, Jr."), Some("say \"hi\"\nbye")]),
+            row(&[Some("2"), None, Some("")]),
+        ];
+        // records split at every possible boundary
+        for chunk_size in 1..=data.len() {
+            assert_eq!(
+                expected,
+                parse_in_chunks(CopyFormat::Csv(options.clone()), data, chunk_size)
+            );
+        }
+    }
+
+    #[test]
+    fn test_csv_options() {
+        let mut options = CopyCsvOptions::new();
+        options.set_delimiter(b'|');
+        options.set_quote(b'\'');
+        options.set_escape(b'\\');
+        options.set_null("NULL".to_owned());
+
+        let data = b"'it\\'s'|NULL|'NULL'\n\\.\nignored";
+        assert_eq!(
+            vec![row(&[Some("it's"), None, Some("NULL")])],
+            parse_in_chunks(CopyFormat::Csv(options), data, 3)
+        );
+    }
+
+    #[test]
+    fn test_text() {
+        let data = b"1\tline\\nbreak\t\\N\n2\ttab\\there\\\\\t\\x41\\101\n3\t\t\\.";
+        let expected = vec![
+            row(&[Some("1"), Some("line\nbreak"), None]),
+            row(&[Some("2"), Some("tab\there\\"), Some("AA")]),
+            row(&[Some("3"), Some(""), Some(".")]),
+        ];
+        for chunk_size in 1..=data.len() {
+            assert_eq!(
+                expected,
+                parse_in_chunks(CopyFormat::Text(CopyTextOptions::new()), data, chunk_size)
+            );
+        }
+    }
+
+    #[test]
+    fn test_unterminated_quote() {
+        let mut parser = CopyRowParser::new(CopyFormat::Csv(CopyCsvOptions::new()));
+        parser.feed(b"1,\"abc\n").unwrap();
+        assert!(matches!(
+            parser.finish(),
+            Err(PgWireError::InvalidCopyData(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_copy_rows_stream() {
+        let chunks = vec![
+            Ok(Bytes::from_static(b"1,a\n2,")),
+            Ok(Bytes::from_static(b"b\n3,\"c")),
+        ];
+        let rows = copy_rows(stream::iter(chunks), CopyFormat::Csv(CopyCsvOptions::new()));
+        let result: Vec<PgWireResult<Vec<Option<String>>>> = rows.collect().await;
+
+        assert_eq!(3, result.len());
+        assert_eq!(row(&[Some("1"), Some("a")]), *result[0].as_ref().unwrap());
+        assert_eq!(row(&[Some("2"), Some("b")]), *result[1].as_ref().unwrap());
+        assert!(result[2].is_err());
+
+        let rows: Vec<Vec<Option<String>>> = copy_rows(
+            stream::iter(vec![Ok(Bytes::from_static(b"x\ty\n"))]),
+            CopyFormat::Text(CopyTextOptions::new()),
+        )
+        .try_collect()
+        .await
+        .unwrap();
+        assert_eq!(vec![row(&[Some("x"), Some("y")])], rows);
+    }
+}
@@ -0,0 +1,394 @@
+//! Adapter from paginated cursors to streams of data rows, and SQL cursors
+//! declared on a connection.
+//!
+//! `DeclaredCursors` keeps result streams of cursors declared with
+//! `DECLARE`, for handlers emulating server side cursors. Parsing of the
+//! cursor statements is up to the handler, which then drives the store and
+//! responds with the returned tags:
+//!
+//! * `DECLARE c CURSOR FOR SELECT ...`: `declare("c", response)` with the
+//!   response of the query, completed with `DECLARE CURSOR`.
+//! * `FETCH 10 FROM c`: `fetch("c", Some(10))`, a response of the next rows,
+//!   completed with `FETCH <rows>`. `None` for `FETCH ALL`.
+//! * `MOVE 10 IN c`: `advance("c", Some(10))`, skipping rows, `MOVE <rows>`.
+//! * `CLOSE c` and `CLOSE ALL`: `close("c")` and `close_all()`.
+//!
+//! Cursors only move forward, like cursors declared with `NO SCROLL`. Cursors
+//! without `WITH HOLD` are closed at the end of their transaction in
+//! postgres, call `close` or `close_all` on `COMMIT` and `ROLLBACK` for them.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use futures::future::Future;
+use futures::stream::{self, BoxStream, Fuse, Stream, StreamExt};
+
+use super::results::{FieldInfo, QueryResponse, QueryResponseItem, Tag};
+use crate::error::{ErrorInfo, PgWireError, PgWireResult, Severity, SqlState};
+use crate::messages::data::DataRow;
+
+/// A stream of `DataRow` backed by a cursor that fetches rows by page.
+///
+/// `fetch_page(offset, limit)` is called for the next page only after all
+/// rows of the previous page have been polled, so no more than a page is
+/// buffered when the client reads slowly. The stream ends when a page has
+/// fewer rows than `page_size`, or on the first error.
+///
+/// Use it as the row stream of `QueryResponse`. Paging is independent of
+/// `max_rows` of `Execute`. Make the response `suspendable` when the cursor
+/// owns its state, so that a portal suspended at `max_rows` keeps fetching
+/// pages as its rows are sent, instead of reading the rest of them into
+/// memory.
+pub struct CursorStream<'a> {
+    inner: BoxStream<'a, PgWireResult<DataRow>>,
+}
+
+struct CursorState<F, E, R> {
+    fetch_page: F,
+    encode_row: E,
+    page_size: usize,
+    offset: usize,
+    buffer: VecDeque<R>,
+    exhausted: bool,
+}
+
+impl<'a> CursorStream<'a> {
+    pub fn new<F, Fut, R, E>(page_size: usize, fetch_page: F, encode_row: E) -> CursorStream<'a>
+    where
+        F: FnMut(usize, usize) -> Fut + Send + 'a,
+        Fut: Future<Output = PgWireResult<Vec<R>>> + Send + 'a,
+        R: Send + 'a,
+        E: FnMut(R) -> PgWireResult<DataRow> + Send + 'a,
+    {
+        let state = CursorState {
+            fetch_page,
+            encode_row,
+            page_size: page_size.max(1),
+            offset: 0,
+            buffer: VecDeque::new(),
+            exhausted: false,
+        };
+        let inner = stream::unfold(Some(state), |state| async move {
+            let mut state = state?;
+            if state.buffer.is_empty() && !state.exhausted {
+                match (state.fetch_page)(state.offset, state.page_size).await {
+                    Ok(page) => {
+                        state.exhausted = page.len() < state.page_size;
+                        state.offset += page.len();
+                        state.buffer.extend(page);
+                    }
+                    Err(e) => return Some((Err(e), None)),
+                }
+            }
+
+            let row = state.buffer.pop_front()?;
+            match (state.encode_row)(row) {
+                Ok(row) => Some((Ok(row), Some(state))),
+                Err(e) => Some((Err(e), None)),
+            }
+        })
+        .boxed();
+
+        CursorStream { inner }
+    }
+}
+
+impl<'a> Stream for CursorStream<'a> {
+    type Item = PgWireResult<DataRow>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
+    }
+}
+
+/// Cursors declared on a connection, with the remaining rows of each.
+///
+/// Keep one per connection, for example in a handler made per connection,
+/// as cursor names are local to a session.
+#[derive(Default)]
+pub struct DeclaredCursors {
+    cursors: Mutex<HashMap<String, DeclaredCursor>>,
+}
+
+struct DeclaredCursor {
+    row_schema: Arc<Vec<FieldInfo>>,
+    /// fused, as exhausted cursors are still read from
+    rows: Fuse<BoxStream<'static, PgWireResult<QueryResponseItem>>>,
+}
+
+impl fmt::Debug for DeclaredCursors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cursors = self.cursors.lock().unwrap();
+        f.debug_set().entries(cursors.keys()).finish()
+    }
+}
+
+impl DeclaredCursors {
+    pub fn new() -> DeclaredCursors {
+        DeclaredCursors::default()
+    }
+
+    /// Whether cursor `name` is declared
+    pub fn contains(&self, name: &str) -> bool {
+        self.cursors.lock().unwrap().contains_key(name)
+    }
+
+    /// Declare cursor `name` over rows of `response`. Fails with `42P03`
+    /// when the cursor already exists.
+    pub fn declare(&self, name: &str, response: QueryResponse<'static>) -> PgWireResult<Tag> {
+        let mut cursors = self.cursors.lock().unwrap();
+        if cursors.contains_key(name) {
+            return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                Severity::Error,
+                SqlState::DuplicateCursor,
+                format!("cursor \"{name}\" already exists"),
+            ))));
+        }
+        cursors.insert(
+            name.to_owned(),
+            DeclaredCursor {
+                row_schema: response.row_schema,
+                rows: response.data_rows.boxed().fuse(),
+            },
+        );
+        Ok(Tag::new_for_declare_cursor())
+    }
+
+    /// Fetch next `count` rows of cursor `name`, or all remaining rows for
+    /// `None`.
+    ///
+    /// The rows are read from the cursor before the response is returned.
+    /// Notices between them are kept, and not counted in `FETCH <rows>`. On
+    /// an error of the row stream, the cursor is closed.
+    pub async fn fetch(
+        &self,
+        name: &str,
+        count: Option<usize>,
+    ) -> PgWireResult<QueryResponse<'static>> {
+        let (row_schema, items, rows) = self.read(name, count, true).await?;
+        Ok(
+            QueryResponse::with_notices(row_schema, stream::iter(items.into_iter().map(Ok)))
+                .with_command_tag(Tag::new_for_fetch(rows as u64)),
+        )
+    }
+
+    /// Skip next `count` rows of cursor `name`, or all remaining rows for
+    /// `None`, as by `MOVE`.
+    pub async fn advance(&self, name: &str, count: Option<usize>) -> PgWireResult<Tag> {
+        let (_, _, rows) = self.read(name, count, false).await?;
+        Ok(Tag::new_for_move(rows as u64))
+    }
+
+    /// Close cursor `name`. Fails with `34000` when it doesn't exist.
+    pub fn close(&self, name: &str) -> PgWireResult<Tag> {
+        self.cursors
+            .lock()
+            .unwrap()
+            .remove(name)
+            .ok_or_else(|| cursor_not_found(name))?;
+        Ok(Tag::new_for_close_cursor(false))
+    }
+
+    /// Close all cursors, as by `CLOSE ALL`
+    pub fn close_all(&self) -> Tag {
+        self.cursors.lock().unwrap().clear();
+        Tag::new_for_close_cursor(true)
+    }
+
+    async fn read(
+        &self,
+        name: &str,
+        count: Option<usize>,
+        keep_items: bool,
+    ) -> PgWireResult<(Arc<Vec<FieldInfo>>, Vec<QueryResponseItem>, usize)> {
+        // taken out of the map, so that the lock isn't held across reads
+        let mut cursor = self
+            .cursors
+            .lock()
+            .unwrap()
+            .remove(name)
+            .ok_or_else(|| cursor_not_found(name))?;
+
+        let count = count.unwrap_or(usize::MAX);
+        let mut items = Vec::new();
+        let mut rows = 0;
+        while rows < count {
+            match cursor.rows.next().await {
+                Some(Ok(item)) => {
+                    if let QueryResponseItem::Row(_) = item {
+                        rows += 1;
+                    }
+                    if keep_items {
+                        items.push(item);
+                    }
+                }
+                Some(Err(e)) => return Err(e),
+                None => break,
+            }
+        }
+
+        let row_schema = cursor.row_schema.clone();
+        self.cursors.lock().unwrap().insert(name.to_owned(), cursor);
+        Ok((row_schema, items, rows))
+    }
+}
+
+fn cursor_not_found(name: &str) -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        Severity::Error,
+        SqlState::InvalidCursorName,
+        format!("cursor \"{name}\" does not exist"),
+    )))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::messages::response::CommandComplete;
+
+    fn cursor(
+        total: usize,
+        page_size: usize,
+        fetches: Arc<Mutex<Vec<(usize, usize)>>>,
+    ) -> CursorStream<'static> {
+        CursorStream::new(
+            page_size,
+            move |offset, limit| {
+                fetches.lock().unwrap().push((offset, limit));
+                async move { Ok((offset..total.min(offset + limit)).collect::<Vec<usize>>()) }
+            },
+            |v| Ok(DataRow::new(vec![Some(Bytes::from(v.to_string()))])),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_cursor_stream() {
+        let fetches = Arc::new(Mutex::new(Vec::new()));
+        let rows = cursor(7, 3, fetches.clone())
+            .map(|row| row.unwrap().fields()[0].clone().unwrap())
+            .collect::<Vec<Bytes>>()
+            .await;
+        assert_eq!(
+            (0..7)
+                .map(|v| Bytes::from(v.to_string()))
+                .collect::<Vec<_>>(),
+            rows
+        );
+        assert_eq!(vec![(0, 3), (3, 3), (6, 3)], *fetches.lock().unwrap());
+
+        // pages are fetched on demand
+        let fetches = Arc::new(Mutex::new(Vec::new()));
+        let rows = cursor(7, 3, fetches.clone())
+            .take(2)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(2, rows.len());
+        assert_eq!(vec![(0, 3)], *fetches.lock().unwrap());
+
+        // an exact multiple of page size ends with an empty page
+        let fetches = Arc::new(Mutex::new(Vec::new()));
+        assert_eq!(6, cursor(6, 3, fetches.clone()).count().await);
+        assert_eq!(vec![(0, 3), (3, 3), (6, 3)], *fetches.lock().unwrap());
+    }
+
+    fn error_code<T>(result: PgWireResult<T>) -> String {
+        match result {
+            Err(PgWireError::UserError(error)) => error.code().clone(),
+            Err(e) => panic!("unexpected error {e:?}"),
+            Ok(_) => panic!("unexpected success"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_declared_cursors() {
+        let cursors = DeclaredCursors::new();
+        let response = || {
+            QueryResponse::new(
+                Arc::new(vec![]),
+                cursor(5, 2, Arc::new(Mutex::new(Vec::new()))),
+            )
+        };
+        let tag = cursors.declare("c", response()).unwrap();
+        assert_eq!("DECLARE CURSOR", CommandComplete::from(tag).tag());
+        assert_eq!("42P03", error_code(cursors.declare("c", response())));
+
+        let fetched = |response: QueryResponse<'static>| async move {
+            let tag = CommandComplete::from(response.command_tag.unwrap())
+                .tag()
+                .clone();
+            let rows = response
+                .data_rows
+                .map(|item| match item.unwrap() {
+                    QueryResponseItem::Row(row) => row.fields()[0].clone().unwrap(),
+                    item => panic!("unexpected item {item:?}"),
+                })
+                .collect::<Vec<Bytes>>()
+                .await;
+            (tag, rows)
+        };
+        let (tag, rows) = fetched(cursors.fetch("c", Some(2)).await.unwrap()).await;
+        assert_eq!("FETCH 2", tag);
+        assert_eq!(vec![Bytes::from("0"), Bytes::from("1")], rows);
+
+        let tag = cursors.advance("c", Some(2)).await.unwrap();
+        assert_eq!("MOVE 2", CommandComplete::from(tag).tag());
+        let (tag, rows) = fetched(cursors.fetch("c", None).await.unwrap()).await;
+        assert_eq!("FETCH 1", tag);
+        assert_eq!(vec![Bytes::from("4")], rows);
+        // an exhausted cursor is still open
+        let (tag, rows) = fetched(cursors.fetch("c", None).await.unwrap()).await;
+        assert_eq!("FETCH 0", tag);
+        assert!(rows.is_empty());
+
+        let tag = cursors.close("c").unwrap();
+        assert_eq!("CLOSE CURSOR", CommandComplete::from(tag).tag());
+        assert_eq!("34000", error_code(cursors.fetch("c", Some(1)).await));
+        assert_eq!("34000", error_code(cursors.close("c")));
+
+        cursors.declare("c1", response()).unwrap();
+        cursors.declare("c2", response()).unwrap();
+        let tag = cursors.close_all();
+        assert_eq!("CLOSE CURSOR ALL", CommandComplete::from(tag).tag());
+        assert!(!cursors.contains("c1") && !cursors.contains("c2"));
+    }
+
+    #[tokio::test]
+    async fn test_declared_cursor_error() {
+        let cursors = DeclaredCursors::new();
+        let rows = stream::iter(vec![
+            Ok(DataRow::new(vec![])),
+            Err(PgWireError::ApiError("scan failed".into())),
+        ]);
+        cursors
+            .declare("c", QueryResponse::new(Arc::new(vec![]), rows))
+            .unwrap();
+        assert!(cursors.advance("c", None).await.is_err());
+        // the cursor is closed on error
+        assert!(!cursors.contains("c"));
+    }
+
+    #[tokio::test]
+    async fn test_cursor_stream_error() {
+        let stream = CursorStream::new(
+            2,
+            |offset, _limit| async move {
+                if offset == 0 {
+                    Ok(vec![1, 2])
+                } else {
+                    Err(PgWireError::ApiError("cursor closed".into()))
+                }
+            },
+            |v: i32| Ok(DataRow::new(vec![Some(Bytes::from(v.to_string()))])),
+        );
+        let rows = stream.collect::<Vec<_>>().await;
+        assert_eq!(3, rows.len());
+        assert!(rows[2].is_err());
+    }
+}
@@ -0,0 +1,96 @@
+//! A mock client for testing handlers without network.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::Sink;
+
+use super::{ClientInfo, ClientInfoHolder, Extensions, PgWireConnectionState, TransactionStatus};
+use crate::error::PgWireError;
+use crate::messages::PgWireBackendMessage;
+
+/// A client that records all messages sent to it.
+#[derive(Debug)]
+pub(crate) struct MockClient {
+    info: ClientInfoHolder,
+    pub(crate) sent_messages: Vec<PgWireBackendMessage>,
+}
+
+impl MockClient {
+    pub(crate) fn new() -> MockClient {
+        MockClient {
+            info: ClientInfoHolder::new("127.0.0.1:5432".parse().unwrap(), false),
+            sent_messages: Vec::new(),
+        }
+    }
+
+    /// Take sent messages from this client
+    pub(crate) fn take_messages(&mut self) -> Vec<PgWireBackendMessage> {
+        std::mem::take(&mut self.sent_messages)
+    }
+}
+
+impl ClientInfo for MockClient {
+    fn socket_addr(&self) -> &SocketAddr {
+        self.info.socket_addr()
+    }
+
+    fn is_secure(&self) -> bool {
+        *self.info.is_secure()
+    }
+
+    fn state(&self) -> &PgWireConnectionState {
+        self.info.state()
+    }
+
+    fn set_state(&mut self, new_state: PgWireConnectionState) {
+        self.info.set_state(new_state);
+    }
+
+    fn transaction_status(&self) -> TransactionStatus {
+        *self.info.transaction_status()
+    }
+
+    fn set_transaction_status(&mut self, new_status: TransactionStatus) {
+        self.info.set_transaction_status(new_status);
+    }
+
+    fn metadata(&self) -> &HashMap<String, String> {
+        self.info.metadata()
+    }
+
+    fn metadata_mut(&mut self) -> &mut HashMap<String, String> {
+        self.info.metadata_mut()
+    }
+
+    fn extensions(&self) -> &Extensions {
+        self.info.extensions()
+    }
+
+    fn extensions_mut(&mut self) -> &mut Extensions {
+        self.info.extensions_mut()
+    }
+}
+
+impl Sink<PgWireBackendMessage> for MockClient {
+    type Error = PgWireError;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: PgWireBackendMessage) -> Result<(), Self::Error> {
+        self.sent_messages.push(item);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
@@ -1,17 +1,39 @@
 //! APIs for building postgresql compatible servers.
 
+use std::any::{Any, TypeId};
 use std::collections::HashMap;
+use std::fmt;
 use std::net::SocketAddr;
 use std::sync::Arc;
 
 pub use postgres_types::Type;
 
+use self::auth::INTERVAL_STYLE;
+use self::params::ReportedParameters;
+use self::transaction::{IsolationLevel, TransactionIsolation};
+use crate::messages::response::{
+    READY_STATUS_FAILED_TRANSACTION_BLOCK, READY_STATUS_IDLE, READY_STATUS_TRANSACTION_BLOCK,
+};
+use crate::types::IntervalStyle;
+
+pub mod activity;
+#[cfg(feature = "with-arrow")]
+pub mod arrow;
 pub mod auth;
+pub mod cache;
+pub mod catalog;
+pub mod copy;
+pub mod cursor;
+#[cfg(test)]
+pub(crate) mod mock;
+pub mod params;
 pub mod portal;
 pub mod query;
 pub mod results;
+pub mod schema;
 pub mod stmt;
 pub mod store;
+pub mod transaction;
 
 pub const DEFAULT_NAME: &str = "POSTGRESQL_DEFAULT_NAME";
 
@@ -22,6 +44,75 @@ pub enum PgWireConnectionState {
     AuthenticationInProgress,
     ReadyForQuery,
     QueryInProgress,
+    /// An error occurred in extended query, messages are ignored until the
+    /// next `Sync`.
+    AwaitingSync,
+    /// `COPY FROM STDIN` started, by extended query if `true`. Data from
+    /// client is passed to `CopyHandler` until `CopyDone` or `CopyFail`.
+    CopyInProgress(bool),
+}
+
+/// Transaction status of a connection, reported to client in
+/// `ReadyForQuery`.
+///
+/// The status models postgres transaction blocks:
+///
+/// - Out of an explicit transaction block, the status is `Idle`. Statements
+///   of a simple query, or of an extended query pipeline between two `Sync`s,
+///   run in an implicit transaction. When one of them fails, the rest are
+///   skipped and the handler is expected to roll back the implicit
+///   transaction. The status stays `Idle`.
+/// - A statement completed with tag `BEGIN` or `START TRANSACTION` starts an
+///   explicit transaction block, and the status is `Transaction` until a
+///   `COMMIT`, `ROLLBACK` or `PREPARE TRANSACTION` tag. A block spans any
+///   number of queries and `Sync`s.
+/// - An error in a transaction block changes the status to `Error`. The
+///   handler is expected to reject statements other than `COMMIT`,
+///   `ROLLBACK` and `ROLLBACK TO SAVEPOINT` with `25P02` until the block
+///   ends.
+/// - `ROLLBACK TO SAVEPOINT` also completes with tag `ROLLBACK`, but it
+///   keeps the transaction block, and recovers a failed one to
+///   `Transaction`. It's told apart from `ROLLBACK` by the statement.
+///
+/// Handlers that manage transactions differently can set the status with
+/// `ClientInfo::set_transaction_status`, or have it tracked from query
+/// strings with `detect_transaction_control` of the query handlers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TransactionStatus {
+    #[default]
+    Idle,
+    Transaction,
+    Error,
+}
+
+impl TransactionStatus {
+    /// Status byte of `ReadyForQuery`
+    pub fn to_ready_status(self) -> u8 {
+        match self {
+            TransactionStatus::Idle => READY_STATUS_IDLE,
+            TransactionStatus::Transaction => READY_STATUS_TRANSACTION_BLOCK,
+            TransactionStatus::Error => READY_STATUS_FAILED_TRANSACTION_BLOCK,
+        }
+    }
+
+    /// Status after a statement completed with command tag `command`
+    pub fn after_command(self, command: &str) -> TransactionStatus {
+        match command {
+            "BEGIN" | "START TRANSACTION" if self == TransactionStatus::Idle => {
+                TransactionStatus::Transaction
+            }
+            "COMMIT" | "ROLLBACK" | "PREPARE TRANSACTION" => TransactionStatus::Idle,
+            _ => self,
+        }
+    }
+
+    /// Status after a statement failed
+    pub fn after_error(self) -> TransactionStatus {
+        match self {
+            TransactionStatus::Idle => TransactionStatus::Idle,
+            _ => TransactionStatus::Error,
+        }
+    }
 }
 
 /// Describe a client infomation holder
@@ -30,13 +121,118 @@ pub trait ClientInfo {
 
     fn is_secure(&self) -> bool;
 
+    /// Whether the connection completed a TLS handshake, of at least the min
+    /// version of `TlsOptions`, so that applications can refuse sensitive
+    /// operations over plaintext connections, like `hostssl` rules in
+    /// postgres.
+    ///
+    /// Unlike `is_secure`, which is set once `SSLRequest` is accepted, or by
+    /// the transport for connections not from `process_socket`, it's only
+    /// true when the `TlsSession` is in `Extensions`.
+    fn is_encrypted(&self) -> bool {
+        self.extensions().get::<TlsSession>().is_some()
+    }
+
     fn state(&self) -> &PgWireConnectionState;
 
     fn set_state(&mut self, new_state: PgWireConnectionState);
 
+    /// Transaction status reported in `ReadyForQuery`
+    fn transaction_status(&self) -> TransactionStatus;
+
+    fn set_transaction_status(&mut self, new_status: TransactionStatus);
+
     fn metadata(&self) -> &HashMap<String, String>;
 
     fn metadata_mut(&mut self) -> &mut HashMap<String, String>;
+
+    /// Application state attached to this connection. pgwire keeps its own
+    /// per-connection state here too, like startup parameters reported by
+    /// `finish_authentication`.
+    fn extensions(&self) -> &Extensions;
+
+    fn extensions_mut(&mut self) -> &mut Extensions;
+
+    /// Isolation level of the current transaction block, or of new
+    /// transactions outside of one. It's tracked from query strings with
+    /// `detect_transaction_control` of the query handlers, and `read
+    /// committed` otherwise.
+    fn isolation_level(&self) -> IsolationLevel {
+        self.extensions()
+            .get::<TransactionIsolation>()
+            .map(TransactionIsolation::current)
+            .unwrap_or_default()
+    }
+
+    /// `IntervalStyle` of the connection, as reported to client and changed
+    /// by `set_parameter`, `postgres` when it's not reported or unknown.
+    fn interval_style(&self) -> IntervalStyle {
+        self.extensions()
+            .get::<ReportedParameters>()
+            .and_then(|params| params.get(INTERVAL_STYLE))
+            .and_then(IntervalStyle::parse)
+            .unwrap_or_default()
+    }
+
+    /// Whether more frontend messages from client are already received and
+    /// waiting to be processed, like the rest of a pipeline after current
+    /// `Execute`.
+    ///
+    /// This is a hint for adaptive flushing and metrics. Messages not yet
+    /// arrived are unknown, and buffered data may be an incomplete message.
+    /// `false` by default.
+    fn is_pipelined(&self) -> bool {
+        false
+    }
+
+    /// TLS protocol version negotiated by the connection, like `TLSv1.3`,
+    /// `None` for plaintext connections
+    fn tls_protocol_version(&self) -> Option<&str> {
+        self.extensions()
+            .get::<TlsSession>()
+            .map(|session| session.protocol_version().as_str())
+    }
+
+    /// TLS cipher suite negotiated by the connection, like
+    /// `TLS13_AES_256_GCM_SHA384`, `None` for plaintext connections
+    fn tls_cipher_suite(&self) -> Option<&str> {
+        self.extensions()
+            .get::<TlsSession>()
+            .map(|session| session.cipher_suite().as_str())
+    }
+
+    /// Whether the client sent `SSLRequest` before startup, accepted or not,
+    /// to tell a client falling back to plaintext from one never asking for
+    /// TLS
+    fn ssl_requested(&self) -> bool {
+        self.extensions()
+            .get::<ConnectionNegotiation>()
+            .map_or(false, ConnectionNegotiation::ssl_requested)
+    }
+
+    /// Whether `SSLRequest` of the client was accepted
+    fn ssl_accepted(&self) -> bool {
+        self.extensions()
+            .get::<ConnectionNegotiation>()
+            .map_or(false, ConnectionNegotiation::ssl_accepted)
+    }
+
+    /// Whether the client sent `GSSENCRequest` before startup. It's always
+    /// declined.
+    fn gss_encryption_requested(&self) -> bool {
+        self.extensions()
+            .get::<ConnectionNegotiation>()
+            .map_or(false, ConnectionNegotiation::gss_encryption_requested)
+    }
+
+    /// Protocol version of the startup packet as sent by the client, major
+    /// version in the high 16 bits, like `0x30000` for 3.0. `None` before
+    /// startup.
+    fn startup_protocol_version(&self) -> Option<i32> {
+        self.extensions()
+            .get::<ConnectionNegotiation>()
+            .and_then(ConnectionNegotiation::protocol_version)
+    }
 }
 
 pub const METADATA_USER: &str = "user";
@@ -50,7 +246,116 @@ pub struct ClientInfoHolder {
     #[new(default)]
     state: PgWireConnectionState,
     #[new(default)]
+    transaction_status: TransactionStatus,
+    #[new(default)]
     metadata: HashMap<String, String>,
+    #[new(default)]
+    extensions: Extensions,
+}
+
+/// A type-keyed map for attaching arbitrary state to a connection.
+///
+/// Each type can have at most one value in the map. For example, a startup
+/// handler may insert tenant info at login, and query handlers get it back
+/// with `client.extensions().get::<Tenant>()`.
+#[derive(Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    pub fn new() -> Extensions {
+        Extensions::default()
+    }
+
+    /// Insert a value, returning the previous value of the same type.
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) -> Option<T> {
+        self.map
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|prev| prev.downcast().ok().map(|boxed| *boxed))
+    }
+
+    /// Get a reference to the value of type `T`
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.map
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref())
+    }
+
+    /// Get a mutable reference to the value of type `T`
+    pub fn get_mut<T: Send + Sync + 'static>(&mut self) -> Option<&mut T> {
+        self.map
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_mut())
+    }
+
+    /// Remove and return the value of type `T`
+    pub fn remove<T: Send + Sync + 'static>(&mut self) -> Option<T> {
+        self.map
+            .remove(&TypeId::of::<T>())
+            .and_then(|prev| prev.downcast().ok().map(|boxed| *boxed))
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.map.len())
+            .finish()
+    }
+}
+
+/// Process id and secret key of a connection, sent to client in
+/// `BackendKeyData` for cancelling its queries.
+///
+/// It is set in `Extensions` of connections served with a cancellation
+/// registry, see `tokio::CancellationRegistry`. Otherwise, the process id of
+/// the server and a random secret key are sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, new, Getters)]
+#[getset(get = "pub")]
+pub struct BackendKey {
+    pid: i32,
+    secret_key: i32,
+}
+
+/// Parameters of the TLS session of a connection, like `pg_stat_ssl` of
+/// postgres.
+///
+/// It is set in `Extensions` of TLS connections when the handshake completes,
+/// see `ClientInfo::tls_protocol_version` and `ClientInfo::tls_cipher_suite`.
+#[derive(Debug, Clone, PartialEq, Eq, new, Getters)]
+#[getset(get = "pub")]
+pub struct TlsSession {
+    protocol_version: String,
+    cipher_suite: String,
+}
+
+/// Requests of the client before authentication, and how they were answered,
+/// for diagnostics like why a client fell back to plaintext.
+///
+/// It is set in `Extensions` of connections accepted by `process_socket`,
+/// and completed with the protocol version of the startup packet, see
+/// `ClientInfo::ssl_requested` and `ClientInfo::startup_protocol_version`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, new, CopyGetters, Setters)]
+#[getset(get_copy = "pub", set = "pub(crate)")]
+pub struct ConnectionNegotiation {
+    /// client sent `SSLRequest`
+    ssl_requested: bool,
+    /// `SSLRequest` was answered with `S`
+    ssl_accepted: bool,
+    /// client sent `GSSENCRequest`
+    gss_encryption_requested: bool,
+    /// version of the startup packet, also when it's negotiated down
+    #[new(default)]
+    protocol_version: Option<i32>,
 }
 
 pub trait MakeHandler {
@@ -69,3 +374,32 @@ impl<H> MakeHandler for StatelessMakeHandler<H> {
         self.0.clone()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct Tenant(String);
+
+    #[test]
+    fn test_extensions() {
+        let mut extensions = Extensions::new();
+        assert!(extensions.get::<Tenant>().is_none());
+
+        assert!(extensions.insert(Tenant("a".to_owned())).is_none());
+        extensions.insert(42u32);
+        assert_eq!(2, extensions.len());
+
+        assert_eq!(
+            Some(Tenant("a".to_owned())),
+            extensions.insert(Tenant("b".to_owned()))
+        );
+        assert_eq!(Some(&Tenant("b".to_owned())), extensions.get::<Tenant>());
+
+        *extensions.get_mut::<u32>().unwrap() += 1;
+        assert_eq!(Some(43), extensions.remove::<u32>());
+        assert!(extensions.get::<u32>().is_none());
+        assert_eq!(1, extensions.len());
+    }
+}
@@ -0,0 +1,621 @@
+//! Tracking of server parameters reported to client.
+//!
+//! Clients cache parameters reported by `ParameterStatus`, and expect a new
+//! `ParameterStatus` whenever one of them changes, for example on `SET`,
+//! `RESET ALL` or `DISCARD ALL`. `finish_authentication` stores the reported
+//! parameters in connection `Extensions` as `ReportedParameters`, and
+//! `set_parameter`/`reset_all_parameters` update them and notify the client.
+//!
+//! Drivers also `SET` parameters applications don't implement, like
+//! `extra_float_digits`. With `LenientSetOptions` as `lenient_set` of
+//! `ServerOptions`, such a `SET` is accepted as a no-op, and the value is
+//! kept in `SessionParameters` so that `SHOW` returns it. Reported
+//! parameters are real settings of the connection, their `SET` is left to
+//! the query handler, except for `client_encoding`, which only accepts
+//! `UTF8` as pgwire doesn't convert encodings.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use futures::sink::{Sink, SinkExt};
+use postgres_types::Type;
+
+use super::results::{into_row_description, DataRowEncoder, FieldFormat, FieldInfo, Tag};
+use super::{ClientInfo, TransactionStatus};
+use crate::error::{ErrorInfo, PgWireError, PgWireResult, Severity, SqlState};
+use crate::messages::response::ReadyForQuery;
+use crate::messages::startup::ParameterStatus;
+use crate::messages::PgWireBackendMessage;
+
+/// Reportable parameters of a connection, with their current and default
+/// values.
+///
+/// Parameters reported at startup are reportable, changes to other
+/// parameters are not reported. Names are matched case-insensitively, as
+/// postgres does.
+#[derive(Debug, Default, Clone)]
+pub struct ReportedParameters {
+    defaults: HashMap<String, String>,
+    current: HashMap<String, String>,
+}
+
+impl ReportedParameters {
+    /// Create with the parameters reported at startup as defaults
+    pub fn new(defaults: HashMap<String, String>) -> ReportedParameters {
+        ReportedParameters {
+            current: defaults.clone(),
+            defaults,
+        }
+    }
+
+    /// Get current value of a parameter
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.key(name)
+            .and_then(|key| self.current.get(key))
+            .map(String::as_str)
+    }
+
+    /// Set a parameter. Returns the `ParameterStatus` to send when it's
+    /// reportable and the value changed.
+    pub fn set(&mut self, name: &str, value: &str) -> Option<ParameterStatus> {
+        let key = self.key(name)?.to_owned();
+        let current = self.current.get_mut(&key)?;
+        if current == value {
+            return None;
+        }
+        *current = value.to_owned();
+        Some(ParameterStatus::new(key, value.to_owned()))
+    }
+
+    /// Reset a parameter to default. Returns the `ParameterStatus` to send
+    /// when the value changed.
+    pub fn reset(&mut self, name: &str) -> Option<ParameterStatus> {
+        let key = self.key(name)?;
+        let default = self.defaults.get(key)?.clone();
+        self.set(name, &default)
+    }
+
+    /// Reset all parameters to defaults, as by `RESET ALL`. Returns the
+    /// `ParameterStatus` for each changed parameter.
+    pub fn reset_all(&mut self) -> Vec<ParameterStatus> {
+        let mut changed = Vec::new();
+        for (key, default) in &self.defaults {
+            if let Some(current) = self.current.get_mut(key) {
+                if current != default {
+                    *current = default.clone();
+                    changed.push(ParameterStatus::new(key.clone(), default.clone()));
+                }
+            }
+        }
+        changed
+    }
+
+    fn key(&self, name: &str) -> Option<&str> {
+        self.defaults
+            .keys()
+            .find(|key| key.eq_ignore_ascii_case(name))
+            .map(String::as_str)
+    }
+}
+
+/// Set a parameter of the connection, and send `ParameterStatus` if it's
+/// reportable and changed.
+///
+/// The message is buffered, and sent to client with the response of current
+/// query.
+pub async fn set_parameter<C>(client: &mut C, name: &str, value: &str) -> PgWireResult<()>
+where
+    C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+    C::Error: Debug,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+{
+    let status = client
+        .extensions_mut()
+        .get_mut::<ReportedParameters>()
+        .and_then(|params| params.set(name, value));
+    if let Some(status) = status {
+        client
+            .feed(PgWireBackendMessage::ParameterStatus(status))
+            .await?;
+    }
+    Ok(())
+}
+
+/// Reset all parameters of the connection to defaults, and send
+/// `ParameterStatus` for each changed reportable parameter. Call this on
+/// `RESET ALL` and `DISCARD ALL`.
+///
+/// The messages are buffered, and sent to client with the response of current
+/// query.
+pub async fn reset_all_parameters<C>(client: &mut C) -> PgWireResult<()>
+where
+    C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+    C::Error: Debug,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+{
+    let changed = client
+        .extensions_mut()
+        .get_mut::<ReportedParameters>()
+        .map(ReportedParameters::reset_all)
+        .unwrap_or_default();
+    for status in changed {
+        client
+            .feed(PgWireBackendMessage::ParameterStatus(status))
+            .await?;
+    }
+    Ok(())
+}
+
+/// Options of lenient `SET`, see `lenient_set` of `ServerOptions`
+#[derive(Debug, Clone, Default, new, Getters, Setters, MutGetters)]
+#[getset(get = "pub", set = "pub", get_mut = "pub")]
+pub struct LenientSetOptions {
+    /// send a `NoticeResponse` for each `SET` accepted as a no-op, disabled
+    /// by default
+    #[new(default)]
+    notice: bool,
+    /// parameters implemented by the application, whose `SET` and `SHOW`
+    /// are passed to the query handler. Names are matched
+    /// case-insensitively.
+    #[new(default)]
+    claimed: Vec<String>,
+}
+
+impl LenientSetOptions {
+    fn is_claimed(&self, name: &str) -> bool {
+        self.claimed
+            .iter()
+            .any(|claimed| claimed.eq_ignore_ascii_case(name))
+    }
+}
+
+/// Values of parameters set leniently on a connection, kept in its
+/// `Extensions`
+#[derive(Debug, Default, Clone)]
+pub struct SessionParameters {
+    values: HashMap<String, String>,
+}
+
+impl SessionParameters {
+    /// Get value of a parameter, names are matched case-insensitively
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(&name.to_lowercase()).map(String::as_str)
+    }
+}
+
+/// A `SET` or `SHOW` of a single parameter in a simple query
+#[derive(Debug, PartialEq, Eq)]
+enum ParameterCommand {
+    /// `SET name TO value`, `None` for `DEFAULT`
+    Set(String, Option<String>),
+    Show(String),
+}
+
+impl ParameterCommand {
+    /// Parse `query` of a single statement. Other forms, like `SET LOCAL`,
+    /// `SET TIME ZONE` or `SET ROLE`, are not parsed and left to the handler.
+    fn parse(query: &str) -> Option<ParameterCommand> {
+        let query = query.trim().trim_end_matches(';').trim_end();
+        let (command, rest) = query.split_once(|c: char| c.is_ascii_whitespace())?;
+        let rest = rest.trim_start();
+        let name_len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+            .unwrap_or(rest.len());
+        let (name, rest) = rest.split_at(name_len);
+        if name.is_empty() {
+            return None;
+        }
+        let name = name.to_lowercase();
+        let rest = rest.trim_start();
+
+        if command.eq_ignore_ascii_case("show") {
+            return rest.is_empty().then_some(ParameterCommand::Show(name));
+        }
+        if !command.eq_ignore_ascii_case("set") || name == "session" || name == "local" {
+            return None;
+        }
+        let value = if let Some(value) = rest.strip_prefix('=') {
+            value
+        } else {
+            let (to, value) = rest.split_at(rest.len().min(2));
+            if !to.eq_ignore_ascii_case("to") || !value.starts_with(char::is_whitespace) {
+                return None;
+            }
+            value
+        };
+        let value = parse_value(value.trim())?;
+        if value.eq_ignore_ascii_case("default") {
+            Some(ParameterCommand::Set(name, None))
+        } else {
+            Some(ParameterCommand::Set(name, Some(value)))
+        }
+    }
+}
+
+/// Parse a value of `SET`, a comma separated list of words, numbers and
+/// string literals. Quotes of literals are removed, like postgres does.
+fn parse_value(value: &str) -> Option<String> {
+    let mut items = Vec::new();
+    let mut chars = value.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let mut item = String::new();
+        if chars.next_if_eq(&'\'').is_some() {
+            loop {
+                match chars.next()? {
+                    '\'' if chars.next_if_eq(&'\'').is_some() => item.push('\''),
+                    '\'' => break,
+                    c => item.push(c),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| *c != ',' && !c.is_whitespace()) {
+                // a `;` would start another statement
+                if c == ';' || c == '\'' || c == '"' {
+                    return None;
+                }
+                item.push(c);
+            }
+            if item.is_empty() {
+                return None;
+            }
+        }
+        items.push(item);
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        match chars.next() {
+            None => return Some(items.join(", ")),
+            Some(',') => {}
+            Some(_) => return None,
+        }
+    }
+}
+
+/// Answer `query` when it's a `SET` or `SHOW` of a parameter not claimed by
+/// the application, nor reported to client. Returns `false` when the query is
+/// left to the handler.
+///
+/// `SET client_encoding` is answered unless claimed: `UTF8` is accepted,
+/// other encodings fail with `22023`, as clients would convert text the
+/// server doesn't. `SHOW` is answered only for parameters set here before.
+///
+/// `query` is the query as received, before the `QueryRewriter` of the
+/// handler, which only applies to queries passed to the handler.
+pub(crate) async fn lenient_set<C>(
+    client: &mut C,
+    options: &LenientSetOptions,
+    query: &str,
+) -> PgWireResult<bool>
+where
+    C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+    C::Error: Debug,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+{
+    // a failed transaction rejects all commands, leave that to the handler
+    if client.transaction_status() == TransactionStatus::Error {
+        return Ok(false);
+    }
+    let command = match ParameterCommand::parse(query) {
+        Some(command) => command,
+        None => return Ok(false),
+    };
+
+    match command {
+        ParameterCommand::Set(name, value) => {
+            if options.is_claimed(&name) {
+                return Ok(false);
+            }
+            if name == CLIENT_ENCODING {
+                set_client_encoding(client, value.as_deref()).await?;
+                return Ok(true);
+            }
+            let reported = client
+                .extensions()
+                .get::<ReportedParameters>()
+                .map_or(false, |params| params.get(&name).is_some());
+            if reported {
+                return Ok(false);
+            }
+            if *options.notice() {
+                let notice = ErrorInfo::new(
+                    Severity::Notice,
+                    SqlState::SuccessfulCompletion,
+                    format!("parameter \"{name}\" is not supported, ignoring SET"),
+                );
+                client
+                    .feed(PgWireBackendMessage::NoticeResponse(notice.into()))
+                    .await?;
+            }
+
+            let extensions = client.extensions_mut();
+            if extensions.get::<SessionParameters>().is_none() {
+                extensions.insert(SessionParameters::default());
+            }
+            if let Some(params) = extensions.get_mut::<SessionParameters>() {
+                match value {
+                    Some(value) => params.values.insert(name, value),
+                    None => params.values.remove(&name),
+                };
+            }
+            client
+                .feed(PgWireBackendMessage::CommandComplete(
+                    Tag::new_for_execution("SET", None).into(),
+                ))
+                .await?;
+        }
+        ParameterCommand::Show(name) => {
+            let value = match client.extensions().get::<SessionParameters>() {
+                Some(params) if !options.is_claimed(&name) => params.get(&name),
+                _ => None,
+            };
+            let value = match value {
+                Some(value) => value.to_owned(),
+                None => return Ok(false),
+            };
+            let schema = Arc::new(vec![FieldInfo::new(
+                name,
+                None,
+                None,
+                Type::TEXT,
+                FieldFormat::Text,
+            )]);
+            let mut encoder = DataRowEncoder::new(schema.clone());
+            encoder.encode_field(&value)?;
+            client
+                .feed(PgWireBackendMessage::RowDescription(into_row_description(
+                    &schema,
+                )))
+                .await?;
+            client
+                .feed(PgWireBackendMessage::DataRow(encoder.finish()?))
+                .await?;
+            client
+                .feed(PgWireBackendMessage::CommandComplete(
+                    Tag::new_for_execution("SHOW", None).into(),
+                ))
+                .await?;
+        }
+    }
+
+    send_ready(client).await?;
+    Ok(true)
+}
+
+const CLIENT_ENCODING: &str = "client_encoding";
+
+/// Answer `SET client_encoding`, `None` for `DEFAULT`. Only `UTF8` is
+/// accepted, in any spelling postgres accepts.
+async fn set_client_encoding<C>(client: &mut C, value: Option<&str>) -> PgWireResult<()>
+where
+    C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+    C::Error: Debug,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+{
+    if let Some(value) = value {
+        let normalized = value.replace(['-', '_'], "");
+        if !normalized.eq_ignore_ascii_case("utf8") && !normalized.eq_ignore_ascii_case("unicode") {
+            return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                Severity::Error,
+                SqlState::InvalidParameterValue,
+                format!("invalid value for parameter \"{CLIENT_ENCODING}\": \"{value}\""),
+            ))));
+        }
+    }
+    // the default is reported, and UTF8 is the only supported value
+    let status = client
+        .extensions_mut()
+        .get_mut::<ReportedParameters>()
+        .and_then(|params| params.set(CLIENT_ENCODING, "UTF8"));
+    if let Some(status) = status {
+        client
+            .feed(PgWireBackendMessage::ParameterStatus(status))
+            .await?;
+    }
+    client
+        .feed(PgWireBackendMessage::CommandComplete(
+            Tag::new_for_execution("SET", None).into(),
+        ))
+        .await?;
+    send_ready(client).await
+}
+
+async fn send_ready<C>(client: &mut C) -> PgWireResult<()>
+where
+    C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+    C::Error: Debug,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+{
+    client
+        .feed(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(
+            client.transaction_status().to_ready_status(),
+        )))
+        .await?;
+    client.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::auth::{finish_authentication, DefaultServerParameterProvider};
+    use crate::api::mock::MockClient;
+
+    fn status_of(messages: &[PgWireBackendMessage]) -> Vec<(&str, &str)> {
+        messages
+            .iter()
+            .filter_map(|msg| match msg {
+                PgWireBackendMessage::ParameterStatus(status) => {
+                    Some((status.name().as_str(), status.value().as_str()))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_reset_all_parameters() {
+        let mut client = MockClient::new();
+        finish_authentication(&mut client, &DefaultServerParameterProvider)
+            .await
+            .unwrap();
+        client.take_messages();
+
+        set_parameter(&mut client, "datestyle", "German, DMY")
+            .await
+            .unwrap();
+        set_parameter(&mut client, "client_encoding", "UTF8")
+            .await
+            .unwrap();
+        // not reportable
+        set_parameter(&mut client, "work_mem", "64MB")
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![("DateStyle", "German, DMY")],
+            status_of(&client.take_messages())
+        );
+
+        reset_all_parameters(&mut client).await.unwrap();
+        assert_eq!(
+            vec![("DateStyle", "ISO YMD")],
+            status_of(&client.take_messages())
+        );
+
+        reset_all_parameters(&mut client).await.unwrap();
+        assert!(client.take_messages().is_empty());
+    }
+
+    #[test]
+    fn test_parse_parameter_command() {
+        for (query, expected) in [
+            (
+                "SET extra_float_digits = 3",
+                Some(ParameterCommand::Set(
+                    "extra_float_digits".to_owned(),
+                    Some("3".to_owned()),
+                )),
+            ),
+            (
+                "set Application_Name to 'it''s me';",
+                Some(ParameterCommand::Set(
+                    "application_name".to_owned(),
+                    Some("it's me".to_owned()),
+                )),
+            ),
+            (
+                "SET search_path TO app,'public'",
+                Some(ParameterCommand::Set(
+                    "search_path".to_owned(),
+                    Some("app, public".to_owned()),
+                )),
+            ),
+            (
+                "SET lc_monetary TO DEFAULT",
+                Some(ParameterCommand::Set("lc_monetary".to_owned(), None)),
+            ),
+            (
+                "show my.option",
+                Some(ParameterCommand::Show("my.option".to_owned())),
+            ),
+            ("SET LOCAL work_mem = '64MB'", None),
+            ("SET TIME ZONE 'UTC'", None),
+            ("SET ROLE admin", None),
+            ("SET TRANSACTION ISOLATION LEVEL SERIALIZABLE", None),
+            ("SET a = 1; DROP TABLE t", None),
+            ("SET a = 'unterminated", None),
+            ("SET a TOO 1", None),
+            ("SELECT 1", None),
+        ] {
+            assert_eq!(expected, ParameterCommand::parse(query), "{query}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lenient_set() {
+        let mut client = MockClient::new();
+        finish_authentication(&mut client, &DefaultServerParameterProvider)
+            .await
+            .unwrap();
+        client.take_messages();
+        let mut options = LenientSetOptions::new();
+        options
+            .set_notice(true)
+            .set_claimed(vec!["Search_Path".to_owned()]);
+
+        assert!(
+            lenient_set(&mut client, &options, "SET extra_float_digits = 3")
+                .await
+                .unwrap()
+        );
+        let messages = client.take_messages();
+        assert!(matches!(
+            messages.as_slice(),
+            [
+                PgWireBackendMessage::NoticeResponse(_),
+                PgWireBackendMessage::CommandComplete(_),
+                PgWireBackendMessage::ReadyForQuery(_)
+            ]
+        ));
+
+        assert!(
+            lenient_set(&mut client, &options, "SHOW extra_float_digits")
+                .await
+                .unwrap()
+        );
+        let row = client
+            .take_messages()
+            .into_iter()
+            .find_map(|msg| match msg {
+                PgWireBackendMessage::DataRow(row) => Some(row),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(Some(bytes::Bytes::from("3")), row.fields()[0]);
+
+        // only UTF8 is accepted for client encoding
+        options.set_notice(false);
+        assert!(
+            lenient_set(&mut client, &options, "SET client_encoding TO 'utf-8'")
+                .await
+                .unwrap()
+        );
+        let messages = client.take_messages();
+        assert!(status_of(&messages).is_empty());
+        assert!(matches!(
+            messages.as_slice(),
+            [
+                PgWireBackendMessage::CommandComplete(_),
+                PgWireBackendMessage::ReadyForQuery(_)
+            ]
+        ));
+        match lenient_set(&mut client, &options, "SET client_encoding = 'LATIN1'").await {
+            Err(PgWireError::UserError(info)) => assert_eq!("22023", info.code()),
+            result => panic!("unexpected result {result:?}"),
+        }
+        assert!(client.take_messages().is_empty());
+
+        // claimed, reported and unknown parameters are left to the handler
+        for query in [
+            "SET search_path = app",
+            "SHOW search_path",
+            "SHOW work_mem",
+            "SET DateStyle TO 'German'",
+            "SHOW DateStyle",
+        ] {
+            assert!(!lenient_set(&mut client, &options, query).await.unwrap());
+        }
+        client.set_transaction_status(TransactionStatus::Error);
+        assert!(!lenient_set(&mut client, &options, "SET work_mem = 1")
+            .await
+            .unwrap());
+        assert!(client.take_messages().is_empty());
+        assert_eq!(
+            Some("3"),
+            client
+                .extensions()
+                .get::<SessionParameters>()
+                .unwrap()
+                .get("EXTRA_FLOAT_DIGITS")
+        );
+    }
+}
@@ -1,14 +1,24 @@
-use std::sync::Arc;
+use std::fmt;
+use std::sync::{Arc, Mutex};
 
 use bytes::Bytes;
-use postgres_types::FromSqlOwned;
+use futures::stream::BoxStream;
+use postgres_types::{FromSqlOwned, Type};
 
 use crate::{
-    error::{PgWireError, PgWireResult},
-    messages::{data::FORMAT_CODE_BINARY, extendedquery::Bind},
+    error::{ErrorInfo, PgWireError, PgWireResult, Severity, SqlState},
+    messages::{
+        data::{FORMAT_CODE_BINARY, FORMAT_CODE_TEXT},
+        extendedquery::Bind,
+    },
+    types::{validate_binary, PgValue},
 };
 
-use super::{results::FieldFormat, stmt::StoredStatement, DEFAULT_NAME};
+use super::{
+    results::{FieldFormat, QueryResponseItem, Tag},
+    stmt::StoredStatement,
+    DEFAULT_NAME,
+};
 
 /// Represent a prepared sql statement and its parameters bound by a `Bind`
 /// request.
@@ -19,7 +29,32 @@ pub struct Portal<S> {
     statement: Arc<StoredStatement<S>>,
     parameter_format: Format,
     parameters: Vec<Option<Bytes>>,
+    /// formats of result columns requested by `Bind`, see `result_formats`
     result_column_format: Format,
+    /// formats of all result columns, resolved from `result_column_format`
+    /// on `Bind` or, if the number of columns isn't known yet, on the first
+    /// `Describe` or `Execute`
+    #[getset(skip)]
+    result_formats: Arc<Mutex<Option<Vec<FieldFormat>>>>,
+    /// rest of the result after `Execute` suspended the portal at `max_rows`
+    #[getset(skip)]
+    suspended: Arc<Mutex<Option<SuspendedResult>>>,
+}
+
+/// Rows left of a suspended portal, with the tag to complete them, kept
+/// until the next `Execute` of the portal. Rows read into memory on suspend
+/// end with a failure of the query, in place of the rows after it.
+pub(crate) struct SuspendedResult {
+    pub(crate) items: BoxStream<'static, PgWireResult<QueryResponseItem>>,
+    pub(crate) command_tag: Option<Tag>,
+}
+
+impl fmt::Debug for SuspendedResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SuspendedResult")
+            .field("command_tag", &self.command_tag)
+            .finish_non_exhaustive()
+    }
 }
 
 #[derive(Debug, Clone, Default)]
@@ -46,10 +81,19 @@ impl Format {
         match self {
             Format::UnifiedText => FieldFormat::Text,
             Format::UnifiedBinary => FieldFormat::Binary,
-            Format::Individual(ref fv) => FieldFormat::from(fv[idx]),
+            // text is assumed for columns not covered by format codes
+            Format::Individual(ref fv) => fv
+                .get(idx)
+                .map(|code| FieldFormat::from(*code))
+                .unwrap_or(FieldFormat::Text),
         }
     }
 
+    /// Get formats of `ncols` columns, one per column
+    pub fn field_formats(&self, ncols: usize) -> Vec<FieldFormat> {
+        (0..ncols).map(|idx| self.format_for(idx)).collect()
+    }
+
     /// Test if `idx` field is text format
     pub fn is_text(&self, idx: usize) -> bool {
         self.format_for(idx) == FieldFormat::Text
@@ -71,6 +115,107 @@ impl Format {
     }
 }
 
+impl<S> Portal<S> {
+    /// Get formats of `ncols` result columns, as requested by `Bind`.
+    ///
+    /// This is the only source of result formats of the portal: the
+    /// `RowDescription` sent on `Describe` and the `DataRow`s of `Execute`
+    /// both use the formats resolved once for the portal, so they agree
+    /// however many times the portal is described.
+    pub fn result_formats(&self, ncols: usize) -> Vec<FieldFormat> {
+        match self.resolved_result_formats() {
+            Some(formats) if formats.len() == ncols => formats,
+            _ => self.result_column_format.field_formats(ncols),
+        }
+    }
+
+    /// Get plan of the statement the portal is bound from, as returned by
+    /// `QueryParser::parse_sql` on `Parse`.
+    ///
+    /// The plan is made once per `Parse`, and shared by all portals bound
+    /// from the statement, so `Bind` doesn't plan the query again.
+    pub fn statement_plan(&self) -> &S {
+        self.statement.statement()
+    }
+
+    /// Get formats of `ncols` result columns, checking that `Bind` has no
+    /// format codes, one code for all columns, or exactly one per column.
+    ///
+    /// Other counts fail with `08P01` like in postgres. This is checked on
+    /// `Bind` if the statement has been described, and the number of its
+    /// result columns is known. Otherwise it's checked on the first
+    /// `Describe` or `Execute` of the portal returning rows, which resolves
+    /// the formats for the later ones. `result_formats` takes columns without
+    /// code as text instead.
+    pub fn checked_result_formats(&self, ncols: usize) -> PgWireResult<Vec<FieldFormat>> {
+        if let Some(formats) = self.resolved_result_formats() {
+            if formats.len() == ncols {
+                return Ok(formats);
+            }
+            return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                Severity::Error,
+                SqlState::ProtocolViolation,
+                format!(
+                    "portal has {} result columns but query has {ncols} columns",
+                    formats.len()
+                ),
+            ))));
+        }
+        let formats = resolve_result_formats(&self.result_column_format, ncols)?;
+        if let Ok(mut resolved) = self.result_formats.lock() {
+            *resolved = Some(formats.clone());
+        }
+        Ok(formats)
+    }
+
+    fn resolved_result_formats(&self) -> Option<Vec<FieldFormat>> {
+        self.result_formats
+            .lock()
+            .ok()
+            .and_then(|formats| formats.clone())
+    }
+
+    /// Whether the last `Execute` of the portal stopped at its `max_rows`,
+    /// and the next one continues with the rest of the result.
+    pub fn is_suspended(&self) -> bool {
+        self.suspended
+            .lock()
+            .map(|suspended| suspended.is_some())
+            .unwrap_or(false)
+    }
+
+    pub(crate) fn suspend(&self, result: SuspendedResult) {
+        if let Ok(mut suspended) = self.suspended.lock() {
+            *suspended = Some(result);
+        }
+    }
+
+    pub(crate) fn take_suspended(&self) -> Option<SuspendedResult> {
+        self.suspended
+            .lock()
+            .ok()
+            .and_then(|mut suspended| suspended.take())
+    }
+}
+
+/// Formats of `ncols` result columns, failing with `08P01` like postgres if
+/// there are several format codes but not one per column
+fn resolve_result_formats(format: &Format, ncols: usize) -> PgWireResult<Vec<FieldFormat>> {
+    if let Format::Individual(codes) = format {
+        if codes.len() != ncols {
+            return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                Severity::Error,
+                SqlState::ProtocolViolation,
+                format!(
+                    "bind message has {} result formats but query has {ncols} columns",
+                    codes.len()
+                ),
+            ))));
+        }
+    }
+    Ok(format.field_formats(ncols))
+}
+
 impl<S: Clone> Portal<S> {
     /// Try to create portal from bind command and current client state
     pub fn try_new(bind: &Bind, statement: Arc<StoredStatement<S>>) -> PgWireResult<Self> {
@@ -85,12 +230,29 @@ impl<S: Clone> Portal<S> {
         // format
         let result_format = Format::from_codes(bind.result_column_format_codes());
 
+        check_format_codes(bind.parameter_format_codes())?;
+        check_format_codes(bind.result_column_format_codes())?;
+        // with the number of columns of a described statement, the formats
+        // are checked on bind like in postgres
+        let result_formats = statement
+            .result_len()
+            .map(|ncols| resolve_result_formats(&result_format, ncols))
+            .transpose()?;
+
+        validate_binary_parameters(
+            bind.parameters(),
+            &param_format,
+            statement.parameter_types(),
+        )?;
+
         Ok(Portal {
             name: portal_name,
             statement,
             parameter_format: param_format,
             parameters: bind.parameters().clone(),
             result_column_format: result_format,
+            result_formats: Arc::new(Mutex::new(result_formats)),
+            suspended: Arc::default(),
         })
     }
 
@@ -99,6 +261,39 @@ impl<S: Clone> Portal<S> {
         self.parameters.len()
     }
 
+    /// Get parameters as received in `Bind`, paired with format code of each
+    /// parameter.
+    ///
+    /// Values are not decoded, so they can be forwarded verbatim, for example
+    /// into a `Bind` for an upstream server.
+    pub fn raw_parameters(&self) -> Vec<(i16, Option<Bytes>)> {
+        self.parameters
+            .iter()
+            .enumerate()
+            .map(|(idx, param)| (self.parameter_format.format_for(idx).value(), param.clone()))
+            .collect()
+    }
+
+    /// Decode all parameters by their declared types and formats.
+    ///
+    /// Parameters of other than common scalar and array types are kept as
+    /// received in `PgValue::Raw`. Parameters without declared type are
+    /// decoded as `unknown`, which is text in text format.
+    pub fn typed_parameters(&self) -> PgWireResult<Vec<PgValue>> {
+        let types = self.statement.parameter_types();
+        self.parameters
+            .iter()
+            .enumerate()
+            .map(|(idx, param)| {
+                PgValue::decode(
+                    types.get(idx).unwrap_or(&Type::UNKNOWN),
+                    self.parameter_format.format_for(idx),
+                    param.as_ref(),
+                )
+            })
+            .collect()
+    }
+
     /// Attempt to get parameter at given index as type `T`.
     ///
     pub fn parameter<T>(&self, idx: usize) -> PgWireResult<Option<T>>
@@ -136,3 +331,293 @@ impl<S: Clone> Portal<S> {
         }
     }
 }
+
+/// Check that format codes of `Bind` are text or binary. Other codes would be
+/// taken as text, and the client would misread the values.
+fn check_format_codes(codes: &[i16]) -> PgWireResult<()> {
+    match codes
+        .iter()
+        .find(|code| **code != FORMAT_CODE_TEXT && **code != FORMAT_CODE_BINARY)
+    {
+        Some(code) => Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+            Severity::Error,
+            SqlState::InvalidParameterValue,
+            format!("unsupported format code: {code}"),
+        )))),
+        None => Ok(()),
+    }
+}
+
+/// Check that binary parameters are valid values of their declared types, so
+/// that malformed values from clients are rejected on `Bind`.
+///
+/// Fails with `22P03`, or `22021` for invalid UTF-8 of text types, naming the
+/// parameter by its 1-based position like postgres does. Parameters without
+/// declared type, or of types without decoder, are not checked. Values are
+/// only checked, not decoded, they are decoded when the handler asks for
+/// them, like with `typed_parameters`.
+fn validate_binary_parameters(
+    parameters: &[Option<Bytes>],
+    format: &Format,
+    types: &[Type],
+) -> PgWireResult<()> {
+    for (idx, (param, ty)) in parameters.iter().zip(types).enumerate() {
+        if !format.is_binary(idx) {
+            continue;
+        }
+        let param = match param {
+            Some(param) => param,
+            None => continue,
+        };
+        if let Err(e) = validate_binary(ty, param) {
+            let error_info = if e.downcast_ref::<std::str::Utf8Error>().is_some() {
+                ErrorInfo::new(
+                    Severity::Error,
+                    SqlState::CharacterNotInRepertoire,
+                    format!(
+                        "invalid byte sequence for encoding \"UTF8\" in bind parameter {}",
+                        idx + 1
+                    ),
+                )
+            } else {
+                ErrorInfo::new(
+                    Severity::Error,
+                    SqlState::InvalidBinaryRepresentation,
+                    format!("incorrect binary data format in bind parameter {}", idx + 1),
+                )
+            };
+            return Err(PgWireError::UserError(Box::new(error_info)));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_raw_parameters() {
+        let params = vec![Some(Bytes::from_static(b"1")), None, Some(Bytes::new())];
+        let bind = Bind::new(None, None, vec![0, 1, 1], params.clone(), vec![]);
+        let statement = Arc::new(StoredStatement::new(
+            DEFAULT_NAME.to_owned(),
+            "SELECT $1, $2, $3".to_owned(),
+            vec![],
+        ));
+        let portal = Portal::try_new(&bind, statement.clone()).unwrap();
+        assert_eq!(
+            vec![(0, params[0].clone()), (1, None), (1, params[2].clone())],
+            portal.raw_parameters()
+        );
+
+        let bind = Bind::new(None, None, vec![1], params, vec![]);
+        let portal = Portal::try_new(&bind, statement).unwrap();
+        assert!(portal.raw_parameters().iter().all(|(code, _)| *code == 1));
+    }
+
+    #[test]
+    fn test_typed_parameters() {
+        let params = vec![
+            Some(Bytes::from_static(b"42")),
+            Some(Bytes::from_static(&[0, 0, 0, 7])),
+            None,
+            Some(Bytes::from_static(b"{a,b}")),
+            Some(Bytes::from_static(b"tom")),
+        ];
+        let bind = Bind::new(None, None, vec![0, 1, 0, 0, 0], params, vec![]);
+        let statement = Arc::new(StoredStatement::new(
+            DEFAULT_NAME.to_owned(),
+            "SELECT $1, $2, $3, $4, $5".to_owned(),
+            vec![Type::INT8, Type::INT4, Type::BOOL, Type::VARCHAR_ARRAY],
+        ));
+        let portal = Portal::try_new(&bind, statement.clone()).unwrap();
+        assert_eq!(
+            vec![
+                PgValue::Int8(42),
+                PgValue::Int4(7),
+                PgValue::Null,
+                PgValue::Array(vec![
+                    PgValue::Text("a".to_owned()),
+                    PgValue::Text("b".to_owned())
+                ]),
+                // type not declared
+                PgValue::Text("tom".to_owned()),
+            ],
+            portal.typed_parameters().unwrap()
+        );
+
+        let bind = Bind::new(
+            None,
+            None,
+            vec![],
+            vec![Some(Bytes::from_static(b"x"))],
+            vec![],
+        );
+        let portal = Portal::try_new(&bind, statement).unwrap();
+        assert!(matches!(
+            portal.typed_parameters(),
+            Err(PgWireError::FailedToParseParameter(_))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_binary_parameters() {
+        let statement = Arc::new(StoredStatement::new(
+            DEFAULT_NAME.to_owned(),
+            "SELECT $1, $2".to_owned(),
+            vec![Type::INT4, Type::TEXT],
+        ));
+        for (params, code, message) in [
+            (
+                vec![Some(Bytes::from_static(&[0, 0, 7])), None],
+                "22P03",
+                "incorrect binary data format in bind parameter 1",
+            ),
+            (
+                vec![None, Some(Bytes::from_static(&[0x61, 0xff]))],
+                "22021",
+                "invalid byte sequence for encoding \"UTF8\" in bind parameter 2",
+            ),
+        ] {
+            let bind = Bind::new(None, None, vec![1], params, vec![]);
+            match Portal::try_new(&bind, statement.clone()) {
+                Err(PgWireError::UserError(info)) => {
+                    assert_eq!(code, info.code());
+                    assert_eq!(message, info.message());
+                }
+                result => panic!("unexpected result {result:?}"),
+            }
+        }
+
+        // malformed text parameters are left to be decoded by handler
+        let bind = Bind::new(
+            None,
+            None,
+            vec![0],
+            vec![Some(Bytes::from_static(&[0, 0, 7])), None],
+            vec![],
+        );
+        assert!(Portal::try_new(&bind, statement).is_ok());
+    }
+
+    #[test]
+    fn test_shared_statement_plan() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use crate::api::stmt::QueryParser;
+        use crate::messages::extendedquery::Parse;
+
+        #[derive(Default)]
+        struct PlanningParser {
+            plans: AtomicUsize,
+        }
+
+        impl QueryParser for PlanningParser {
+            type Statement = Arc<String>;
+
+            fn parse_sql(&self, sql: &str, _types: &[Type]) -> PgWireResult<Self::Statement> {
+                self.plans.fetch_add(1, Ordering::Relaxed);
+                Ok(Arc::new(format!("plan of {sql}")))
+            }
+        }
+
+        let parser = PlanningParser::default();
+        let parse = Parse::new(Some("s1".to_owned()), "SELECT $1".to_owned(), vec![23]);
+        let statement = Arc::new(StoredStatement::parse(&parse, &parser).unwrap());
+
+        let portals = [1, 2].map(|value: i32| {
+            let bind = Bind::new(
+                Some(format!("p{value}")),
+                Some("s1".to_owned()),
+                vec![1],
+                vec![Some(Bytes::copy_from_slice(&value.to_be_bytes()))],
+                vec![],
+            );
+            Portal::try_new(&bind, statement.clone()).unwrap()
+        });
+        // planned once on parse, not on bind
+        assert_eq!(1, parser.plans.load(Ordering::Relaxed));
+        assert_eq!("plan of SELECT $1", portals[0].statement_plan().as_str());
+        assert!(Arc::ptr_eq(
+            portals[0].statement_plan(),
+            portals[1].statement_plan()
+        ));
+        assert_eq!(Some(2), portals[1].parameter::<i32>(0).unwrap());
+    }
+
+    #[test]
+    fn test_result_formats() {
+        let statement = Arc::new(StoredStatement::new(
+            DEFAULT_NAME.to_owned(),
+            "SELECT 1, 2, 3".to_owned(),
+            vec![],
+        ));
+        for (codes, expected) in [
+            (vec![], vec![FieldFormat::Text; 3]),
+            (vec![1], vec![FieldFormat::Binary; 3]),
+            (
+                vec![1, 0],
+                vec![FieldFormat::Binary, FieldFormat::Text, FieldFormat::Text],
+            ),
+        ] {
+            let bind = Bind::new(None, None, vec![], vec![], codes);
+            let portal = Portal::try_new(&bind, statement.clone()).unwrap();
+            assert_eq!(expected, portal.result_formats(3));
+        }
+
+        // more than one code, but not one per column
+        for (codes, ncols, ok) in [
+            (vec![], 3, true),
+            (vec![1], 3, true),
+            (vec![1, 0, 1], 3, true),
+            (vec![1, 0], 3, false),
+            (vec![1, 0, 1], 2, false),
+        ] {
+            let bind = Bind::new(None, None, vec![], vec![], codes);
+            let portal = Portal::try_new(&bind, statement.clone()).unwrap();
+            match portal.checked_result_formats(ncols) {
+                Ok(formats) => {
+                    assert!(ok);
+                    assert_eq!(ncols, formats.len());
+                }
+                Err(PgWireError::UserError(info)) => {
+                    assert!(!ok);
+                    assert_eq!("08P01", info.code());
+                }
+                Err(e) => panic!("unexpected error {e:?}"),
+            }
+        }
+
+        // the formats are resolved once, for a query of three columns
+        let bind = Bind::new(None, None, vec![], vec![], vec![1]);
+        let portal = Portal::try_new(&bind, statement.clone()).unwrap();
+        assert!(portal.checked_result_formats(3).is_ok());
+        assert!(portal.checked_result_formats(2).is_err());
+
+        // or on bind, for a described statement
+        let mut described = statement.as_ref().clone();
+        described.set_result_len(Some(3));
+        let described = Arc::new(described);
+        let bind = Bind::new(None, None, vec![], vec![], vec![1, 0, 1]);
+        let portal = Portal::try_new(&bind, described.clone()).unwrap();
+        assert_eq!(
+            vec![FieldFormat::Binary, FieldFormat::Text, FieldFormat::Binary],
+            portal.checked_result_formats(3).unwrap()
+        );
+        let bind = Bind::new(None, None, vec![], vec![], vec![1, 0]);
+        match Portal::try_new(&bind, described) {
+            Err(PgWireError::UserError(info)) => assert_eq!("08P01", info.code()),
+            result => panic!("unexpected result {result:?}"),
+        }
+
+        let bind = Bind::new(None, None, vec![], vec![], vec![0, 2]);
+        match Portal::try_new(&bind, statement) {
+            Err(PgWireError::UserError(info)) => {
+                assert_eq!("22023", info.code());
+                assert_eq!("unsupported format code: 2", info.message());
+            }
+            result => panic!("unexpected result {result:?}"),
+        }
+    }
+}
@@ -1,29 +1,63 @@
+use std::collections::VecDeque;
 use std::fmt::Debug;
 use std::sync::Arc;
 
 use async_trait::async_trait;
 use futures::sink::{Sink, SinkExt};
-use futures::stream::StreamExt;
+use futures::stream::{self, BoxStream, StreamExt};
 
-use super::portal::Portal;
-use super::results::{into_row_description, Tag};
+use super::cache::{CachedResult, ResultCache};
+use super::portal::{Portal, SuspendedResult};
+use super::results::{
+    check_field_formats, into_row_description, into_row_description_with_formats, CopyOutData,
+    CopyResponse, Tag,
+};
 use super::stmt::{NoopQueryParser, QueryParser, StoredStatement};
 use super::store::{MemPortalStore, PortalStore};
-use super::{ClientInfo, DEFAULT_NAME};
-use crate::api::results::{DescribeResponse, QueryResponse, Response};
-use crate::error::{PgWireError, PgWireResult};
-use crate::messages::data::ParameterDescription;
+use super::transaction::{IsolationSetting, TransactionControl, TransactionIsolation};
+use super::{ClientInfo, TransactionStatus, DEFAULT_NAME};
+use crate::api::results::{DataRows, DescribeResponse, QueryResponse, QueryResponseItem, Response};
+use crate::error::{ErrorInfo, PgWireError, PgWireResult, Severity, SqlState};
+use crate::messages::copy::{CopyData, CopyDone};
+use crate::messages::data::{DataRow, NoData, ParameterDescription};
 use crate::messages::extendedquery::{
     Bind, BindComplete, Close, CloseComplete, Describe, Execute, Parse, ParseComplete,
-    Sync as PgSync, TARGET_TYPE_BYTE_PORTAL, TARGET_TYPE_BYTE_STATEMENT,
+    PortalSuspended, Sync as PgSync, TARGET_TYPE_BYTE_PORTAL, TARGET_TYPE_BYTE_STATEMENT,
 };
-use crate::messages::response::{EmptyQueryResponse, ReadyForQuery, READY_STATUS_IDLE};
+use crate::messages::response::{EmptyQueryResponse, ReadyForQuery};
 use crate::messages::simplequery::Query;
 use crate::messages::PgWireBackendMessage;
 
+/// Hook to rewrite query strings from client before they are dispatched, for
+/// example to adapt client SQL to the dialect of the backend.
+///
+/// `statement` is `None` for simple query, and the statement name for
+/// `Parse` of extended query, `DEFAULT_NAME` for the unnamed statement. Return
+/// `Ok(None)` to keep the query as is, `Ok(Some(sql))` to replace it, or an
+/// error to reject the query. The error is sent to client like one of
+/// `do_query`.
+pub trait QueryRewriter: Send + Sync {
+    fn rewrite(&self, statement: Option<&str>, query: &str) -> PgWireResult<Option<String>>;
+}
+
 /// handler for processing simple query.
 #[async_trait]
 pub trait SimpleQueryHandler: Send + Sync {
+    /// Get the `QueryRewriter` applied to incoming queries, none by default.
+    fn query_rewriter(&self) -> Option<&dyn QueryRewriter> {
+        None
+    }
+
+    /// Whether to track transaction status from transaction control
+    /// statements in query strings, like `BEGIN` and `ROLLBACK`, instead of
+    /// command tags of responses. Disabled by default.
+    ///
+    /// Status changes made by the handler while executing a statement take
+    /// precedence. See `TransactionControl` for the detection.
+    fn detect_transaction_control(&self) -> bool {
+        false
+    }
+
     /// Executed on `Query` request arrived. This is how postgres respond to
     /// simple query. The default implementation calls `do_query` with the
     /// incoming query string.
@@ -34,33 +68,80 @@ pub trait SimpleQueryHandler: Send + Sync {
         PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
     {
         client.set_state(super::PgWireConnectionState::QueryInProgress);
-        let query_string = query.query();
+        let rewritten = match self.query_rewriter() {
+            Some(rewriter) => rewriter.rewrite(None, query.query())?,
+            None => None,
+        };
+        let query_string = rewritten.as_deref().unwrap_or(query.query().as_str());
+        let raw_query = match rewritten.as_deref() {
+            Some(rewritten) => rewritten.as_bytes(),
+            None => query.raw_query(),
+        };
         if query_string.is_empty() {
             client
                 .feed(PgWireBackendMessage::EmptyQueryResponse(EmptyQueryResponse))
                 .await?;
         } else {
-            let resp = self.do_query(client, query.query()).await?;
-            for r in resp {
+            let (controls, isolations) = if self.detect_transaction_control() {
+                (
+                    TransactionControl::detect(query_string),
+                    IsolationSetting::detect(query_string),
+                )
+            } else {
+                (Vec::new(), Vec::new())
+            };
+            let resp = self.do_raw_query(client, query_string, raw_query).await?;
+            for (idx, r) in resp.into_iter().enumerate() {
+                // responses match statements of the query by position
+                let control = controls.get(idx).copied().flatten();
+                let isolation = isolations.get(idx).copied().flatten();
                 match r {
                     Response::Query(results) => {
-                        send_query_response(client, results, true).await?;
+                        send_query_response(client, results, true, 0, None).await?;
+                    }
+                    Response::DeferredQuery(results) => {
+                        send_query_response(client, results.await?, true, 0, None).await?;
                     }
                     Response::Execution(tag) => {
-                        send_execution_response(client, tag).await?;
+                        let control = if self.detect_transaction_control() {
+                            control
+                        } else if tag.command() == "ROLLBACK" {
+                            savepoint_rollback(
+                                TransactionControl::detect(query_string)
+                                    .get(idx)
+                                    .copied()
+                                    .flatten(),
+                            )
+                        } else {
+                            None
+                        };
+                        send_execution_response(client, tag, control, isolation).await?;
                     }
                     Response::Error(e) => {
+                        client.set_transaction_status(client.transaction_status().after_error());
                         client
                             .feed(PgWireBackendMessage::ErrorResponse((*e).into()))
                             .await?;
                     }
+                    Response::CopyIn(copy) => {
+                        // `ReadyForQuery` is sent when copy finished, responses
+                        // after `CopyIn` are dropped
+                        client
+                            .send(PgWireBackendMessage::CopyInResponse((&copy).into()))
+                            .await?;
+                        client.set_state(super::PgWireConnectionState::CopyInProgress(false));
+                        return Ok(());
+                    }
+                    Response::CopyOut(copy, data) => {
+                        send_copy_out_response(client, copy, data).await?;
+                    }
                 }
             }
         }
 
         client
             .feed(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(
-                READY_STATUS_IDLE,
+                client.transaction_status().to_ready_status(),
             )))
             .await?;
         client.flush().await?;
@@ -69,6 +150,13 @@ pub trait SimpleQueryHandler: Send + Sync {
     }
 
     /// Provide your query implementation using the incoming query string.
+    ///
+    /// The query string is identical to what the client sent, without any
+    /// trimming, splitting or normalization. Whitespaces and comments are
+    /// preserved, so it's safe to log or hash, for example, as cache key.
+    /// Invalid utf-8 bytes are replaced by `U+FFFD`, implement `do_raw_query`
+    /// for the bytes as sent. Use `transaction::statement_spans` for the
+    /// source of each statement in the query.
     async fn do_query<'a, 'b: 'a, C>(
         &'b self,
         client: &C,
@@ -76,6 +164,21 @@ pub trait SimpleQueryHandler: Send + Sync {
     ) -> PgWireResult<Vec<Response<'a>>>
     where
         C: ClientInfo + Unpin + Send + Sync;
+
+    /// Like `do_query`, with `raw` bytes of the query as sent by client.
+    /// `query` is the same bytes decoded as utf-8. The default implementation
+    /// calls `do_query`.
+    async fn do_raw_query<'a, 'b: 'a, C>(
+        &'b self,
+        client: &C,
+        query: &'a str,
+        _raw: &'a [u8],
+    ) -> PgWireResult<Vec<Response<'a>>>
+    where
+        C: ClientInfo + Unpin + Send + Sync,
+    {
+        self.do_query(client, query).await
+    }
 }
 
 #[async_trait]
@@ -85,17 +188,58 @@ pub trait ExtendedQueryHandler: Send + Sync {
     type PortalStore: PortalStore<Statement = Self::Statement>;
 
     /// Get a reference to associated `PortalStore` implementation
+    ///
+    /// The store must belong to one connection, as statement and portal
+    /// names are only unique within a connection. Create the handler for
+    /// each connection with a `MakeHandler`, like `examples/sqlite.rs`. With
+    /// a store shared by connections, one connection replaces statements of
+    /// another, and `Parse` fails with `42P05` on names prepared by another
+    /// connection, like `S_1` of JDBC.
     fn portal_store(&self) -> Arc<Self::PortalStore>;
 
+    /// Whether to track transaction status from transaction control
+    /// statements in query strings, like `BEGIN` and `ROLLBACK`, instead of
+    /// command tags of responses. Disabled by default.
+    ///
+    /// Status changes made by the handler while executing a statement take
+    /// precedence. See `TransactionControl` for the detection.
+    fn detect_transaction_control(&self) -> bool {
+        false
+    }
+
     /// Get a reference to associated `QueryParser` implementation
     fn query_parser(&self) -> Arc<Self::QueryParser>;
 
-    async fn on_parse<C>(&self, client: &mut C, message: Parse) -> PgWireResult<()>
+    /// Get the `QueryRewriter` applied to queries of `Parse`, none by default.
+    fn query_rewriter(&self) -> Option<&dyn QueryRewriter> {
+        None
+    }
+
+    /// Get the `ResultCache` consulted on `Execute`, none by default. See
+    /// `cache` module for when results are cached.
+    fn result_cache(&self) -> Option<&dyn ResultCache<Self::Statement>> {
+        None
+    }
+
+    async fn on_parse<C>(&self, client: &mut C, mut message: Parse) -> PgWireResult<()>
     where
         C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
         C::Error: Debug,
         PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
     {
+        // a named statement has to be closed before it's parsed again, the
+        // unnamed one is replaced
+        if let Some(name) = message.name() {
+            if self.portal_store().get_statement(name).is_some() {
+                return Err(PgWireError::DuplicateStatement(name.clone()));
+            }
+        }
+        if let Some(rewriter) = self.query_rewriter() {
+            let name = message.name().as_deref().unwrap_or(DEFAULT_NAME);
+            if let Some(query) = rewriter.rewrite(Some(name), message.query())? {
+                message.set_query(query);
+            }
+        }
         let stmt = StoredStatement::parse(&message, self.query_parser().as_ref())?;
         self.portal_store().put_statement(Arc::new(stmt));
         client
@@ -133,27 +277,70 @@ pub trait ExtendedQueryHandler: Send + Sync {
     {
         let portal_name = message.name().as_deref().unwrap_or(DEFAULT_NAME);
         if let Some(portal) = self.portal_store().get_portal(portal_name) {
-            match self
-                .do_query(client, portal.as_ref(), *message.max_rows() as usize)
-                .await?
-            {
+            let max_rows = *message.max_rows() as usize;
+            if let Some(suspended) = portal.take_suspended() {
+                return resume_portal(client, &portal, suspended, max_rows).await;
+            }
+
+            let status = client.transaction_status();
+            let cache = self.result_cache().filter(|_| {
+                max_rows == 0
+                    && status == TransactionStatus::Idle
+                    && portal.statement().transaction_control().is_none()
+            });
+            if let Some(cache) = cache {
+                if let Some(cached) = cache.get(&portal).await {
+                    if send_cached_result(client, &portal, &cached).await? {
+                        return Ok(());
+                    }
+                }
+            }
+
+            let response = self.do_query(client, portal.as_ref(), max_rows).await?;
+            // the handler setting transaction status wins over detection
+            let control = if client.transaction_status() != status {
+                None
+            } else if self.detect_transaction_control() {
+                *portal.statement().transaction_control()
+            } else {
+                savepoint_rollback(*portal.statement().transaction_control())
+            };
+            let isolation = if self.detect_transaction_control() {
+                *portal.statement().isolation_setting()
+            } else {
+                None
+            };
+            match response {
                 Response::Query(results) => {
-                    send_query_response(client, results, false).await?;
+                    send_portal_query_response(client, &portal, results, max_rows, cache).await?;
+                }
+                Response::DeferredQuery(results) => {
+                    let results = results.await?;
+                    send_portal_query_response(client, &portal, results, max_rows, cache).await?;
                 }
                 Response::Execution(tag) => {
-                    send_execution_response(client, tag).await?;
+                    let control = control.filter(|_| {
+                        self.detect_transaction_control() || tag.command() == "ROLLBACK"
+                    });
+                    send_execution_response(client, tag, control, isolation).await?;
                 }
                 Response::Error(err) => {
+                    client.set_transaction_status(client.transaction_status().after_error());
                     client
                         .send(PgWireBackendMessage::ErrorResponse((*err).into()))
                         .await?;
+                    client.set_state(super::PgWireConnectionState::AwaitingSync);
+                }
+                Response::CopyIn(copy) => {
+                    client
+                        .send(PgWireBackendMessage::CopyInResponse((&copy).into()))
+                        .await?;
+                    client.set_state(super::PgWireConnectionState::CopyInProgress(true));
+                }
+                Response::CopyOut(copy, data) => {
+                    send_copy_out_response(client, copy, data).await?;
                 }
             }
-            client
-                .send(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(
-                    READY_STATUS_IDLE,
-                )))
-                .await?;
 
             Ok(())
         } else {
@@ -174,8 +361,27 @@ pub trait ExtendedQueryHandler: Send + Sync {
                     let describe_response = self
                         .do_describe(client, StatementOrPortal::Statement(&stmt))
                         .await?;
+                    // keep inferred types for decoding parameters, and the
+                    // number of columns for result formats, of portals bound
+                    // from the statement
+                    let result_len = if *describe_response.no_data() {
+                        0
+                    } else {
+                        describe_response.fields().len()
+                    };
+                    let parameter_types = describe_response
+                        .parameters()
+                        .as_ref()
+                        .filter(|types| *types != stmt.parameter_types());
+                    if parameter_types.is_some() || *stmt.result_len() != Some(result_len) {
+                        let mut described = stmt.as_ref().clone();
+                        if let Some(parameter_types) = parameter_types {
+                            described.set_parameter_types(parameter_types.clone());
+                        }
+                        described.set_result_len(Some(result_len));
+                        self.portal_store().put_statement(Arc::new(described));
+                    }
                     if let Some(parameter_types) = describe_response.parameters() {
-                        // parameter type inference
                         client
                             .send(PgWireBackendMessage::ParameterDescription(
                                 ParameterDescription::new(
@@ -184,15 +390,16 @@ pub trait ExtendedQueryHandler: Send + Sync {
                             ))
                             .await?;
                     }
-                    let row_desc = into_row_description(describe_response.fields());
-                    client
-                        .send(PgWireBackendMessage::RowDescription(row_desc))
-                        .await?;
-                    client
-                        .send(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(
-                            READY_STATUS_IDLE,
-                        )))
-                        .await?;
+                    if *describe_response.no_data() {
+                        client
+                            .send(PgWireBackendMessage::NoData(NoData::new()))
+                            .await?;
+                    } else {
+                        let row_desc = into_row_description(describe_response.fields());
+                        client
+                            .send(PgWireBackendMessage::RowDescription(row_desc))
+                            .await?;
+                    }
                 } else {
                     return Err(PgWireError::StatementNotFound(name.to_owned()));
                 }
@@ -202,8 +409,16 @@ pub trait ExtendedQueryHandler: Send + Sync {
                     let describe_response = self
                         .do_describe(client, StatementOrPortal::Portal(&portal))
                         .await?;
+                    // parameters are never sent for a portal
+                    if *describe_response.no_data() {
+                        client
+                            .send(PgWireBackendMessage::NoData(NoData::new()))
+                            .await?;
+                        return Ok(());
+                    }
                     let row_schema = describe_response.fields();
-                    let row_desc = into_row_description(row_schema);
+                    let formats = portal.checked_result_formats(row_schema.len())?;
+                    let row_desc = into_row_description_with_formats(row_schema, &formats);
                     client
                         .send(PgWireBackendMessage::RowDescription(row_desc))
                         .await?;
@@ -217,12 +432,24 @@ pub trait ExtendedQueryHandler: Send + Sync {
         Ok(())
     }
 
+    /// Executed on `Sync` request arrived, which ends current extended query
+    /// batch.
+    ///
+    /// The default implementation closes the unnamed portal, and responds
+    /// with `ReadyForQuery`. Named statements and portals are kept. The
+    /// "ignore until sync" state after an error is reset before this call.
     async fn on_sync<C>(&self, client: &mut C, _message: PgSync) -> PgWireResult<()>
     where
         C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
         C::Error: Debug,
         PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
     {
+        self.portal_store().rm_portal(DEFAULT_NAME);
+        client
+            .feed(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(
+                client.transaction_status().to_ready_status(),
+            )))
+            .await?;
         client.flush().await?;
         Ok(())
     }
@@ -246,15 +473,22 @@ pub trait ExtendedQueryHandler: Send + Sync {
         client
             .send(PgWireBackendMessage::CloseComplete(CloseComplete))
             .await?;
-        client
-            .send(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(
-                READY_STATUS_IDLE,
-            )))
-            .await?;
         Ok(())
     }
 
     /// Return resultset metadata without actually execute statement or portal
+    ///
+    /// For a statement, this is where parameter types are inferred.
+    /// Parameters the client left unspecified are `unknown` in
+    /// `parameter_types` of the statement, or missing at its end. Return the
+    /// types of all parameters, inferred from the query, with result fields
+    /// following from them, like `int4` for both of `SELECT $1 + 1`. They are
+    /// sent in `ParameterDescription` and `RowDescription`, and inferred
+    /// types replace `parameter_types` of the stored statement, so that
+    /// portals bound afterwards decode parameters with them.
+    ///
+    /// Clients may bind without describing first, then `do_query` gets
+    /// parameters of `unknown` types.
     async fn do_describe<C>(
         &self,
         client: &mut C,
@@ -268,7 +502,13 @@ pub trait ExtendedQueryHandler: Send + Sync {
     ///
     /// - `client`: Information of the client sending the query
     /// - `portal`: Statement and parameters for the query
-    /// - `max_rows`: Max requested rows of the query
+    /// - `max_rows`: Max requested rows of the query, 0 for all rows
+    ///
+    /// The response doesn't need to respect `max_rows`. Only that many rows
+    /// are sent for the `Execute`, followed by `PortalSuspended` when there
+    /// are more. The rest of the rows is then kept in the portal, and sent
+    /// by the next `Execute` of it without calling `do_query` again. See
+    /// `QueryResponse::suspendable` for keeping long streams unpolled.
     async fn do_query<'a, 'b: 'a, C>(
         &'b self,
         client: &mut C,
@@ -279,11 +519,57 @@ pub trait ExtendedQueryHandler: Send + Sync {
         C: ClientInfo + Unpin + Send + Sync;
 }
 
+/// Send `ReadyForQuery` with given transaction status, for handlers that
+/// implement their own message flow instead of the default `on_query` or
+/// `on_sync`. Other messages can be sent to client as is with `SinkExt::send`
+/// or `SinkExt::feed`.
+///
+/// The transaction status of the connection is updated, and the connection
+/// is ready for next query. On error of the current query, `ReadyForQuery` is
+/// not sent again after `ErrorResponse`.
+pub async fn send_ready_for_query<C>(client: &mut C, status: TransactionStatus) -> PgWireResult<()>
+where
+    C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+    C::Error: Debug,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+{
+    client.set_transaction_status(status);
+    client
+        .send(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(
+            status.to_ready_status(),
+        )))
+        .await?;
+    client.set_state(super::PgWireConnectionState::ReadyForQuery);
+    Ok(())
+}
+
+/// Max rows of a response, that is not `suspendable`, read into memory when
+/// its portal is suspended at `max_rows`
+pub const MAX_SUSPENDED_ROWS: usize = 10_000;
+
+/// How sending of a query response ended
+enum SentResponse<'a> {
+    /// all rows were sent, and completed with the tag
+    Completed(Tag),
+    /// `max_rows` rows were sent and `PortalSuspended`, leaving the rest
+    Suspended(QueryResponse<'a>),
+}
+
+/// Send rows and command tag of a query response, returning the tag.
+///
+/// With `max_rows` other than 0, at most that many rows are sent. When there
+/// are more, `PortalSuspended` is sent instead of the tag, and the rest of
+/// the response is returned.
+///
+/// Rows are also collected into `collect`, up to the given number plus one,
+/// so that the caller can tell whether the result exceeds it.
 async fn send_query_response<'a, C>(
     client: &mut C,
     results: QueryResponse<'a>,
     send_describe: bool,
-) -> PgWireResult<()>
+    max_rows: usize,
+    mut collect: Option<(&mut Vec<DataRow>, usize)>,
+) -> PgWireResult<SentResponse<'a>>
 where
     C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
     C::Error: Debug,
@@ -292,38 +578,278 @@ where
     let QueryResponse {
         row_schema,
         mut data_rows,
+        command_tag,
     } = results;
 
+    // The first item is polled before `RowDescription`, so a query failing
+    // before any row is produced responds with just the `ErrorResponse`.
+    let mut first = data_rows.next().await.transpose()?;
+
     // Simple query has row_schema in query response. For extended query,
     // row_schema is returned as response of `Describe`.
     if send_describe {
         let row_desc = into_row_description(&row_schema);
         client
-            .send(PgWireBackendMessage::RowDescription(row_desc))
+            .feed(PgWireBackendMessage::RowDescription(row_desc))
             .await?;
     }
 
+    // Rows are buffered in client without flushing each of them. The client
+    // flushes when its buffer is full, and the next row is only polled after
+    // that, so a slow reader slows down the stream.
     let mut rows = 0;
-    while let Some(row) = data_rows.next().await {
-        let row = row?;
-        rows += 1;
-        client.send(PgWireBackendMessage::DataRow(row)).await?;
+    while let Some(item) = match first.take() {
+        Some(item) => Some(Ok(item)),
+        None => data_rows.next().await,
+    } {
+        match item? {
+            QueryResponseItem::Row(row) => {
+                if max_rows > 0 && rows == max_rows {
+                    client
+                        .send(PgWireBackendMessage::PortalSuspended(PortalSuspended))
+                        .await?;
+                    return Ok(SentResponse::Suspended(QueryResponse {
+                        row_schema,
+                        data_rows: data_rows.prepend(row.into()),
+                        command_tag,
+                    }));
+                }
+                rows += 1;
+                if let Some((ref mut collected, max)) = collect {
+                    if collected.len() <= max {
+                        collected.push(row.clone());
+                    }
+                }
+                client.feed(PgWireBackendMessage::DataRow(row)).await?;
+            }
+            QueryResponseItem::Notice(notice) => {
+                client
+                    .feed(PgWireBackendMessage::NoticeResponse(notice))
+                    .await?;
+            }
+        }
     }
 
-    let tag = Tag::new_for_query(rows);
+    let tag = match command_tag {
+        Some(tag) => tag.with_default_rows(rows as u64),
+        None => Tag::new_for_query(rows as u64),
+    };
     client
-        .send(PgWireBackendMessage::CommandComplete(tag.into()))
+        .send(PgWireBackendMessage::CommandComplete(tag.clone().into()))
         .await?;
 
+    Ok(SentResponse::Completed(tag))
+}
+
+async fn send_portal_query_response<'a, C, S>(
+    client: &mut C,
+    portal: &Portal<S>,
+    results: QueryResponse<'a>,
+    max_rows: usize,
+    cache: Option<&dyn ResultCache<S>>,
+) -> PgWireResult<()>
+where
+    C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+    C::Error: Debug,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    S: Send + Sync,
+{
+    // the row description has been sent with portal formats on describe, data
+    // rows must be encoded accordingly
+    let formats = portal.checked_result_formats(results.row_schema().len())?;
+    check_field_formats(results.row_schema(), &formats)?;
+    // responses with their own tag, like `UPDATE ... RETURNING`, have side
+    // effects and are not cached
+    let cache = cache.filter(|_| results.command_tag().is_none());
+    let sent = match cache {
+        Some(cache) => {
+            let row_schema = results.row_schema().clone();
+            let mut rows = Vec::new();
+            let max = cache.max_rows();
+            let sent =
+                send_query_response(client, results, false, max_rows, Some((&mut rows, max)))
+                    .await?;
+            // the statement may have started a transaction
+            if let SentResponse::Completed(tag) = &sent {
+                if rows.len() <= max && client.transaction_status() == TransactionStatus::Idle {
+                    let result = CachedResult::new(row_schema, rows, tag.clone());
+                    cache.put(portal, Arc::new(result)).await;
+                }
+            }
+            sent
+        }
+        None => send_query_response(client, results, false, max_rows, None).await?,
+    };
+    if let SentResponse::Suspended(rest) = sent {
+        suspend_portal(portal, rest).await;
+    }
     Ok(())
 }
 
-async fn send_execution_response<C>(client: &mut C, tag: Tag) -> PgWireResult<()>
+/// Keep the rest of a response in the suspended portal.
+///
+/// A `suspendable` response is kept as is. Others may borrow from the handler
+/// and the portal for just this `Execute`, so the rest of their rows is read
+/// into memory, up to the first error, which is kept to fail the `Execute`
+/// reaching it. More than `MAX_SUSPENDED_ROWS` rows fail with `54000` there.
+async fn suspend_portal<S>(portal: &Portal<S>, rest: QueryResponse<'_>) {
+    let QueryResponse {
+        data_rows,
+        command_tag,
+        ..
+    } = rest;
+    let items = match data_rows {
+        DataRows::Owned(items) => items,
+        DataRows::Borrowed(mut items) => {
+            let mut buffered = VecDeque::new();
+            while let Some(item) = items.next().await {
+                let failed = item.is_err();
+                buffered.push_back(item);
+                if failed {
+                    break;
+                }
+                if buffered.len() > MAX_SUSPENDED_ROWS {
+                    buffered.push_back(Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                        Severity::Error,
+                        SqlState::ProgramLimitExceeded,
+                        format!(
+                            "more than {MAX_SUSPENDED_ROWS} rows left in suspended portal, \
+                             the response is not suspendable"
+                        ),
+                    )))));
+                    break;
+                }
+            }
+            stream::iter(buffered).boxed()
+        }
+    };
+    portal.suspend(SuspendedResult { items, command_tag });
+}
+
+/// Continue a suspended portal with up to `max_rows` of its buffered rows.
+async fn resume_portal<C, S>(
+    client: &mut C,
+    portal: &Portal<S>,
+    suspended: SuspendedResult,
+    max_rows: usize,
+) -> PgWireResult<()>
 where
     C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
     C::Error: Debug,
     PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
 {
+    let SuspendedResult { items, command_tag } = suspended;
+    // the row description was sent for the first execution, the schema is
+    // not used again
+    let results = QueryResponse {
+        row_schema: Arc::default(),
+        data_rows: DataRows::Owned(items),
+        command_tag,
+    };
+    if let SentResponse::Suspended(rest) =
+        send_query_response(client, results, false, max_rows, None).await?
+    {
+        suspend_portal(portal, rest).await;
+    }
+    Ok(())
+}
+
+/// Replay a cached result of `portal`. Returns `false` without sending
+/// anything if the cached rows are not encoded in formats of the portal, and
+/// fails like the query if the portal has a wrong number of format codes.
+async fn send_cached_result<C, S>(
+    client: &mut C,
+    portal: &Portal<S>,
+    cached: &CachedResult,
+) -> PgWireResult<bool>
+where
+    C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+    C::Error: Debug,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+{
+    let formats = portal.checked_result_formats(cached.row_schema().len())?;
+    if check_field_formats(cached.row_schema(), &formats).is_err() {
+        return Ok(false);
+    }
+    for row in cached.rows() {
+        client
+            .feed(PgWireBackendMessage::DataRow(row.clone()))
+            .await?;
+    }
+    client
+        .send(PgWireBackendMessage::CommandComplete(
+            cached.tag().clone().into(),
+        ))
+        .await?;
+    Ok(true)
+}
+
+/// `ROLLBACK TO SAVEPOINT` of a statement tracked by command tags, which
+/// completes with tag `ROLLBACK` like `ROLLBACK`
+fn savepoint_rollback(control: Option<TransactionControl>) -> Option<TransactionControl> {
+    control.filter(|control| *control == TransactionControl::RollbackToSavepoint)
+}
+
+async fn send_execution_response<C>(
+    client: &mut C,
+    tag: Tag,
+    control: Option<TransactionControl>,
+    isolation: Option<IsolationSetting>,
+) -> PgWireResult<()>
+where
+    C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+    C::Error: Debug,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+{
+    let status = client.transaction_status();
+    let new_status = match control {
+        Some(control) => control.apply(status),
+        None => status.after_command(tag.command()),
+    };
+    client.set_transaction_status(new_status);
+    let extensions = client.extensions_mut();
+    if isolation.is_some() && extensions.get::<TransactionIsolation>().is_none() {
+        extensions.insert(TransactionIsolation::default());
+    }
+    if let Some(levels) = extensions.get_mut::<TransactionIsolation>() {
+        levels.apply(isolation, status, new_status);
+    }
+    // flushed with `ReadyForQuery`, or on `Flush` and `Sync` in extended
+    // query
+    client
+        .feed(PgWireBackendMessage::CommandComplete(tag.into()))
+        .await?;
+
+    Ok(())
+}
+
+async fn send_copy_out_response<'a, C>(
+    client: &mut C,
+    copy: CopyResponse,
+    mut data: BoxStream<'a, PgWireResult<CopyOutData>>,
+) -> PgWireResult<()>
+where
+    C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+    C::Error: Debug,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+{
+    client
+        .feed(PgWireBackendMessage::CopyOutResponse((&copy).into()))
+        .await?;
+    let mut rows = 0;
+    while let Some(chunk) = data.next().await {
+        let chunk = chunk?;
+        rows += *chunk.rows();
+        client
+            .feed(PgWireBackendMessage::CopyData(CopyData::new(
+                chunk.data().clone(),
+            )))
+            .await?;
+    }
+    client
+        .feed(PgWireBackendMessage::CopyDone(CopyDone::new()))
+        .await?;
+    let tag = Tag::new_for_execution("COPY", Some(rows));
     client
         .send(PgWireBackendMessage::CommandComplete(tag.into()))
         .await?;
@@ -381,3 +907,1708 @@ impl ExtendedQueryHandler for PlaceholderExtendedQueryHandler {
         unimplemented!("Extended Query is not implemented on this server.")
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    use bytes::{BufMut, Bytes, BytesMut};
+
+    use futures::stream;
+
+    use super::*;
+    use crate::api::mock::MockClient;
+    use crate::api::portal::Format;
+    use crate::api::results::{DataRowEncoder, FieldFormat, FieldInfo};
+    use crate::api::transaction::IsolationLevel;
+    use crate::api::{PgWireConnectionState, Type};
+    use crate::error::{ErrorInfo, Severity, SqlState};
+    use crate::messages::Message;
+
+    #[derive(Default)]
+    struct RecordingQueryHandler {
+        queries: Mutex<Vec<String>>,
+        raw_queries: Mutex<Vec<Vec<u8>>>,
+    }
+
+    #[async_trait]
+    impl SimpleQueryHandler for RecordingQueryHandler {
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &C,
+            query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            self.queries.lock().unwrap().push(query.to_owned());
+            Ok(vec![Response::Execution(Tag::new_for_execution(
+                "OK", None,
+            ))])
+        }
+
+        async fn do_raw_query<'a, 'b: 'a, C>(
+            &'b self,
+            client: &C,
+            query: &'a str,
+            raw: &'a [u8],
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            self.raw_queries.lock().unwrap().push(raw.to_vec());
+            self.do_query(client, query).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_simple_query_is_untouched() {
+        let handler = RecordingQueryHandler::default();
+        let mut client = MockClient::new();
+
+        let query = "  SELECT 1; SELECT 2 -- trailing comment\n\t ";
+        handler
+            .on_query(&mut client, Query::new(query.to_owned()))
+            .await
+            .unwrap();
+
+        assert_eq!(vec![query.to_owned()], *handler.queries.lock().unwrap());
+        assert!(matches!(
+            client.take_messages().as_slice(),
+            [
+                PgWireBackendMessage::CommandComplete(_),
+                PgWireBackendMessage::ReadyForQuery(_)
+            ]
+        ));
+
+        // invalid utf-8 bytes are kept for do_raw_query
+        let mut buf = BytesMut::new();
+        buf.put_u8(b'Q');
+        buf.put_i32(12);
+        buf.put_slice(b"SELECT\xff\0");
+        let message = Query::decode(&mut buf).unwrap().unwrap();
+        handler.on_query(&mut client, message).await.unwrap();
+        assert_eq!("SELECT\u{fffd}", handler.queries.lock().unwrap()[1]);
+        assert_eq!(
+            vec![query.as_bytes().to_vec(), b"SELECT\xff".to_vec()],
+            *handler.raw_queries.lock().unwrap()
+        );
+    }
+
+    type CacheKey = (String, Vec<Option<Bytes>>);
+
+    /// cache keyed by query and parameters
+    #[derive(Default)]
+    struct MemResultCache {
+        results: Mutex<HashMap<CacheKey, Arc<CachedResult>>>,
+    }
+
+    #[async_trait]
+    impl ResultCache<String> for MemResultCache {
+        async fn get(&self, portal: &Portal<String>) -> Option<Arc<CachedResult>> {
+            let key = (
+                portal.statement().statement().clone(),
+                portal.parameters().clone(),
+            );
+            self.results.lock().unwrap().get(&key).cloned()
+        }
+
+        async fn put(&self, portal: &Portal<String>, result: Arc<CachedResult>) {
+            let key = (
+                portal.statement().statement().clone(),
+                portal.parameters().clone(),
+            );
+            self.results.lock().unwrap().insert(key, result);
+        }
+    }
+
+    struct FixedRowQueryHandler {
+        portal_store: Arc<MemPortalStore<String>>,
+        honor_portal_format: bool,
+        result_cache: Option<MemResultCache>,
+        command_tag: Option<Tag>,
+        executions: AtomicUsize,
+    }
+
+    impl FixedRowQueryHandler {
+        fn new(honor_portal_format: bool) -> FixedRowQueryHandler {
+            FixedRowQueryHandler {
+                portal_store: Arc::new(MemPortalStore::new()),
+                honor_portal_format,
+                result_cache: None,
+                command_tag: None,
+                executions: AtomicUsize::new(0),
+            }
+        }
+
+        fn schema(&self, format: &Format) -> Vec<FieldInfo> {
+            [
+                ("id", Type::INT4),
+                ("age", Type::INT4),
+                ("name", Type::VARCHAR),
+            ]
+            .into_iter()
+            .enumerate()
+            .map(|(idx, (name, ty))| {
+                let format = if self.honor_portal_format {
+                    format.format_for(idx)
+                } else {
+                    FieldFormat::Text
+                };
+                FieldInfo::new(name.to_owned(), None, None, ty, format)
+            })
+            .collect()
+        }
+    }
+
+    #[async_trait]
+    impl ExtendedQueryHandler for FixedRowQueryHandler {
+        type Statement = String;
+        type PortalStore = MemPortalStore<Self::Statement>;
+        type QueryParser = NoopQueryParser;
+
+        fn portal_store(&self) -> Arc<Self::PortalStore> {
+            self.portal_store.clone()
+        }
+
+        fn query_parser(&self) -> Arc<Self::QueryParser> {
+            Arc::new(NoopQueryParser::new())
+        }
+
+        fn result_cache(&self) -> Option<&dyn ResultCache<Self::Statement>> {
+            self.result_cache
+                .as_ref()
+                .map(|cache| cache as &dyn ResultCache<Self::Statement>)
+        }
+
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &mut C,
+            portal: &'a Portal<Self::Statement>,
+            _max_rows: usize,
+        ) -> PgWireResult<Response<'a>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            self.executions.fetch_add(1, Ordering::Relaxed);
+            let schema = Arc::new(self.schema(portal.result_column_format()));
+            let schema_ref = schema.clone();
+            // 3 rows regardless of max_rows, which is left to the framework
+            let rows = stream::iter(0..3i32).map(move |idx| {
+                let mut encoder = DataRowEncoder::new(schema_ref.clone());
+                encoder.encode_field(&(7 + idx))?;
+                encoder.encode_field(&42i32)?;
+                encoder.encode_field(&"tom")?;
+                encoder.finish()
+            });
+            let response = QueryResponse::new(schema, rows);
+            Ok(Response::Query(match &self.command_tag {
+                Some(tag) => response.with_command_tag(tag.clone()),
+                None => response,
+            }))
+        }
+
+        async fn do_describe<C>(
+            &self,
+            _client: &mut C,
+            target: StatementOrPortal<'_, Self::Statement>,
+        ) -> PgWireResult<DescribeResponse>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            let format = match target {
+                StatementOrPortal::Statement(_) => Format::UnifiedText,
+                StatementOrPortal::Portal(portal) => portal.result_column_format().clone(),
+            };
+            Ok(DescribeResponse::portal(self.schema(&format)))
+        }
+    }
+
+    async fn bind_mixed_format<H>(handler: &H, client: &mut MockClient)
+    where
+        H: ExtendedQueryHandler,
+    {
+        let parse = Parse::new(None, "SELECT id, age, name FROM t".to_owned(), vec![]);
+        handler.on_parse(client, parse).await.unwrap();
+        let bind = Bind::new(None, None, vec![], vec![], vec![0, 1, 0]);
+        handler.on_bind(client, bind).await.unwrap();
+        client.take_messages();
+    }
+
+    #[tokio::test]
+    async fn test_mixed_result_formats() {
+        let handler = FixedRowQueryHandler::new(true);
+        let mut client = MockClient::new();
+        bind_mixed_format(&handler, &mut client).await;
+
+        handler
+            .on_describe(&mut client, Describe::new(TARGET_TYPE_BYTE_PORTAL, None))
+            .await
+            .unwrap();
+        handler
+            .on_execute(&mut client, Execute::new(None, 0))
+            .await
+            .unwrap();
+
+        let messages = client.take_messages();
+        match &messages[0] {
+            PgWireBackendMessage::RowDescription(desc) => assert_eq!(
+                vec![0, 1, 0],
+                desc.fields()
+                    .iter()
+                    .map(|f| *f.format_code())
+                    .collect::<Vec<i16>>()
+            ),
+            msg => panic!("unexpected message {msg:?}"),
+        }
+        match &messages[1] {
+            PgWireBackendMessage::DataRow(row) => {
+                assert_eq!(row.fields()[0].as_deref(), Some(&b"7"[..]));
+                assert_eq!(row.fields()[1].as_deref(), Some(&42i32.to_be_bytes()[..]));
+                assert_eq!(row.fields()[2].as_deref(), Some(&b"tom"[..]));
+            }
+            msg => panic!("unexpected message {msg:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_result_format_count_mismatch() {
+        let handler = FixedRowQueryHandler::new(true);
+        let mut client = MockClient::new();
+        let parse = Parse::new(None, "SELECT id, age, name FROM t".to_owned(), vec![]);
+        handler.on_parse(&mut client, parse).await.unwrap();
+        // codes for two of three columns are accepted on bind
+        let bind = Bind::new(None, None, vec![], vec![], vec![0, 1]);
+        handler.on_bind(&mut client, bind).await.unwrap();
+        client.take_messages();
+
+        let describe = Describe::new(TARGET_TYPE_BYTE_PORTAL, None);
+        let execute = Execute::new(None, 0);
+        for result in [
+            handler.on_describe(&mut client, describe).await,
+            handler.on_execute(&mut client, execute).await,
+        ] {
+            match result {
+                Err(PgWireError::UserError(info)) => assert_eq!("08P01", info.code()),
+                result => panic!("unexpected result {result:?}"),
+            }
+        }
+        assert!(client.take_messages().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_execute_row_count() {
+        let handler = FixedRowQueryHandler::new(true);
+
+        for (max_rows, expected) in [(0, "SELECT 3"), (3, "SELECT 3"), (5, "SELECT 3")] {
+            let mut client = MockClient::new();
+            bind_mixed_format(&handler, &mut client).await;
+            handler
+                .on_execute(&mut client, Execute::new(None, max_rows))
+                .await
+                .unwrap();
+
+            let messages = client.take_messages();
+            assert_eq!(4, messages.len());
+            match messages.last() {
+                Some(PgWireBackendMessage::CommandComplete(tag)) => {
+                    assert_eq!(expected, tag.tag())
+                }
+                msg => panic!("unexpected message {msg:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_execute_suspended_portal() {
+        let handler = FixedRowQueryHandler::new(true);
+        let mut client = MockClient::new();
+        bind_mixed_format(&handler, &mut client).await;
+
+        handler
+            .on_execute(&mut client, Execute::new(None, 2))
+            .await
+            .unwrap();
+        let messages = client.take_messages();
+        assert_eq!(3, messages.len());
+        assert!(matches!(messages[0], PgWireBackendMessage::DataRow(_)));
+        assert!(matches!(messages[1], PgWireBackendMessage::DataRow(_)));
+        assert!(matches!(
+            messages[2],
+            PgWireBackendMessage::PortalSuspended(_)
+        ));
+        let portal = handler.portal_store.get_portal(DEFAULT_NAME).unwrap();
+        assert!(portal.is_suspended());
+
+        // the rest of the rows, without querying again
+        handler
+            .on_execute(&mut client, Execute::new(None, 2))
+            .await
+            .unwrap();
+        let messages = client.take_messages();
+        assert_eq!(2, messages.len());
+        match &messages[0] {
+            PgWireBackendMessage::DataRow(row) => {
+                assert_eq!(row.fields()[0].as_deref(), Some(&b"9"[..]));
+            }
+            msg => panic!("unexpected message {msg:?}"),
+        }
+        match &messages[1] {
+            PgWireBackendMessage::CommandComplete(tag) => assert_eq!("SELECT 1", tag.tag()),
+            msg => panic!("unexpected message {msg:?}"),
+        }
+        assert!(!portal.is_suspended());
+        assert_eq!(1, handler.executions.load(Ordering::Relaxed));
+    }
+
+    /// Extended query handler of an endless stream of rows
+    struct EndlessQueryHandler {
+        portal_store: Arc<MemPortalStore<String>>,
+        suspendable: bool,
+        produced: Arc<AtomicUsize>,
+    }
+
+    impl EndlessQueryHandler {
+        fn new(suspendable: bool) -> EndlessQueryHandler {
+            EndlessQueryHandler {
+                portal_store: Arc::new(MemPortalStore::new()),
+                suspendable,
+                produced: Arc::default(),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl ExtendedQueryHandler for EndlessQueryHandler {
+        type Statement = String;
+        type PortalStore = MemPortalStore<Self::Statement>;
+        type QueryParser = NoopQueryParser;
+
+        fn portal_store(&self) -> Arc<Self::PortalStore> {
+            self.portal_store.clone()
+        }
+
+        fn query_parser(&self) -> Arc<Self::QueryParser> {
+            Arc::new(NoopQueryParser::new())
+        }
+
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &mut C,
+            _portal: &'a Portal<Self::Statement>,
+            _max_rows: usize,
+        ) -> PgWireResult<Response<'a>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            let schema = Arc::new(vec![FieldInfo::new(
+                "v".to_owned(),
+                None,
+                None,
+                Type::INT4,
+                FieldFormat::Text,
+            )]);
+            let schema_ref = schema.clone();
+            let produced = self.produced.clone();
+            let rows = stream::iter(0..).map(move |v: i32| {
+                produced.fetch_add(1, Ordering::SeqCst);
+                let mut encoder = DataRowEncoder::new(schema_ref.clone());
+                encoder.encode_field(&v)?;
+                encoder.finish()
+            });
+            let response = QueryResponse::new(schema, rows);
+            Ok(Response::Query(if self.suspendable {
+                response.suspendable()
+            } else {
+                response
+            }))
+        }
+
+        async fn do_describe<C>(
+            &self,
+            _client: &mut C,
+            _target: StatementOrPortal<'_, Self::Statement>,
+        ) -> PgWireResult<DescribeResponse>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            Ok(DescribeResponse::no_data_portal())
+        }
+    }
+
+    async fn bind_endless<H>(handler: &H, client: &mut MockClient)
+    where
+        H: ExtendedQueryHandler,
+    {
+        let parse = Parse::new(None, "SELECT v FROM endless".to_owned(), vec![]);
+        handler.on_parse(client, parse).await.unwrap();
+        let bind = Bind::new(None, None, vec![], vec![], vec![]);
+        handler.on_bind(client, bind).await.unwrap();
+        client.take_messages();
+    }
+
+    #[tokio::test]
+    async fn test_suspendable_response() {
+        let handler = EndlessQueryHandler::new(true);
+        let mut client = MockClient::new();
+        bind_endless(&handler, &mut client).await;
+
+        for expected in [["0", "1"], ["2", "3"]] {
+            handler
+                .on_execute(&mut client, Execute::new(None, 2))
+                .await
+                .unwrap();
+            let messages = client.take_messages();
+            assert_eq!(3, messages.len());
+            for (message, expected) in messages.iter().zip(expected) {
+                match message {
+                    PgWireBackendMessage::DataRow(row) => {
+                        assert_eq!(row.fields()[0].as_deref(), Some(expected.as_bytes()));
+                    }
+                    msg => panic!("unexpected message {msg:?}"),
+                }
+            }
+            assert!(matches!(
+                messages[2],
+                PgWireBackendMessage::PortalSuspended(_)
+            ));
+        }
+        // the stream is polled by the rows sent, and the row telling there
+        // are more
+        assert_eq!(5, handler.produced.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_suspended_rows_limit() {
+        let handler = EndlessQueryHandler::new(false);
+        let mut client = MockClient::new();
+        bind_endless(&handler, &mut client).await;
+
+        handler
+            .on_execute(&mut client, Execute::new(None, 1))
+            .await
+            .unwrap();
+        let produced = handler.produced.load(Ordering::SeqCst);
+        assert!(produced <= MAX_SUSPENDED_ROWS + 2, "{produced} rows read");
+        client.take_messages();
+
+        match handler.on_execute(&mut client, Execute::new(None, 0)).await {
+            Err(PgWireError::UserError(info)) => assert_eq!("54000", info.code()),
+            result => panic!("unexpected result {result:?}"),
+        }
+        let rows = client.take_messages();
+        assert!(rows
+            .iter()
+            .all(|msg| matches!(msg, PgWireBackendMessage::DataRow(_))));
+        assert_eq!(produced - 1, rows.len());
+    }
+
+    #[tokio::test]
+    async fn test_result_cache() {
+        let mut handler = FixedRowQueryHandler::new(true);
+        handler.result_cache = Some(MemResultCache::default());
+
+        let mut results = vec![];
+        for _ in 0..2 {
+            let mut client = MockClient::new();
+            bind_mixed_format(&handler, &mut client).await;
+            handler
+                .on_execute(&mut client, Execute::new(None, 0))
+                .await
+                .unwrap();
+            results.push(client.take_messages());
+        }
+        // the second execution is replayed from cache
+        assert_eq!(1, handler.executions.load(Ordering::Relaxed));
+        assert_eq!(4, results[1].len());
+        assert_eq!(format!("{:?}", results[0]), format!("{:?}", results[1]));
+
+        // partial executions are neither cached nor replayed
+        let mut client = MockClient::new();
+        bind_mixed_format(&handler, &mut client).await;
+        handler
+            .on_execute(&mut client, Execute::new(None, 2))
+            .await
+            .unwrap();
+        assert_eq!(2, handler.executions.load(Ordering::Relaxed));
+
+        // nor in a transaction block
+        let mut client = MockClient::new();
+        client.set_transaction_status(TransactionStatus::Transaction);
+        bind_mixed_format(&handler, &mut client).await;
+        handler
+            .on_execute(&mut client, Execute::new(None, 0))
+            .await
+            .unwrap();
+        assert_eq!(3, handler.executions.load(Ordering::Relaxed));
+        assert_eq!(4, client.take_messages().len());
+
+        // a cached result is not replayed with codes for two of three columns
+        let mut client = MockClient::new();
+        let parse = Parse::new(None, "SELECT id, age, name FROM t".to_owned(), vec![]);
+        handler.on_parse(&mut client, parse).await.unwrap();
+        let bind = Bind::new(None, None, vec![], vec![], vec![0, 1]);
+        handler.on_bind(&mut client, bind).await.unwrap();
+        client.take_messages();
+        match handler.on_execute(&mut client, Execute::new(None, 0)).await {
+            Err(PgWireError::UserError(info)) => assert_eq!("08P01", info.code()),
+            result => panic!("unexpected result {result:?}"),
+        }
+        assert!(client.take_messages().is_empty());
+        assert_eq!(3, handler.executions.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn test_result_cache_skips_command_tag() {
+        let mut handler = FixedRowQueryHandler::new(true);
+        handler.result_cache = Some(MemResultCache::default());
+        handler.command_tag = Some(Tag::new_for_execution("UPDATE", None));
+
+        for executions in 1..=2 {
+            let mut client = MockClient::new();
+            bind_mixed_format(&handler, &mut client).await;
+            handler
+                .on_execute(&mut client, Execute::new(None, 0))
+                .await
+                .unwrap();
+            match client.take_messages().last() {
+                Some(PgWireBackendMessage::CommandComplete(tag)) => {
+                    assert_eq!("UPDATE 3", tag.tag())
+                }
+                msg => panic!("unexpected message {msg:?}"),
+            }
+            assert_eq!(executions, handler.executions.load(Ordering::Relaxed));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_describe_binary_portal() {
+        // the handler describes its columns as text, the portal formats are
+        // reported anyway
+        let handler = FixedRowQueryHandler::new(false);
+        let mut client = MockClient::new();
+        let parse = Parse::new(None, "SELECT id, age, name FROM t".to_owned(), vec![]);
+        handler.on_parse(&mut client, parse).await.unwrap();
+        // a single format code applies to all columns
+        let bind = Bind::new(None, None, vec![], vec![], vec![1]);
+        handler.on_bind(&mut client, bind).await.unwrap();
+        client.take_messages();
+
+        handler
+            .on_describe(&mut client, Describe::new(TARGET_TYPE_BYTE_PORTAL, None))
+            .await
+            .unwrap();
+        match client.take_messages().as_slice() {
+            [PgWireBackendMessage::RowDescription(desc)] => assert_eq!(
+                vec![1, 1, 1],
+                desc.fields()
+                    .iter()
+                    .map(|f| *f.format_code())
+                    .collect::<Vec<i16>>()
+            ),
+            messages => panic!("unexpected messages {messages:?}"),
+        }
+
+        // rows in another format than described are not sent
+        assert!(matches!(
+            handler.on_execute(&mut client, Execute::new(None, 0)).await,
+            Err(PgWireError::ResultFormatMismatch(0, FieldFormat::Binary))
+        ));
+        assert!(client.take_messages().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_result_format_mismatch() {
+        let handler = FixedRowQueryHandler::new(false);
+        let mut client = MockClient::new();
+        bind_mixed_format(&handler, &mut client).await;
+
+        handler
+            .on_describe(&mut client, Describe::new(TARGET_TYPE_BYTE_PORTAL, None))
+            .await
+            .unwrap();
+        match &client.take_messages()[0] {
+            PgWireBackendMessage::RowDescription(desc) => {
+                assert_eq!(1, *desc.fields()[1].format_code())
+            }
+            msg => panic!("unexpected message {msg:?}"),
+        }
+
+        assert!(matches!(
+            handler.on_execute(&mut client, Execute::new(None, 0)).await,
+            Err(PgWireError::ResultFormatMismatch(1, FieldFormat::Binary))
+        ));
+        assert!(client.take_messages().is_empty());
+    }
+
+    #[derive(Default)]
+    struct DeferredQueryHandler {
+        events: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl SimpleQueryHandler for DeferredQueryHandler {
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &C,
+            query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            Ok(query
+                .split(';')
+                .map(|stmt| {
+                    let events = self.events.clone();
+                    Response::deferred_query(async move {
+                        events.lock().unwrap().push(format!("plan {stmt}"));
+                        let schema = Arc::new(vec![FieldInfo::new(
+                            stmt.to_owned(),
+                            None,
+                            None,
+                            Type::VARCHAR,
+                            FieldFormat::Text,
+                        )]);
+                        let schema_ref = schema.clone();
+                        let rows = stream::iter(vec![stmt]).map(move |v| {
+                            events.lock().unwrap().push(format!("row {v}"));
+                            let mut encoder = DataRowEncoder::new(schema_ref.clone());
+                            encoder.encode_field(&v)?;
+                            encoder.finish()
+                        });
+                        Ok(QueryResponse::new(schema, rows))
+                    })
+                })
+                .collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deferred_query_response() {
+        let handler = DeferredQueryHandler::default();
+        let mut client = MockClient::new();
+
+        handler
+            .on_query(&mut client, Query::new("a;b".to_owned()))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            vec!["plan a", "row a", "plan b", "row b"],
+            *handler.events.lock().unwrap()
+        );
+        let messages = client.take_messages();
+        assert!(matches!(
+            messages.as_slice(),
+            [
+                PgWireBackendMessage::RowDescription(_),
+                PgWireBackendMessage::DataRow(_),
+                PgWireBackendMessage::CommandComplete(_),
+                PgWireBackendMessage::RowDescription(_),
+                PgWireBackendMessage::DataRow(_),
+                PgWireBackendMessage::CommandComplete(_),
+                PgWireBackendMessage::ReadyForQuery(_),
+            ]
+        ));
+        match &messages[3] {
+            PgWireBackendMessage::RowDescription(desc) => assert_eq!("b", desc.fields()[0].name()),
+            msg => panic!("unexpected message {msg:?}"),
+        }
+    }
+
+    /// Handler returning `OK` for every statement, with transaction status
+    /// detected from query strings
+    #[derive(Default)]
+    struct TransactionQueryHandler {
+        portal_store: Arc<MemPortalStore<String>>,
+    }
+
+    #[async_trait]
+    impl SimpleQueryHandler for TransactionQueryHandler {
+        fn detect_transaction_control(&self) -> bool {
+            true
+        }
+
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &C,
+            query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            Ok(TransactionControl::detect(query)
+                .into_iter()
+                .map(|_| Response::Execution(Tag::new_for_execution("OK", None)))
+                .collect())
+        }
+    }
+
+    #[async_trait]
+    impl ExtendedQueryHandler for TransactionQueryHandler {
+        type Statement = String;
+        type PortalStore = MemPortalStore<Self::Statement>;
+        type QueryParser = NoopQueryParser;
+
+        fn portal_store(&self) -> Arc<Self::PortalStore> {
+            self.portal_store.clone()
+        }
+
+        fn query_parser(&self) -> Arc<Self::QueryParser> {
+            Arc::new(NoopQueryParser::new())
+        }
+
+        fn detect_transaction_control(&self) -> bool {
+            true
+        }
+
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            client: &mut C,
+            portal: &'a Portal<Self::Statement>,
+            _max_rows: usize,
+        ) -> PgWireResult<Response<'a>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            // this handler sets the status by itself on some statements
+            match portal.statement().statement().as_str() {
+                "ROLLBACK TO missing" => client.set_transaction_status(TransactionStatus::Error),
+                "DISCARD ALL" => client.set_transaction_status(TransactionStatus::Idle),
+                _ => {}
+            }
+            Ok(Response::Execution(Tag::new_for_execution("OK", None)))
+        }
+
+        async fn do_describe<C>(
+            &self,
+            _client: &mut C,
+            target: StatementOrPortal<'_, Self::Statement>,
+        ) -> PgWireResult<DescribeResponse>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            Ok(match target {
+                StatementOrPortal::Statement(stmt) => {
+                    DescribeResponse::no_data_statement(stmt.parameter_types().clone())
+                }
+                StatementOrPortal::Portal(_) => DescribeResponse::no_data_portal(),
+            })
+        }
+    }
+
+    fn ready_status(messages: &[PgWireBackendMessage]) -> Option<u8> {
+        messages.iter().rev().find_map(|msg| match msg {
+            PgWireBackendMessage::ReadyForQuery(ready) => Some(*ready.status()),
+            _ => None,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_describe_no_data() {
+        let handler = TransactionQueryHandler::default();
+        let mut client = MockClient::new();
+        let parse = Parse::new(None, "BEGIN ISOLATION LEVEL $1".to_owned(), vec![25]);
+        handler.on_parse(&mut client, parse).await.unwrap();
+        let bind = Bind::new(None, None, vec![], vec![None], vec![]);
+        handler.on_bind(&mut client, bind).await.unwrap();
+        client.take_messages();
+
+        handler
+            .on_describe(&mut client, Describe::new(TARGET_TYPE_BYTE_STATEMENT, None))
+            .await
+            .unwrap();
+        match client.take_messages().as_slice() {
+            [PgWireBackendMessage::ParameterDescription(params), PgWireBackendMessage::NoData(_)] =>
+            {
+                assert_eq!(&vec![25], params.types())
+            }
+            messages => panic!("unexpected messages {messages:?}"),
+        }
+
+        // no parameters for a portal
+        handler
+            .on_describe(&mut client, Describe::new(TARGET_TYPE_BYTE_PORTAL, None))
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.take_messages().as_slice(),
+            [PgWireBackendMessage::NoData(_)]
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_detect_transaction_control() {
+        let handler = TransactionQueryHandler::default();
+        let mut client = MockClient::new();
+
+        for (query, status) in [
+            ("BEGIN; SELECT 'COMMIT'", b'T'),
+            ("SAVEPOINT sp; /* ROLLBACK */ SELECT 1", b'T'),
+            ("ROLLBACK TO sp", b'T'),
+            ("COMMIT AND CHAIN", b'T'),
+            ("end", b'I'),
+        ] {
+            handler
+                .on_query(&mut client, Query::new(query.to_owned()))
+                .await
+                .unwrap();
+            assert_eq!(
+                Some(status),
+                ready_status(&client.take_messages()),
+                "{query}"
+            );
+        }
+
+        // errors fail the transaction until rollback
+        client.set_transaction_status(TransactionStatus::Transaction);
+        client.set_transaction_status(client.transaction_status().after_error());
+        for (query, status) in [
+            ("ROLLBACK TO SAVEPOINT sp", TransactionStatus::Transaction),
+            // the status set by handler wins
+            ("ROLLBACK TO missing", TransactionStatus::Error),
+            ("ABORT", TransactionStatus::Idle),
+            ("BEGIN", TransactionStatus::Transaction),
+            ("DISCARD ALL", TransactionStatus::Idle),
+        ] {
+            let parse = Parse::new(None, query.to_owned(), vec![]);
+            handler.on_parse(&mut client, parse).await.unwrap();
+            let bind = Bind::new(None, None, vec![], vec![], vec![]);
+            handler.on_bind(&mut client, bind).await.unwrap();
+            handler
+                .on_execute(&mut client, Execute::new(None, 0))
+                .await
+                .unwrap();
+            assert_eq!(status, client.transaction_status(), "{query}");
+        }
+    }
+
+    /// Handler returning postgres command tags, `INSERT 0 2` for `INSERT`,
+    /// with transaction status tracked from them
+    #[derive(Default)]
+    struct UtilityQueryHandler {
+        portal_store: Arc<MemPortalStore<String>>,
+    }
+
+    impl UtilityQueryHandler {
+        fn respond<'a>(query: &str) -> Response<'a> {
+            if query.starts_with("INSERT") {
+                return Response::Execution(Tag::new_for_insert(0, 2));
+            }
+            match Tag::new_for_utility(query) {
+                Some(tag) => Response::Execution(tag),
+                None => Response::Error(Box::new(ErrorInfo::new(
+                    Severity::Error,
+                    SqlState::DivisionByZero,
+                    "division by zero",
+                ))),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl SimpleQueryHandler for UtilityQueryHandler {
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &C,
+            query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            Ok(vec![Self::respond(query)])
+        }
+    }
+
+    #[async_trait]
+    impl ExtendedQueryHandler for UtilityQueryHandler {
+        type Statement = String;
+        type PortalStore = MemPortalStore<Self::Statement>;
+        type QueryParser = NoopQueryParser;
+
+        fn portal_store(&self) -> Arc<Self::PortalStore> {
+            self.portal_store.clone()
+        }
+
+        fn query_parser(&self) -> Arc<Self::QueryParser> {
+            Arc::new(NoopQueryParser::new())
+        }
+
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &mut C,
+            portal: &'a Portal<Self::Statement>,
+            _max_rows: usize,
+        ) -> PgWireResult<Response<'a>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            Ok(Self::respond(portal.statement().statement()))
+        }
+
+        async fn do_describe<C>(
+            &self,
+            _client: &mut C,
+            _target: StatementOrPortal<'_, Self::Statement>,
+        ) -> PgWireResult<DescribeResponse>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            Ok(DescribeResponse::no_data_portal())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_savepoint_status_by_tags() {
+        let handler = UtilityQueryHandler::default();
+        let steps = [
+            ("BEGIN", b'T'),
+            ("SAVEPOINT sp", b'T'),
+            ("SELECT 1/0", b'E'),
+            // `ROLLBACK` tag, but the block is recovered
+            ("ROLLBACK TO SAVEPOINT sp", b'T'),
+            ("RELEASE sp", b'T'),
+            ("SELECT 1/0", b'E'),
+            ("ROLLBACK", b'I'),
+        ];
+
+        let mut client = MockClient::new();
+        for (query, status) in steps {
+            handler
+                .on_query(&mut client, Query::new(query.to_owned()))
+                .await
+                .unwrap();
+            assert_eq!(
+                Some(status),
+                ready_status(&client.take_messages()),
+                "{query}"
+            );
+        }
+
+        let mut client = MockClient::new();
+        for (query, status) in steps {
+            let parse = Parse::new(None, query.to_owned(), vec![]);
+            handler.on_parse(&mut client, parse).await.unwrap();
+            let bind = Bind::new(None, None, vec![], vec![], vec![]);
+            handler.on_bind(&mut client, bind).await.unwrap();
+            handler
+                .on_execute(&mut client, Execute::new(None, 0))
+                .await
+                .unwrap();
+            client.set_state(PgWireConnectionState::ReadyForQuery);
+            handler.on_sync(&mut client, PgSync::new()).await.unwrap();
+            assert_eq!(
+                Some(status),
+                ready_status(&client.take_messages()),
+                "{query}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_execute_portal_without_rows() {
+        let handler = UtilityQueryHandler::default();
+        let mut client = MockClient::new();
+        let parse = Parse::new(None, "INSERT INTO t VALUES (1), (2)".to_owned(), vec![]);
+        handler.on_parse(&mut client, parse).await.unwrap();
+        let bind = Bind::new(None, None, vec![], vec![], vec![]);
+        handler.on_bind(&mut client, bind).await.unwrap();
+        handler
+            .on_describe(&mut client, Describe::new(TARGET_TYPE_BYTE_PORTAL, None))
+            .await
+            .unwrap();
+        handler
+            .on_execute(&mut client, Execute::new(None, 0))
+            .await
+            .unwrap();
+        handler.on_sync(&mut client, PgSync::new()).await.unwrap();
+
+        // parse, bind, no data, command complete and ready, no rows
+        let messages = client.take_messages();
+        assert_eq!(5, messages.len());
+        assert!(matches!(messages[2], PgWireBackendMessage::NoData(_)));
+        match &messages[3] {
+            PgWireBackendMessage::CommandComplete(complete) => {
+                assert_eq!("INSERT 0 2", complete.tag())
+            }
+            message => panic!("unexpected message {message:?}"),
+        }
+        assert!(matches!(
+            messages[4],
+            PgWireBackendMessage::ReadyForQuery(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_track_isolation_level() {
+        let handler = TransactionQueryHandler::default();
+        let mut client = MockClient::new();
+        assert_eq!(IsolationLevel::ReadCommitted, client.isolation_level());
+
+        for (query, level) in [
+            (
+                "SET SESSION CHARACTERISTICS AS TRANSACTION ISOLATION LEVEL REPEATABLE READ",
+                IsolationLevel::RepeatableRead,
+            ),
+            (
+                "BEGIN; SET TRANSACTION ISOLATION LEVEL SERIALIZABLE",
+                IsolationLevel::Serializable,
+            ),
+            ("COMMIT", IsolationLevel::RepeatableRead),
+            (
+                "START TRANSACTION ISOLATION LEVEL READ COMMITTED",
+                IsolationLevel::ReadCommitted,
+            ),
+        ] {
+            handler
+                .on_query(&mut client, Query::new(query.to_owned()))
+                .await
+                .unwrap();
+            assert_eq!(level, client.isolation_level(), "{query}");
+        }
+
+        let parse = Parse::new(None, "ROLLBACK".to_owned(), vec![]);
+        handler.on_parse(&mut client, parse).await.unwrap();
+        let bind = Bind::new(None, None, vec![], vec![], vec![]);
+        handler.on_bind(&mut client, bind).await.unwrap();
+        handler
+            .on_execute(&mut client, Execute::new(None, 0))
+            .await
+            .unwrap();
+        assert_eq!(IsolationLevel::RepeatableRead, client.isolation_level());
+    }
+
+    struct FailingQueryHandler;
+
+    #[async_trait]
+    impl SimpleQueryHandler for FailingQueryHandler {
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &C,
+            query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            let error = ErrorInfo::new(
+                "ERROR".to_owned(),
+                "22012".to_owned(),
+                "division by zero".to_owned(),
+            );
+            if query == "error" {
+                return Ok(vec![Response::Error(Box::new(error))]);
+            }
+
+            // schema is declared, but execution fails before the first row
+            let schema = Arc::new(vec![FieldInfo::new(
+                "ratio".to_owned(),
+                None,
+                None,
+                Type::INT4,
+                FieldFormat::Text,
+            )]);
+            let rows = stream::iter(vec![Err(PgWireError::UserError(Box::new(error)))]);
+            Ok(vec![Response::Query(QueryResponse::new(schema, rows))])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_error_before_rows() {
+        let handler = FailingQueryHandler;
+
+        let mut client = MockClient::new();
+        handler
+            .on_query(&mut client, Query::new("error".to_owned()))
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.take_messages().as_slice(),
+            [
+                PgWireBackendMessage::ErrorResponse(_),
+                PgWireBackendMessage::ReadyForQuery(_)
+            ]
+        ));
+
+        // the error is sent on return, with `ReadyForQuery`, by the
+        // connection loop
+        let mut client = MockClient::new();
+        let result = handler
+            .on_query(&mut client, Query::new("SELECT 1 / 0".to_owned()))
+            .await;
+        assert!(matches!(result, Err(PgWireError::UserError(_))));
+        assert!(client.take_messages().is_empty());
+    }
+
+    struct CopyOutQueryHandler;
+
+    #[async_trait]
+    impl SimpleQueryHandler for CopyOutQueryHandler {
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &C,
+            _query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            let rows = stream::iter(vec![
+                Ok(CopyOutData::row(Bytes::from_static(b"1\ttom\n"))),
+                Ok(CopyOutData::new(
+                    Bytes::from_static(b"2\tjerry\n3\tspike\n"),
+                    2,
+                )),
+            ]);
+            Ok(vec![
+                Response::copy_out(CopyResponse::new(FieldFormat::Text, 2), rows),
+                Response::Execution(Tag::new_for_execution("SET", None)),
+            ])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_copy_out() {
+        let mut client = MockClient::new();
+        CopyOutQueryHandler
+            .on_query(
+                &mut client,
+                Query::new("COPY users TO STDOUT; SET x = 1".to_owned()),
+            )
+            .await
+            .unwrap();
+
+        match client.take_messages().as_slice() {
+            [PgWireBackendMessage::CopyOutResponse(resp), PgWireBackendMessage::CopyData(row1), PgWireBackendMessage::CopyData(row2), PgWireBackendMessage::CopyDone(_), PgWireBackendMessage::CommandComplete(copy_complete), PgWireBackendMessage::CommandComplete(_), PgWireBackendMessage::ReadyForQuery(_)] =>
+            {
+                assert_eq!(&vec![0, 0], resp.column_formats());
+                assert_eq!(&b"1\ttom\n"[..], row1.data().as_ref());
+                assert_eq!(&b"2\tjerry\n3\tspike\n"[..], row2.data().as_ref());
+                assert_eq!("COPY 3", copy_complete.tag());
+            }
+            msgs => panic!("unexpected messages {msgs:?}"),
+        }
+        assert!(matches!(
+            client.state(),
+            PgWireConnectionState::ReadyForQuery
+        ));
+    }
+
+    struct ProgressQueryHandler;
+
+    #[async_trait]
+    impl SimpleQueryHandler for ProgressQueryHandler {
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &C,
+            _query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            let schema = Arc::new(vec![FieldInfo::new(
+                "id".to_owned(),
+                None,
+                None,
+                Type::INT4,
+                FieldFormat::Text,
+            )]);
+            let schema_ref = schema.clone();
+            let items = stream::iter(1..=2i32).flat_map(move |v| {
+                let mut encoder = DataRowEncoder::new(schema_ref.clone());
+                let row = encoder.encode_field(&v).and_then(|_| encoder.finish());
+                let notice = ErrorInfo::new(Severity::Notice, "00000", format!("scanned {v}"));
+                stream::iter(vec![
+                    row.map(QueryResponseItem::Row),
+                    Ok(QueryResponseItem::Notice(notice.into())),
+                ])
+            });
+            Ok(vec![Response::Query(QueryResponse::with_notices(
+                schema, items,
+            ))])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_notice_between_rows() {
+        let handler = ProgressQueryHandler;
+        let mut client = MockClient::new();
+
+        handler
+            .on_query(&mut client, Query::new("VACUUM".to_owned()))
+            .await
+            .unwrap();
+
+        let messages = client.take_messages();
+        assert!(matches!(
+            messages.as_slice(),
+            [
+                PgWireBackendMessage::RowDescription(_),
+                PgWireBackendMessage::DataRow(_),
+                PgWireBackendMessage::NoticeResponse(_),
+                PgWireBackendMessage::DataRow(_),
+                PgWireBackendMessage::NoticeResponse(_),
+                PgWireBackendMessage::CommandComplete(_),
+                PgWireBackendMessage::ReadyForQuery(_),
+            ]
+        ));
+        match &messages[5] {
+            PgWireBackendMessage::CommandComplete(tag) => assert_eq!("SELECT 2", tag.tag()),
+            msg => panic!("unexpected message {msg:?}"),
+        }
+    }
+
+    struct NoColumnQueryHandler;
+
+    #[async_trait]
+    impl SimpleQueryHandler for NoColumnQueryHandler {
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &C,
+            _query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            let schema = Arc::new(vec![]);
+            let schema_ref = schema.clone();
+            let rows =
+                stream::iter(0..3).map(move |_| DataRowEncoder::new(schema_ref.clone()).finish());
+            Ok(vec![Response::Query(QueryResponse::new(schema, rows))])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_zero_column_result() {
+        let handler = NoColumnQueryHandler;
+        let mut client = MockClient::new();
+
+        handler
+            .on_query(&mut client, Query::new("SELECT FROM t".to_owned()))
+            .await
+            .unwrap();
+
+        let messages = client.take_messages();
+        assert_eq!(6, messages.len());
+        let mut frames = Vec::new();
+        for message in &messages[..4] {
+            let mut buf = bytes::BytesMut::new();
+            message.encode(&mut buf).unwrap();
+            frames.push(buf.to_vec());
+        }
+        // field count 0 of both
+        assert_eq!(vec![b'T', 0, 0, 0, 6, 0, 0], frames[0]);
+        for frame in &frames[1..] {
+            assert_eq!(&vec![b'D', 0, 0, 0, 6, 0, 0], frame);
+        }
+        match &messages[4] {
+            PgWireBackendMessage::CommandComplete(tag) => assert_eq!("SELECT 3", tag.tag()),
+            msg => panic!("unexpected message {msg:?}"),
+        }
+    }
+
+    struct ReturningQueryHandler;
+
+    #[async_trait]
+    impl SimpleQueryHandler for ReturningQueryHandler {
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &C,
+            query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            let schema = Arc::new(vec![FieldInfo::new(
+                "id".to_owned(),
+                None,
+                None,
+                Type::INT4,
+                FieldFormat::Text,
+            )]);
+            let schema_ref = schema.clone();
+            let rows = stream::iter(1..=3i32).map(move |v| {
+                let mut encoder = DataRowEncoder::new(schema_ref.clone());
+                encoder.encode_field(&v)?;
+                encoder.finish()
+            });
+            let tag = if query.starts_with("INSERT") {
+                Tag::new_for_insert(0, 5)
+            } else if query.starts_with("CALL") {
+                Tag::new_for_call()
+            } else {
+                Tag::new_for_execution("UPDATE", None)
+            };
+            Ok(vec![Response::Query(
+                QueryResponse::new(schema, rows).with_command_tag(tag),
+            )])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_with_command_tag() {
+        let handler = ReturningQueryHandler;
+
+        for (query, expected) in [
+            ("UPDATE t SET v = 1 RETURNING id", "UPDATE 3"),
+            ("INSERT INTO t SELECT * FROM s RETURNING id", "INSERT 0 5"),
+            // output parameters of a procedure, without row count
+            ("CALL next_ids(3)", "CALL"),
+        ] {
+            let mut client = MockClient::new();
+            handler
+                .on_query(&mut client, Query::new(query.to_owned()))
+                .await
+                .unwrap();
+
+            let messages = client.take_messages();
+            assert_eq!(6, messages.len());
+            match &messages[4] {
+                PgWireBackendMessage::CommandComplete(tag) => assert_eq!(expected, tag.tag()),
+                msg => panic!("unexpected message {msg:?}"),
+            }
+        }
+    }
+
+    struct CompatRewriter {
+        seen: Mutex<Vec<(Option<String>, String)>>,
+    }
+
+    impl QueryRewriter for CompatRewriter {
+        fn rewrite(&self, statement: Option<&str>, query: &str) -> PgWireResult<Option<String>> {
+            self.seen
+                .lock()
+                .unwrap()
+                .push((statement.map(str::to_owned), query.to_owned()));
+            if query == "SELECT version()" {
+                Ok(Some("SELECT '15.0'".to_owned()))
+            } else if query.starts_with("VACUUM") {
+                Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                    Severity::Error,
+                    "0A000",
+                    "VACUUM is not supported",
+                ))))
+            } else {
+                Ok(None)
+            }
+        }
+    }
+
+    struct RewritingQueryHandler {
+        rewriter: CompatRewriter,
+        inner: RecordingQueryHandler,
+        portal_store: Arc<MemPortalStore<String>>,
+    }
+
+    #[async_trait]
+    impl SimpleQueryHandler for RewritingQueryHandler {
+        fn query_rewriter(&self) -> Option<&dyn QueryRewriter> {
+            Some(&self.rewriter)
+        }
+
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            client: &C,
+            query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            self.inner.do_query(client, query).await
+        }
+    }
+
+    #[async_trait]
+    impl ExtendedQueryHandler for RewritingQueryHandler {
+        type Statement = String;
+        type PortalStore = MemPortalStore<Self::Statement>;
+        type QueryParser = NoopQueryParser;
+
+        fn portal_store(&self) -> Arc<Self::PortalStore> {
+            self.portal_store.clone()
+        }
+
+        fn query_parser(&self) -> Arc<Self::QueryParser> {
+            Arc::new(NoopQueryParser::new())
+        }
+
+        fn query_rewriter(&self) -> Option<&dyn QueryRewriter> {
+            Some(&self.rewriter)
+        }
+
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &mut C,
+            _portal: &'a Portal<Self::Statement>,
+            _max_rows: usize,
+        ) -> PgWireResult<Response<'a>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            unimplemented!()
+        }
+
+        async fn do_describe<C>(
+            &self,
+            _client: &mut C,
+            _target: StatementOrPortal<'_, Self::Statement>,
+        ) -> PgWireResult<DescribeResponse>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            unimplemented!()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_rewriter() {
+        let handler = RewritingQueryHandler {
+            rewriter: CompatRewriter {
+                seen: Mutex::new(Vec::new()),
+            },
+            inner: RecordingQueryHandler::default(),
+            portal_store: Arc::new(MemPortalStore::new()),
+        };
+        let mut client = MockClient::new();
+
+        for query in ["SELECT version()", "SELECT 1"] {
+            SimpleQueryHandler::on_query(&handler, &mut client, Query::new(query.to_owned()))
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            vec!["SELECT '15.0'".to_owned(), "SELECT 1".to_owned()],
+            *handler.inner.queries.lock().unwrap()
+        );
+
+        // rejected queries are not dispatched
+        assert!(SimpleQueryHandler::on_query(
+            &handler,
+            &mut client,
+            Query::new("VACUUM t".to_owned())
+        )
+        .await
+        .is_err());
+        assert_eq!(2, handler.inner.queries.lock().unwrap().len());
+
+        let parse = Parse::new(Some("s1".to_owned()), "SELECT version()".to_owned(), vec![]);
+        handler.on_parse(&mut client, parse).await.unwrap();
+        let parse = Parse::new(None, "SELECT 2".to_owned(), vec![]);
+        handler.on_parse(&mut client, parse).await.unwrap();
+        assert_eq!(
+            "SELECT '15.0'",
+            handler
+                .portal_store
+                .get_statement("s1")
+                .unwrap()
+                .statement()
+        );
+        assert_eq!(
+            "SELECT 2",
+            handler
+                .portal_store
+                .get_statement(DEFAULT_NAME)
+                .unwrap()
+                .statement()
+        );
+
+        let parse = Parse::new(Some("s2".to_owned()), "VACUUM t".to_owned(), vec![]);
+        assert!(handler.on_parse(&mut client, parse).await.is_err());
+        assert!(handler.portal_store.get_statement("s2").is_none());
+
+        let seen = handler.rewriter.seen.lock().unwrap();
+        assert_eq!(
+            vec![None, None, None, Some("s1"), Some(DEFAULT_NAME), Some("s2")],
+            seen.iter()
+                .map(|(name, _)| name.as_deref())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    /// Handler of `SELECT $1 + 1`, inferring `int4` for the parameter and
+    /// the result
+    #[derive(Default)]
+    struct InferringQueryHandler {
+        portal_store: Arc<MemPortalStore<String>>,
+    }
+
+    fn sum_fields(parameter_types: &[Type], format: FieldFormat) -> Vec<FieldInfo> {
+        let ty = parameter_types.first().unwrap_or(&Type::UNKNOWN).clone();
+        vec![FieldInfo::new(
+            "?column?".to_owned(),
+            None,
+            None,
+            ty,
+            format,
+        )]
+    }
+
+    #[async_trait]
+    impl ExtendedQueryHandler for InferringQueryHandler {
+        type Statement = String;
+        type PortalStore = MemPortalStore<Self::Statement>;
+        type QueryParser = NoopQueryParser;
+
+        fn portal_store(&self) -> Arc<Self::PortalStore> {
+            self.portal_store.clone()
+        }
+
+        fn query_parser(&self) -> Arc<Self::QueryParser> {
+            Arc::new(NoopQueryParser::new())
+        }
+
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &mut C,
+            portal: &'a Portal<Self::Statement>,
+            _max_rows: usize,
+        ) -> PgWireResult<Response<'a>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            let value = portal.parameter::<i32>(0)?.unwrap_or_default();
+            let schema = Arc::new(sum_fields(
+                portal.statement().parameter_types(),
+                portal.result_column_format().format_for(0),
+            ));
+            let mut encoder = DataRowEncoder::new(schema.clone());
+            encoder.encode_field(&(value + 1))?;
+            let rows = stream::iter(vec![encoder.finish()]);
+            Ok(Response::Query(QueryResponse::new(schema, rows)))
+        }
+
+        async fn do_describe<C>(
+            &self,
+            _client: &mut C,
+            target: StatementOrPortal<'_, Self::Statement>,
+        ) -> PgWireResult<DescribeResponse>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            match target {
+                StatementOrPortal::Statement(stmt) => {
+                    let parameter_types = match stmt.parameter_types().first() {
+                        None | Some(&Type::UNKNOWN) => vec![Type::INT4],
+                        Some(ty) => vec![ty.clone()],
+                    };
+                    let fields = sum_fields(&parameter_types, FieldFormat::Text);
+                    Ok(DescribeResponse::statement(parameter_types, fields))
+                }
+                StatementOrPortal::Portal(portal) => Ok(DescribeResponse::portal(sum_fields(
+                    portal.statement().parameter_types(),
+                    portal.result_column_format().format_for(0),
+                ))),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_describe_infers_parameter_types() {
+        // parameter types unspecified, or omitted
+        for type_oids in [vec![0], vec![]] {
+            let handler = InferringQueryHandler::default();
+            let mut client = MockClient::new();
+            let parse = Parse::new(Some("s".to_owned()), "SELECT $1 + 1".to_owned(), type_oids);
+            handler.on_parse(&mut client, parse).await.unwrap();
+            let describe = Describe::new(TARGET_TYPE_BYTE_STATEMENT, Some("s".to_owned()));
+            handler.on_describe(&mut client, describe).await.unwrap();
+
+            // parse complete, then both descriptions from one inference
+            let messages = client.take_messages();
+            assert_eq!(3, messages.len());
+            match &messages[1] {
+                PgWireBackendMessage::ParameterDescription(parameters) => {
+                    assert_eq!(&vec![Type::INT4.oid()], parameters.types())
+                }
+                message => panic!("unexpected message {message:?}"),
+            }
+            match &messages[2] {
+                PgWireBackendMessage::RowDescription(row) => {
+                    assert_eq!(Type::INT4.oid(), *row.fields()[0].type_id())
+                }
+                message => panic!("unexpected message {message:?}"),
+            }
+
+            // the binary parameter is decoded as the inferred type
+            let bind = Bind::new(
+                None,
+                Some("s".to_owned()),
+                vec![1],
+                vec![Some(Bytes::from_static(&[0, 0, 0, 41]))],
+                vec![],
+            );
+            handler.on_bind(&mut client, bind).await.unwrap();
+            handler
+                .on_execute(&mut client, Execute::new(None, 0))
+                .await
+                .unwrap();
+            match &client.take_messages()[1] {
+                PgWireBackendMessage::DataRow(row) => {
+                    assert_eq!(Some(&Bytes::from_static(b"42")), row.fields()[0].as_ref())
+                }
+                message => panic!("unexpected message {message:?}"),
+            }
+        }
+    }
+
+    /// Handler streaming rows borrowed from its own buffer, filtered by a
+    /// prefix borrowed from the query
+    struct BorrowingQueryHandler {
+        names: Vec<String>,
+    }
+
+    #[async_trait]
+    impl SimpleQueryHandler for BorrowingQueryHandler {
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &C,
+            query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            let prefix = query.trim_start_matches("SELECT name WHERE name LIKE ");
+            let schema = Arc::new(vec![FieldInfo::new(
+                "name".to_owned(),
+                None,
+                None,
+                Type::TEXT,
+                FieldFormat::Text,
+            )]);
+            let schema_ref = schema.clone();
+            let rows = stream::iter(self.names.iter())
+                .filter(move |name| futures::future::ready(name.starts_with(prefix)))
+                .map(move |name| {
+                    let mut encoder = DataRowEncoder::new(schema_ref.clone());
+                    encoder.encode_str_field(name)?;
+                    encoder.finish()
+                });
+            Ok(vec![Response::Query(QueryResponse::new(schema, rows))])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_response_borrowing_handler() {
+        let handler = BorrowingQueryHandler {
+            names: vec!["Tom".to_owned(), "Jerry".to_owned(), "Tuffy".to_owned()],
+        };
+        let mut client = MockClient::new();
+        handler
+            .on_query(
+                &mut client,
+                Query::new("SELECT name WHERE name LIKE T".to_owned()),
+            )
+            .await
+            .unwrap();
+
+        let rows: Vec<_> = client
+            .take_messages()
+            .into_iter()
+            .filter_map(|message| match message {
+                PgWireBackendMessage::DataRow(row) => row.fields()[0].clone(),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(vec![Bytes::from("Tom"), Bytes::from("Tuffy")], rows);
+    }
+}
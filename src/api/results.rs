@@ -1,49 +1,268 @@
-use std::{fmt::Debug, sync::Arc};
+use std::{
+    any::Any,
+    error::Error,
+    fmt::{Debug, Write},
+    num::IntErrorKind,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
 
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
 use futures::{
-    stream::{BoxStream, StreamExt},
-    Stream,
+    future::{BoxFuture, FutureExt},
+    stream::{self, BoxStream, StreamExt},
+    Future, Stream,
 };
-use postgres_types::{IsNull, ToSql, Type};
+use postgres_types::{FromSql, IsNull, Oid, ToSql, Type};
 
 use crate::{
-    error::{ErrorInfo, PgWireResult},
+    error::{ErrorInfo, PgWireError, PgWireResult, Severity, SqlState},
     messages::{
+        copy::{CopyInResponse, CopyOutResponse},
         data::{DataRow, FieldDescription, RowDescription, FORMAT_CODE_BINARY, FORMAT_CODE_TEXT},
-        response::CommandComplete,
+        response::{CommandComplete, NoticeResponse},
+    },
+    types::{
+        encoding_type, with_text_style, IntervalStyle, ToSqlText, TypeEncoderRegistry, TypeRegistry,
     },
-    types::ToSqlText,
 };
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Tag {
     command: String,
-    rows: Option<usize>,
+    oid: Option<Oid>,
+    rows: Option<u64>,
+    /// tags of utility statements have no row count, even when they return
+    /// rows like `SHOW` and `CALL`
+    utility: bool,
 }
 
 impl Tag {
-    pub fn new_for_query(rows: usize) -> Tag {
+    pub fn new_for_query(rows: u64) -> Tag {
         Tag {
             command: "SELECT".to_owned(),
+            oid: None,
             rows: Some(rows),
+            utility: false,
         }
     }
 
-    pub fn new_for_execution(command: &str, rows: Option<usize>) -> Tag {
+    pub fn new_for_execution(command: &str, rows: Option<u64>) -> Tag {
         Tag {
             command: command.to_owned(),
+            oid: None,
             rows,
+            utility: false,
+        }
+    }
+
+    /// Create tag `INSERT <oid> <rows>` for `INSERT`.
+    ///
+    /// `oid` is the oid of the inserted row when one row is inserted into a
+    /// table with oids, by legacy servers. It's 0 otherwise.
+    pub fn new_for_insert(oid: Oid, rows: u64) -> Tag {
+        Tag {
+            command: "INSERT".to_owned(),
+            oid: Some(oid),
+            rows: Some(rows),
+            utility: false,
+        }
+    }
+
+    /// Create tag `CALL` for `CALL` of a procedure.
+    ///
+    /// The tag has no row count, also for a procedure with output parameters.
+    /// Postgres returns those in a single row, respond with a `QueryResponse`
+    /// of the row, and this tag as its `with_command_tag`. Result sets of
+    /// refcursor parameters are fetched by client with `FETCH` afterwards.
+    pub fn new_for_call() -> Tag {
+        Tag {
+            command: "CALL".to_owned(),
+            oid: None,
+            rows: None,
+            utility: true,
+        }
+    }
+
+    /// Create tag `DECLARE CURSOR` for `DECLARE` of a cursor.
+    pub fn new_for_declare_cursor() -> Tag {
+        Tag {
+            command: "DECLARE CURSOR".to_owned(),
+            oid: None,
+            rows: None,
+            utility: true,
+        }
+    }
+
+    /// Create tag `FETCH <rows>` for `FETCH` from a cursor, with the number
+    /// of rows returned.
+    pub fn new_for_fetch(rows: u64) -> Tag {
+        Tag::new_for_execution("FETCH", Some(rows))
+    }
+
+    /// Create tag `MOVE <rows>` for `MOVE` of a cursor, with the number of
+    /// rows the cursor moved over.
+    pub fn new_for_move(rows: u64) -> Tag {
+        Tag::new_for_execution("MOVE", Some(rows))
+    }
+
+    /// Create tag `CLOSE CURSOR` for `CLOSE` of a cursor, or
+    /// `CLOSE CURSOR ALL` for `CLOSE ALL`.
+    pub fn new_for_close_cursor(all: bool) -> Tag {
+        Tag {
+            command: if all {
+                "CLOSE CURSOR ALL"
+            } else {
+                "CLOSE CURSOR"
+            }
+            .to_owned(),
+            oid: None,
+            rows: None,
+            utility: true,
+        }
+    }
+
+    /// Command of the tag, like `SELECT` or `CREATE TABLE`
+    pub fn command(&self) -> &str {
+        &self.command
+    }
+
+    /// Fill in row count of the tag, if it isn't set by handler
+    pub(crate) fn with_default_rows(mut self, rows: u64) -> Tag {
+        if !self.utility {
+            self.rows.get_or_insert(rows);
         }
+        self
+    }
+
+    /// Create tag for a utility statement, which has no row count, from the
+    /// statement text.
+    ///
+    /// The tag is what postgres reports for the statement, for example
+    /// `COMMIT` for `END` or `COMMIT TRANSACTION`, `CREATE INDEX` for
+    /// `CREATE UNIQUE INDEX` and `TRUNCATE TABLE` for `TRUNCATE`. Returns
+    /// `None` when the statement is not a known utility statement.
+    pub fn new_for_utility(statement: &str) -> Option<Tag> {
+        let words: Vec<String> = statement
+            .split_whitespace()
+            .take(UTILITY_TAG_MAX_WORDS)
+            .map(|w| w.trim_end_matches(';').to_uppercase())
+            .collect();
+        let first = words.first()?;
+
+        let command = if matches!(first.as_str(), "CREATE" | "DROP" | "ALTER") {
+            let object = words[1..]
+                .iter()
+                .map(String::as_str)
+                .skip_while(|w| DDL_MODIFIERS.contains(w))
+                .collect::<Vec<&str>>();
+            let object = match object.as_slice() {
+                ["MATERIALIZED", "VIEW", ..] => "MATERIALIZED VIEW",
+                ["FOREIGN", "TABLE", ..] => "FOREIGN TABLE",
+                ["USER", ..] => "ROLE",
+                [w, ..] if DDL_OBJECTS.contains(w) => w,
+                _ => return None,
+            };
+            format!("{first} {object}")
+        } else {
+            UTILITY_TAGS
+                .iter()
+                .find(|(prefix, _)| {
+                    prefix
+                        .split(' ')
+                        .zip(
+                            words
+                                .iter()
+                                .map(String::as_str)
+                                .chain(std::iter::repeat("")),
+                        )
+                        .all(|(p, w)| p == w)
+                })
+                .map(|(_, tag)| (*tag).to_owned())?
+        };
+
+        Some(Tag {
+            command,
+            oid: None,
+            rows: None,
+            utility: true,
+        })
     }
 }
 
+const UTILITY_TAG_MAX_WORDS: usize = 5;
+
+/// Statement prefix to tag mapping for utility statements, longer prefixes
+/// first.
+const UTILITY_TAGS: &[(&str, &str)] = &[
+    ("START TRANSACTION", "START TRANSACTION"),
+    ("DISCARD ALL", "DISCARD ALL"),
+    ("DEALLOCATE ALL", "DEALLOCATE ALL"),
+    ("CLOSE ALL", "CLOSE CURSOR ALL"),
+    ("BEGIN", "BEGIN"),
+    ("COMMIT", "COMMIT"),
+    ("END", "COMMIT"),
+    ("ROLLBACK", "ROLLBACK"),
+    ("ABORT", "ROLLBACK"),
+    ("SAVEPOINT", "SAVEPOINT"),
+    ("RELEASE", "RELEASE"),
+    ("SET", "SET"),
+    ("RESET", "RESET"),
+    ("SHOW", "SHOW"),
+    ("DISCARD", "DISCARD"),
+    ("DEALLOCATE", "DEALLOCATE"),
+    ("TRUNCATE", "TRUNCATE TABLE"),
+    ("LOCK", "LOCK TABLE"),
+    ("VACUUM", "VACUUM"),
+    ("ANALYZE", "ANALYZE"),
+    ("CALL", "CALL"),
+    ("DECLARE", "DECLARE CURSOR"),
+    ("CLOSE", "CLOSE CURSOR"),
+    ("GRANT", "GRANT"),
+    ("REVOKE", "REVOKE"),
+    ("COMMENT", "COMMENT"),
+    ("LISTEN", "LISTEN"),
+    ("UNLISTEN", "UNLISTEN"),
+    ("NOTIFY", "NOTIFY"),
+];
+
+/// Words between `CREATE`/`DROP`/`ALTER` and the object type, which are not
+/// part of the tag
+const DDL_MODIFIERS: &[&str] = &[
+    "OR",
+    "REPLACE",
+    "UNIQUE",
+    "TEMP",
+    "TEMPORARY",
+    "UNLOGGED",
+    "GLOBAL",
+    "LOCAL",
+];
+
+const DDL_OBJECTS: &[&str] = &[
+    "TABLE",
+    "INDEX",
+    "VIEW",
+    "SCHEMA",
+    "SEQUENCE",
+    "FUNCTION",
+    "PROCEDURE",
+    "DATABASE",
+    "ROLE",
+    "TYPE",
+    "EXTENSION",
+    "TRIGGER",
+    "DOMAIN",
+];
+
 impl From<Tag> for CommandComplete {
     fn from(tag: Tag) -> CommandComplete {
-        let tag_string = if let Some(rows) = tag.rows {
-            format!("{} {rows}", tag.command)
-        } else {
-            tag.command
+        let tag_string = match (tag.oid, tag.rows) {
+            (Some(oid), Some(rows)) => format!("{} {oid} {rows}", tag.command),
+            (_, Some(rows)) => format!("{} {rows}", tag.command),
+            (_, None) => tag.command,
         };
         CommandComplete::new(tag_string)
     }
@@ -86,6 +305,171 @@ pub struct FieldInfo {
     column_id: Option<i16>,
     datatype: Type,
     format: FieldFormat,
+    /// type modifier, `atttypmod` in postgres, -1 for none
+    #[new(value = "-1")]
+    type_modifier: i32,
+}
+
+/// Offset added to length or precision in type modifier of `varchar`,
+/// `bpchar` and `numeric`, `VARHDRSZ` in postgres
+const TYPE_MODIFIER_HEADER_SIZE: i32 = 4;
+
+impl FieldInfo {
+    /// Create text field `name` of type `varchar(length)`
+    pub fn varchar(name: &str, length: i32) -> FieldInfo {
+        FieldInfo::for_type(name, Type::VARCHAR)
+            .with_type_modifier(length + TYPE_MODIFIER_HEADER_SIZE)
+    }
+
+    /// Create text field `name` of type `char(length)`
+    pub fn bpchar(name: &str, length: i32) -> FieldInfo {
+        FieldInfo::for_type(name, Type::BPCHAR)
+            .with_type_modifier(length + TYPE_MODIFIER_HEADER_SIZE)
+    }
+
+    /// Create text field `name` of type `numeric(precision, scale)`
+    pub fn numeric(name: &str, precision: u16, scale: u16) -> FieldInfo {
+        let modifier = ((precision as i32) << 16 | scale as i32) + TYPE_MODIFIER_HEADER_SIZE;
+        FieldInfo::for_type(name, Type::NUMERIC).with_type_modifier(modifier)
+    }
+
+    /// Create text field `name` of type `bit(length)`
+    pub fn bit(name: &str, length: i32) -> FieldInfo {
+        FieldInfo::for_type(name, Type::BIT).with_type_modifier(length)
+    }
+
+    /// Create text field `name` of type `varbit(length)`
+    pub fn varbit(name: &str, length: i32) -> FieldInfo {
+        FieldInfo::for_type(name, Type::VARBIT).with_type_modifier(length)
+    }
+
+    /// Create text field `name` of `count(..)`, which is `int8`
+    pub fn for_count(name: &str) -> FieldInfo {
+        FieldInfo::for_type(name, Type::INT8)
+    }
+
+    /// Create text field `name` of the result of `function` taking an
+    /// `argument` type, see `function_result_type`. Returns `None` for
+    /// unknown functions.
+    pub fn for_function(name: &str, function: &str, argument: Option<&Type>) -> Option<FieldInfo> {
+        function_result_type(function, argument).map(|datatype| FieldInfo::for_type(name, datatype))
+    }
+
+    fn for_type(name: &str, datatype: Type) -> FieldInfo {
+        FieldInfo::new(name.to_owned(), None, None, datatype, FieldFormat::Text)
+    }
+
+    /// Set type modifier, for types without a constructor here, like
+    /// precision of `timestamp(p)`
+    pub fn with_type_modifier(mut self, type_modifier: i32) -> FieldInfo {
+        self.type_modifier = type_modifier;
+        self
+    }
+
+    /// Set format of the field
+    pub fn with_format(mut self, format: FieldFormat) -> FieldInfo {
+        self.format = format;
+        self
+    }
+}
+
+/// Size of type, `typlen` in postgres: the number of bytes of fixed size
+/// types, -1 for variable length types and -2 for `cstring`.
+///
+/// `name` is a fixed size type of 64 bytes, although its values are sent as
+/// variable length strings.
+fn type_size(ty: &Type) -> i16 {
+    match *encoding_type(ty) {
+        Type::BOOL | Type::CHAR => 1,
+        Type::INT2 => 2,
+        Type::INT4 | Type::OID | Type::FLOAT4 | Type::DATE | Type::REGPROC | Type::REGTYPE => 4,
+        Type::INT8
+        | Type::FLOAT8
+        | Type::MONEY
+        | Type::TIME
+        | Type::TIMESTAMP
+        | Type::TIMESTAMPTZ => 8,
+        Type::TIMETZ => 12,
+        Type::UUID | Type::INTERVAL | Type::POINT => 16,
+        Type::NAME => 64,
+        Type::CSTRING => -2,
+        _ => -1,
+    }
+}
+
+/// Result type of common postgres function `function`, called with an
+/// argument of type `argument` when the result depends on it. Names are
+/// matched case-insensitively.
+///
+/// Results of aggregates are often wider than their argument, and advertising
+/// the argument type breaks clients parsing large values:
+///
+/// | function | argument | result |
+/// |----------|----------|--------|
+/// | `count` | any | `int8` |
+/// | `sum` | `int2`, `int4` | `int8` |
+/// | `sum` | `int8`, `numeric` | `numeric` |
+/// | `sum` | `float4`, `float8`, `money`, `interval` | same as argument |
+/// | `avg` | `int2`, `int4`, `int8`, `numeric` | `numeric` |
+/// | `avg` | `float4`, `float8` | `float8` |
+/// | `avg` | `interval` | `interval` |
+/// | `stddev`, `variance` and variants | `int2`, `int4`, `int8`, `numeric` | `numeric` |
+/// | `stddev`, `variance` and variants | `float4`, `float8` | `float8` |
+/// | `min`, `max`, `abs`, `coalesce` | any, `None` without argument | same as argument |
+/// | `bool_and`, `bool_or`, `every` | any | `bool` |
+/// | `string_agg` | `bytea` | `bytea` |
+/// | `string_agg`, `lower`, `upper`, `concat` | other | `text` |
+/// | `length`, `char_length`, `octet_length` | any | `int4` |
+/// | `row_number`, `rank`, `dense_rank` | none | `int8` |
+/// | `ntile` | any | `int4` |
+/// | `percent_rank`, `cume_dist`, `random` | none | `float8` |
+/// | `now`, `current_timestamp` | none | `timestamptz` |
+/// | `current_date` | none | `date` |
+/// | `version`, `current_database` | none | `text` |
+///
+/// `None` is returned for other functions, and for argument types `sum`,
+/// `avg`, `stddev` and `variance` don't accept, like `sum(text)`. Arguments
+/// of other functions are not checked, so `abs(text)` is `text` and
+/// `bool_and(int4)` is `bool`, though postgres rejects them.
+pub fn function_result_type(function: &str, argument: Option<&Type>) -> Option<Type> {
+    let function = function.to_lowercase();
+    let arg = argument.cloned();
+    let int = |ty: &Type| [Type::INT2, Type::INT4, Type::INT8].contains(ty);
+    let float = |ty: &Type| [Type::FLOAT4, Type::FLOAT8].contains(ty);
+
+    match function.as_str() {
+        "count" => Some(Type::INT8),
+        "sum" => match arg? {
+            ty if ty == Type::INT2 || ty == Type::INT4 => Some(Type::INT8),
+            ty if ty == Type::INT8 || ty == Type::NUMERIC => Some(Type::NUMERIC),
+            ty if float(&ty) || ty == Type::MONEY || ty == Type::INTERVAL => Some(ty),
+            _ => None,
+        },
+        "avg" => match arg? {
+            ty if int(&ty) || ty == Type::NUMERIC => Some(Type::NUMERIC),
+            ty if float(&ty) => Some(Type::FLOAT8),
+            ty if ty == Type::INTERVAL => Some(ty),
+            _ => None,
+        },
+        "stddev" | "stddev_pop" | "stddev_samp" | "variance" | "var_pop" | "var_samp" => match arg?
+        {
+            ty if int(&ty) || ty == Type::NUMERIC => Some(Type::NUMERIC),
+            ty if float(&ty) => Some(Type::FLOAT8),
+            _ => None,
+        },
+        "min" | "max" | "abs" | "coalesce" => arg,
+        "bool_and" | "bool_or" | "every" => Some(Type::BOOL),
+        "string_agg" if arg == Some(Type::BYTEA) => arg,
+        "string_agg" | "lower" | "upper" | "concat" | "version" | "current_database" => {
+            Some(Type::TEXT)
+        }
+        "length" | "char_length" | "octet_length" | "ntile" => Some(Type::INT4),
+        "row_number" | "rank" | "dense_rank" => Some(Type::INT8),
+        "percent_rank" | "cume_dist" | "random" => Some(Type::FLOAT8),
+        "now" | "current_timestamp" => Some(Type::TIMESTAMPTZ),
+        "current_date" => Some(Type::DATE),
+        _ => None,
+    }
 }
 
 impl From<&FieldInfo> for FieldDescription {
@@ -95,9 +479,8 @@ impl From<&FieldInfo> for FieldDescription {
             fi.table_id.unwrap_or(0),  // table_id
             fi.column_id.unwrap_or(0), // column_id
             fi.datatype.oid(),         // type_id
-            // TODO: type size and modifier
-            0,
-            0,
+            type_size(&fi.datatype),   // type_size
+            fi.type_modifier,
             fi.format.value(),
         )
     }
@@ -107,49 +490,329 @@ pub(crate) fn into_row_description(fields: &[FieldInfo]) -> RowDescription {
     RowDescription::new(fields.iter().map(Into::into).collect())
 }
 
+/// Create `RowDescription` with format code of each column taken from
+/// `formats`, instead of the schema.
+pub(crate) fn into_row_description_with_formats(
+    fields: &[FieldInfo],
+    formats: &[FieldFormat],
+) -> RowDescription {
+    RowDescription::new(
+        fields
+            .iter()
+            .zip(formats)
+            .map(|(field, format)| {
+                let mut desc = FieldDescription::from(field);
+                desc.set_format_code(format.value());
+                desc
+            })
+            .collect(),
+    )
+}
+
+/// Check that each column of `fields` is encoded in the format of `formats`.
+pub(crate) fn check_field_formats(
+    fields: &[FieldInfo],
+    formats: &[FieldFormat],
+) -> PgWireResult<()> {
+    for (idx, (field, format)) in fields.iter().zip(formats).enumerate() {
+        if field.format() != format {
+            return Err(PgWireError::ResultFormatMismatch(idx, *format));
+        }
+    }
+    Ok(())
+}
+
+/// Item of the data stream in `QueryResponse`
+#[derive(Debug)]
+pub enum QueryResponseItem {
+    /// A row of the result set
+    Row(DataRow),
+    /// A notice sent to client between rows, for example to report progress
+    /// of a long running scan
+    Notice(NoticeResponse),
+}
+
+impl From<DataRow> for QueryResponseItem {
+    fn from(row: DataRow) -> Self {
+        QueryResponseItem::Row(row)
+    }
+}
+
 #[derive(Getters)]
 #[getset(get = "pub")]
 pub struct QueryResponse<'a> {
     pub(crate) row_schema: Arc<Vec<FieldInfo>>,
-    pub(crate) data_rows: BoxStream<'a, PgWireResult<DataRow>>,
+    #[getset(skip)]
+    pub(crate) data_rows: DataRows<'a>,
+    pub(crate) command_tag: Option<Tag>,
+}
+
+/// Item stream of `QueryResponse`, `Owned` for a response made
+/// `suspendable`, so that it can be kept in a suspended portal
+pub(crate) enum DataRows<'a> {
+    Borrowed(BoxStream<'a, PgWireResult<QueryResponseItem>>),
+    Owned(BoxStream<'static, PgWireResult<QueryResponseItem>>),
+}
+
+impl<'a> DataRows<'a> {
+    /// `item` followed by these items, keeping an owned stream owned
+    pub(crate) fn prepend(self, item: QueryResponseItem) -> DataRows<'a> {
+        let first = stream::iter(Some(Ok(item)));
+        match self {
+            DataRows::Borrowed(rows) => DataRows::Borrowed(first.chain(rows).boxed()),
+            DataRows::Owned(rows) => DataRows::Owned(first.chain(rows).boxed()),
+        }
+    }
+}
+
+impl<'a> Stream for DataRows<'a> {
+    type Item = PgWireResult<QueryResponseItem>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.get_mut() {
+            DataRows::Borrowed(rows) => rows.poll_next_unpin(cx),
+            DataRows::Owned(rows) => rows.poll_next_unpin(cx),
+        }
+    }
 }
 
 impl<'a> QueryResponse<'a> {
     /// Create `QueryResponse` from column schemas and stream of data row
+    ///
+    /// The response is completed with `SELECT <rows>`, where rows is the
+    /// number of rows actually sent from the stream, so handlers don't need to
+    /// know it in advance. For a portal executed with `max_rows`, that's the
+    /// rows sent by the `Execute` completing the portal.
+    ///
+    /// The stream doesn't need to be `'static`. It may borrow from the handler
+    /// and the query or portal for `'a`, like rows decoded from a buffer of
+    /// the handler, as it's sent before the call on the handler returns. See
+    /// example `borrowed_rows`.
     pub fn new<S>(field_defs: Arc<Vec<FieldInfo>>, row_stream: S) -> QueryResponse<'a>
     where
         S: Stream<Item = PgWireResult<DataRow>> + Send + Unpin + 'a,
     {
         QueryResponse {
             row_schema: field_defs,
-            data_rows: row_stream.boxed(),
+            data_rows: DataRows::Borrowed(row_stream.map(|row| row.map(Into::into)).boxed()),
+            command_tag: None,
+        }
+    }
+
+    /// Create `QueryResponse` from column schemas and stream of data rows
+    /// interleaved with notices.
+    ///
+    /// Notices are sent as `NoticeResponse` in the position they are yielded,
+    /// and are not counted as rows in `CommandComplete`.
+    pub fn with_notices<S>(field_defs: Arc<Vec<FieldInfo>>, item_stream: S) -> QueryResponse<'a>
+    where
+        S: Stream<Item = PgWireResult<QueryResponseItem>> + Send + 'a,
+    {
+        QueryResponse {
+            row_schema: field_defs,
+            data_rows: DataRows::Borrowed(item_stream.boxed()),
+            command_tag: None,
+        }
+    }
+
+    /// Complete the response with `tag`, instead of `SELECT <rows>`.
+    ///
+    /// This is for DML statements returning rows, like `UPDATE ... RETURNING`,
+    /// which postgres completes with `UPDATE <rows>`. When `tag` has no row
+    /// count, the number of rows in the stream is used, so
+    /// `Tag::new_for_execution("DELETE", None)` completes as `DELETE 3` after
+    /// three rows.
+    pub fn with_command_tag(mut self, tag: Tag) -> QueryResponse<'a> {
+        self.command_tag = Some(tag);
+        self
+    }
+}
+
+impl QueryResponse<'static> {
+    /// Keep the stream of this response as is in a portal suspended at
+    /// `max_rows`, to be polled again by the next `Execute` of the portal.
+    ///
+    /// Rest of the rows of other responses is read into memory on suspend,
+    /// as they may borrow from the handler or the portal, up to
+    /// `MAX_SUSPENDED_ROWS`. Make long or endless streams suspendable, like a
+    /// `CursorStream` owning its cursor.
+    pub fn suspendable(mut self) -> QueryResponse<'static> {
+        if let DataRows::Borrowed(rows) = self.data_rows {
+            self.data_rows = DataRows::Owned(rows);
         }
+        self
     }
 }
 
+/// Default max size in bytes of an encoded field, 1GiB like the max size of
+/// a message
+pub const DEFAULT_MAX_FIELD_SIZE: usize = 1 << 30;
+
 pub struct DataRowEncoder {
     buffer: DataRow,
     field_buffer: BytesMut,
     schema: Arc<Vec<FieldInfo>>,
     col_index: usize,
+    type_registry: Option<Arc<TypeRegistry>>,
+    encoder_registry: Option<Arc<TypeEncoderRegistry>>,
+    interval_style: IntervalStyle,
+    strict_encoding: bool,
+    max_field_size: usize,
 }
 
 impl DataRowEncoder {
     /// New DataRowEncoder from schemas of column
     pub fn new(fields: Arc<Vec<FieldInfo>>) -> DataRowEncoder {
+        Self::with_capacity(fields, 8)
+    }
+
+    /// New DataRowEncoder from schemas of column, with estimated size in bytes
+    /// of the whole row.
+    ///
+    /// Fields are split from a shared buffer, so all fields of a row fit into
+    /// a single allocation when the estimation is large enough. This avoids
+    /// repeated reallocations for wide rows.
+    pub fn with_capacity(fields: Arc<Vec<FieldInfo>>, estimated_bytes: usize) -> DataRowEncoder {
         let ncols = fields.len();
         Self {
             buffer: DataRow::new(Vec::with_capacity(ncols)),
-            field_buffer: BytesMut::with_capacity(8),
+            field_buffer: BytesMut::with_capacity(estimated_bytes),
             schema: fields,
             col_index: 0,
+            type_registry: None,
+            encoder_registry: None,
+            interval_style: IntervalStyle::default(),
+            strict_encoding: false,
+            max_field_size: DEFAULT_MAX_FIELD_SIZE,
+        }
+    }
+
+    /// Attach a `TypeRegistry` for resolving custom type oids in
+    /// `encode_field_with_type_oid`.
+    pub fn with_type_registry(mut self, registry: Arc<TypeRegistry>) -> DataRowEncoder {
+        self.type_registry = Some(registry);
+        self
+    }
+
+    /// Attach a `TypeEncoderRegistry` for encoding values in `encode_value`.
+    pub fn with_encoder_registry(mut self, registry: Arc<TypeEncoderRegistry>) -> DataRowEncoder {
+        self.encoder_registry = Some(registry);
+        self
+    }
+
+    /// Encode intervals in text format in `style`, like
+    /// `ClientInfo::interval_style` of the connection. Defaults to
+    /// `postgres`.
+    pub fn with_interval_style(mut self, style: IntervalStyle) -> DataRowEncoder {
+        self.interval_style = style;
+        self
+    }
+
+    /// Decode each binary field of builtin types after encoding it, and fail
+    /// with `EncodeError` of the column when it's not a valid value of the
+    /// column type. Disabled by default.
+    ///
+    /// This catches encoders producing malformed values, like an `i64` for an
+    /// `int2` column, before clients fail to decode them. Fields of all
+    /// `encode_*` methods are checked, as encoded and before integers are
+    /// converted to the width of the column. It's expensive, so enable it for
+    /// testing only. Values of other types, like composites, are not checked.
+    pub fn with_strict_encoding(mut self, strict_encoding: bool) -> DataRowEncoder {
+        self.strict_encoding = strict_encoding;
+        self
+    }
+
+    /// Reject encoded fields larger than `max_field_size` bytes with error
+    /// `54000` naming the column, instead of sending a huge value that
+    /// clients may fail to allocate. Defaults to `DEFAULT_MAX_FIELD_SIZE`.
+    pub fn with_max_field_size(mut self, max_field_size: usize) -> DataRowEncoder {
+        self.max_field_size = max_field_size;
+        self
+    }
+
+    fn check_field_size(&mut self, len: usize) -> PgWireResult<()> {
+        if len <= self.max_field_size {
+            return Ok(());
+        }
+        self.field_buffer.clear();
+        let column = self
+            .schema
+            .get(self.col_index)
+            .map_or_else(|| self.col_index.to_string(), |field| field.name().clone());
+        Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+            Severity::Error,
+            SqlState::ProgramLimitExceeded,
+            format!(
+                "value of column \"{column}\" exceeds the max field size of {} bytes",
+                self.max_field_size
+            ),
+        ))))
+    }
+
+    fn check_strict_encoding(
+        &mut self,
+        data_type: &Type,
+        format: FieldFormat,
+        is_null: &IsNull,
+    ) -> PgWireResult<()> {
+        if !self.strict_encoding || format != FieldFormat::Binary || matches!(is_null, IsNull::Yes)
+        {
+            return Ok(());
+        }
+        if let Err(source) = check_binary(data_type, &self.field_buffer) {
+            self.field_buffer.clear();
+            return Err(PgWireError::EncodeError {
+                column: self.col_index,
+                type_oid: data_type.oid(),
+                source,
+            });
+        }
+        Ok(())
+    }
+
+    /// Encode value using the encoder of the column type, defined by schema
+    ///
+    /// The encoder is looked up in the `TypeEncoderRegistry` attached to this
+    /// encoder, falling back to builtin encoders. Unlike `encode_field`, the
+    /// value doesn't need to implement `ToSql` and `ToSqlText`, so types of
+    /// extensions can be encoded by registered encoders.
+    ///
+    /// Panic when encoding more columns than provided as schema.
+    pub fn encode_value<T: Any>(&mut self, value: &T) -> PgWireResult<()> {
+        let schema = self.schema.clone();
+        let field = &schema[self.col_index];
+        let out = &mut self.field_buffer;
+        let is_null = with_text_style(self.interval_style, || match self.encoder_registry {
+            Some(ref registry) => registry.encode(value, field.datatype(), *field.format(), out),
+            None => {
+                TypeEncoderRegistry::new().encode(value, field.datatype(), *field.format(), out)
+            }
+        });
+        let is_null = match is_null {
+            Ok(is_null) => is_null,
+            Err(e) => {
+                self.field_buffer.clear();
+                return Err(e);
+            }
+        };
+        self.check_strict_encoding(field.datatype(), *field.format(), &is_null)?;
+        self.check_field_size(self.field_buffer.len())?;
+
+        if let IsNull::No = is_null {
+            let buf = self.field_buffer.split().freeze();
+            self.buffer.fields_mut().push(Some(buf));
+        } else {
+            self.buffer.fields_mut().push(None);
         }
+        self.col_index += 1;
+        Ok(())
     }
 
     /// Encode value with custom type and format
     ///
     /// This encode function ignores data type and format information from
-    /// schema of this encoder.
+    /// schema of this encoder. Returns `EncodeError` with index of the column
+    /// when the value cannot be encoded as the type.
     pub fn encode_field_with_type_and_format<T>(
         &mut self,
         value: &T,
@@ -159,11 +822,28 @@ impl DataRowEncoder {
     where
         T: ToSql + ToSqlText + Sized,
     {
+        let data_type = encoding_type(data_type);
+        let width = integer_width(data_type);
+        if width.is_some() && is_integer_type::<T>() {
+            return self.encode_integer(value, data_type, format);
+        }
         let is_null = if format == FieldFormat::Text {
-            value.to_sql_text(data_type, &mut self.field_buffer)?
+            let out = &mut self.field_buffer;
+            with_text_style(self.interval_style, || value.to_sql_text(data_type, out))
         } else {
-            value.to_sql(data_type, &mut self.field_buffer)?
+            value.to_sql(data_type, &mut self.field_buffer)
+        };
+        let is_null = match is_null {
+            Ok(is_null) => is_null,
+            Err(source) => return Err(self.encode_error(data_type, source)),
         };
+        self.check_strict_encoding(data_type, format, &is_null)?;
+        if let (IsNull::No, Some((width, name))) = (&is_null, width) {
+            if let Err(e) = check_integer_field(width, name, format, &self.field_buffer) {
+                return Err(self.encode_error(data_type, e));
+            }
+        }
+        self.check_field_size(self.field_buffer.len())?;
 
         if let IsNull::No = is_null {
             let buf = self.field_buffer.split().freeze();
@@ -177,6 +857,98 @@ impl DataRowEncoder {
         Ok(())
     }
 
+    /// Encode an integer value as integer column type `data_type`, of another
+    /// width maybe. The value is range checked for the width of the column
+    /// before formatting, and rejected with `22003` if it doesn't fit, instead
+    /// of being truncated or failing on client side.
+    fn encode_integer<T>(
+        &mut self,
+        value: &T,
+        data_type: &Type,
+        format: FieldFormat,
+    ) -> PgWireResult<()>
+    where
+        T: ToSql,
+    {
+        // safe to unwrap, only called for integer types
+        let (width, name) = integer_width(data_type).unwrap();
+        // written in binary format of the value's own width
+        let is_null = match value.to_sql(data_type, &mut self.field_buffer) {
+            Ok(is_null) => is_null,
+            Err(source) => return Err(self.encode_error(data_type, source)),
+        };
+        // strict encoding reports values of another width, before they are
+        // fitted to the column
+        self.check_strict_encoding(data_type, format, &is_null)?;
+        if let IsNull::Yes = is_null {
+            self.field_buffer.clear();
+            self.buffer.fields_mut().push(None);
+            self.col_index += 1;
+            return Ok(());
+        }
+
+        let value = match *self.field_buffer {
+            [a, b] => i16::from_be_bytes([a, b]) as i64,
+            [a, b, c, d] => i32::from_be_bytes([a, b, c, d]) as i64,
+            [a, b, c, d, e, f, g, h] => i64::from_be_bytes([a, b, c, d, e, f, g, h]),
+            _ => {
+                let error = not_an_integer(name);
+                return Err(self.encode_error(data_type, error));
+            }
+        };
+        if !fits_integer_width(value, width) {
+            let error = integer_out_of_range(name);
+            return Err(self.encode_error(data_type, error));
+        }
+        self.field_buffer.clear();
+        if format == FieldFormat::Binary {
+            self.field_buffer
+                .extend_from_slice(&value.to_be_bytes()[8 - width..]);
+        } else {
+            // same as `ToSqlText` of integers
+            let _ = write!(self.field_buffer, "{value}");
+        }
+        self.push_field_buffer()
+    }
+
+    /// `EncodeError` of the current column, discarding the partially encoded
+    /// value
+    fn encode_error(
+        &mut self,
+        data_type: &Type,
+        source: Box<dyn Error + Sync + Send>,
+    ) -> PgWireError {
+        self.field_buffer.clear();
+        PgWireError::EncodeError {
+            column: self.col_index,
+            type_oid: data_type.oid(),
+            source,
+        }
+    }
+
+    /// Encode value with type oid and format
+    ///
+    /// The oid is resolved from builtin types, or the `TypeRegistry` attached
+    /// to this encoder. Returns `UnknownTypeId` error when the type is not
+    /// found.
+    pub fn encode_field_with_type_oid<T>(
+        &mut self,
+        value: &T,
+        oid: Oid,
+        format: FieldFormat,
+    ) -> PgWireResult<()>
+    where
+        T: ToSql + ToSqlText + Sized,
+    {
+        let data_type = match self.type_registry {
+            Some(ref registry) => registry.get(oid),
+            None => Type::from_oid(oid),
+        }
+        .ok_or(PgWireError::UnknownTypeId(oid))?;
+
+        self.encode_field_with_type_and_format(value, &data_type, format)
+    }
+
     /// Encode value using type and format, defined by schema
     ///
     /// Panic when encoding more columns than provided as schema.
@@ -184,24 +956,109 @@ impl DataRowEncoder {
     where
         T: ToSql + ToSqlText + Sized,
     {
-        let data_type = self.schema[self.col_index].datatype();
-        let format = self.schema[self.col_index].format();
+        let schema = self.schema.clone();
+        let field = &schema[self.col_index];
 
-        let is_null = if *format == FieldFormat::Text {
-            value.to_sql_text(data_type, &mut self.field_buffer)?
-        } else {
-            value.to_sql(data_type, &mut self.field_buffer)?
-        };
+        self.encode_field_with_type_and_format(value, field.datatype(), *field.format())
+    }
 
-        if let IsNull::No = is_null {
-            let buf = self.field_buffer.split().freeze();
-            self.buffer.fields_mut().push(Some(buf));
+    /// Encode a field of `len` bytes by collecting a stream of chunks
+    ///
+    /// A convenience for values read in chunks, like a document from storage,
+    /// it doesn't stream the field. The chunks must be already encoded in the
+    /// format defined by schema, for example UTF-8 text of a `text` column.
+    /// They are copied into a buffer of `len` bytes as they arrive, and the
+    /// buffer is copied again into the `DataRow` message when sent, so the
+    /// whole value is in memory, twice while the message is written. Bound
+    /// the size with `with_max_field_size`. Returns `FieldLengthMismatch` if
+    /// the chunks don't add up to `len` bytes.
+    pub async fn encode_buffered_field<S>(&mut self, len: usize, chunks: S) -> PgWireResult<()>
+    where
+        S: Stream<Item = PgWireResult<Bytes>>,
+    {
+        self.check_field_size(len)?;
+        futures::pin_mut!(chunks);
+        self.field_buffer.reserve(len);
+        let mut received = 0;
+        while let Some(chunk) = chunks.next().await {
+            let chunk = chunk?;
+            received += chunk.len();
+            if received > len {
+                break;
+            }
+            self.field_buffer.extend_from_slice(&chunk);
+        }
+        if received != len {
+            self.field_buffer.clear();
+            return Err(PgWireError::FieldLengthMismatch(len, received));
+        }
+
+        self.push_field_buffer()
+    }
+
+    /// Encode a string field, the fast path of `encode_field` for text
+    /// columns.
+    ///
+    /// The string is copied into the row without going through `ToSql` or
+    /// `ToSqlText`, the bytes sent are the same as `encode_field(&value)`.
+    ///
+    /// Panic when encoding more columns than provided as schema.
+    pub fn encode_str_field(&mut self, value: &str) -> PgWireResult<()> {
+        if self.is_integer_column() {
+            // checked for range like the generic path
+            return self.encode_field(&value);
+        }
+        self.check_field_size(value.len())?;
+        self.push_field(value.as_bytes())
+    }
+
+    /// Encode a `bytea` field, the fast path of `encode_field` for `&[u8]`
+    /// and `Bytes`.
+    ///
+    /// The bytes are copied into the row as is in binary format, and hex
+    /// encoded in text format. The bytes sent are the same as
+    /// `encode_field(&value)`.
+    ///
+    /// Panic when encoding more columns than provided as schema.
+    pub fn encode_bytes_field(&mut self, value: &[u8]) -> PgWireResult<()> {
+        if self.is_integer_column() {
+            return self.encode_field(&value);
+        }
+        if *self.schema[self.col_index].format() == FieldFormat::Text {
+            self.check_field_size(value.len() * 2)?;
+            let start = self.field_buffer.len();
+            self.field_buffer.resize(start + value.len() * 2, 0);
+            // safe to unwrap, the buffer is sized for the hex string
+            hex::encode_to_slice(value, &mut self.field_buffer[start..]).unwrap();
+            self.push_field_buffer()
         } else {
-            self.buffer.fields_mut().push(None);
+            self.check_field_size(value.len())?;
+            self.push_field(value)
         }
+    }
+
+    /// Integers are range checked on encoding, so they can't take the fast
+    /// path
+    fn is_integer_column(&self) -> bool {
+        let ty = encoding_type(self.schema[self.col_index].datatype());
+        *ty == Type::INT2 || *ty == Type::INT4 || *ty == Type::INT8
+    }
 
+    fn push_field(&mut self, value: &[u8]) -> PgWireResult<()> {
+        self.field_buffer.extend_from_slice(value);
+        self.push_field_buffer()
+    }
+
+    /// Push the encoded value in `field_buffer` as the field of the current
+    /// column, after checking it for strict encoding
+    fn push_field_buffer(&mut self) -> PgWireResult<()> {
+        if let Some(field) = self.schema.clone().get(self.col_index) {
+            let data_type = encoding_type(field.datatype());
+            self.check_strict_encoding(data_type, *field.format(), &IsNull::No)?;
+        }
+        let buf = self.field_buffer.split().freeze();
+        self.buffer.fields_mut().push(Some(buf));
         self.col_index += 1;
-        self.field_buffer.clear();
         Ok(())
     }
 
@@ -211,30 +1068,334 @@ impl DataRowEncoder {
     }
 }
 
+/// Whether `T` is an integer, by the integer types its `ToSql` accepts
+fn is_integer_type<T: ToSql>() -> bool {
+    T::accepts(&Type::INT2) || T::accepts(&Type::INT4) || T::accepts(&Type::INT8)
+}
+
+/// Width in bytes and name of integer type `ty`, `None` for other types
+fn integer_width(ty: &Type) -> Option<(usize, &'static str)> {
+    if *ty == Type::INT2 {
+        Some((2, "smallint"))
+    } else if *ty == Type::INT4 {
+        Some((4, "integer"))
+    } else if *ty == Type::INT8 {
+        Some((8, "bigint"))
+    } else {
+        None
+    }
+}
+
+fn fits_integer_width(value: i64, width: usize) -> bool {
+    match width {
+        2 => i16::try_from(value).is_ok(),
+        4 => i32::try_from(value).is_ok(),
+        _ => true,
+    }
+}
+
+fn integer_out_of_range(name: &str) -> Box<ErrorInfo> {
+    Box::new(ErrorInfo::new(
+        Severity::Error,
+        SqlState::NumericValueOutOfRange,
+        format!("{name} out of range"),
+    ))
+}
+
+fn not_an_integer(name: &str) -> Box<ErrorInfo> {
+    Box::new(ErrorInfo::new(
+        Severity::Error,
+        SqlState::DatatypeMismatch,
+        format!("value of a non-integer type cannot be encoded as {name}"),
+    ))
+}
+
+/// Check an encoded value of a non-integer type for integer column `name` of
+/// `width` bytes.
+///
+/// In binary format it fails with `42804`, as its bytes don't make an
+/// integer. In text format, like a string, it's checked to be an integer,
+/// failing with `22P02` otherwise, or `22003` when it overflows the column.
+fn check_integer_field(
+    width: usize,
+    name: &str,
+    format: FieldFormat,
+    buf: &[u8],
+) -> Result<(), Box<ErrorInfo>> {
+    if format == FieldFormat::Binary {
+        return Err(not_an_integer(name));
+    }
+    let parsed = std::str::from_utf8(buf)
+        .map_err(|_| None)
+        .and_then(|v| v.parse::<i64>().map_err(Some));
+    match parsed {
+        Ok(value) if fits_integer_width(value, width) => Ok(()),
+        Ok(_) => Err(integer_out_of_range(name)),
+        Err(Some(e))
+            if matches!(
+                e.kind(),
+                IntErrorKind::PosOverflow | IntErrorKind::NegOverflow
+            ) =>
+        {
+            Err(integer_out_of_range(name))
+        }
+        Err(_) => Err(Box::new(ErrorInfo::new(
+            Severity::Error,
+            SqlState::InvalidTextRepresentation,
+            format!(
+                "invalid input syntax for type {name}: \"{}\"",
+                String::from_utf8_lossy(buf)
+            ),
+        ))),
+    }
+}
+
+/// Decode a binary value of builtin type `ty`, for `with_strict_encoding`.
+/// Values of other types are not checked.
+fn check_binary(ty: &Type, raw: &[u8]) -> Result<(), Box<dyn Error + Sync + Send>> {
+    type Check = fn(&Type, &[u8]) -> Result<(), Box<dyn Error + Sync + Send>>;
+
+    fn decode<T: for<'a> FromSql<'a>>(
+        ty: &Type,
+        raw: &[u8],
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        T::from_sql(ty, raw).map(|_| ())
+    }
+
+    let checks: [(Type, Check); 16] = [
+        (Type::BOOL, decode::<bool>),
+        (Type::CHAR, decode::<i8>),
+        (Type::INT2, decode::<i16>),
+        (Type::INT4, decode::<i32>),
+        (Type::INT8, decode::<i64>),
+        (Type::OID, decode::<u32>),
+        (Type::FLOAT4, decode::<f32>),
+        (Type::FLOAT8, decode::<f64>),
+        (Type::TEXT, decode::<String>),
+        (Type::VARCHAR, decode::<String>),
+        (Type::BPCHAR, decode::<String>),
+        (Type::NAME, decode::<String>),
+        (Type::DATE, decode::<NaiveDate>),
+        (Type::TIME, decode::<NaiveTime>),
+        (Type::TIMESTAMP, decode::<NaiveDateTime>),
+        (Type::TIMESTAMPTZ, decode::<DateTime<Utc>>),
+    ];
+    match checks.iter().find(|(check_type, _)| check_type == ty) {
+        Some((_, check)) => check(ty, raw),
+        None => Ok(()),
+    }
+}
+
+/// Create `QueryResponse` of one row, with columns `fields`.
+///
+/// `encode` is called with an encoder of `fields` to encode each column of
+/// the row, in order.
+pub fn single_row_response<'a, F>(
+    fields: Vec<FieldInfo>,
+    encode: F,
+) -> PgWireResult<QueryResponse<'a>>
+where
+    F: FnOnce(&mut DataRowEncoder) -> PgWireResult<()>,
+{
+    let schema = Arc::new(fields);
+    let mut encoder = DataRowEncoder::new(schema.clone());
+    encode(&mut encoder)?;
+    let row = encoder.finish()?;
+    Ok(QueryResponse::new(
+        schema,
+        futures::stream::iter(vec![Ok(row)]),
+    ))
+}
+
+/// Create `QueryResponse` of one row and one column named `name`, for queries
+/// like `SELECT version()` or `SHOW x`.
+pub fn single_value_response<'a, T>(
+    name: &str,
+    datatype: Type,
+    value: &T,
+    format: FieldFormat,
+) -> PgWireResult<QueryResponse<'a>>
+where
+    T: ToSql + ToSqlText + Sized,
+{
+    let field = FieldInfo::new(name.to_owned(), None, None, datatype, format);
+    single_row_response(vec![field], |encoder| encoder.encode_field(value))
+}
+
+/// Create `QueryResponse` streaming rows received from `rx`, for rows
+/// produced by another task or a worker pool.
+///
+/// The stream ends when all senders are dropped, and a sent `Err` fails the
+/// query like an error of any row stream. With a bounded channel, producers
+/// wait while rows are not written to the socket, so backpressure of a slow
+/// client reaches them. When the response is dropped, like on cancellation,
+/// `send` of producers fails.
+#[cfg(feature = "tokio")]
+pub fn query_response_from_channel<'a>(
+    field_defs: Arc<Vec<FieldInfo>>,
+    rx: tokio::sync::mpsc::Receiver<PgWireResult<DataRow>>,
+) -> QueryResponse<'a> {
+    let rows = futures::stream::unfold(
+        rx,
+        |mut rx| async move { rx.recv().await.map(|row| (row, rx)) },
+    );
+    QueryResponse::new(field_defs, rows.boxed())
+}
+
+/// Format and number of columns of a `COPY` operation
+#[derive(Debug, new, Getters, Clone)]
+#[getset(get = "pub")]
+pub struct CopyResponse {
+    format: FieldFormat,
+    columns: usize,
+}
+
+/// Data of a `CopyData` message in `COPY TO STDOUT`, and the number of rows
+/// in it.
+///
+/// A chunk may hold any number of rows, or only part of a row, as long as
+/// chunks concatenated are valid data of the format. Rows are summed up for
+/// the `COPY n` command tag, so count each row in exactly one chunk. The
+/// header and trailer of binary format have no rows.
+#[derive(Debug, new, Getters, Clone)]
+#[getset(get = "pub")]
+pub struct CopyOutData {
+    data: Bytes,
+    rows: u64,
+}
+
+impl CopyOutData {
+    /// Chunk of exactly one row
+    pub fn row(data: Bytes) -> CopyOutData {
+        CopyOutData::new(data, 1)
+    }
+}
+
+impl From<&CopyResponse> for CopyInResponse {
+    fn from(copy: &CopyResponse) -> CopyInResponse {
+        CopyInResponse::new(
+            copy.format.value() as i8,
+            copy.columns as i16,
+            vec![copy.format.value(); copy.columns],
+        )
+    }
+}
+
+impl From<&CopyResponse> for CopyOutResponse {
+    fn from(copy: &CopyResponse) -> CopyOutResponse {
+        CopyOutResponse::new(
+            copy.format.value() as i8,
+            copy.columns as i16,
+            vec![copy.format.value(); copy.columns],
+        )
+    }
+}
+
 /// Response for frontend describe requests.
 ///
 /// There are two types of describe: statement and portal. When describing
 /// statement, frontend expects parameter types inferenced by server. And both
 /// describe messages will require column definitions for resultset being
-/// returned.
+/// returned, or `NoData` for statements like `INSERT` that return no rows.
+///
+/// Prefer the constructors for each target over `new`. Parameters are only
+/// sent for a statement, in `ParameterDescription`, and the fields in
+/// `RowDescription`, or `NoData` from the `no_data_` constructors.
 #[derive(Debug, Getters, new)]
 #[getset(get = "pub")]
 pub struct DescribeResponse {
     parameters: Option<Vec<Type>>,
     fields: Vec<FieldInfo>,
+    /// respond `NoData` instead of `RowDescription`
+    #[new(default)]
+    no_data: bool,
 }
 
-impl DescribeResponse {}
+impl DescribeResponse {
+    /// Describe a statement with its parameter types and result fields
+    pub fn statement(parameters: Vec<Type>, fields: Vec<FieldInfo>) -> DescribeResponse {
+        DescribeResponse::new(Some(parameters), fields)
+    }
+
+    /// Describe a statement that returns no rows
+    pub fn no_data_statement(parameters: Vec<Type>) -> DescribeResponse {
+        DescribeResponse {
+            parameters: Some(parameters),
+            fields: vec![],
+            no_data: true,
+        }
+    }
+
+    /// Describe a portal with its result fields
+    pub fn portal(fields: Vec<FieldInfo>) -> DescribeResponse {
+        DescribeResponse::new(None, fields)
+    }
+
+    /// Describe a portal that returns no rows
+    pub fn no_data_portal() -> DescribeResponse {
+        DescribeResponse {
+            parameters: None,
+            fields: vec![],
+            no_data: true,
+        }
+    }
+}
 
 /// Query response types:
 ///
 /// * Query: the response contains data rows
+/// * DeferredQuery: the response contains data rows, with schema and rows
+///   resolved by a future when the response is being sent
 /// * Execution: response for ddl/dml execution
 /// * Error: error response
+/// * CopyIn: start `COPY FROM STDIN`, data from client is then handled by
+///   `CopyHandler`
+/// * CopyOut: `COPY TO STDOUT`, data is sent to client from the stream
+///
+/// No `RowDescription` is sent for copy responses.
 pub enum Response<'a> {
     Query(QueryResponse<'a>),
+    DeferredQuery(BoxFuture<'a, PgWireResult<QueryResponse<'a>>>),
     Execution(Tag),
     Error(Box<ErrorInfo>),
+    CopyIn(CopyResponse),
+    CopyOut(CopyResponse, BoxStream<'a, PgWireResult<CopyOutData>>),
+}
+
+impl<'a> Response<'a> {
+    /// Create a query response whose schema is only known after execution
+    /// started.
+    ///
+    /// The future is polled when this response is about to be sent, that is
+    /// after all previous responses of the same query have been sent.
+    /// `RowDescription` is sent once the future resolves, before the first
+    /// `DataRow`. So planning and execution can be done in one pass, and
+    /// statements of a multi-statement query are executed one by one.
+    pub fn deferred_query<F>(f: F) -> Response<'a>
+    where
+        F: Future<Output = PgWireResult<QueryResponse<'a>>> + Send + 'a,
+    {
+        Response::DeferredQuery(f.boxed())
+    }
+
+    /// Create a `COPY TO STDOUT` response.
+    ///
+    /// Each item of `data` is sent as a `CopyData`, in the format of `copy`,
+    /// like `CopyOutData::row(Bytes::from_static(b"1\ttom\n"))` in text
+    /// format. The command tag is `COPY n`, where `n` is the sum of `rows` of
+    /// all items.
+    ///
+    /// The stream is polled as the client reads, so large exports are not
+    /// held in memory. Once encoded messages exceed the write buffer size of
+    /// the connection, no more items are polled until the client reads them.
+    /// Each item is sent as one message, so its size still adds to memory of
+    /// the connection. Keep items small, like a row or a few rows.
+    pub fn copy_out<S>(copy: CopyResponse, data: S) -> Response<'a>
+    where
+        S: Stream<Item = PgWireResult<CopyOutData>> + Send + 'a,
+    {
+        Response::CopyOut(copy, data.boxed())
+    }
 }
 
 #[cfg(test)]
@@ -242,6 +1403,7 @@ mod test {
     use std::time::SystemTime;
 
     use super::*;
+    use crate::types::{ArrayEncoder, Interval, PgValue};
 
     #[test]
     fn test_command_complete() {
@@ -249,6 +1411,210 @@ mod test {
         let cc = CommandComplete::from(tag);
 
         assert_eq!(cc.tag(), "INSERT 100");
+
+        let cc = CommandComplete::from(Tag::new_for_insert(0, 3));
+        assert_eq!(cc.tag(), "INSERT 0 3");
+        let cc = CommandComplete::from(Tag::new_for_insert(16385, 1));
+        assert_eq!(cc.tag(), "INSERT 16385 1");
+
+        // row counts beyond 32 bits are not truncated
+        let cc = CommandComplete::from(Tag::new_for_query(u64::MAX));
+        assert_eq!(cc.tag(), "SELECT 18446744073709551615");
+        let cc = CommandComplete::from(Tag::new_for_execution("COPY", Some(5_000_000_000)));
+        assert_eq!(cc.tag(), "COPY 5000000000");
+    }
+
+    #[test]
+    fn test_field_type_modifier() {
+        for (field, datatype, modifier) in [
+            (FieldInfo::varchar("name", 255), Type::VARCHAR, 259),
+            (FieldInfo::bpchar("code", 2), Type::BPCHAR, 6),
+            (
+                FieldInfo::numeric("price", 10, 2),
+                Type::NUMERIC,
+                (10 << 16 | 2) + 4,
+            ),
+            (FieldInfo::bit("flags", 8), Type::BIT, 8),
+            (FieldInfo::varbit("mask", 64), Type::VARBIT, 64),
+            (
+                FieldInfo::new("id".to_owned(), None, None, Type::INT4, FieldFormat::Text),
+                Type::INT4,
+                -1,
+            ),
+        ] {
+            let desc = FieldDescription::from(&field);
+            assert_eq!(datatype.oid(), *desc.type_id());
+            assert_eq!(modifier, *desc.type_modifier());
+        }
+    }
+
+    #[test]
+    fn test_function_result_type() {
+        assert_eq!(&Type::INT8, FieldInfo::for_count("count").datatype());
+        for (function, argument, expected) in [
+            ("COUNT", None, Some(Type::INT8)),
+            ("sum", Some(Type::INT4), Some(Type::INT8)),
+            ("sum", Some(Type::INT8), Some(Type::NUMERIC)),
+            ("sum", Some(Type::FLOAT4), Some(Type::FLOAT4)),
+            ("sum", Some(Type::TEXT), None),
+            ("avg", Some(Type::INT4), Some(Type::NUMERIC)),
+            ("avg", Some(Type::FLOAT4), Some(Type::FLOAT8)),
+            ("stddev", Some(Type::INT8), Some(Type::NUMERIC)),
+            ("max", Some(Type::TIMESTAMP), Some(Type::TIMESTAMP)),
+            ("string_agg", Some(Type::VARCHAR), Some(Type::TEXT)),
+            ("string_agg", Some(Type::BYTEA), Some(Type::BYTEA)),
+            ("row_number", None, Some(Type::INT8)),
+            ("now", None, Some(Type::TIMESTAMPTZ)),
+            ("my_function", None, None),
+        ] {
+            assert_eq!(
+                expected,
+                function_result_type(function, argument.as_ref()),
+                "{function}({argument:?})"
+            );
+        }
+
+        let field = FieldInfo::for_function("total", "sum", Some(&Type::INT2)).unwrap();
+        assert_eq!("total", field.name());
+        assert_eq!(&Type::INT8, field.datatype());
+        assert!(FieldInfo::for_function("total", "sum", None).is_none());
+    }
+
+    #[test]
+    fn test_name_and_citext() {
+        let mut types = TypeRegistry::new();
+        let citext = types.register_citext(90001);
+        let schema = Arc::new(vec![
+            FieldInfo::new("relname".into(), None, None, Type::NAME, FieldFormat::Text),
+            FieldInfo::new(
+                "relname".into(),
+                None,
+                None,
+                Type::NAME,
+                FieldFormat::Binary,
+            ),
+            FieldInfo::new(
+                "email".into(),
+                None,
+                None,
+                citext.clone(),
+                FieldFormat::Text,
+            ),
+            FieldInfo::new("email".into(), None, None, citext, FieldFormat::Binary),
+        ]);
+
+        // name values are not padded to their 64 bytes size
+        let mut encoder = DataRowEncoder::new(schema.clone());
+        encoder.encode_field(&"pg_class").unwrap();
+        encoder.encode_field(&"pg_class").unwrap();
+        encoder.encode_field(&"Tom@Example.com").unwrap();
+        encoder.encode_value(&"Tom@Example.com").unwrap();
+        let row = encoder.finish().unwrap();
+        assert_eq!(
+            vec![
+                Some(Bytes::from_static(b"pg_class")),
+                Some(Bytes::from_static(b"pg_class")),
+                Some(Bytes::from_static(b"Tom@Example.com")),
+                Some(Bytes::from_static(b"Tom@Example.com")),
+            ],
+            *row.fields()
+        );
+
+        let sizes = schema
+            .iter()
+            .map(|field| *FieldDescription::from(field).type_size())
+            .collect::<Vec<_>>();
+        assert_eq!(vec![64, 64, -1, -1], sizes);
+        assert_eq!(4, type_size(&Type::INT4));
+        assert_eq!(-1, type_size(&Type::TEXT));
+    }
+
+    #[test]
+    fn test_utility_tag() {
+        for (stmt, tag) in [
+            ("CREATE TABLE t (id int)", "CREATE TABLE"),
+            ("create unique index idx on t (id)", "CREATE INDEX"),
+            ("CREATE OR REPLACE VIEW v AS SELECT 1", "CREATE VIEW"),
+            (
+                "CREATE MATERIALIZED VIEW mv AS SELECT 1",
+                "CREATE MATERIALIZED VIEW",
+            ),
+            ("create user alice", "CREATE ROLE"),
+            ("DROP INDEX idx", "DROP INDEX"),
+            ("ALTER TABLE t ADD COLUMN name text", "ALTER TABLE"),
+            ("BEGIN", "BEGIN"),
+            ("start transaction read only", "START TRANSACTION"),
+            ("COMMIT TRANSACTION", "COMMIT"),
+            ("end;", "COMMIT"),
+            ("ABORT", "ROLLBACK"),
+            ("SET search_path TO public", "SET"),
+            ("TRUNCATE t", "TRUNCATE TABLE"),
+            ("DISCARD ALL", "DISCARD ALL"),
+            ("call transfer(1, 2, 100.0)", "CALL"),
+            ("DECLARE c CURSOR FOR SELECT 1", "DECLARE CURSOR"),
+            ("close c", "CLOSE CURSOR"),
+            ("CLOSE ALL", "CLOSE CURSOR ALL"),
+        ] {
+            let cc = CommandComplete::from(Tag::new_for_utility(stmt).unwrap());
+            assert_eq!(tag, cc.tag(), "{stmt}");
+        }
+
+        // cursor tags of fetch and move have row counts
+        for (tag, expected) in [
+            (Tag::new_for_fetch(5), "FETCH 5"),
+            (Tag::new_for_move(0), "MOVE 0"),
+            (
+                Tag::new_for_declare_cursor().with_default_rows(1),
+                "DECLARE CURSOR",
+            ),
+            (Tag::new_for_close_cursor(false), "CLOSE CURSOR"),
+        ] {
+            assert_eq!(expected, CommandComplete::from(tag).tag());
+        }
+
+        assert!(Tag::new_for_utility("SELECT 1").is_none());
+        assert!(Tag::new_for_utility("CREATE").is_none());
+        assert!(Tag::new_for_utility("").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_single_value_response() {
+        let response =
+            single_value_response("version", Type::TEXT, &"PostgreSQL 15", FieldFormat::Text)
+                .unwrap();
+        assert_eq!("version", response.row_schema()[0].name());
+        let rows = response.data_rows.collect::<Vec<_>>().await;
+        assert_eq!(1, rows.len());
+        match &rows[0] {
+            Ok(QueryResponseItem::Row(row)) => {
+                assert_eq!(Some(&b"PostgreSQL 15"[..]), row.fields()[0].as_deref())
+            }
+            item => panic!("unexpected item {item:?}"),
+        }
+
+        let response = single_value_response("n", Type::INT4, &7i32, FieldFormat::Binary).unwrap();
+        match &response.data_rows.collect::<Vec<_>>().await[0] {
+            Ok(QueryResponseItem::Row(row)) => {
+                assert_eq!(Some(&7i32.to_be_bytes()[..]), row.fields()[0].as_deref())
+            }
+            item => panic!("unexpected item {item:?}"),
+        }
+
+        let fields = vec![
+            FieldInfo::new("name".into(), None, None, Type::VARCHAR, FieldFormat::Text),
+            FieldInfo::new("setting".into(), None, None, Type::INT4, FieldFormat::Text),
+        ];
+        let response = single_row_response(fields, |encoder| {
+            encoder.encode_field(&"max_connections")?;
+            encoder.encode_field(&100i32)
+        })
+        .unwrap();
+        match &response.data_rows.collect::<Vec<_>>().await[0] {
+            Ok(QueryResponseItem::Row(row)) => {
+                assert_eq!(Some(&b"100"[..]), row.fields()[1].as_deref())
+            }
+            item => panic!("unexpected item {item:?}"),
+        }
     }
 
     #[test]
@@ -270,4 +1636,547 @@ mod test {
         assert_eq!(row.fields()[1].as_ref().unwrap().len(), 4);
         assert_eq!(row.fields()[2].as_ref().unwrap().len(), 26);
     }
+
+    #[test]
+    fn test_data_row_encoder_fast_path() {
+        let schema = Arc::new(vec![
+            FieldInfo::new("name".into(), None, None, Type::VARCHAR, FieldFormat::Text),
+            FieldInfo::new("name".into(), None, None, Type::TEXT, FieldFormat::Binary),
+            FieldInfo::new("data".into(), None, None, Type::BYTEA, FieldFormat::Text),
+            FieldInfo::new("data".into(), None, None, Type::BYTEA, FieldFormat::Binary),
+            FieldInfo::new("id".into(), None, None, Type::INT2, FieldFormat::Text),
+        ]);
+        let name = "ünicode".to_owned();
+        let data = Bytes::from_static(b"\x00\xffab");
+
+        let mut encoder = DataRowEncoder::new(schema.clone());
+        encoder.encode_field(&name).unwrap();
+        encoder.encode_field(&name).unwrap();
+        encoder.encode_field(&data.as_ref()).unwrap();
+        encoder.encode_field(&data.as_ref()).unwrap();
+        encoder.encode_field(&"12").unwrap();
+        let expected = encoder.finish().unwrap();
+
+        let mut encoder = DataRowEncoder::new(schema.clone());
+        encoder.encode_str_field(&name).unwrap();
+        encoder.encode_str_field(name.as_str()).unwrap();
+        encoder.encode_bytes_field(&data).unwrap();
+        encoder.encode_bytes_field(&data).unwrap();
+        encoder.encode_str_field("12").unwrap();
+        assert_eq!(expected, encoder.finish().unwrap());
+
+        // integers are still range checked
+        let mut encoder = DataRowEncoder::new(schema);
+        for _ in 0..4 {
+            encoder.encode_str_field("").unwrap();
+        }
+        assert!(encoder.encode_str_field("65536").is_err());
+    }
+
+    #[test]
+    fn test_data_row_encoder_bool() {
+        // SELECT true, false, NULL::bool
+        let values = [Some(true), Some(false), None];
+        for (format, expected) in [
+            (FieldFormat::Text, [Some(&b"t"[..]), Some(b"f"), None]),
+            (FieldFormat::Binary, [Some(&[1][..]), Some(&[0]), None]),
+        ] {
+            let schema = Arc::new(
+                (0..3)
+                    .map(|_| FieldInfo::new("bool".into(), None, None, Type::BOOL, format))
+                    .collect(),
+            );
+            let mut encoder = DataRowEncoder::new(schema);
+            for value in &values {
+                encoder.encode_field(value).unwrap();
+            }
+            let row = encoder.finish().unwrap();
+            let fields: Vec<_> = row.fields().iter().map(|f| f.as_deref()).collect();
+            assert_eq!(&expected[..], &fields[..]);
+
+            let decoded: Vec<_> = row
+                .fields()
+                .iter()
+                .map(|f| PgValue::decode(&Type::BOOL, format, f.as_ref()).unwrap())
+                .collect();
+            let values = values.map(|v| v.map_or(PgValue::Null, PgValue::Bool));
+            assert_eq!(&values[..], &decoded[..]);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "time-format")]
+    fn test_binary_timestamp_is_integer() {
+        // 2000-01-02 00:00:00.000001, one day and one microsecond after
+        // postgres epoch
+        let ts = chrono::NaiveDate::from_ymd_opt(2000, 1, 2)
+            .unwrap()
+            .and_hms_micro_opt(0, 0, 0, 1)
+            .unwrap();
+        let schema = Arc::new(vec![FieldInfo::new(
+            "ts".into(),
+            None,
+            None,
+            Type::TIMESTAMP,
+            FieldFormat::Binary,
+        )]);
+        let mut encoder = DataRowEncoder::new(schema);
+        encoder.encode_field(&ts).unwrap();
+        let row = encoder.finish().unwrap();
+
+        let expected = 86_400_000_001i64.to_be_bytes();
+        assert_eq!(row.fields()[0].as_deref(), Some(&expected[..]));
+    }
+
+    #[test]
+    fn test_data_row_encoder_custom_type() {
+        let mut registry = TypeRegistry::new();
+        let email = registry.register(90001, "email", Type::TEXT);
+        let schema = Arc::new(vec![
+            FieldInfo::new("email".into(), None, None, email, FieldFormat::Binary),
+            FieldInfo::new("id".into(), None, None, Type::INT8, FieldFormat::Binary),
+        ]);
+        let mut encoder = DataRowEncoder::new(schema).with_type_registry(Arc::new(registry));
+        encoder.encode_field(&"a@b.c").unwrap();
+        encoder
+            .encode_field_with_type_oid(&1i64, 20, FieldFormat::Binary)
+            .unwrap();
+        assert!(encoder
+            .encode_field_with_type_oid(&1i64, 90003, FieldFormat::Binary)
+            .is_err());
+
+        let row = encoder.finish().unwrap();
+        assert_eq!(row.fields()[0].as_deref(), Some(&b"a@b.c"[..]));
+        assert_eq!(row.fields()[1].as_deref(), Some(&1i64.to_be_bytes()[..]));
+    }
+
+    #[test]
+    fn test_data_row_encoder_value() {
+        let mut types = TypeRegistry::new();
+        let citext = types.register(90001, "citext", Type::TEXT);
+        let schema = Arc::new(vec![
+            FieldInfo::new("id".into(), None, None, Type::INT4, FieldFormat::Binary),
+            FieldInfo::new("name".into(), None, None, citext, FieldFormat::Text),
+        ]);
+        let mut encoders = TypeEncoderRegistry::new();
+        encoders.register(90001, |value, _ty, _format, buf| {
+            let value = value.downcast_ref::<String>().unwrap();
+            buf.extend_from_slice(value.to_lowercase().as_bytes());
+            Ok(IsNull::No)
+        });
+
+        let mut encoder =
+            DataRowEncoder::new(schema.clone()).with_encoder_registry(Arc::new(encoders));
+        encoder.encode_value(&7i32).unwrap();
+        encoder.encode_value(&"Tom".to_owned()).unwrap();
+        let row = encoder.finish().unwrap();
+        assert_eq!(row.fields()[0].as_deref(), Some(&7i32.to_be_bytes()[..]));
+        assert_eq!(row.fields()[1].as_deref(), Some(&b"tom"[..]));
+
+        // builtin encoders only
+        let mut encoder = DataRowEncoder::new(schema);
+        assert!(encoder.encode_value(&7i64).is_err());
+        encoder.encode_value(&7i32).unwrap();
+        encoder.encode_value(&None::<String>).unwrap();
+        let row = encoder.finish().unwrap();
+        assert_eq!(row.fields()[1], None);
+    }
+
+    #[test]
+    fn test_data_row_encoder_interval_style() {
+        let schema = Arc::new(vec![FieldInfo::new(
+            "i".into(),
+            None,
+            None,
+            Type::INTERVAL,
+            FieldFormat::Text,
+        )]);
+        let interval = Interval::new(14, 3, 14_706_000_000);
+        let mut encoder = DataRowEncoder::new(schema.clone());
+        encoder.encode_field(&interval).unwrap();
+        let row = encoder.finish().unwrap();
+        assert_eq!(
+            row.fields()[0].as_deref(),
+            Some(&b"1 year 2 mons 3 days 04:05:06"[..])
+        );
+
+        let mut encoder = DataRowEncoder::new(schema).with_interval_style(IntervalStyle::Iso8601);
+        encoder.encode_field(&Some(interval)).unwrap();
+        let row = encoder.finish().unwrap();
+        assert_eq!(row.fields()[0].as_deref(), Some(&b"P1Y2M3DT4H5M6S"[..]));
+
+        // and to elements of arrays
+        let schema = Arc::new(vec![FieldInfo::new(
+            "a".into(),
+            None,
+            None,
+            Type::INTERVAL_ARRAY,
+            FieldFormat::Text,
+        )]);
+        let intervals = vec![interval, Interval::new(0, 0, 1_000_000)];
+        for (style, expected) in [
+            (
+                IntervalStyle::Postgres,
+                &b"{\"1 year 2 mons 3 days 04:05:06\",00:00:01}"[..],
+            ),
+            (IntervalStyle::Iso8601, &b"{P1Y2M3DT4H5M6S,PT1S}"[..]),
+        ] {
+            let mut encoder = DataRowEncoder::new(schema.clone()).with_interval_style(style);
+            encoder.encode_field(&intervals).unwrap();
+            let row = encoder.finish().unwrap();
+            assert_eq!(row.fields()[0].as_deref(), Some(expected));
+        }
+
+        let mut array = ArrayEncoder::new(Type::INTERVAL, FieldFormat::Text)
+            .with_interval_style(IntervalStyle::SqlStandard);
+        array.encode_element(&interval).unwrap();
+        array.encode_element(&None::<Interval>).unwrap();
+        assert_eq!(
+            &b"{\"+1-2 +3 +4:05:06\",NULL}"[..],
+            &array.finish().bytes()[..]
+        );
+    }
+
+    #[test]
+    fn test_data_row_encoder_strict_encoding() {
+        #[derive(Debug)]
+        struct Wide;
+
+        impl ToSql for Wide {
+            fn to_sql(
+                &self,
+                _ty: &Type,
+                out: &mut BytesMut,
+            ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+                out.extend_from_slice(&[0xff, 0, 1]);
+                Ok(IsNull::No)
+            }
+
+            fn accepts(_ty: &Type) -> bool {
+                true
+            }
+
+            postgres_types::to_sql_checked!();
+        }
+
+        impl ToSqlText for Wide {
+            fn to_sql_text(
+                &self,
+                _ty: &Type,
+                out: &mut BytesMut,
+            ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+                out.extend_from_slice(b"1");
+                Ok(IsNull::No)
+            }
+        }
+
+        let schema = Arc::new(vec![
+            FieldInfo::new("id".into(), None, None, Type::INT4, FieldFormat::Binary),
+            FieldInfo::new("name".into(), None, None, Type::TEXT, FieldFormat::Binary),
+        ]);
+        // malformed values pass unless strict
+        let mut encoder = DataRowEncoder::new(schema.clone());
+        encoder.encode_field(&1i32).unwrap();
+        encoder.encode_field(&Wide).unwrap();
+
+        let mut encoder = DataRowEncoder::new(schema).with_strict_encoding(true);
+        encoder.encode_field(&1i32).unwrap();
+        assert!(matches!(
+            encoder.encode_field(&Wide),
+            Err(PgWireError::EncodeError { column: 1, .. })
+        ));
+        encoder.encode_field(&"name").unwrap();
+        assert_eq!(2, encoder.finish().unwrap().fields().len());
+
+        let schema = Arc::new(vec![FieldInfo::new(
+            "id".into(),
+            None,
+            None,
+            Type::INT4,
+            FieldFormat::Binary,
+        )]);
+        // integers of a wrong width fail anyway, as malformed when strict
+        let mut encoder = DataRowEncoder::new(schema.clone());
+        assert!(matches!(
+            encoder.encode_field(&Wide),
+            Err(PgWireError::EncodeError { column: 0, source, .. })
+                if source.downcast_ref::<ErrorInfo>().unwrap().code() == "42804"
+        ));
+        let mut encoder = DataRowEncoder::new(schema).with_strict_encoding(true);
+        assert!(matches!(
+            encoder.encode_field(&Wide),
+            Err(PgWireError::EncodeError {
+                column: 0,
+                type_oid: 23,
+                ..
+            })
+        ));
+        // before an integer of another width is fitted to the column
+        assert!(matches!(
+            encoder.encode_field(&1i64),
+            Err(PgWireError::EncodeError { column: 0, .. })
+        ));
+        encoder.encode_field(&1i32).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_data_row_encoder_strict_fast_paths() {
+        let schema = Arc::new(vec![
+            FieldInfo::new("a".into(), None, None, Type::BOOL, FieldFormat::Binary),
+            FieldInfo::new("b".into(), None, None, Type::FLOAT8, FieldFormat::Binary),
+            FieldInfo::new("c".into(), None, None, Type::BOOL, FieldFormat::Binary),
+        ]);
+        let mut encoder = DataRowEncoder::new(schema).with_strict_encoding(true);
+        let malformed = |result: PgWireResult<()>, column: usize| matches!(result, Err(PgWireError::EncodeError { column: c, .. }) if c == column);
+
+        assert!(malformed(encoder.encode_str_field("yes"), 0));
+        encoder.encode_str_field("\x01").unwrap();
+        assert!(malformed(encoder.encode_bytes_field(&[1, 2, 3]), 1));
+        encoder.encode_bytes_field(&[0; 8]).unwrap();
+        let chunks = futures::stream::iter(vec![
+            Ok(Bytes::from_static(&[1])),
+            Ok(Bytes::from_static(&[1])),
+        ]);
+        assert!(malformed(encoder.encode_buffered_field(2, chunks).await, 2));
+        let chunks = futures::stream::iter(vec![Ok(Bytes::from_static(&[0]))]);
+        encoder.encode_buffered_field(1, chunks).await.unwrap();
+        assert_eq!(3, encoder.finish().unwrap().fields().len());
+    }
+
+    #[test]
+    fn test_data_row_encoder_integer_range() {
+        let schema = Arc::new(vec![
+            FieldInfo::new("a".into(), None, None, Type::INT4, FieldFormat::Binary),
+            FieldInfo::new("b".into(), None, None, Type::INT8, FieldFormat::Binary),
+            FieldInfo::new("c".into(), None, None, Type::INT2, FieldFormat::Text),
+        ]);
+        let mut encoder = DataRowEncoder::new(schema.clone());
+        encoder.encode_field(&-7i64).unwrap();
+        encoder.encode_field(&-7i16).unwrap();
+        encoder.encode_field(&32767i64).unwrap();
+        let row = encoder.finish().unwrap();
+        assert_eq!(row.fields()[0].as_deref(), Some(&(-7i32).to_be_bytes()[..]));
+        assert_eq!(row.fields()[1].as_deref(), Some(&(-7i64).to_be_bytes()[..]));
+        assert_eq!(row.fields()[2].as_deref(), Some(&b"32767"[..]));
+
+        // failures are `EncodeError` of the column, with the code of source
+        let failed_with = |result: PgWireResult<()>, column: usize, code: &str| match result {
+            Err(PgWireError::EncodeError {
+                column: c,
+                type_oid,
+                source,
+            }) => {
+                c == column
+                    && type_oid == schema[column].datatype().oid()
+                    && source.downcast_ref::<ErrorInfo>().unwrap().code() == code
+            }
+            _ => false,
+        };
+        let mut encoder = DataRowEncoder::new(schema.clone());
+        let error = encoder.encode_field(&(i32::MAX as i64 + 1)).unwrap_err();
+        assert_eq!(
+            "Failed to encode column 0 as type 23: integer out of range",
+            error.to_string()
+        );
+        assert!(failed_with(Err(error), 0, "22003"));
+        encoder.encode_field(&i32::MIN).unwrap();
+        encoder.encode_field(&i64::MIN).unwrap();
+        assert!(failed_with(encoder.encode_field(&32768i32), 2, "22003"));
+        assert!(failed_with(encoder.encode_field(&"-32769"), 2, "22003"));
+        assert!(failed_with(
+            encoder.encode_field(&"99999999999999999999"),
+            2,
+            "22003"
+        ));
+        encoder.encode_field(&-32768i64).unwrap();
+        let row = encoder.finish().unwrap();
+        assert_eq!(row.fields()[2].as_deref(), Some(&b"-32768"[..]));
+
+        // only integers are fitted
+        let mut encoder = DataRowEncoder::new(schema.clone());
+        assert!(failed_with(encoder.encode_field(&1.5f32), 0, "42804"));
+        encoder.encode_field(&None::<i16>).unwrap();
+        assert!(failed_with(encoder.encode_field(&1.5f64), 1, "42804"));
+        encoder.encode_field(&7i64).unwrap();
+        assert!(failed_with(encoder.encode_field(&"abc"), 2, "22P02"));
+        encoder.encode_field(&"12").unwrap();
+        let row = encoder.finish().unwrap();
+        assert_eq!(row.fields()[0], None);
+        assert_eq!(row.fields()[2].as_deref(), Some(&b"12"[..]));
+    }
+
+    #[derive(Debug)]
+    struct Unencodable;
+
+    impl ToSql for Unencodable {
+        fn to_sql(
+            &self,
+            _ty: &Type,
+            _out: &mut BytesMut,
+        ) -> Result<IsNull, Box<dyn std::error::Error + Sync + Send>> {
+            Err("value is not encodable".into())
+        }
+
+        postgres_types::accepts!(INT4);
+        postgres_types::to_sql_checked!();
+    }
+
+    impl ToSqlText for Unencodable {
+        fn to_sql_text(
+            &self,
+            ty: &Type,
+            out: &mut BytesMut,
+        ) -> Result<IsNull, Box<dyn std::error::Error + Sync + Send>> {
+            self.to_sql(ty, out)
+        }
+    }
+
+    #[test]
+    fn test_data_row_encoder_error() {
+        let schema = Arc::new(vec![
+            FieldInfo::new("id".into(), None, None, Type::INT4, FieldFormat::Binary),
+            FieldInfo::new("score".into(), None, None, Type::INT4, FieldFormat::Text),
+        ]);
+        let mut encoder = DataRowEncoder::new(schema);
+        encoder.encode_field(&1i32).unwrap();
+        let error = encoder.encode_field(&Unencodable).unwrap_err();
+        assert_eq!(
+            "Failed to encode column 1 as type 23: value is not encodable",
+            error.to_string()
+        );
+        assert!(matches!(
+            error,
+            PgWireError::EncodeError {
+                column: 1,
+                type_oid: 23,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_data_row_encoder_max_field_size() {
+        let schema = Arc::new(vec![
+            FieldInfo::new("name".into(), None, None, Type::TEXT, FieldFormat::Text),
+            FieldInfo::new("data".into(), None, None, Type::BYTEA, FieldFormat::Text),
+        ]);
+        let too_large = |result: PgWireResult<()>| match result {
+            Err(PgWireError::UserError(info)) => {
+                assert_eq!("54000", info.code());
+                info.message().clone()
+            }
+            result => panic!("unexpected result {result:?}"),
+        };
+
+        let mut encoder = DataRowEncoder::new(schema.clone()).with_max_field_size(4);
+        assert_eq!(
+            "value of column \"name\" exceeds the max field size of 4 bytes",
+            too_large(encoder.encode_field(&"large"))
+        );
+        assert!(too_large(encoder.encode_str_field("large")).contains("\"name\""));
+        let chunks = futures::stream::iter(vec![Ok(Bytes::from_static(b"large"))]);
+        too_large(encoder.encode_buffered_field(5, chunks).await);
+        encoder.encode_field(&"name").unwrap();
+        // hex encoded in text format
+        too_large(encoder.encode_bytes_field(b"abc"));
+        encoder.encode_bytes_field(b"ab").unwrap();
+        assert_eq!(2, encoder.finish().unwrap().fields().len());
+
+        assert!(DataRowEncoder::new(schema)
+            .encode_field(&"large".repeat(1000))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_data_row_encoder_with_capacity() {
+        let schema = Arc::new(vec![
+            FieldInfo::new("id".into(), None, None, Type::INT4, FieldFormat::Binary),
+            FieldInfo::new("name".into(), None, None, Type::VARCHAR, FieldFormat::Text),
+        ]);
+        let mut encoder = DataRowEncoder::with_capacity(schema, 1024);
+        encoder.encode_field(&2001i32).unwrap();
+        encoder.encode_field(&"x".repeat(2048)).unwrap();
+
+        let row = encoder.finish().unwrap();
+        assert_eq!(row.fields()[0].as_deref(), Some(&2001i32.to_be_bytes()[..]));
+        assert_eq!(row.fields()[1].as_ref().unwrap().len(), 2048);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_query_response_from_channel() {
+        let schema = Arc::new(vec![FieldInfo::new(
+            "id".into(),
+            None,
+            None,
+            Type::INT4,
+            FieldFormat::Text,
+        )]);
+        let (tx, rx) = tokio::sync::mpsc::channel(1);
+        let producer = tokio::spawn({
+            let schema = schema.clone();
+            async move {
+                for id in 0..3i32 {
+                    let mut encoder = DataRowEncoder::new(schema.clone());
+                    encoder.encode_field(&id).unwrap();
+                    if tx.send(encoder.finish()).await.is_err() {
+                        return;
+                    }
+                }
+                let _ = tx
+                    .send(Err(PgWireError::ApiError("worker failed".into())))
+                    .await;
+            }
+        });
+
+        let response = query_response_from_channel(schema, rx);
+        let items = response.data_rows.collect::<Vec<_>>().await;
+        assert_eq!(4, items.len());
+        match &items[2] {
+            Ok(QueryResponseItem::Row(row)) => {
+                assert_eq!(Some(&b"2"[..]), row.fields()[0].as_deref())
+            }
+            item => panic!("unexpected item {item:?}"),
+        }
+        assert!(matches!(items[3], Err(PgWireError::ApiError(_))));
+        producer.await.unwrap();
+
+        // producers stop when the response is dropped
+        let (tx, rx) = tokio::sync::mpsc::channel(1);
+        drop(query_response_from_channel(Arc::new(vec![]), rx));
+        assert!(tx.send(Ok(DataRow::new(vec![]))).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_data_row_encoder_from_chunks() {
+        let schema = Arc::new(vec![
+            FieldInfo::new("id".into(), None, None, Type::INT4, FieldFormat::Binary),
+            FieldInfo::new("doc".into(), None, None, Type::TEXT, FieldFormat::Text),
+        ]);
+        let chunks = || {
+            futures::stream::iter(
+                ["lorem ", "ipsum ", "dolor"]
+                    .into_iter()
+                    .map(|chunk| Ok(Bytes::from_static(chunk.as_bytes()))),
+            )
+        };
+
+        let mut encoder = DataRowEncoder::new(schema.clone());
+        encoder.encode_field(&1i32).unwrap();
+        encoder.encode_buffered_field(17, chunks()).await.unwrap();
+        let row = encoder.finish().unwrap();
+        assert_eq!(row.fields()[0].as_deref(), Some(&1i32.to_be_bytes()[..]));
+        assert_eq!(row.fields()[1].as_deref(), Some(&b"lorem ipsum dolor"[..]));
+
+        let mut encoder = DataRowEncoder::new(schema);
+        encoder.encode_field(&1i32).unwrap();
+        assert!(matches!(
+            encoder.encode_buffered_field(16, chunks()).await,
+            Err(PgWireError::FieldLengthMismatch(16, 17))
+        ));
+        assert!(matches!(
+            encoder.encode_buffered_field(18, chunks()).await,
+            Err(PgWireError::FieldLengthMismatch(18, 17))
+        ));
+    }
 }
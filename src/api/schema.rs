@@ -0,0 +1,293 @@
+//! Row schemas of Rust structs, for handlers serving rows of a fixed type.
+//!
+//! Implement `RowSchema` for a struct with `impl_row_schema!`, listing the
+//! fields that become columns. Column names are the field names, and column
+//! types are inferred from field types with `PgType`:
+//!
+//! ```
+//! use pgwire::api::schema::RowSchema;
+//! use pgwire::impl_row_schema;
+//! use pgwire::types::IntervalStyle;
+//!
+//! struct User {
+//!     id: i64,
+//!     name: String,
+//!     email: Option<String>,
+//! }
+//!
+//! impl_row_schema!(User { id, name, email });
+//!
+//! let schema = User::row_schema();
+//! assert_eq!("name", schema[1].name());
+//! let row = User { id: 1, name: "tom".to_owned(), email: None }
+//!     .encode_row(&schema, IntervalStyle::default())
+//!     .unwrap();
+//! ```
+//!
+//! Use `into_query_response` to respond with a list of rows. Both take the
+//! `IntervalStyle` of the connection, `ClientInfo::interval_style`, for text
+//! output of interval columns.
+
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, TimeZone};
+use futures::stream;
+use postgres_types::Type;
+
+use super::portal::Format;
+use super::results::{DataRowEncoder, FieldInfo, QueryResponse};
+use crate::error::PgWireResult;
+use crate::messages::data::DataRow;
+use crate::types::{
+    BitString, Int2Vector, Interval, IntervalStyle, MacAddr, MacAddr8, Numeric, OidVector, TimeTz,
+    TsQuery, TsVector, Xml,
+};
+
+/// Postgres type of values of a Rust type, the type `ToSql` and `ToSqlText`
+/// encode them to
+pub trait PgType {
+    fn pg_type() -> Type;
+}
+
+macro_rules! impl_pg_type {
+    ($($t:ty => $pg_type:expr),* $(,)?) => {
+        $(
+            impl PgType for $t {
+                fn pg_type() -> Type {
+                    $pg_type
+                }
+            }
+        )*
+    };
+}
+
+impl_pg_type!(
+    bool => Type::BOOL,
+    i8 => Type::CHAR,
+    i16 => Type::INT2,
+    i32 => Type::INT4,
+    i64 => Type::INT8,
+    u32 => Type::OID,
+    f32 => Type::FLOAT4,
+    f64 => Type::FLOAT8,
+    String => Type::TEXT,
+    &str => Type::TEXT,
+    Vec<u8> => Type::BYTEA,
+    &[u8] => Type::BYTEA,
+    SystemTime => Type::TIMESTAMPTZ,
+    NaiveDate => Type::DATE,
+    NaiveTime => Type::TIME,
+    NaiveDateTime => Type::TIMESTAMP,
+    BitString => Type::VARBIT,
+    OidVector => Type::OID_VECTOR,
+    Int2Vector => Type::INT2_VECTOR,
+    TsVector => Type::TS_VECTOR,
+    TsQuery => Type::TSQUERY,
+    Xml => Type::XML,
+    Interval => Type::INTERVAL,
+    MacAddr => Type::MACADDR,
+    MacAddr8 => Type::MACADDR8,
+    TimeTz => Type::TIMETZ,
+    Numeric => Type::NUMERIC,
+);
+
+impl<Tz: TimeZone> PgType for DateTime<Tz> {
+    fn pg_type() -> Type {
+        Type::TIMESTAMPTZ
+    }
+}
+
+/// `NULL` for `None`
+impl<T: PgType> PgType for Option<T> {
+    fn pg_type() -> Type {
+        T::pg_type()
+    }
+}
+
+/// A row type with fixed columns, usually implemented with `impl_row_schema!`
+pub trait RowSchema {
+    /// Names and types of columns
+    fn columns() -> Vec<(&'static str, Type)>;
+
+    /// Encode values of columns of this row, in order of `columns`
+    fn encode_fields(&self, encoder: &mut DataRowEncoder) -> PgWireResult<()>;
+
+    /// Schema of rows in text format
+    fn row_schema() -> Arc<Vec<FieldInfo>> {
+        Self::row_schema_with_format(&Format::UnifiedText)
+    }
+
+    /// Schema of rows in `format`, like `result_column_format` of the
+    /// executed portal
+    fn row_schema_with_format(format: &Format) -> Arc<Vec<FieldInfo>> {
+        Arc::new(
+            Self::columns()
+                .into_iter()
+                .enumerate()
+                .map(|(idx, (name, datatype))| {
+                    FieldInfo::new(
+                        name.to_owned(),
+                        None,
+                        None,
+                        datatype,
+                        format.format_for(idx),
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    /// Encode this row with `schema` from `row_schema`, and intervals in
+    /// `interval_style`
+    fn encode_row(
+        &self,
+        schema: &Arc<Vec<FieldInfo>>,
+        interval_style: IntervalStyle,
+    ) -> PgWireResult<DataRow> {
+        let mut encoder = DataRowEncoder::new(schema.clone()).with_interval_style(interval_style);
+        self.encode_fields(&mut encoder)?;
+        encoder.finish()
+    }
+}
+
+/// Create `QueryResponse` of `rows` encoded in `format`, and intervals in
+/// `interval_style`
+pub fn into_query_response<'a, R, I>(
+    rows: I,
+    format: &Format,
+    interval_style: IntervalStyle,
+) -> QueryResponse<'a>
+where
+    R: RowSchema,
+    I: IntoIterator<Item = R>,
+{
+    let schema = R::row_schema_with_format(format);
+    let rows = rows
+        .into_iter()
+        .map(|row| row.encode_row(&schema, interval_style))
+        .collect::<Vec<_>>();
+    QueryResponse::new(schema, stream::iter(rows))
+}
+
+/// Get type of a field of `R`, for `impl_row_schema!`
+#[doc(hidden)]
+pub fn field_type<R, T: PgType>(_field: fn(&R) -> &T) -> Type {
+    T::pg_type()
+}
+
+/// Implement `RowSchema` for a struct, with a column for each listed field.
+///
+/// Types of the fields must implement `PgType`, `ToSql` and `ToSqlText`.
+#[macro_export]
+macro_rules! impl_row_schema {
+    ($row:ty { $($field:ident),+ $(,)? }) => {
+        impl $crate::api::schema::RowSchema for $row {
+            fn columns() -> Vec<(&'static str, $crate::api::Type)> {
+                vec![$((
+                    stringify!($field),
+                    $crate::api::schema::field_type(|row: &$row| &row.$field),
+                )),+]
+            }
+
+            fn encode_fields(
+                &self,
+                encoder: &mut $crate::api::results::DataRowEncoder,
+            ) -> $crate::error::PgWireResult<()> {
+                $(encoder.encode_field(&self.$field)?;)+
+                Ok(())
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::api::results::{FieldFormat, QueryResponseItem};
+
+    struct Account {
+        id: i32,
+        owner: String,
+        balance: Option<f64>,
+        opened: NaiveDate,
+        // not a column
+        #[allow(dead_code)]
+        secret: Vec<u8>,
+    }
+
+    impl_row_schema!(Account {
+        id,
+        owner,
+        balance,
+        opened,
+    });
+
+    #[tokio::test]
+    async fn test_row_schema() {
+        let schema = Account::row_schema_with_format(&Format::Individual(vec![1, 0]));
+        assert_eq!(
+            vec![
+                ("id", Type::INT4, FieldFormat::Binary),
+                ("owner", Type::TEXT, FieldFormat::Text),
+                ("balance", Type::FLOAT8, FieldFormat::Text),
+                ("opened", Type::DATE, FieldFormat::Text),
+            ],
+            schema
+                .iter()
+                .map(|field| (
+                    field.name().as_str(),
+                    field.datatype().clone(),
+                    *field.format()
+                ))
+                .collect::<Vec<_>>()
+        );
+
+        let accounts = vec![Account {
+            id: 7,
+            owner: "tom".to_owned(),
+            balance: None,
+            opened: NaiveDate::from_ymd_opt(2023, 4, 1).unwrap(),
+            secret: vec![],
+        }];
+        let response = into_query_response(
+            accounts,
+            &Format::Individual(vec![1, 0]),
+            IntervalStyle::default(),
+        );
+        let rows = response.data_rows.collect::<Vec<_>>().await;
+        match rows.as_slice() {
+            [Ok(QueryResponseItem::Row(row))] => assert_eq!(
+                &vec![
+                    Some(Bytes::from_static(&[0, 0, 0, 7])),
+                    Some(Bytes::from_static(b"tom")),
+                    None,
+                    Some(Bytes::from_static(b"2023-04-01")),
+                ],
+                row.fields()
+            ),
+            rows => panic!("unexpected rows {rows:?}"),
+        }
+    }
+
+    struct Lease {
+        term: Interval,
+    }
+
+    impl_row_schema!(Lease { term });
+
+    #[test]
+    fn test_row_schema_interval_style() {
+        let schema = Lease::row_schema();
+        let lease = Lease {
+            term: Interval::new(14, 0, 0),
+        };
+        let row = lease.encode_row(&schema, IntervalStyle::Iso8601).unwrap();
+        assert_eq!(row.fields()[0].as_deref(), Some(&b"P1Y2M"[..]));
+        let row = lease.encode_row(&schema, IntervalStyle::Postgres).unwrap();
+        assert_eq!(row.fields()[0].as_deref(), Some(&b"1 year 2 mons"[..]));
+    }
+}
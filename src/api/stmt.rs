@@ -3,9 +3,10 @@ use postgres_types::Type;
 use crate::error::{PgWireError, PgWireResult};
 use crate::messages::extendedquery::Parse;
 
+use super::transaction::{IsolationSetting, TransactionControl};
 use super::DEFAULT_NAME;
 
-#[derive(Debug, Default, new, Getters, Setters)]
+#[derive(Debug, Default, Clone, new, Getters, Setters)]
 #[getset(get = "pub", set = "pub", get_mut = "pub")]
 pub struct StoredStatement<S> {
     /// name of the statement
@@ -13,8 +14,19 @@ pub struct StoredStatement<S> {
     /// parsed query statement
     statement: S,
     /// type ids of query parameters, can be empty if frontend asks backend for
-    /// type inference
+    /// type inference. Parameters sent with oid 0 are `unknown`. Replaced by
+    /// the types inferred by `do_describe` of the statement.
     parameter_types: Vec<Type>,
+    /// transaction control of the statement, detected from query string
+    #[new(default)]
+    transaction_control: Option<TransactionControl>,
+    /// isolation level set by the statement, detected from query string
+    #[new(default)]
+    isolation_setting: Option<IsolationSetting>,
+    /// number of result columns, known once the statement is described, so
+    /// that portals bound from it get their result formats on `Bind`
+    #[new(default)]
+    result_len: Option<usize>,
 }
 
 impl<S> StoredStatement<S> {
@@ -25,7 +37,11 @@ impl<S> StoredStatement<S> {
         let types = parse
             .type_oids()
             .iter()
-            .map(|oid| Type::from_oid(*oid).ok_or_else(|| PgWireError::UnknownTypeId(*oid)))
+            .map(|oid| match oid {
+                // unspecified, to be inferred by server
+                0 => Ok(Type::UNKNOWN),
+                oid => Type::from_oid(*oid).ok_or(PgWireError::UnknownTypeId(*oid)),
+            })
             .collect::<PgWireResult<Vec<Type>>>()?;
         let statement = parser.parse_sql(parse.query(), &types)?;
         Ok(StoredStatement {
@@ -35,12 +51,28 @@ impl<S> StoredStatement<S> {
                 .unwrap_or_else(|| DEFAULT_NAME.to_owned()),
             statement,
             parameter_types: types,
+            transaction_control: TransactionControl::detect(parse.query())
+                .first()
+                .copied()
+                .flatten(),
+            isolation_setting: IsolationSetting::detect(parse.query())
+                .first()
+                .copied()
+                .flatten(),
+            result_len: None,
         })
     }
 }
 
 /// Trait for sql parser. The parser transforms string query into its statement
 /// type.
+///
+/// `parse_sql` is called once on `Parse`, and the statement is shared by all
+/// portals bound from it, see `Portal::statement_plan`. So the statement can
+/// be a prepared plan rather than a syntax tree, to plan a query executed with
+/// different parameters only once. It's cloned when parameter types are
+/// inferred on `Describe`, an `Arc` of the plan keeps that cheap, like
+/// `Arc<dyn Any + Send + Sync>` for a plan opaque to the handler trait.
 pub trait QueryParser {
     type Statement;
 
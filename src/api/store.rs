@@ -4,11 +4,16 @@ use std::sync::{Arc, RwLock};
 use super::portal::Portal;
 use super::stmt::StoredStatement;
 
+/// Statements and portals of a connection, by name. See
+/// `ExtendedQueryHandler::portal_store`.
 pub trait PortalStore: Send + Sync {
     type Statement;
 
     fn put_statement(&self, statement: Arc<StoredStatement<Self::Statement>>);
 
+    /// Remove a statement, as by `Close`. Portals bound from it keep their
+    /// own reference to the statement, and remain executable until they are
+    /// closed themselves.
     fn rm_statement(&self, name: &str);
 
     fn get_statement(&self, name: &str) -> Option<Arc<StoredStatement<Self::Statement>>>;
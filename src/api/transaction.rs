@@ -0,0 +1,568 @@
+//! Detection of transaction control statements in query strings.
+//!
+//! By default, the transaction status of a connection follows the command
+//! tags returned by handlers, see `TransactionStatus`. Handlers that return
+//! other tags for transaction control statements, or no tag at all, can
+//! enable `detect_transaction_control` on `SimpleQueryHandler` and
+//! `ExtendedQueryHandler` to track the status from query strings instead.
+//!
+//! With detection enabled, the isolation level set by `SET SESSION
+//! CHARACTERISTICS`, `SET TRANSACTION` and `BEGIN ISOLATION LEVEL` is tracked
+//! too, see `ClientInfo::isolation_level`. `statement_spans` gives the source
+//! of each statement detected.
+
+use std::fmt;
+use std::ops::Range;
+
+use super::TransactionStatus;
+
+/// A statement that starts or ends a transaction block, or a savepoint in it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionControl {
+    /// `BEGIN` or `START TRANSACTION`
+    Begin,
+    /// `COMMIT` or `END`, starting a new transaction block with `AND CHAIN`
+    Commit { chain: bool },
+    /// `ROLLBACK` or `ABORT`, starting a new transaction block with
+    /// `AND CHAIN`
+    Rollback { chain: bool },
+    /// `SAVEPOINT name`
+    Savepoint,
+    /// `RELEASE [SAVEPOINT] name`
+    Release,
+    /// `ROLLBACK TO [SAVEPOINT] name`
+    RollbackToSavepoint,
+    /// `PREPARE TRANSACTION 'id'`
+    PrepareTransaction,
+}
+
+impl TransactionControl {
+    /// Detect transaction control of each statement of `query`.
+    ///
+    /// Statements are split by `;`. String literals, quoted identifiers,
+    /// dollar-quoted strings and comments are skipped, so keywords or `;` in
+    /// them are ignored. Empty statements are dropped, like postgres does, so
+    /// that the result matches responses of the query by position. `None` is
+    /// returned for statements that are not transaction control.
+    pub fn detect(query: &str) -> Vec<Option<TransactionControl>> {
+        split_statements(query)
+            .into_iter()
+            .map(|statement| classify(&statement.words))
+            .collect()
+    }
+
+    /// Transaction status after this statement completed in `status`
+    pub fn apply(self, status: TransactionStatus) -> TransactionStatus {
+        match self {
+            TransactionControl::Begin if status == TransactionStatus::Idle => {
+                TransactionStatus::Transaction
+            }
+            TransactionControl::Commit { chain } | TransactionControl::Rollback { chain } => {
+                if chain && status != TransactionStatus::Idle {
+                    TransactionStatus::Transaction
+                } else {
+                    TransactionStatus::Idle
+                }
+            }
+            TransactionControl::RollbackToSavepoint if status == TransactionStatus::Error => {
+                TransactionStatus::Transaction
+            }
+            TransactionControl::PrepareTransaction => TransactionStatus::Idle,
+            _ => status,
+        }
+    }
+}
+
+/// Transaction isolation level, as values of `transaction_isolation`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IsolationLevel {
+    ReadUncommitted,
+    #[default]
+    ReadCommitted,
+    RepeatableRead,
+    Serializable,
+}
+
+impl IsolationLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IsolationLevel::ReadUncommitted => "read uncommitted",
+            IsolationLevel::ReadCommitted => "read committed",
+            IsolationLevel::RepeatableRead => "repeatable read",
+            IsolationLevel::Serializable => "serializable",
+        }
+    }
+
+    /// Parse the level following `ISOLATION LEVEL` in uppercase `words`
+    fn parse(words: &[&str]) -> Option<IsolationLevel> {
+        match words {
+            ["SERIALIZABLE", ..] => Some(IsolationLevel::Serializable),
+            ["REPEATABLE", "READ", ..] => Some(IsolationLevel::RepeatableRead),
+            ["READ", "COMMITTED", ..] => Some(IsolationLevel::ReadCommitted),
+            ["READ", "UNCOMMITTED", ..] => Some(IsolationLevel::ReadUncommitted),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for IsolationLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A statement that sets the isolation level
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsolationSetting {
+    /// `SET SESSION CHARACTERISTICS AS TRANSACTION ISOLATION LEVEL ..`, the
+    /// default of later transactions
+    Session(IsolationLevel),
+    /// `SET TRANSACTION ISOLATION LEVEL ..`, or `BEGIN` and `START
+    /// TRANSACTION` with an isolation level, for the current transaction
+    /// block only
+    Transaction(IsolationLevel),
+}
+
+impl IsolationSetting {
+    /// Detect isolation setting of each statement of `query`, matching
+    /// `TransactionControl::detect` by position
+    pub fn detect(query: &str) -> Vec<Option<IsolationSetting>> {
+        split_statements(query)
+            .into_iter()
+            .map(|statement| {
+                let words = statement
+                    .words
+                    .iter()
+                    .map(String::as_str)
+                    .collect::<Vec<&str>>();
+                let level = words
+                    .windows(2)
+                    .position(|pair| pair == ["ISOLATION", "LEVEL"])
+                    .and_then(|idx| IsolationLevel::parse(&words[idx + 2..]))?;
+                match words.as_slice() {
+                    ["SET", "SESSION", "CHARACTERISTICS", ..] => {
+                        Some(IsolationSetting::Session(level))
+                    }
+                    ["SET", "TRANSACTION", ..] | ["BEGIN", ..] | ["START", "TRANSACTION", ..] => {
+                        Some(IsolationSetting::Transaction(level))
+                    }
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Isolation levels of a connection, kept in connection `Extensions`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TransactionIsolation {
+    session: IsolationLevel,
+    transaction: Option<IsolationLevel>,
+}
+
+impl TransactionIsolation {
+    /// Default level of new transactions, as `default_transaction_isolation`
+    pub fn session(&self) -> IsolationLevel {
+        self.session
+    }
+
+    /// Level of the current transaction block, or the session level outside
+    /// of one
+    pub fn current(&self) -> IsolationLevel {
+        self.transaction.unwrap_or(self.session)
+    }
+
+    /// Update levels after a statement with `setting` changed the
+    /// transaction status from `before` to `after`
+    pub(crate) fn apply(
+        &mut self,
+        setting: Option<IsolationSetting>,
+        before: TransactionStatus,
+        after: TransactionStatus,
+    ) {
+        if after == TransactionStatus::Idle {
+            self.transaction = None;
+        } else if before == TransactionStatus::Idle {
+            self.transaction = Some(self.session);
+        }
+        match setting {
+            Some(IsolationSetting::Session(level)) => self.session = level,
+            // postgres ignores `SET TRANSACTION` outside of transaction blocks
+            Some(IsolationSetting::Transaction(level)) if after != TransactionStatus::Idle => {
+                self.transaction = Some(level)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Byte range of each statement of `query`, split like
+/// `TransactionControl::detect`, so they match responses of the query by
+/// position.
+///
+/// A statement spans from the end of the previous `;` to its own `;`,
+/// excluded, so its whitespaces and comments are kept as sent by client.
+/// For example, `SELECT 1; -- one\nSELECT 2` gives `SELECT 1` and
+/// ` -- one\nSELECT 2`.
+pub fn statement_spans(query: &str) -> Vec<Range<usize>> {
+    split_statements(query)
+        .into_iter()
+        .map(|statement| statement.span)
+        .collect()
+}
+
+struct Statement {
+    /// byte range in query
+    span: Range<usize>,
+    /// leading keywords in uppercase
+    words: Vec<String>,
+}
+
+/// Split query into statements. Words after the first quoted token are not
+/// collected, as keywords of transaction control come before any of them.
+fn split_statements(query: &str) -> Vec<Statement> {
+    let chars = query.chars().collect::<Vec<char>>();
+    // byte offset of each char, and of the end of query
+    let offsets = query
+        .char_indices()
+        .map(|(offset, _)| offset)
+        .chain(std::iter::once(query.len()))
+        .collect::<Vec<usize>>();
+    let mut statements = Vec::new();
+    let mut words = Vec::new();
+    let mut start = 0;
+    let mut non_empty = false;
+    let mut quoted = false;
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        match c {
+            ';' => {
+                let words = std::mem::take(&mut words);
+                if non_empty {
+                    statements.push(Statement {
+                        span: offsets[start]..offsets[i],
+                        words,
+                    });
+                }
+                non_empty = false;
+                quoted = false;
+                i += 1;
+                start = i;
+            }
+            '-' if next == Some('-') => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+            }
+            '/' if next == Some('*') => {
+                // block comments nest in postgres
+                let mut depth = 0;
+                while i < chars.len() {
+                    if chars[i] == '/' && chars.get(i + 1) == Some(&'*') {
+                        depth += 1;
+                        i += 2;
+                    } else if chars[i] == '*' && chars.get(i + 1) == Some(&'/') {
+                        depth -= 1;
+                        i += 2;
+                        if depth == 0 {
+                            break;
+                        }
+                    } else {
+                        i += 1;
+                    }
+                }
+            }
+            '\'' | '"' => {
+                i = skip_quoted(&chars, i, c, false);
+                non_empty = true;
+                quoted = true;
+            }
+            '$' => {
+                if let Some(tag) = dollar_tag(&chars, i) {
+                    i += tag.len();
+                    while i < chars.len() && !chars[i..].starts_with(&tag) {
+                        i += 1;
+                    }
+                    i = (i + tag.len()).min(chars.len());
+                    quoted = true;
+                } else {
+                    i += 1;
+                }
+                non_empty = true;
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                let start = i;
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                // escape string constant, like E'it\'s'
+                if i - start == 1 && (c == 'e' || c == 'E') && chars.get(i) == Some(&'\'') {
+                    i = skip_quoted(&chars, i, '\'', true);
+                    quoted = true;
+                } else if !quoted {
+                    words.push(chars[start..i].iter().collect::<String>().to_uppercase());
+                }
+                non_empty = true;
+            }
+            c => {
+                if !c.is_whitespace() {
+                    non_empty = true;
+                }
+                i += 1;
+            }
+        }
+    }
+    if non_empty {
+        statements.push(Statement {
+            span: offsets[start]..query.len(),
+            words,
+        });
+    }
+    statements
+}
+
+/// Skip a quoted token starting at `start`, returning the index after its
+/// closing quote. A doubled quote is an escaped quote.
+fn skip_quoted(chars: &[char], start: usize, quote: char, backslash_escapes: bool) -> usize {
+    let mut i = start + 1;
+    while i < chars.len() {
+        if backslash_escapes && chars[i] == '\\' {
+            i += 2;
+        } else if chars[i] == quote {
+            if chars.get(i + 1) == Some(&quote) {
+                i += 2;
+            } else {
+                return i + 1;
+            }
+        } else {
+            i += 1;
+        }
+    }
+    chars.len()
+}
+
+/// Get the opening tag of a dollar-quoted string at `start`, like `$$` or
+/// `$body$`. Positional parameters, like `$1`, are not tags.
+fn dollar_tag(chars: &[char], start: usize) -> Option<Vec<char>> {
+    let mut i = start + 1;
+    while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+        if i == start + 1 && chars[i].is_ascii_digit() {
+            return None;
+        }
+        i += 1;
+    }
+    if chars.get(i) == Some(&'$') {
+        Some(chars[start..=i].to_vec())
+    } else {
+        None
+    }
+}
+
+fn classify(words: &[String]) -> Option<TransactionControl> {
+    let words = words.iter().map(String::as_str).collect::<Vec<&str>>();
+    // optional noise words of `COMMIT WORK`, `ROLLBACK TRANSACTION` etc.
+    let rest = match words.get(1) {
+        Some(&"WORK") | Some(&"TRANSACTION") => &words[2..],
+        _ => words.get(1..).unwrap_or_default(),
+    };
+    let chain = rest.starts_with(&["AND", "CHAIN"]);
+
+    match words.first().copied()? {
+        "BEGIN" => Some(TransactionControl::Begin),
+        "START" if words.get(1) == Some(&"TRANSACTION") => Some(TransactionControl::Begin),
+        "COMMIT" | "END" if rest.first() != Some(&"PREPARED") => {
+            Some(TransactionControl::Commit { chain })
+        }
+        "ROLLBACK" | "ABORT" => match rest.first() {
+            Some(&"PREPARED") => None,
+            Some(&"TO") => Some(TransactionControl::RollbackToSavepoint),
+            _ => Some(TransactionControl::Rollback { chain }),
+        },
+        "SAVEPOINT" => Some(TransactionControl::Savepoint),
+        "RELEASE" => Some(TransactionControl::Release),
+        "PREPARE" if words.get(1) == Some(&"TRANSACTION") => {
+            Some(TransactionControl::PrepareTransaction)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_detect_transaction_control() {
+        for (query, expected) in [
+            ("BEGIN", vec![Some(TransactionControl::Begin)]),
+            (
+                "start transaction isolation level serializable",
+                vec![Some(TransactionControl::Begin)],
+            ),
+            (
+                "commit work",
+                vec![Some(TransactionControl::Commit { chain: false })],
+            ),
+            (
+                "END AND CHAIN",
+                vec![Some(TransactionControl::Commit { chain: true })],
+            ),
+            (
+                "ROLLBACK AND NO CHAIN",
+                vec![Some(TransactionControl::Rollback { chain: false })],
+            ),
+            (
+                "rollback transaction to savepoint sp1",
+                vec![Some(TransactionControl::RollbackToSavepoint)],
+            ),
+            ("ROLLBACK PREPARED 'tx1'", vec![None]),
+            ("COMMIT PREPARED 'tx1'", vec![None]),
+            (
+                "PREPARE TRANSACTION 'tx1'",
+                vec![Some(TransactionControl::PrepareTransaction)],
+            ),
+            ("PREPARE q AS SELECT 1", vec![None]),
+            (
+                "SAVEPOINT \"commit\"; RELEASE SAVEPOINT \"commit\"",
+                vec![
+                    Some(TransactionControl::Savepoint),
+                    Some(TransactionControl::Release),
+                ],
+            ),
+            (
+                "  ; BEGIN;; SELECT 'a;b', $1 ; ;",
+                vec![Some(TransactionControl::Begin), None],
+            ),
+            // keywords in strings and comments are ignored
+            ("SELECT 'COMMIT'", vec![None]),
+            (
+                "-- ROLLBACK;\nBEGIN /* COMMIT; /* nested */ ; */",
+                vec![Some(TransactionControl::Begin)],
+            ),
+            (
+                "SELECT E'it\\'s; ROLLBACK', $tag$ ; COMMIT $tag$; commit",
+                vec![None, Some(TransactionControl::Commit { chain: false })],
+            ),
+            (
+                "DO $$ BEGIN COMMIT; END $$; SELECT \"rollback\"",
+                vec![None, None],
+            ),
+            ("", vec![]),
+            ("/* BEGIN */", vec![]),
+        ] {
+            assert_eq!(expected, TransactionControl::detect(query), "{query}");
+        }
+    }
+
+    #[test]
+    fn test_statement_spans() {
+        for (query, expected) in [
+            ("SELECT 1", vec!["SELECT 1"]),
+            (
+                "  BEGIN;; SELECT 'a;b' ; ; -- trailing",
+                vec!["  BEGIN", " SELECT 'a;b' "],
+            ),
+            (
+                "SELECT 1; -- one\nSELECT 'é' /* ; */ \n",
+                vec!["SELECT 1", " -- one\nSELECT 'é' /* ; */ \n"],
+            ),
+            ("DO $$ ; $$;", vec!["DO $$ ; $$"]),
+            ("", vec![]),
+        ] {
+            let spans = statement_spans(query)
+                .into_iter()
+                .map(|span| &query[span])
+                .collect::<Vec<&str>>();
+            assert_eq!(expected, spans, "{query}");
+        }
+    }
+
+    #[test]
+    fn test_isolation_level() {
+        use TransactionStatus::*;
+
+        assert_eq!(
+            vec![
+                Some(IsolationSetting::Session(IsolationLevel::Serializable)),
+                Some(IsolationSetting::Transaction(
+                    IsolationLevel::RepeatableRead
+                )),
+                Some(IsolationSetting::Transaction(
+                    IsolationLevel::ReadUncommitted
+                )),
+                None,
+                None,
+            ],
+            IsolationSetting::detect(
+                "SET SESSION CHARACTERISTICS AS TRANSACTION ISOLATION LEVEL SERIALIZABLE; \
+                 begin read only, isolation level repeatable read; \
+                 SET TRANSACTION ISOLATION LEVEL READ UNCOMMITTED; \
+                 BEGIN; SELECT 'ISOLATION LEVEL SERIALIZABLE'"
+            )
+        );
+
+        let mut isolation = TransactionIsolation::default();
+        assert_eq!(IsolationLevel::ReadCommitted, isolation.current());
+        // no effect outside of transaction block
+        isolation.apply(
+            Some(IsolationSetting::Transaction(IsolationLevel::Serializable)),
+            Idle,
+            Idle,
+        );
+        assert_eq!(IsolationLevel::ReadCommitted, isolation.current());
+
+        isolation.apply(
+            Some(IsolationSetting::Session(IsolationLevel::RepeatableRead)),
+            Idle,
+            Idle,
+        );
+        isolation.apply(None, Idle, Transaction);
+        assert_eq!(IsolationLevel::RepeatableRead, isolation.current());
+        isolation.apply(
+            Some(IsolationSetting::Transaction(IsolationLevel::Serializable)),
+            Transaction,
+            Transaction,
+        );
+        // the session level does not change the current transaction
+        isolation.apply(
+            Some(IsolationSetting::Session(IsolationLevel::ReadCommitted)),
+            Transaction,
+            Transaction,
+        );
+        assert_eq!(IsolationLevel::Serializable, isolation.current());
+        isolation.apply(None, Error, Idle);
+        assert_eq!("read committed", isolation.current().to_string());
+    }
+
+    #[test]
+    fn test_apply_transaction_control() {
+        use TransactionStatus::*;
+
+        for (control, status, expected) in [
+            (TransactionControl::Begin, Idle, Transaction),
+            (TransactionControl::Begin, Error, Error),
+            (TransactionControl::Commit { chain: false }, Error, Idle),
+            (
+                TransactionControl::Commit { chain: true },
+                Transaction,
+                Transaction,
+            ),
+            (TransactionControl::Rollback { chain: false }, Error, Idle),
+            (
+                TransactionControl::Rollback { chain: true },
+                Error,
+                Transaction,
+            ),
+            (TransactionControl::Rollback { chain: true }, Idle, Idle),
+            (TransactionControl::Savepoint, Transaction, Transaction),
+            (TransactionControl::Release, Error, Error),
+            (TransactionControl::RollbackToSavepoint, Error, Transaction),
+            (TransactionControl::PrepareTransaction, Transaction, Idle),
+        ] {
+            assert_eq!(expected, control.apply(status), "{control:?} in {status:?}");
+        }
+    }
+}
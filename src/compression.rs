@@ -0,0 +1,363 @@
+//! Compression of the byte stream of a connection, negotiated with startup
+//! option `_pq_.compression`.
+//!
+//! Postgres has no standard wire compression, so this is only useful to
+//! clients implementing the same scheme:
+//!
+//! * The client lists algorithms it supports in startup parameter
+//!   `_pq_.compression`, like `lz4,zstd`.
+//! * When `compression` of `ServerOptions` is empty, the option is reported as
+//!   unrecognized in `NegotiateProtocolVersion`, and the connection is not
+//!   compressed. This is the default.
+//! * Otherwise the server picks the first algorithm of the list it supports,
+//!   and reports it in `ParameterStatus` `_pq_.compression` before
+//!   authentication, `none` when there is no common algorithm.
+//! * All bytes after the `ReadyForQuery` ending startup are then sent in
+//!   compressed blocks, by both sides.
+//!
+//! A block is its length as `Int32` including itself, the length of the
+//! uncompressed data as `Int32`, and the compressed data of one or more whole
+//! messages. The server compresses all messages written between two flushes
+//! into one block, and decompresses blocks from client one at a time, when
+//! it needs more bytes to decode a message.
+
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+#[cfg(feature = "tokio")]
+use std::{
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::error::{PgWireError, PgWireResult};
+use crate::messages::codec::MAX_MESSAGE_LENGTH;
+
+/// Startup option listing compression algorithms supported by client
+pub const STARTUP_OPTION_COMPRESSION: &str = "_pq_.compression";
+
+/// Max size of uncompressed data of a block, the max length of a message.
+/// Larger blocks are rejected before they are decompressed.
+pub const MAX_BLOCK_SIZE: usize = MAX_MESSAGE_LENGTH;
+
+/// An algorithm compressing blocks of the byte stream
+pub trait CompressionAlgorithm: fmt::Debug + Send + Sync {
+    /// Name of the algorithm in `_pq_.compression`, like `lz4`
+    fn name(&self) -> &str;
+
+    fn compress(&self, input: &[u8]) -> io::Result<Vec<u8>>;
+
+    /// Decompress `input` of a block with `raw_len` bytes of uncompressed
+    /// data
+    fn decompress(&self, input: &[u8], raw_len: usize) -> io::Result<Vec<u8>>;
+}
+
+/// Pick the first algorithm of comma separated `requested` that is
+/// supported
+pub(crate) fn select_algorithm(
+    supported: &[Arc<dyn CompressionAlgorithm>],
+    requested: &str,
+) -> Option<Arc<dyn CompressionAlgorithm>> {
+    requested.split(',').map(str::trim).find_map(|name| {
+        supported
+            .iter()
+            .find(|algorithm| algorithm.name() == name)
+            .cloned()
+    })
+}
+
+/// Compress `raw` into a block appended to `out`
+pub(crate) fn write_block(
+    algorithm: &dyn CompressionAlgorithm,
+    raw: &[u8],
+    out: &mut BytesMut,
+) -> io::Result<()> {
+    let compressed = algorithm.compress(raw)?;
+    let len = i32::try_from(8 + compressed.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "block too large"))?;
+    out.reserve(8 + compressed.len());
+    out.put_i32(len);
+    out.put_i32(raw.len() as i32);
+    out.put_slice(&compressed);
+    Ok(())
+}
+
+/// Decompress the first block of `src` into `out`. Returns `false` without
+/// consuming `src` when the block is not complete.
+pub(crate) fn read_block(
+    algorithm: &dyn CompressionAlgorithm,
+    src: &mut BytesMut,
+    out: &mut BytesMut,
+) -> PgWireResult<bool> {
+    if src.len() < 8 {
+        return Ok(false);
+    }
+    let len = (&src[0..4]).get_i32();
+    let raw_len = (&src[4..8]).get_i32();
+    if len < 8 {
+        return Err(PgWireError::InvalidMessageLength(len));
+    }
+    if raw_len < 0 || raw_len as usize > MAX_BLOCK_SIZE {
+        return Err(PgWireError::InvalidCompressedData(format!(
+            "invalid uncompressed length {raw_len}"
+        )));
+    }
+    if src.len() < len as usize {
+        return Ok(false);
+    }
+
+    let block = src.split_to(len as usize);
+    let raw = algorithm
+        .decompress(&block[8..], raw_len as usize)
+        .map_err(|e| PgWireError::InvalidCompressedData(e.to_string()))?;
+    if raw.len() != raw_len as usize {
+        return Err(PgWireError::InvalidCompressedData(format!(
+            "expected {raw_len} bytes, decompressed {}",
+            raw.len()
+        )));
+    }
+    out.extend_from_slice(&raw);
+    Ok(true)
+}
+
+/// Stream compressing the bytes written between two flushes into one block,
+/// once compression is enabled. Bytes read are passed through, they are
+/// decompressed by the codec.
+#[cfg(feature = "tokio")]
+#[derive(Debug)]
+pub(crate) struct CompressedWriter<S> {
+    inner: S,
+    algorithm: Option<Arc<dyn CompressionAlgorithm>>,
+    /// uncompressed bytes written since the last block
+    pending: BytesMut,
+    /// compressed blocks not yet written to `inner`
+    blocks: BytesMut,
+}
+
+#[cfg(feature = "tokio")]
+impl<S> CompressedWriter<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    pub(crate) fn new(inner: S) -> CompressedWriter<S> {
+        CompressedWriter {
+            inner,
+            algorithm: None,
+            pending: BytesMut::new(),
+            blocks: BytesMut::new(),
+        }
+    }
+
+    /// Compress bytes written from now on. Bytes written before must be
+    /// flushed.
+    pub(crate) fn enable_compression(&mut self, algorithm: Arc<dyn CompressionAlgorithm>) {
+        debug_assert!(self.pending.is_empty() && self.blocks.is_empty());
+        self.algorithm = Some(algorithm);
+    }
+
+    fn finish_block(&mut self) -> io::Result<()> {
+        if let Some(ref algorithm) = self.algorithm {
+            if !self.pending.is_empty() {
+                write_block(algorithm.as_ref(), &self.pending, &mut self.blocks)?;
+                self.pending.clear();
+            }
+        }
+        Ok(())
+    }
+
+    fn poll_write_blocks(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.blocks.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.blocks))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.blocks.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<S> AsyncRead for CompressedWriter<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<S> AsyncWrite for CompressedWriter<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.algorithm.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        if this.pending.len() >= MAX_BLOCK_SIZE {
+            this.finish_block()?;
+            ready!(this.poll_write_blocks(cx))?;
+        }
+        let n = buf.len().min(MAX_BLOCK_SIZE - this.pending.len());
+        this.pending.extend_from_slice(&buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.finish_block()?;
+        ready!(this.poll_write_blocks(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// lz4 block compression, with feature `compression-lz4`
+#[cfg(feature = "compression-lz4")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Lz4Compression;
+
+#[cfg(feature = "compression-lz4")]
+impl CompressionAlgorithm for Lz4Compression {
+    fn name(&self) -> &str {
+        "lz4"
+    }
+
+    fn compress(&self, input: &[u8]) -> io::Result<Vec<u8>> {
+        lz4::block::compress(input, None, false)
+    }
+
+    fn decompress(&self, input: &[u8], raw_len: usize) -> io::Result<Vec<u8>> {
+        lz4::block::decompress(input, Some(raw_len as i32))
+    }
+}
+
+/// Test algorithm inverting bits, so that uncompressed bytes are not mistaken
+/// for compressed ones
+#[cfg(test)]
+#[derive(Debug)]
+pub(crate) struct InvertCompression;
+
+#[cfg(test)]
+impl CompressionAlgorithm for InvertCompression {
+    fn name(&self) -> &str {
+        "invert"
+    }
+
+    fn compress(&self, input: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(input.iter().map(|b| !b).collect())
+    }
+
+    fn decompress(&self, input: &[u8], _raw_len: usize) -> io::Result<Vec<u8>> {
+        self.compress(input)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_compressed_blocks() {
+        let supported: Vec<Arc<dyn CompressionAlgorithm>> = vec![Arc::new(InvertCompression)];
+        assert!(select_algorithm(&supported, "zstd").is_none());
+        let algorithm = select_algorithm(&supported, "zstd, invert").unwrap();
+
+        let mut stream = BytesMut::new();
+        write_block(algorithm.as_ref(), b"hello", &mut stream).unwrap();
+        write_block(algorithm.as_ref(), b" world", &mut stream).unwrap();
+        assert_eq!(8 + 5 + 8 + 6, stream.len());
+
+        // incomplete blocks are left in buffer
+        let mut partial = BytesMut::from(&stream[..12]);
+        let mut raw = BytesMut::new();
+        assert!(!read_block(algorithm.as_ref(), &mut partial, &mut raw).unwrap());
+        assert_eq!(12, partial.len());
+
+        while read_block(algorithm.as_ref(), &mut stream, &mut raw).unwrap() {}
+        assert_eq!(&b"hello world"[..], &raw[..]);
+        assert!(stream.is_empty());
+
+        let mut invalid = BytesMut::new();
+        invalid.put_i32(8);
+        invalid.put_i32(-1);
+        assert!(matches!(
+            read_block(algorithm.as_ref(), &mut invalid, &mut raw),
+            Err(PgWireError::InvalidCompressedData(_))
+        ));
+
+        // a small block can't claim more uncompressed data than a message
+        let mut oversized = BytesMut::new();
+        oversized.put_i32(16);
+        oversized.put_i32(MAX_BLOCK_SIZE as i32 + 1);
+        oversized.put_slice(&[0; 8]);
+        assert!(matches!(
+            read_block(algorithm.as_ref(), &mut oversized, &mut raw),
+            Err(PgWireError::InvalidCompressedData(_))
+        ));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_compressed_writer() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut writer = CompressedWriter::new(server);
+        let mut client = client;
+
+        // passed through until enabled
+        writer.write_all(b"N").await.unwrap();
+        writer.flush().await.unwrap();
+        writer.enable_compression(Arc::new(InvertCompression));
+        // bytes written between flushes are in one block
+        writer.write_all(b"hello").await.unwrap();
+        writer.write_all(b" world").await.unwrap();
+        writer.flush().await.unwrap();
+        writer.write_all(b"!").await.unwrap();
+        writer.shutdown().await.unwrap();
+
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(b'N', received[0]);
+        let mut stream = BytesMut::from(&received[1..]);
+        assert_eq!(8 + 11 + 8 + 1, stream.len());
+        let mut raw = BytesMut::new();
+        assert!(read_block(&InvertCompression, &mut stream, &mut raw).unwrap());
+        assert_eq!(&b"hello world"[..], &raw[..]);
+        assert!(read_block(&InvertCompression, &mut stream, &mut raw).unwrap());
+        assert_eq!(&b"hello world!"[..], &raw[..]);
+    }
+
+    #[cfg(feature = "compression-lz4")]
+    #[test]
+    fn test_lz4_compression() {
+        let raw = b"SELECT 1; SELECT 1; SELECT 1; SELECT 1;".repeat(10);
+        let mut stream = BytesMut::new();
+        write_block(&Lz4Compression, &raw, &mut stream).unwrap();
+        assert!(stream.len() < raw.len());
+
+        let mut decompressed = BytesMut::new();
+        assert!(read_block(&Lz4Compression, &mut stream, &mut decompressed).unwrap());
+        assert_eq!(&raw[..], &decompressed[..]);
+    }
+}
@@ -1,16 +1,31 @@
+use std::error::Error;
+use std::fmt::{self, Write};
 use std::io::{Error as IOError, ErrorKind};
 
 use postgres_types::Oid;
 use thiserror::Error;
 
+use crate::api::results::FieldFormat;
 use crate::messages::response::{ErrorResponse, NoticeResponse};
 
+mod sqlstate;
+
+pub use sqlstate::SqlState;
+
 #[derive(Error, Debug)]
 pub enum PgWireError {
     #[error("Invalid protocol version, received {0}")]
     InvalidProtocolVersion(i32),
     #[error("Invalid message recevied, received {0}")]
     InvalidMessageType(u8),
+    #[error("Invalid message length, received {0}")]
+    InvalidMessageLength(i32),
+    #[error("Unexpected end of message")]
+    UnexpectedEndOfMessage,
+    #[error("Invalid count or length in message, received {0}")]
+    InvalidCount(i32),
+    #[error("Invalid message format, {0} bytes left after the end of message")]
+    TrailingBytesInMessage(usize),
     #[error("Invalid target type, received {0}")]
     InvalidTargetType(u8),
     #[error(transparent)]
@@ -19,6 +34,8 @@ pub enum PgWireError {
     PortalNotFound(String),
     #[error("Statement not found for name: {0:?}")]
     StatementNotFound(String),
+    #[error("Statement already exists for name: {0:?}")]
+    DuplicateStatement(String),
     #[error("Unknown type: {0:?}")]
     UnknownTypeId(Oid),
     #[error("Parameter index out of bound: {0:?}")]
@@ -27,14 +44,40 @@ pub enum PgWireError {
     ParameterTypeIndexOutOfBound(usize),
     #[error("Cannot convert postgre type {0:?} to given rust type")]
     InvalidRustTypeForParameter(String),
+    #[error("Cannot encode given rust type as postgre type {0:?}")]
+    InvalidRustTypeForField(String),
     #[error("Failed to parse parameter: {0:?}")]
     FailedToParseParameter(Box<dyn std::error::Error + Send + Sync>),
+    #[error("Invalid utf-8 string: {0}")]
+    InvalidUtf8String(std::string::FromUtf8Error),
     #[error("Failed to parse scram message: {0}")]
     InvalidScramMessage(String),
+    #[error("Failed to parse SASL message: {0}")]
+    InvalidSASLMessage(String),
     #[error("Certificate algorithm is not supported")]
     UnsupportedCertificateSignatureAlgorithm,
     #[error("Username is required")]
     UserNameRequired,
+    #[error("User {0:?} does not exist")]
+    UnknownUser(String),
+    #[error("Invalid COPY data: {0}")]
+    InvalidCopyData(String),
+    #[error("Column {0} is not encoded in {1:?} format requested by portal")]
+    ResultFormatMismatch(usize, FieldFormat),
+    #[error("Field length mismatch, expected {0} bytes, received {1}")]
+    FieldLengthMismatch(usize, usize),
+    #[error("Timeout reading message from client")]
+    MessageReadTimeout,
+    #[error("Invalid compressed data: {0}")]
+    InvalidCompressedData(String),
+    #[error("Handler panicked: {0}")]
+    HandlerPanicked(String),
+    #[error("Failed to encode column {column} as type {type_oid}: {source}")]
+    EncodeError {
+        column: usize,
+        type_oid: Oid,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
 
     #[error(transparent)]
     ApiError(#[from] Box<dyn std::error::Error + 'static + Send + Sync>),
@@ -51,10 +94,47 @@ impl From<PgWireError> for IOError {
 
 pub type PgWireResult<T> = Result<T, PgWireError>;
 
+/// Severity of an error or notice message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Fatal,
+    Panic,
+    Warning,
+    Notice,
+    Debug,
+    Info,
+    Log,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Error => "ERROR",
+            Severity::Fatal => "FATAL",
+            Severity::Panic => "PANIC",
+            Severity::Warning => "WARNING",
+            Severity::Notice => "NOTICE",
+            Severity::Debug => "DEBUG",
+            Severity::Info => "INFO",
+            Severity::Log => "LOG",
+        }
+    }
+}
+
+impl From<Severity> for String {
+    fn from(severity: Severity) -> String {
+        severity.as_str().to_owned()
+    }
+}
+
+/// Max length in bytes of the detail of `ErrorInfo::internal`
+pub const MAX_INTERNAL_ERROR_DETAIL_LEN: usize = 1024;
+
 // Postgres error and notice message fields
 // This part of protocol is defined in
 // https://www.postgresql.org/docs/8.2/protocol-error-fields.html
-#[derive(new, Setters, Getters, Debug)]
+#[derive(Setters, Getters, Debug)]
 #[getset(get = "pub", set = "pub", get_mut = "pub")]
 pub struct ErrorInfo {
     // severity can be one of `ERROR`, `FATAL`, or `PANIC` (in an error
@@ -67,40 +147,99 @@ pub struct ErrorInfo {
     // readable message
     message: String,
     // optional secondary message
-    #[new(default)]
     detail: Option<String>,
     // optional suggestion for fixing the issue
-    #[new(default)]
     hint: Option<String>,
     // Position: the field value is a decimal ASCII integer, indicating an error
     // cursor position as an index into the original query string.
-    #[new(default)]
     position: Option<String>,
     // Internal position: this is defined the same as the P field, but it is
     // used when the cursor position refers to an internally generated command
     // rather than the one submitted by the client
-    #[new(default)]
     internal_position: Option<String>,
     // Internal query: the text of a failed internally-generated command.
-    #[new(default)]
     internal_query: Option<String>,
     // Where: an indication of the context in which the error occurred.
-    #[new(default)]
     where_context: Option<String>,
     // File: the file name of the source-code location where the error was
     // reported.
-    #[new(default)]
     file_name: Option<String>,
     // Line: the line number of the source-code location where the error was
     // reported.
-    #[new(default)]
     line: Option<usize>,
     // Routine: the name of the source-code routine reporting the error.
-    #[new(default)]
     routine: Option<String>,
 }
 
 impl ErrorInfo {
+    /// Create error info with severity, code and message.
+    ///
+    /// Both typed values and strings are accepted, for example
+    /// `ErrorInfo::new(Severity::Error, SqlState::UniqueViolation, msg)` or
+    /// `ErrorInfo::new("ERROR", "23505", msg)`.
+    pub fn new<S, C, M>(severity: S, code: C, message: M) -> ErrorInfo
+    where
+        S: Into<String>,
+        C: Into<String>,
+        M: Into<String>,
+    {
+        ErrorInfo {
+            severity: severity.into(),
+            code: code.into(),
+            message: message.into(),
+            detail: None,
+            hint: None,
+            position: None,
+            internal_position: None,
+            internal_query: None,
+            where_context: None,
+            file_name: None,
+            line: None,
+            routine: None,
+        }
+    }
+
+    /// Create an `ERROR` with code `XX000` for an unexpected error, like one
+    /// from the storage backend of a handler.
+    ///
+    /// The message is `error` itself, and its chain of sources is in the
+    /// detail, one `caused by: ` line each, truncated to
+    /// `MAX_INTERNAL_ERROR_DETAIL_LEN`. An `anyhow::Error` can be passed with
+    /// `err.as_ref()`.
+    pub fn internal<E: Error + ?Sized>(error: &E) -> ErrorInfo {
+        let mut info = ErrorInfo::new(Severity::Error, SqlState::InternalError, error.to_string());
+
+        let mut detail = String::new();
+        let mut source = error.source();
+        while let Some(cause) = source {
+            if detail.len() > MAX_INTERNAL_ERROR_DETAIL_LEN {
+                break;
+            }
+            if !detail.is_empty() {
+                detail.push('\n');
+            }
+            let _ = write!(detail, "caused by: {cause}");
+            source = cause.source();
+        }
+        if detail.len() > MAX_INTERNAL_ERROR_DETAIL_LEN {
+            let mut end = MAX_INTERNAL_ERROR_DETAIL_LEN - 3;
+            while !detail.is_char_boundary(end) {
+                end -= 1;
+            }
+            detail.truncate(end);
+            detail.push_str("...");
+        }
+        if !detail.is_empty() {
+            info.detail = Some(detail);
+        }
+        info
+    }
+
+    /// Get the code as `SqlState`
+    pub fn sql_state(&self) -> SqlState {
+        SqlState::from_code(&self.code)
+    }
+
     fn into_fields(self) -> Vec<(u8, String)> {
         let mut fields = Vec::with_capacity(11);
 
@@ -139,6 +278,22 @@ impl ErrorInfo {
     }
 }
 
+impl fmt::Display for ErrorInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// As the source of an `EncodeError`, like of a value out of range of the
+/// column type, its code is sent to client.
+impl Error for ErrorInfo {}
+
+impl From<Box<dyn Error + Send + Sync>> for ErrorInfo {
+    fn from(error: Box<dyn Error + Send + Sync>) -> ErrorInfo {
+        ErrorInfo::internal(error.as_ref())
+    }
+}
+
 impl From<ErrorInfo> for ErrorResponse {
     fn from(ei: ErrorInfo) -> ErrorResponse {
         ErrorResponse::new(ei.into_fields())
@@ -166,5 +321,61 @@ mod test {
         assert_eq!("28P01", error_info.code());
         assert_eq!("Password authentication failed", error_info.message());
         assert!(error_info.file_name().is_none());
+
+        let error_info = ErrorInfo::new(
+            Severity::Error,
+            SqlState::UniqueViolation,
+            "duplicate key value violates unique constraint",
+        );
+        assert_eq!("ERROR", error_info.severity());
+        assert_eq!("23505", error_info.code());
+        assert_eq!(SqlState::UniqueViolation, error_info.sql_state());
+    }
+
+    #[derive(Error, Debug)]
+    #[error("{message}")]
+    struct ChainedError {
+        message: String,
+        source: Option<Box<dyn Error + Send + Sync>>,
+    }
+
+    fn chained(messages: &[&str]) -> Box<dyn Error + Send + Sync> {
+        messages
+            .iter()
+            .rev()
+            .fold(None, |source, message| {
+                Some(Box::new(ChainedError {
+                    message: message.to_string(),
+                    source,
+                }) as Box<dyn Error + Send + Sync>)
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_internal_error_info() {
+        let error_info = ErrorInfo::internal(chained(&["query failed"]).as_ref());
+        assert_eq!("ERROR", error_info.severity());
+        assert_eq!(SqlState::InternalError, error_info.sql_state());
+        assert_eq!("query failed", error_info.message());
+        assert!(error_info.detail().is_none());
+
+        let error_info = ErrorInfo::from(chained(&[
+            "query failed",
+            "cannot read page 3",
+            "disk full",
+        ]));
+        assert_eq!("query failed", error_info.message());
+        assert_eq!(
+            Some("caused by: cannot read page 3\ncaused by: disk full"),
+            error_info.detail().as_deref()
+        );
+
+        // long chains are truncated, at a char boundary
+        let long = "é".repeat(400);
+        let error_info = ErrorInfo::from(chained(&["query failed", &long, &long]));
+        let detail = error_info.detail().as_ref().unwrap();
+        assert!(detail.len() <= MAX_INTERNAL_ERROR_DETAIL_LEN);
+        assert!(detail.ends_with("é..."));
     }
 }
@@ -13,10 +13,10 @@
 //!
 //! - Startup: client-server handshake and authentication.
 //! - Simple Query: The legacy query protocol of postgresql. Query are provided
-//! as string, and server is allowed to stream data in response.
+//!   as string, and server is allowed to stream data in response.
 //! - Extended Query: A new sub-protocol for query which has ability to cache
-//! the query on server-side and reuse it with new parameters. The response part
-//! is identical to Simple Query.
+//!   the query on server-side and reuse it with new parameters. The response part
+//!   is identical to Simple Query.
 //!
 //! Also note that Postgres Wire Protocol has no semantics about SQL, so
 //! literally you can use any query language, data formats or even natural
@@ -37,7 +37,7 @@
 //! application from any level of abstraction. They are:
 //!
 //! - Protocol layer: Just use message definitions and codecs in `messages`
-//! module.
+//!   module.
 //! - Message handler layer: Implement `on_` prefixed methods in traits:
 //!   - `StartupHandler`
 //!   - `SimpleQueryHandler`
@@ -60,10 +60,15 @@ extern crate derive_new;
 
 /// handler layer and high-level API layer.
 pub mod api;
+/// compression of connections, negotiated at startup.
+pub mod compression;
 /// error types.
 pub mod error;
 /// the protocol layer.
 pub mod messages;
+/// transports for testing servers, enabled by feature `test-util`.
+#[cfg(all(feature = "tokio", any(test, feature = "test-util")))]
+pub mod test_util;
 /// server entry-point for tokio based application.
 #[cfg(feature = "tokio")]
 pub mod tokio;
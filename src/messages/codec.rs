@@ -2,13 +2,18 @@ use std::str;
 
 use bytes::{Buf, BufMut, BytesMut};
 
-use crate::error::PgWireResult;
+use crate::error::{PgWireError, PgWireResult};
+
+/// Max length of a message, including the length integer itself. Postgres
+/// rejects messages of 1GiB or larger.
+pub(crate) const MAX_MESSAGE_LENGTH: usize = 0x3fff_ffff;
 
 /// Get null-terminated string, returns None when empty cstring read.
 ///
 /// Note that this implementation will also advance cursor by 1 after reading
 /// empty cstring. This behaviour works for how postgres wire protocol handling
-/// key-value pairs, which is ended by a single `\0`
+/// key-value pairs, which is ended by a single `\0`. A string not terminated
+/// before the end of buffer takes the rest of buffer.
 pub(crate) fn get_cstring(buf: &mut BytesMut) -> Option<String> {
     let mut i = 0;
 
@@ -19,7 +24,7 @@ pub(crate) fn get_cstring(buf: &mut BytesMut) -> Option<String> {
 
     // i+1: include the '\0'
     // move cursor to the end of cstring
-    let string_buf = buf.split_to(i + 1);
+    let string_buf = buf.split_to((i + 1).min(buf.remaining()));
 
     if i == 0 {
         None
@@ -28,6 +33,60 @@ pub(crate) fn get_cstring(buf: &mut BytesMut) -> Option<String> {
     }
 }
 
+/// Get null-terminated bytes without any conversion, excluding the `\0`.
+///
+/// Unlike `get_cstring`, the bytes are not decoded as utf-8, so they are
+/// always identical to bytes from the wire. Returns error when the bytes are
+/// not terminated.
+pub(crate) fn get_cstring_exact(buf: &mut BytesMut) -> PgWireResult<BytesMut> {
+    let mut i = 0;
+
+    while i < buf.remaining() && buf[i] != b'\0' {
+        i += 1;
+    }
+    ensure_remaining(buf, i + 1)?;
+
+    let mut string_buf = buf.split_to(i + 1);
+    string_buf.truncate(i);
+    Ok(string_buf)
+}
+
+/// Check the message body has at least `len` bytes left, before reading fixed
+/// size fields
+pub(crate) fn ensure_remaining(buf: &BytesMut, len: usize) -> PgWireResult<()> {
+    if buf.remaining() >= len {
+        Ok(())
+    } else {
+        Err(PgWireError::UnexpectedEndOfMessage)
+    }
+}
+
+/// Get a count of items, like parameters, rejecting negative counts and
+/// counts of more items of `item_size` than bytes left
+pub(crate) fn get_count(buf: &mut BytesMut, item_size: usize) -> PgWireResult<usize> {
+    ensure_remaining(buf, 2)?;
+    let count = buf.get_i16();
+    if count < 0 {
+        return Err(PgWireError::InvalidCount(count as i32));
+    }
+    ensure_remaining(buf, count as usize * item_size)?;
+    Ok(count as usize)
+}
+
+/// Get a length-prefixed value, `None` for length `-1`
+pub(crate) fn get_length_prefixed(buf: &mut BytesMut) -> PgWireResult<Option<BytesMut>> {
+    ensure_remaining(buf, 4)?;
+    let len = buf.get_i32();
+    if len == -1 {
+        Ok(None)
+    } else if len < 0 {
+        Err(PgWireError::InvalidCount(len))
+    } else {
+        ensure_remaining(buf, len as usize)?;
+        Ok(Some(buf.split_to(len as usize)))
+    }
+}
+
 /// Put null-termianted string
 ///
 /// You can put empty string by giving `""` as input.
@@ -36,6 +95,28 @@ pub(crate) fn put_cstring(buf: &mut BytesMut, input: &str) {
     buf.put_u8(b'\0');
 }
 
+/// Replacement of nul bytes by `put_cstring_lossy`
+const NUL_REPLACEMENT: char = '\u{FFFD}';
+
+/// Put null-terminated string, with nul bytes of `input` replaced by U+FFFD
+/// so that they don't end the string early. For text that may come from
+/// data, like messages of errors and notices.
+pub(crate) fn put_cstring_lossy(buf: &mut BytesMut, input: &str) {
+    for (idx, part) in input.split('\0').enumerate() {
+        if idx > 0 {
+            buf.put_slice(NUL_REPLACEMENT.encode_utf8(&mut [0; 4]).as_bytes());
+        }
+        buf.put_slice(part.as_bytes());
+    }
+    buf.put_u8(b'\0');
+}
+
+/// Length of `input` put by `put_cstring_lossy`, including the terminator
+pub(crate) fn cstring_lossy_len(input: &str) -> usize {
+    let nuls = input.bytes().filter(|b| *b == b'\0').count();
+    input.len() + nuls * (NUL_REPLACEMENT.len_utf8() - 1) + 1
+}
+
 pub(crate) fn put_option_cstring(buf: &mut BytesMut, input: &Option<String>) {
     if let Some(input) = input {
         put_cstring(buf, input);
@@ -45,9 +126,9 @@ pub(crate) fn put_option_cstring(buf: &mut BytesMut, input: &Option<String>) {
 }
 
 /// Try to read message length from buf, without actually move the cursor
-pub(crate) fn get_length(buf: &BytesMut, offset: usize) -> Option<usize> {
+pub(crate) fn get_length(buf: &BytesMut, offset: usize) -> Option<i32> {
     if buf.remaining() >= 4 + offset {
-        Some((&buf[offset..4 + offset]).get_i32() as usize)
+        Some((&buf[offset..4 + offset]).get_i32())
     } else {
         None
     }
@@ -55,6 +136,10 @@ pub(crate) fn get_length(buf: &BytesMut, offset: usize) -> Option<usize> {
 
 /// Check if message_length matches and move the cursor to right position then
 /// call the `decode_fn` for the body
+///
+/// `decode_fn` gets a buffer of the message body only, so a malformed message
+/// never consumes bytes of the next. Lengths out of `4..=MAX_MESSAGE_LENGTH`
+/// are rejected without waiting for the body.
 pub(crate) fn decode_packet<T, F>(
     buf: &mut BytesMut,
     offset: usize,
@@ -64,9 +149,15 @@ where
     F: Fn(&mut BytesMut, usize) -> PgWireResult<T>,
 {
     if let Some(msg_len) = get_length(buf, offset) {
+        if msg_len < 4 || msg_len as usize > MAX_MESSAGE_LENGTH {
+            return Err(PgWireError::InvalidMessageLength(msg_len));
+        }
+        let msg_len = msg_len as usize;
+
         if buf.remaining() >= msg_len + offset {
             buf.advance(offset + 4);
-            return decode_fn(buf, msg_len).map(|r| Some(r));
+            let mut body = buf.split_to(msg_len - 4);
+            return decode_fn(&mut body, msg_len).map(|r| Some(r));
         }
     }
 
@@ -84,5 +175,5 @@ where
 // }
 
 pub(crate) fn option_string_len(s: &Option<String>) -> usize {
-    1 + s.as_ref().map(|s| s.as_bytes().len()).unwrap_or(0)
+    1 + s.as_ref().map(|s| s.len()).unwrap_or(0)
 }
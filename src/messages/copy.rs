@@ -0,0 +1,179 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use super::codec;
+use super::Message;
+use crate::error::PgWireResult;
+
+/// A chunk of `COPY` data. Frames are split at arbitrary positions, not at row
+/// boundaries.
+#[derive(Getters, Setters, MutGetters, PartialEq, Eq, Debug, new)]
+#[getset(get = "pub", set = "pub", get_mut = "pub")]
+pub struct CopyData {
+    data: Bytes,
+}
+
+pub const MESSAGE_TYPE_BYTE_COPY_DATA: u8 = b'd';
+
+impl Message for CopyData {
+    #[inline]
+    fn message_type() -> Option<u8> {
+        Some(MESSAGE_TYPE_BYTE_COPY_DATA)
+    }
+
+    #[inline]
+    fn message_length(&self) -> usize {
+        4 + self.data.len()
+    }
+
+    fn encode_body(&self, buf: &mut BytesMut) -> PgWireResult<()> {
+        buf.put_slice(self.data.as_ref());
+        Ok(())
+    }
+
+    fn decode_body(buf: &mut BytesMut, msg_len: usize) -> PgWireResult<Self> {
+        let data = buf.split_to(msg_len - 4).freeze();
+        Ok(CopyData::new(data))
+    }
+}
+
+#[derive(Getters, Setters, MutGetters, PartialEq, Eq, Debug, new)]
+#[getset(get = "pub", set = "pub", get_mut = "pub")]
+pub struct CopyDone;
+
+pub const MESSAGE_TYPE_BYTE_COPY_DONE: u8 = b'c';
+
+impl Message for CopyDone {
+    #[inline]
+    fn message_type() -> Option<u8> {
+        Some(MESSAGE_TYPE_BYTE_COPY_DONE)
+    }
+
+    #[inline]
+    fn message_length(&self) -> usize {
+        4
+    }
+
+    fn encode_body(&self, _buf: &mut BytesMut) -> PgWireResult<()> {
+        Ok(())
+    }
+
+    fn decode_body(_buf: &mut BytesMut, _msg_len: usize) -> PgWireResult<Self> {
+        Ok(CopyDone)
+    }
+}
+
+/// Sent by frontend to abort `COPY FROM STDIN`, with the cause of failure
+#[derive(Getters, Setters, MutGetters, PartialEq, Eq, Debug, new)]
+#[getset(get = "pub", set = "pub", get_mut = "pub")]
+pub struct CopyFail {
+    message: String,
+}
+
+pub const MESSAGE_TYPE_BYTE_COPY_FAIL: u8 = b'f';
+
+impl Message for CopyFail {
+    #[inline]
+    fn message_type() -> Option<u8> {
+        Some(MESSAGE_TYPE_BYTE_COPY_FAIL)
+    }
+
+    #[inline]
+    fn message_length(&self) -> usize {
+        5 + self.message.len()
+    }
+
+    fn encode_body(&self, buf: &mut BytesMut) -> PgWireResult<()> {
+        codec::put_cstring(buf, &self.message);
+        Ok(())
+    }
+
+    fn decode_body(buf: &mut BytesMut, _msg_len: usize) -> PgWireResult<Self> {
+        let message = codec::get_cstring(buf).unwrap_or_default();
+        Ok(CopyFail::new(message))
+    }
+}
+
+/// Sent by backend to start `COPY FROM STDIN`
+#[derive(Getters, Setters, MutGetters, PartialEq, Eq, Debug, new)]
+#[getset(get = "pub", set = "pub", get_mut = "pub")]
+pub struct CopyInResponse {
+    /// 0 for text, 1 for binary
+    format: i8,
+    columns: i16,
+    /// format code of each column, all 0 for text
+    column_formats: Vec<i16>,
+}
+
+pub const MESSAGE_TYPE_BYTE_COPY_IN_RESPONSE: u8 = b'G';
+
+impl Message for CopyInResponse {
+    #[inline]
+    fn message_type() -> Option<u8> {
+        Some(MESSAGE_TYPE_BYTE_COPY_IN_RESPONSE)
+    }
+
+    #[inline]
+    fn message_length(&self) -> usize {
+        4 + 1 + 2 + self.column_formats.len() * 2
+    }
+
+    fn encode_body(&self, buf: &mut BytesMut) -> PgWireResult<()> {
+        buf.put_i8(self.format);
+        buf.put_i16(self.columns);
+        for format in &self.column_formats {
+            buf.put_i16(*format);
+        }
+        Ok(())
+    }
+
+    fn decode_body(buf: &mut BytesMut, _msg_len: usize) -> PgWireResult<Self> {
+        codec::ensure_remaining(buf, 1)?;
+        let format = buf.get_i8();
+        let columns = codec::get_count(buf, 2)?;
+        let column_formats = (0..columns).map(|_| buf.get_i16()).collect();
+        Ok(CopyInResponse::new(format, columns as i16, column_formats))
+    }
+}
+
+/// Sent by backend to start `COPY TO STDOUT`, followed by `CopyData` and
+/// `CopyDone`
+#[derive(Getters, Setters, MutGetters, PartialEq, Eq, Debug, new)]
+#[getset(get = "pub", set = "pub", get_mut = "pub")]
+pub struct CopyOutResponse {
+    /// 0 for text, 1 for binary
+    format: i8,
+    columns: i16,
+    /// format code of each column, all 0 for text
+    column_formats: Vec<i16>,
+}
+
+pub const MESSAGE_TYPE_BYTE_COPY_OUT_RESPONSE: u8 = b'H';
+
+impl Message for CopyOutResponse {
+    #[inline]
+    fn message_type() -> Option<u8> {
+        Some(MESSAGE_TYPE_BYTE_COPY_OUT_RESPONSE)
+    }
+
+    #[inline]
+    fn message_length(&self) -> usize {
+        4 + 1 + 2 + self.column_formats.len() * 2
+    }
+
+    fn encode_body(&self, buf: &mut BytesMut) -> PgWireResult<()> {
+        buf.put_i8(self.format);
+        buf.put_i16(self.columns);
+        for format in &self.column_formats {
+            buf.put_i16(*format);
+        }
+        Ok(())
+    }
+
+    fn decode_body(buf: &mut BytesMut, _msg_len: usize) -> PgWireResult<Self> {
+        codec::ensure_remaining(buf, 1)?;
+        let format = buf.get_i8();
+        let columns = codec::get_count(buf, 2)?;
+        let column_formats = (0..columns).map(|_| buf.get_i16()).collect();
+        Ok(CopyOutResponse::new(format, columns as i16, column_formats))
+    }
+}
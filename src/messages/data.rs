@@ -45,7 +45,7 @@ impl Message for RowDescription {
             + self
                 .fields
                 .iter()
-                .map(|f| f.name.as_bytes().len() + 1 + 4 + 2 + 4 + 2 + 4 + 2)
+                .map(|f| f.name.len() + 1 + 4 + 2 + 4 + 2 + 4 + 2)
                 .sum::<usize>()
     }
 
@@ -128,6 +128,32 @@ impl Message for ParameterDescription {
     }
 }
 
+/// Response to describe of a statement or portal that returns no rows
+#[derive(PartialEq, Eq, Debug, Default, new, Clone)]
+pub struct NoData;
+
+pub const MESSAGE_TYPE_BYTE_NO_DATA: u8 = b'n';
+
+impl Message for NoData {
+    #[inline]
+    fn message_type() -> Option<u8> {
+        Some(MESSAGE_TYPE_BYTE_NO_DATA)
+    }
+
+    #[inline]
+    fn message_length(&self) -> usize {
+        4
+    }
+
+    fn encode_body(&self, _buf: &mut BytesMut) -> PgWireResult<()> {
+        Ok(())
+    }
+
+    fn decode_body(_buf: &mut BytesMut, _: usize) -> PgWireResult<Self> {
+        Ok(NoData)
+    }
+}
+
 /// Data structure for postgresql wire protocol `DataRow` message.
 ///
 /// Data can be represented as text or binary format as specified by format
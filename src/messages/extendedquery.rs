@@ -1,4 +1,4 @@
-use bytes::{Buf, BufMut, Bytes};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use postgres_types::Oid;
 
 use super::{codec, Message};
@@ -23,8 +23,8 @@ impl Message for Parse {
 
     fn message_length(&self) -> usize {
         4 + codec::option_string_len(&self.name) // name
-            + (1 + self.query.as_bytes().len()) // query
-            + (4 * self.type_oids.len()) // type oids
+            + (1 + self.query.len()) // query
+            + 2 + (4 * self.type_oids.len()) // type oids
     }
 
     fn encode_body(&self, buf: &mut bytes::BytesMut) -> PgWireResult<()> {
@@ -42,9 +42,9 @@ impl Message for Parse {
     fn decode_body(buf: &mut bytes::BytesMut, _: usize) -> PgWireResult<Self> {
         let name = codec::get_cstring(buf);
         let query = codec::get_cstring(buf).unwrap_or_else(|| "".to_owned());
-        let type_oid_count = buf.get_i16();
+        let type_oid_count = codec::get_count(buf, 4)?;
 
-        let mut type_oids = Vec::with_capacity(type_oid_count as usize);
+        let mut type_oids = Vec::with_capacity(type_oid_count);
         for _ in 0..type_oid_count {
             type_oids.push(buf.get_u32());
         }
@@ -118,6 +118,7 @@ impl Message for Close {
     }
 
     fn decode_body(buf: &mut bytes::BytesMut, _: usize) -> PgWireResult<Self> {
+        codec::ensure_remaining(buf, 1)?;
         let target_type = buf.get_u8();
         let name = codec::get_cstring(buf);
 
@@ -217,28 +218,22 @@ impl Message for Bind {
         let portal_name = codec::get_cstring(buf);
         let statement_name = codec::get_cstring(buf);
 
-        let parameter_format_code_len = buf.get_i16();
-        let mut parameter_format_codes = Vec::with_capacity(parameter_format_code_len as usize);
+        let parameter_format_code_len = codec::get_count(buf, 2)?;
+        let mut parameter_format_codes = Vec::with_capacity(parameter_format_code_len);
 
         for _ in 0..parameter_format_code_len {
             parameter_format_codes.push(buf.get_i16());
         }
 
-        let parameter_len = buf.get_i16();
-        let mut parameters = Vec::with_capacity(parameter_len as usize);
+        // each parameter has at least its length
+        let parameter_len = codec::get_count(buf, 4)?;
+        let mut parameters = Vec::with_capacity(parameter_len);
         for _ in 0..parameter_len {
-            let data_len = buf.get_i32();
-
-            if data_len >= 0 {
-                parameters.push(Some(buf.split_to(data_len as usize).freeze()));
-            } else {
-                parameters.push(None);
-            }
+            parameters.push(codec::get_length_prefixed(buf)?.map(BytesMut::freeze));
         }
 
-        let result_column_format_code_len = buf.get_i16();
-        let mut result_column_format_codes =
-            Vec::with_capacity(result_column_format_code_len as usize);
+        let result_column_format_code_len = codec::get_count(buf, 2)?;
+        let mut result_column_format_codes = Vec::with_capacity(result_column_format_code_len);
         for _ in 0..result_column_format_code_len {
             result_column_format_codes.push(buf.get_i16());
         }
@@ -314,6 +309,7 @@ impl Message for Describe {
     }
 
     fn decode_body(buf: &mut bytes::BytesMut, _: usize) -> PgWireResult<Self> {
+        codec::ensure_remaining(buf, 1)?;
         let target_type = buf.get_u8();
         let name = codec::get_cstring(buf);
 
@@ -349,6 +345,7 @@ impl Message for Execute {
 
     fn decode_body(buf: &mut bytes::BytesMut, _: usize) -> PgWireResult<Self> {
         let name = codec::get_cstring(buf);
+        codec::ensure_remaining(buf, 4)?;
         let max_rows = buf.get_i32();
 
         Ok(Execute { name, max_rows })
@@ -32,11 +32,16 @@ pub trait Message: Sized {
     /// Message type and length are encoded in this implementation and it calls
     /// `encode_body` for remaining parts.
     fn encode(&self, buf: &mut BytesMut) -> PgWireResult<()> {
+        let len = self.message_length();
+        // reserve space for the whole message to avoid reallocations during
+        // encoding body
+        buf.reserve(len + 1);
+
         if let Some(mt) = Self::message_type() {
             buf.put_u8(mt);
         }
 
-        buf.put_i32(self.message_length() as i32);
+        buf.put_i32(len as i32);
         self.encode_body(buf)
     }
 
@@ -54,7 +59,9 @@ pub trait Message: Sized {
     }
 }
 
-mod codec;
+pub(crate) mod codec;
+/// Copy messages
+pub mod copy;
 /// Data related messages
 pub mod data;
 /// Extended query messages, including request/response for parse, bind and etc.
@@ -72,6 +79,7 @@ pub mod terminate;
 #[derive(Debug)]
 pub enum PgWireFrontendMessage {
     Startup(startup::Startup),
+    CancelRequest(startup::CancelRequest),
     PasswordMessageFamily(startup::PasswordMessageFamily),
 
     Query(simplequery::Query),
@@ -84,6 +92,10 @@ pub enum PgWireFrontendMessage {
     Flush(extendedquery::Flush),
     Sync(extendedquery::Sync),
 
+    CopyData(copy::CopyData),
+    CopyDone(copy::CopyDone),
+    CopyFail(copy::CopyFail),
+
     Terminate(terminate::Terminate),
 }
 
@@ -91,6 +103,7 @@ impl PgWireFrontendMessage {
     pub fn encode(&self, buf: &mut BytesMut) -> PgWireResult<()> {
         match self {
             Self::Startup(msg) => msg.encode(buf),
+            Self::CancelRequest(msg) => msg.encode(buf),
             Self::PasswordMessageFamily(msg) => msg.encode(buf),
 
             Self::Query(msg) => msg.encode(buf),
@@ -103,10 +116,21 @@ impl PgWireFrontendMessage {
             Self::Flush(msg) => msg.encode(buf),
             Self::Sync(msg) => msg.encode(buf),
 
+            Self::CopyData(msg) => msg.encode(buf),
+            Self::CopyDone(msg) => msg.encode(buf),
+            Self::CopyFail(msg) => msg.encode(buf),
+
             Self::Terminate(msg) => msg.encode(buf),
         }
     }
 
+    /// Decode a message from `buf`, except `Startup` which is decoded with
+    /// `Startup::decode` before startup finished.
+    ///
+    /// Returns `Ok(None)` without consuming `buf` when more bytes are needed.
+    /// Malformed input never panics: invalid type, length or body is
+    /// returned as error, and a message never consumes bytes beyond its
+    /// length. This is the entry point of the fuzz targets in `fuzz/`.
     pub fn decode(buf: &mut BytesMut) -> PgWireResult<Option<Self>> {
         if buf.remaining() > 1 {
             let first_byte = buf[0];
@@ -141,6 +165,16 @@ impl PgWireFrontendMessage {
                     extendedquery::Sync::decode(buf).map(|v| v.map(Self::Sync))
                 }
 
+                copy::MESSAGE_TYPE_BYTE_COPY_DATA => {
+                    copy::CopyData::decode(buf).map(|v| v.map(Self::CopyData))
+                }
+                copy::MESSAGE_TYPE_BYTE_COPY_DONE => {
+                    copy::CopyDone::decode(buf).map(|v| v.map(Self::CopyDone))
+                }
+                copy::MESSAGE_TYPE_BYTE_COPY_FAIL => {
+                    copy::CopyFail::decode(buf).map(|v| v.map(Self::CopyFail))
+                }
+
                 terminate::MESSAGE_TYPE_BYTE_TERMINATE => {
                     terminate::Terminate::decode(buf).map(|v| v.map(Self::Terminate))
                 }
@@ -159,6 +193,7 @@ pub enum PgWireBackendMessage {
     Authentication(startup::Authentication),
     ParameterStatus(startup::ParameterStatus),
     BackendKeyData(startup::BackendKeyData),
+    NegotiateProtocolVersion(startup::NegotiateProtocolVersion),
 
     // extended query
     ParseComplete(extendedquery::ParseComplete),
@@ -176,7 +211,14 @@ pub enum PgWireBackendMessage {
     // data
     ParameterDescription(data::ParameterDescription),
     RowDescription(data::RowDescription),
+    NoData(data::NoData),
     DataRow(data::DataRow),
+
+    // copy
+    CopyInResponse(copy::CopyInResponse),
+    CopyOutResponse(copy::CopyOutResponse),
+    CopyData(copy::CopyData),
+    CopyDone(copy::CopyDone),
 }
 
 impl PgWireBackendMessage {
@@ -185,6 +227,7 @@ impl PgWireBackendMessage {
             Self::Authentication(msg) => msg.encode(buf),
             Self::ParameterStatus(msg) => msg.encode(buf),
             Self::BackendKeyData(msg) => msg.encode(buf),
+            Self::NegotiateProtocolVersion(msg) => msg.encode(buf),
 
             Self::ParseComplete(msg) => msg.encode(buf),
             Self::BindComplete(msg) => msg.encode(buf),
@@ -199,10 +242,18 @@ impl PgWireBackendMessage {
 
             Self::ParameterDescription(msg) => msg.encode(buf),
             Self::RowDescription(msg) => msg.encode(buf),
+            Self::NoData(msg) => msg.encode(buf),
             Self::DataRow(msg) => msg.encode(buf),
+
+            Self::CopyInResponse(msg) => msg.encode(buf),
+            Self::CopyOutResponse(msg) => msg.encode(buf),
+            Self::CopyData(msg) => msg.encode(buf),
+            Self::CopyDone(msg) => msg.encode(buf),
         }
     }
 
+    /// Decode a message from `buf`, with the same guarantees on incomplete
+    /// and malformed input as [`PgWireFrontendMessage::decode`].
     pub fn decode(buf: &mut BytesMut) -> PgWireResult<Option<Self>> {
         if buf.remaining() > 1 {
             let first_byte = buf[0];
@@ -216,6 +267,10 @@ impl PgWireBackendMessage {
                 startup::MESSAGE_TYPE_BYTE_BACKEND_KEY_DATA => {
                     startup::BackendKeyData::decode(buf).map(|v| v.map(Self::BackendKeyData))
                 }
+                startup::MESSAGE_TYPE_BYTE_NEGOTIATE_PROTOCOL_VERSION => {
+                    startup::NegotiateProtocolVersion::decode(buf)
+                        .map(|v| v.map(Self::NegotiateProtocolVersion))
+                }
 
                 extendedquery::MESSAGE_TYPE_BYTE_PARSE_COMPLETE => {
                     extendedquery::ParseComplete::decode(buf).map(|v| v.map(Self::ParseComplete))
@@ -255,9 +310,25 @@ impl PgWireBackendMessage {
                 data::MESSAGE_TYPE_BYTE_ROW_DESCRITION => {
                     data::RowDescription::decode(buf).map(|v| v.map(Self::RowDescription))
                 }
+                data::MESSAGE_TYPE_BYTE_NO_DATA => {
+                    data::NoData::decode(buf).map(|v| v.map(Self::NoData))
+                }
                 data::MESSAGE_TYPE_BYTE_DATA_ROW => {
                     data::DataRow::decode(buf).map(|v| v.map(Self::DataRow))
                 }
+
+                copy::MESSAGE_TYPE_BYTE_COPY_IN_RESPONSE => {
+                    copy::CopyInResponse::decode(buf).map(|v| v.map(Self::CopyInResponse))
+                }
+                copy::MESSAGE_TYPE_BYTE_COPY_OUT_RESPONSE => {
+                    copy::CopyOutResponse::decode(buf).map(|v| v.map(Self::CopyOutResponse))
+                }
+                copy::MESSAGE_TYPE_BYTE_COPY_DATA => {
+                    copy::CopyData::decode(buf).map(|v| v.map(Self::CopyData))
+                }
+                copy::MESSAGE_TYPE_BYTE_COPY_DONE => {
+                    copy::CopyDone::decode(buf).map(|v| v.map(Self::CopyDone))
+                }
                 _ => Err(PgWireError::InvalidMessageType(first_byte)),
             }
         } else {
@@ -268,14 +339,16 @@ impl PgWireBackendMessage {
 
 #[cfg(test)]
 mod test {
+    use super::copy::*;
     use super::data::*;
     use super::extendedquery::*;
     use super::response::*;
     use super::simplequery::*;
     use super::startup::*;
     use super::terminate::*;
-    use super::Message;
-    use bytes::{Buf, Bytes, BytesMut};
+    use super::{Message, PgWireFrontendMessage};
+    use crate::error::PgWireError;
+    use bytes::{Buf, BufMut, Bytes, BytesMut};
 
     macro_rules! roundtrip {
         ($ins:ident, $st:ty) => {
@@ -306,6 +379,7 @@ mod test {
             Authentication::Ok,
             Authentication::CleartextPassword,
             Authentication::KerberosV5,
+            Authentication::SSPI,
         ];
         for s in ss {
             roundtrip!(s, Authentication);
@@ -313,6 +387,9 @@ mod test {
 
         let md5pass = Authentication::MD5Password(vec![b'p', b's', b't', b'g']);
         roundtrip!(md5pass, Authentication);
+
+        let gss_continue = Authentication::GSSContinue(Bytes::from_static(b"token"));
+        roundtrip!(gss_continue, Authentication);
     }
 
     #[test]
@@ -327,12 +404,69 @@ mod test {
         roundtrip!(pps, ParameterStatus);
     }
 
+    #[test]
+    fn test_negotiate_protocol_version() {
+        let negotiate = NegotiateProtocolVersion::new(0, vec!["_pq_.compression".to_owned()]);
+        roundtrip!(negotiate, NegotiateProtocolVersion);
+    }
+
     #[test]
     fn test_query() {
         let query = Query::new("SELECT 1".to_owned());
         roundtrip!(query, Query);
     }
 
+    #[test]
+    fn test_query_bytes_preserved() {
+        let query = Query::new("SELECT 1 -- comment  \n\t ".to_owned());
+        roundtrip!(query, Query);
+
+        let mut buffer = BytesMut::new();
+        buffer.put_u8(b'Q');
+        buffer.put_i32(8);
+        buffer.put_slice(&[b'a', 0xff, b'b', 0]);
+        let query = Query::decode(&mut buffer).unwrap().unwrap();
+        assert_eq!("a\u{fffd}b", query.query());
+        assert_eq!(&[b'a', 0xff, b'b'], query.raw_query());
+        roundtrip!(query, Query);
+    }
+
+    #[test]
+    fn test_query_length_edge_cases() {
+        let decode = |len: i32, body: &[u8]| {
+            let mut buffer = BytesMut::new();
+            buffer.put_u8(b'Q');
+            buffer.put_i32(len);
+            buffer.put_slice(body);
+            let result = Query::decode(&mut buffer);
+            (result, buffer.remaining())
+        };
+
+        // empty query
+        let (result, remaining) = decode(5, b"\0");
+        assert_eq!("", result.unwrap().unwrap().query());
+        assert_eq!(0, remaining);
+
+        // embedded NUL
+        let (result, remaining) = decode(17, b"SELECT 1\0; x\0");
+        assert!(matches!(
+            result,
+            Err(PgWireError::TrailingBytesInMessage(4))
+        ));
+        // the whole message is consumed, so the next one can be read
+        assert_eq!(0, remaining);
+
+        // length ends before the NUL
+        let (result, remaining) = decode(8, b"SELECT 1\0");
+        assert!(matches!(result, Err(PgWireError::UnexpectedEndOfMessage)));
+        assert_eq!(5, remaining);
+
+        // length ends after the NUL, with the rest of message not arrived
+        let (result, remaining) = decode(16, b"SELECT 1\0");
+        assert!(matches!(result, Ok(None)));
+        assert_eq!(14, remaining);
+    }
+
     #[test]
     fn test_command_complete() {
         let cc = CommandComplete::new("DELETE 5".to_owned());
@@ -354,6 +488,36 @@ mod test {
         roundtrip!(error, ErrorResponse);
     }
 
+    #[test]
+    fn test_error_response_text() {
+        // lengths are in bytes of multi-byte characters
+        let message = "значение «日本語» 🐘 слишком длинное";
+        let mut error = ErrorResponse::default();
+        error.fields_mut().push((b'M', message.to_owned()));
+        let mut buffer = BytesMut::new();
+        error.encode(&mut buffer).unwrap();
+        assert_eq!(buffer.len() - 1, (&buffer[1..5]).get_i32() as usize);
+        assert_eq!(4 + 1 + message.len() + 1 + 1, buffer.len() - 1);
+        roundtrip!(error, ErrorResponse);
+
+        // nul bytes would end the field early, and shift the rest of message
+        let mut notice = NoticeResponse::default();
+        notice.fields_mut().push((b'M', "bad\0value\0".to_owned()));
+        notice.fields_mut().push((b'D', "detail".to_owned()));
+        let mut buffer = BytesMut::new();
+        notice.encode(&mut buffer).unwrap();
+        assert_eq!(buffer.len() - 1, (&buffer[1..5]).get_i32() as usize);
+        let decoded = NoticeResponse::decode(&mut buffer).unwrap().unwrap();
+        assert!(buffer.is_empty());
+        assert_eq!(
+            &vec![
+                (b'M', "bad\u{FFFD}value\u{FFFD}".to_owned()),
+                (b'D', "detail".to_owned())
+            ],
+            decoded.fields()
+        );
+    }
+
     #[test]
     fn test_notice_response() {
         let mut error = NoticeResponse::default();
@@ -455,6 +619,28 @@ mod test {
         roundtrip!(sslreq, SslRequest);
     }
 
+    #[test]
+    fn test_gssencrequest() {
+        let gssencreq = GssEncRequest::new();
+        roundtrip!(gssencreq, GssEncRequest);
+        // not mistaken for each other
+        let mut buffer = BytesMut::new();
+        gssencreq.encode(&mut buffer).unwrap();
+        assert!(SslRequest::decode(&mut buffer).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_cancel_request() {
+        let cancel = CancelRequest::new(42, -7);
+        let mut buffer = BytesMut::new();
+        cancel.encode(&mut buffer).unwrap();
+        assert!(CancelRequest::is_cancel_request(&buffer[..8]));
+        assert!(CancelRequest::decode(&mut buffer.clone().split_to(12))
+            .unwrap()
+            .is_none());
+        roundtrip!(cancel, CancelRequest);
+    }
+
     #[test]
     fn test_saslresponse() {
         let saslinitialresp =
@@ -471,6 +657,12 @@ mod test {
         roundtrip!(param_desc, ParameterDescription);
     }
 
+    #[test]
+    fn test_no_data() {
+        let no_data = NoData::new();
+        roundtrip!(no_data, NoData);
+    }
+
     #[test]
     fn test_password_family() {
         let password = Password::new("tomcat".to_owned());
@@ -493,4 +685,178 @@ mod test {
         assert_eq!(buffer.remaining(), 0);
         assert_eq!(saslinitialresp, item2.into_sasl_initial_response().unwrap());
     }
+
+    #[test]
+    fn test_copy() {
+        let copy_data = CopyData::new(Bytes::from_static(b"1\ttom\n"));
+        roundtrip!(copy_data, CopyData);
+
+        let copy_done = CopyDone::new();
+        roundtrip!(copy_done, CopyDone);
+
+        let copy_fail = CopyFail::new("cancelled".to_owned());
+        roundtrip!(copy_fail, CopyFail);
+
+        let copy_in = CopyInResponse::new(0, 3, vec![0, 0, 0]);
+        roundtrip!(copy_in, CopyInResponse);
+
+        let copy_out = CopyOutResponse::new(1, 2, vec![1, 1]);
+        roundtrip!(copy_out, CopyOutResponse);
+    }
+
+    #[test]
+    fn test_decode_truncated_copy_responses() {
+        fn truncated(message: &impl Message) -> Vec<BytesMut> {
+            let mut encoded = BytesMut::new();
+            message.encode(&mut encoded).unwrap();
+            // complete messages with a shorter body
+            (0..encoded.len() - 5)
+                .map(|len| {
+                    let mut buf = BytesMut::from(&encoded[..5 + len]);
+                    buf[1..5].copy_from_slice(&(4 + len as i32).to_be_bytes());
+                    buf
+                })
+                .collect()
+        }
+
+        for mut buf in truncated(&CopyInResponse::new(0, 3, vec![0, 0, 0])) {
+            assert!(matches!(
+                CopyInResponse::decode(&mut buf),
+                Err(PgWireError::UnexpectedEndOfMessage)
+            ));
+        }
+        for mut buf in truncated(&CopyOutResponse::new(1, 2, vec![1, 1])) {
+            assert!(matches!(
+                CopyOutResponse::decode(&mut buf),
+                Err(PgWireError::UnexpectedEndOfMessage)
+            ));
+        }
+
+        let mut buf = BytesMut::new();
+        CopyOutResponse::new(0, -1, vec![])
+            .encode(&mut buf)
+            .unwrap();
+        assert!(matches!(
+            CopyOutResponse::decode(&mut buf),
+            Err(PgWireError::InvalidCount(-1))
+        ));
+    }
+
+    /// Decode all messages in buf, and check every decoded message consumes
+    /// bytes
+    fn decode_all(buf: &mut BytesMut) -> Vec<PgWireFrontendMessage> {
+        let mut messages = Vec::new();
+        loop {
+            let remaining = buf.remaining();
+            match PgWireFrontendMessage::decode(buf) {
+                Ok(Some(msg)) => {
+                    assert!(buf.remaining() < remaining);
+                    messages.push(msg);
+                }
+                Ok(None) => {
+                    assert_eq!(remaining, buf.remaining());
+                    return messages;
+                }
+                Err(_) => return messages,
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_malformed_messages() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        let messages = vec![
+            PgWireFrontendMessage::Query(Query::new("SELECT 1".to_owned())),
+            PgWireFrontendMessage::Parse(Parse::new(None, "SELECT $1".to_owned(), vec![23])),
+            PgWireFrontendMessage::Bind(Bind::new(
+                None,
+                None,
+                vec![0, 1],
+                vec![Some(Bytes::from_static(b"1")), None],
+                vec![1],
+            )),
+            PgWireFrontendMessage::Describe(Describe::new(b'S', None)),
+            PgWireFrontendMessage::Execute(Execute::new(None, 10)),
+            PgWireFrontendMessage::Close(Close::new(b'P', Some("p".to_owned()))),
+            PgWireFrontendMessage::Sync(Sync::new()),
+            PgWireFrontendMessage::CopyFail(CopyFail::new("cancelled".to_owned())),
+        ];
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for message in messages {
+            let mut encoded = BytesMut::new();
+            message.encode(&mut encoded).unwrap();
+
+            // incomplete messages need more bytes
+            for len in 0..encoded.len() {
+                let mut buf = BytesMut::from(&encoded[..len]);
+                assert!(matches!(PgWireFrontendMessage::decode(&mut buf), Ok(None)));
+                assert_eq!(len, buf.remaining());
+            }
+
+            // invalid length
+            for len in [0i32, 3, -1, i32::MAX] {
+                let mut buf = encoded.clone();
+                buf[1..5].copy_from_slice(&len.to_be_bytes());
+                assert!(PgWireFrontendMessage::decode(&mut buf).is_err());
+            }
+
+            // shorter length or corrupted body
+            for _ in 0..1000 {
+                let mut buf = encoded.clone();
+                if rng.gen_bool(0.5) || encoded.len() == 5 {
+                    let len = rng.gen_range(4..encoded.len() as i32);
+                    buf[1..5].copy_from_slice(&len.to_be_bytes());
+                } else {
+                    for _ in 0..rng.gen_range(1..4) {
+                        let idx = rng.gen_range(5..buf.len());
+                        buf[idx] = rng.gen();
+                    }
+                }
+                decode_all(&mut buf);
+            }
+        }
+
+        // random bytes
+        let types = b"pQPBCDESdcfX";
+        for _ in 0..10000 {
+            let mut buf = BytesMut::new();
+            while buf.len() < 64 {
+                buf.put_u8(types[rng.gen_range(0..types.len())]);
+                let len = rng.gen_range(4..16);
+                buf.put_i32(len);
+                for _ in 4..len {
+                    buf.put_u8(rng.gen_range(0..4));
+                }
+            }
+            decode_all(&mut buf);
+
+            let _ = Startup::decode(&mut buf);
+        }
+    }
+
+    #[test]
+    fn test_decode_truncated_password_messages() {
+        // password messages are decoded by authentication handlers, from the
+        // raw body
+        let message = SASLInitialResponse::new(
+            "SCRAM-SHA-256".to_owned(),
+            Some(Bytes::from_static(b"n,,n=,r=nonce")),
+        );
+        let mut encoded = BytesMut::new();
+        message.encode(&mut encoded).unwrap();
+        let body = encoded.split_off(5);
+
+        for len in 0..body.len() {
+            let raw = || PasswordMessageFamily::Raw(BytesMut::from(&body[..len]));
+            assert!(raw().into_sasl_initial_response().is_err(), "{len}");
+            let _ = raw().into_password();
+            let _ = raw().into_sasl_response();
+            let _ = raw().into_gss_response();
+        }
+        let raw = PasswordMessageFamily::Raw(body);
+        assert_eq!(message, raw.into_sasl_initial_response().unwrap());
+    }
 }
@@ -19,7 +19,7 @@ impl Message for CommandComplete {
     }
 
     fn message_length(&self) -> usize {
-        5 + self.tag.as_bytes().len()
+        5 + self.tag.len()
     }
 
     fn encode_body(&self, buf: &mut BytesMut) -> PgWireResult<()> {
@@ -113,15 +113,16 @@ impl Message for ErrorResponse {
         4 + self
             .fields
             .iter()
-            .map(|f| 1 + f.1.as_bytes().len() + 1)
+            .map(|f| 1 + codec::cstring_lossy_len(&f.1))
             .sum::<usize>()
             + 1
     }
 
     fn encode_body(&self, buf: &mut BytesMut) -> PgWireResult<()> {
+        // messages may contain values of data, with nul bytes
         for (code, value) in &self.fields {
             buf.put_u8(*code);
-            codec::put_cstring(buf, value);
+            codec::put_cstring_lossy(buf, value);
         }
 
         buf.put_u8(b'\0');
@@ -163,15 +164,16 @@ impl Message for NoticeResponse {
         4 + self
             .fields
             .iter()
-            .map(|f| 1 + f.1.as_bytes().len() + 1)
+            .map(|f| 1 + codec::cstring_lossy_len(&f.1))
             .sum::<usize>()
             + 1
     }
 
     fn encode_body(&self, buf: &mut BytesMut) -> PgWireResult<()> {
+        // messages may contain values of data, with nul bytes
         for (code, value) in &self.fields {
             buf.put_u8(*code);
-            codec::put_cstring(buf, value);
+            codec::put_cstring_lossy(buf, value);
         }
 
         buf.put_u8(b'\0');
@@ -1,14 +1,29 @@
-use bytes::BytesMut;
+use std::borrow::Cow;
+
+use bytes::{BufMut, Bytes, BytesMut};
 
 use super::codec;
 use super::Message;
-use crate::error::PgWireResult;
+use crate::error::{PgWireError, PgWireResult};
 
 /// A sql query sent from frontend to backend.
 #[derive(Getters, Setters, MutGetters, PartialEq, Eq, Debug, new)]
 #[getset(get = "pub", set = "pub", get_mut = "pub")]
 pub struct Query {
+    /// the query decoded as utf-8, invalid bytes replaced by `U+FFFD`
     query: String,
+    /// bytes from the wire, only kept when they are not valid utf-8
+    #[getset(skip)]
+    #[new(default)]
+    raw: Option<Bytes>,
+}
+
+impl Query {
+    /// Bytes of the query as sent by client, identical to `query()` unless
+    /// the query is not valid utf-8
+    pub fn raw_query(&self) -> &[u8] {
+        self.raw.as_deref().unwrap_or(self.query.as_bytes())
+    }
 }
 
 pub const MESSAGE_TYPE_BYTE_QUERY: u8 = b'Q';
@@ -20,18 +35,31 @@ impl Message for Query {
     }
 
     fn message_length(&self) -> usize {
-        5 + self.query.as_bytes().len()
+        5 + self.raw_query().len()
     }
 
     fn encode_body(&self, buf: &mut BytesMut) -> PgWireResult<()> {
-        codec::put_cstring(buf, &self.query);
+        buf.put_slice(self.raw_query());
+        buf.put_u8(b'\0');
 
         Ok(())
     }
 
+    /// The query is the whole body up to its terminating NUL. A NUL inside
+    /// the query, or a length not ending right after the NUL, is rejected.
     fn decode_body(buf: &mut BytesMut, _: usize) -> PgWireResult<Self> {
-        let query = codec::get_cstring(buf).unwrap_or_else(|| "".to_owned());
+        let raw = codec::get_cstring_exact(buf)?.freeze();
+        if !buf.is_empty() {
+            return Err(PgWireError::TrailingBytesInMessage(buf.len()));
+        }
 
-        Ok(Query::new(query))
+        let query = match String::from_utf8_lossy(&raw) {
+            Cow::Borrowed(query) => Query::new(query.to_owned()),
+            Cow::Owned(query) => Query {
+                query,
+                raw: Some(raw),
+            },
+        };
+        Ok(query)
     }
 }
@@ -29,8 +29,10 @@ impl Default for Startup {
 }
 
 impl Startup {
+    /// Any minor version of protocol 3 is accepted, a newer minor version is
+    /// negotiated down to 3.0 with `NegotiateProtocolVersion`.
     fn is_protocol_version_supported(version: i32) -> bool {
-        version == 196608
+        version >> 16 == 3
     }
 }
 
@@ -39,7 +41,7 @@ impl Message for Startup {
         let param_length = self
             .parameters
             .iter()
-            .map(|(k, v)| k.as_bytes().len() + v.as_bytes().len() + 2)
+            .map(|(k, v)| k.len() + v.len() + 2)
             .sum::<usize>();
         // length:4 + protocol_number:4 + param.len + nullbyte:1
         9 + param_length
@@ -71,12 +73,13 @@ impl Message for Startup {
             }
         }
 
-        codec::decode_packet(buf, 0, |buf, full_len| Self::decode_body(buf, full_len))
+        codec::decode_packet(buf, 0, Self::decode_body)
     }
 
     fn decode_body(buf: &mut BytesMut, _: usize) -> PgWireResult<Self> {
         let mut msg = Startup::default();
         // parse
+        codec::ensure_remaining(buf, 4)?;
         msg.set_protocol_number_major(buf.get_u16());
         msg.set_protocol_number_minor(buf.get_u16());
 
@@ -102,12 +105,13 @@ pub enum Authentication {
     SASLContinue(Bytes), // code 11, with authentication data
     SASLFinal(Bytes),    // code 12, with additional authentication data
 
-                         // TODO: more types
-                         // AuthenticationSCMCredential
-                         //
-                         // AuthenticationGSS
-                         // AuthenticationGSSContinue
-                         // AuthenticationSSPI
+    SSPI, // code 9
+    GSSContinue(Bytes), // code 8, with GSSAPI or SSPI authentication data
+
+          // TODO: more types
+          // AuthenticationSCMCredential
+          //
+          // AuthenticationGSS
 }
 
 pub const MESSAGE_TYPE_BYTE_AUTHENTICATION: u8 = b'R';
@@ -121,15 +125,17 @@ impl Message for Authentication {
     #[inline]
     fn message_length(&self) -> usize {
         match self {
-            Authentication::Ok | Authentication::CleartextPassword | Authentication::KerberosV5 => {
-                8
-            }
+            Authentication::Ok
+            | Authentication::CleartextPassword
+            | Authentication::KerberosV5
+            | Authentication::SSPI => 8,
             Authentication::MD5Password(_) => 12,
             Authentication::SASL(methods) => {
                 8 + methods.iter().map(|v| v.len() + 1).sum::<usize>() + 1
             }
             Authentication::SASLContinue(data) => 8 + data.len(),
             Authentication::SASLFinal(data) => 8 + data.len(),
+            Authentication::GSSContinue(data) => 8 + data.len(),
         }
     }
 
@@ -157,17 +163,24 @@ impl Message for Authentication {
                 buf.put_i32(12);
                 buf.put_slice(data.as_ref());
             }
+            Authentication::SSPI => buf.put_i32(9),
+            Authentication::GSSContinue(data) => {
+                buf.put_i32(8);
+                buf.put_slice(data.as_ref());
+            }
         }
         Ok(())
     }
 
     fn decode_body(buf: &mut BytesMut, msg_len: usize) -> PgWireResult<Self> {
+        codec::ensure_remaining(buf, 4)?;
         let code = buf.get_i32();
         let msg = match code {
             0 => Authentication::Ok,
             2 => Authentication::KerberosV5,
             3 => Authentication::CleartextPassword,
             5 => {
+                codec::ensure_remaining(buf, 4)?;
                 let mut salt_vec = vec![0; 4];
                 buf.copy_to_slice(&mut salt_vec);
                 Authentication::MD5Password(salt_vec)
@@ -180,13 +193,18 @@ impl Message for Authentication {
                 Authentication::SASL(methods)
             }
             11 => {
-                let data = buf.split_to(msg_len - 4).freeze();
+                let data = buf.split_to(msg_len - 8).freeze();
                 Authentication::SASLContinue(data)
             }
             12 => {
-                let data = buf.split_to(msg_len - 4).freeze();
+                let data = buf.split_to(msg_len - 8).freeze();
                 Authentication::SASLFinal(data)
             }
+            9 => Authentication::SSPI,
+            8 => {
+                let data = buf.split_to(msg_len - 8).freeze();
+                Authentication::GSSContinue(data)
+            }
             _ => unreachable!(),
         };
 
@@ -220,6 +238,8 @@ pub enum PasswordMessageFamily {
     SASLInitialResponse(SASLInitialResponse),
     /// SASLResponse
     SASLResponse(SASLResponse),
+    /// GSSResponse, also used for SSPI
+    GSSResponse(GSSResponse),
 }
 
 impl Message for PasswordMessageFamily {
@@ -233,6 +253,7 @@ impl Message for PasswordMessageFamily {
             PasswordMessageFamily::Password(inner) => inner.message_length(),
             PasswordMessageFamily::SASLInitialResponse(inner) => inner.message_length(),
             PasswordMessageFamily::SASLResponse(inner) => inner.message_length(),
+            PasswordMessageFamily::GSSResponse(inner) => inner.message_length(),
         }
     }
 
@@ -245,6 +266,7 @@ impl Message for PasswordMessageFamily {
             PasswordMessageFamily::Password(inner) => inner.encode_body(buf),
             PasswordMessageFamily::SASLInitialResponse(inner) => inner.encode_body(buf),
             PasswordMessageFamily::SASLResponse(inner) => inner.encode_body(buf),
+            PasswordMessageFamily::GSSResponse(inner) => inner.encode_body(buf),
         }
     }
 
@@ -305,6 +327,23 @@ impl PasswordMessageFamily {
             )
         }
     }
+
+    /// Coerce the raw message into `GSSResponse`
+    ///
+    /// # Panics
+    ///
+    /// Panic when the message is already coerced into concrete type.
+    pub fn into_gss_response(self) -> PgWireResult<GSSResponse> {
+        if let PasswordMessageFamily::Raw(mut body) = self {
+            let len = body.len() + 4;
+            GSSResponse::decode_body(&mut body, len)
+        } else {
+            unreachable!(
+                "Do not coerce password message when it has a concrete type {:?}",
+                self
+            )
+        }
+    }
 }
 
 /// password packet sent from frontend
@@ -321,7 +360,7 @@ impl Message for Password {
     }
 
     fn message_length(&self) -> usize {
-        5 + self.password.as_bytes().len()
+        5 + self.password.len()
     }
 
     fn encode_body(&self, buf: &mut BytesMut) -> PgWireResult<()> {
@@ -354,7 +393,7 @@ impl Message for ParameterStatus {
     }
 
     fn message_length(&self) -> usize {
-        4 + 2 + self.name.as_bytes().len() + self.value.as_bytes().len()
+        4 + 2 + self.name.len() + self.value.len()
     }
 
     fn encode_body(&self, buf: &mut BytesMut) -> PgWireResult<()> {
@@ -372,6 +411,68 @@ impl Message for ParameterStatus {
     }
 }
 
+/// `NegotiateProtocolVersion` message, sent from backend when the client
+/// requested a newer minor protocol version, or protocol options starting with
+/// `_pq_.` that are not recognized
+#[derive(Getters, Setters, MutGetters, PartialEq, Eq, Debug, new)]
+#[getset(get = "pub", set = "pub", get_mut = "pub")]
+pub struct NegotiateProtocolVersion {
+    /// newest minor protocol version supported by server
+    newest_minor_version: i32,
+    /// protocol options not recognized by server
+    unrecognized_options: Vec<String>,
+}
+
+pub const MESSAGE_TYPE_BYTE_NEGOTIATE_PROTOCOL_VERSION: u8 = b'v';
+
+impl Message for NegotiateProtocolVersion {
+    #[inline]
+    fn message_type() -> Option<u8> {
+        Some(MESSAGE_TYPE_BYTE_NEGOTIATE_PROTOCOL_VERSION)
+    }
+
+    fn message_length(&self) -> usize {
+        4 + 4
+            + 4
+            + self
+                .unrecognized_options
+                .iter()
+                .map(|option| option.len() + 1)
+                .sum::<usize>()
+    }
+
+    fn encode_body(&self, buf: &mut BytesMut) -> PgWireResult<()> {
+        buf.put_i32(self.newest_minor_version);
+        buf.put_i32(self.unrecognized_options.len() as i32);
+        for option in &self.unrecognized_options {
+            codec::put_cstring(buf, option);
+        }
+        Ok(())
+    }
+
+    fn decode_body(buf: &mut BytesMut, _: usize) -> PgWireResult<Self> {
+        codec::ensure_remaining(buf, 8)?;
+        let newest_minor_version = buf.get_i32();
+        let count = buf.get_i32();
+        if count < 0 {
+            return Err(PgWireError::InvalidCount(count));
+        }
+        // each option is at least its nul byte
+        let count = count as usize;
+        codec::ensure_remaining(buf, count)?;
+        let mut unrecognized_options = Vec::with_capacity(count);
+        for _ in 0..count {
+            let option = codec::get_cstring_exact(buf)?.to_vec();
+            unrecognized_options
+                .push(String::from_utf8(option).map_err(PgWireError::InvalidUtf8String)?);
+        }
+        Ok(NegotiateProtocolVersion::new(
+            newest_minor_version,
+            unrecognized_options,
+        ))
+    }
+}
+
 /// `BackendKeyData` message, sent from backend to frontend for issuing
 /// `CancelRequestMessage`
 #[derive(Getters, Setters, MutGetters, PartialEq, Eq, Debug, new)]
@@ -456,6 +557,114 @@ impl Message for SslRequest {
     }
 }
 
+/// `GSSENCRequest` sent from frontend to negotiate GSSAPI encryption, before
+/// or instead of `SslRequest`. Like `SslRequest`, the packet has no message
+/// type and contains only a length(4) and an i32 value.
+///
+/// GSSAPI encryption is not supported, the backend always answers with a
+/// single byte 'N', and the frontend may then send `SslRequest` or startup.
+#[derive(Getters, Setters, MutGetters, PartialEq, Eq, Debug, new)]
+#[getset(get = "pub", set = "pub", get_mut = "pub")]
+pub struct GssEncRequest {}
+
+impl GssEncRequest {
+    pub const BODY_MAGIC_NUMBER: i32 = 80877104;
+    pub const BODY_SIZE: usize = 8;
+}
+
+impl Message for GssEncRequest {
+    #[inline]
+    fn message_type() -> Option<u8> {
+        None
+    }
+
+    #[inline]
+    fn message_length(&self) -> usize {
+        Self::BODY_SIZE
+    }
+
+    fn encode_body(&self, buf: &mut BytesMut) -> PgWireResult<()> {
+        buf.put_i32(Self::BODY_MAGIC_NUMBER);
+        Ok(())
+    }
+
+    fn decode_body(_buf: &mut BytesMut, _full_len: usize) -> PgWireResult<Self> {
+        unreachable!();
+    }
+
+    /// Try to decode and check if the packet is a `GssEncRequest`.
+    fn decode(buf: &mut BytesMut) -> PgWireResult<Option<Self>> {
+        if buf.remaining() >= 8 && (&buf[4..8]).get_i32() == Self::BODY_MAGIC_NUMBER {
+            buf.advance(8);
+            Ok(Some(GssEncRequest {}))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// `CancelRequest` sent from frontend on a new connection, instead of a
+/// startup message, to cancel the query running on the connection identified
+/// by `pid` and `secret_key` of its `BackendKeyData`. The packet has no message
+/// type, the backend closes the connection without response.
+#[derive(Getters, Setters, MutGetters, PartialEq, Eq, Debug, new)]
+#[getset(get = "pub", set = "pub", get_mut = "pub")]
+pub struct CancelRequest {
+    pid: i32,
+    secret_key: i32,
+}
+
+impl CancelRequest {
+    pub const BODY_MAGIC_NUMBER: i32 = 80877102;
+    pub const BODY_SIZE: usize = 16;
+
+    /// Test if `buf` starts with a `CancelRequest`, possibly incomplete
+    pub fn is_cancel_request(buf: &[u8]) -> bool {
+        buf.len() >= 8 && (&buf[4..8]).get_i32() == Self::BODY_MAGIC_NUMBER
+    }
+}
+
+impl Message for CancelRequest {
+    #[inline]
+    fn message_type() -> Option<u8> {
+        None
+    }
+
+    #[inline]
+    fn message_length(&self) -> usize {
+        Self::BODY_SIZE
+    }
+
+    fn encode_body(&self, buf: &mut BytesMut) -> PgWireResult<()> {
+        buf.put_i32(Self::BODY_MAGIC_NUMBER);
+        buf.put_i32(self.pid);
+        buf.put_i32(self.secret_key);
+        Ok(())
+    }
+
+    fn decode_body(buf: &mut BytesMut, _full_len: usize) -> PgWireResult<Self> {
+        // skip magic number
+        buf.get_i32();
+        let pid = buf.get_i32();
+        let secret_key = buf.get_i32();
+        Ok(CancelRequest { pid, secret_key })
+    }
+
+    /// Decode a packet checked by `is_cancel_request`. Returns `Ok(None)`
+    /// without consuming `buf` when more bytes are needed.
+    fn decode(buf: &mut BytesMut) -> PgWireResult<Option<Self>> {
+        if buf.remaining() < Self::BODY_SIZE {
+            return Ok(None);
+        }
+        let len = (&buf[0..4]).get_i32();
+        if len != Self::BODY_SIZE as i32 {
+            return Err(PgWireError::InvalidMessageLength(len));
+        }
+        buf.advance(4);
+        Self::decode_body(buf, Self::BODY_SIZE).map(Some)
+    }
+}
+
 #[derive(Getters, Setters, MutGetters, PartialEq, Eq, Debug, new)]
 #[getset(get = "pub", set = "pub", get_mut = "pub")]
 pub struct SASLInitialResponse {
@@ -471,10 +680,7 @@ impl Message for SASLInitialResponse {
 
     #[inline]
     fn message_length(&self) -> usize {
-        4 + self.auth_method.as_bytes().len()
-            + 1
-            + 4
-            + self.data.as_ref().map(|b| b.len()).unwrap_or(0)
+        4 + self.auth_method.len() + 1 + 4 + self.data.as_ref().map(|b| b.len()).unwrap_or(0)
     }
 
     fn encode_body(&self, buf: &mut BytesMut) -> PgWireResult<()> {
@@ -490,12 +696,7 @@ impl Message for SASLInitialResponse {
 
     fn decode_body(buf: &mut BytesMut, _full_len: usize) -> PgWireResult<Self> {
         let auth_method = codec::get_cstring(buf).unwrap_or_else(|| "".to_owned());
-        let data_len = buf.get_i32();
-        let data = if data_len == -1 {
-            None
-        } else {
-            Some(buf.split_to(data_len as usize).freeze())
-        };
+        let data = codec::get_length_prefixed(buf)?.map(BytesMut::freeze);
 
         Ok(SASLInitialResponse { auth_method, data })
     }
@@ -528,3 +729,32 @@ impl Message for SASLResponse {
         Ok(SASLResponse { data })
     }
 }
+
+/// GSSAPI or SSPI token sent from frontend
+#[derive(Getters, Setters, MutGetters, PartialEq, Eq, Debug, new)]
+#[getset(get = "pub", set = "pub", get_mut = "pub")]
+pub struct GSSResponse {
+    data: Bytes,
+}
+
+impl Message for GSSResponse {
+    #[inline]
+    fn message_type() -> Option<u8> {
+        Some(MESSAGE_TYPE_BYTE_PASWORD_MESSAGE_FAMILY)
+    }
+
+    #[inline]
+    fn message_length(&self) -> usize {
+        4 + self.data.len()
+    }
+
+    fn encode_body(&self, buf: &mut BytesMut) -> PgWireResult<()> {
+        buf.put_slice(self.data.as_ref());
+        Ok(())
+    }
+
+    fn decode_body(buf: &mut BytesMut, full_len: usize) -> PgWireResult<Self> {
+        let data = buf.split_to(full_len - 4).freeze();
+        Ok(GSSResponse { data })
+    }
+}
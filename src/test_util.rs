@@ -0,0 +1,264 @@
+//! Transports for testing servers under adverse network conditions.
+//!
+//! `ChaosStream` wraps a stream, like one half of `tokio::io::duplex`, and
+//! delays reads and writes, splits them into small chunks, or stalls writes
+//! as if the peer stopped reading. It makes timeouts, backpressure and
+//! partial reads reproducible in tests without a real network.
+//!
+//! This module is enabled by feature `test-util`.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use futures::task::AtomicWaker;
+use futures::Future;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{self, Sleep};
+
+/// Faults injected by `ChaosStream`, none by default.
+#[derive(Debug, Clone, Default, new, Getters, Setters)]
+#[getset(get = "pub", set = "pub")]
+pub struct ChaosOptions {
+    /// time to wait before each read from the inner stream
+    #[new(default)]
+    read_delay: Option<Duration>,
+    /// time to wait before each write to the inner stream
+    #[new(default)]
+    write_delay: Option<Duration>,
+    /// max number of bytes returned by each read
+    #[new(default)]
+    max_read_size: Option<usize>,
+    /// max number of bytes accepted by each write, so that writers have to
+    /// handle partial writes
+    #[new(default)]
+    max_write_size: Option<usize>,
+}
+
+/// Handle to stall and resume writes of a `ChaosStream`, to simulate a peer
+/// that stopped reading.
+#[derive(Debug, Clone, Default)]
+pub struct StallHandle {
+    stalled: Arc<AtomicBool>,
+    waker: Arc<AtomicWaker>,
+}
+
+impl StallHandle {
+    /// Stop accepting writes. Pending writes wait until `resume`.
+    pub fn stall(&self) {
+        self.stalled.store(true, Ordering::SeqCst);
+    }
+
+    /// Accept writes again, waking up the pending writer
+    pub fn resume(&self) {
+        self.stalled.store(false, Ordering::SeqCst);
+        self.waker.wake();
+    }
+
+    pub fn is_stalled(&self) -> bool {
+        self.stalled.load(Ordering::SeqCst)
+    }
+
+    fn poll_resumed(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if !self.is_stalled() {
+            return Poll::Ready(());
+        }
+        self.waker.register(cx.waker());
+        // resumed before the waker is registered
+        if self.is_stalled() {
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    }
+}
+
+/// A stream injecting delays, short reads and writes, and stalls into the
+/// inner stream.
+#[derive(Debug)]
+pub struct ChaosStream<S> {
+    inner: S,
+    options: ChaosOptions,
+    stall: StallHandle,
+    read_delay: Option<Pin<Box<Sleep>>>,
+    write_delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> ChaosStream<S> {
+    pub fn new(inner: S, options: ChaosOptions) -> ChaosStream<S> {
+        ChaosStream {
+            inner,
+            options,
+            stall: StallHandle::default(),
+            read_delay: None,
+            write_delay: None,
+        }
+    }
+
+    /// Get a handle to stall writes of this stream
+    pub fn stall_handle(&self) -> StallHandle {
+        self.stall.clone()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+/// Wait for `duration` once per operation. The timer is kept until the
+/// operation completes, so polling a pending operation doesn't restart it.
+fn poll_delay(
+    delay: &mut Option<Pin<Box<Sleep>>>,
+    duration: Option<Duration>,
+    cx: &mut Context<'_>,
+) -> Poll<()> {
+    match duration {
+        Some(duration) => delay
+            .get_or_insert_with(|| Box::pin(time::sleep(duration)))
+            .as_mut()
+            .poll(cx),
+        None => Poll::Ready(()),
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ChaosStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(poll_delay(
+            &mut this.read_delay,
+            this.options.read_delay,
+            cx
+        ));
+
+        let result = match this.options.max_read_size {
+            Some(max) if max < buf.remaining() => {
+                let mut chunk = vec![0; max];
+                let mut chunk_buf = ReadBuf::new(&mut chunk);
+                let result = ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk_buf));
+                buf.put_slice(chunk_buf.filled());
+                result
+            }
+            _ => ready!(Pin::new(&mut this.inner).poll_read(cx, buf)),
+        };
+        this.read_delay = None;
+        Poll::Ready(result)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ChaosStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.stall.poll_resumed(cx));
+        ready!(poll_delay(
+            &mut this.write_delay,
+            this.options.write_delay,
+            cx
+        ));
+
+        let len = this
+            .options
+            .max_write_size
+            .map_or(buf.len(), |max| max.min(buf.len()));
+        let result = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..len]));
+        this.write_delay = None;
+        Poll::Ready(result)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::time::Instant;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_short_reads_and_writes() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut options = ChaosOptions::new();
+        options
+            .set_max_read_size(Some(3))
+            .set_max_write_size(Some(2));
+        let mut server = ChaosStream::new(server, options);
+
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(3, server.read(&mut buf).await.unwrap());
+        assert_eq!(b"hel", &buf[..3]);
+        assert_eq!(2, server.read(&mut buf).await.unwrap());
+        assert_eq!(b"lo", &buf[..2]);
+
+        assert_eq!(2, server.write(b"world").await.unwrap());
+        server.write_all(b"rld").await.unwrap();
+        let mut buf = [0; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"world", &buf);
+    }
+
+    #[tokio::test]
+    async fn test_delays() {
+        // delays are timed by the paused clock, advanced when all tasks wait
+        time::pause();
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut options = ChaosOptions::new();
+        options
+            .set_read_delay(Some(Duration::from_millis(50)))
+            .set_write_delay(Some(Duration::from_millis(50)));
+        let mut server = ChaosStream::new(server, options);
+
+        let started = Instant::now();
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        server.write_all(b"pong").await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"pong", &buf);
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn test_stalled_reader() {
+        time::pause();
+        let (mut client, server) = tokio::io::duplex(64);
+        let server = ChaosStream::new(server, ChaosOptions::new());
+        let stall = server.stall_handle();
+        stall.stall();
+
+        let writer = tokio::spawn(async move {
+            let mut server = server;
+            server.write_all(b"data").await.unwrap();
+        });
+        // returns once the writer waits, without waiting real time
+        time::sleep(Duration::from_millis(50)).await;
+        assert!(!writer.is_finished());
+
+        stall.resume();
+        writer.await.unwrap();
+        let mut buf = [0; 4];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"data", &buf);
+    }
+}
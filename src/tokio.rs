@@ -1,41 +1,87 @@
-use std::io::Error as IOError;
-use std::sync::Arc;
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{Error as IOError, ErrorKind};
+use std::net::SocketAddr;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::Duration;
 
-use bytes::Buf;
-use futures::future::poll_fn;
-use futures::{SinkExt, StreamExt};
+use async_trait::async_trait;
+use bytes::{Buf, BytesMut};
+use futures::future::{self, poll_fn};
+use futures::Future;
+use futures::{FutureExt, SinkExt, StreamExt};
+use socket2::{SockRef, TcpKeepalive};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
-use tokio::net::TcpStream;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::time::{self, Instant, Sleep};
+use tokio_rustls::rustls::{version, Certificate, PrivateKey, ProtocolVersion, ServerConfig};
 use tokio_rustls::TlsAcceptor;
-use tokio_util::codec::{Decoder, Encoder, Framed};
+use tokio_util::codec::{Decoder, Encoder, Framed, FramedParts};
+pub use tokio_util::sync::CancellationToken;
 
+use crate::api::activity::ConnectionRegistry;
 use crate::api::auth::StartupHandler;
+use crate::api::copy::CopyHandler;
+use crate::api::params::{lenient_set, LenientSetOptions};
 use crate::api::query::ExtendedQueryHandler;
 use crate::api::query::SimpleQueryHandler;
-use crate::api::{ClientInfo, ClientInfoHolder, PgWireConnectionState};
-use crate::error::{ErrorInfo, PgWireError, PgWireResult};
+use crate::api::{
+    BackendKey, ClientInfo, ClientInfoHolder, ConnectionNegotiation, Extensions, MakeHandler,
+    PgWireConnectionState, TlsSession, TransactionStatus, METADATA_USER,
+};
+use crate::compression::{
+    self, CompressedWriter, CompressionAlgorithm, STARTUP_OPTION_COMPRESSION,
+};
+use crate::error::{ErrorInfo, PgWireError, PgWireResult, Severity, SqlState};
+use crate::messages::extendedquery::TARGET_TYPE_BYTE_STATEMENT;
 use crate::messages::response::ReadyForQuery;
-use crate::messages::response::READY_STATUS_IDLE;
-use crate::messages::startup::{SslRequest, Startup};
+use crate::messages::startup::{
+    Authentication, CancelRequest, GssEncRequest, NegotiateProtocolVersion, ParameterStatus,
+    SslRequest, Startup,
+};
 use crate::messages::{Message, PgWireBackendMessage, PgWireFrontendMessage};
 
 #[derive(Debug, new, Getters, Setters, MutGetters)]
 #[getset(get = "pub", set = "pub", get_mut = "pub")]
 pub struct PgWireMessageServerCodec {
     client_info: ClientInfoHolder,
+    /// whether `ReadyForQuery` has been sent since the last message from
+    /// client
+    #[new(default)]
+    ready_for_query_sent: bool,
+    /// compression of the byte stream from client, enabled after startup.
+    /// Messages to client are compressed by `CompressedWriter`.
+    #[new(default)]
+    #[getset(skip)]
+    compression: Option<Arc<dyn CompressionAlgorithm>>,
+    /// decompressed bytes not yet decoded into messages
+    #[new(default)]
+    #[getset(skip)]
+    decompressed: BytesMut,
 }
 
-impl Decoder for PgWireMessageServerCodec {
-    type Item = PgWireFrontendMessage;
-    type Error = PgWireError;
+impl PgWireMessageServerCodec {
+    /// Decompress messages from client from now on
+    pub(crate) fn enable_compression(&mut self, algorithm: Arc<dyn CompressionAlgorithm>) {
+        self.compression = Some(algorithm);
+    }
 
-    fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+    fn decode_message(
+        &mut self,
+        src: &mut BytesMut,
+    ) -> Result<Option<PgWireFrontendMessage>, PgWireError> {
         match self.client_info.state() {
             PgWireConnectionState::AwaitingStartup => {
-                if let Some(startup) = Startup::decode(src)? {
-                    Ok(Some(PgWireFrontendMessage::Startup(startup)))
+                if CancelRequest::is_cancel_request(src) {
+                    CancelRequest::decode(src).map(|v| v.map(PgWireFrontendMessage::CancelRequest))
                 } else {
-                    Ok(None)
+                    Startup::decode(src).map(|v| v.map(PgWireFrontendMessage::Startup))
                 }
             }
             _ => PgWireFrontendMessage::decode(src),
@@ -43,6 +89,34 @@ impl Decoder for PgWireMessageServerCodec {
     }
 }
 
+impl Decoder for PgWireMessageServerCodec {
+    type Item = PgWireFrontendMessage;
+    type Error = PgWireError;
+
+    fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let message = match self.compression.clone() {
+            // decompress the next block only when decompressed bytes have no
+            // complete message
+            Some(compression) => loop {
+                let mut decompressed = std::mem::take(&mut self.decompressed);
+                let message = self.decode_message(&mut decompressed);
+                self.decompressed = decompressed;
+                if let Some(message) = message? {
+                    break Some(message);
+                }
+                if !compression::read_block(compression.as_ref(), src, &mut self.decompressed)? {
+                    break None;
+                }
+            },
+            None => self.decode_message(src)?,
+        };
+        if message.is_some() {
+            self.ready_for_query_sent = false;
+        }
+        Ok(message)
+    }
+}
+
 impl Encoder<PgWireBackendMessage> for PgWireMessageServerCodec {
     type Error = IOError;
 
@@ -51,6 +125,9 @@ impl Encoder<PgWireBackendMessage> for PgWireMessageServerCodec {
         item: PgWireBackendMessage,
         dst: &mut bytes::BytesMut,
     ) -> Result<(), Self::Error> {
+        if let PgWireBackendMessage::ReadyForQuery(_) = item {
+            self.ready_for_query_sent = true;
+        }
         item.encode(dst).map_err(Into::into)
     }
 }
@@ -72,6 +149,16 @@ impl<T> ClientInfo for Framed<T, PgWireMessageServerCodec> {
         self.codec_mut().client_info_mut().set_state(new_state);
     }
 
+    fn transaction_status(&self) -> TransactionStatus {
+        *self.codec().client_info().transaction_status()
+    }
+
+    fn set_transaction_status(&mut self, new_status: TransactionStatus) {
+        self.codec_mut()
+            .client_info_mut()
+            .set_transaction_status(new_status);
+    }
+
     fn metadata(&self) -> &std::collections::HashMap<String, String> {
         self.codec().client_info().metadata()
     }
@@ -79,37 +166,159 @@ impl<T> ClientInfo for Framed<T, PgWireMessageServerCodec> {
     fn metadata_mut(&mut self) -> &mut std::collections::HashMap<String, String> {
         self.codec_mut().client_info_mut().metadata_mut()
     }
+
+    fn extensions(&self) -> &Extensions {
+        self.codec().client_info().extensions()
+    }
+
+    fn extensions_mut(&mut self) -> &mut Extensions {
+        self.codec_mut().client_info_mut().extensions_mut()
+    }
+
+    fn is_pipelined(&self) -> bool {
+        !self.read_buffer().is_empty() || !self.codec().decompressed.is_empty()
+    }
+}
+
+/// Names of the named statements and portals a connection created, kept in
+/// its `Extensions` for `max_prepared_statements`. They are counted apart
+/// from the `PortalStore`, which may be shared by connections.
+#[derive(Debug, Default)]
+struct PreparedNames {
+    statements: HashSet<String>,
+    portals: HashSet<String>,
+}
+
+fn prepared_names<S>(socket: &mut Framed<S, PgWireMessageServerCodec>) -> &mut PreparedNames {
+    let extensions = socket.extensions_mut();
+    if extensions.get::<PreparedNames>().is_none() {
+        extensions.insert(PreparedNames::default());
+    }
+    extensions
+        .get_mut::<PreparedNames>()
+        .expect("inserted above")
+}
+
+fn prepared_limit_exceeded(kind: &str, max: usize) -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        Severity::Error,
+        SqlState::ProgramLimitExceeded,
+        format!("too many {kind}, the limit of a connection is {max}"),
+    )))
 }
 
-async fn process_message<S, A, Q, EQ>(
+async fn process_message<S, A, Q, EQ, CH>(
     message: PgWireFrontendMessage,
     socket: &mut Framed<S, PgWireMessageServerCodec>,
     authenticator: Arc<A>,
     query_handler: Arc<Q>,
     extended_query_handler: Arc<EQ>,
+    copy_handler: Arc<CH>,
+    limits: &ConnectionLimits,
 ) -> PgWireResult<()>
 where
     S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
     A: StartupHandler + 'static,
     Q: SimpleQueryHandler + 'static,
     EQ: ExtendedQueryHandler + 'static,
+    CH: CopyHandler + 'static,
 {
     match socket.codec().client_info().state() {
         PgWireConnectionState::AwaitingStartup
         | PgWireConnectionState::AuthenticationInProgress => {
             authenticator.on_startup(socket, message).await?;
         }
+        PgWireConnectionState::CopyInProgress(extended) => {
+            let extended = *extended;
+            match message {
+                PgWireFrontendMessage::CopyData(copy_data) => {
+                    if let Err(e) = copy_handler.on_copy_data(socket, copy_data).await {
+                        // the rest of the copy data, `CopyDone` and `CopyFail`
+                        // from client are dropped once out of copy state
+                        socket.set_state(PgWireConnectionState::ReadyForQuery);
+                        return Err(copy_aborted(e));
+                    }
+                }
+                PgWireFrontendMessage::CopyDone(copy_done) => {
+                    let result = copy_handler.on_copy_done(socket, copy_done).await;
+                    socket.set_state(PgWireConnectionState::ReadyForQuery);
+                    let tag = result.map_err(copy_aborted)?;
+                    socket
+                        .feed(PgWireBackendMessage::CommandComplete(tag.into()))
+                        .await?;
+                    if !extended {
+                        socket
+                            .feed(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(
+                                socket.transaction_status().to_ready_status(),
+                            )))
+                            .await?;
+                    }
+                    socket.flush().await?;
+                }
+                PgWireFrontendMessage::CopyFail(copy_fail) => {
+                    socket.set_state(PgWireConnectionState::ReadyForQuery);
+                    let message = format!("COPY from stdin failed: {}", copy_fail.message());
+                    copy_handler.on_copy_fail(socket, copy_fail).await?;
+                    return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                        Severity::Error,
+                        SqlState::QueryCanceled,
+                        message,
+                    ))));
+                }
+                // `Flush` and `Sync` are ignored during copy
+                _ => {}
+            }
+        }
+        PgWireConnectionState::AwaitingSync => {
+            // skip messages until sync after an error in extended query
+            if let PgWireFrontendMessage::Sync(sync) = message {
+                socket.set_state(PgWireConnectionState::ReadyForQuery);
+                extended_query_handler.on_sync(socket, sync).await?;
+            }
+        }
         _ => {
+            if limits.simple_query_only && is_extended_query_message(&message) {
+                return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                    Severity::Error,
+                    SqlState::FeatureNotSupported,
+                    "extended query protocol is not supported",
+                ))));
+            }
             // query or query in progress
             match message {
                 PgWireFrontendMessage::Query(query) => {
+                    if let Some(ref options) = limits.lenient_set {
+                        if lenient_set(socket, options, query.query()).await? {
+                            return Ok(());
+                        }
+                    }
                     query_handler.on_query(socket, query).await?;
                 }
                 PgWireFrontendMessage::Parse(parse) => {
+                    let name = parse.name().clone();
+                    if let (Some(name), Some(max)) = (&name, limits.max_prepared_statements) {
+                        let names = prepared_names(socket);
+                        if !names.statements.contains(name) && names.statements.len() >= max {
+                            return Err(prepared_limit_exceeded("prepared statements", max));
+                        }
+                    }
                     extended_query_handler.on_parse(socket, parse).await?;
+                    if let (Some(name), Some(_)) = (name, limits.max_prepared_statements) {
+                        prepared_names(socket).statements.insert(name);
+                    }
                 }
                 PgWireFrontendMessage::Bind(bind) => {
+                    let name = bind.portal_name().clone();
+                    if let (Some(name), Some(max)) = (&name, limits.max_prepared_statements) {
+                        let names = prepared_names(socket);
+                        if !names.portals.contains(name) && names.portals.len() >= max {
+                            return Err(prepared_limit_exceeded("portals", max));
+                        }
+                    }
                     extended_query_handler.on_bind(socket, bind).await?;
+                    if let (Some(name), Some(_)) = (name, limits.max_prepared_statements) {
+                        prepared_names(socket).portals.insert(name);
+                    }
                 }
                 PgWireFrontendMessage::Execute(execute) => {
                     extended_query_handler.on_execute(socket, execute).await?;
@@ -120,8 +329,22 @@ where
                 PgWireFrontendMessage::Sync(sync) => {
                     extended_query_handler.on_sync(socket, sync).await?;
                 }
+                PgWireFrontendMessage::Flush(_) => {
+                    socket.flush().await?;
+                }
                 PgWireFrontendMessage::Close(close) => {
+                    let target = (close.target_type(), close.name().clone());
                     extended_query_handler.on_close(socket, close).await?;
+                    if let ((target_type, Some(name)), Some(_)) =
+                        (target, limits.max_prepared_statements)
+                    {
+                        let names = prepared_names(socket);
+                        if target_type == TARGET_TYPE_BYTE_STATEMENT {
+                            names.statements.remove(&name);
+                        } else {
+                            names.portals.remove(&name);
+                        }
+                    }
                 }
                 _ => {}
             }
@@ -130,42 +353,166 @@ where
     Ok(())
 }
 
+/// Error aborting `COPY FROM STDIN`. Parse errors of copy data, like those
+/// of `CopyRowParser`, are errors of the copy rather than of the connection,
+/// so they are recoverable.
+fn copy_aborted(error: PgWireError) -> PgWireError {
+    match error {
+        PgWireError::InvalidCopyData(message) => PgWireError::UserError(Box::new(ErrorInfo::new(
+            Severity::Error,
+            SqlState::BadCopyFileFormat,
+            message,
+        ))),
+        PgWireError::InvalidUtf8String(e) => PgWireError::UserError(Box::new(ErrorInfo::new(
+            Severity::Error,
+            SqlState::CharacterNotInRepertoire,
+            format!("invalid byte sequence for encoding \"UTF8\": {e}"),
+        ))),
+        error => error,
+    }
+}
+
+/// Test if the message belongs to an extended query batch, which is ended by
+/// `Sync`
+fn is_extended_query_message(message: &PgWireFrontendMessage) -> bool {
+    matches!(
+        message,
+        PgWireFrontendMessage::Parse(_)
+            | PgWireFrontendMessage::Bind(_)
+            | PgWireFrontendMessage::Describe(_)
+            | PgWireFrontendMessage::Execute(_)
+            | PgWireFrontendMessage::Close(_)
+            | PgWireFrontendMessage::Flush(_)
+    )
+}
+
+/// Close connection of a client with unsupported protocol version. Like
+/// postgres, the error is sent in the format of the client protocol, with
+/// a bare message for protocol 2 and older.
+async fn reject_protocol_version<S>(
+    socket: &mut Framed<S, PgWireMessageServerCodec>,
+    version: i32,
+) -> Result<(), IOError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    let major = version >> 16;
+    let message = format!(
+        "unsupported frontend protocol {}.{}: server supports 3.0 to 3.0",
+        major,
+        version & 0xffff
+    );
+    if (0..3).contains(&major) {
+        let stream = socket.get_mut();
+        stream.write_all(b"E").await?;
+        stream
+            .write_all(format!("FATAL:  {message}\n\0").as_bytes())
+            .await?;
+        stream.shutdown().await?;
+    } else {
+        let error_info = ErrorInfo::new(Severity::Fatal, SqlState::ProtocolViolation, message);
+        socket
+            .send(PgWireBackendMessage::ErrorResponse(error_info.into()))
+            .await?;
+        socket.close().await?;
+    }
+    Ok(())
+}
+
 async fn process_error<S>(
     socket: &mut Framed<S, PgWireMessageServerCodec>,
     error: PgWireError,
+    extended_query: bool,
 ) -> Result<(), IOError>
 where
     S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
 {
-    match error {
-        PgWireError::UserError(error_info) => {
-            socket
-                .feed(PgWireBackendMessage::ErrorResponse((*error_info).into()))
-                .await?;
+    if let PgWireError::InvalidProtocolVersion(version) = error {
+        return reject_protocol_version(socket, version).await;
+    }
+
+    // user, api and encode errors, and references to missing or duplicate
+    // statements or portals are recoverable
+    let recoverable = match error {
+        PgWireError::UserError(error_info) => Ok(*error_info),
+        PgWireError::StatementNotFound(name) => Ok(ErrorInfo::new(
+            Severity::Error,
+            SqlState::InvalidSqlStatementName,
+            format!("prepared statement \"{name}\" does not exist"),
+        )),
+        PgWireError::DuplicateStatement(name) => Ok(ErrorInfo::new(
+            Severity::Error,
+            SqlState::DuplicatePstatement,
+            format!("prepared statement \"{name}\" already exists"),
+        )),
+        PgWireError::PortalNotFound(name) => Ok(ErrorInfo::new(
+            Severity::Error,
+            SqlState::InvalidCursorName,
+            format!("portal \"{name}\" does not exist"),
+        )),
+        PgWireError::ApiError(e) => Ok(ErrorInfo::internal(e.as_ref())),
+        PgWireError::EncodeError {
+            column,
+            type_oid,
+            source,
+        } => {
+            // values not fitting the column keep their code, like `22003`
+            let code = match source.downcast_ref::<ErrorInfo>() {
+                Some(info) => info.sql_state(),
+                None => SqlState::InternalError,
+            };
+            let error = PgWireError::EncodeError {
+                column,
+                type_oid,
+                source,
+            };
+            Ok(ErrorInfo::new(Severity::Error, code, error.to_string()))
+        }
+        error => Err(error),
+    };
 
+    match recoverable {
+        // errors of startup, like of `on_session_start`, end the connection
+        Ok(error_info) if is_starting_up(socket.state()) => {
             socket
-                .feed(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(
-                    READY_STATUS_IDLE,
-                )))
+                .send(PgWireBackendMessage::ErrorResponse(error_info.into()))
                 .await?;
-            socket.flush().await?;
+            socket.close().await?;
         }
-        PgWireError::ApiError(e) => {
-            let error_info = ErrorInfo::new("ERROR".to_owned(), "XX000".to_owned(), e.to_string());
+        Ok(error_info) => {
+            socket.set_transaction_status(socket.transaction_status().after_error());
             socket
                 .feed(PgWireBackendMessage::ErrorResponse(error_info.into()))
                 .await?;
-            socket
-                .feed(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(
-                    READY_STATUS_IDLE,
-                )))
-                .await?;
+
+            if extended_query {
+                // ReadyForQuery is sent on the next Sync
+                socket.set_state(PgWireConnectionState::AwaitingSync);
+            } else if !socket.codec().ready_for_query_sent() {
+                // the handler may have sent it already with
+                // `send_ready_for_query`
+                socket
+                    .feed(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(
+                        socket.transaction_status().to_ready_status(),
+                    )))
+                    .await?;
+            }
             socket.flush().await?;
         }
-        _ => {
-            // Internal error
-            let error_info =
-                ErrorInfo::new("FATAL".to_owned(), "XX000".to_owned(), error.to_string());
+        Err(error) => {
+            let code = match error {
+                PgWireError::MessageReadTimeout
+                | PgWireError::InvalidMessageType(_)
+                | PgWireError::InvalidMessageLength(_)
+                | PgWireError::UnexpectedEndOfMessage
+                | PgWireError::InvalidCount(_)
+                | PgWireError::TrailingBytesInMessage(_)
+                | PgWireError::InvalidCompressedData(_) => SqlState::ProtocolViolation,
+                PgWireError::UnknownUser(_) => SqlState::InvalidAuthorizationSpecification,
+                // Internal error
+                _ => SqlState::InternalError,
+            };
+            let error_info = ErrorInfo::new(Severity::Fatal, code, error.to_string());
             socket
                 .send(PgWireBackendMessage::ErrorResponse(error_info.into()))
                 .await?;
@@ -176,94 +523,4256 @@ where
     Ok(())
 }
 
-async fn peek_for_sslrequest(
+fn is_starting_up(state: &PgWireConnectionState) -> bool {
+    matches!(
+        state,
+        PgWireConnectionState::AwaitingStartup | PgWireConnectionState::AuthenticationInProgress
+    )
+}
+
+/// Answer `GSSENCRequest` and `SSLRequest` sent by client before startup.
+/// GSSAPI encryption is always declined, and TLS is accepted when supported.
+async fn negotiate_encryption(
     tcp_socket: &mut TcpStream,
     ssl_supported: bool,
-) -> Result<bool, IOError> {
-    let mut ssl = false;
+) -> Result<ConnectionNegotiation, IOError> {
+    let mut negotiation = ConnectionNegotiation::default();
     let mut buf = [0u8; SslRequest::BODY_SIZE];
-    let mut buf = ReadBuf::new(&mut buf);
     loop {
+        let mut buf = ReadBuf::new(&mut buf);
         let size = poll_fn(|cx| tcp_socket.poll_peek(cx, &mut buf)).await?;
         if size == 0 {
             // the tcp_stream has ended
-            return Ok(false);
+            return Ok(negotiation);
         }
         if size == SslRequest::BODY_SIZE {
             let mut buf_ref = buf.filled();
             // skip first 4 bytes
             buf_ref.get_i32();
-            if buf_ref.get_i32() == SslRequest::BODY_MAGIC_NUMBER {
+            let code = buf_ref.get_i32();
+            // like libpq, GSSENCRequest may be followed by SSLRequest when
+            // it's declined
+            if code == GssEncRequest::BODY_MAGIC_NUMBER && !negotiation.gss_encryption_requested() {
+                tcp_socket
+                    .read_exact(&mut [0u8; GssEncRequest::BODY_SIZE])
+                    .await?;
+                negotiation.set_gss_encryption_requested(true);
+                tcp_socket.write_all(b"N").await?;
+                continue;
+            }
+            if code == SslRequest::BODY_MAGIC_NUMBER {
                 // the socket is sending sslrequest, read the first 8 bytes
                 // skip first 8 bytes
                 tcp_socket
                     .read_exact(&mut [0u8; SslRequest::BODY_SIZE])
                     .await?;
+                negotiation.set_ssl_requested(true);
                 // ssl configured
                 if ssl_supported {
-                    ssl = true;
+                    negotiation.set_ssl_accepted(true);
                     tcp_socket.write_all(b"S").await?;
                 } else {
                     tcp_socket.write_all(b"N").await?;
                 }
             }
 
-            return Ok(ssl);
+            return Ok(negotiation);
+        }
+    }
+}
+
+/// Options for the server and its connections.
+#[derive(Debug, Clone, new, Getters, Setters, MutGetters)]
+#[getset(get = "pub", set = "pub", get_mut = "pub")]
+pub struct ServerOptions {
+    /// handle for coordinating graceful shutdown
+    #[new(default)]
+    shutdown: Option<ShutdownHandle>,
+    /// options for TLS connections
+    #[new(default)]
+    tls: TlsOptions,
+    /// max size in bytes of encoded messages buffered for writing, 8KiB by
+    /// default. When the buffer is full, the connection waits for the client
+    /// to read before polling more rows from the result stream.
+    #[new(value = "8 * 1024")]
+    write_buffer_size: usize,
+    /// max time to receive the rest of a message once its first bytes
+    /// arrived, no limit by default. The time between messages is not
+    /// limited by this timeout. When it elapses, the connection is closed
+    /// with error `08P01`.
+    #[new(default)]
+    read_timeout: Option<Duration>,
+    /// max time to wait for each step before authentication succeeds: the
+    /// SSL negotiation, the TLS handshake and every message of startup and
+    /// authentication. No limit by default. The timeout restarts on each
+    /// step, so a slow exchange that keeps making progress, like SCRAM with a
+    /// high iteration count on client, is not timed out. When it elapses, the
+    /// connection is closed, with error `57014` once the startup message has
+    /// been read.
+    #[new(default)]
+    auth_timeout: Option<Duration>,
+    /// max time from accepting a connection until authentication succeeds,
+    /// no limit by default, like `authentication_timeout` of postgres. Caps
+    /// `auth_timeout` so that a client sending its messages slowly cannot
+    /// keep the connection unauthenticated forever.
+    #[new(default)]
+    max_auth_duration: Option<Duration>,
+    /// TCP keepalive of accepted connections, disabled by default. Enable it
+    /// to detect dead peers and to keep idle connections alive behind NAT
+    /// or load balancers that drop idle flows.
+    #[new(default)]
+    tcp_keepalive: Option<TcpKeepaliveOptions>,
+    /// set `TCP_NODELAY` on accepted connections, enabled by default like
+    /// postgres does, so that small responses are not delayed by Nagle's
+    /// algorithm.
+    #[new(value = "true")]
+    tcp_nodelay: bool,
+    /// size of `SO_RCVBUF` of accepted connections, system default when not
+    /// set
+    #[new(default)]
+    recv_buffer_size: Option<usize>,
+    /// size of `SO_SNDBUF` of accepted connections, system default when not
+    /// set
+    #[new(default)]
+    send_buffer_size: Option<usize>,
+    /// policy hook called for each accepted connection, before reading from
+    /// it
+    #[new(default)]
+    accept_handler: Option<Arc<dyn AcceptHandler>>,
+    /// max number of named prepared statements of a connection, and of its
+    /// named portals, no limit by default. A `Parse` or `Bind` that would
+    /// exceed it fails with error `54000`, so clients that never close their
+    /// statements cannot exhaust memory. The unnamed statement and portal are
+    /// not counted, nor are statements of other connections sharing the
+    /// `PortalStore`.
+    #[new(default)]
+    max_prepared_statements: Option<usize>,
+    /// registry for cancelling queries of connections, disabled by default.
+    /// When set, each connection gets a unique pid in `BackendKeyData`, and
+    /// `CancelRequest` from clients are handled.
+    #[new(default)]
+    cancellation_registry: Option<CancellationRegistry>,
+    /// registry of live connections and their activity, disabled by default
+    #[new(default)]
+    connection_registry: Option<ConnectionRegistry>,
+    /// only serve the simple query protocol, disabled by default. Messages of
+    /// the extended query protocol, like `Parse` and `Bind`, are rejected with
+    /// error `0A000`, and the rest of the pipeline is skipped until `Sync`.
+    #[new(default)]
+    simple_query_only: bool,
+    /// catch panics of handlers, disabled by default so that panics
+    /// propagate. When enabled, a panic while processing a query is reported
+    /// to client as error `XX000` and the connection is kept, like other
+    /// handler errors. A panic during startup or COPY, where the protocol
+    /// state cannot be recovered, closes the connection with a `FATAL`
+    /// error instead.
+    #[new(default)]
+    catch_handler_panics: bool,
+    /// compression algorithms offered to clients requesting it with startup
+    /// option `_pq_.compression`, none by default. See `compression` module
+    /// for the negotiation. Connections of other clients are not compressed.
+    #[new(default)]
+    compression: Vec<Arc<dyn CompressionAlgorithm>>,
+    /// accept `SET` of parameters not claimed by the application as a no-op,
+    /// disabled by default so that the query handler decides. When enabled,
+    /// a simple query `SET name = value` of a parameter neither claimed nor
+    /// reported to client is answered without calling the handler, and a
+    /// later `SHOW name` returns the value. `client_encoding` only accepts
+    /// `UTF8`. Queries are matched before the `QueryRewriter` of the
+    /// handler. See `LenientSetOptions`.
+    #[new(default)]
+    lenient_set: Option<LenientSetOptions>,
+    /// user of health check probes, like those of load balancers, disabled
+    /// by default. A startup message of this user is answered with
+    /// `AuthenticationOk` and `ReadyForQuery` without calling the startup
+    /// handler, and the connection is closed on the next message. Other
+    /// messages than `Terminate` get error `28000`, so the probe cannot run
+    /// queries.
+    #[new(default)]
+    health_check_user: Option<String>,
+}
+
+impl ServerOptions {
+    /// Apply TCP options to an accepted socket, before anything is read from
+    /// it or TLS is negotiated.
+    fn apply_socket_options(&self, tcp_socket: &TcpStream) -> Result<(), IOError> {
+        if let Some(ref keepalive) = self.tcp_keepalive {
+            keepalive.apply(tcp_socket)?;
+        }
+        tcp_socket.set_nodelay(self.tcp_nodelay)?;
+        let sock_ref = SockRef::from(tcp_socket);
+        if let Some(size) = self.recv_buffer_size {
+            sock_ref.set_recv_buffer_size(size)?;
         }
+        if let Some(size) = self.send_buffer_size {
+            sock_ref.set_send_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions::new()
     }
 }
 
-pub async fn process_socket<A, Q, EQ>(
+/// Decision of `AcceptHandler` on an accepted connection.
+#[derive(Debug)]
+pub enum AcceptDecision {
+    /// process the connection
+    Continue,
+    /// close the connection with the error
+    Reject(Box<ErrorInfo>),
+    /// process the connection only if the client starts TLS, close it with
+    /// error `28000` otherwise
+    RequireTls,
+}
+
+/// Connection policy, like IP allowlisting, forced TLS for some sources or
+/// connection accounting.
+///
+/// `on_accept` is called right after the TCP connection is accepted, before
+/// any bytes are read from client. Set it as `accept_handler` of
+/// `ServerOptions`.
+#[async_trait]
+pub trait AcceptHandler: Send + Sync {
+    async fn on_accept(&self, peer: SocketAddr) -> AcceptDecision;
+}
+
+impl fmt::Debug for dyn AcceptHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AcceptHandler")
+    }
+}
+
+/// Send `error_info` to client and close the connection, before startup.
+async fn reject_connection(
     mut tcp_socket: TcpStream,
-    tls_acceptor: Option<Arc<TlsAcceptor>>,
-    startup_handler: Arc<A>,
-    query_handler: Arc<Q>,
-    extended_query_handler: Arc<EQ>,
-) -> Result<(), IOError>
-where
-    A: StartupHandler + 'static,
-    Q: SimpleQueryHandler + 'static,
-    EQ: ExtendedQueryHandler + 'static,
-{
-    let addr = tcp_socket.peer_addr()?;
-    let ssl = peek_for_sslrequest(&mut tcp_socket, tls_acceptor.is_some()).await?;
+    error_info: ErrorInfo,
+) -> Result<(), IOError> {
+    let mut buf = BytesMut::new();
+    PgWireBackendMessage::ErrorResponse(error_info.into()).encode(&mut buf)?;
+    tcp_socket.write_all(&buf).await?;
+    tcp_socket.shutdown().await?;
+    // drain what the client has sent until it closes, closing with unread
+    // data resets the connection and the client may miss the error
+    let _ = time::timeout(
+        Duration::from_secs(1),
+        tokio::io::copy(&mut tcp_socket, &mut tokio::io::sink()),
+    )
+    .await;
+    Ok(())
+}
 
-    let client_info = ClientInfoHolder::new(addr, ssl);
-    if ssl {
-        // safe to unwrap tls_acceptor here
-        let ssl_socket = tls_acceptor.unwrap().accept(tcp_socket).await?;
-        let mut socket = Framed::new(ssl_socket, PgWireMessageServerCodec::new(client_info));
+/// TCP keepalive settings, applied to each accepted socket with
+/// `SO_KEEPALIVE`.
+///
+/// `interval` and `retries` fall back to system defaults when not set, and
+/// are ignored on platforms that can't configure them.
+#[derive(Debug, Clone, new, Getters, Setters, MutGetters)]
+#[getset(get = "pub", set = "pub", get_mut = "pub")]
+pub struct TcpKeepaliveOptions {
+    /// idle time before the first keepalive probe is sent
+    idle: Duration,
+    /// time between keepalive probes
+    #[new(default)]
+    interval: Option<Duration>,
+    /// number of unanswered probes before the connection is dropped
+    #[new(default)]
+    retries: Option<u32>,
+}
 
-        while let Some(Ok(msg)) = socket.next().await {
-            if let Err(e) = process_message(
-                msg,
-                &mut socket,
-                startup_handler.clone(),
-                query_handler.clone(),
-                extended_query_handler.clone(),
-            )
-            .await
-            {
-                process_error(&mut socket, e).await?;
+impl TcpKeepaliveOptions {
+    fn apply(&self, tcp_socket: &TcpStream) -> Result<(), IOError> {
+        #[allow(unused_mut)]
+        let mut keepalive = TcpKeepalive::new().with_time(self.idle);
+        #[cfg(any(
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "fuchsia",
+            target_os = "ios",
+            target_os = "linux",
+            target_os = "macos",
+            target_os = "netbsd",
+            target_os = "windows",
+        ))]
+        {
+            if let Some(interval) = self.interval {
+                keepalive = keepalive.with_interval(interval);
+            }
+            if let Some(retries) = self.retries {
+                keepalive = keepalive.with_retries(retries);
             }
         }
-    } else {
-        let mut socket = Framed::new(tcp_socket, PgWireMessageServerCodec::new(client_info));
+        SockRef::from(tcp_socket).set_tcp_keepalive(&keepalive)
+    }
+}
 
-        while let Some(Ok(msg)) = socket.next().await {
-            if let Err(e) = process_message(
-                msg,
-                &mut socket,
-                startup_handler.clone(),
-                query_handler.clone(),
-                extended_query_handler.clone(),
-            )
-            .await
-            {
-                process_error(&mut socket, e).await?;
+/// TLS protocol versions, ordered from oldest to newest.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    #[default]
+    Tls12,
+    Tls13,
+}
+
+impl TlsVersion {
+    fn from_protocol_version(v: ProtocolVersion) -> Option<TlsVersion> {
+        match v {
+            ProtocolVersion::TLSv1_2 => Some(TlsVersion::Tls12),
+            ProtocolVersion::TLSv1_3 => Some(TlsVersion::Tls13),
+            _ => None,
+        }
+    }
+
+    /// Name of the version, as `version` of postgres `pg_stat_ssl`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TlsVersion::Tls12 => "TLSv1.2",
+            TlsVersion::Tls13 => "TLSv1.3",
+        }
+    }
+}
+
+/// Options for TLS setup and negotiation.
+///
+/// Renegotiation is never supported by rustls, so it is always rejected and
+/// there is no option for it.
+#[derive(Debug, Default, Clone, new, Getters, Setters, MutGetters)]
+#[getset(get = "pub", set = "pub", get_mut = "pub")]
+pub struct TlsOptions {
+    /// the minimum TLS version accepted, TLS 1.2 by default
+    #[new(default)]
+    min_version: TlsVersion,
+}
+
+/// Create a `TlsAcceptor` from certificate chain and private key, that only
+/// negotiates TLS versions allowed by `options`.
+pub fn build_tls_acceptor(
+    certs: Vec<Certificate>,
+    key: PrivateKey,
+    options: &TlsOptions,
+) -> Result<TlsAcceptor, IOError> {
+    let versions: &[&'static tokio_rustls::rustls::SupportedProtocolVersion] =
+        match options.min_version {
+            TlsVersion::Tls12 => &[&version::TLS13, &version::TLS12],
+            TlsVersion::Tls13 => &[&version::TLS13],
+        };
+    let config = ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(versions)
+        .map_err(|e| IOError::new(ErrorKind::InvalidInput, e))?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| IOError::new(ErrorKind::InvalidInput, e))?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// A handle for graceful shutdown of the server.
+///
+/// Once shutdown is triggered, the server stops accepting new connections.
+/// Each existing connection finishes its current command, then it is told to
+/// disconnect by an `ErrorResponse` with code `57P01`, and closed.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    sender: Arc<watch::Sender<bool>>,
+}
+
+impl Default for ShutdownHandle {
+    fn default() -> Self {
+        ShutdownHandle::new()
+    }
+}
+
+impl ShutdownHandle {
+    pub fn new() -> ShutdownHandle {
+        let (sender, _) = watch::channel(false);
+        ShutdownHandle {
+            sender: Arc::new(sender),
+        }
+    }
+
+    /// Test if shutdown has been triggered
+    pub fn is_shutdown(&self) -> bool {
+        *self.sender.borrow()
+    }
+
+    /// Trigger shutdown. The returned future resolves when all connections
+    /// have been closed.
+    pub async fn shutdown(&self) {
+        self.sender.send_replace(true);
+        self.sender.closed().await;
+    }
+
+    fn subscribe(&self) -> ShutdownSignal {
+        ShutdownSignal(Some(self.sender.subscribe()))
+    }
+}
+
+/// Receiving side of `ShutdownHandle`, held by each connection and the accept
+/// loop
+struct ShutdownSignal(Option<watch::Receiver<bool>>);
+
+impl ShutdownSignal {
+    fn is_shutdown(&self) -> bool {
+        self.0.as_ref().map(|rx| *rx.borrow()).unwrap_or(false)
+    }
+
+    /// Resolves when shutdown is triggered, or pending forever if shutdown not
+    /// configured.
+    async fn wait(&mut self) {
+        if let Some(ref mut rx) = self.0 {
+            while !*rx.borrow_and_update() {
+                if rx.changed().await.is_err() {
+                    break;
+                }
+            }
+            if *rx.borrow() {
+                return;
             }
         }
+        future::pending::<()>().await
     }
+}
 
-    Ok(())
+/// Registry of connections for cancelling their running queries, keyed by
+/// the pid sent to client in `BackendKeyData`.
+///
+/// Set it as `cancellation_registry` of `ServerOptions`. Each message from
+/// client is then processed under a new `CancellationToken`, and `cancel`
+/// triggers the token of the message being processed on the connection. The
+/// handler future, including the stream of rows being sent, is dropped at
+/// its next await point, and client receives error `57014`. A
+/// `CancelRequest` from client with matching pid and secret key cancels the
+/// same way.
+///
+/// Handlers doing long work between await points, or in spawned tasks, should
+/// observe the cancellation themselves. The token is in `Extensions` of the
+/// client while a message is processed:
+///
+/// ```no_run
+/// # use pgwire::api::ClientInfo;
+/// # use pgwire::tokio::CancellationToken;
+/// # fn scan<C: ClientInfo>(client: &C) {
+/// let token = client.extensions().get::<CancellationToken>().cloned();
+/// for _batch in 0..1024 {
+///     if token.as_ref().map_or(false, |t| t.is_cancelled()) {
+///         break;
+///     }
+///     // ... process the batch
+/// }
+/// # }
+/// ```
+///
+/// Async work, like a spawned task, can also `select!` on
+/// `token.cancelled()`.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationRegistry {
+    inner: Arc<CancellationRegistryInner>,
+}
+
+#[derive(Debug, Default)]
+struct CancellationRegistryInner {
+    last_pid: AtomicI32,
+    connections: Mutex<HashMap<i32, RegisteredConnection>>,
+}
+
+#[derive(Debug)]
+struct RegisteredConnection {
+    secret_key: i32,
+    /// token of the message being processed
+    token: Option<CancellationToken>,
+}
+
+impl CancellationRegistry {
+    pub fn new() -> CancellationRegistry {
+        CancellationRegistry::default()
+    }
+
+    /// Cancel the query running on connection `pid`. Returns `false` if the
+    /// connection is not found or idle.
+    pub fn cancel(&self, pid: i32) -> bool {
+        let connections = self.inner.connections.lock().unwrap();
+        match connections.get(&pid).and_then(|c| c.token.as_ref()) {
+            Some(token) => {
+                token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Cancel the query running on connection `pid`, like `cancel`, if
+    /// `secret_key` matches the one sent to its client.
+    pub fn cancel_with_key(&self, pid: i32, secret_key: i32) -> bool {
+        let matched = self
+            .inner
+            .connections
+            .lock()
+            .unwrap()
+            .get(&pid)
+            .map_or(false, |c| c.secret_key == secret_key);
+        matched && self.cancel(pid)
+    }
+
+    /// Number of registered connections
+    pub fn len(&self) -> usize {
+        self.inner.connections.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn register(&self) -> ConnectionCancellation {
+        let mut connections = self.inner.connections.lock().unwrap();
+        let pid = loop {
+            let pid = self
+                .inner
+                .last_pid
+                .fetch_add(1, Ordering::Relaxed)
+                .wrapping_add(1);
+            if pid > 0 && !connections.contains_key(&pid) {
+                break pid;
+            }
+        };
+        let key = BackendKey::new(pid, rand::random::<i32>());
+        connections.insert(
+            pid,
+            RegisteredConnection {
+                secret_key: *key.secret_key(),
+                token: None,
+            },
+        );
+        ConnectionCancellation {
+            registry: self.clone(),
+            key,
+        }
+    }
+}
+
+/// Registration of a connection in `CancellationRegistry`, removed on drop
+#[derive(Debug)]
+struct ConnectionCancellation {
+    registry: CancellationRegistry,
+    key: BackendKey,
+}
+
+impl ConnectionCancellation {
+    /// Create the token for processing a message
+    fn start(&self) -> CancellationToken {
+        let token = CancellationToken::new();
+        if let Some(c) = self
+            .registry
+            .inner
+            .connections
+            .lock()
+            .unwrap()
+            .get_mut(self.key.pid())
+        {
+            c.token = Some(token.clone());
+        }
+        token
+    }
+
+    fn finish(&self) {
+        if let Some(c) = self
+            .registry
+            .inner
+            .connections
+            .lock()
+            .unwrap()
+            .get_mut(self.key.pid())
+        {
+            c.token = None;
+        }
+    }
+}
+
+impl Drop for ConnectionCancellation {
+    fn drop(&mut self) {
+        if let Ok(mut connections) = self.registry.inner.connections.lock() {
+            connections.remove(self.key.pid());
+        }
+    }
+}
+
+impl ServerOptions {
+    /// Limits of a connection accepted at `accepted_at`
+    fn connection_limits(&self, accepted_at: Instant) -> ConnectionLimits {
+        ConnectionLimits {
+            read_timeout: self.read_timeout,
+            auth_timeout: self.auth_timeout,
+            auth_deadline: self
+                .max_auth_duration
+                .map(|duration| accepted_at + duration),
+            max_prepared_statements: self.max_prepared_statements,
+            cancellation_registry: self.cancellation_registry.clone(),
+            connection_registry: self.connection_registry.clone(),
+            simple_query_only: self.simple_query_only,
+            catch_handler_panics: self.catch_handler_panics,
+            compression: self.compression.clone(),
+            lenient_set: self.lenient_set.clone(),
+            health_check_user: self.health_check_user.clone(),
+        }
+    }
+
+    fn shutdown_signal(&self) -> ShutdownSignal {
+        self.shutdown
+            .as_ref()
+            .map(ShutdownHandle::subscribe)
+            .unwrap_or(ShutdownSignal(None))
+    }
+}
+
+/// Read next message from socket. Fails with `MessageReadTimeout` when part of
+/// a message has been received, but not the whole message within
+/// `read_timeout`.
+async fn next_message<S>(
+    socket: &mut Framed<S, PgWireMessageServerCodec>,
+    read_timeout: Option<Duration>,
+) -> Option<PgWireResult<PgWireFrontendMessage>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    let mut deadline: Option<Pin<Box<Sleep>>> = None;
+    poll_fn(|cx| {
+        if let Poll::Ready(msg) = socket.poll_next_unpin(cx) {
+            return Poll::Ready(msg);
+        }
+        if let Some(timeout) = read_timeout {
+            // the timer starts when the first bytes of a message are buffered
+            if !socket.read_buffer().is_empty() {
+                let sleep = deadline.get_or_insert_with(|| Box::pin(time::sleep(timeout)));
+                if sleep.as_mut().poll(cx).is_ready() {
+                    return Poll::Ready(Some(Err(PgWireError::MessageReadTimeout)));
+                }
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+/// Run a step of connection setup before startup message, failing when
+/// `auth_timeout` or `max_auth_duration` elapses
+async fn within_auth_deadline<F, T>(limits: &ConnectionLimits, step: F) -> Result<T, IOError>
+where
+    F: Future<Output = Result<T, IOError>>,
+{
+    match limits.auth_step_deadline() {
+        Some(deadline) => time::timeout_at(deadline, step).await.map_err(|_| {
+            IOError::new(
+                ErrorKind::TimedOut,
+                "canceling authentication due to timeout",
+            )
+        })?,
+        None => step.await,
+    }
+}
+
+/// Timeouts, limits, cancellation and registration of a connection, from
+/// `ServerOptions`
+#[derive(Debug, Default, Clone)]
+struct ConnectionLimits {
+    read_timeout: Option<Duration>,
+    /// max time to wait for each step of authentication
+    auth_timeout: Option<Duration>,
+    /// `max_auth_duration` after the connection was accepted
+    auth_deadline: Option<Instant>,
+    max_prepared_statements: Option<usize>,
+    cancellation_registry: Option<CancellationRegistry>,
+    connection_registry: Option<ConnectionRegistry>,
+    simple_query_only: bool,
+    catch_handler_panics: bool,
+    compression: Vec<Arc<dyn CompressionAlgorithm>>,
+    lenient_set: Option<LenientSetOptions>,
+    health_check_user: Option<String>,
+}
+
+impl ConnectionLimits {
+    /// Deadline of the next step of authentication starting now, the earlier
+    /// of `auth_timeout` from now and `auth_deadline`
+    fn auth_step_deadline(&self) -> Option<Instant> {
+        let step_deadline = self.auth_timeout.map(|timeout| Instant::now() + timeout);
+        match (step_deadline, self.auth_deadline) {
+            (Some(step), Some(overall)) => Some(step.min(overall)),
+            (step, overall) => step.or(overall),
+        }
+    }
+}
+
+fn query_canceled() -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        Severity::Error,
+        SqlState::QueryCanceled,
+        "canceling statement due to user request",
+    )))
+}
+
+/// Negotiate protocol version and options of `startup`, like postgres does
+/// before authentication. Protocol options starting with `_pq_.` are removed
+/// from startup parameters, and the agreed compression is returned.
+async fn negotiate_protocol<S>(
+    socket: &mut Framed<S, PgWireMessageServerCodec>,
+    startup: &mut Startup,
+    limits: &ConnectionLimits,
+) -> Result<Option<Arc<dyn CompressionAlgorithm>>, IOError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    let options: Vec<String> = startup
+        .parameters()
+        .keys()
+        .filter(|key| key.starts_with("_pq_."))
+        .cloned()
+        .collect();
+
+    let mut unrecognized = Vec::new();
+    let mut compression = None;
+    let mut compression_requested = false;
+    for option in options {
+        // safe to unwrap, the key is from parameters
+        let value = startup.parameters_mut().remove(&option).unwrap();
+        if option == STARTUP_OPTION_COMPRESSION && !limits.compression.is_empty() {
+            compression_requested = true;
+            compression = compression::select_algorithm(&limits.compression, &value);
+        } else {
+            unrecognized.push(option);
+        }
+    }
+
+    if *startup.protocol_number_minor() > 0 || !unrecognized.is_empty() {
+        socket
+            .feed(PgWireBackendMessage::NegotiateProtocolVersion(
+                NegotiateProtocolVersion::new(0, unrecognized),
+            ))
+            .await?;
+    }
+    if compression_requested {
+        let name = compression
+            .as_ref()
+            .map_or("none", |algorithm| algorithm.name())
+            .to_owned();
+        socket
+            .feed(PgWireBackendMessage::ParameterStatus(ParameterStatus::new(
+                STARTUP_OPTION_COMPRESSION.to_owned(),
+                name,
+            )))
+            .await?;
+    }
+    Ok(compression)
+}
+
+/// Error of a handler panic. The connection is closed when the panic left
+/// it in startup or COPY.
+fn handler_panicked(panic: Box<dyn Any + Send>, state: &PgWireConnectionState) -> PgWireError {
+    let message = panic
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_owned());
+    match state {
+        PgWireConnectionState::AwaitingStartup
+        | PgWireConnectionState::AuthenticationInProgress
+        | PgWireConnectionState::CopyInProgress(_) => PgWireError::HandlerPanicked(message),
+        _ => PgWireError::UserError(Box::new(ErrorInfo::new(
+            Severity::Error,
+            SqlState::InternalError,
+            format!("handler panicked: {message}"),
+        ))),
+    }
+}
+
+/// Whether `error` is from the peer closing the connection
+fn is_disconnect(error: &IOError) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset | ErrorKind::BrokenPipe
+    )
+}
+
+/// Answer a health check probe without authentication, see
+/// `health_check_user` of `ServerOptions`. The connection is closed after
+/// the next message from client, or when `auth_timeout` or
+/// `max_auth_duration` elapses.
+async fn serve_health_check<S>(
+    socket: &mut Framed<CompressedWriter<S>, PgWireMessageServerCodec>,
+    compression: Option<Arc<dyn CompressionAlgorithm>>,
+    limits: &ConnectionLimits,
+) -> Result<(), IOError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    socket.set_state(PgWireConnectionState::ReadyForQuery);
+    socket
+        .feed(PgWireBackendMessage::Authentication(Authentication::Ok))
+        .await?;
+    socket
+        .send(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(
+            TransactionStatus::Idle.to_ready_status(),
+        )))
+        .await?;
+    if let Some(compression) = compression {
+        enable_compression(socket, compression).await?;
+    }
+
+    let msg = tokio::select! {
+        _ = sleep_until_or_pending(limits.auth_step_deadline()) => None,
+        msg = next_message(socket, limits.read_timeout) => msg,
+    };
+    match msg {
+        Some(Ok(PgWireFrontendMessage::Terminate(_)))
+        | Some(Err(PgWireError::IoError(_)))
+        | None => {}
+        Some(_) => {
+            let error_info = ErrorInfo::new(
+                Severity::Fatal,
+                SqlState::InvalidAuthorizationSpecification,
+                "health check connections cannot run commands",
+            );
+            socket
+                .send(PgWireBackendMessage::ErrorResponse(error_info.into()))
+                .await?;
+        }
+    }
+    socket.close().await
+}
+
+/// Compress the byte stream in both directions from now on, once messages
+/// already written are flushed uncompressed
+async fn enable_compression<S>(
+    socket: &mut Framed<CompressedWriter<S>, PgWireMessageServerCodec>,
+    algorithm: Arc<dyn CompressionAlgorithm>,
+) -> Result<(), IOError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    socket.flush().await?;
+    socket.get_mut().enable_compression(algorithm.clone());
+    socket.codec_mut().enable_compression(algorithm);
+    Ok(())
+}
+
+/// Wrap the stream of `socket` to compress messages written between flushes
+/// into one block, once compression is enabled
+fn with_compressed_writer<S>(
+    socket: Framed<S, PgWireMessageServerCodec>,
+) -> Framed<CompressedWriter<S>, PgWireMessageServerCodec>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let backpressure_boundary = socket.backpressure_boundary();
+    let parts = socket.into_parts();
+    let mut wrapped = FramedParts::new(CompressedWriter::new(parts.io), parts.codec);
+    wrapped.read_buf = parts.read_buf;
+    wrapped.write_buf = parts.write_buf;
+    let mut socket = Framed::from_parts(wrapped);
+    socket.set_backpressure_boundary(backpressure_boundary);
+    socket
+}
+
+async fn sleep_until_or_pending(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => future::pending().await,
+    }
+}
+
+async fn process_connection<S, A, Q, EQ, CH>(
+    socket: Framed<S, PgWireMessageServerCodec>,
+    startup_handler: Arc<A>,
+    query_handler: Arc<Q>,
+    extended_query_handler: Arc<EQ>,
+    copy_handler: Arc<CH>,
+    mut shutdown: ShutdownSignal,
+    limits: ConnectionLimits,
+) -> Result<(), IOError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    A: StartupHandler + 'static,
+    Q: SimpleQueryHandler + 'static,
+    EQ: ExtendedQueryHandler + 'static,
+    CH: CopyHandler + 'static,
+{
+    let mut socket = with_compressed_writer(socket);
+    let cancellation = limits
+        .cancellation_registry
+        .as_ref()
+        .map(CancellationRegistry::register);
+    if let Some(ref cancellation) = cancellation {
+        socket.extensions_mut().insert(cancellation.key);
+    }
+    let mut activity = limits.connection_registry.as_ref().map(|registry| {
+        let pid = cancellation
+            .as_ref()
+            .map(|cancellation| *cancellation.key.pid());
+        registry.register(*socket.socket_addr(), pid)
+    });
+    if let (Some(ref activity), None) = (&activity, &cancellation) {
+        // the pid of `BackendKeyData` identifies the connection in the
+        // registry
+        socket
+            .extensions_mut()
+            .insert(BackendKey::new(activity.pid(), rand::random::<i32>()));
+    }
+    let mut pending_compression = None;
+
+    loop {
+        // restarted on each message until authenticated
+        let auth_deadline = match socket.state() {
+            PgWireConnectionState::AwaitingStartup
+            | PgWireConnectionState::AuthenticationInProgress => limits.auth_step_deadline(),
+            _ => None,
+        };
+        let msg = tokio::select! {
+            biased;
+            _ = sleep_until_or_pending(auth_deadline) => {
+                let error_info = ErrorInfo::new(
+                    Severity::Fatal,
+                    SqlState::QueryCanceled,
+                    "canceling authentication due to timeout",
+                );
+                socket
+                    .send(PgWireBackendMessage::ErrorResponse(error_info.into()))
+                    .await?;
+                socket.close().await?;
+                break;
+            }
+            _ = shutdown.wait() => {
+                let error_info = ErrorInfo::new(
+                    Severity::Fatal,
+                    SqlState::AdminShutdown,
+                    "terminating connection due to administrator command",
+                );
+                socket
+                    .send(PgWireBackendMessage::ErrorResponse(error_info.into()))
+                    .await?;
+                socket.close().await?;
+                break;
+            }
+            msg = next_message(&mut socket, limits.read_timeout) => msg,
+        };
+
+        match msg {
+            Some(Ok(PgWireFrontendMessage::CancelRequest(cancel))) => {
+                // the connection is closed without response
+                if let Some(ref registry) = limits.cancellation_registry {
+                    registry.cancel_with_key(*cancel.pid(), *cancel.secret_key());
+                }
+                break;
+            }
+            Some(Ok(mut msg)) => {
+                if let PgWireFrontendMessage::Startup(ref mut startup) = msg {
+                    let version = (i32::from(*startup.protocol_number_major()) << 16)
+                        | i32::from(*startup.protocol_number_minor());
+                    let mut negotiation = socket
+                        .extensions()
+                        .get::<ConnectionNegotiation>()
+                        .copied()
+                        .unwrap_or_default();
+                    negotiation.set_protocol_version(Some(version));
+                    socket.extensions_mut().insert(negotiation);
+                    pending_compression = negotiate_protocol(&mut socket, startup, &limits).await?;
+                    let user = startup.parameters().get(METADATA_USER);
+                    if user.is_some() && user == limits.health_check_user.as_ref() {
+                        return serve_health_check(&mut socket, pending_compression, &limits).await;
+                    }
+                }
+                let extended_query = is_extended_query_message(&msg)
+                    || matches!(socket.state(), PgWireConnectionState::CopyInProgress(true));
+                if let Some(ref mut activity) = activity {
+                    activity.start(&msg);
+                }
+                let token = cancellation.as_ref().map(ConnectionCancellation::start);
+                if let Some(ref token) = token {
+                    socket.extensions_mut().insert(token.clone());
+                }
+                let process = process_message(
+                    msg,
+                    &mut socket,
+                    startup_handler.clone(),
+                    query_handler.clone(),
+                    extended_query_handler.clone(),
+                    copy_handler.clone(),
+                    &limits,
+                );
+                let catch_handler_panics = limits.catch_handler_panics;
+                let process = async move {
+                    if catch_handler_panics {
+                        AssertUnwindSafe(process).catch_unwind().await
+                    } else {
+                        Ok(process.await)
+                    }
+                };
+                let result = match token {
+                    Some(ref token) => tokio::select! {
+                        biased;
+                        _ = token.cancelled() => Ok(Err(query_canceled())),
+                        result = process => result,
+                    },
+                    None => process.await,
+                };
+                let result =
+                    result.unwrap_or_else(|panic| Err(handler_panicked(panic, socket.state())));
+                if let Some(ref cancellation) = cancellation {
+                    cancellation.finish();
+                    socket.extensions_mut().remove::<CancellationToken>();
+                }
+                let succeeded = result.is_ok();
+                if let Err(e) = result {
+                    let starting_up = is_starting_up(socket.state());
+                    process_error(&mut socket, e, extended_query).await?;
+                    if starting_up {
+                        break;
+                    }
+                }
+                if let Some(ref mut activity) = activity {
+                    activity.finish(&socket, *socket.codec().ready_for_query_sent(), succeeded);
+                }
+                // compression starts after the `ReadyForQuery` ending startup
+                if matches!(socket.state(), PgWireConnectionState::ReadyForQuery) {
+                    if let Some(compression) = pending_compression.take() {
+                        enable_compression(&mut socket, compression).await?;
+                    }
+                }
+            }
+            Some(Err(PgWireError::IoError(_))) | None => break,
+            Some(Err(e)) => {
+                // malformed message from client, the connection cannot be
+                // recovered from this state
+                process_error(&mut socket, e, false).await?;
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn process_socket<A, Q, EQ, CH>(
+    tcp_socket: TcpStream,
+    tls_acceptor: Option<Arc<TlsAcceptor>>,
+    startup_handler: Arc<A>,
+    query_handler: Arc<Q>,
+    extended_query_handler: Arc<EQ>,
+    copy_handler: Arc<CH>,
+) -> Result<(), IOError>
+where
+    A: StartupHandler + 'static,
+    Q: SimpleQueryHandler + 'static,
+    EQ: ExtendedQueryHandler + 'static,
+    CH: CopyHandler + 'static,
+{
+    process_socket_with_options(
+        tcp_socket,
+        tls_acceptor,
+        startup_handler,
+        query_handler,
+        extended_query_handler,
+        copy_handler,
+        Arc::new(ServerOptions::default()),
+    )
+    .await
+}
+
+/// Process socket with given `ServerOptions`
+///
+/// A client closing the connection before its startup message, like a probe
+/// only sending `SSLRequest`, is not an error.
+pub async fn process_socket_with_options<A, Q, EQ, CH>(
+    mut tcp_socket: TcpStream,
+    tls_acceptor: Option<Arc<TlsAcceptor>>,
+    startup_handler: Arc<A>,
+    query_handler: Arc<Q>,
+    extended_query_handler: Arc<EQ>,
+    copy_handler: Arc<CH>,
+    options: Arc<ServerOptions>,
+) -> Result<(), IOError>
+where
+    A: StartupHandler + 'static,
+    Q: SimpleQueryHandler + 'static,
+    EQ: ExtendedQueryHandler + 'static,
+    CH: CopyHandler + 'static,
+{
+    let accepted_at = Instant::now();
+    let shutdown = options.shutdown_signal();
+    if shutdown.is_shutdown() {
+        // refuse new connection when server is shutting down
+        return Ok(());
+    }
+    let limits = options.connection_limits(accepted_at);
+
+    options.apply_socket_options(&tcp_socket)?;
+
+    let addr = tcp_socket.peer_addr()?;
+    let require_tls = match options.accept_handler() {
+        Some(handler) => match handler.on_accept(addr).await {
+            AcceptDecision::Continue => false,
+            AcceptDecision::RequireTls => true,
+            AcceptDecision::Reject(error_info) => {
+                return reject_connection(tcp_socket, *error_info).await;
+            }
+        },
+        None => false,
+    };
+
+    let negotiation = match within_auth_deadline(
+        &limits,
+        negotiate_encryption(&mut tcp_socket, tls_acceptor.is_some()),
+    )
+    .await
+    {
+        Ok(negotiation) => negotiation,
+        // probes closing the connection before startup are not errors
+        Err(e) if is_disconnect(&e) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let ssl = negotiation.ssl_accepted();
+    if require_tls && !ssl {
+        let error_info = ErrorInfo::new(
+            Severity::Fatal,
+            SqlState::InvalidAuthorizationSpecification,
+            format!("SSL connection is required for host {}", addr.ip()),
+        );
+        return reject_connection(tcp_socket, error_info).await;
+    }
+
+    let mut client_info = ClientInfoHolder::new(addr, ssl);
+    // kept for diagnostics, see `ConnectionNegotiation`
+    client_info.extensions_mut().insert(negotiation);
+    if ssl {
+        // safe to unwrap tls_acceptor here
+        // the client is expecting TLS now, on handshake failure the
+        // connection is closed instead of falling back to plaintext
+        let handshake = tls_acceptor.unwrap().accept(tcp_socket);
+        let ssl_socket = within_auth_deadline(&limits, async {
+            match handshake.await {
+                Ok(ssl_socket) => Ok(Some(ssl_socket)),
+                Err(e) if is_disconnect(&e) => Ok(None),
+                Err(e) => Err(IOError::new(
+                    ErrorKind::ConnectionAborted,
+                    format!("TLS handshake failed: {e}"),
+                )),
+            }
+        })
+        .await?;
+        let mut ssl_socket = match ssl_socket {
+            Some(ssl_socket) => ssl_socket,
+            // closed by client before the handshake, like a probe
+            None => return Ok(()),
+        };
+
+        // also enforce minimum version for acceptors not created by
+        // `build_tls_acceptor`
+        let session = ssl_socket.get_ref().1;
+        let negotiated = session
+            .protocol_version()
+            .and_then(TlsVersion::from_protocol_version);
+        let cipher_suite = session
+            .negotiated_cipher_suite()
+            .map(|suite| {
+                let suite = suite.suite();
+                suite
+                    .as_str()
+                    .map_or_else(|| format!("{suite:?}"), str::to_owned)
+            })
+            .unwrap_or_default();
+        let negotiated = match negotiated {
+            Some(v) if v >= *options.tls().min_version() => v,
+            _ => {
+                ssl_socket.shutdown().await?;
+                return Err(IOError::new(
+                    ErrorKind::PermissionDenied,
+                    format!("TLS version {negotiated:?} is not allowed"),
+                ));
+            }
+        };
+        // keep the negotiated parameters for auditing, see `TlsSession`
+        client_info.extensions_mut().insert(TlsSession::new(
+            negotiated.as_str().to_owned(),
+            cipher_suite,
+        ));
+
+        let mut socket = Framed::new(ssl_socket, PgWireMessageServerCodec::new(client_info));
+        socket.set_backpressure_boundary(*options.write_buffer_size());
+        process_connection(
+            socket,
+            startup_handler,
+            query_handler,
+            extended_query_handler,
+            copy_handler,
+            shutdown,
+            limits,
+        )
+        .await
+    } else {
+        let mut socket = Framed::new(tcp_socket, PgWireMessageServerCodec::new(client_info));
+        socket.set_backpressure_boundary(*options.write_buffer_size());
+        process_connection(
+            socket,
+            startup_handler,
+            query_handler,
+            extended_query_handler,
+            copy_handler,
+            shutdown,
+            limits,
+        )
+        .await
+    }
+}
+
+/// How long to wait before accepting again after `error`, `None` to retry
+/// right away. Errors of a single connection, like one reset by peer before
+/// accepted, don't affect others. Other errors are mostly from running out
+/// of resources, like file descriptors, so wait for connections to close.
+fn accept_backoff(error: &IOError) -> Option<Duration> {
+    match error.kind() {
+        ErrorKind::ConnectionAborted
+        | ErrorKind::ConnectionReset
+        | ErrorKind::ConnectionRefused
+        | ErrorKind::Interrupted
+        | ErrorKind::WouldBlock => None,
+        _ => Some(Duration::from_secs(1)),
+    }
+}
+
+/// Accept connections from `listener` and serve them with handlers created by
+/// given `MakeHandler`s.
+///
+/// This function returns when shutdown is triggered from the `ShutdownHandle`
+/// in `options`. Use `ShutdownHandle::shutdown` to wait for all connections
+/// to close. Errors accepting connections are logged and don't stop the
+/// server, on errors like running out of file descriptors it waits a moment
+/// before accepting again.
+pub async fn serve<MA, MQ, MEQ, MCH, A, Q, EQ, CH>(
+    listener: TcpListener,
+    tls_acceptor: Option<Arc<TlsAcceptor>>,
+    startup_handler: Arc<MA>,
+    query_handler: Arc<MQ>,
+    extended_query_handler: Arc<MEQ>,
+    copy_handler: Arc<MCH>,
+    options: Arc<ServerOptions>,
+) -> Result<(), IOError>
+where
+    MA: MakeHandler<Handler = Arc<A>>,
+    MQ: MakeHandler<Handler = Arc<Q>>,
+    MEQ: MakeHandler<Handler = Arc<EQ>>,
+    MCH: MakeHandler<Handler = Arc<CH>>,
+    A: StartupHandler + 'static,
+    Q: SimpleQueryHandler + 'static,
+    EQ: ExtendedQueryHandler + 'static,
+    CH: CopyHandler + 'static,
+{
+    let mut shutdown = options.shutdown_signal();
+    loop {
+        let incoming = tokio::select! {
+            biased;
+            _ = shutdown.wait() => break,
+            incoming = listener.accept() => incoming,
+        };
+        let tcp_socket = match incoming {
+            Ok((tcp_socket, _)) => tcp_socket,
+            Err(e) => match accept_backoff(&e) {
+                None => {
+                    log::debug!("failed to accept connection: {e}");
+                    continue;
+                }
+                Some(backoff) => {
+                    log::error!("failed to accept connection: {e}, retrying in {backoff:?}");
+                    tokio::select! {
+                        biased;
+                        _ = shutdown.wait() => break,
+                        _ = time::sleep(backoff) => continue,
+                    }
+                }
+            },
+        };
+
+        let tls_acceptor_ref = tls_acceptor.clone();
+        let startup_handler_ref = startup_handler.make();
+        let query_handler_ref = query_handler.make();
+        let extended_query_handler_ref = extended_query_handler.make();
+        let copy_handler_ref = copy_handler.make();
+        let options_ref = options.clone();
+        tokio::spawn(async move {
+            process_socket_with_options(
+                tcp_socket,
+                tls_acceptor_ref,
+                startup_handler_ref,
+                query_handler_ref,
+                extended_query_handler_ref,
+                copy_handler_ref,
+                options_ref,
+            )
+            .await
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fmt::Debug;
+
+    use async_trait::async_trait;
+    use bytes::{BufMut, Bytes, BytesMut};
+    use futures::Sink;
+
+    use super::*;
+    use crate::api::auth::noop::NoopStartupHandler;
+    use crate::api::auth::{finish_authentication, DefaultServerParameterProvider};
+    use crate::api::copy::{CopyCsvOptions, CopyFormat, CopyRowParser, NoopCopyHandler};
+    use crate::api::portal::Portal;
+    use crate::api::query::{
+        send_ready_for_query, PlaceholderExtendedQueryHandler, StatementOrPortal,
+    };
+    use crate::api::results::{CopyOutData, CopyResponse};
+    use crate::api::results::{
+        DataRowEncoder, DescribeResponse, FieldFormat, FieldInfo, QueryResponse, Response, Tag,
+    };
+    use crate::api::stmt::NoopQueryParser;
+    use crate::api::store::{MemPortalStore, PortalStore};
+    use crate::api::{StatelessMakeHandler, DEFAULT_NAME};
+    use crate::compression::InvertCompression;
+    use crate::messages::copy::{CopyData, CopyDone, CopyFail};
+    use crate::messages::extendedquery::{
+        Bind, Close, Describe, Execute, Parse, Sync as PgSync, TARGET_TYPE_BYTE_PORTAL,
+        TARGET_TYPE_BYTE_STATEMENT,
+    };
+    use crate::messages::response::ErrorResponse;
+    use crate::messages::simplequery::Query;
+    use crate::messages::startup::{Password, PasswordMessageFamily};
+    use crate::messages::terminate::Terminate;
+
+    struct EmptyQueryHandler;
+
+    #[async_trait]
+    impl SimpleQueryHandler for EmptyQueryHandler {
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &C,
+            _query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            Ok(vec![Response::Execution(Tag::new_for_execution(
+                "OK", None,
+            ))])
+        }
+    }
+
+    async fn read_message<S>(stream: &mut S, buf: &mut BytesMut) -> PgWireBackendMessage
+    where
+        S: AsyncRead + Unpin,
+    {
+        loop {
+            if let Some(msg) = PgWireBackendMessage::decode(buf).unwrap() {
+                return msg;
+            }
+            assert!(stream.read_buf(buf).await.unwrap() > 0, "connection closed");
+        }
+    }
+
+    async fn connect(addr: std::net::SocketAddr) -> (TcpStream, BytesMut) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut startup = Startup::new();
+        startup
+            .parameters_mut()
+            .insert("user".to_owned(), "tomcat".to_owned());
+        let mut buf = BytesMut::new();
+        startup.encode(&mut buf).unwrap();
+        stream.write_all(&buf).await.unwrap();
+
+        let mut buf = BytesMut::new();
+        while !matches!(
+            read_message(&mut stream, &mut buf).await,
+            PgWireBackendMessage::ReadyForQuery(_)
+        ) {}
+        (stream, buf)
+    }
+
+    fn error_code(error: &ErrorResponse) -> &str {
+        &error.fields().iter().find(|f| f.0 == b'C').unwrap().1
+    }
+
+    /// The server end of a connection, for a client in `state`
+    fn server_socket<S>(
+        stream: S,
+        state: PgWireConnectionState,
+    ) -> Framed<S, PgWireMessageServerCodec> {
+        let mut client_info = ClientInfoHolder::new("127.0.0.1:5432".parse().unwrap(), false);
+        client_info.set_state(state);
+        Framed::new(stream, PgWireMessageServerCodec::new(client_info))
+    }
+
+    /// Serve `socket` with the handlers, without shutdown signal
+    fn spawn_connection<S, A, Q, EQ, CH>(
+        socket: Framed<S, PgWireMessageServerCodec>,
+        startup_handler: Arc<A>,
+        query_handler: Arc<Q>,
+        extended_query_handler: Arc<EQ>,
+        copy_handler: Arc<CH>,
+        limits: ConnectionLimits,
+    ) -> tokio::task::JoinHandle<Result<(), IOError>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static,
+        A: StartupHandler + 'static,
+        Q: SimpleQueryHandler + 'static,
+        EQ: ExtendedQueryHandler + 'static,
+        CH: CopyHandler + 'static,
+    {
+        tokio::spawn(process_connection(
+            socket,
+            startup_handler,
+            query_handler,
+            extended_query_handler,
+            copy_handler,
+            ShutdownSignal(None),
+            limits,
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_tcp_keepalive() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        assert!(!SockRef::from(&socket).keepalive().unwrap());
+
+        let mut keepalive = TcpKeepaliveOptions::new(Duration::from_secs(60));
+        keepalive.set_interval(Some(Duration::from_secs(10)));
+        keepalive.set_retries(Some(3));
+        keepalive.apply(&socket).unwrap();
+
+        let sock_ref = SockRef::from(&socket);
+        assert!(sock_ref.keepalive().unwrap());
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(Duration::from_secs(60), sock_ref.keepalive_time().unwrap());
+            assert_eq!(
+                Duration::from_secs(10),
+                sock_ref.keepalive_interval().unwrap()
+            );
+            assert_eq!(3, sock_ref.keepalive_retries().unwrap());
+        }
+        drop(client);
+    }
+
+    #[test]
+    fn test_accept_backoff() {
+        assert_eq!(
+            None,
+            accept_backoff(&IOError::from(ErrorKind::ConnectionAborted))
+        );
+        assert_eq!(
+            None,
+            accept_backoff(&IOError::from(ErrorKind::ConnectionReset))
+        );
+        // EMFILE
+        assert!(accept_backoff(&IOError::from_raw_os_error(24)).is_some());
+        assert!(accept_backoff(&IOError::from(ErrorKind::OutOfMemory)).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_socket_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+
+        let mut options = ServerOptions::new();
+        options.apply_socket_options(&socket).unwrap();
+        assert!(socket.nodelay().unwrap());
+
+        options
+            .set_tcp_nodelay(false)
+            .set_recv_buffer_size(Some(256 * 1024))
+            .set_send_buffer_size(Some(128 * 1024));
+        options.apply_socket_options(&socket).unwrap();
+        assert!(!socket.nodelay().unwrap());
+        // the kernel may round or double the sizes
+        let sock_ref = SockRef::from(&socket);
+        assert!(sock_ref.recv_buffer_size().unwrap() >= 128 * 1024);
+        assert!(sock_ref.send_buffer_size().unwrap() >= 64 * 1024);
+        drop(client);
+    }
+
+    /// Accept handler that returns decisions in order
+    struct ScriptedAcceptHandler {
+        decisions: std::sync::Mutex<Vec<AcceptDecision>>,
+        peers: std::sync::Mutex<Vec<SocketAddr>>,
+    }
+
+    #[async_trait]
+    impl AcceptHandler for ScriptedAcceptHandler {
+        async fn on_accept(&self, peer: SocketAddr) -> AcceptDecision {
+            self.peers.lock().unwrap().push(peer);
+            self.decisions.lock().unwrap().remove(0)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_accept_handler() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accept_handler = Arc::new(ScriptedAcceptHandler {
+            decisions: std::sync::Mutex::new(vec![
+                AcceptDecision::Reject(Box::new(ErrorInfo::new(
+                    Severity::Fatal,
+                    "53300",
+                    "too many connections",
+                ))),
+                AcceptDecision::RequireTls,
+                AcceptDecision::Continue,
+            ]),
+            peers: std::sync::Mutex::new(Vec::new()),
+        });
+        let mut options = ServerOptions::new();
+        options.set_accept_handler(Some(accept_handler.clone()));
+        tokio::spawn(serve(
+            listener,
+            None,
+            Arc::new(StatelessMakeHandler::new(Arc::new(NoopStartupHandler))),
+            Arc::new(StatelessMakeHandler::new(Arc::new(EmptyQueryHandler))),
+            Arc::new(StatelessMakeHandler::new(Arc::new(
+                PlaceholderExtendedQueryHandler,
+            ))),
+            Arc::new(StatelessMakeHandler::new(Arc::new(NoopCopyHandler))),
+            Arc::new(options),
+        ));
+
+        for expected in ["53300", "28000"] {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let mut buf = BytesMut::new();
+            let mut startup = Startup::new();
+            startup
+                .parameters_mut()
+                .insert("user".to_owned(), "tomcat".to_owned());
+            startup.encode(&mut buf).unwrap();
+            stream.write_all(&buf).await.unwrap();
+
+            let mut buf = BytesMut::new();
+            match read_message(&mut stream, &mut buf).await {
+                PgWireBackendMessage::ErrorResponse(error) => {
+                    assert_eq!(expected, error_code(&error))
+                }
+                msg => panic!("unexpected message {msg:?}"),
+            }
+            assert_eq!(0, stream.read_buf(&mut buf).await.unwrap());
+        }
+
+        connect(addr).await;
+        let peers = accept_handler.peers.lock().unwrap();
+        assert_eq!(3, peers.len());
+        assert!(peers.iter().all(|peer| peer.ip() == addr.ip()));
+    }
+
+    #[tokio::test]
+    async fn test_graceful_shutdown() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = ShutdownHandle::new();
+        let mut options = ServerOptions::new();
+        options.set_shutdown(Some(shutdown.clone()));
+
+        let server = tokio::spawn(serve(
+            listener,
+            None,
+            Arc::new(StatelessMakeHandler::new(Arc::new(NoopStartupHandler))),
+            Arc::new(StatelessMakeHandler::new(Arc::new(EmptyQueryHandler))),
+            Arc::new(StatelessMakeHandler::new(Arc::new(
+                PlaceholderExtendedQueryHandler,
+            ))),
+            Arc::new(StatelessMakeHandler::new(Arc::new(NoopCopyHandler))),
+            Arc::new(options),
+        ));
+
+        let (mut stream, mut buf) = connect(addr).await;
+
+        let drain = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.shutdown().await }
+        });
+
+        match read_message(&mut stream, &mut buf).await {
+            PgWireBackendMessage::ErrorResponse(error) => assert_eq!("57P01", error_code(&error)),
+            msg => panic!("unexpected message {msg:?}"),
+        }
+        assert_eq!(0, stream.read_buf(&mut buf).await.unwrap());
+
+        drain.await.unwrap();
+        server.await.unwrap().unwrap();
+        assert!(shutdown.is_shutdown());
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    fn test_tls_acceptor(options: &TlsOptions) -> Arc<TlsAcceptor> {
+        use std::fs::File;
+        use std::io::BufReader;
+
+        let certs = rustls_pemfile::certs(&mut BufReader::new(
+            File::open("examples/ssl/server.crt").unwrap(),
+        ))
+        .unwrap()
+        .into_iter()
+        .map(Certificate)
+        .collect();
+        let key = rustls_pemfile::pkcs8_private_keys(&mut BufReader::new(
+            File::open("examples/ssl/server.key").unwrap(),
+        ))
+        .unwrap()
+        .into_iter()
+        .map(PrivateKey)
+        .next()
+        .unwrap();
+
+        Arc::new(build_tls_acceptor(certs, key, options).unwrap())
+    }
+
+    /// Accept a single connection with TLS enabled, and return the result of
+    /// its processing
+    async fn tls_server(
+        options: ServerOptions,
+    ) -> (
+        std::net::SocketAddr,
+        tokio::task::JoinHandle<Result<(), IOError>>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = test_tls_acceptor(options.tls());
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            process_socket_with_options(
+                socket,
+                Some(acceptor),
+                Arc::new(NoopStartupHandler),
+                Arc::new(EmptyQueryHandler),
+                Arc::new(PlaceholderExtendedQueryHandler),
+                Arc::new(NoopCopyHandler),
+                Arc::new(options),
+            )
+            .await
+        });
+        (addr, server)
+    }
+
+    async fn send_ssl_request(stream: &mut TcpStream) {
+        let mut buf = BytesMut::new();
+        SslRequest {}.encode(&mut buf).unwrap();
+        stream.write_all(&buf).await.unwrap();
+        assert_eq!(b'S', stream.read_u8().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_failed_tls_handshake_closes_connection() {
+        let (addr, server) = tls_server(ServerOptions::new()).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        send_ssl_request(&mut stream).await;
+
+        // send a plaintext startup packet instead of tls handshake
+        let mut startup = Startup::new();
+        startup
+            .parameters_mut()
+            .insert("user".to_owned(), "tomcat".to_owned());
+        let mut buf = BytesMut::new();
+        startup.encode(&mut buf).unwrap();
+        stream.write_all(&buf).await.unwrap();
+
+        let err = server.await.unwrap().unwrap_err();
+        assert_eq!(ErrorKind::ConnectionAborted, err.kind());
+
+        // startup is not processed, at most a tls alert is sent back
+        let mut buf = BytesMut::new();
+        while stream.read_buf(&mut buf).await.unwrap() > 0 {}
+        assert_ne!(Some(&b'R'), buf.first());
+    }
+
+    #[tokio::test]
+    async fn test_health_check_probes() {
+        // disconnect after `SSLRequest`
+        let (addr, server) = tls_server(ServerOptions::new()).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        send_ssl_request(&mut stream).await;
+        drop(stream);
+        server.await.unwrap().unwrap();
+
+        let start = |server| {
+            spawn_connection(
+                server_socket(server, PgWireConnectionState::AwaitingStartup),
+                Arc::new(MultiStepStartupHandler {
+                    steps: 1,
+                    received: Default::default(),
+                }),
+                Arc::new(EmptyQueryHandler),
+                Arc::new(PlaceholderExtendedQueryHandler),
+                Arc::new(NoopCopyHandler),
+                ConnectionLimits {
+                    health_check_user: Some("probe".to_owned()),
+                    ..Default::default()
+                },
+            )
+        };
+        let startup = |user: &str| {
+            let mut buf = BytesMut::new();
+            let mut startup = Startup::new();
+            startup
+                .parameters_mut()
+                .insert("user".to_owned(), user.to_owned());
+            startup.encode(&mut buf).unwrap();
+            buf
+        };
+
+        for next in [
+            PgWireFrontendMessage::Terminate(Terminate::new()),
+            PgWireFrontendMessage::Query(Query::new("SELECT 1".to_owned())),
+        ] {
+            let (mut client, server) = tokio::io::duplex(4096);
+            let server = start(server);
+            client.write_all(&startup("probe")).await.unwrap();
+            let mut buf = BytesMut::new();
+            assert!(matches!(
+                read_message(&mut client, &mut buf).await,
+                PgWireBackendMessage::Authentication(Authentication::Ok)
+            ));
+            assert!(matches!(
+                read_message(&mut client, &mut buf).await,
+                PgWireBackendMessage::ReadyForQuery(_)
+            ));
+
+            let is_query = matches!(next, PgWireFrontendMessage::Query(_));
+            let mut message = BytesMut::new();
+            next.encode(&mut message).unwrap();
+            client.write_all(&message).await.unwrap();
+            if is_query {
+                match read_message(&mut client, &mut buf).await {
+                    PgWireBackendMessage::ErrorResponse(error) => {
+                        assert_eq!("28000", error_code(&error))
+                    }
+                    msg => panic!("unexpected message {msg:?}"),
+                }
+            }
+            assert_eq!(0, client.read_buf(&mut buf).await.unwrap());
+            server.await.unwrap().unwrap();
+        }
+
+        // other users are authenticated by the startup handler
+        let (mut client, server) = tokio::io::duplex(4096);
+        let _server = start(server);
+        client.write_all(&startup("tomcat")).await.unwrap();
+        let mut buf = BytesMut::new();
+        assert!(matches!(
+            read_message(&mut client, &mut buf).await,
+            PgWireBackendMessage::Authentication(Authentication::CleartextPassword)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_tls_min_version() {
+        use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerName};
+        use tokio_rustls::TlsConnector;
+
+        let mut options = ServerOptions::new();
+        options.tls_mut().set_min_version(TlsVersion::Tls13);
+        let (addr, server) = tls_server(options).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        send_ssl_request(&mut stream).await;
+
+        let client_config = ClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&version::TLS12])
+            .unwrap()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        let result = TlsConnector::from(Arc::new(client_config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await;
+        assert!(result.is_err());
+
+        let err = server.await.unwrap().unwrap_err();
+        assert_eq!(ErrorKind::ConnectionAborted, err.kind());
+    }
+
+    #[tokio::test]
+    async fn test_tls_session_info() {
+        use std::time::SystemTime;
+        use tokio_rustls::rustls::client::{ServerCertVerified, ServerCertVerifier};
+        use tokio_rustls::rustls::{ClientConfig, Error as TlsError, ServerName};
+        use tokio_rustls::TlsConnector;
+
+        struct AcceptAnyCert;
+
+        impl ServerCertVerifier for AcceptAnyCert {
+            fn verify_server_cert(
+                &self,
+                _end_entity: &Certificate,
+                _intermediates: &[Certificate],
+                _server_name: &ServerName,
+                _scts: &mut dyn Iterator<Item = &[u8]>,
+                _ocsp_response: &[u8],
+                _now: SystemTime,
+            ) -> Result<ServerCertVerified, TlsError> {
+                Ok(ServerCertVerified::assertion())
+            }
+        }
+
+        let registry = ConnectionRegistry::new();
+        let mut options = ServerOptions::new();
+        options.set_connection_registry(Some(registry.clone()));
+        let (addr, server) = tls_server(options).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        send_ssl_request(&mut stream).await;
+        let client_config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(AcceptAnyCert))
+            .with_no_client_auth();
+        let mut stream = TlsConnector::from(Arc::new(client_config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+        let cipher_suite = stream
+            .get_ref()
+            .1
+            .negotiated_cipher_suite()
+            .unwrap()
+            .suite();
+
+        let mut startup = Startup::new();
+        startup
+            .parameters_mut()
+            .insert("user".to_owned(), "tomcat".to_owned());
+        let mut buf = BytesMut::new();
+        startup.encode(&mut buf).unwrap();
+        stream.write_all(&buf).await.unwrap();
+        let mut buf = BytesMut::new();
+        while !matches!(
+            read_message(&mut stream, &mut buf).await,
+            PgWireBackendMessage::ReadyForQuery(_)
+        ) {}
+
+        let connection = registry.connections().pop().unwrap();
+        let session = connection.tls().as_ref().unwrap();
+        assert_eq!("TLSv1.3", session.protocol_version());
+        assert_eq!(cipher_suite.as_str().unwrap(), session.cipher_suite());
+
+        // the connection ends without error when the client goes away
+        drop(stream);
+        server.await.unwrap().unwrap();
+        assert!(registry.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_encryption_negotiation_info() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let registry = ConnectionRegistry::new();
+        let mut options = ServerOptions::new();
+        options.set_connection_registry(Some(registry.clone()));
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            process_socket_with_options(
+                socket,
+                None,
+                Arc::new(NoopStartupHandler),
+                Arc::new(EmptyQueryHandler),
+                Arc::new(PlaceholderExtendedQueryHandler),
+                Arc::new(NoopCopyHandler),
+                Arc::new(options),
+            )
+            .await
+        });
+
+        // like libpq with gssencmode and sslmode prefer, both declined
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut buf = BytesMut::new();
+        GssEncRequest::new().encode(&mut buf).unwrap();
+        stream.write_all(&buf).await.unwrap();
+        assert_eq!(b'N', stream.read_u8().await.unwrap());
+        let mut buf = BytesMut::new();
+        SslRequest::new().encode(&mut buf).unwrap();
+        stream.write_all(&buf).await.unwrap();
+        assert_eq!(b'N', stream.read_u8().await.unwrap());
+
+        let mut startup = Startup::new();
+        startup.set_protocol_number_minor(2);
+        startup
+            .parameters_mut()
+            .insert("user".to_owned(), "tomcat".to_owned());
+        let mut buf = BytesMut::new();
+        startup.encode(&mut buf).unwrap();
+        stream.write_all(&buf).await.unwrap();
+        let mut buf = BytesMut::new();
+        while !matches!(
+            read_message(&mut stream, &mut buf).await,
+            PgWireBackendMessage::ReadyForQuery(_)
+        ) {}
+
+        let connection = registry.connections().pop().unwrap();
+        let negotiation = *connection.negotiation();
+        assert!(negotiation.ssl_requested());
+        assert!(!negotiation.ssl_accepted());
+        assert!(negotiation.gss_encryption_requested());
+        // the version sent, not the negotiated 3.0
+        assert_eq!(Some(3 << 16 | 2), negotiation.protocol_version());
+
+        let mut client_info = crate::api::mock::MockClient::new();
+        assert!(!client_info.ssl_requested());
+        assert_eq!(None, client_info.startup_protocol_version());
+        client_info.extensions_mut().insert(negotiation);
+        assert!(client_info.ssl_requested());
+        assert!(!client_info.ssl_accepted());
+        assert!(client_info.gss_encryption_requested());
+        assert_eq!(Some(0x30002), client_info.startup_protocol_version());
+
+        drop(stream);
+        server.await.unwrap().ok();
+    }
+
+    /// Extended query handler that fails to execute statement `FAIL`,
+    /// responds with utility tag to transaction control statements, and with
+    /// `PIPELINED 1` to statement `PIPELINED` if more messages are buffered
+    struct FailingQueryHandler {
+        portal_store: Arc<MemPortalStore<String>>,
+    }
+
+    #[async_trait]
+    impl ExtendedQueryHandler for FailingQueryHandler {
+        type Statement = String;
+        type PortalStore = MemPortalStore<Self::Statement>;
+        type QueryParser = NoopQueryParser;
+
+        fn portal_store(&self) -> Arc<Self::PortalStore> {
+            self.portal_store.clone()
+        }
+
+        fn query_parser(&self) -> Arc<Self::QueryParser> {
+            Arc::new(NoopQueryParser::new())
+        }
+
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            client: &mut C,
+            portal: &'a Portal<Self::Statement>,
+            _max_rows: usize,
+        ) -> PgWireResult<Response<'a>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            if portal.statement().statement() == "PIPELINED" {
+                return Ok(Response::Execution(Tag::new_for_execution(
+                    "PIPELINED",
+                    Some(client.is_pipelined() as u64),
+                )));
+            }
+            if portal.statement().statement() == "PANIC" {
+                panic!("handler bug");
+            }
+            if portal.statement().statement() == "FAIL" {
+                Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                    Severity::Error,
+                    SqlState::DivisionByZero,
+                    "division by zero",
+                ))))
+            } else {
+                let statement = portal.statement().statement();
+                Ok(Response::Execution(
+                    Tag::new_for_utility(statement)
+                        .unwrap_or_else(|| Tag::new_for_execution("OK", Some(1))),
+                ))
+            }
+        }
+
+        async fn do_describe<C>(
+            &self,
+            _client: &mut C,
+            _target: StatementOrPortal<'_, Self::Statement>,
+        ) -> PgWireResult<DescribeResponse>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            Ok(DescribeResponse::portal(vec![]))
+        }
+    }
+
+    /// Read command tags until `ReadyForQuery`
+    async fn read_tags<S>(stream: &mut S, buf: &mut BytesMut) -> Vec<String>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let mut tags = Vec::new();
+        loop {
+            match read_message(stream, buf).await {
+                PgWireBackendMessage::CommandComplete(cc) => tags.push(cc.tag().clone()),
+                PgWireBackendMessage::ReadyForQuery(_) => return tags,
+                _ => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_simple_query_only() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let server = spawn_connection(
+            server_socket(server, PgWireConnectionState::ReadyForQuery),
+            Arc::new(NoopStartupHandler),
+            Arc::new(EmptyQueryHandler),
+            Arc::new(FailingQueryHandler {
+                portal_store: Arc::new(MemPortalStore::new()),
+            }),
+            Arc::new(NoopCopyHandler),
+            ConnectionLimits {
+                simple_query_only: true,
+                ..Default::default()
+            },
+        );
+
+        let messages = vec![
+            PgWireFrontendMessage::Parse(Parse::new(None, "SELECT 1".to_owned(), vec![])),
+            PgWireFrontendMessage::Bind(Bind::new(None, None, vec![], vec![], vec![])),
+            PgWireFrontendMessage::Execute(Execute::new(None, 0)),
+            PgWireFrontendMessage::Sync(PgSync::new()),
+            PgWireFrontendMessage::Query(Query::new("SELECT 1".to_owned())),
+        ];
+        let mut buf = BytesMut::new();
+        for message in messages {
+            message.encode(&mut buf).unwrap();
+        }
+        client.write_all(&buf).await.unwrap();
+
+        let mut buf = BytesMut::new();
+        let mut received = Vec::new();
+        for _ in 0..4 {
+            received.push(match read_message(&mut client, &mut buf).await {
+                PgWireBackendMessage::ReadyForQuery(_) => "ready".to_owned(),
+                PgWireBackendMessage::CommandComplete(_) => "complete".to_owned(),
+                PgWireBackendMessage::ErrorResponse(error) => error_code(&error).to_owned(),
+                msg => panic!("unexpected message {msg:?}"),
+            });
+        }
+        // the connection is kept, and simple queries still work
+        assert_eq!(vec!["0A000", "ready", "complete", "ready"], received);
+
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_lenient_set() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let server = spawn_connection(
+            server_socket(server, PgWireConnectionState::ReadyForQuery),
+            Arc::new(NoopStartupHandler),
+            Arc::new(EmptyQueryHandler),
+            Arc::new(FailingQueryHandler {
+                portal_store: Arc::new(MemPortalStore::new()),
+            }),
+            Arc::new(NoopCopyHandler),
+            ConnectionLimits {
+                lenient_set: Some(LenientSetOptions::new()),
+                ..Default::default()
+            },
+        );
+
+        let mut buf = BytesMut::new();
+        for query in [
+            "SET extra_float_digits = 3",
+            "SHOW extra_float_digits",
+            "SHOW work_mem",
+        ] {
+            PgWireFrontendMessage::Query(Query::new(query.to_owned()))
+                .encode(&mut buf)
+                .unwrap();
+        }
+        client.write_all(&buf).await.unwrap();
+
+        let mut buf = BytesMut::new();
+        let mut received = Vec::new();
+        for _ in 0..8 {
+            received.push(match read_message(&mut client, &mut buf).await {
+                PgWireBackendMessage::ReadyForQuery(_) => "ready".to_owned(),
+                PgWireBackendMessage::CommandComplete(cc) => cc.tag().clone(),
+                PgWireBackendMessage::RowDescription(_) => "row description".to_owned(),
+                PgWireBackendMessage::DataRow(row) => {
+                    String::from_utf8(row.fields()[0].clone().unwrap().to_vec()).unwrap()
+                }
+                msg => panic!("unexpected message {msg:?}"),
+            });
+        }
+        // `SHOW` of a parameter not set before goes to the handler
+        assert_eq!(
+            vec![
+                "SET",
+                "ready",
+                "row description",
+                "3",
+                "SHOW",
+                "ready",
+                "OK",
+                "ready"
+            ],
+            received
+        );
+
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_catch_handler_panics() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let server = spawn_connection(
+            server_socket(server, PgWireConnectionState::ReadyForQuery),
+            Arc::new(NoopStartupHandler),
+            Arc::new(EmptyQueryHandler),
+            Arc::new(FailingQueryHandler {
+                portal_store: Arc::new(MemPortalStore::new()),
+            }),
+            Arc::new(NoopCopyHandler),
+            ConnectionLimits {
+                catch_handler_panics: true,
+                ..Default::default()
+            },
+        );
+
+        let mut buf = BytesMut::new();
+        for query in ["PANIC", "SELECT 1"] {
+            let messages = [
+                PgWireFrontendMessage::Parse(Parse::new(None, query.to_owned(), vec![])),
+                PgWireFrontendMessage::Bind(Bind::new(None, None, vec![], vec![], vec![])),
+                PgWireFrontendMessage::Execute(Execute::new(None, 0)),
+                PgWireFrontendMessage::Sync(PgSync::new()),
+            ];
+            for message in messages {
+                message.encode(&mut buf).unwrap();
+            }
+        }
+        client.write_all(&buf).await.unwrap();
+
+        let mut buf = BytesMut::new();
+        let mut received = Vec::new();
+        for _ in 0..8 {
+            received.push(match read_message(&mut client, &mut buf).await {
+                PgWireBackendMessage::ParseComplete(_) => "parse".to_owned(),
+                PgWireBackendMessage::BindComplete(_) => "bind".to_owned(),
+                PgWireBackendMessage::ReadyForQuery(_) => "ready".to_owned(),
+                PgWireBackendMessage::CommandComplete(_) => "complete".to_owned(),
+                PgWireBackendMessage::ErrorResponse(error) => error_code(&error).to_owned(),
+                msg => panic!("unexpected message {msg:?}"),
+            });
+        }
+        // the panic is reported like an error, and the connection is kept
+        assert_eq!(
+            vec!["parse", "bind", "XX000", "ready", "parse", "bind", "complete", "ready"],
+            received
+        );
+
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_is_pipelined() {
+        tokio::time::pause();
+        let (mut client, server) = tokio::io::duplex(4096);
+        let server = spawn_connection(
+            server_socket(server, PgWireConnectionState::ReadyForQuery),
+            Arc::new(NoopStartupHandler),
+            Arc::new(EmptyQueryHandler),
+            Arc::new(FailingQueryHandler {
+                portal_store: Arc::new(MemPortalStore::new()),
+            }),
+            Arc::new(NoopCopyHandler),
+            ConnectionLimits::default(),
+        );
+
+        let bind = || {
+            PgWireFrontendMessage::Bind(Bind::new(
+                None,
+                Some("s1".to_owned()),
+                vec![],
+                vec![],
+                vec![],
+            ))
+        };
+        let execute = || PgWireFrontendMessage::Execute(Execute::new(None, 0));
+        let write = |messages: Vec<PgWireFrontendMessage>| {
+            let mut buf = BytesMut::new();
+            for message in messages {
+                message.encode(&mut buf).unwrap();
+            }
+            buf
+        };
+        let mut buf = BytesMut::new();
+
+        // a pipeline sent at once, each Execute is followed by more messages
+        client
+            .write_all(&write(vec![
+                PgWireFrontendMessage::Parse(Parse::new(
+                    Some("s1".to_owned()),
+                    "PIPELINED".to_owned(),
+                    vec![],
+                )),
+                bind(),
+                execute(),
+                bind(),
+                execute(),
+                PgWireFrontendMessage::Sync(PgSync::new()),
+            ]))
+            .await
+            .unwrap();
+        assert_eq!(
+            vec!["PIPELINED 1", "PIPELINED 1"],
+            read_tags(&mut client, &mut buf).await
+        );
+
+        // a standalone Execute, Sync arrives after it's processed
+        client
+            .write_all(&write(vec![bind(), execute()]))
+            .await
+            .unwrap();
+        // with time paused, returns once the Execute is processed, without
+        // waiting real time
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        client
+            .write_all(&write(vec![PgWireFrontendMessage::Sync(PgSync::new())]))
+            .await
+            .unwrap();
+        assert_eq!(vec!["PIPELINED 0"], read_tags(&mut client, &mut buf).await);
+
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sync_after_error_keeps_named_statement() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let handler = Arc::new(FailingQueryHandler {
+            portal_store: Arc::new(MemPortalStore::new()),
+        });
+        let server = spawn_connection(
+            server_socket(server, PgWireConnectionState::ReadyForQuery),
+            Arc::new(NoopStartupHandler),
+            Arc::new(EmptyQueryHandler),
+            handler.clone(),
+            Arc::new(NoopCopyHandler),
+            ConnectionLimits::default(),
+        );
+
+        let bind = |statement: Option<&str>| {
+            PgWireFrontendMessage::Bind(Bind::new(
+                None,
+                statement.map(ToOwned::to_owned),
+                vec![],
+                vec![],
+                vec![],
+            ))
+        };
+        let messages = vec![
+            PgWireFrontendMessage::Parse(Parse::new(
+                Some("s1".to_owned()),
+                "SELECT 1".to_owned(),
+                vec![],
+            )),
+            PgWireFrontendMessage::Parse(Parse::new(None, "FAIL".to_owned(), vec![])),
+            bind(None),
+            PgWireFrontendMessage::Execute(Execute::new(None, 0)),
+            // skipped until sync
+            bind(Some("s1")),
+            PgWireFrontendMessage::Execute(Execute::new(None, 0)),
+            PgWireFrontendMessage::Sync(PgSync::new()),
+            // named statement is still available
+            bind(Some("s1")),
+            PgWireFrontendMessage::Execute(Execute::new(None, 0)),
+            PgWireFrontendMessage::Sync(PgSync::new()),
+        ];
+        let mut buf = BytesMut::new();
+        for message in messages {
+            message.encode(&mut buf).unwrap();
+        }
+        client.write_all(&buf).await.unwrap();
+
+        let mut buf = BytesMut::new();
+        let mut received = Vec::new();
+        for _ in 0..8 {
+            received.push(read_message(&mut client, &mut buf).await);
+        }
+        assert!(
+            matches!(
+                received.as_slice(),
+                [
+                    PgWireBackendMessage::ParseComplete(_),
+                    PgWireBackendMessage::ParseComplete(_),
+                    PgWireBackendMessage::BindComplete(_),
+                    PgWireBackendMessage::ErrorResponse(_),
+                    PgWireBackendMessage::ReadyForQuery(_),
+                    PgWireBackendMessage::BindComplete(_),
+                    PgWireBackendMessage::CommandComplete(_),
+                    PgWireBackendMessage::ReadyForQuery(_),
+                ]
+            ),
+            "unexpected messages {received:?}"
+        );
+        assert!(handler.portal_store.get_statement("s1").is_some());
+        // unnamed portal closed by sync
+        assert!(handler.portal_store.get_portal(DEFAULT_NAME).is_none());
+
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_acknowledgements() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let server = spawn_connection(
+            server_socket(server, PgWireConnectionState::ReadyForQuery),
+            Arc::new(NoopStartupHandler),
+            Arc::new(EmptyQueryHandler),
+            Arc::new(FailingQueryHandler {
+                portal_store: Arc::new(MemPortalStore::new()),
+            }),
+            Arc::new(NoopCopyHandler),
+            ConnectionLimits::default(),
+        );
+
+        let bind = |statement: &str| {
+            PgWireFrontendMessage::Bind(Bind::new(
+                None,
+                Some(statement.to_owned()),
+                vec![],
+                vec![],
+                vec![],
+            ))
+        };
+        let messages = vec![
+            PgWireFrontendMessage::Parse(Parse::new(
+                Some("s1".to_owned()),
+                "SELECT 1".to_owned(),
+                vec![],
+            )),
+            bind("s1"),
+            PgWireFrontendMessage::Describe(Describe::new(TARGET_TYPE_BYTE_PORTAL, None)),
+            PgWireFrontendMessage::Execute(Execute::new(None, 0)),
+            PgWireFrontendMessage::Sync(PgSync::new()),
+            // failing at bind, the rest is skipped until sync
+            PgWireFrontendMessage::Parse(Parse::new(
+                Some("s2".to_owned()),
+                "SELECT 2".to_owned(),
+                vec![],
+            )),
+            bind("missing"),
+            PgWireFrontendMessage::Describe(Describe::new(TARGET_TYPE_BYTE_PORTAL, None)),
+            PgWireFrontendMessage::Execute(Execute::new(None, 0)),
+            PgWireFrontendMessage::Sync(PgSync::new()),
+            bind("s2"),
+            PgWireFrontendMessage::Execute(Execute::new(None, 0)),
+            PgWireFrontendMessage::Sync(PgSync::new()),
+        ];
+        let mut buf = BytesMut::new();
+        for message in messages {
+            message.encode(&mut buf).unwrap();
+        }
+        client.write_all(&buf).await.unwrap();
+
+        let mut buf = BytesMut::new();
+        let mut received = Vec::new();
+        for _ in 0..11 {
+            received.push(match read_message(&mut client, &mut buf).await {
+                PgWireBackendMessage::ParseComplete(_) => "parse".to_owned(),
+                PgWireBackendMessage::BindComplete(_) => "bind".to_owned(),
+                PgWireBackendMessage::RowDescription(_) => "describe".to_owned(),
+                PgWireBackendMessage::CommandComplete(_) => "complete".to_owned(),
+                PgWireBackendMessage::ReadyForQuery(_) => "ready".to_owned(),
+                PgWireBackendMessage::ErrorResponse(error) => error_code(&error).to_owned(),
+                msg => panic!("unexpected message {msg:?}"),
+            });
+        }
+        assert_eq!(
+            vec![
+                "parse", "bind", "describe", "complete", "ready", "parse", "26000", "ready",
+                "bind", "complete", "ready",
+            ],
+            received
+        );
+
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_single_ready_for_query() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let server = spawn_connection(
+            server_socket(server, PgWireConnectionState::ReadyForQuery),
+            Arc::new(NoopStartupHandler),
+            Arc::new(EmptyQueryHandler),
+            Arc::new(FailingQueryHandler {
+                portal_store: Arc::new(MemPortalStore::new()),
+            }),
+            Arc::new(NoopCopyHandler),
+            ConnectionLimits::default(),
+        );
+
+        let mut buf = BytesMut::new();
+        for query in ["INSERT 1", "INSERT 2", "INSERT 3"] {
+            let messages = [
+                PgWireFrontendMessage::Parse(Parse::new(None, query.to_owned(), vec![])),
+                PgWireFrontendMessage::Bind(Bind::new(None, None, vec![], vec![], vec![])),
+                PgWireFrontendMessage::Execute(Execute::new(None, 0)),
+            ];
+            for message in messages {
+                message.encode(&mut buf).unwrap();
+            }
+        }
+        PgWireFrontendMessage::Sync(PgSync::new())
+            .encode(&mut buf)
+            .unwrap();
+        PgWireFrontendMessage::Query(Query::new("SELECT 1".to_owned()))
+            .encode(&mut buf)
+            .unwrap();
+        client.write_all(&buf).await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let mut buf = BytesMut::new();
+        let mut received = Vec::new();
+        for _ in 0..12 {
+            received.push(match read_message(&mut client, &mut buf).await {
+                PgWireBackendMessage::ParseComplete(_) => "parse",
+                PgWireBackendMessage::BindComplete(_) => "bind",
+                PgWireBackendMessage::CommandComplete(_) => "complete",
+                PgWireBackendMessage::ReadyForQuery(_) => "ready",
+                msg => panic!("unexpected message {msg:?}"),
+            });
+        }
+        // a `CommandComplete` for each `Execute`, `ReadyForQuery` only for
+        // `Sync` and `Query`
+        assert_eq!(
+            vec![
+                "parse", "bind", "complete", "parse", "bind", "complete", "parse", "bind",
+                "complete", "ready", "complete", "ready",
+            ],
+            received
+        );
+        assert!(buf.is_empty());
+        assert_eq!(0, client.read_buf(&mut buf).await.unwrap());
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_portal_outlives_closed_statement() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let server = spawn_connection(
+            server_socket(server, PgWireConnectionState::ReadyForQuery),
+            Arc::new(NoopStartupHandler),
+            Arc::new(EmptyQueryHandler),
+            Arc::new(FailingQueryHandler {
+                portal_store: Arc::new(MemPortalStore::new()),
+            }),
+            Arc::new(NoopCopyHandler),
+            ConnectionLimits::default(),
+        );
+
+        let close = |target_type, name: &str| {
+            PgWireFrontendMessage::Close(Close::new(target_type, Some(name.to_owned())))
+        };
+        let execute = || PgWireFrontendMessage::Execute(Execute::new(Some("p1".to_owned()), 0));
+        let messages = vec![
+            PgWireFrontendMessage::Parse(Parse::new(
+                Some("s1".to_owned()),
+                "SELECT 1".to_owned(),
+                vec![],
+            )),
+            PgWireFrontendMessage::Bind(Bind::new(
+                Some("p1".to_owned()),
+                Some("s1".to_owned()),
+                vec![],
+                vec![],
+                vec![],
+            )),
+            // the portal survives its statement
+            close(TARGET_TYPE_BYTE_STATEMENT, "s1"),
+            execute(),
+            PgWireFrontendMessage::Sync(PgSync::new()),
+            // but not being closed itself
+            close(TARGET_TYPE_BYTE_PORTAL, "p1"),
+            execute(),
+            PgWireFrontendMessage::Sync(PgSync::new()),
+        ];
+        let mut buf = BytesMut::new();
+        for message in messages {
+            message.encode(&mut buf).unwrap();
+        }
+        client.write_all(&buf).await.unwrap();
+
+        let mut buf = BytesMut::new();
+        let mut received = Vec::new();
+        for _ in 0..8 {
+            received.push(match read_message(&mut client, &mut buf).await {
+                PgWireBackendMessage::ParseComplete(_) => "parse".to_owned(),
+                PgWireBackendMessage::BindComplete(_) => "bind".to_owned(),
+                PgWireBackendMessage::CloseComplete(_) => "close".to_owned(),
+                PgWireBackendMessage::CommandComplete(_) => "complete".to_owned(),
+                PgWireBackendMessage::ReadyForQuery(_) => "ready".to_owned(),
+                PgWireBackendMessage::ErrorResponse(error) => error_code(&error).to_owned(),
+                msg => panic!("unexpected message {msg:?}"),
+            });
+        }
+        assert_eq!(
+            vec!["parse", "bind", "close", "complete", "ready", "close", "34000", "ready"],
+            received
+        );
+
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_statement_name() {
+        // a handler and its store for each connection
+        struct MakeFailingQueryHandler;
+
+        impl MakeHandler for MakeFailingQueryHandler {
+            type Handler = Arc<FailingQueryHandler>;
+
+            fn make(&self) -> Self::Handler {
+                Arc::new(FailingQueryHandler {
+                    portal_store: Arc::new(MemPortalStore::new()),
+                })
+            }
+        }
+
+        let start = || {
+            let (client, server) = tokio::io::duplex(4096);
+            let server = spawn_connection(
+                server_socket(server, PgWireConnectionState::ReadyForQuery),
+                Arc::new(NoopStartupHandler),
+                Arc::new(EmptyQueryHandler),
+                MakeFailingQueryHandler.make(),
+                Arc::new(NoopCopyHandler),
+                ConnectionLimits::default(),
+            );
+            (client, server)
+        };
+        let (mut client, server) = start();
+
+        let parse = |name: Option<&str>| {
+            PgWireFrontendMessage::Parse(Parse::new(
+                name.map(str::to_owned),
+                "SELECT 1".to_owned(),
+                vec![],
+            ))
+        };
+        let messages = vec![
+            parse(Some("s1")),
+            PgWireFrontendMessage::Sync(PgSync::new()),
+            parse(Some("s1")),
+            PgWireFrontendMessage::Sync(PgSync::new()),
+            // the unnamed statement is replaced
+            parse(None),
+            parse(None),
+            // a closed name can be reused
+            PgWireFrontendMessage::Close(Close::new(
+                TARGET_TYPE_BYTE_STATEMENT,
+                Some("s1".to_owned()),
+            )),
+            parse(Some("s1")),
+            PgWireFrontendMessage::Sync(PgSync::new()),
+        ];
+        let mut buf = BytesMut::new();
+        for message in messages {
+            message.encode(&mut buf).unwrap();
+        }
+        client.write_all(&buf).await.unwrap();
+
+        let mut buf = BytesMut::new();
+        let mut received = Vec::new();
+        for _ in 0..9 {
+            received.push(match read_message(&mut client, &mut buf).await {
+                PgWireBackendMessage::ParseComplete(_) => "parse".to_owned(),
+                PgWireBackendMessage::CloseComplete(_) => "close".to_owned(),
+                PgWireBackendMessage::ReadyForQuery(_) => "ready".to_owned(),
+                PgWireBackendMessage::ErrorResponse(error) => error_code(&error).to_owned(),
+                msg => panic!("unexpected message {msg:?}"),
+            });
+        }
+        assert_eq!(
+            vec!["parse", "ready", "42P05", "ready", "parse", "parse", "close", "parse", "ready"],
+            received
+        );
+
+        // names of another connection do not collide
+        let (mut other, other_server) = start();
+        let mut buf = BytesMut::new();
+        parse(Some("s1")).encode(&mut buf).unwrap();
+        PgWireFrontendMessage::Sync(PgSync::new())
+            .encode(&mut buf)
+            .unwrap();
+        other.write_all(&buf).await.unwrap();
+        let mut buf = BytesMut::new();
+        assert!(matches!(
+            read_message(&mut other, &mut buf).await,
+            PgWireBackendMessage::ParseComplete(_)
+        ));
+        assert!(matches!(
+            read_message(&mut other, &mut buf).await,
+            PgWireBackendMessage::ReadyForQuery(_)
+        ));
+
+        drop(client);
+        server.await.unwrap().unwrap();
+        drop(other);
+        other_server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_invalid_binary_parameter() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let server = spawn_connection(
+            server_socket(server, PgWireConnectionState::ReadyForQuery),
+            Arc::new(NoopStartupHandler),
+            Arc::new(EmptyQueryHandler),
+            Arc::new(FailingQueryHandler {
+                portal_store: Arc::new(MemPortalStore::new()),
+            }),
+            Arc::new(NoopCopyHandler),
+            ConnectionLimits::default(),
+        );
+
+        let bind = |param: &'static [u8]| {
+            PgWireFrontendMessage::Bind(Bind::new(
+                None,
+                Some("s1".to_owned()),
+                vec![1],
+                vec![Some(Bytes::from_static(param))],
+                vec![],
+            ))
+        };
+        let messages = vec![
+            PgWireFrontendMessage::Parse(Parse::new(
+                Some("s1".to_owned()),
+                "SELECT $1".to_owned(),
+                vec![23],
+            )),
+            // 3 bytes for an int4 parameter
+            bind(&[0, 0, 1]),
+            PgWireFrontendMessage::Execute(Execute::new(None, 0)),
+            PgWireFrontendMessage::Sync(PgSync::new()),
+            bind(&[0, 0, 0, 1]),
+            PgWireFrontendMessage::Execute(Execute::new(None, 0)),
+            PgWireFrontendMessage::Sync(PgSync::new()),
+        ];
+        let mut buf = BytesMut::new();
+        for message in messages {
+            message.encode(&mut buf).unwrap();
+        }
+        client.write_all(&buf).await.unwrap();
+
+        let mut buf = BytesMut::new();
+        let mut received = Vec::new();
+        for _ in 0..6 {
+            received.push(match read_message(&mut client, &mut buf).await {
+                PgWireBackendMessage::ParseComplete(_) => "parse".to_owned(),
+                PgWireBackendMessage::BindComplete(_) => "bind".to_owned(),
+                PgWireBackendMessage::CommandComplete(_) => "complete".to_owned(),
+                PgWireBackendMessage::ReadyForQuery(_) => "ready".to_owned(),
+                PgWireBackendMessage::ErrorResponse(error) => {
+                    assert!(error
+                        .fields()
+                        .iter()
+                        .any(|f| f.1 == "incorrect binary data format in bind parameter 1"));
+                    error_code(&error).to_owned()
+                }
+                msg => panic!("unexpected message {msg:?}"),
+            });
+        }
+        assert_eq!(
+            vec!["parse", "22P03", "ready", "bind", "complete", "ready"],
+            received
+        );
+
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_max_prepared_statements() {
+        // connections share the store, but are limited each
+        let handler = Arc::new(FailingQueryHandler {
+            portal_store: Arc::new(MemPortalStore::new()),
+        });
+        let start = || {
+            let (client, server) = tokio::io::duplex(4096);
+            let server = spawn_connection(
+                server_socket(server, PgWireConnectionState::ReadyForQuery),
+                Arc::new(NoopStartupHandler),
+                Arc::new(EmptyQueryHandler),
+                handler.clone(),
+                Arc::new(NoopCopyHandler),
+                ConnectionLimits {
+                    max_prepared_statements: Some(2),
+                    ..Default::default()
+                },
+            );
+            (client, server)
+        };
+        let (mut client, server) = start();
+
+        let parse = |name: Option<&str>| {
+            PgWireFrontendMessage::Parse(Parse::new(
+                name.map(ToOwned::to_owned),
+                "SELECT 1".to_owned(),
+                vec![],
+            ))
+        };
+        let bind = |portal: Option<&str>| {
+            PgWireFrontendMessage::Bind(Bind::new(
+                portal.map(ToOwned::to_owned),
+                Some("s1".to_owned()),
+                vec![],
+                vec![],
+                vec![],
+            ))
+        };
+        let messages = vec![
+            parse(Some("s1")),
+            parse(Some("s2")),
+            // the unnamed statement is not counted
+            parse(None),
+            parse(Some("s3")),
+            PgWireFrontendMessage::Sync(PgSync::new()),
+            bind(Some("p1")),
+            bind(Some("p2")),
+            bind(None),
+            bind(Some("p3")),
+            PgWireFrontendMessage::Sync(PgSync::new()),
+            // room is made by closing a statement
+            PgWireFrontendMessage::Close(Close::new(
+                TARGET_TYPE_BYTE_STATEMENT,
+                Some("s1".to_owned()),
+            )),
+            parse(Some("s3")),
+            PgWireFrontendMessage::Sync(PgSync::new()),
+        ];
+        let mut buf = BytesMut::new();
+        for message in messages {
+            message.encode(&mut buf).unwrap();
+        }
+        client.write_all(&buf).await.unwrap();
+
+        let mut buf = BytesMut::new();
+        let mut received = Vec::new();
+        for _ in 0..13 {
+            received.push(match read_message(&mut client, &mut buf).await {
+                PgWireBackendMessage::ParseComplete(_) => "parse".to_owned(),
+                PgWireBackendMessage::BindComplete(_) => "bind".to_owned(),
+                PgWireBackendMessage::CloseComplete(_) => "close".to_owned(),
+                PgWireBackendMessage::ReadyForQuery(_) => "ready".to_owned(),
+                PgWireBackendMessage::ErrorResponse(error) => error_code(&error).to_owned(),
+                msg => panic!("unexpected message {msg:?}"),
+            });
+        }
+        assert_eq!(
+            vec![
+                "parse", "parse", "parse", "54000", "ready", "bind", "bind", "bind", "54000",
+                "ready", "close", "parse", "ready",
+            ],
+            received
+        );
+        assert!(handler.portal_store.get_statement("s1").is_none());
+        assert!(handler.portal_store.get_statement("s3").is_some());
+
+        // statements of the other connection are not counted
+        let (mut other, other_server) = start();
+        let mut buf = BytesMut::new();
+        for message in [
+            parse(Some("s4")),
+            parse(Some("s5")),
+            PgWireFrontendMessage::Sync(PgSync::new()),
+        ] {
+            message.encode(&mut buf).unwrap();
+        }
+        other.write_all(&buf).await.unwrap();
+        let mut buf = BytesMut::new();
+        for _ in 0..2 {
+            assert!(matches!(
+                read_message(&mut other, &mut buf).await,
+                PgWireBackendMessage::ParseComplete(_)
+            ));
+        }
+        assert!(matches!(
+            read_message(&mut other, &mut buf).await,
+            PgWireBackendMessage::ReadyForQuery(_)
+        ));
+
+        drop(client);
+        server.await.unwrap().unwrap();
+        drop(other);
+        other_server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_transaction_status() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let server = spawn_connection(
+            server_socket(server, PgWireConnectionState::ReadyForQuery),
+            Arc::new(NoopStartupHandler),
+            Arc::new(EmptyQueryHandler),
+            Arc::new(FailingQueryHandler {
+                portal_store: Arc::new(MemPortalStore::new()),
+            }),
+            Arc::new(NoopCopyHandler),
+            ConnectionLimits::default(),
+        );
+
+        let pipelines = vec![
+            // implicit transaction, the last statement is skipped
+            vec!["INSERT 1", "FAIL", "INSERT 2"],
+            // explicit transaction block, failed and then rolled back
+            vec!["BEGIN", "INSERT 1"],
+            vec!["FAIL", "INSERT 2"],
+            vec!["ROLLBACK"],
+        ];
+
+        let mut read_buf = BytesMut::new();
+        let mut results = Vec::new();
+        for pipeline in pipelines {
+            let mut buf = BytesMut::new();
+            for sql in pipeline {
+                PgWireFrontendMessage::Parse(Parse::new(None, sql.to_owned(), vec![]))
+                    .encode(&mut buf)
+                    .unwrap();
+                PgWireFrontendMessage::Bind(Bind::new(None, None, vec![], vec![], vec![]))
+                    .encode(&mut buf)
+                    .unwrap();
+                PgWireFrontendMessage::Execute(Execute::new(None, 0))
+                    .encode(&mut buf)
+                    .unwrap();
+            }
+            PgWireFrontendMessage::Sync(PgSync::new())
+                .encode(&mut buf)
+                .unwrap();
+            client.write_all(&buf).await.unwrap();
+
+            // (completed statements, errors, transaction status)
+            let mut result = (0, 0, 0u8);
+            loop {
+                match read_message(&mut client, &mut read_buf).await {
+                    PgWireBackendMessage::ReadyForQuery(ready) => {
+                        result.2 = *ready.status();
+                        break;
+                    }
+                    PgWireBackendMessage::CommandComplete(_) => result.0 += 1,
+                    PgWireBackendMessage::ErrorResponse(_) => result.1 += 1,
+                    PgWireBackendMessage::ParseComplete(_)
+                    | PgWireBackendMessage::BindComplete(_) => {}
+                    msg => panic!("unexpected message {msg:?}"),
+                }
+            }
+            results.push(result);
+        }
+        assert_eq!(
+            vec![(1, 1, b'I'), (2, 0, b'T'), (0, 1, b'E'), (1, 0, b'I')],
+            results
+        );
+
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    /// Simple query handler with its own message flow, that sends
+    /// `ReadyForQuery` by itself and fails statement `FAIL` afterwards
+    struct CustomFlowQueryHandler;
+
+    #[async_trait]
+    impl SimpleQueryHandler for CustomFlowQueryHandler {
+        async fn on_query<C>(&self, client: &mut C, query: Query) -> PgWireResult<()>
+        where
+            C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            client
+                .feed(PgWireBackendMessage::CommandComplete(
+                    Tag::new_for_execution(query.query(), None).into(),
+                ))
+                .await?;
+            send_ready_for_query(client, TransactionStatus::Transaction).await?;
+            if query.query() == "FAIL" {
+                return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                    Severity::Error,
+                    "XX000",
+                    "failed after ready",
+                ))));
+            }
+            Ok(())
+        }
+
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &C,
+            _query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            unimplemented!()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_ready_for_query() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let server = spawn_connection(
+            server_socket(server, PgWireConnectionState::ReadyForQuery),
+            Arc::new(NoopStartupHandler),
+            Arc::new(CustomFlowQueryHandler),
+            Arc::new(PlaceholderExtendedQueryHandler),
+            Arc::new(NoopCopyHandler),
+            ConnectionLimits::default(),
+        );
+
+        let mut buf = BytesMut::new();
+        for sql in ["BEGIN", "FAIL", "SELECT"] {
+            PgWireFrontendMessage::Query(Query::new(sql.to_owned()))
+                .encode(&mut buf)
+                .unwrap();
+        }
+        client.write_all(&buf).await.unwrap();
+
+        let mut buf = BytesMut::new();
+        let mut received = Vec::new();
+        for _ in 0..7 {
+            received.push(match read_message(&mut client, &mut buf).await {
+                PgWireBackendMessage::CommandComplete(cc) => cc.tag().clone(),
+                PgWireBackendMessage::ReadyForQuery(ready) => (*ready.status() as char).to_string(),
+                PgWireBackendMessage::ErrorResponse(error) => error_code(&error).to_owned(),
+                msg => panic!("unexpected message {msg:?}"),
+            });
+        }
+        // no second `ReadyForQuery` after the error
+        assert_eq!(
+            vec!["BEGIN", "T", "FAIL", "T", "XX000", "SELECT", "T"],
+            received
+        );
+
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    /// Simple query handler that returns endless rows, and counts rows polled
+    /// from the stream
+    #[derive(Default)]
+    struct EndlessQueryHandler {
+        produced: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl SimpleQueryHandler for EndlessQueryHandler {
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &C,
+            _query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            let schema = Arc::new(vec![FieldInfo::new(
+                "v".to_owned(),
+                None,
+                None,
+                postgres_types::Type::INT8,
+                FieldFormat::Text,
+            )]);
+            let schema_ref = schema.clone();
+            let produced = self.produced.clone();
+            let rows = futures::stream::repeat(()).map(move |_| {
+                let n = produced.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                let mut encoder = DataRowEncoder::new(schema_ref.clone());
+                encoder.encode_field(&(n as i64))?;
+                encoder.finish()
+            });
+            Ok(vec![Response::Query(QueryResponse::new(schema, rows))])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backpressure_with_slow_reader() {
+        use std::sync::atomic::Ordering;
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut socket = server_socket(server, PgWireConnectionState::ReadyForQuery);
+        socket.set_backpressure_boundary(4096);
+
+        let handler = Arc::new(EndlessQueryHandler::default());
+        let server = spawn_connection(
+            socket,
+            Arc::new(NoopStartupHandler),
+            handler.clone(),
+            Arc::new(PlaceholderExtendedQueryHandler),
+            Arc::new(NoopCopyHandler),
+            ConnectionLimits::default(),
+        );
+
+        let mut buf = BytesMut::new();
+        Query::new("SELECT v FROM endless".to_owned())
+            .encode(&mut buf)
+            .unwrap();
+        client.write_all(&buf).await.unwrap();
+
+        // the client doesn't read, so the rows polled are bounded by buffer
+        // sizes, each row is at least 12 bytes in this test
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let produced = handler.produced.load(Ordering::SeqCst);
+        assert!(produced > 0);
+        assert!(produced < (4096 + 1024) / 10, "{produced} rows buffered");
+
+        // reading from client resumes the stream
+        let mut buf = BytesMut::new();
+        for _ in 0..(produced * 2) {
+            read_message(&mut client, &mut buf).await;
+        }
+        assert!(handler.produced.load(Ordering::SeqCst) > produced);
+
+        drop(client);
+        assert!(server.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connection_registry() {
+        use crate::api::activity::ActivityState;
+
+        tokio::time::pause();
+        let registry = ConnectionRegistry::new();
+        let (mut client, server) = tokio::io::duplex(1024);
+        let server = spawn_connection(
+            server_socket(server, PgWireConnectionState::AwaitingStartup),
+            Arc::new(NoopStartupHandler),
+            Arc::new(EndlessQueryHandler::default()),
+            Arc::new(PlaceholderExtendedQueryHandler),
+            Arc::new(NoopCopyHandler),
+            ConnectionLimits {
+                connection_registry: Some(registry.clone()),
+                ..Default::default()
+            },
+        );
+
+        let mut startup = Startup::new();
+        for (k, v) in [
+            ("user", "alice"),
+            ("database", "app"),
+            ("application_name", "psql"),
+        ] {
+            startup.parameters_mut().insert(k.to_owned(), v.to_owned());
+        }
+        let mut buf = BytesMut::new();
+        startup.encode(&mut buf).unwrap();
+        client.write_all(&buf).await.unwrap();
+        let mut buf = BytesMut::new();
+        let mut pid = None;
+        loop {
+            match read_message(&mut client, &mut buf).await {
+                PgWireBackendMessage::BackendKeyData(key) => pid = Some(*key.pid()),
+                PgWireBackendMessage::ReadyForQuery(_) => break,
+                _ => {}
+            }
+        }
+
+        let connections = registry.connections();
+        assert_eq!(1, connections.len());
+        let connection = &connections[0];
+        // identified by the pid sent to client
+        assert_eq!(pid, Some(*connection.pid()));
+        assert_eq!(Some("alice"), connection.user().as_deref());
+        assert_eq!(Some("app"), connection.database().as_deref());
+        assert_eq!(Some("psql"), connection.application_name().as_deref());
+        assert_eq!(ActivityState::Idle, *connection.state());
+        assert!(connection.query().is_none());
+
+        // the endless query is blocked by the client not reading
+        let mut buf = BytesMut::new();
+        Query::new("SELECT v FROM endless".to_owned())
+            .encode(&mut buf)
+            .unwrap();
+        client.write_all(&buf).await.unwrap();
+        // with time paused, returns once the connection is blocked, without
+        // waiting real time
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        let connection = registry.get(*connection.pid()).unwrap();
+        assert_eq!(ActivityState::Active, *connection.state());
+        assert_eq!(Some("SELECT v FROM endless"), connection.query().as_deref());
+        assert!(connection.query_start().is_some());
+
+        drop(client);
+        assert!(server.await.unwrap().is_err());
+        assert!(registry.is_empty());
+    }
+
+    /// Simple query handler that returns an endless `COPY TO STDOUT`, and
+    /// counts chunks polled from the stream
+    #[derive(Default)]
+    struct EndlessCopyOutHandler {
+        produced: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    const COPY_CHUNK_SIZE: usize = 16 * 1024;
+
+    #[async_trait]
+    impl SimpleQueryHandler for EndlessCopyOutHandler {
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &C,
+            _query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            let produced = self.produced.clone();
+            let data = futures::stream::repeat(()).map(move |_| {
+                produced.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                Ok(CopyOutData::row(Bytes::from(vec![b'x'; COPY_CHUNK_SIZE])))
+            });
+            Ok(vec![Response::copy_out(
+                CopyResponse::new(FieldFormat::Text, 1),
+                data,
+            )])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_copy_out_slow_reader() {
+        use std::sync::atomic::Ordering;
+
+        use crate::test_util::{ChaosOptions, ChaosStream};
+
+        let (client, server) = tokio::io::duplex(COPY_CHUNK_SIZE);
+        let mut socket = server_socket(server, PgWireConnectionState::ReadyForQuery);
+        socket.set_backpressure_boundary(4096);
+
+        let handler = Arc::new(EndlessCopyOutHandler::default());
+        let server = spawn_connection(
+            socket,
+            Arc::new(NoopStartupHandler),
+            handler.clone(),
+            Arc::new(PlaceholderExtendedQueryHandler),
+            Arc::new(NoopCopyHandler),
+            ConnectionLimits::default(),
+        );
+
+        let mut options = ChaosOptions::new();
+        options
+            .set_read_delay(Some(std::time::Duration::from_millis(1)))
+            .set_max_read_size(Some(8192));
+        let mut client = ChaosStream::new(client, options);
+        let mut buf = BytesMut::new();
+        Query::new("COPY endless TO STDOUT".to_owned())
+            .encode(&mut buf)
+            .unwrap();
+        client.write_all(&buf).await.unwrap();
+
+        let mut buf = BytesMut::new();
+        assert!(matches!(
+            read_message(&mut client, &mut buf).await,
+            PgWireBackendMessage::CopyOutResponse(_)
+        ));
+        // read 1MiB slowly, chunks polled ahead of the reader are bounded by
+        // the write buffer and the socket buffer, not by the data sent
+        let mut received = 0;
+        while received < 1024 * 1024 / COPY_CHUNK_SIZE {
+            match read_message(&mut client, &mut buf).await {
+                PgWireBackendMessage::CopyData(data) => {
+                    assert_eq!(COPY_CHUNK_SIZE, data.data().len())
+                }
+                msg => panic!("unexpected message {msg:?}"),
+            }
+            received += 1;
+            let ahead = handler.produced.load(Ordering::SeqCst) - received;
+            assert!(ahead <= 3, "{ahead} chunks buffered");
+        }
+
+        drop(client);
+        assert!(server.await.unwrap().is_err());
+    }
+
+    /// Startup handler that asks for `steps` password messages
+    struct MultiStepStartupHandler {
+        steps: usize,
+        received: std::sync::atomic::AtomicUsize,
+    }
+
+    #[async_trait]
+    impl StartupHandler for MultiStepStartupHandler {
+        async fn on_startup<C>(
+            &self,
+            client: &mut C,
+            message: PgWireFrontendMessage,
+        ) -> PgWireResult<()>
+        where
+            C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            use std::sync::atomic::Ordering;
+
+            if let PgWireFrontendMessage::Startup(_) = message {
+                client.set_state(PgWireConnectionState::AuthenticationInProgress);
+            } else if self.received.fetch_add(1, Ordering::SeqCst) + 1 == self.steps {
+                finish_authentication(client, &DefaultServerParameterProvider).await?;
+                return Ok(());
+            }
+            client
+                .send(PgWireBackendMessage::Authentication(
+                    Authentication::CleartextPassword,
+                ))
+                .await?;
+            Ok(())
+        }
+    }
+
+    /// Server parameter provider failing the session start
+    struct FailingSessionStartProvider;
+
+    #[async_trait]
+    impl crate::api::auth::ServerParameterProvider for FailingSessionStartProvider {
+        fn server_parameters<C>(&self, client: &C) -> Option<HashMap<String, String>>
+        where
+            C: ClientInfo,
+        {
+            DefaultServerParameterProvider.server_parameters(client)
+        }
+
+        async fn on_session_start<C>(&self, _client: &mut C) -> PgWireResult<()>
+        where
+            C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                Severity::Fatal,
+                SqlState::InvalidAuthorizationSpecification,
+                "no session defaults",
+            ))))
+        }
+    }
+
+    struct FailingSessionStartupHandler;
+
+    #[async_trait]
+    impl StartupHandler for FailingSessionStartupHandler {
+        async fn on_startup<C>(
+            &self,
+            client: &mut C,
+            _message: PgWireFrontendMessage,
+        ) -> PgWireResult<()>
+        where
+            C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            finish_authentication(client, &FailingSessionStartProvider).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_session_start_error_ends_connection() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let server = spawn_connection(
+            server_socket(server, PgWireConnectionState::AwaitingStartup),
+            Arc::new(FailingSessionStartupHandler),
+            Arc::new(EmptyQueryHandler),
+            Arc::new(PlaceholderExtendedQueryHandler),
+            Arc::new(NoopCopyHandler),
+            ConnectionLimits::default(),
+        );
+
+        let mut startup = Startup::new();
+        startup
+            .parameters_mut()
+            .insert("user".to_owned(), "tomcat".to_owned());
+        let mut buf = BytesMut::new();
+        startup.encode(&mut buf).unwrap();
+        client.write_all(&buf).await.unwrap();
+
+        // the error is the last message, without BackendKeyData and
+        // ReadyForQuery
+        let mut buf = BytesMut::new();
+        loop {
+            match read_message(&mut client, &mut buf).await {
+                PgWireBackendMessage::ErrorResponse(error) => {
+                    assert_eq!("28000", error_code(&error));
+                    break;
+                }
+                msg @ (PgWireBackendMessage::BackendKeyData(_)
+                | PgWireBackendMessage::ReadyForQuery(_)) => {
+                    panic!("unexpected message {msg:?}")
+                }
+                _ => {}
+            }
+        }
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty() && buf.is_empty());
+        server.await.unwrap().unwrap();
+    }
+
+    struct UnknownUserStartupHandler;
+
+    #[async_trait]
+    impl StartupHandler for UnknownUserStartupHandler {
+        async fn on_startup<C>(
+            &self,
+            _client: &mut C,
+            _message: PgWireFrontendMessage,
+        ) -> PgWireResult<()>
+        where
+            C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            Err(PgWireError::UnknownUser("tomcat".to_owned()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unknown_user_error() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let server = spawn_connection(
+            server_socket(server, PgWireConnectionState::AwaitingStartup),
+            Arc::new(UnknownUserStartupHandler),
+            Arc::new(EmptyQueryHandler),
+            Arc::new(PlaceholderExtendedQueryHandler),
+            Arc::new(NoopCopyHandler),
+            ConnectionLimits::default(),
+        );
+
+        let mut buf = BytesMut::new();
+        Startup::new().encode(&mut buf).unwrap();
+        client.write_all(&buf).await.unwrap();
+        let mut buf = BytesMut::new();
+        match read_message(&mut client, &mut buf).await {
+            PgWireBackendMessage::ErrorResponse(error) => {
+                assert_eq!("28000", error_code(&error));
+                assert!(error.fields().iter().any(|f| f.1 == "FATAL"));
+            }
+            msg => panic!("unexpected message {msg:?}"),
+        }
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_protocol_version() {
+        let start = |server| {
+            spawn_connection(
+                server_socket(server, PgWireConnectionState::AwaitingStartup),
+                Arc::new(NoopStartupHandler),
+                Arc::new(EmptyQueryHandler),
+                Arc::new(PlaceholderExtendedQueryHandler),
+                Arc::new(NoopCopyHandler),
+                ConnectionLimits::default(),
+            )
+        };
+        let startup = |version: i32| {
+            let mut buf = BytesMut::new();
+            buf.put_i32(4 + 4 + 13);
+            buf.put_i32(version);
+            buf.put_slice(b"user\0tomcat\0\0");
+            buf
+        };
+
+        // protocol 2.0 clients get the error in their own format
+        let (mut client, server) = tokio::io::duplex(4096);
+        let server = start(server);
+        client.write_all(&startup(2 << 16)).await.unwrap();
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(
+            &b"EFATAL:  unsupported frontend protocol 2.0: server supports 3.0 to 3.0\n\0"[..],
+            &received[..]
+        );
+        server.await.unwrap().unwrap();
+
+        let (mut client, server) = tokio::io::duplex(4096);
+        let server = start(server);
+        client.write_all(&startup(4 << 16 | 1)).await.unwrap();
+        let mut buf = BytesMut::new();
+        match read_message(&mut client, &mut buf).await {
+            PgWireBackendMessage::ErrorResponse(error) => {
+                assert_eq!("08P01", error_code(&error));
+                assert!(error
+                    .fields()
+                    .iter()
+                    .any(|f| f.1.starts_with("unsupported frontend protocol 4.1")));
+            }
+            msg => panic!("unexpected message {msg:?}"),
+        }
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let server = spawn_connection(
+            server_socket(server, PgWireConnectionState::AwaitingStartup),
+            Arc::new(NoopStartupHandler),
+            Arc::new(EmptyQueryHandler),
+            Arc::new(PlaceholderExtendedQueryHandler),
+            Arc::new(NoopCopyHandler),
+            ConnectionLimits {
+                compression: vec![Arc::new(InvertCompression)],
+                ..Default::default()
+            },
+        );
+
+        let mut startup = Startup::new();
+        startup.set_protocol_number_minor(1);
+        for (key, value) in [
+            ("user", "tomcat"),
+            ("_pq_.compression", "zstd,invert"),
+            ("_pq_.tracing", "on"),
+        ] {
+            startup
+                .parameters_mut()
+                .insert(key.to_owned(), value.to_owned());
+        }
+        let mut buf = BytesMut::new();
+        startup.encode(&mut buf).unwrap();
+        client.write_all(&buf).await.unwrap();
+
+        let mut buf = BytesMut::new();
+        match read_message(&mut client, &mut buf).await {
+            PgWireBackendMessage::NegotiateProtocolVersion(negotiate) => {
+                assert_eq!(0, *negotiate.newest_minor_version());
+                assert_eq!(&vec!["_pq_.tracing"], negotiate.unrecognized_options());
+            }
+            msg => panic!("unexpected message {msg:?}"),
+        }
+        match read_message(&mut client, &mut buf).await {
+            PgWireBackendMessage::ParameterStatus(status) => {
+                assert_eq!("_pq_.compression", status.name());
+                assert_eq!("invert", status.value());
+            }
+            msg => panic!("unexpected message {msg:?}"),
+        }
+        loop {
+            if let PgWireBackendMessage::ReadyForQuery(_) =
+                read_message(&mut client, &mut buf).await
+            {
+                break;
+            }
+        }
+
+        // messages are compressed in both directions after startup
+        let mut raw = BytesMut::new();
+        PgWireFrontendMessage::Query(Query::new("SELECT 1".to_owned()))
+            .encode(&mut raw)
+            .unwrap();
+        let mut compressed = BytesMut::new();
+        compression::write_block(&InvertCompression, &raw, &mut compressed).unwrap();
+        client.write_all(&compressed).await.unwrap();
+
+        let mut decompressed = BytesMut::new();
+        let mut received = Vec::new();
+        let mut blocks = 0;
+        while received.len() < 2 {
+            if let Some(msg) = PgWireBackendMessage::decode(&mut decompressed).unwrap() {
+                received.push(msg);
+            } else if compression::read_block(&InvertCompression, &mut buf, &mut decompressed)
+                .unwrap()
+            {
+                blocks += 1;
+            } else {
+                client.read_buf(&mut buf).await.unwrap();
+            }
+        }
+        // flushed together, in one block
+        assert_eq!(1, blocks);
+        assert!(matches!(
+            received.as_slice(),
+            [
+                PgWireBackendMessage::CommandComplete(_),
+                PgWireBackendMessage::ReadyForQuery(_)
+            ]
+        ));
+
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_decompress_one_block_at_a_time() {
+        let mut client_info = ClientInfoHolder::new("127.0.0.1:5432".parse().unwrap(), false);
+        client_info.set_state(PgWireConnectionState::ReadyForQuery);
+        let mut codec = PgWireMessageServerCodec::new(client_info);
+        codec.enable_compression(Arc::new(InvertCompression));
+
+        let mut src = BytesMut::new();
+        for query in ["SELECT 1", "SELECT 2"] {
+            let mut raw = BytesMut::new();
+            Query::new(query.to_owned()).encode(&mut raw).unwrap();
+            compression::write_block(&InvertCompression, &raw, &mut src).unwrap();
+        }
+        let block_len = src.len() / 2;
+
+        assert!(matches!(
+            codec.decode(&mut src).unwrap(),
+            Some(PgWireFrontendMessage::Query(query)) if query.query() == "SELECT 1"
+        ));
+        // the second block is still compressed
+        assert_eq!(block_len, src.len());
+        assert!(codec.decompressed.is_empty());
+        assert!(matches!(
+            codec.decode(&mut src).unwrap(),
+            Some(PgWireFrontendMessage::Query(query)) if query.query() == "SELECT 2"
+        ));
+        assert!(codec.decode(&mut src).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_auth_timeout() {
+        let start = |server, max_auth_duration: Option<Duration>| {
+            spawn_connection(
+                server_socket(server, PgWireConnectionState::AwaitingStartup),
+                Arc::new(MultiStepStartupHandler {
+                    steps: 3,
+                    received: Default::default(),
+                }),
+                Arc::new(EmptyQueryHandler),
+                Arc::new(PlaceholderExtendedQueryHandler),
+                Arc::new(NoopCopyHandler),
+                ConnectionLimits {
+                    read_timeout: None,
+                    auth_timeout: Some(Duration::from_millis(200)),
+                    auth_deadline: max_auth_duration.map(|duration| Instant::now() + duration),
+                    ..Default::default()
+                },
+            )
+        };
+        let mut startup = BytesMut::new();
+        let mut message = Startup::new();
+        message
+            .parameters_mut()
+            .insert("user".to_owned(), "tomcat".to_owned());
+        message.encode(&mut startup).unwrap();
+        let mut password = BytesMut::new();
+        PgWireFrontendMessage::PasswordMessageFamily(PasswordMessageFamily::Password(
+            Password::new("tomcat".to_owned()),
+        ))
+        .encode(&mut password)
+        .unwrap();
+
+        // stalled in the middle of authentication
+        let (mut client, server) = tokio::io::duplex(4096);
+        let server = start(server, None);
+        client.write_all(&startup).await.unwrap();
+        client.write_all(&password).await.unwrap();
+        let mut buf = BytesMut::new();
+        let mut received = Vec::new();
+        loop {
+            match read_message(&mut client, &mut buf).await {
+                PgWireBackendMessage::Authentication(_) => received.push("auth".to_owned()),
+                PgWireBackendMessage::ErrorResponse(error) => {
+                    received.push(error_code(&error).to_owned());
+                    break;
+                }
+                msg => panic!("unexpected message {msg:?}"),
+            }
+        }
+        assert_eq!(vec!["auth", "auth", "57014"], received);
+        server.await.unwrap().unwrap();
+
+        // a slow exchange making progress is capped by max_auth_duration
+        let (mut client, server) = tokio::io::duplex(4096);
+        let server = start(server, Some(Duration::from_millis(250)));
+        client.write_all(&startup).await.unwrap();
+        for _ in 0..3 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            if client.write_all(&password).await.is_err() {
+                break;
+            }
+        }
+        let mut buf = BytesMut::new();
+        loop {
+            match read_message(&mut client, &mut buf).await {
+                PgWireBackendMessage::Authentication(_) => {}
+                PgWireBackendMessage::ErrorResponse(error) => {
+                    assert_eq!("57014", error_code(&error));
+                    break;
+                }
+                msg => panic!("unexpected message {msg:?}"),
+            }
+        }
+        server.await.unwrap().unwrap();
+
+        // the timeout restarts on each message, a slow exchange making
+        // progress is not timed out
+        let (mut client, server) = tokio::io::duplex(4096);
+        let server = start(server, Some(Duration::from_secs(5)));
+        client.write_all(&startup).await.unwrap();
+        for _ in 0..3 {
+            tokio::time::sleep(Duration::from_millis(120)).await;
+            client.write_all(&password).await.unwrap();
+        }
+        let mut buf = BytesMut::new();
+        while !matches!(
+            read_message(&mut client, &mut buf).await,
+            PgWireBackendMessage::ReadyForQuery(_)
+        ) {}
+
+        // not limited after authentication
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let mut query = BytesMut::new();
+        Query::new("SELECT 1".to_owned())
+            .encode(&mut query)
+            .unwrap();
+        client.write_all(&query).await.unwrap();
+        assert!(matches!(
+            read_message(&mut client, &mut buf).await,
+            PgWireBackendMessage::CommandComplete(_)
+        ));
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_query_with_embedded_nul() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let server = spawn_connection(
+            server_socket(server, PgWireConnectionState::ReadyForQuery),
+            Arc::new(NoopStartupHandler),
+            Arc::new(EmptyQueryHandler),
+            Arc::new(PlaceholderExtendedQueryHandler),
+            Arc::new(NoopCopyHandler),
+            ConnectionLimits::default(),
+        );
+
+        let mut buf = BytesMut::new();
+        buf.put_u8(b'Q');
+        buf.put_i32(4 + 14);
+        buf.put_slice(b"SELECT 1\0; x\0\0");
+        client.write_all(&buf).await.unwrap();
+        let mut received = BytesMut::new();
+        match read_message(&mut client, &mut received).await {
+            PgWireBackendMessage::ErrorResponse(error) => {
+                assert_eq!("08P01", error_code(&error))
+            }
+            msg => panic!("unexpected message {msg:?}"),
+        }
+        assert!(server.await.unwrap().is_ok());
+    }
+
+    /// Handler of `SELECT '<float>'::float8`, responding with the float
+    struct FloatQueryHandler {
+        portal_store: Arc<MemPortalStore<String>>,
+    }
+
+    impl FloatQueryHandler {
+        fn schema(format: FieldFormat) -> Arc<Vec<FieldInfo>> {
+            Arc::new(vec![FieldInfo::new(
+                "float8".to_owned(),
+                None,
+                None,
+                postgres_types::Type::FLOAT8,
+                format,
+            )])
+        }
+
+        fn respond<'a>(query: &str, format: FieldFormat) -> PgWireResult<Response<'a>> {
+            let literal = query.split('\'').nth(1).unwrap_or_default();
+            let value: f64 = literal.parse().map_err(|_| {
+                PgWireError::UserError(Box::new(ErrorInfo::new(
+                    Severity::Error,
+                    SqlState::InvalidTextRepresentation,
+                    format!("invalid input syntax for type double precision: \"{literal}\""),
+                )))
+            })?;
+            let schema = FloatQueryHandler::schema(format);
+            let mut encoder = DataRowEncoder::new(schema.clone());
+            encoder.encode_field(&value)?;
+            let row = encoder.finish();
+            Ok(Response::Query(QueryResponse::new(
+                schema,
+                futures::stream::iter(vec![row]),
+            )))
+        }
+    }
+
+    #[async_trait]
+    impl SimpleQueryHandler for FloatQueryHandler {
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &C,
+            query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            Ok(vec![FloatQueryHandler::respond(query, FieldFormat::Text)?])
+        }
+    }
+
+    #[async_trait]
+    impl ExtendedQueryHandler for FloatQueryHandler {
+        type Statement = String;
+        type PortalStore = MemPortalStore<Self::Statement>;
+        type QueryParser = NoopQueryParser;
+
+        fn portal_store(&self) -> Arc<Self::PortalStore> {
+            self.portal_store.clone()
+        }
+
+        fn query_parser(&self) -> Arc<Self::QueryParser> {
+            Arc::new(NoopQueryParser::new())
+        }
+
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &mut C,
+            portal: &'a Portal<Self::Statement>,
+            _max_rows: usize,
+        ) -> PgWireResult<Response<'a>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            let format = portal.result_column_format().format_for(0);
+            FloatQueryHandler::respond(portal.statement().statement(), format)
+        }
+
+        async fn do_describe<C>(
+            &self,
+            _client: &mut C,
+            target: StatementOrPortal<'_, Self::Statement>,
+        ) -> PgWireResult<DescribeResponse>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            let format = match target {
+                StatementOrPortal::Statement(_) => FieldFormat::Text,
+                StatementOrPortal::Portal(portal) => portal.result_column_format().format_for(0),
+            };
+            Ok(DescribeResponse::new(
+                None,
+                FloatQueryHandler::schema(format).as_ref().clone(),
+            ))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_float_nan_round_trip() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let handler = Arc::new(FloatQueryHandler {
+            portal_store: Arc::new(MemPortalStore::new()),
+        });
+        let server = spawn_connection(
+            server_socket(server, PgWireConnectionState::ReadyForQuery),
+            Arc::new(NoopStartupHandler),
+            handler.clone(),
+            handler,
+            Arc::new(NoopCopyHandler),
+            ConnectionLimits::default(),
+        );
+
+        // like a driver, a simple query in text, and an extended query in
+        // binary, each decoded as f64
+        let query = "SELECT 'NaN'::float8";
+        let messages = vec![
+            PgWireFrontendMessage::Query(Query::new(query.to_owned())),
+            PgWireFrontendMessage::Parse(Parse::new(None, query.to_owned(), vec![])),
+            PgWireFrontendMessage::Bind(Bind::new(None, None, vec![], vec![], vec![1])),
+            PgWireFrontendMessage::Execute(Execute::new(None, 0)),
+            PgWireFrontendMessage::Sync(PgSync::new()),
+        ];
+        let mut buf = BytesMut::new();
+        for message in messages {
+            message.encode(&mut buf).unwrap();
+        }
+        client.write_all(&buf).await.unwrap();
+
+        let mut buf = BytesMut::new();
+        let mut fields = Vec::new();
+        let mut ready = 0;
+        while ready < 2 {
+            match read_message(&mut client, &mut buf).await {
+                PgWireBackendMessage::DataRow(row) => fields.push(row.fields()[0].clone().unwrap()),
+                PgWireBackendMessage::ReadyForQuery(_) => ready += 1,
+                PgWireBackendMessage::ErrorResponse(error) => panic!("{error:?}"),
+                _ => {}
+            }
+        }
+        assert_eq!(2, fields.len());
+        assert_eq!(&b"NaN"[..], &fields[0][..]);
+        let text: f64 = std::str::from_utf8(&fields[0]).unwrap().parse().unwrap();
+        assert!(text.is_nan());
+        // float8send('NaN'::float8) of postgres 15
+        assert_eq!(&[0x7f, 0xf8, 0, 0, 0, 0, 0, 0][..], &fields[1][..]);
+        let binary =
+            <f64 as postgres_types::FromSql>::from_sql(&postgres_types::Type::FLOAT8, &fields[1])
+                .unwrap();
+        assert!(binary.is_nan());
+
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_message_read_timeout() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let server = spawn_connection(
+            server_socket(server, PgWireConnectionState::ReadyForQuery),
+            Arc::new(NoopStartupHandler),
+            Arc::new(EmptyQueryHandler),
+            Arc::new(PlaceholderExtendedQueryHandler),
+            Arc::new(NoopCopyHandler),
+            ConnectionLimits {
+                read_timeout: Some(Duration::from_millis(50)),
+                auth_deadline: None,
+                ..Default::default()
+            },
+        );
+
+        // idle time between messages is not limited
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut buf = BytesMut::new();
+        Query::new("SELECT 1".to_owned()).encode(&mut buf).unwrap();
+        client.write_all(&buf).await.unwrap();
+        let mut received = BytesMut::new();
+        assert!(matches!(
+            read_message(&mut client, &mut received).await,
+            PgWireBackendMessage::CommandComplete(_)
+        ));
+        assert!(matches!(
+            read_message(&mut client, &mut received).await,
+            PgWireBackendMessage::ReadyForQuery(_)
+        ));
+
+        // a message that never completes
+        client.write_all(&buf[..buf.len() - 2]).await.unwrap();
+        match read_message(&mut client, &mut received).await {
+            PgWireBackendMessage::ErrorResponse(error) => {
+                assert_eq!("08P01", error_code(&error))
+            }
+            msg => panic!("unexpected message {msg:?}"),
+        }
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_timeout_slow_client() {
+        use crate::test_util::{ChaosOptions, ChaosStream};
+
+        let start = |server, read_timeout| {
+            // a client sending one byte every 20ms
+            let mut options = ChaosOptions::new();
+            options
+                .set_max_read_size(Some(1))
+                .set_read_delay(Some(Duration::from_millis(20)));
+            spawn_connection(
+                server_socket(
+                    ChaosStream::new(server, options),
+                    PgWireConnectionState::ReadyForQuery,
+                ),
+                Arc::new(NoopStartupHandler),
+                Arc::new(EmptyQueryHandler),
+                Arc::new(PlaceholderExtendedQueryHandler),
+                Arc::new(NoopCopyHandler),
+                ConnectionLimits {
+                    read_timeout: Some(read_timeout),
+                    ..Default::default()
+                },
+            )
+        };
+        let mut query = BytesMut::new();
+        Query::new("SELECT 1".to_owned())
+            .encode(&mut query)
+            .unwrap();
+
+        // trickling a message slower than the timeout
+        let (mut client, server) = tokio::io::duplex(4096);
+        let server = start(server, Duration::from_millis(100));
+        client.write_all(&query).await.unwrap();
+        let mut buf = BytesMut::new();
+        match read_message(&mut client, &mut buf).await {
+            PgWireBackendMessage::ErrorResponse(error) => {
+                assert_eq!("08P01", error_code(&error))
+            }
+            msg => panic!("unexpected message {msg:?}"),
+        }
+        server.await.unwrap().unwrap();
+
+        // split reads completing within the timeout
+        let (mut client, server) = tokio::io::duplex(4096);
+        let server = start(server, Duration::from_secs(5));
+        client.write_all(&query).await.unwrap();
+        let mut buf = BytesMut::new();
+        assert!(matches!(
+            read_message(&mut client, &mut buf).await,
+            PgWireBackendMessage::CommandComplete(_)
+        ));
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    struct EncryptionQueryHandler;
+
+    #[async_trait]
+    impl SimpleQueryHandler for EncryptionQueryHandler {
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            client: &C,
+            _query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            let tag = if client.is_encrypted() {
+                "ENCRYPTED"
+            } else {
+                "PLAINTEXT"
+            };
+            Ok(vec![Response::Execution(Tag::new_for_execution(tag, None))])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_is_encrypted() {
+        // secure without a TLS session, like a transport setting it, is not
+        // encrypted
+        for (secure, session, expected) in [
+            (true, true, "ENCRYPTED"),
+            (true, false, "PLAINTEXT"),
+            (false, false, "PLAINTEXT"),
+        ] {
+            let (mut client, server) = tokio::io::duplex(4096);
+            let mut socket = server_socket(server, PgWireConnectionState::ReadyForQuery);
+            let client_info = socket.codec_mut().client_info_mut();
+            client_info.set_is_secure(secure);
+            if session {
+                client_info.extensions_mut().insert(TlsSession::new(
+                    "TLSv1.3".to_owned(),
+                    "TLS13_AES_256_GCM_SHA384".to_owned(),
+                ));
+            }
+            let server = spawn_connection(
+                socket,
+                Arc::new(NoopStartupHandler),
+                Arc::new(EncryptionQueryHandler),
+                Arc::new(PlaceholderExtendedQueryHandler),
+                Arc::new(NoopCopyHandler),
+                ConnectionLimits::default(),
+            );
+
+            let mut buf = BytesMut::new();
+            Query::new("SHOW ssl".to_owned()).encode(&mut buf).unwrap();
+            client.write_all(&buf).await.unwrap();
+            let mut received = BytesMut::new();
+            match read_message(&mut client, &mut received).await {
+                PgWireBackendMessage::CommandComplete(cc) => assert_eq!(expected, cc.tag()),
+                msg => panic!("unexpected message {msg:?}"),
+            }
+
+            drop(client);
+            server.await.unwrap().unwrap();
+        }
+    }
+
+    struct CopyQueryHandler;
+
+    #[async_trait]
+    impl SimpleQueryHandler for CopyQueryHandler {
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &C,
+            _query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            Ok(vec![Response::CopyIn(CopyResponse::new(
+                FieldFormat::Text,
+                2,
+            ))])
+        }
+    }
+
+    /// Parses copy data as csv, and keeps the rows received
+    struct CsvCopyHandler {
+        parser: std::sync::Mutex<CopyRowParser>,
+        rows: std::sync::Mutex<Vec<Vec<Option<String>>>>,
+    }
+
+    impl CsvCopyHandler {
+        fn new() -> CsvCopyHandler {
+            CsvCopyHandler {
+                parser: std::sync::Mutex::new(CopyRowParser::new(CopyFormat::Csv(
+                    CopyCsvOptions::new(),
+                ))),
+                rows: std::sync::Mutex::new(Vec::new()),
+            }
+        }
+
+        fn take_parsed_rows(&self) {
+            let mut parser = self.parser.lock().unwrap();
+            while let Some(row) = parser.next_row() {
+                self.rows.lock().unwrap().push(row);
+            }
+        }
+    }
+
+    #[async_trait]
+    impl CopyHandler for CsvCopyHandler {
+        async fn on_copy_data<C>(&self, _client: &mut C, copy_data: CopyData) -> PgWireResult<()>
+        where
+            C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            self.parser.lock().unwrap().feed(copy_data.data())?;
+            self.take_parsed_rows();
+            Ok(())
+        }
+
+        async fn on_copy_done<C>(&self, _client: &mut C, _copy_done: CopyDone) -> PgWireResult<Tag>
+        where
+            C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            self.parser.lock().unwrap().finish()?;
+            self.take_parsed_rows();
+            let rows = self.rows.lock().unwrap().len() as u64;
+            Ok(Tag::new_for_execution("COPY", Some(rows)))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_copy_in_row_split_across_frames() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let copy_handler = Arc::new(CsvCopyHandler::new());
+        let server = spawn_connection(
+            server_socket(server, PgWireConnectionState::ReadyForQuery),
+            Arc::new(NoopStartupHandler),
+            Arc::new(CopyQueryHandler),
+            Arc::new(PlaceholderExtendedQueryHandler),
+            copy_handler.clone(),
+            ConnectionLimits::default(),
+        );
+
+        let mut buf = BytesMut::new();
+        Query::new("COPY t FROM STDIN WITH (FORMAT csv)".to_owned())
+            .encode(&mut buf)
+            .unwrap();
+        client.write_all(&buf).await.unwrap();
+        let mut received = BytesMut::new();
+        match read_message(&mut client, &mut received).await {
+            PgWireBackendMessage::CopyInResponse(resp) => assert_eq!(2, *resp.columns()),
+            msg => panic!("unexpected message {msg:?}"),
+        }
+
+        // the second row is split in the middle of a quoted field
+        let mut buf = BytesMut::new();
+        for frame in [&b"1,tom\n2,\"jer"[..], &b"ry, jr\"\n"[..]] {
+            PgWireFrontendMessage::CopyData(CopyData::new(Bytes::from_static(frame)))
+                .encode(&mut buf)
+                .unwrap();
+        }
+        PgWireFrontendMessage::CopyDone(CopyDone::new())
+            .encode(&mut buf)
+            .unwrap();
+        client.write_all(&buf).await.unwrap();
+
+        match read_message(&mut client, &mut received).await {
+            PgWireBackendMessage::CommandComplete(cc) => assert_eq!("COPY 2", cc.tag()),
+            msg => panic!("unexpected message {msg:?}"),
+        }
+        assert!(matches!(
+            read_message(&mut client, &mut received).await,
+            PgWireBackendMessage::ReadyForQuery(_)
+        ));
+        assert_eq!(
+            vec![
+                vec![Some("1".to_owned()), Some("tom".to_owned())],
+                vec![Some("2".to_owned()), Some("jerry, jr".to_owned())],
+            ],
+            *copy_handler.rows.lock().unwrap()
+        );
+
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_copy_in_aborted_by_handler() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let copy_handler = Arc::new(CsvCopyHandler::new());
+        let server = spawn_connection(
+            server_socket(server, PgWireConnectionState::ReadyForQuery),
+            Arc::new(NoopStartupHandler),
+            Arc::new(CopyQueryHandler),
+            Arc::new(PlaceholderExtendedQueryHandler),
+            copy_handler.clone(),
+            ConnectionLimits::default(),
+        );
+
+        // the client keeps sending data before it sees the error
+        let mut buf = BytesMut::new();
+        Query::new("COPY t FROM STDIN WITH (FORMAT csv)".to_owned())
+            .encode(&mut buf)
+            .unwrap();
+        for frame in [&b"1,tom\n"[..], &b"2,\xff\n"[..], &b"3,jerry\n"[..]] {
+            PgWireFrontendMessage::CopyData(CopyData::new(Bytes::from_static(frame)))
+                .encode(&mut buf)
+                .unwrap();
+        }
+        PgWireFrontendMessage::CopyDone(CopyDone::new())
+            .encode(&mut buf)
+            .unwrap();
+        PgWireFrontendMessage::CopyFail(CopyFail::new("late".to_owned()))
+            .encode(&mut buf)
+            .unwrap();
+        Query::new("COPY t FROM STDIN WITH (FORMAT csv)".to_owned())
+            .encode(&mut buf)
+            .unwrap();
+        client.write_all(&buf).await.unwrap();
+
+        let mut received = BytesMut::new();
+        let mut messages = Vec::new();
+        for _ in 0..4 {
+            messages.push(match read_message(&mut client, &mut received).await {
+                PgWireBackendMessage::CopyInResponse(_) => "copy in".to_owned(),
+                PgWireBackendMessage::ReadyForQuery(_) => "ready".to_owned(),
+                PgWireBackendMessage::ErrorResponse(error) => error_code(&error).to_owned(),
+                msg => panic!("unexpected message {msg:?}"),
+            });
+        }
+        // the rest of the aborted copy is dropped, and the connection is
+        // usable for the next query
+        assert_eq!(vec!["copy in", "22021", "ready", "copy in"], messages);
+        assert_eq!(1, copy_handler.rows.lock().unwrap().len());
+
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    /// Query handler that never completes
+    struct PendingQueryHandler;
+
+    #[async_trait]
+    impl SimpleQueryHandler for PendingQueryHandler {
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            client: &C,
+            _query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            let token = client.extensions().get::<CancellationToken>().unwrap();
+            assert!(!token.is_cancelled());
+            future::pending().await
+        }
+    }
+
+    fn cancellable_connection(
+        registry: &CancellationRegistry,
+    ) -> (
+        tokio::io::DuplexStream,
+        tokio::task::JoinHandle<Result<(), IOError>>,
+    ) {
+        let (client, server) = tokio::io::duplex(4096);
+        let server = spawn_connection(
+            server_socket(server, PgWireConnectionState::AwaitingStartup),
+            Arc::new(NoopStartupHandler),
+            Arc::new(PendingQueryHandler),
+            Arc::new(PlaceholderExtendedQueryHandler),
+            Arc::new(NoopCopyHandler),
+            ConnectionLimits {
+                cancellation_registry: Some(registry.clone()),
+                ..Default::default()
+            },
+        );
+        (client, server)
+    }
+
+    async fn send_cancel_request(registry: &CancellationRegistry, pid: i32, secret_key: i32) {
+        let (mut client, server) = cancellable_connection(registry);
+        let mut buf = BytesMut::new();
+        CancelRequest::new(pid, secret_key)
+            .encode(&mut buf)
+            .unwrap();
+        client.write_all(&buf).await.unwrap();
+        server.await.unwrap().unwrap();
+        assert_eq!(0, client.read_buf(&mut buf).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_query() {
+        // sleeps below return as soon as the connection waits for the query,
+        // the paused clock is advanced without waiting
+        tokio::time::pause();
+        let registry = CancellationRegistry::new();
+        let (mut client, server) = cancellable_connection(&registry);
+
+        let mut buf = BytesMut::new();
+        let mut startup = Startup::new();
+        startup
+            .parameters_mut()
+            .insert("user".to_owned(), "tomcat".to_owned());
+        startup.encode(&mut buf).unwrap();
+        client.write_all(&buf).await.unwrap();
+        let mut received = BytesMut::new();
+        let key = loop {
+            if let PgWireBackendMessage::BackendKeyData(key) =
+                read_message(&mut client, &mut received).await
+            {
+                break key;
+            }
+        };
+        read_message(&mut client, &mut received).await;
+        assert_ne!(std::process::id() as i32, *key.pid());
+        // nothing to cancel on idle connection
+        assert!(!registry.cancel(*key.pid()));
+
+        let mut buf = BytesMut::new();
+        Query::new("SELECT pg_sleep(3600)".to_owned())
+            .encode(&mut buf)
+            .unwrap();
+        client.write_all(&buf).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // wrong secret key is ignored
+        send_cancel_request(&registry, *key.pid(), key.secret_key().wrapping_add(1)).await;
+        send_cancel_request(&registry, *key.pid(), *key.secret_key()).await;
+        match read_message(&mut client, &mut received).await {
+            PgWireBackendMessage::ErrorResponse(error) => assert_eq!("57014", error_code(&error)),
+            msg => panic!("unexpected message {msg:?}"),
+        }
+        assert!(matches!(
+            read_message(&mut client, &mut received).await,
+            PgWireBackendMessage::ReadyForQuery(_)
+        ));
+
+        // in-process cancellation of the next query
+        client.write_all(&buf).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(registry.cancel(*key.pid()));
+        match read_message(&mut client, &mut received).await {
+            PgWireBackendMessage::ErrorResponse(error) => assert_eq!("57014", error_code(&error)),
+            msg => panic!("unexpected message {msg:?}"),
+        }
+        read_message(&mut client, &mut received).await;
+
+        assert_eq!(1, registry.len());
+        drop(client);
+        server.await.unwrap().unwrap();
+        assert!(registry.is_empty());
+    }
 }
@@ -0,0 +1,155 @@
+use std::error::Error;
+
+use bytes::{BufMut, BytesMut};
+use postgres_types::{to_sql_checked, IsNull, ToSql, Type};
+
+use super::ToSqlText;
+
+/// Value of postgres `bit(n)` and `varbit` types.
+///
+/// Bits are packed from the most significant bit of the first byte. Padding
+/// bits of the last byte are always zero, as postgres requires them to be.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BitString {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl BitString {
+    /// Create bit string of `len` bits packed in `bytes`. Extra bytes are
+    /// dropped and padding bits are cleared. Returns `None` when `bytes`
+    /// are fewer than `len` bits.
+    pub fn from_bytes(mut bytes: Vec<u8>, len: usize) -> Option<BitString> {
+        let byte_len = (len + 7) / 8;
+        if bytes.len() < byte_len {
+            return None;
+        }
+        bytes.truncate(byte_len);
+        if len % 8 != 0 {
+            if let Some(last) = bytes.last_mut() {
+                *last &= 0xffu8 << (8 - len % 8);
+            }
+        }
+        Some(BitString { bytes, len })
+    }
+
+    /// Create bit string from bits, in order
+    pub fn from_bits<I: IntoIterator<Item = bool>>(bits: I) -> BitString {
+        let mut bit_string = BitString::default();
+        for bit in bits {
+            bit_string.push(bit);
+        }
+        bit_string
+    }
+
+    /// Parse text form of bits, like `0101`. Returns `None` for characters
+    /// other than `0` and `1`.
+    pub fn parse(text: &str) -> Option<BitString> {
+        text.chars()
+            .map(|c| match c {
+                '0' => Some(false),
+                '1' => Some(true),
+                _ => None,
+            })
+            .collect::<Option<Vec<bool>>>()
+            .map(BitString::from_bits)
+    }
+
+    pub fn push(&mut self, bit: bool) {
+        if self.len % 8 == 0 {
+            self.bytes.push(0);
+        }
+        if bit {
+            // safe to unwrap, byte pushed above
+            *self.bytes.last_mut().unwrap() |= 0x80 >> (self.len % 8);
+        }
+        self.len += 1;
+    }
+
+    /// Get bit at `idx`
+    pub fn get(&self, idx: usize) -> Option<bool> {
+        if idx < self.len {
+            Some(self.bytes[idx / 8] & (0x80 >> (idx % 8)) != 0)
+        } else {
+            None
+        }
+    }
+
+    /// Number of bits
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Packed bytes, with zero padding bits
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl ToSqlText for BitString {
+    fn to_sql_text(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        out.reserve(self.len);
+        for idx in 0..self.len {
+            // safe to unwrap, idx is in bound
+            out.put_u8(if self.get(idx).unwrap() { b'1' } else { b'0' });
+        }
+        Ok(IsNull::No)
+    }
+}
+
+impl ToSql for BitString {
+    fn to_sql(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        let len = i32::try_from(self.len).map_err(|_| "bit string too long")?;
+        out.put_i32(len);
+        out.put_slice(&self.bytes);
+        Ok(IsNull::No)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        *ty == Type::BIT || *ty == Type::VARBIT
+    }
+
+    to_sql_checked!();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bit_string_encoding() {
+        let bits = BitString::parse("1010000011").unwrap();
+        assert_eq!(10, bits.len());
+        assert_eq!(&[0b1010_0000, 0b1100_0000], bits.as_bytes());
+
+        let mut text = BytesMut::new();
+        bits.to_sql_text(&Type::VARBIT, &mut text).unwrap();
+        assert_eq!(&b"1010000011"[..], &text[..]);
+
+        let mut binary = BytesMut::new();
+        bits.to_sql_checked(&Type::BIT, &mut binary).unwrap();
+        assert_eq!(&[0, 0, 0, 10, 0b1010_0000, 0b1100_0000][..], &binary[..]);
+        assert!(bits.to_sql_checked(&Type::BYTEA, &mut binary).is_err());
+
+        // padding bits are cleared
+        assert_eq!(
+            bits,
+            BitString::from_bytes(vec![0b1010_0000, 0b1111_1111, 0xff], 10).unwrap()
+        );
+        assert!(BitString::from_bytes(vec![0xff], 9).is_none());
+        assert!(BitString::parse("10a").is_none());
+        assert!(BitString::parse("").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,419 @@
+//! Encoders of composite values and arrays, for values built at runtime,
+//! like anonymous `record` returned by set-returning functions and
+//! `record[]`.
+
+use std::error::Error;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use postgres_types::{to_sql_checked, IsNull, ToSql, Type};
+
+use super::{encoding_type, with_text_style, IntervalStyle, ToSqlText};
+use crate::api::results::FieldFormat;
+
+type EncodeResult<T> = Result<T, Box<dyn Error + Sync + Send>>;
+
+/// A value already encoded in wire format, like the output of
+/// `CompositeEncoder` and `ArrayEncoder`.
+///
+/// It's written as is, as a column, an array element or a field of a
+/// composite, of any type. Encoding it in another format than the one it's
+/// encoded in fails. Use `None::<EncodedValue>` for `NULL`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncodedValue {
+    format: FieldFormat,
+    bytes: Bytes,
+}
+
+impl EncodedValue {
+    pub fn new(format: FieldFormat, bytes: Bytes) -> EncodedValue {
+        EncodedValue { format, bytes }
+    }
+
+    pub fn format(&self) -> FieldFormat {
+        self.format
+    }
+
+    pub fn bytes(&self) -> &Bytes {
+        &self.bytes
+    }
+
+    fn write(&self, format: FieldFormat, out: &mut BytesMut) -> EncodeResult<IsNull> {
+        if self.format != format {
+            return Err(format!(
+                "value is encoded in {:?} format, not {:?}",
+                self.format, format
+            )
+            .into());
+        }
+        out.extend_from_slice(&self.bytes);
+        Ok(IsNull::No)
+    }
+}
+
+impl ToSql for EncodedValue {
+    fn to_sql(&self, _ty: &Type, out: &mut BytesMut) -> EncodeResult<IsNull> {
+        self.write(FieldFormat::Binary, out)
+    }
+
+    fn accepts(_ty: &Type) -> bool {
+        true
+    }
+
+    to_sql_checked!();
+}
+
+impl ToSqlText for EncodedValue {
+    fn to_sql_text(&self, _ty: &Type, out: &mut BytesMut) -> EncodeResult<IsNull> {
+        self.write(FieldFormat::Text, out)
+    }
+}
+
+/// Encode `value` to text, with intervals in `style` if it's set
+fn to_sql_text<T: ToSqlText>(
+    value: &T,
+    ty: &Type,
+    out: &mut BytesMut,
+    style: Option<IntervalStyle>,
+) -> EncodeResult<IsNull> {
+    match style {
+        Some(style) => with_text_style(style, || value.to_sql_text(ty, out)),
+        None => value.to_sql_text(ty, out),
+    }
+}
+
+/// Encode a value of fields with their own types into a composite value, of
+/// a `record` or a composite type.
+///
+/// In binary format, the oid of each field is sent along with it. In text
+/// format, the value is like `(1,"a b",)`, with quoted fields when needed and
+/// empty fields for `NULL`.
+#[derive(Debug)]
+pub struct CompositeEncoder {
+    format: FieldFormat,
+    buf: BytesMut,
+    field_count: i32,
+    interval_style: Option<IntervalStyle>,
+}
+
+impl CompositeEncoder {
+    pub fn new(format: FieldFormat) -> CompositeEncoder {
+        let mut buf = BytesMut::new();
+        match format {
+            // number of fields, updated on finish
+            FieldFormat::Binary => buf.put_i32(0),
+            FieldFormat::Text => buf.put_u8(b'('),
+        }
+        CompositeEncoder {
+            format,
+            buf,
+            field_count: 0,
+            interval_style: None,
+        }
+    }
+
+    /// Encode intervals in text format in `style`, like
+    /// `ClientInfo::interval_style` of the connection. By default, the style
+    /// of the enclosing `DataRowEncoder` or `ArrayEncoder` applies, or
+    /// `postgres` outside of them.
+    pub fn with_interval_style(mut self, style: IntervalStyle) -> CompositeEncoder {
+        self.interval_style = Some(style);
+        self
+    }
+
+    /// Encode next field as type `ty`, `NULL` for `None`.
+    ///
+    /// The field is not added when it fails to encode.
+    pub fn encode_field<T>(&mut self, value: &T, ty: &Type) -> EncodeResult<()>
+    where
+        T: ToSql + ToSqlText + Sized,
+    {
+        let mut field = BytesMut::new();
+        match self.format {
+            FieldFormat::Binary => {
+                let is_null = value.to_sql(encoding_type(ty), &mut field)?;
+                self.buf.put_u32(ty.oid());
+                put_binary_element(is_null, &field, &mut self.buf);
+            }
+            FieldFormat::Text => {
+                let is_null =
+                    to_sql_text(value, encoding_type(ty), &mut field, self.interval_style)?;
+                if self.field_count > 0 {
+                    self.buf.put_u8(b',');
+                }
+                if let IsNull::No = is_null {
+                    put_quoted(&field, composite_needs_quote(&field), b'"', &mut self.buf);
+                }
+            }
+        }
+        self.field_count += 1;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> EncodedValue {
+        match self.format {
+            FieldFormat::Binary => self.buf[..4].copy_from_slice(&self.field_count.to_be_bytes()),
+            FieldFormat::Text => self.buf.put_u8(b')'),
+        }
+        EncodedValue::new(self.format, self.buf.freeze())
+    }
+}
+
+/// Encode a one-dimensional array of values of `element_type`.
+///
+/// Elements can be composites encoded by `CompositeEncoder`, for `record[]`
+/// with element type `RECORD`. A `NULL` element is different from a
+/// composite with `NULL` fields, the former is encoded from `None`:
+///
+/// ```
+/// use pgwire::api::results::FieldFormat;
+/// use pgwire::types::{ArrayEncoder, CompositeEncoder, EncodedValue};
+/// use postgres_types::Type;
+///
+/// # fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+/// let mut records = ArrayEncoder::new(Type::RECORD, FieldFormat::Text);
+/// for (id, name) in [(1, Some("a b")), (2, None)] {
+///     let mut record = CompositeEncoder::new(FieldFormat::Text);
+///     record.encode_field(&id, &Type::INT4)?;
+///     record.encode_field(&name, &Type::TEXT)?;
+///     records.encode_element(&record.finish())?;
+/// }
+/// records.encode_element(&None::<EncodedValue>)?;
+///
+/// let records = records.finish();
+/// assert_eq!(&b"{\"(1,\\\"a b\\\")\",\"(2,)\",NULL}"[..], &records.bytes()[..]);
+/// // encode it as a column of type `RECORD_ARRAY`, with `DataRowEncoder`
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct ArrayEncoder {
+    element_type: Type,
+    format: FieldFormat,
+    elements: BytesMut,
+    len: i32,
+    has_null: bool,
+    interval_style: Option<IntervalStyle>,
+}
+
+impl ArrayEncoder {
+    pub fn new(element_type: Type, format: FieldFormat) -> ArrayEncoder {
+        ArrayEncoder {
+            element_type,
+            format,
+            elements: BytesMut::new(),
+            len: 0,
+            has_null: false,
+            interval_style: None,
+        }
+    }
+
+    /// Encode intervals in text format in `style`, like
+    /// `ClientInfo::interval_style` of the connection. By default, the style
+    /// of the enclosing `DataRowEncoder` or `CompositeEncoder` applies, or
+    /// `postgres` outside of them.
+    pub fn with_interval_style(mut self, style: IntervalStyle) -> ArrayEncoder {
+        self.interval_style = Some(style);
+        self
+    }
+
+    /// Encode next element, `NULL` for `None`.
+    ///
+    /// The element is not added when it fails to encode.
+    pub fn encode_element<T>(&mut self, value: &T) -> EncodeResult<()>
+    where
+        T: ToSql + ToSqlText + Sized,
+    {
+        let ty = encoding_type(&self.element_type);
+        let mut element = BytesMut::new();
+        let is_null = match self.format {
+            FieldFormat::Binary => value.to_sql(ty, &mut element)?,
+            FieldFormat::Text => to_sql_text(value, ty, &mut element, self.interval_style)?,
+        };
+        if let IsNull::Yes = is_null {
+            self.has_null = true;
+        }
+
+        match self.format {
+            FieldFormat::Binary => put_binary_element(is_null, &element, &mut self.elements),
+            FieldFormat::Text => {
+                if self.len > 0 {
+                    self.elements.put_u8(b',');
+                }
+                match is_null {
+                    IsNull::Yes => self.elements.put_slice(b"NULL"),
+                    IsNull::No => put_quoted(
+                        &element,
+                        array_element_needs_quote(&element),
+                        b'\\',
+                        &mut self.elements,
+                    ),
+                }
+            }
+        }
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn finish(self) -> EncodedValue {
+        let mut buf = BytesMut::with_capacity(self.elements.len() + 20);
+        match self.format {
+            FieldFormat::Binary => {
+                // number of dimensions, with no dimension for empty array
+                buf.put_i32(if self.len == 0 { 0 } else { 1 });
+                buf.put_i32(self.has_null as i32);
+                buf.put_u32(self.element_type.oid());
+                if self.len > 0 {
+                    buf.put_i32(self.len);
+                    // lower bound
+                    buf.put_i32(1);
+                }
+                buf.put_slice(&self.elements);
+            }
+            FieldFormat::Text => {
+                buf.put_u8(b'{');
+                buf.put_slice(&self.elements);
+                buf.put_u8(b'}');
+            }
+        }
+        EncodedValue::new(self.format, buf.freeze())
+    }
+}
+
+/// Put length prefixed element of binary arrays and composites
+fn put_binary_element(is_null: IsNull, value: &[u8], buf: &mut BytesMut) {
+    match is_null {
+        IsNull::Yes => buf.put_i32(-1),
+        IsNull::No => {
+            buf.put_i32(value.len() as i32);
+            buf.put_slice(value);
+        }
+    }
+}
+
+fn composite_needs_quote(value: &[u8]) -> bool {
+    value.is_empty()
+        || value
+            .iter()
+            .any(|b| matches!(b, b'"' | b'\\' | b'(' | b')' | b',') || b.is_ascii_whitespace())
+}
+
+fn array_element_needs_quote(value: &[u8]) -> bool {
+    value.is_empty()
+        || value.eq_ignore_ascii_case(b"NULL")
+        || value
+            .iter()
+            .any(|b| matches!(b, b'"' | b'\\' | b'{' | b'}' | b',') || b.is_ascii_whitespace())
+}
+
+/// Put `value`, in double quotes if `quote`, escaping `"` and `\` by `escape`.
+/// Composites escape them by doubling, arrays by backslash.
+fn put_quoted(value: &[u8], quote: bool, escape: u8, buf: &mut BytesMut) {
+    if !quote {
+        buf.put_slice(value);
+        return;
+    }
+    buf.put_u8(b'"');
+    for &b in value {
+        if b == b'"' || b == b'\\' {
+            buf.put_u8(if escape == b'"' { b } else { escape });
+        }
+        buf.put_u8(b);
+    }
+    buf.put_u8(b'"');
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(format: FieldFormat, id: Option<i32>, name: &str) -> EncodedValue {
+        let mut record = CompositeEncoder::new(format);
+        record.encode_field(&id, &Type::INT4).unwrap();
+        record.encode_field(&name, &Type::TEXT).unwrap();
+        record.finish()
+    }
+
+    #[test]
+    fn test_encode_record_array_text() {
+        let mut records = ArrayEncoder::new(Type::RECORD, FieldFormat::Text);
+        records
+            .encode_element(&record(FieldFormat::Text, Some(1), "plain"))
+            .unwrap();
+        records
+            .encode_element(&record(FieldFormat::Text, None, "say \"hi\", (x)"))
+            .unwrap();
+        records.encode_element(&None::<EncodedValue>).unwrap();
+        records
+            .encode_element(&record(FieldFormat::Text, Some(3), ""))
+            .unwrap();
+
+        assert_eq!(
+            r#"{"(1,plain)","(,\"say \"\"hi\"\", (x)\")",NULL,"(3,\"\")"}"#,
+            std::str::from_utf8(records.finish().bytes()).unwrap()
+        );
+
+        let mut names = ArrayEncoder::new(Type::TEXT, FieldFormat::Text);
+        for name in ["a", "null", "", "b\\c"] {
+            names.encode_element(&name).unwrap();
+        }
+        assert_eq!(
+            r#"{a,"null","","b\\c"}"#,
+            std::str::from_utf8(names.finish().bytes()).unwrap()
+        );
+        assert_eq!(
+            &b"{}"[..],
+            &ArrayEncoder::new(Type::INT4, FieldFormat::Text)
+                .finish()
+                .bytes()[..]
+        );
+    }
+
+    #[test]
+    fn test_encode_record_array_binary() {
+        let mut records = ArrayEncoder::new(Type::RECORD, FieldFormat::Binary);
+        records
+            .encode_element(&record(FieldFormat::Binary, None, "a"))
+            .unwrap();
+        records.encode_element(&None::<EncodedValue>).unwrap();
+
+        let mut expected = BytesMut::new();
+        // ndim, has null, element oid, dim, lower bound
+        for v in [1, 1, 2249, 2, 1] {
+            expected.put_i32(v);
+        }
+        // record of 2 fields, int4 null and text `a`
+        expected.put_i32(21);
+        for v in [2, 23, -1, 25, 1] {
+            expected.put_i32(v);
+        }
+        expected.put_u8(b'a');
+        // null element
+        expected.put_i32(-1);
+        assert_eq!(&expected[..], &records.finish().bytes()[..]);
+
+        let mut expected = BytesMut::new();
+        for v in [0, 0, 23] {
+            expected.put_i32(v);
+        }
+        assert_eq!(
+            &expected[..],
+            &ArrayEncoder::new(Type::INT4, FieldFormat::Binary)
+                .finish()
+                .bytes()[..]
+        );
+    }
+
+    #[test]
+    fn test_encoded_value_format_mismatch() {
+        let mut records = ArrayEncoder::new(Type::RECORD, FieldFormat::Binary);
+        assert!(records
+            .encode_element(&record(FieldFormat::Text, Some(1), "a"))
+            .is_err());
+        // the failed element is not added
+        let mut expected = BytesMut::new();
+        for v in [0, 0, 2249] {
+            expected.put_i32(v);
+        }
+        assert_eq!(&expected[..], &records.finish().bytes()[..]);
+    }
+}
@@ -0,0 +1,246 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use bytes::BytesMut;
+use postgres_types::{IsNull, Oid, ToSql, Type};
+
+use super::{encoding_type, ToSqlText};
+use crate::api::results::FieldFormat;
+use crate::error::{PgWireError, PgWireResult};
+
+/// Function that encodes a value of some type into `buf`, in given format.
+///
+/// The value is passed as `Any`, downcast it to the rust type expected for
+/// the postgres type.
+pub type TypeEncoder =
+    dyn Fn(&dyn Any, &Type, FieldFormat, &mut BytesMut) -> PgWireResult<IsNull> + Send + Sync;
+
+/// Registry of wire encoders by type oid, used by
+/// `DataRowEncoder::encode_value`.
+///
+/// Builtin types are encoded by default from their usual rust types, like
+/// `i32` for `INT4` and `String` or `&'static str` for `TEXT`, and `Option`
+/// of them for nullable values. Register encoders for extension types, like
+/// `geometry` or `ltree`, or to override how a builtin type is encoded.
+#[derive(Default, Clone)]
+pub struct TypeEncoderRegistry {
+    encoders: HashMap<Oid, Arc<TypeEncoder>>,
+}
+
+impl fmt::Debug for TypeEncoderRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TypeEncoderRegistry")
+            .field("oids", &self.encoders.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl TypeEncoderRegistry {
+    pub fn new() -> TypeEncoderRegistry {
+        TypeEncoderRegistry::default()
+    }
+
+    /// Register encoder for type `oid`, replacing the builtin or previously
+    /// registered one.
+    pub fn register<F>(&mut self, oid: Oid, encoder: F)
+    where
+        F: Fn(&dyn Any, &Type, FieldFormat, &mut BytesMut) -> PgWireResult<IsNull>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.encoders.insert(oid, Arc::new(encoder));
+    }
+
+    /// Test if an encoder is registered for type `oid`, builtin encoders
+    /// excluded.
+    pub fn contains(&self, oid: Oid) -> bool {
+        self.encoders.contains_key(&oid)
+    }
+
+    /// Encode `value` as type `ty` into `buf`.
+    ///
+    /// The registered encoder of `ty` is used, or the one of its base type for
+    /// a domain type, before falling back to builtin encoders. Returns
+    /// `InvalidRustTypeForField` when no encoder accepts the value.
+    pub fn encode(
+        &self,
+        value: &dyn Any,
+        ty: &Type,
+        format: FieldFormat,
+        buf: &mut BytesMut,
+    ) -> PgWireResult<IsNull> {
+        let base = encoding_type(ty);
+        if let Some(encoder) = self
+            .encoders
+            .get(&ty.oid())
+            .or_else(|| self.encoders.get(&base.oid()))
+        {
+            return encoder(value, ty, format, buf);
+        }
+
+        encode_builtin(value, base, format, buf)
+            .unwrap_or_else(|| Err(PgWireError::InvalidRustTypeForField(ty.name().to_owned())))
+    }
+}
+
+fn encode_as<T>(
+    value: &dyn Any,
+    ty: &Type,
+    format: FieldFormat,
+    buf: &mut BytesMut,
+) -> Option<PgWireResult<IsNull>>
+where
+    T: ToSql + ToSqlText + 'static,
+{
+    fn encode<T: ToSql + ToSqlText>(
+        value: &T,
+        ty: &Type,
+        format: FieldFormat,
+        buf: &mut BytesMut,
+    ) -> PgWireResult<IsNull> {
+        let is_null = if format == FieldFormat::Text {
+            value.to_sql_text(ty, buf)?
+        } else {
+            value.to_sql(ty, buf)?
+        };
+        Ok(is_null)
+    }
+
+    if let Some(value) = value.downcast_ref::<T>() {
+        Some(encode(value, ty, format, buf))
+    } else {
+        value
+            .downcast_ref::<Option<T>>()
+            .map(|value| encode(value, ty, format, buf))
+    }
+}
+
+fn encode_builtin(
+    value: &dyn Any,
+    ty: &Type,
+    format: FieldFormat,
+    buf: &mut BytesMut,
+) -> Option<PgWireResult<IsNull>> {
+    if *ty == Type::BOOL {
+        encode_as::<bool>(value, ty, format, buf)
+    } else if *ty == Type::CHAR {
+        encode_as::<i8>(value, ty, format, buf)
+    } else if *ty == Type::INT2 {
+        encode_as::<i16>(value, ty, format, buf)
+    } else if *ty == Type::INT4 {
+        encode_as::<i32>(value, ty, format, buf)
+    } else if *ty == Type::INT8 {
+        encode_as::<i64>(value, ty, format, buf)
+    } else if *ty == Type::OID {
+        encode_as::<u32>(value, ty, format, buf)
+    } else if *ty == Type::FLOAT4 {
+        encode_as::<f32>(value, ty, format, buf)
+    } else if *ty == Type::FLOAT8 {
+        encode_as::<f64>(value, ty, format, buf)
+    } else if [
+        Type::TEXT,
+        Type::VARCHAR,
+        Type::BPCHAR,
+        Type::NAME,
+        Type::UNKNOWN,
+    ]
+    .contains(ty)
+        || ty.name() == "citext"
+    {
+        encode_as::<String>(value, ty, format, buf)
+            .or_else(|| encode_as::<&'static str>(value, ty, format, buf))
+    } else if *ty == Type::BYTEA {
+        encode_as::<Vec<u8>>(value, ty, format, buf)
+            .or_else(|| encode_as::<&'static [u8]>(value, ty, format, buf))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::BufMut;
+
+    use super::*;
+    use crate::types::TypeRegistry;
+
+    #[test]
+    fn test_builtin_encoders() {
+        let registry = TypeEncoderRegistry::new();
+        let mut buf = BytesMut::new();
+
+        registry
+            .encode(&42i32, &Type::INT4, FieldFormat::Binary, &mut buf)
+            .unwrap();
+        assert_eq!(&42i32.to_be_bytes()[..], &buf.split()[..]);
+        registry
+            .encode(&"tom", &Type::TEXT, FieldFormat::Text, &mut buf)
+            .unwrap();
+        assert_eq!(&b"tom"[..], &buf.split()[..]);
+        assert!(matches!(
+            registry.encode(&None::<i64>, &Type::INT8, FieldFormat::Text, &mut buf),
+            Ok(IsNull::Yes)
+        ));
+
+        assert!(matches!(
+            registry.encode(&42i64, &Type::INT4, FieldFormat::Text, &mut buf),
+            Err(PgWireError::InvalidRustTypeForField(_))
+        ));
+    }
+
+    #[test]
+    fn test_custom_encoders() {
+        let mut types = TypeRegistry::new();
+        let ltree = types.register(90001, "ltree", Type::TEXT);
+
+        let mut registry = TypeEncoderRegistry::new();
+        // ltree binary format is a version byte followed by text
+        registry.register(90001, |value, _ty, format, buf| {
+            let path = value
+                .downcast_ref::<Vec<&str>>()
+                .ok_or_else(|| PgWireError::InvalidRustTypeForField("ltree".to_owned()))?;
+            if format == FieldFormat::Binary {
+                buf.put_u8(1);
+            }
+            buf.put_slice(path.join(".").as_bytes());
+            Ok(IsNull::No)
+        });
+        // booleans as `t` and `f`, like postgres does
+        registry.register(Type::BOOL.oid(), |value, _ty, _format, buf| {
+            let value = value.downcast_ref::<bool>().unwrap();
+            buf.put_u8(if *value { b't' } else { b'f' });
+            Ok(IsNull::No)
+        });
+        assert!(registry.contains(90001));
+        assert!(!registry.contains(Type::INT4.oid()));
+
+        let mut buf = BytesMut::new();
+        registry
+            .encode(
+                &vec!["top", "science"],
+                &ltree,
+                FieldFormat::Binary,
+                &mut buf,
+            )
+            .unwrap();
+        assert_eq!(&b"\x01top.science"[..], &buf.split()[..]);
+        registry
+            .encode(&true, &Type::BOOL, FieldFormat::Text, &mut buf)
+            .unwrap();
+        assert_eq!(&b"t"[..], &buf.split()[..]);
+
+        // domain types without an encoder are encoded like their base type
+        registry
+            .encode(
+                &"a@b.c",
+                &types.register(90002, "email", Type::TEXT),
+                FieldFormat::Text,
+                &mut buf,
+            )
+            .unwrap();
+        assert_eq!(&b"a@b.c"[..], &buf.split()[..]);
+    }
+}
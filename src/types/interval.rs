@@ -0,0 +1,454 @@
+//! Values of `interval`, and their text output in each `IntervalStyle`.
+//!
+//! An interval is months, days and microseconds, kept apart like postgres
+//! does as their lengths vary. The text output follows `IntervalStyle` of the
+//! connection, see `ClientInfo::interval_style`, and
+//! `DataRowEncoder::with_interval_style` to encode rows in it. The encoder
+//! sets the style for the duration of encoding a field, so it applies to
+//! intervals in `Option`s and arrays too. `ToSqlText` called directly encodes
+//! in the default `postgres` style. The binary form doesn't depend on the
+//! style.
+
+use std::cell::Cell;
+use std::error::Error;
+use std::fmt::Write;
+
+use bytes::{BufMut, BytesMut};
+use postgres_types::{to_sql_checked, IsNull, ToSql, Type};
+
+use super::{ArrayEncoder, ToSqlText};
+use crate::api::results::FieldFormat;
+
+const USECS_PER_SEC: i64 = 1_000_000;
+const USECS_PER_MINUTE: i64 = 60 * USECS_PER_SEC;
+const USECS_PER_HOUR: i64 = 60 * USECS_PER_MINUTE;
+
+thread_local! {
+    static TEXT_STYLE: Cell<IntervalStyle> = const { Cell::new(IntervalStyle::Postgres) };
+}
+
+/// Run `f` with intervals encoded to text in `style`
+pub(crate) fn with_text_style<R>(style: IntervalStyle, f: impl FnOnce() -> R) -> R {
+    struct Restore(IntervalStyle);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            TEXT_STYLE.with(|current| current.set(self.0));
+        }
+    }
+
+    let _restore = Restore(TEXT_STYLE.with(|current| current.replace(style)));
+    f()
+}
+
+/// Output format of intervals, as values of parameter `IntervalStyle`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IntervalStyle {
+    /// like `1 year 2 mons 3 days 04:05:06`
+    #[default]
+    Postgres,
+    /// like `@ 1 year 2 mons 3 days 4 hours 5 mins 6 secs`
+    PostgresVerbose,
+    /// like `1-2 3 4:05:06` for mixed intervals, `1-2` or `3 4:05:06`
+    /// otherwise
+    SqlStandard,
+    /// like `P1Y2M3DT4H5M6S`
+    Iso8601,
+}
+
+impl IntervalStyle {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IntervalStyle::Postgres => "postgres",
+            IntervalStyle::PostgresVerbose => "postgres_verbose",
+            IntervalStyle::SqlStandard => "sql_standard",
+            IntervalStyle::Iso8601 => "iso_8601",
+        }
+    }
+
+    /// Parse value of `IntervalStyle`, ignoring case
+    pub fn parse(value: &str) -> Option<IntervalStyle> {
+        [
+            IntervalStyle::Postgres,
+            IntervalStyle::PostgresVerbose,
+            IntervalStyle::SqlStandard,
+            IntervalStyle::Iso8601,
+        ]
+        .into_iter()
+        .find(|style| style.as_str().eq_ignore_ascii_case(value.trim()))
+    }
+}
+
+/// Value of postgres `interval` type
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, new)]
+pub struct Interval {
+    pub months: i32,
+    pub days: i32,
+    pub microseconds: i64,
+}
+
+/// Fields of an interval, each with its own sign
+struct IntervalFields {
+    year: i32,
+    mon: i32,
+    mday: i32,
+    hour: i64,
+    min: i64,
+    sec: i64,
+    fsec: i64,
+}
+
+impl IntervalFields {
+    fn is_time_zero(&self) -> bool {
+        self.hour == 0 && self.min == 0 && self.sec == 0 && self.fsec == 0
+    }
+
+    fn is_time_negative(&self) -> bool {
+        self.hour < 0 || self.min < 0 || self.sec < 0 || self.fsec < 0
+    }
+}
+
+impl Interval {
+    fn fields(&self) -> IntervalFields {
+        let mut time = self.microseconds;
+        let hour = time / USECS_PER_HOUR;
+        time -= hour * USECS_PER_HOUR;
+        let min = time / USECS_PER_MINUTE;
+        time -= min * USECS_PER_MINUTE;
+        let sec = time / USECS_PER_SEC;
+        IntervalFields {
+            year: self.months / 12,
+            mon: self.months % 12,
+            mday: self.days,
+            hour,
+            min,
+            sec,
+            fsec: time - sec * USECS_PER_SEC,
+        }
+    }
+
+    /// Format as text in `style`, like postgres does
+    pub fn to_text(&self, style: IntervalStyle) -> String {
+        let f = self.fields();
+        let mut out = String::new();
+        match style {
+            IntervalStyle::Postgres => {
+                let mut is_zero = true;
+                let mut is_before = false;
+                for (value, unit) in [(f.year, "year"), (f.mon, "mon"), (f.mday, "day")] {
+                    if value == 0 {
+                        continue;
+                    }
+                    let space = if is_zero { "" } else { " " };
+                    let plus = if is_before && value > 0 { "+" } else { "" };
+                    let plural = if value != 1 { "s" } else { "" };
+                    let _ = write!(out, "{space}{plus}{value} {unit}{plural}");
+                    is_before = value < 0;
+                    is_zero = false;
+                }
+                if is_zero || !f.is_time_zero() {
+                    let space = if is_zero { "" } else { " " };
+                    let sign = if f.is_time_negative() {
+                        "-"
+                    } else if is_before {
+                        "+"
+                    } else {
+                        ""
+                    };
+                    let _ = write!(out, "{space}{sign}{:02}:{:02}:", f.hour.abs(), f.min.abs());
+                    push_seconds(&mut out, f.sec, f.fsec, true);
+                }
+            }
+            IntervalStyle::PostgresVerbose => {
+                let mut is_zero = true;
+                let mut is_before = false;
+                out.push('@');
+                for (value, unit) in [
+                    (f.year as i64, "year"),
+                    (f.mon as i64, "mon"),
+                    (f.mday as i64, "day"),
+                    (f.hour, "hour"),
+                    (f.min, "min"),
+                ] {
+                    if value == 0 {
+                        continue;
+                    }
+                    // the first non-zero field decides the sign, `ago`
+                    let value = if is_zero {
+                        is_before = value < 0;
+                        value.abs()
+                    } else if is_before {
+                        -value
+                    } else {
+                        value
+                    };
+                    let plural = if value == 1 { "" } else { "s" };
+                    let _ = write!(out, " {value} {unit}{plural}");
+                    is_zero = false;
+                }
+                if f.sec != 0 || f.fsec != 0 {
+                    out.push(' ');
+                    if f.sec < 0 || (f.sec == 0 && f.fsec < 0) {
+                        if is_zero {
+                            is_before = true;
+                        } else if !is_before {
+                            out.push('-');
+                        }
+                    } else if is_before {
+                        out.push('-');
+                    }
+                    push_seconds(&mut out, f.sec, f.fsec, false);
+                    let plural = if f.sec.abs() != 1 || f.fsec != 0 {
+                        "s"
+                    } else {
+                        ""
+                    };
+                    let _ = write!(out, " sec{plural}");
+                    is_zero = false;
+                }
+                if is_zero {
+                    out.push_str(" 0");
+                }
+                if is_before {
+                    out.push_str(" ago");
+                }
+            }
+            IntervalStyle::SqlStandard => {
+                let has_negative = f.year < 0 || f.mon < 0 || f.mday < 0 || f.is_time_negative();
+                let has_positive = f.year > 0
+                    || f.mon > 0
+                    || f.mday > 0
+                    || f.hour > 0
+                    || f.min > 0
+                    || f.sec > 0
+                    || f.fsec > 0;
+                let has_year_month = f.year != 0 || f.mon != 0;
+                let has_day_time = f.mday != 0 || !f.is_time_zero();
+                let mixed = (has_negative && has_positive) || (has_year_month && has_day_time);
+
+                if !has_negative && !has_positive {
+                    out.push('0');
+                } else if mixed {
+                    // all fields with explicit signs
+                    let year_sign = if f.year < 0 || f.mon < 0 { '-' } else { '+' };
+                    let day_sign = if f.mday < 0 { '-' } else { '+' };
+                    let sec_sign = if f.is_time_negative() { '-' } else { '+' };
+                    let _ = write!(
+                        out,
+                        "{year_sign}{}-{} {day_sign}{} {sec_sign}{}:{:02}:",
+                        f.year.abs(),
+                        f.mon.abs(),
+                        f.mday.abs(),
+                        f.hour.abs(),
+                        f.min.abs()
+                    );
+                    push_seconds(&mut out, f.sec, f.fsec, true);
+                } else {
+                    // a single sign for the whole value
+                    if has_negative {
+                        out.push('-');
+                    }
+                    if has_year_month {
+                        let _ = write!(out, "{}-{}", f.year.abs(), f.mon.abs());
+                    } else {
+                        if f.mday != 0 {
+                            let _ = write!(out, "{} ", f.mday.abs());
+                        }
+                        let _ = write!(out, "{}:{:02}:", f.hour.abs(), f.min.abs());
+                        push_seconds(&mut out, f.sec, f.fsec, true);
+                    }
+                }
+            }
+            IntervalStyle::Iso8601 => {
+                if f.year == 0 && f.mon == 0 && f.mday == 0 && f.is_time_zero() {
+                    return "PT0S".to_owned();
+                }
+                out.push('P');
+                for (value, unit) in [(f.year, 'Y'), (f.mon, 'M'), (f.mday, 'D')] {
+                    if value != 0 {
+                        let _ = write!(out, "{value}{unit}");
+                    }
+                }
+                if !f.is_time_zero() {
+                    out.push('T');
+                }
+                for (value, unit) in [(f.hour, 'H'), (f.min, 'M')] {
+                    if value != 0 {
+                        let _ = write!(out, "{value}{unit}");
+                    }
+                }
+                if f.sec != 0 || f.fsec != 0 {
+                    if f.sec < 0 || f.fsec < 0 {
+                        out.push('-');
+                    }
+                    push_seconds(&mut out, f.sec, f.fsec, false);
+                    out.push('S');
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Append absolute seconds, with fractional digits up to microseconds
+/// without trailing zeros
+fn push_seconds(out: &mut String, sec: i64, fsec: i64, fill_zeros: bool) {
+    if fill_zeros {
+        let _ = write!(out, "{:02}", sec.abs());
+    } else {
+        let _ = write!(out, "{}", sec.abs());
+    }
+    if fsec != 0 {
+        let fraction = format!("{:06}", fsec.abs());
+        let _ = write!(out, ".{}", fraction.trim_end_matches('0'));
+    }
+}
+
+impl ToSqlText for Interval {
+    fn to_sql_text(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        let style = TEXT_STYLE.with(Cell::get);
+        out.put_slice(self.to_text(style).as_bytes());
+        Ok(IsNull::No)
+    }
+}
+
+impl ToSqlText for Vec<Interval> {
+    fn to_sql_text(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        let mut array = ArrayEncoder::new(Type::INTERVAL, FieldFormat::Text);
+        for interval in self {
+            array.encode_element(interval)?;
+        }
+        out.put_slice(array.finish().bytes());
+        Ok(IsNull::No)
+    }
+}
+
+impl ToSqlText for Vec<Option<Interval>> {
+    fn to_sql_text(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        let mut array = ArrayEncoder::new(Type::INTERVAL, FieldFormat::Text);
+        for interval in self {
+            array.encode_element(interval)?;
+        }
+        out.put_slice(array.finish().bytes());
+        Ok(IsNull::No)
+    }
+}
+
+impl ToSql for Interval {
+    fn to_sql(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        out.put_i64(self.microseconds);
+        out.put_i32(self.days);
+        out.put_i32(self.months);
+        Ok(IsNull::No)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        *ty == Type::INTERVAL
+    }
+
+    to_sql_checked!();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hms(hour: i64, min: i64, sec: i64) -> i64 {
+        hour * USECS_PER_HOUR + min * USECS_PER_MINUTE + sec * USECS_PER_SEC
+    }
+
+    #[test]
+    fn test_interval_styles() {
+        use IntervalStyle::*;
+
+        // outputs of postgres, including the examples of its docs
+        for (interval, postgres, verbose, sql_standard, iso_8601) in [
+            (
+                Interval::new(14, 0, 0),
+                "1 year 2 mons",
+                "@ 1 year 2 mons",
+                "1-2",
+                "P1Y2M",
+            ),
+            (
+                Interval::new(0, 3, hms(4, 5, 6)),
+                "3 days 04:05:06",
+                "@ 3 days 4 hours 5 mins 6 secs",
+                "3 4:05:06",
+                "P3DT4H5M6S",
+            ),
+            (
+                Interval::new(-14, 3, -hms(4, 5, 6)),
+                "-1 years -2 mons +3 days -04:05:06",
+                "@ 1 year 2 mons -3 days 4 hours 5 mins 6 secs ago",
+                "-1-2 +3 -4:05:06",
+                "P-1Y-2M3DT-4H-5M-6S",
+            ),
+            (
+                Interval::new(14, 3, hms(4, 5, 6)),
+                "1 year 2 mons 3 days 04:05:06",
+                "@ 1 year 2 mons 3 days 4 hours 5 mins 6 secs",
+                "+1-2 +3 +4:05:06",
+                "P1Y2M3DT4H5M6S",
+            ),
+            (Interval::new(0, 0, 0), "00:00:00", "@ 0", "0", "PT0S"),
+            (
+                Interval::new(0, 0, 1_500_000),
+                "00:00:01.5",
+                "@ 1.5 secs",
+                "0:00:01.5",
+                "PT1.5S",
+            ),
+            (
+                Interval::new(0, -1, 0),
+                "-1 days",
+                "@ 1 day ago",
+                "-1 0:00:00",
+                "P-1D",
+            ),
+            (
+                Interval::new(0, 0, -hms(1, 0, 1)),
+                "-01:00:01",
+                "@ 1 hour 1 sec ago",
+                "-1:00:01",
+                "PT-1H-1S",
+            ),
+        ] {
+            assert_eq!(postgres, interval.to_text(Postgres), "{interval:?}");
+            assert_eq!(verbose, interval.to_text(PostgresVerbose), "{interval:?}");
+            assert_eq!(sql_standard, interval.to_text(SqlStandard), "{interval:?}");
+            assert_eq!(iso_8601, interval.to_text(Iso8601), "{interval:?}");
+        }
+
+        assert_eq!(Some(Iso8601), IntervalStyle::parse("ISO_8601"));
+        assert_eq!(None, IntervalStyle::parse("iso"));
+    }
+
+    #[test]
+    fn test_interval_binary() {
+        let mut buf = BytesMut::new();
+        Interval::new(14, 3, 1)
+            .to_sql_checked(&Type::INTERVAL, &mut buf)
+            .unwrap();
+        assert_eq!(
+            &[0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 3, 0, 0, 0, 14][..],
+            &buf[..]
+        );
+    }
+}
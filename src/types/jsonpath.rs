@@ -0,0 +1,78 @@
+use std::error::Error;
+
+use bytes::{BufMut, BytesMut};
+use postgres_types::{to_sql_checked, IsNull, ToSql, Type};
+
+use super::ToSqlText;
+
+/// Version of `jsonpath` binary format
+const JSONPATH_BINARY_VERSION: u8 = 1;
+
+/// Value of postgres `jsonpath` type, like `$.store.book[*] ? (@.price < 10)`.
+///
+/// The path is sent as is. Binary format is the text prefixed with a version
+/// byte.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JsonPath(String);
+
+impl JsonPath {
+    pub fn new<S: Into<String>>(path: S) -> JsonPath {
+        JsonPath(path.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn into_inner(self) -> String {
+        self.0
+    }
+}
+
+impl ToSqlText for JsonPath {
+    fn to_sql_text(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        out.put_slice(self.0.as_bytes());
+        Ok(IsNull::No)
+    }
+}
+
+impl ToSql for JsonPath {
+    fn to_sql(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        out.put_u8(JSONPATH_BINARY_VERSION);
+        out.put_slice(self.0.as_bytes());
+        Ok(IsNull::No)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        *ty == Type::JSONPATH
+    }
+
+    to_sql_checked!();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_jsonpath_encoding() {
+        let path = JsonPath::new("$.a[*]");
+
+        let mut text = BytesMut::new();
+        path.to_sql_text(&Type::JSONPATH, &mut text).unwrap();
+        assert_eq!(&b"$.a[*]"[..], &text[..]);
+
+        let mut binary = BytesMut::new();
+        path.to_sql_checked(&Type::JSONPATH, &mut binary).unwrap();
+        assert_eq!(&b"\x01$.a[*]"[..], &binary[..]);
+        assert!(path.to_sql_checked(&Type::JSON, &mut binary).is_err());
+    }
+}
@@ -0,0 +1,186 @@
+//! Values of `macaddr` and `macaddr8`, MAC addresses of 6 and 8 bytes.
+//!
+//! The binary form is the raw bytes. Postgres emits the text form as
+//! lowercase hex bytes separated by colons, like `08:00:2b:01:02:03`, while
+//! it accepts other forms on input, like `08-00-2b-01-02-03`,
+//! `08002b:010203` or `0800.2b01.0203`. `parse` accepts those as well.
+
+use std::error::Error;
+use std::fmt;
+
+use bytes::{BufMut, BytesMut};
+use postgres_types::{to_sql_checked, IsNull, ToSql, Type};
+
+use super::ToSqlText;
+
+/// Value of postgres `macaddr` type
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct MacAddr(pub [u8; 6]);
+
+/// Value of postgres `macaddr8` type
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct MacAddr8(pub [u8; 8]);
+
+/// Parse hex digits in pairs, optionally separated by one kind of `:`, `-`
+/// or `.` between pairs
+fn parse_hex(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(8);
+    let mut high: Option<u8> = None;
+    let mut separator = None;
+    let mut after_separator = false;
+    for c in text.trim().chars() {
+        if let Some(digit) = c.to_digit(16) {
+            match high.take() {
+                Some(h) => bytes.push(h << 4 | digit as u8),
+                None => high = Some(digit as u8),
+            }
+            after_separator = false;
+        } else if matches!(c, ':' | '-' | '.') {
+            if bytes.is_empty() || high.is_some() || after_separator {
+                return None;
+            }
+            if *separator.get_or_insert(c) != c {
+                return None;
+            }
+            after_separator = true;
+        } else {
+            return None;
+        }
+    }
+    if high.is_some() || after_separator {
+        return None;
+    }
+    Some(bytes)
+}
+
+fn fmt_hex(bytes: &[u8], f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    for (idx, byte) in bytes.iter().enumerate() {
+        if idx > 0 {
+            f.write_str(":")?;
+        }
+        write!(f, "{byte:02x}")?;
+    }
+    Ok(())
+}
+
+impl MacAddr {
+    /// Parse text form of a 6 bytes address. Returns `None` for other lengths
+    /// or an invalid form.
+    pub fn parse(text: &str) -> Option<MacAddr> {
+        parse_hex(text)?.try_into().ok().map(MacAddr)
+    }
+}
+
+impl MacAddr8 {
+    /// Parse text form of a 8 bytes address. A 6 bytes address is converted
+    /// to EUI-64 by inserting `ff:fe` in the middle, like postgres does.
+    pub fn parse(text: &str) -> Option<MacAddr8> {
+        let bytes = parse_hex(text)?;
+        match bytes.len() {
+            6 => MacAddr::parse(text).map(MacAddr8::from),
+            _ => bytes.try_into().ok().map(MacAddr8),
+        }
+    }
+}
+
+impl From<MacAddr> for MacAddr8 {
+    fn from(addr: MacAddr) -> MacAddr8 {
+        let [a, b, c, d, e, f] = addr.0;
+        MacAddr8([a, b, c, 0xff, 0xfe, d, e, f])
+    }
+}
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt_hex(&self.0, f)
+    }
+}
+
+impl fmt::Display for MacAddr8 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt_hex(&self.0, f)
+    }
+}
+
+macro_rules! impl_macaddr {
+    ($t:ty, $pg_type:expr) => {
+        impl ToSqlText for $t {
+            fn to_sql_text(
+                &self,
+                _ty: &Type,
+                out: &mut BytesMut,
+            ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+                out.put_slice(self.to_string().as_bytes());
+                Ok(IsNull::No)
+            }
+        }
+
+        impl ToSql for $t {
+            fn to_sql(
+                &self,
+                _ty: &Type,
+                out: &mut BytesMut,
+            ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+                out.put_slice(&self.0);
+                Ok(IsNull::No)
+            }
+
+            fn accepts(ty: &Type) -> bool {
+                *ty == $pg_type
+            }
+
+            to_sql_checked!();
+        }
+    };
+}
+
+impl_macaddr!(MacAddr, Type::MACADDR);
+impl_macaddr!(MacAddr8, Type::MACADDR8);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_macaddr() {
+        let addr = MacAddr([0x08, 0x00, 0x2b, 0x01, 0x02, 0x03]);
+        for text in [
+            "08:00:2b:01:02:03",
+            "08-00-2b-01-02-03",
+            "08002b:010203",
+            "08002b-010203",
+            "0800.2b01.0203",
+            "0800-2b01-0203",
+            "08002b010203",
+            "08:00:2B:01:02:03",
+        ] {
+            assert_eq!(Some(addr), MacAddr::parse(text), "{text}");
+        }
+        for text in [
+            "08:00:2b:01:02",
+            "08:00-2b:01:02:03",
+            "08::002b010203",
+            "0g:00:2b:01:02:03",
+        ] {
+            assert_eq!(None, MacAddr::parse(text), "{text}");
+        }
+
+        let mut text = BytesMut::new();
+        addr.to_sql_text(&Type::MACADDR, &mut text).unwrap();
+        assert_eq!(&b"08:00:2b:01:02:03"[..], &text[..]);
+        let mut binary = BytesMut::new();
+        addr.to_sql_checked(&Type::MACADDR, &mut binary).unwrap();
+        assert_eq!(&addr.0[..], &binary[..]);
+        assert!(addr.to_sql_checked(&Type::MACADDR8, &mut binary).is_err());
+
+        let addr8 = MacAddr8::parse("08:00:2b:01:02:03:04:05").unwrap();
+        assert_eq!("08:00:2b:01:02:03:04:05", addr8.to_string());
+        assert_eq!(
+            "08:00:2b:ff:fe:01:02:03",
+            MacAddr8::parse("08002b010203").unwrap().to_string()
+        );
+        let mut binary = BytesMut::new();
+        addr8.to_sql_checked(&Type::MACADDR8, &mut binary).unwrap();
+        assert_eq!(&addr8.0[..], &binary[..]);
+    }
+}
@@ -1,10 +1,39 @@
+use std::collections::HashMap;
 use std::time::SystemTime;
 use std::{error::Error, fmt};
 
 use bytes::{BufMut, BytesMut};
 use chrono::offset::Utc;
 use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, TimeZone};
-use postgres_types::{IsNull, Type, WrongType};
+use postgres_types::{IsNull, Kind, Oid, Type, WrongType};
+
+mod bit;
+mod composite;
+mod encoder;
+mod interval;
+mod jsonpath;
+mod macaddr;
+mod numeric;
+mod timetz;
+mod tsearch;
+mod value;
+mod vector;
+mod xml;
+
+pub use bit::BitString;
+pub use composite::{ArrayEncoder, CompositeEncoder, EncodedValue};
+pub use encoder::{TypeEncoder, TypeEncoderRegistry};
+pub(crate) use interval::with_text_style;
+pub use interval::{Interval, IntervalStyle};
+pub use jsonpath::JsonPath;
+pub use macaddr::{MacAddr, MacAddr8};
+pub use numeric::Numeric;
+pub use timetz::TimeTz;
+pub use tsearch::{TsQuery, TsVector, TsWeight, TS_MAX_POSITION, TS_MAX_POSITIONS};
+pub(crate) use value::validate_binary;
+pub use value::PgValue;
+pub use vector::{Int2Vector, OidVector};
+pub use xml::Xml;
 
 pub trait ToSqlText: fmt::Debug {
     /// Converts value to text format of Postgres type.
@@ -20,7 +49,7 @@ pub trait ToSqlText: fmt::Debug {
         Self: Sized;
 }
 
-impl<'a, T> ToSqlText for &'a T
+impl<T> ToSqlText for &T
 where
     T: ToSqlText,
 {
@@ -58,7 +87,7 @@ impl ToSqlText for String {
     }
 }
 
-impl<'a> ToSqlText for &'a str {
+impl ToSqlText for &str {
     fn to_sql_text(
         &self,
         _ty: &Type,
@@ -94,11 +123,50 @@ impl_to_sql_text!(u16);
 impl_to_sql_text!(u32);
 impl_to_sql_text!(u64);
 impl_to_sql_text!(u128);
-impl_to_sql_text!(f32);
-impl_to_sql_text!(f64);
-impl_to_sql_text!(bool);
 impl_to_sql_text!(char);
 
+impl ToSqlText for bool {
+    fn to_sql_text(
+        &self,
+        _ty: &Type,
+        w: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        // postgres spells booleans `t` and `f` in text format
+        w.put_u8(if *self { b't' } else { b'f' });
+        Ok(IsNull::No)
+    }
+}
+
+macro_rules! impl_to_sql_text_for_float {
+    ($t:ty) => {
+        impl ToSqlText for $t {
+            fn to_sql_text(
+                &self,
+                _ty: &Type,
+                w: &mut BytesMut,
+            ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+                // postgres spells special values differently from rust
+                if self.is_nan() {
+                    w.put_slice(b"NaN");
+                } else if self.is_infinite() {
+                    if self.is_sign_positive() {
+                        w.put_slice(b"Infinity");
+                    } else {
+                        w.put_slice(b"-Infinity");
+                    }
+                } else {
+                    // rust formats -0.0 as "-0", identical to postgres
+                    w.put_slice(self.to_string().as_bytes());
+                }
+                Ok(IsNull::No)
+            }
+        }
+    };
+}
+
+impl_to_sql_text_for_float!(f32);
+impl_to_sql_text_for_float!(f64);
+
 impl ToSqlText for Vec<u8> {
     fn to_sql_text(
         &self,
@@ -225,10 +293,74 @@ impl ToSqlText for NaiveTime {
     }
 }
 
+/// Registry of custom types that are unknown to `postgres_types`.
+///
+/// Domain types and custom base types have oids that `Type::from_oid` cannot
+/// resolve. By registering them here, each custom type is mapped to a builtin
+/// type that defines its wire encoding. For example, a custom type can be
+/// encoded like `TEXT`, like `INT8`, or as an array of some builtin type.
+#[derive(Debug, Default, Clone)]
+pub struct TypeRegistry {
+    types: HashMap<Oid, Type>,
+}
+
+impl TypeRegistry {
+    pub fn new() -> TypeRegistry {
+        TypeRegistry::default()
+    }
+
+    /// Register a custom type which is encoded like builtin type `base`.
+    ///
+    /// The returned `Type` can be used in `FieldInfo`, so that the custom oid
+    /// is reported to client in `RowDescription`.
+    pub fn register(&mut self, oid: Oid, name: &str, base: Type) -> Type {
+        self.insert(oid, name, Kind::Domain(base))
+    }
+
+    /// Register a custom array type, with elements encoded like builtin type
+    /// `element`.
+    pub fn register_array(&mut self, oid: Oid, name: &str, element: Type) -> Type {
+        self.insert(oid, name, Kind::Array(element))
+    }
+
+    /// Register the `citext` extension type, which is created with an oid
+    /// assigned by the server it's installed on.
+    ///
+    /// `citext` is a base type of its own, not a domain, but is encoded like
+    /// `TEXT` in both formats. Case-insensitive comparison is up to the
+    /// handler, values are sent as is.
+    pub fn register_citext(&mut self, oid: Oid) -> Type {
+        self.insert(oid, "citext", Kind::Simple)
+    }
+
+    fn insert(&mut self, oid: Oid, name: &str, kind: Kind) -> Type {
+        let ty = Type::new(name.to_owned(), oid, kind, "public".to_owned());
+        self.types.insert(oid, ty.clone());
+        ty
+    }
+
+    /// Resolve type by oid, from builtin types or registered custom types.
+    pub fn get(&self, oid: Oid) -> Option<Type> {
+        Type::from_oid(oid).or_else(|| self.types.get(&oid).cloned())
+    }
+}
+
+/// Get the type that defines wire encoding of `ty`.
+///
+/// For domain types, this is the underlying base type. Other types are encoded
+/// as themselves.
+pub(crate) fn encoding_type(ty: &Type) -> &Type {
+    match ty.kind() {
+        Kind::Domain(base) => encoding_type(base),
+        _ => ty,
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use chrono::offset::Local;
+    use postgres_types::{FromSql, ToSql};
 
     #[test]
     fn test_date_time_format() {
@@ -247,4 +379,54 @@ mod test {
         // format: 2023-02-01 22:31:49.479895+08
         assert_eq!(29, String::from_utf8_lossy(buf.freeze().as_ref()).len());
     }
+
+    #[test]
+    fn test_float_special_values() {
+        let cases = [
+            (f64::NAN, "NaN"),
+            (f64::INFINITY, "Infinity"),
+            (f64::NEG_INFINITY, "-Infinity"),
+            (-0.0, "-0"),
+            (1.5, "1.5"),
+        ];
+        for (value, text) in cases {
+            let mut buf = BytesMut::new();
+            value.to_sql_text(&Type::FLOAT8, &mut buf).unwrap();
+            assert_eq!(text, String::from_utf8_lossy(&buf));
+
+            // binary format is big-endian ieee 754
+            let mut buf = BytesMut::new();
+            value.to_sql(&Type::FLOAT8, &mut buf).unwrap();
+            assert_eq!(&value.to_be_bytes()[..], &buf[..]);
+            let decoded = f64::from_sql(&Type::FLOAT8, &buf).unwrap();
+            assert_eq!(value.to_bits(), decoded.to_bits());
+        }
+
+        let mut buf = BytesMut::new();
+        f32::NEG_INFINITY
+            .to_sql_text(&Type::FLOAT4, &mut buf)
+            .unwrap();
+        assert_eq!("-Infinity", String::from_utf8_lossy(&buf));
+    }
+
+    #[test]
+    fn test_type_registry() {
+        let mut registry = TypeRegistry::new();
+        let email = registry.register(90001, "email", Type::TEXT);
+        let ids = registry.register_array(90002, "_id", Type::INT8);
+
+        assert_eq!(Some(Type::INT4), registry.get(23));
+        assert_eq!(Some(email.clone()), registry.get(90001));
+        assert_eq!(Some(ids.clone()), registry.get(90002));
+        assert_eq!(None, registry.get(90003));
+
+        assert_eq!(&Type::TEXT, encoding_type(&email));
+        assert_eq!(&ids, encoding_type(&ids));
+        assert_eq!(&Type::INT4, encoding_type(&Type::INT4));
+
+        let citext = registry.register_citext(90003);
+        assert_eq!("citext", citext.name());
+        assert_eq!(&Kind::Simple, citext.kind());
+        assert_eq!(Some(citext), registry.get(90003));
+    }
 }
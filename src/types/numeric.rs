@@ -0,0 +1,282 @@
+//! Values of `numeric`, arbitrary precision decimals, including the special
+//! values `NaN`, `Infinity` and `-Infinity`.
+//!
+//! The binary form is the number of digits in base 10000, the weight of the
+//! first digit, the sign and the number of decimal digits after the point
+//! (`dscale`), all `Int16`, followed by the digits. The special values have
+//! no digits, and sign `0xC000` for `NaN`, `0xD000` for `Infinity` and
+//! `0xF000` for `-Infinity`. The text form is like `-12.50`, or the names of
+//! the special values.
+
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+use bytes::{BufMut, BytesMut};
+use postgres_types::{to_sql_checked, FromSql, IsNull, ToSql, Type};
+
+use super::value::decode_binary_numeric;
+use super::ToSqlText;
+
+const NUMERIC_POS: u16 = 0x0000;
+const NUMERIC_NEG: u16 = 0x4000;
+const NUMERIC_NAN: u16 = 0xC000;
+const NUMERIC_PINF: u16 = 0xD000;
+const NUMERIC_NINF: u16 = 0xF000;
+
+/// Value of postgres `numeric` type
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Numeric {
+    /// a finite value in its text form, like `-12.50`, keeping its scale.
+    /// Parse it from a string to have it checked.
+    Value(String),
+    NaN,
+    Infinity,
+    NegativeInfinity,
+}
+
+impl FromStr for Numeric {
+    type Err = Box<dyn Error + Sync + Send>;
+
+    /// Parse a numeric like postgres input, without exponent. Leading zeros
+    /// and sign `+` are dropped, and spellings of special values are case
+    /// insensitive, like `nan` and `-inf`.
+    fn from_str(s: &str) -> Result<Numeric, Self::Err> {
+        let s = s.trim();
+        match s.to_ascii_lowercase().as_str() {
+            "nan" => return Ok(Numeric::NaN),
+            "infinity" | "+infinity" | "inf" | "+inf" => return Ok(Numeric::Infinity),
+            "-infinity" | "-inf" => return Ok(Numeric::NegativeInfinity),
+            _ => {}
+        }
+
+        let (negative, unsigned) = match s.as_bytes().first() {
+            Some(b'-') => (true, &s[1..]),
+            Some(b'+') => (false, &s[1..]),
+            _ => (false, s),
+        };
+        let (int_part, frac_part) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+        if int_part.is_empty() && frac_part.is_empty()
+            || !int_part
+                .bytes()
+                .chain(frac_part.bytes())
+                .all(|b| b.is_ascii_digit())
+        {
+            return Err(format!("invalid input syntax for type numeric: \"{s}\"").into());
+        }
+
+        let int_part = int_part.trim_start_matches('0');
+        let int_part = if int_part.is_empty() { "0" } else { int_part };
+        let is_zero = int_part == "0" && frac_part.bytes().all(|b| b == b'0');
+        let mut value = String::with_capacity(s.len() + 1);
+        // -0 is 0
+        if negative && !is_zero {
+            value.push('-');
+        }
+        value.push_str(int_part);
+        if !frac_part.is_empty() {
+            value.push('.');
+            value.push_str(frac_part);
+        }
+        Ok(Numeric::Value(value))
+    }
+}
+
+impl From<f64> for Numeric {
+    /// Convert a float, with its non-finite values to the special values
+    fn from(v: f64) -> Numeric {
+        if v.is_nan() {
+            Numeric::NaN
+        } else if v == f64::INFINITY {
+            Numeric::Infinity
+        } else if v == f64::NEG_INFINITY {
+            Numeric::NegativeInfinity
+        } else {
+            // display of floats has no exponent
+            v.to_string()
+                .parse()
+                .expect("display of finite float is a valid numeric")
+        }
+    }
+}
+
+impl fmt::Display for Numeric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Numeric::Value(v) => f.write_str(v),
+            Numeric::NaN => f.write_str("NaN"),
+            Numeric::Infinity => f.write_str("Infinity"),
+            Numeric::NegativeInfinity => f.write_str("-Infinity"),
+        }
+    }
+}
+
+impl ToSqlText for Numeric {
+    fn to_sql_text(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        out.put_slice(self.to_string().as_bytes());
+        Ok(IsNull::No)
+    }
+}
+
+impl ToSql for Numeric {
+    fn to_sql(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        let value = match self {
+            Numeric::Value(value) => value,
+            special => {
+                let sign = match special {
+                    Numeric::NaN => NUMERIC_NAN,
+                    Numeric::Infinity => NUMERIC_PINF,
+                    _ => NUMERIC_NINF,
+                };
+                // no digits, weight and dscale 0
+                out.put_i16(0);
+                out.put_i16(0);
+                out.put_u16(sign);
+                out.put_i16(0);
+                return Ok(IsNull::No);
+            }
+        };
+        // values built without parsing are checked here
+        let value = match value.parse::<Numeric>()? {
+            Numeric::Value(value) => value,
+            _ => return Err(format!("invalid numeric value: \"{value}\"").into()),
+        };
+
+        let (negative, unsigned) = match value.strip_prefix('-') {
+            Some(unsigned) => (true, unsigned),
+            None => (false, value.as_str()),
+        };
+        let (int_part, frac_part) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+        let int_part = int_part.trim_start_matches('0');
+        let dscale = i16::try_from(frac_part.len()).map_err(|_| "numeric scale too large")?;
+
+        // groups of 4 decimal digits, aligned at the point
+        let int_pad = (4 - int_part.len() % 4) % 4;
+        let frac_pad = (4 - frac_part.len() % 4) % 4;
+        let padded_int = "0".repeat(int_pad) + int_part;
+        let padded_frac = frac_part.to_owned() + &"0".repeat(frac_pad);
+        let group = |digits: &str| digits.parse::<i16>().expect("4 ascii digits");
+        let mut digits: Vec<i16> = padded_int
+            .as_bytes()
+            .chunks(4)
+            .chain(padded_frac.as_bytes().chunks(4))
+            .map(|chunk| group(std::str::from_utf8(chunk).expect("ascii digits")))
+            .collect();
+        let mut weight = (padded_int.len() / 4) as i32 - 1;
+
+        let leading = digits.iter().take_while(|d| **d == 0).count();
+        digits.drain(..leading);
+        weight -= leading as i32;
+        while digits.last() == Some(&0) {
+            digits.pop();
+        }
+        if digits.is_empty() {
+            weight = 0;
+        }
+        let ndigits = i16::try_from(digits.len()).map_err(|_| "numeric too long")?;
+        let weight = i16::try_from(weight).map_err(|_| "numeric weight out of range")?;
+
+        out.put_i16(ndigits);
+        out.put_i16(weight);
+        out.put_u16(if negative { NUMERIC_NEG } else { NUMERIC_POS });
+        out.put_i16(dscale);
+        for digit in digits {
+            out.put_i16(digit);
+        }
+        Ok(IsNull::No)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        *ty == Type::NUMERIC
+    }
+
+    to_sql_checked!();
+}
+
+impl<'a> FromSql<'a> for Numeric {
+    fn from_sql(_ty: &Type, raw: &'a [u8]) -> Result<Numeric, Box<dyn Error + Sync + Send>> {
+        decode_binary_numeric(raw)?.parse()
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        *ty == Type::NUMERIC
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn binary(value: &Numeric) -> Vec<i16> {
+        let mut buf = BytesMut::new();
+        value.to_sql_checked(&Type::NUMERIC, &mut buf).unwrap();
+        buf.chunks(2)
+            .map(|chunk| i16::from_be_bytes([chunk[0], chunk[1]]))
+            .collect()
+    }
+
+    #[test]
+    fn test_numeric_special_values() {
+        // as sent by postgres, no digits, weight 0 and dscale 0
+        for (value, text, sign) in [
+            (Numeric::NaN, "NaN", 0xC000u16),
+            (Numeric::Infinity, "Infinity", 0xD000),
+            (Numeric::NegativeInfinity, "-Infinity", 0xF000),
+        ] {
+            let mut buf = BytesMut::new();
+            value.to_sql_text(&Type::NUMERIC, &mut buf).unwrap();
+            assert_eq!(text.as_bytes(), &buf[..]);
+            assert_eq!(value, text.parse().unwrap());
+
+            let mut buf = BytesMut::new();
+            value.to_sql(&Type::NUMERIC, &mut buf).unwrap();
+            let sign = sign.to_be_bytes();
+            assert_eq!(&[0, 0, 0, 0, sign[0], sign[1], 0, 0][..], &buf[..]);
+            assert_eq!(value, Numeric::from_sql(&Type::NUMERIC, &buf).unwrap());
+        }
+
+        assert_eq!(Numeric::NaN, Numeric::from(f64::NAN));
+        assert_eq!(Numeric::Infinity, Numeric::from(f64::INFINITY));
+        assert_eq!(Numeric::NegativeInfinity, Numeric::from(f64::NEG_INFINITY));
+        assert_eq!(Numeric::NegativeInfinity, "-inf".parse().unwrap());
+    }
+
+    #[test]
+    fn test_numeric_encoding() {
+        for (text, expected, raw) in [
+            ("12.50", "12.50", vec![2, 0, 0, 2, 12, 5000]),
+            ("-10000.1", "-10000.1", vec![3, 1, 0x4000, 1, 1, 0, 1000]),
+            ("0.0005", "0.0005", vec![1, -1, 0, 4, 5]),
+            ("+000.00000005", "0.00000005", vec![1, -2, 0, 8, 5]),
+            ("1000000", "1000000", vec![1, 1, 0, 0, 100]),
+            ("-0.00", "0.00", vec![0, 0, 0, 2]),
+            (".5", "0.5", vec![1, -1, 0, 1, 5000]),
+        ] {
+            let value: Numeric = text.parse().unwrap();
+            assert_eq!(Numeric::Value(expected.to_owned()), value, "{text}");
+            let raw: Vec<i16> = raw.into_iter().map(|v: i32| v as i16).collect();
+            assert_eq!(raw, binary(&value), "{text}");
+
+            let mut buf = BytesMut::new();
+            value.to_sql(&Type::NUMERIC, &mut buf).unwrap();
+            assert_eq!(value, Numeric::from_sql(&Type::NUMERIC, &buf).unwrap());
+        }
+
+        assert_eq!(Numeric::Value("0.1".to_owned()), Numeric::from(0.1));
+        for invalid in ["", "-", "1.2.3", "1e5", "12a"] {
+            assert!(invalid.parse::<Numeric>().is_err(), "{invalid}");
+        }
+        let mut buf = BytesMut::new();
+        assert!(Numeric::Value("abc".to_owned())
+            .to_sql(&Type::NUMERIC, &mut buf)
+            .is_err());
+    }
+}
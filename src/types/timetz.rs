@@ -0,0 +1,209 @@
+//! Values of `timetz`, `time with time zone`, a time of day with a fixed
+//! offset from UTC.
+//!
+//! The binary form is the microseconds since midnight as `Int64`, followed by
+//! the offset as `Int32` seconds **west** of UTC, the opposite sign of the
+//! displayed offset. So `04:05:06-07` has zone `25200`. The text form is like
+//! postgres output, with trailing zeros of fractional seconds trimmed, and
+//! minutes and seconds of the offset only when not zero, like
+//! `04:05:06.5+05:30`.
+
+use std::error::Error;
+use std::fmt;
+
+use bytes::{Buf, BufMut, BytesMut};
+use chrono::{FixedOffset, NaiveTime, Timelike};
+use postgres_types::{to_sql_checked, FromSql, IsNull, ToSql, Type};
+
+use super::ToSqlText;
+
+/// Value of postgres `timetz` type
+///
+/// Postgres allows `24:00:00`, the end of a day, which is kept as the leap
+/// second `23:59:60` of chrono, so that it's sent back as `24:00:00`. Other
+/// leap seconds are out of range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, new)]
+pub struct TimeTz {
+    pub time: NaiveTime,
+    /// offset east of UTC, as displayed
+    pub offset: FixedOffset,
+}
+
+impl TimeTz {
+    /// Microseconds since midnight, a leap second counts as the 60th second
+    fn microseconds(&self) -> i64 {
+        self.time.num_seconds_from_midnight() as i64 * 1_000_000
+            + self.time.nanosecond() as i64 / 1_000
+    }
+
+    /// Check that the time is at most `24:00:00`, the only leap second
+    fn check_range(&self) -> Result<(), Box<dyn Error + Sync + Send>> {
+        if self.time.nanosecond() >= 1_000_000_000 && self.time != end_of_day() {
+            return Err("timetz out of range".into());
+        }
+        Ok(())
+    }
+}
+
+/// `24:00:00`, as a leap second
+fn end_of_day() -> NaiveTime {
+    NaiveTime::from_hms_nano_opt(23, 59, 59, 1_000_000_000).expect("valid leap second")
+}
+
+impl fmt::Display for TimeTz {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let micros = self.microseconds();
+        let secs = micros / 1_000_000;
+        write!(
+            f,
+            "{:02}:{:02}:{:02}",
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60
+        )?;
+        let fraction = micros % 1_000_000;
+        if fraction != 0 {
+            let fraction = format!("{fraction:06}");
+            write!(f, ".{}", fraction.trim_end_matches('0'))?;
+        }
+
+        let offset = self.offset.local_minus_utc();
+        let sign = if offset < 0 { '-' } else { '+' };
+        let offset = offset.abs();
+        write!(f, "{sign}{:02}", offset / 3600)?;
+        if offset % 3600 != 0 {
+            write!(f, ":{:02}", offset / 60 % 60)?;
+            if offset % 60 != 0 {
+                write!(f, ":{:02}", offset % 60)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ToSqlText for TimeTz {
+    fn to_sql_text(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        self.check_range()?;
+        out.put_slice(self.to_string().as_bytes());
+        Ok(IsNull::No)
+    }
+}
+
+impl ToSql for TimeTz {
+    fn to_sql(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        self.check_range()?;
+        out.put_i64(self.microseconds());
+        // postgres keeps the offset as seconds west of UTC
+        out.put_i32(-self.offset.local_minus_utc());
+        Ok(IsNull::No)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        *ty == Type::TIMETZ
+    }
+
+    to_sql_checked!();
+}
+
+impl<'a> FromSql<'a> for TimeTz {
+    fn from_sql(_ty: &Type, mut raw: &'a [u8]) -> Result<TimeTz, Box<dyn Error + Sync + Send>> {
+        if raw.len() != 12 {
+            return Err("invalid message length: timetz size mismatch".into());
+        }
+        let micros = raw.get_i64();
+        let zone = raw.get_i32();
+
+        let secs =
+            u32::try_from(micros.div_euclid(1_000_000)).map_err(|_| "timetz out of range")?;
+        let nanos = micros.rem_euclid(1_000_000) as u32 * 1_000;
+        let time = NaiveTime::from_num_seconds_from_midnight_opt(secs, nanos)
+            // 24:00:00 is allowed by postgres, and kept as a leap second
+            .or_else(|| (micros == 86_400_000_000).then(end_of_day))
+            .ok_or("timetz out of range")?;
+        let offset = FixedOffset::east_opt(-zone).ok_or("timetz offset out of range")?;
+        Ok(TimeTz { time, offset })
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        *ty == Type::TIMETZ
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn time_tz(h: u32, m: u32, s: u32, micro: u32, offset: i32) -> TimeTz {
+        TimeTz::new(
+            NaiveTime::from_hms_micro_opt(h, m, s, micro).unwrap(),
+            FixedOffset::east_opt(offset).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_timetz_encoding() {
+        for (value, text) in [
+            (time_tz(4, 5, 6, 0, -7 * 3600), "04:05:06-07"),
+            (
+                time_tz(4, 5, 6, 500_000, 5 * 3600 + 1800),
+                "04:05:06.5+05:30",
+            ),
+            (time_tz(0, 0, 0, 1, 0), "00:00:00.000001+00"),
+            (
+                time_tz(23, 59, 59, 0, -(3600 + 60 + 1)),
+                "23:59:59-01:01:01",
+            ),
+        ] {
+            let mut buf = BytesMut::new();
+            value.to_sql_text(&Type::TIMETZ, &mut buf).unwrap();
+            assert_eq!(text.as_bytes(), &buf[..]);
+        }
+
+        // the zone has the opposite sign of the displayed offset
+        let value = time_tz(4, 5, 6, 0, -7 * 3600);
+        let mut buf = BytesMut::new();
+        value.to_sql_checked(&Type::TIMETZ, &mut buf).unwrap();
+        let micros: i64 = (4 * 3600 + 5 * 60 + 6) * 1_000_000;
+        let mut expected = micros.to_be_bytes().to_vec();
+        expected.extend_from_slice(&25200i32.to_be_bytes());
+        assert_eq!(&expected[..], &buf[..]);
+        assert_eq!(value, TimeTz::from_sql(&Type::TIMETZ, &buf).unwrap());
+        assert!(value.to_sql_checked(&Type::TIME, &mut buf).is_err());
+
+        let value = time_tz(23, 59, 59, 999_999, 14 * 3600);
+        let mut buf = BytesMut::new();
+        value.to_sql(&Type::TIMETZ, &mut buf).unwrap();
+        assert_eq!(value, TimeTz::from_sql(&Type::TIMETZ, &buf).unwrap());
+        assert!(TimeTz::from_sql(&Type::TIMETZ, &buf[..8]).is_err());
+    }
+
+    #[test]
+    fn test_timetz_end_of_day() {
+        // timetz_send('24:00:00-07'::timetz) of postgres 15
+        let raw = [0, 0, 0, 0x14, 0x1d, 0xd7, 0x60, 0, 0, 0, 0x62, 0x70];
+        let value = TimeTz::from_sql(&Type::TIMETZ, &raw).unwrap();
+        assert_ne!(NaiveTime::MIN, value.time);
+        assert_eq!("24:00:00-07", value.to_string());
+        let mut buf = BytesMut::new();
+        value.to_sql(&Type::TIMETZ, &mut buf).unwrap();
+        assert_eq!(&raw[..], &buf[..]);
+
+        // after the end of day
+        let mut raw = raw;
+        raw[7] = 1;
+        assert!(TimeTz::from_sql(&Type::TIMETZ, &raw).is_err());
+        let leap = TimeTz::new(
+            NaiveTime::from_hms_nano_opt(12, 0, 59, 1_000_000_000).unwrap(),
+            FixedOffset::east_opt(0).unwrap(),
+        );
+        assert!(leap.to_sql(&Type::TIMETZ, &mut buf).is_err());
+    }
+}
@@ -0,0 +1,471 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use bytes::{BufMut, BytesMut};
+use postgres_types::{to_sql_checked, IsNull, ToSql, Type};
+
+use super::ToSqlText;
+
+/// Max position of a lexeme, larger positions are clamped to it as postgres
+/// does
+pub const TS_MAX_POSITION: u16 = (1 << 14) - 1;
+/// Max number of positions of a lexeme, extra positions are dropped
+pub const TS_MAX_POSITIONS: usize = 256;
+
+/// Weight of a lexeme position, `D` is the default and omitted in text form
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum TsWeight {
+    A,
+    B,
+    C,
+    D,
+}
+
+impl TsWeight {
+    fn letter(&self) -> char {
+        match self {
+            TsWeight::A => 'A',
+            TsWeight::B => 'B',
+            TsWeight::C => 'C',
+            TsWeight::D => 'D',
+        }
+    }
+
+    /// Weight stored in the 2 high bits of binary position
+    fn position_bits(&self) -> u16 {
+        match self {
+            TsWeight::A => 3,
+            TsWeight::B => 2,
+            TsWeight::C => 1,
+            TsWeight::D => 0,
+        }
+    }
+
+    /// Bit in the weight mask of a `tsquery` operand
+    fn mask_bit(&self) -> u8 {
+        match self {
+            TsWeight::A => 1 << 3,
+            TsWeight::B => 1 << 2,
+            TsWeight::C => 1 << 1,
+            TsWeight::D => 1,
+        }
+    }
+}
+
+/// Value of postgres `tsvector` type.
+///
+/// Lexemes are kept sorted and unique, and positions of each lexeme sorted
+/// and unique, as postgres requires them to be.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TsVector {
+    lexemes: BTreeMap<String, BTreeMap<u16, TsWeight>>,
+}
+
+impl TsVector {
+    pub fn new() -> TsVector {
+        TsVector::default()
+    }
+
+    /// Add a lexeme without position
+    pub fn add_lexeme<S: Into<String>>(&mut self, lexeme: S) -> &mut Self {
+        self.lexemes.entry(lexeme.into()).or_default();
+        self
+    }
+
+    /// Add a position to lexeme. Position is clamped to
+    /// `1..=TS_MAX_POSITION`, and a duplicated position replaces the weight
+    /// of previous one.
+    pub fn add<S: Into<String>>(
+        &mut self,
+        lexeme: S,
+        position: u16,
+        weight: TsWeight,
+    ) -> &mut Self {
+        let position = position.clamp(1, TS_MAX_POSITION);
+        self.lexemes
+            .entry(lexeme.into())
+            .or_default()
+            .insert(position, weight);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.lexemes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lexemes.is_empty()
+    }
+
+    /// Lexemes with their positions, in order
+    pub fn lexemes(&self) -> impl Iterator<Item = (&str, Vec<(u16, TsWeight)>)> {
+        self.lexemes.iter().map(|(lexeme, positions)| {
+            (
+                lexeme.as_str(),
+                positions
+                    .iter()
+                    .take(TS_MAX_POSITIONS)
+                    .map(|(pos, weight)| (*pos, *weight))
+                    .collect(),
+            )
+        })
+    }
+}
+
+/// Write a lexeme quoted, with quotes and backslashes doubled
+fn put_quoted_lexeme(out: &mut BytesMut, lexeme: &str) {
+    out.put_u8(b'\'');
+    for c in lexeme.chars() {
+        if c == '\'' || c == '\\' {
+            out.put_u8(c as u8);
+        }
+        let mut utf8 = [0u8; 4];
+        out.put_slice(c.encode_utf8(&mut utf8).as_bytes());
+    }
+    out.put_u8(b'\'');
+}
+
+fn put_binary_lexeme(out: &mut BytesMut, lexeme: &str) -> Result<(), Box<dyn Error + Sync + Send>> {
+    if lexeme.contains('\0') {
+        return Err("lexeme contains null character".into());
+    }
+    out.put_slice(lexeme.as_bytes());
+    out.put_u8(b'\0');
+    Ok(())
+}
+
+impl ToSqlText for TsVector {
+    fn to_sql_text(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        for (i, (lexeme, positions)) in self.lexemes().enumerate() {
+            if i > 0 {
+                out.put_u8(b' ');
+            }
+            put_quoted_lexeme(out, lexeme);
+            for (j, (position, weight)) in positions.iter().enumerate() {
+                out.put_u8(if j == 0 { b':' } else { b',' });
+                out.put_slice(position.to_string().as_bytes());
+                if *weight != TsWeight::D {
+                    out.put_u8(weight.letter() as u8);
+                }
+            }
+        }
+        Ok(IsNull::No)
+    }
+}
+
+impl ToSql for TsVector {
+    fn to_sql(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        out.put_i32(self.len() as i32);
+        for (lexeme, positions) in self.lexemes() {
+            put_binary_lexeme(out, lexeme)?;
+            out.put_u16(positions.len() as u16);
+            for (position, weight) in positions {
+                out.put_u16(weight.position_bits() << 14 | position);
+            }
+        }
+        Ok(IsNull::No)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        *ty == Type::TS_VECTOR
+    }
+
+    to_sql_checked!();
+}
+
+/// Value of postgres `tsquery` type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TsQuery {
+    /// A lexeme, optionally restricted to `weights` and matching as prefix
+    Lexeme {
+        lexeme: String,
+        weights: Vec<TsWeight>,
+        prefix: bool,
+    },
+    /// `!query`
+    Not(Box<TsQuery>),
+    /// `left & right`
+    And(Box<TsQuery>, Box<TsQuery>),
+    /// `left | right`
+    Or(Box<TsQuery>, Box<TsQuery>),
+    /// `left <distance> right`, `<->` for distance 1
+    Phrase(Box<TsQuery>, Box<TsQuery>, u16),
+}
+
+const TS_QUERY_ITEM_VALUE: i8 = 1;
+const TS_QUERY_ITEM_OPERATOR: i8 = 2;
+
+const TS_QUERY_OP_NOT: i8 = 1;
+const TS_QUERY_OP_AND: i8 = 2;
+const TS_QUERY_OP_OR: i8 = 3;
+const TS_QUERY_OP_PHRASE: i8 = 4;
+
+impl TsQuery {
+    pub fn lexeme<S: Into<String>>(lexeme: S) -> TsQuery {
+        TsQuery::Lexeme {
+            lexeme: lexeme.into(),
+            weights: Vec::new(),
+            prefix: false,
+        }
+    }
+
+    /// Prefix match lexeme, `'lexeme':*`
+    pub fn prefix<S: Into<String>>(lexeme: S) -> TsQuery {
+        TsQuery::Lexeme {
+            lexeme: lexeme.into(),
+            weights: Vec::new(),
+            prefix: true,
+        }
+    }
+
+    /// Lexeme matching only positions of given weights, `'lexeme':AB`
+    pub fn weighted<S: Into<String>>(lexeme: S, weights: &[TsWeight]) -> TsQuery {
+        TsQuery::Lexeme {
+            lexeme: lexeme.into(),
+            weights: weights.to_vec(),
+            prefix: false,
+        }
+    }
+
+    pub fn negate(query: TsQuery) -> TsQuery {
+        TsQuery::Not(Box::new(query))
+    }
+
+    pub fn and(left: TsQuery, right: TsQuery) -> TsQuery {
+        TsQuery::And(Box::new(left), Box::new(right))
+    }
+
+    pub fn or(left: TsQuery, right: TsQuery) -> TsQuery {
+        TsQuery::Or(Box::new(left), Box::new(right))
+    }
+
+    pub fn phrase(left: TsQuery, right: TsQuery, distance: u16) -> TsQuery {
+        TsQuery::Phrase(Box::new(left), Box::new(right), distance)
+    }
+
+    fn priority(&self) -> u8 {
+        match self {
+            TsQuery::Lexeme { .. } | TsQuery::Not(_) => 4,
+            TsQuery::Phrase(..) => 3,
+            TsQuery::And(..) => 2,
+            TsQuery::Or(..) => 1,
+        }
+    }
+
+    fn item_count(&self) -> usize {
+        match self {
+            TsQuery::Lexeme { .. } => 1,
+            TsQuery::Not(query) => 1 + query.item_count(),
+            TsQuery::And(left, right)
+            | TsQuery::Or(left, right)
+            | TsQuery::Phrase(left, right, _) => 1 + left.item_count() + right.item_count(),
+        }
+    }
+
+    fn put_text(&self, out: &mut BytesMut, parenthesis: bool) {
+        if parenthesis {
+            out.put_slice(b"( ");
+        }
+        match self {
+            TsQuery::Lexeme {
+                lexeme,
+                weights,
+                prefix,
+            } => {
+                put_quoted_lexeme(out, lexeme);
+                if *prefix || !weights.is_empty() {
+                    out.put_u8(b':');
+                }
+                if *prefix {
+                    out.put_u8(b'*');
+                }
+                let mut weights = weights.clone();
+                weights.sort();
+                weights.dedup();
+                for weight in weights {
+                    out.put_u8(weight.letter() as u8);
+                }
+            }
+            TsQuery::Not(query) => {
+                out.put_u8(b'!');
+                query.put_text(out, query.priority() < self.priority());
+            }
+            TsQuery::And(left, right)
+            | TsQuery::Or(left, right)
+            | TsQuery::Phrase(left, right, _) => {
+                left.put_text(out, left.priority() < self.priority());
+                match self {
+                    TsQuery::And(..) => out.put_slice(b" & "),
+                    TsQuery::Or(..) => out.put_slice(b" | "),
+                    TsQuery::Phrase(_, _, 1) => out.put_slice(b" <-> "),
+                    TsQuery::Phrase(_, _, distance) => {
+                        out.put_slice(format!(" <{distance}> ").as_bytes())
+                    }
+                    _ => unreachable!(),
+                }
+                // phrase operator is not associative
+                let right_parenthesis = right.priority() < self.priority()
+                    || (matches!(self, TsQuery::Phrase(..))
+                        && matches!(**right, TsQuery::Phrase(..)));
+                right.put_text(out, right_parenthesis);
+            }
+        }
+        if parenthesis {
+            out.put_slice(b" )");
+        }
+    }
+
+    /// Items in the order postgres stores them: an operator followed by its
+    /// right operand, and then its left operand
+    fn put_binary(&self, out: &mut BytesMut) -> Result<(), Box<dyn Error + Sync + Send>> {
+        match self {
+            TsQuery::Lexeme {
+                lexeme,
+                weights,
+                prefix,
+            } => {
+                out.put_i8(TS_QUERY_ITEM_VALUE);
+                out.put_u8(weights.iter().fold(0, |mask, w| mask | w.mask_bit()));
+                out.put_u8(*prefix as u8);
+                put_binary_lexeme(out, lexeme)?;
+            }
+            TsQuery::Not(query) => {
+                out.put_i8(TS_QUERY_ITEM_OPERATOR);
+                out.put_i8(TS_QUERY_OP_NOT);
+                query.put_binary(out)?;
+            }
+            TsQuery::And(left, right)
+            | TsQuery::Or(left, right)
+            | TsQuery::Phrase(left, right, _) => {
+                out.put_i8(TS_QUERY_ITEM_OPERATOR);
+                match self {
+                    TsQuery::And(..) => out.put_i8(TS_QUERY_OP_AND),
+                    TsQuery::Or(..) => out.put_i8(TS_QUERY_OP_OR),
+                    TsQuery::Phrase(_, _, distance) => {
+                        out.put_i8(TS_QUERY_OP_PHRASE);
+                        out.put_u16(*distance);
+                    }
+                    _ => unreachable!(),
+                }
+                right.put_binary(out)?;
+                left.put_binary(out)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ToSqlText for TsQuery {
+    fn to_sql_text(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        self.put_text(out, false);
+        Ok(IsNull::No)
+    }
+}
+
+impl ToSql for TsQuery {
+    fn to_sql(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        out.put_i32(self.item_count() as i32);
+        self.put_binary(out)?;
+        Ok(IsNull::No)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        *ty == Type::TSQUERY
+    }
+
+    to_sql_checked!();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn text<T: ToSqlText>(value: &T, ty: &Type) -> String {
+        let mut buf = BytesMut::new();
+        value.to_sql_text(ty, &mut buf).unwrap();
+        String::from_utf8(buf.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_tsvector_encoding() {
+        let mut vector = TsVector::new();
+        vector
+            .add("sat", 3, TsWeight::D)
+            .add("cat", 1, TsWeight::A)
+            .add("cat", 5, TsWeight::D)
+            .add("it's", 20000, TsWeight::B)
+            .add_lexeme("a b\\");
+        assert_eq!(
+            "'a b\\\\' 'cat':1A,5 'it''s':16383B 'sat':3",
+            text(&vector, &Type::TS_VECTOR)
+        );
+
+        let mut buf = BytesMut::new();
+        let mut cat = TsVector::new();
+        cat.add("cat", 1, TsWeight::A).add("cat", 5, TsWeight::D);
+        cat.to_sql_checked(&Type::TS_VECTOR, &mut buf).unwrap();
+        assert_eq!(
+            &[0, 0, 0, 1, b'c', b'a', b't', 0, 0, 2, 0xc0, 1, 0, 5][..],
+            &buf[..]
+        );
+
+        assert!(TsVector::new()
+            .to_sql_checked(&Type::TSQUERY, &mut buf)
+            .is_err());
+    }
+
+    #[test]
+    fn test_tsquery_encoding() {
+        let query = TsQuery::and(
+            TsQuery::lexeme("fat"),
+            TsQuery::or(
+                TsQuery::weighted("rat", &[TsWeight::B, TsWeight::A]),
+                TsQuery::negate(TsQuery::prefix("ca't")),
+            ),
+        );
+        assert_eq!(
+            "'fat' & ( 'rat':AB | !'ca''t':* )",
+            text(&query, &Type::TSQUERY)
+        );
+
+        let phrase = TsQuery::phrase(
+            TsQuery::lexeme("a"),
+            TsQuery::phrase(TsQuery::lexeme("b"), TsQuery::lexeme("c"), 2),
+            1,
+        );
+        assert_eq!("'a' <-> ( 'b' <2> 'c' )", text(&phrase, &Type::TSQUERY));
+
+        let mut buf = BytesMut::new();
+        let query = TsQuery::phrase(
+            TsQuery::lexeme("a"),
+            TsQuery::weighted("b", &[TsWeight::A]),
+            2,
+        );
+        query.to_sql_checked(&Type::TSQUERY, &mut buf).unwrap();
+        assert_eq!(
+            &[
+                0, 0, 0, 3, // items
+                2, 4, 0, 2, // phrase with distance 2
+                1, 8, 0, b'b', 0, // right operand
+                1, 0, 0, b'a', 0 // left operand
+            ][..],
+            &buf[..]
+        );
+    }
+}
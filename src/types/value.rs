@@ -0,0 +1,803 @@
+use std::error::Error;
+
+use bytes::Bytes;
+use chrono::offset::Utc;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime};
+use postgres_types::{FromSql, Kind, Type};
+
+use super::encoding_type;
+use crate::api::results::FieldFormat;
+use crate::error::{PgWireError, PgWireResult};
+
+type DecodeResult<T> = Result<T, Box<dyn Error + Sync + Send>>;
+
+/// Max number of array dimensions, `MAXDIM` of postgres
+const MAX_ARRAY_DIMENSIONS: usize = 6;
+
+fn too_many_dimensions(ndim: usize) -> Box<dyn Error + Sync + Send> {
+    format!(
+        "number of array dimensions ({ndim}) exceeds the maximum allowed ({MAX_ARRAY_DIMENSIONS})"
+    )
+    .into()
+}
+
+/// A value decoded from its wire encoding, by its postgres type and format.
+///
+/// Common scalar types and arrays of them are decoded. Values of other types
+/// are kept as received in `Raw`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PgValue {
+    Null,
+    Bool(bool),
+    Int2(i16),
+    Int4(i32),
+    Int8(i64),
+    Oid(u32),
+    Float4(f32),
+    Float8(f64),
+    /// `numeric` in its text representation, like `-12.50`, as it may not
+    /// fit any rust number type
+    Numeric(String),
+    /// text types, like `text`, `varchar`, `name` and `citext`, and `json`
+    /// and `jsonb`
+    Text(String),
+    Bytea(Vec<u8>),
+    Date(NaiveDate),
+    Time(NaiveTime),
+    Timestamp(NaiveDateTime),
+    TimestampTz(DateTime<Utc>),
+    /// array, with nested arrays for each dimension after the first
+    Array(Vec<PgValue>),
+    Raw(Bytes),
+}
+
+impl PgValue {
+    /// Decode `value` of type `ty` in `format`, `None` for null.
+    ///
+    /// Domain types are decoded as their base type. A value of a type
+    /// without decoder is returned as `Raw`. Fails with
+    /// `FailedToParseParameter` on a malformed value.
+    pub fn decode(ty: &Type, format: FieldFormat, value: Option<&Bytes>) -> PgWireResult<PgValue> {
+        let value = match value {
+            Some(value) => value,
+            None => return Ok(PgValue::Null),
+        };
+        let decoded = match format {
+            FieldFormat::Text => std::str::from_utf8(value)
+                .map_err(|e| e.into())
+                .and_then(|text| decode_text(encoding_type(ty), text)),
+            FieldFormat::Binary => decode_binary(encoding_type(ty), value),
+        };
+        decoded
+            .map(|decoded| decoded.unwrap_or_else(|| PgValue::Raw(value.clone())))
+            .map_err(PgWireError::FailedToParseParameter)
+    }
+}
+
+fn is_text_type(ty: &Type) -> bool {
+    matches!(
+        *ty,
+        Type::TEXT
+            | Type::VARCHAR
+            | Type::BPCHAR
+            | Type::NAME
+            | Type::UNKNOWN
+            | Type::JSON
+            | Type::JSONB
+    ) || ty.name() == "citext"
+}
+
+fn decode_binary(ty: &Type, raw: &[u8]) -> DecodeResult<Option<PgValue>> {
+    let value = match *ty {
+        Type::BOOL => PgValue::Bool(bool::from_sql(ty, raw)?),
+        Type::INT2 => PgValue::Int2(i16::from_sql(ty, raw)?),
+        Type::INT4 => PgValue::Int4(i32::from_sql(ty, raw)?),
+        Type::INT8 => PgValue::Int8(i64::from_sql(ty, raw)?),
+        Type::OID => PgValue::Oid(u32::from_sql(ty, raw)?),
+        Type::FLOAT4 => PgValue::Float4(f32::from_sql(ty, raw)?),
+        Type::FLOAT8 => PgValue::Float8(f64::from_sql(ty, raw)?),
+        Type::NUMERIC => PgValue::Numeric(decode_binary_numeric(raw)?),
+        Type::BYTEA => PgValue::Bytea(raw.to_vec()),
+        Type::DATE => PgValue::Date(NaiveDate::from_sql(ty, raw)?),
+        Type::TIME => PgValue::Time(NaiveTime::from_sql(ty, raw)?),
+        Type::TIMESTAMP => PgValue::Timestamp(NaiveDateTime::from_sql(ty, raw)?),
+        Type::TIMESTAMPTZ => PgValue::TimestampTz(DateTime::<Utc>::from_sql(ty, raw)?),
+        Type::JSONB => match raw.split_first() {
+            Some((1, json)) => PgValue::Text(std::str::from_utf8(json)?.to_owned()),
+            _ => return Err("unsupported jsonb encoding version".into()),
+        },
+        _ if is_text_type(ty) => PgValue::Text(std::str::from_utf8(raw)?.to_owned()),
+        _ => match ty.kind() {
+            Kind::Array(element) => return decode_binary_array(element, raw),
+            _ => return Ok(None),
+        },
+    };
+    Ok(Some(value))
+}
+
+/// Check that `raw` is a valid binary value of `ty`, failing like
+/// `PgValue::decode` would, without decoding it into a `PgValue`. Nothing is
+/// allocated, so it's cheap enough to check parameters on each `Bind`.
+pub(crate) fn validate_binary(ty: &Type, raw: &[u8]) -> DecodeResult<()> {
+    let ty = encoding_type(ty);
+    match *ty {
+        Type::BOOL => bool::from_sql(ty, raw).map(drop),
+        Type::INT2 => i16::from_sql(ty, raw).map(drop),
+        Type::INT4 => i32::from_sql(ty, raw).map(drop),
+        Type::INT8 => i64::from_sql(ty, raw).map(drop),
+        Type::OID => u32::from_sql(ty, raw).map(drop),
+        Type::FLOAT4 => f32::from_sql(ty, raw).map(drop),
+        Type::FLOAT8 => f64::from_sql(ty, raw).map(drop),
+        Type::NUMERIC => validate_binary_numeric(raw),
+        Type::DATE => NaiveDate::from_sql(ty, raw).map(drop),
+        Type::TIME => NaiveTime::from_sql(ty, raw).map(drop),
+        Type::TIMESTAMP => NaiveDateTime::from_sql(ty, raw).map(drop),
+        Type::TIMESTAMPTZ => DateTime::<Utc>::from_sql(ty, raw).map(drop),
+        Type::JSONB => match raw.split_first() {
+            Some((1, json)) => std::str::from_utf8(json).map(drop).map_err(Into::into),
+            _ => Err("unsupported jsonb encoding version".into()),
+        },
+        _ if is_text_type(ty) => std::str::from_utf8(raw).map(drop).map_err(Into::into),
+        _ => match ty.kind() {
+            Kind::Array(element) => validate_binary_array(element, raw),
+            _ => Ok(()),
+        },
+    }
+}
+
+fn validate_binary_numeric(mut raw: &[u8]) -> DecodeResult<()> {
+    let ndigits = read_i16(&mut raw)?;
+    for _ in 0..3 {
+        read_i16(&mut raw)?;
+    }
+    for _ in 0..ndigits.max(0) {
+        read_i16(&mut raw)?;
+    }
+    Ok(())
+}
+
+fn validate_binary_array(element: &Type, mut raw: &[u8]) -> DecodeResult<()> {
+    let ndim = read_i32(&mut raw)?;
+    if ndim < 0 {
+        return Err(format!("invalid number of dimensions: {ndim}").into());
+    }
+    if ndim as usize > MAX_ARRAY_DIMENSIONS {
+        return Err(too_many_dimensions(ndim as usize));
+    }
+    let _has_null = read_i32(&mut raw)?;
+    let _element_oid = read_i32(&mut raw)?;
+    let mut total = 1usize;
+    for _ in 0..ndim {
+        let len = read_i32(&mut raw)?;
+        let _lower_bound = read_i32(&mut raw)?;
+        total = total
+            .checked_mul(len.max(0) as usize)
+            .ok_or("array size exceeds the maximum allowed")?;
+    }
+
+    if ndim > 0 {
+        for _ in 0..total {
+            let len = read_i32(&mut raw)?;
+            if len < 0 {
+                continue;
+            }
+            if raw.len() < len as usize {
+                return Err("unexpected end of binary value".into());
+            }
+            let (value, rest) = raw.split_at(len as usize);
+            raw = rest;
+            validate_binary(element, value)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_i32(raw: &mut &[u8]) -> DecodeResult<i32> {
+    if raw.len() < 4 {
+        return Err("unexpected end of binary value".into());
+    }
+    let (head, rest) = raw.split_at(4);
+    *raw = rest;
+    Ok(i32::from_be_bytes([head[0], head[1], head[2], head[3]]))
+}
+
+fn read_i16(raw: &mut &[u8]) -> DecodeResult<i16> {
+    if raw.len() < 2 {
+        return Err("unexpected end of binary value".into());
+    }
+    let (head, rest) = raw.split_at(2);
+    *raw = rest;
+    Ok(i16::from_be_bytes([head[0], head[1]]))
+}
+
+/// Decode binary `numeric`: digits in base 10000, with the weight of the
+/// first one, the sign and the number of decimal digits after the point.
+pub(super) fn decode_binary_numeric(mut raw: &[u8]) -> DecodeResult<String> {
+    let ndigits = read_i16(&mut raw)?;
+    let weight = read_i16(&mut raw)?;
+    let sign = read_i16(&mut raw)? as u16;
+    let scale = read_i16(&mut raw)?.max(0) as usize;
+    match sign {
+        0xC000 => return Ok("NaN".to_owned()),
+        0xD000 => return Ok("Infinity".to_owned()),
+        0xF000 => return Ok("-Infinity".to_owned()),
+        _ => {}
+    }
+    let digits = (0..ndigits.max(0))
+        .map(|_| read_i16(&mut raw))
+        .collect::<DecodeResult<Vec<i16>>>()?;
+
+    let mut int_part = String::new();
+    for idx in 0..=weight.max(-1) {
+        let digit = digits.get(idx as usize).copied().unwrap_or(0);
+        if int_part.is_empty() {
+            if digit != 0 {
+                int_part.push_str(&digit.to_string());
+            }
+        } else {
+            int_part.push_str(&format!("{digit:04}"));
+        }
+    }
+    if int_part.is_empty() {
+        int_part.push('0');
+    }
+
+    let mut frac_part = String::new();
+    let mut idx = weight as i32 + 1;
+    while frac_part.len() < scale {
+        let digit = if idx < 0 {
+            0
+        } else {
+            digits.get(idx as usize).copied().unwrap_or(0)
+        };
+        frac_part.push_str(&format!("{digit:04}"));
+        idx += 1;
+    }
+    frac_part.truncate(scale);
+
+    let mut numeric = String::new();
+    if sign == 0x4000 {
+        numeric.push('-');
+    }
+    numeric.push_str(&int_part);
+    if scale > 0 {
+        numeric.push('.');
+        numeric.push_str(&frac_part);
+    }
+    Ok(numeric)
+}
+
+fn decode_binary_array(element: &Type, mut raw: &[u8]) -> DecodeResult<Option<PgValue>> {
+    let ndim = read_i32(&mut raw)?;
+    if ndim < 0 {
+        return Err(format!("invalid number of dimensions: {ndim}").into());
+    }
+    if ndim as usize > MAX_ARRAY_DIMENSIONS {
+        return Err(too_many_dimensions(ndim as usize));
+    }
+    let _has_null = read_i32(&mut raw)?;
+    let _element_oid = read_i32(&mut raw)?;
+    let mut dims = Vec::new();
+    for _ in 0..ndim {
+        let len = read_i32(&mut raw)?;
+        let _lower_bound = read_i32(&mut raw)?;
+        dims.push(len.max(0) as usize);
+    }
+
+    // dimensions are sent by client, the product may overflow
+    let total = dims
+        .iter()
+        .try_fold(1usize, |total, len| total.checked_mul(*len))
+        .ok_or("array size exceeds the maximum allowed")?;
+    let mut values = Vec::new();
+    if ndim > 0 {
+        for _ in 0..total {
+            let len = read_i32(&mut raw)?;
+            if len < 0 {
+                values.push(PgValue::Null);
+                continue;
+            }
+            if raw.len() < len as usize {
+                return Err("unexpected end of binary value".into());
+            }
+            let (value, rest) = raw.split_at(len as usize);
+            raw = rest;
+            values.push(match decode_binary(element, value)? {
+                Some(value) => value,
+                None => PgValue::Raw(Bytes::copy_from_slice(value)),
+            });
+        }
+    }
+    Ok(Some(nest_array(values, &dims)))
+}
+
+/// Nest flat array values by dimensions, the last dimension varying fastest
+fn nest_array(values: Vec<PgValue>, dims: &[usize]) -> PgValue {
+    match dims {
+        [] | [_] => PgValue::Array(values),
+        [_, inner @ ..] => {
+            let chunk = inner.iter().product::<usize>().max(1);
+            let mut values = values.into_iter();
+            let mut nested = Vec::new();
+            loop {
+                let part = values.by_ref().take(chunk).collect::<Vec<_>>();
+                if part.is_empty() {
+                    break;
+                }
+                nested.push(nest_array(part, inner));
+            }
+            PgValue::Array(nested)
+        }
+    }
+}
+
+fn invalid_text(ty: &Type, text: &str) -> Box<dyn Error + Sync + Send> {
+    format!("invalid input syntax for type {}: \"{text}\"", ty.name()).into()
+}
+
+fn decode_text(ty: &Type, text: &str) -> DecodeResult<Option<PgValue>> {
+    let invalid = || invalid_text(ty, text);
+    let trimmed = text.trim();
+    let value = match *ty {
+        Type::BOOL => match trimmed.to_ascii_lowercase().as_str() {
+            "t" | "true" | "y" | "yes" | "on" | "1" => PgValue::Bool(true),
+            "f" | "false" | "n" | "no" | "off" | "0" => PgValue::Bool(false),
+            _ => return Err(invalid()),
+        },
+        Type::INT2 => PgValue::Int2(trimmed.parse().map_err(|_| invalid())?),
+        Type::INT4 => PgValue::Int4(trimmed.parse().map_err(|_| invalid())?),
+        Type::INT8 => PgValue::Int8(trimmed.parse().map_err(|_| invalid())?),
+        Type::OID => PgValue::Oid(trimmed.parse().map_err(|_| invalid())?),
+        // rust parses `NaN` and `Infinity` case-insensitively, like postgres
+        Type::FLOAT4 => PgValue::Float4(trimmed.parse().map_err(|_| invalid())?),
+        Type::FLOAT8 => PgValue::Float8(trimmed.parse().map_err(|_| invalid())?),
+        Type::NUMERIC => {
+            trimmed.parse::<f64>().map_err(|_| invalid())?;
+            PgValue::Numeric(trimmed.to_owned())
+        }
+        Type::BYTEA => PgValue::Bytea(decode_text_bytea(text).ok_or_else(invalid)?),
+        Type::DATE => {
+            PgValue::Date(NaiveDate::parse_from_str(trimmed, "%Y-%m-%d").map_err(|_| invalid())?)
+        }
+        Type::TIME => PgValue::Time(
+            NaiveTime::parse_from_str(trimmed, "%H:%M:%S%.f")
+                .or_else(|_| NaiveTime::parse_from_str(trimmed, "%H:%M"))
+                .map_err(|_| invalid())?,
+        ),
+        Type::TIMESTAMP => PgValue::Timestamp(
+            NaiveDateTime::parse_from_str(trimmed, "%Y-%m-%d %H:%M:%S%.f")
+                .or_else(|_| NaiveDateTime::parse_from_str(trimmed, "%Y-%m-%dT%H:%M:%S%.f"))
+                .map_err(|_| invalid())?,
+        ),
+        Type::TIMESTAMPTZ => PgValue::TimestampTz(
+            DateTime::parse_from_str(trimmed, "%Y-%m-%d %H:%M:%S%.f%#z")
+                .or_else(|_| DateTime::parse_from_str(trimmed, "%Y-%m-%dT%H:%M:%S%.f%#z"))
+                .map_err(|_| invalid())?
+                .with_timezone(&Utc),
+        ),
+        _ if is_text_type(ty) => PgValue::Text(text.to_owned()),
+        _ => match ty.kind() {
+            Kind::Array(element) => {
+                let mut parser = ArrayParser {
+                    chars: text.chars().collect(),
+                    pos: 0,
+                };
+                return parser
+                    .parse(element)
+                    .map(Some)
+                    .map_err(|_| invalid_text(ty, text));
+            }
+            _ => return Ok(None),
+        },
+    };
+    Ok(Some(value))
+}
+
+/// Decode `bytea` in hex format, like `\x0a0b`, or in escape format, like
+/// `a\000b`
+fn decode_text_bytea(text: &str) -> Option<Vec<u8>> {
+    if let Some(hex) = text.strip_prefix("\\x") {
+        return hex::decode(hex).ok();
+    }
+
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        if bytes[idx] != b'\\' {
+            decoded.push(bytes[idx]);
+            idx += 1;
+        } else if bytes.get(idx + 1) == Some(&b'\\') {
+            decoded.push(b'\\');
+            idx += 2;
+        } else {
+            let octal = std::str::from_utf8(bytes.get(idx + 1..idx + 4)?).ok()?;
+            decoded.push(u8::from_str_radix(octal, 8).ok()?);
+            idx += 4;
+        }
+    }
+    Some(decoded)
+}
+
+/// Parser of array text representation, like `{1,NULL,3}` or
+/// `{{"a b","c\"d"},{e,f}}`
+struct ArrayParser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl ArrayParser {
+    fn parse(&mut self, element: &Type) -> DecodeResult<PgValue> {
+        // optional dimension decoration, like `[0:1]={1,2}`
+        if self.peek() == Some('[') {
+            while self.next_char().ok_or("unexpected end of array")? != '=' {}
+        }
+        let value = self.parse_array(element, 1)?;
+        self.skip_whitespace();
+        if self.pos != self.chars.len() {
+            return Err("junk after closing right brace".into());
+        }
+        Ok(value)
+    }
+
+    /// Parse array at nesting `depth`, 1 for the outermost one
+    fn parse_array(&mut self, element: &Type, depth: usize) -> DecodeResult<PgValue> {
+        if depth > MAX_ARRAY_DIMENSIONS {
+            return Err(too_many_dimensions(depth));
+        }
+        self.skip_whitespace();
+        if self.next_char() != Some('{') {
+            return Err("array value must start with \"{\"".into());
+        }
+        let mut values = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            return Ok(PgValue::Array(values));
+        }
+        loop {
+            self.skip_whitespace();
+            let value = match self.peek() {
+                Some('{') => self.parse_array(element, depth + 1)?,
+                Some('"') => {
+                    let text = self.parse_quoted()?;
+                    decode_element(element, &text)?
+                }
+                _ => {
+                    let text = self.parse_unquoted()?;
+                    if text.eq_ignore_ascii_case("NULL") {
+                        PgValue::Null
+                    } else {
+                        decode_element(element, &text)?
+                    }
+                }
+            };
+            values.push(value);
+            self.skip_whitespace();
+            match self.next_char() {
+                Some(',') => continue,
+                Some('}') => return Ok(PgValue::Array(values)),
+                _ => return Err("unexpected array element delimiter".into()),
+            }
+        }
+    }
+
+    fn parse_quoted(&mut self) -> DecodeResult<String> {
+        self.pos += 1;
+        let mut text = String::new();
+        loop {
+            match self.next_char().ok_or("unexpected end of array")? {
+                '\\' => text.push(self.next_char().ok_or("unexpected end of array")?),
+                '"' => return Ok(text),
+                c => text.push(c),
+            }
+        }
+    }
+
+    fn parse_unquoted(&mut self) -> DecodeResult<String> {
+        let mut text = String::new();
+        while let Some(c) = self.peek() {
+            match c {
+                ',' | '}' => break,
+                '\\' => {
+                    self.pos += 1;
+                    text.push(self.next_char().ok_or("unexpected end of array")?);
+                }
+                '{' | '"' => return Err("unexpected character in array element".into()),
+                c => {
+                    self.pos += 1;
+                    text.push(c);
+                }
+            }
+        }
+        let text = text.trim_end();
+        if text.is_empty() {
+            return Err("empty array element".into());
+        }
+        Ok(text.to_owned())
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().map_or(false, char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next_char(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        Some(c)
+    }
+}
+
+fn decode_element(element: &Type, text: &str) -> DecodeResult<PgValue> {
+    Ok(decode_text(element, text)?.unwrap_or_else(|| PgValue::Raw(Bytes::from(text.to_owned()))))
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::{BufMut, BytesMut};
+    use postgres_types::ToSql;
+
+    use super::*;
+
+    fn text(ty: &Type, value: &str) -> PgWireResult<PgValue> {
+        PgValue::decode(ty, FieldFormat::Text, Some(&Bytes::from(value.to_owned())))
+    }
+
+    fn binary<T: ToSql>(ty: &Type, value: T) -> PgValue {
+        let mut buf = BytesMut::new();
+        value.to_sql(ty, &mut buf).unwrap();
+        PgValue::decode(ty, FieldFormat::Binary, Some(&buf.freeze())).unwrap()
+    }
+
+    #[test]
+    fn test_decode_text() {
+        for (ty, value, expected) in [
+            (Type::BOOL, "t", PgValue::Bool(true)),
+            (Type::BOOL, "off", PgValue::Bool(false)),
+            (Type::INT2, " 12", PgValue::Int2(12)),
+            (Type::INT8, "-9000000000", PgValue::Int8(-9000000000)),
+            (
+                Type::FLOAT8,
+                "-Infinity",
+                PgValue::Float8(f64::NEG_INFINITY),
+            ),
+            (Type::NUMERIC, "12.50", PgValue::Numeric("12.50".to_owned())),
+            (Type::VARCHAR, " tom ", PgValue::Text(" tom ".to_owned())),
+            (Type::BYTEA, "\\x0aff", PgValue::Bytea(vec![10, 255])),
+            (
+                Type::BYTEA,
+                "a\\000\\\\",
+                PgValue::Bytea(vec![b'a', 0, b'\\']),
+            ),
+            (
+                Type::DATE,
+                "2023-03-05",
+                PgValue::Date(NaiveDate::from_ymd_opt(2023, 3, 5).unwrap()),
+            ),
+            (
+                Type::TIMESTAMPTZ,
+                "2023-03-05 10:00:00.5+02",
+                PgValue::TimestampTz(
+                    NaiveDate::from_ymd_opt(2023, 3, 5)
+                        .unwrap()
+                        .and_hms_milli_opt(8, 0, 0, 500)
+                        .unwrap()
+                        .and_utc(),
+                ),
+            ),
+            (
+                Type::INT4_ARRAY,
+                "{1, NULL ,3}",
+                PgValue::Array(vec![PgValue::Int4(1), PgValue::Null, PgValue::Int4(3)]),
+            ),
+            (
+                Type::TEXT_ARRAY,
+                r#"[0:1]={{"a b","c\"d"},{NULL,"NULL"}}"#,
+                PgValue::Array(vec![
+                    PgValue::Array(vec![
+                        PgValue::Text("a b".to_owned()),
+                        PgValue::Text("c\"d".to_owned()),
+                    ]),
+                    PgValue::Array(vec![PgValue::Null, PgValue::Text("NULL".to_owned())]),
+                ]),
+            ),
+            (Type::INT4_ARRAY, "{}", PgValue::Array(vec![])),
+            (
+                Type::INET,
+                "127.0.0.1",
+                PgValue::Raw(Bytes::from("127.0.0.1")),
+            ),
+        ] {
+            assert_eq!(expected, text(&ty, value).unwrap(), "{value}");
+        }
+
+        for (ty, value) in [
+            (Type::INT2, "70000"),
+            (Type::BOOL, "maybe"),
+            (Type::DATE, "2023-02-30"),
+            (Type::INT4_ARRAY, "{1,2"),
+            (Type::INT4_ARRAY, "{1,a}"),
+            (Type::INT4_ARRAY, "{1} x"),
+            // more dimensions than `MAX_ARRAY_DIMENSIONS`
+            (Type::INT4_ARRAY, "{{{{{{{1}}}}}}}"),
+        ] {
+            assert!(
+                matches!(
+                    text(&ty, value),
+                    Err(PgWireError::FailedToParseParameter(_))
+                ),
+                "{value}"
+            );
+        }
+
+        assert_eq!(
+            PgValue::Null,
+            PgValue::decode(&Type::INT4, FieldFormat::Text, None).unwrap()
+        );
+
+        // max dimensions is fine, and deep nesting fails without exhausting
+        // the stack
+        let nested = |depth: usize| "{".repeat(depth) + "1" + &"}".repeat(depth);
+        assert!(text(&Type::INT4_ARRAY, &nested(MAX_ARRAY_DIMENSIONS)).is_ok());
+        assert!(matches!(
+            text(&Type::INT4_ARRAY, &nested(100_000)),
+            Err(PgWireError::FailedToParseParameter(_))
+        ));
+    }
+
+    #[test]
+    fn test_decode_binary() {
+        assert_eq!(PgValue::Int4(42), binary(&Type::INT4, 42i32));
+        assert_eq!(PgValue::Float4(1.5), binary(&Type::FLOAT4, 1.5f32));
+        assert_eq!(PgValue::Text("tom".to_owned()), binary(&Type::NAME, "tom"));
+        assert_eq!(
+            PgValue::Timestamp(NaiveDate::from_ymd_opt(2023, 3, 5).unwrap().into()),
+            binary(
+                &Type::TIMESTAMP,
+                NaiveDateTime::from(NaiveDate::from_ymd_opt(2023, 3, 5).unwrap())
+            )
+        );
+        assert_eq!(
+            PgValue::Array(vec![PgValue::Int8(1), PgValue::Null]),
+            binary(&Type::INT8_ARRAY, vec![Some(1i64), None])
+        );
+
+        let mut jsonb = BytesMut::new();
+        jsonb.put_u8(1);
+        jsonb.put_slice(b"{\"a\":1}");
+        assert_eq!(
+            PgValue::Text("{\"a\":1}".to_owned()),
+            PgValue::decode(&Type::JSONB, FieldFormat::Binary, Some(&jsonb.freeze())).unwrap()
+        );
+
+        // 2 dimensions, no null, int4, 2x2 from 1
+        let mut array = BytesMut::new();
+        for v in [2, 0, 23, 2, 1, 2, 1] {
+            array.put_i32(v);
+        }
+        for v in 1..=4 {
+            array.put_i32(4);
+            array.put_i32(v);
+        }
+        assert_eq!(
+            PgValue::Array(vec![
+                PgValue::Array(vec![PgValue::Int4(1), PgValue::Int4(2)]),
+                PgValue::Array(vec![PgValue::Int4(3), PgValue::Int4(4)]),
+            ]),
+            PgValue::decode(
+                &Type::INT4_ARRAY,
+                FieldFormat::Binary,
+                Some(&array.freeze())
+            )
+            .unwrap()
+        );
+
+        assert!(matches!(
+            PgValue::decode(
+                &Type::INT4,
+                FieldFormat::Binary,
+                Some(&Bytes::from_static(b"\x01"))
+            ),
+            Err(PgWireError::FailedToParseParameter(_))
+        ));
+
+        let decode_array = |header: &[i32]| {
+            let mut array = BytesMut::new();
+            for v in header {
+                array.put_i32(*v);
+            }
+            PgValue::decode(
+                &Type::INT4_ARRAY,
+                FieldFormat::Binary,
+                Some(&array.freeze()),
+            )
+        };
+        // 7 dimensions of 1 element
+        let mut header = vec![7, 0, 23];
+        header.extend([1, 1].repeat(7));
+        assert!(matches!(
+            decode_array(&header),
+            Err(PgWireError::FailedToParseParameter(_))
+        ));
+        // dimensions with a product overflowing usize
+        let mut header = vec![6, 0, 23];
+        header.extend([i32::MAX, 1].repeat(6));
+        assert!(matches!(
+            decode_array(&header),
+            Err(PgWireError::FailedToParseParameter(_))
+        ));
+        // huge dimensions without elements
+        assert!(matches!(
+            decode_array(&[2, 0, 23, i32::MAX, 1, i32::MAX, 1]),
+            Err(PgWireError::FailedToParseParameter(_))
+        ));
+    }
+
+    #[test]
+    fn test_validate_binary() {
+        fn encoded<T: ToSql>(ty: &Type, value: T) -> BytesMut {
+            let mut buf = BytesMut::new();
+            value.to_sql(ty, &mut buf).unwrap();
+            buf
+        }
+        assert!(validate_binary(&Type::INT4, &encoded(&Type::INT4, 42i32)).is_ok());
+        assert!(validate_binary(&Type::NAME, &encoded(&Type::NAME, "tom")).is_ok());
+        let array = encoded(&Type::INT8_ARRAY, vec![Some(1i64), None]);
+        assert!(validate_binary(&Type::INT8_ARRAY, &array).is_ok());
+        assert!(validate_binary(&Type::NUMERIC, &[0, 1, 0, 0, 0, 0, 0, 0, 0, 7]).is_ok());
+        // types without decoder are not checked
+        assert!(validate_binary(&Type::POINT, &[1]).is_ok());
+
+        assert!(validate_binary(&Type::INT4, &[0, 0, 7]).is_err());
+        assert!(validate_binary(&Type::TEXT, &[0x61, 0xff]).is_err());
+        assert!(validate_binary(&Type::JSONB, b"{}").is_err());
+        assert!(validate_binary(&Type::NUMERIC, &[0, 2, 0, 0, 0, 0, 0, 0, 0, 7]).is_err());
+        // an element of wrong length
+        let mut array = BytesMut::new();
+        for v in [1, 0, 23, 1, 1] {
+            array.put_i32(v);
+        }
+        array.put_i32(3);
+        array.put_slice(&[0, 0, 7]);
+        assert!(validate_binary(&Type::INT4_ARRAY, &array).is_err());
+        // dimensions with a product overflowing usize
+        let mut array = BytesMut::new();
+        for v in [6, 0, 23] {
+            array.put_i32(v);
+        }
+        for _ in 0..6 {
+            array.put_i32(i32::MAX);
+            array.put_i32(1);
+        }
+        assert!(validate_binary(&Type::INT4_ARRAY, &array).is_err());
+    }
+
+    #[test]
+    fn test_decode_binary_numeric() {
+        for (raw, expected) in [
+            // 12.50: digits 12, 5000, weight 0, scale 2
+            (vec![2, 0, 0, 2, 12, 5000], "12.50"),
+            // -10000.1: digits 1, 0, 1000, weight 1, scale 1
+            (vec![3, 1, 0x4000, 1, 1, 0, 1000], "-10000.1"),
+            // 0.0005: digit 5, weight -1, scale 4
+            (vec![1, -1, 0, 4, 5], "0.0005"),
+            (vec![0, 0, 0, 0], "0"),
+            (vec![0, 0, 0xC000u16 as i16, 0], "NaN"),
+        ] {
+            let mut buf = BytesMut::new();
+            for v in raw {
+                buf.put_i16(v);
+            }
+            assert_eq!(
+                PgValue::Numeric(expected.to_owned()),
+                PgValue::decode(&Type::NUMERIC, FieldFormat::Binary, Some(&buf.freeze())).unwrap()
+            );
+        }
+    }
+}
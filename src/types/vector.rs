@@ -0,0 +1,165 @@
+//! Values of `oidvector` and `int2vector`, the vector types of system
+//! catalogs, like `proargtypes` of `pg_proc` and `indkey` of `pg_index`.
+//!
+//! Unlike arrays, the text form is a space separated list without braces,
+//! like `23 25`. The binary form is the one of a one-dimensional array with
+//! lower bound 0, and no `NULL` elements.
+
+use std::error::Error;
+
+use bytes::{BufMut, BytesMut};
+use postgres_types::{to_sql_checked, IsNull, Oid, ToSql, Type};
+
+use super::ToSqlText;
+
+/// Value of postgres `oidvector` type
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OidVector(pub Vec<Oid>);
+
+/// Value of postgres `int2vector` type
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Int2Vector(pub Vec<i16>);
+
+fn put_text<T: ToString>(elements: &[T], out: &mut BytesMut) {
+    for (idx, element) in elements.iter().enumerate() {
+        if idx > 0 {
+            out.put_u8(b' ');
+        }
+        out.put_slice(element.to_string().as_bytes());
+    }
+}
+
+fn put_binary_header(
+    element_type: &Type,
+    len: usize,
+    out: &mut BytesMut,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let len = i32::try_from(len).map_err(|_| "vector too long")?;
+    // an empty vector has no dimension, like an empty array
+    out.put_i32(if len > 0 { 1 } else { 0 });
+    // no nulls
+    out.put_i32(0);
+    out.put_u32(element_type.oid());
+    if len > 0 {
+        out.put_i32(len);
+        // lower bound
+        out.put_i32(0);
+    }
+    Ok(())
+}
+
+impl ToSqlText for OidVector {
+    fn to_sql_text(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        put_text(&self.0, out);
+        Ok(IsNull::No)
+    }
+}
+
+impl ToSql for OidVector {
+    fn to_sql(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        put_binary_header(&Type::OID, self.0.len(), out)?;
+        for oid in &self.0 {
+            out.put_i32(4);
+            out.put_u32(*oid);
+        }
+        Ok(IsNull::No)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        *ty == Type::OID_VECTOR
+    }
+
+    to_sql_checked!();
+}
+
+impl ToSqlText for Int2Vector {
+    fn to_sql_text(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        put_text(&self.0, out);
+        Ok(IsNull::No)
+    }
+}
+
+impl ToSql for Int2Vector {
+    fn to_sql(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        put_binary_header(&Type::INT2, self.0.len(), out)?;
+        for value in &self.0 {
+            out.put_i32(2);
+            out.put_i16(*value);
+        }
+        Ok(IsNull::No)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        *ty == Type::INT2_VECTOR
+    }
+
+    to_sql_checked!();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_vector_encoding() {
+        let oids = OidVector(vec![23, 25]);
+        let mut text = BytesMut::new();
+        oids.to_sql_text(&Type::OID_VECTOR, &mut text).unwrap();
+        assert_eq!(&b"23 25"[..], &text[..]);
+
+        let mut binary = BytesMut::new();
+        oids.to_sql_checked(&Type::OID_VECTOR, &mut binary).unwrap();
+        assert_eq!(
+            &[
+                0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 26, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0,
+                23, 0, 0, 0, 4, 0, 0, 0, 25
+            ][..],
+            &binary[..]
+        );
+        assert!(oids.to_sql_checked(&Type::OID_ARRAY, &mut binary).is_err());
+
+        let keys = Int2Vector(vec![1, -3]);
+        let mut text = BytesMut::new();
+        keys.to_sql_text(&Type::INT2_VECTOR, &mut text).unwrap();
+        assert_eq!(&b"1 -3"[..], &text[..]);
+
+        let mut binary = BytesMut::new();
+        keys.to_sql_checked(&Type::INT2_VECTOR, &mut binary)
+            .unwrap();
+        assert_eq!(
+            &[
+                0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 21, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 2, 0, 1, 0,
+                0, 0, 2, 0xff, 0xfd
+            ][..],
+            &binary[..]
+        );
+
+        // empty vectors have no dimension
+        let mut text = BytesMut::new();
+        Int2Vector::default()
+            .to_sql_text(&Type::INT2_VECTOR, &mut text)
+            .unwrap();
+        assert!(text.is_empty());
+        let mut binary = BytesMut::new();
+        OidVector::default()
+            .to_sql_checked(&Type::OID_VECTOR, &mut binary)
+            .unwrap();
+        assert_eq!(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 26][..], &binary[..]);
+    }
+}
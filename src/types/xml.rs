@@ -0,0 +1,154 @@
+use std::error::Error;
+
+use bytes::{BufMut, BytesMut};
+use postgres_types::{to_sql_checked, IsNull, ToSql, Type};
+
+use super::ToSqlText;
+#[cfg(feature = "xml-validation")]
+use crate::error::{ErrorInfo, PgWireError, PgWireResult, Severity, SqlState};
+
+/// Value of postgres `xml` type.
+///
+/// Both text and binary format of `xml` carry the xml text in UTF-8, so the
+/// value is sent as is. Use `Xml::validated` to check the value is well-formed
+/// before sending, with feature `xml-validation`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Xml(String);
+
+impl Xml {
+    /// Create xml value without validation
+    pub fn new<S: Into<String>>(xml: S) -> Xml {
+        Xml(xml.into())
+    }
+
+    /// Create xml value, and check it's a well-formed xml document.
+    ///
+    /// Returns error `2200M` when the document is not well-formed.
+    #[cfg(feature = "xml-validation")]
+    pub fn validated<S: Into<String>>(xml: S) -> PgWireResult<Xml> {
+        let xml = xml.into();
+        check_well_formed(&xml).map_err(|reason| {
+            PgWireError::UserError(Box::new(ErrorInfo::new(
+                Severity::Error,
+                SqlState::InvalidXmlDocument,
+                format!("invalid XML document: {reason}"),
+            )))
+        })?;
+        Ok(Xml(xml))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn into_inner(self) -> String {
+        self.0
+    }
+}
+
+#[cfg(feature = "xml-validation")]
+fn check_well_formed(xml: &str) -> Result<(), String> {
+    use quick_xml::events::Event;
+    use quick_xml::Reader;
+
+    let mut reader = Reader::from_str(xml);
+    reader.check_end_names(true);
+
+    let mut depth = 0usize;
+    let mut roots = 0usize;
+    loop {
+        match reader.read_event().map_err(|e| e.to_string())? {
+            Event::Start(_) => {
+                if depth == 0 {
+                    roots += 1;
+                }
+                depth += 1;
+            }
+            Event::End(_) => depth -= 1,
+            Event::Empty(_) if depth == 0 => roots += 1,
+            Event::Text(text) if depth == 0 && !text.iter().all(u8::is_ascii_whitespace) => {
+                return Err("text outside of root element".to_owned());
+            }
+            Event::CData(_) if depth == 0 => {
+                return Err("CDATA outside of root element".to_owned());
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    if depth > 0 {
+        Err("unclosed element".to_owned())
+    } else if roots != 1 {
+        Err(format!("expected one root element, found {roots}"))
+    } else {
+        Ok(())
+    }
+}
+
+impl ToSqlText for Xml {
+    fn to_sql_text(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        out.put_slice(self.0.as_bytes());
+        Ok(IsNull::No)
+    }
+}
+
+impl ToSql for Xml {
+    fn to_sql(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        out.put_slice(self.0.as_bytes());
+        Ok(IsNull::No)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        *ty == Type::XML
+    }
+
+    to_sql_checked!();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_xml_encoding() {
+        let xml = Xml::new("<a>1</a>");
+        assert!(<Xml as ToSql>::accepts(&Type::XML));
+        assert!(!<Xml as ToSql>::accepts(&Type::TEXT));
+
+        let mut text = BytesMut::new();
+        xml.to_sql_text(&Type::XML, &mut text).unwrap();
+        let mut binary = BytesMut::new();
+        xml.to_sql_checked(&Type::XML, &mut binary).unwrap();
+        assert_eq!(&b"<a>1</a>"[..], &text[..]);
+        assert_eq!(text, binary);
+
+        // no validation by default
+        let mut buf = BytesMut::new();
+        Xml::new("<a>").to_sql_text(&Type::XML, &mut buf).unwrap();
+        assert_eq!(&b"<a>"[..], &buf[..]);
+    }
+
+    #[cfg(feature = "xml-validation")]
+    #[test]
+    fn test_xml_validation() {
+        assert!(Xml::validated("<?xml version=\"1.0\"?>\n<a><b x=\"1\"/>text</a>\n").is_ok());
+
+        for invalid in ["<a>", "<a></b>", "<a/><b/>", "text", "<a></a>text", ""] {
+            match Xml::validated(invalid) {
+                Err(PgWireError::UserError(info)) => {
+                    assert_eq!(SqlState::InvalidXmlDocument, info.sql_state())
+                }
+                r => panic!("unexpected result {r:?} for {invalid:?}"),
+            }
+        }
+    }
+}